@@ -0,0 +1,473 @@
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+
+use crate::attributes::{AttrValue, Attributes};
+use crate::commit::Commit;
+use crate::config::Config;
+use crate::date;
+use crate::error::{bail, format_err, Error, Result};
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::pathname;
+use crate::revparse;
+use crate::tree::{FileMode, Tree};
+use crate::GitRepo;
+
+const BLOCK_SIZE: usize = 512;
+/// Tar output is padded to whole records of 20 blocks
+const RECORD_SIZE: usize = BLOCK_SIZE * 20;
+/// Modes of tar entries are masked by `tar.umask`, this by default
+const DEFAULT_UMASK: u32 = 0o002;
+
+/// Format of archives written by `archive`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Format implied by the extension of the output file, `None` for unknown ones
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        Ok(match format {
+            "tar" => Self::Tar,
+            "zip" => Self::Zip,
+            _ => bail!("Unknown archive format '{}'", format),
+        })
+    }
+}
+
+/// File or directory stored in the archive
+struct Entry {
+    /// Path with the prefix, paths of directories end with `/`
+    path: Vec<u8>,
+    mode: FileMode,
+    /// Object of the entry, names tar extended headers of long paths
+    id: ObjectId,
+    /// Content of files, target of symlinks
+    data: Vec<u8>,
+}
+
+trait ArchiveWriter {
+    fn add(&mut self, entry: &Entry) -> Result<()>;
+
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// Writes archive of the tree-ish to the output, with the prefix prepended to all
+/// paths. Files are stored as checked out, paths with the `export-ignore` attribute of
+/// `.gitattributes` files in the tree are left out.
+pub fn write(
+    repo: &GitRepo,
+    tree_ish: &str,
+    format: ArchiveFormat,
+    prefix: &str,
+    out: impl Write,
+) -> Result<()> {
+    let id = revparse::resolve(repo, &format!("{}^{{}}", tree_ish))?;
+    let (tree_id, commit, time) = match Object::read_header(repo, &id)?.kind {
+        ObjectKind::Commit => {
+            let commit = Commit::read(repo, &id)?;
+            let time = commit.committer.unix_time();
+            (commit.tree_sha, Some(id), time)
+        }
+        ObjectKind::Tree => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+            (id, None, now.as_secs() as i64)
+        }
+        _ => bail!("not a tree object: {}", id),
+    };
+    let mut writer: Box<dyn ArchiveWriter + '_> = match format {
+        ArchiveFormat::Tar => {
+            let umask = match Config::load(repo)?.get("tar.umask") {
+                Some(umask) => u32::from_str_radix(umask, 8)
+                    .map_err(|_| format_err!("Invalid tar.umask {}", umask))?,
+                None => DEFAULT_UMASK,
+            };
+            let mut tar = TarWriter {
+                out,
+                written: 0,
+                time,
+                umask,
+            };
+            if let Some(commit) = &commit {
+                tar.write_global_header(commit)?;
+            }
+            Box::new(tar)
+        }
+        ArchiveFormat::Zip => Box::new(ZipWriter {
+            out,
+            written: 0,
+            time: date::dos_time(time),
+            directory: Vec::new(),
+            count: 0,
+            comment: commit.map(|id| id.to_hex()).unwrap_or_default(),
+        }),
+    };
+
+    if prefix.ends_with('/') {
+        writer.add(&Entry {
+            path: prefix.as_bytes().to_vec(),
+            mode: FileMode::Tree,
+            id: tree_id,
+            data: Vec::new(),
+        })?;
+    }
+    let mut attributes = Attributes::load_tree(repo, &Tree::flatten(repo, &tree_id)?)?;
+    add_tree(
+        repo,
+        &mut attributes,
+        &tree_id,
+        b"",
+        prefix,
+        writer.as_mut(),
+    )?;
+    writer.finish()
+}
+
+/// Adds entries of the tree at the directory, each subtree right before its content
+fn add_tree(
+    repo: &GitRepo,
+    attributes: &mut Attributes,
+    id: &ObjectId,
+    dir: &[u8],
+    prefix: &str,
+    writer: &mut dyn ArchiveWriter,
+) -> Result<()> {
+    let tree: Tree = Object::read(repo, id)?.try_into()?;
+    for tree_entry in tree.entries {
+        let path = [dir, &tree_entry.name].concat();
+        let id = tree_entry.reference;
+        let is_dir = matches!(tree_entry.mode, FileMode::Tree | FileMode::Gitlink);
+        let ignored = if is_dir {
+            attributes.get_dir(&path, "export-ignore")?
+        } else {
+            attributes.get(&path, "export-ignore")?
+        };
+        if ignored == Some(AttrValue::Set) {
+            continue;
+        }
+        let mut entry = Entry {
+            path: [prefix.as_bytes(), &path].concat(),
+            mode: tree_entry.mode,
+            id,
+            data: Vec::new(),
+        };
+        match tree_entry.mode {
+            // Submodules are stored as empty directories
+            FileMode::Tree | FileMode::Gitlink => {
+                entry.path.push(b'/');
+                entry.mode = FileMode::Tree;
+                writer.add(&entry)?;
+                if tree_entry.mode == FileMode::Tree {
+                    add_tree(
+                        repo,
+                        attributes,
+                        &id,
+                        &pathname::concat(&path, b""),
+                        prefix,
+                        writer,
+                    )?;
+                }
+            }
+            FileMode::Symlink => {
+                entry.data = Object::read(repo, &id)?.data;
+                writer.add(&entry)?;
+            }
+            FileMode::Regular | FileMode::Executable => {
+                entry.data = attributes.to_worktree(&path, Object::read(repo, &id)?.data)?;
+                writer.add(&entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes ustar archive the way of git, with long paths in pax extended headers
+struct TarWriter<W: Write> {
+    out: W,
+    written: usize,
+    /// Modification time of all entries
+    time: i64,
+    umask: u32,
+}
+
+/// Record of pax extended header, its length includes the length itself
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let mut len = 1 + 1 + key.len() + 1 + value.len() + 1;
+    let mut i = len;
+    while i > 9 {
+        len += 1;
+        i /= 10;
+    }
+    let mut record = format!("{} {}=", len, key).into_bytes();
+    record.extend(value);
+    record.push(b'\n');
+    record
+}
+
+/// Writes the number as zero padded octal filling the field, terminated by NUL
+fn octal_field(field: &mut [u8], value: u64) {
+    let (last, digits) = field.split_last_mut().unwrap();
+    let text = format!("{:0width$o}", value, width = digits.len());
+    digits.copy_from_slice(text.as_bytes());
+    *last = 0;
+}
+
+impl<W: Write> TarWriter<W> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.out.write_all(data)?;
+        self.written += data.len();
+        Ok(())
+    }
+
+    /// Pads the output to whole blocks
+    fn pad(&mut self) -> Result<()> {
+        let padding = self.written.next_multiple_of(BLOCK_SIZE) - self.written;
+        self.write(&[0; BLOCK_SIZE][..padding])
+    }
+
+    fn header(&self, name: &[u8], mode: u32, typeflag: u8, size: usize) -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name);
+        octal_field(&mut header[100..108], u64::from(mode & 0o7777));
+        octal_field(&mut header[108..116], 0);
+        octal_field(&mut header[116..124], 0);
+        octal_field(&mut header[124..136], size as u64);
+        octal_field(&mut header[136..148], self.time.max(0) as u64);
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[265..269].copy_from_slice(b"root");
+        header[297..301].copy_from_slice(b"root");
+        octal_field(&mut header[329..337], 0);
+        octal_field(&mut header[337..345], 0);
+        header
+    }
+
+    fn write_header(&mut self, mut header: [u8; BLOCK_SIZE]) -> Result<()> {
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|byte| u32::from(*byte)).sum();
+        octal_field(&mut header[148..156], u64::from(checksum));
+        self.write(&header)
+    }
+
+    fn write_extended_header(&mut self, name: &[u8], typeflag: u8, records: &[u8]) -> Result<()> {
+        let header = self.header(name, 0o100666, typeflag, records.len());
+        self.write_header(header)?;
+        self.write(records)?;
+        self.pad()
+    }
+
+    /// Global header with id of the archived commit, read by `get-tar-commit-id`
+    fn write_global_header(&mut self, commit: &ObjectId) -> Result<()> {
+        let records = pax_record("comment", commit.to_hex().as_bytes());
+        self.write_extended_header(b"pax_global_header", b'g', &records)
+    }
+}
+
+impl<W: Write> ArchiveWriter for TarWriter<W> {
+    fn add(&mut self, entry: &Entry) -> Result<()> {
+        let path = &entry.path;
+        let (mode, typeflag, link) = match entry.mode {
+            FileMode::Tree => (0o40777, b'5', &[][..]),
+            FileMode::Symlink => (0o120777, b'2', &entry.data[..]),
+            FileMode::Executable => (0o100777, b'0', &[][..]),
+            _ => (0o100666, b'0', &[][..]),
+        };
+        let size = match typeflag {
+            b'0' => entry.data.len(),
+            _ => 0,
+        };
+        let mut records = Vec::new();
+        // Long paths are split at a slash into the prefix and name fields if possible
+        let (prefix, name) = if path.len() > 100 {
+            let end = path.strip_suffix(b"/").unwrap_or(path).len().min(155);
+            let split = path[..end].iter().rposition(|byte| *byte == b'/');
+            match split.filter(|split| *split > 0 && path.len() - split - 1 <= 100) {
+                Some(split) => (&path[..split], &path[split + 1..]),
+                None => {
+                    records.extend(pax_record("path", path));
+                    (&[][..], &b""[..])
+                }
+            }
+        } else {
+            (&[][..], &path[..])
+        };
+        let data_name = format!("{}.data", entry.id);
+        let name = if name.is_empty() {
+            data_name.as_bytes()
+        } else {
+            name
+        };
+        let see_pax = format!("see {}.paxheader", entry.id);
+        let link = if link.len() > 100 {
+            records.extend(pax_record("linkpath", link));
+            see_pax.as_bytes()
+        } else {
+            link
+        };
+        if !records.is_empty() {
+            let name = format!("{}.paxheader", entry.id);
+            self.write_extended_header(name.as_bytes(), b'x', &records)?;
+        }
+
+        // Permissions of symlinks are meaningless, they are kept as is
+        let mode = match entry.mode {
+            FileMode::Symlink => mode,
+            _ => mode & !self.umask,
+        };
+        let mut header = self.header(name, mode, typeflag, size);
+        header[157..157 + link.len()].copy_from_slice(link);
+        header[345..345 + prefix.len()].copy_from_slice(prefix);
+        self.write_header(header)?;
+        if size > 0 {
+            self.write(&entry.data)?;
+            self.pad()?;
+        }
+        Ok(())
+    }
+
+    /// Ends with at least two empty blocks, padded to whole records
+    fn finish(&mut self) -> Result<()> {
+        let mut padding = self.written.next_multiple_of(RECORD_SIZE) - self.written;
+        if padding < 2 * BLOCK_SIZE {
+            padding += RECORD_SIZE;
+        }
+        self.write(&vec![0; padding])?;
+        Ok(self.out.flush()?)
+    }
+}
+
+/// Writes zip archive, files are compressed with deflate
+struct ZipWriter<W: Write> {
+    out: W,
+    written: usize,
+    /// Modification time of all entries in MS-DOS format
+    time: (u16, u16),
+    /// Central directory records of the written entries
+    directory: Vec<u8>,
+    count: usize,
+    /// Archive comment, id of the archived commit
+    comment: String,
+}
+
+/// Version made by of central directory records, unix with zip 2.0 features
+const ZIP_CREATOR: u16 = 0x0314;
+const ZIP_STORE: u16 = 0;
+const ZIP_DEFLATE: u16 = 8;
+/// Flag of entries with UTF-8 names
+const ZIP_UTF8: u16 = 0x0800;
+
+fn zip_u32(value: usize, what: &str) -> Result<u32> {
+    u32::try_from(value).map_err(|_| format_err!("{} is too large for zip archive", what))
+}
+
+impl<W: Write> ArchiveWriter for ZipWriter<W> {
+    fn add(&mut self, entry: &Entry) -> Result<()> {
+        let name = &entry.path;
+        let flags = if name.is_ascii() || std::str::from_utf8(name).is_err() {
+            0
+        } else {
+            ZIP_UTF8
+        };
+        let (mode, attributes): (u32, u32) = match entry.mode {
+            FileMode::Tree => (0o40755, 0x10),
+            FileMode::Symlink => (0o120777, 0),
+            FileMode::Executable => (0o100755, 0),
+            _ => (0o100644, 0),
+        };
+        let mut crc = Crc::new();
+        crc.update(&entry.data);
+        let mut method = ZIP_STORE;
+        let mut compressed = None;
+        if matches!(entry.mode, FileMode::Regular | FileMode::Executable) && !entry.data.is_empty()
+        {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&entry.data)?;
+            let deflated = encoder.finish()?;
+            if deflated.len() < entry.data.len() {
+                method = ZIP_DEFLATE;
+                compressed = Some(deflated);
+            }
+        }
+        let data = compressed.as_deref().unwrap_or(&entry.data);
+        let version: u16 = if method == ZIP_DEFLATE || entry.mode == FileMode::Tree {
+            20
+        } else {
+            10
+        };
+        let offset = zip_u32(self.written, "Archive")?;
+        let path = pathname::display(&entry.path);
+        let size = zip_u32(entry.data.len(), &path)?;
+        let compressed_size = zip_u32(data.len(), &path)?;
+        let name_len = u16::try_from(name.len())
+            .map_err(|_| format_err!("{} is too long for zip archive", path))?;
+
+        // Fields shared by the local header and the central directory record
+        let mut common = Vec::new();
+        common.extend(version.to_le_bytes());
+        common.extend(flags.to_le_bytes());
+        common.extend(method.to_le_bytes());
+        common.extend(self.time.0.to_le_bytes());
+        common.extend(self.time.1.to_le_bytes());
+        common.extend(crc.sum().to_le_bytes());
+        common.extend(compressed_size.to_le_bytes());
+        common.extend(size.to_le_bytes());
+        common.extend(name_len.to_le_bytes());
+        common.extend(0u16.to_le_bytes());
+
+        let mut local = Vec::new();
+        local.extend(0x04034b50u32.to_le_bytes());
+        local.extend(&common);
+        local.extend(name.iter());
+        self.out.write_all(&local)?;
+        self.out.write_all(data)?;
+        self.written += local.len() + data.len();
+
+        self.directory.extend(0x02014b50u32.to_le_bytes());
+        self.directory.extend(ZIP_CREATOR.to_le_bytes());
+        self.directory.extend(&common);
+        // Comment length, disk number and internal attributes
+        self.directory.extend([0; 6]);
+        self.directory
+            .extend(((mode << 16) | attributes).to_le_bytes());
+        self.directory.extend(offset.to_le_bytes());
+        self.directory.extend(name.iter());
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let count =
+            u16::try_from(self.count).map_err(|_| format_err!("Too many files for zip archive"))?;
+        let offset = zip_u32(self.written, "Archive")?;
+        let size = zip_u32(self.directory.len(), "Archive")?;
+        let mut end = Vec::new();
+        end.extend(0x06054b50u32.to_le_bytes());
+        // Number of this disk and of the disk with the central directory
+        end.extend([0; 4]);
+        end.extend(count.to_le_bytes());
+        end.extend(count.to_le_bytes());
+        end.extend(size.to_le_bytes());
+        end.extend(offset.to_le_bytes());
+        end.extend((self.comment.len() as u16).to_le_bytes());
+        end.extend(self.comment.as_bytes());
+        self.out.write_all(&self.directory)?;
+        self.out.write_all(&end)?;
+        Ok(self.out.flush()?)
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+
+use crate::config::{self, Config};
+use crate::diff;
+use crate::error::{bail, format_err, Context, Result};
+use crate::ignore::{self, Pattern};
+use crate::object::Object;
+use crate::oid::ObjectId;
+use crate::pathname;
+use crate::progress::Progress;
+use crate::GitRepo;
+
+/// State of attribute for a path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttrValue {
+    /// `attr`
+    Set,
+    /// `-attr`
+    Unset,
+    /// `attr=value`
+    Value(String),
+}
+
+/// Line of attributes file: pattern with attributes it assigns. Attributes set to `None`
+/// were reset to unspecified with `!attr`.
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: Pattern,
+    attrs: Vec<(String, Option<AttrValue>)>,
+}
+
+/// Parses attribute assignments, expanding the `binary` macro
+fn parse_attrs(items: &str) -> Vec<(String, Option<AttrValue>)> {
+    let mut attrs = Vec::new();
+    for item in items.split_whitespace() {
+        let (name, value) = if let Some(name) = item.strip_prefix('-') {
+            (name, Some(AttrValue::Unset))
+        } else if let Some(name) = item.strip_prefix('!') {
+            (name, None)
+        } else if let Some((name, value)) = item.split_once('=') {
+            (name, Some(AttrValue::Value(value.to_string())))
+        } else {
+            (item, Some(AttrValue::Set))
+        };
+        if name == "binary" && value == Some(AttrValue::Set) {
+            for name in ["diff", "merge", "text"] {
+                attrs.push((name.to_string(), Some(AttrValue::Unset)));
+            }
+        }
+        attrs.push((name.to_string(), value));
+    }
+    attrs
+}
+
+/// Reads rules from attributes file, missing file has no rules
+fn read_rules(path: &Path, base: &[u8]) -> Result<Vec<Rule>> {
+    let Ok(content) = fs::read(path) else {
+        return Ok(Vec::new());
+    };
+    Ok(parse_rules(&content, path, base))
+}
+
+fn parse_rules(content: &[u8], path: &Path, base: &[u8]) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for (i, line) in ignore::lines(content).enumerate() {
+        let line = line.trim_ascii_start();
+        let (pattern, attrs) = match line.iter().position(|b| *b == b' ' || *b == b'\t') {
+            Some(split) => (&line[..split], &line[split + 1..]),
+            None => (line, &line[line.len()..]),
+        };
+        // Negative patterns are forbidden in attributes files
+        match Pattern::parse(pattern, base, path, i + 1) {
+            Some(pattern) if !pattern.negated => rules.push(Rule {
+                pattern,
+                attrs: parse_attrs(&String::from_utf8_lossy(attrs)),
+            }),
+            _ => {}
+        }
+    }
+    rules
+}
+
+/// Line ending in the working tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Eol {
+    Lf,
+    Crlf,
+}
+
+/// How line endings of the file are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TextMode {
+    Binary,
+    Text,
+    /// Text unless the content looks binary
+    Auto,
+}
+
+/// `core.autocrlf` setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AutoCrlf {
+    False,
+    True,
+    Input,
+}
+
+/// Content which must not get its line endings converted automatically
+fn looks_binary(data: &[u8]) -> bool {
+    diff::is_binary(data)
+        || data
+            .iter()
+            .enumerate()
+            .any(|(i, c)| *c == b'\r' && data.get(i + 1) != Some(&b'\n'))
+}
+
+/// Quotes the path for use in shell command
+fn shell_quote(path: &[u8]) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for b in path {
+        match b {
+            b'\'' => quoted.extend(b"'\\''"),
+            b => quoted.push(*b),
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// Runs filter command through the shell with the data on its standard input,
+/// returning its standard output. `%f` in the command is replaced by the path.
+fn run_filter(root: &Path, command: &str, path: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut script = Vec::new();
+    for (i, part) in command.split("%f").enumerate() {
+        if i > 0 {
+            script.extend(shell_quote(path));
+        }
+        script.extend(part.as_bytes());
+    }
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(pathname::to_os(&script))
+        .current_dir(root)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Cannot fork to run external filter '{}'", command))?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or(format_err!("Filter stdin not captured"))?;
+    // Feed the input from another thread so that the filter cannot block on full output
+    let output = thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(data));
+        let output = child.wait_with_output();
+        // Filters may exit without reading all input
+        let _ = writer.join();
+        output
+    })?;
+    if !output.status.success() {
+        bail!("External filter '{}' failed", command);
+    }
+    Ok(output.stdout)
+}
+
+/// Attributes of paths from `.gitattributes` files of the working tree directories and
+/// `.git/info/attributes`, with line ending conversion between blobs and files and
+/// `filter.<driver>` commands run on the content
+pub struct Attributes {
+    root: PathBuf,
+    info: Vec<Rule>,
+    /// Rules of `.gitattributes` files keyed by their directory, loaded on demand
+    per_dir: HashMap<Vec<u8>, Vec<Rule>>,
+    autocrlf: AutoCrlf,
+    /// `core.eol`, used when `core.autocrlf` is false
+    eol: Eol,
+    /// Configuration with the filter drivers
+    config: Config,
+    /// Receives failures of optional filters
+    progress: Arc<dyn Progress>,
+}
+
+impl Attributes {
+    pub fn load(repo: &GitRepo) -> Result<Self> {
+        let config = Config::load(repo)?;
+        let autocrlf = match config.get("core.autocrlf") {
+            Some("input") => AutoCrlf::Input,
+            value if value.is_some() && config::parse_bool(value)? => AutoCrlf::True,
+            _ => AutoCrlf::False,
+        };
+        let eol = match config.get("core.eol") {
+            Some("crlf") => Eol::Crlf,
+            _ => Eol::Lf,
+        };
+        let info = read_rules(&repo.common_dir.join("info").join("attributes"), b"")
+            .context("Reading info/attributes")?;
+        Ok(Self {
+            root: repo.repo_root.clone(),
+            info,
+            per_dir: HashMap::new(),
+            autocrlf,
+            eol,
+            config,
+            progress: repo.progress.clone(),
+        })
+    }
+
+    /// Attributes of paths in the tree, `.gitattributes` files are read from its blobs
+    /// instead of the working tree
+    pub fn load_tree(repo: &GitRepo, tree: &BTreeMap<Vec<u8>, (u32, ObjectId)>) -> Result<Self> {
+        let mut attributes = Self::load(repo)?;
+        attributes.per_dir.insert(Vec::new(), Vec::new());
+        for path in tree.keys() {
+            for (i, b) in path.iter().enumerate() {
+                if *b == b'/' {
+                    attributes
+                        .per_dir
+                        .entry(path[..i + 1].to_vec())
+                        .or_default();
+                }
+            }
+        }
+        for (path, (_, id)) in tree {
+            let Some(dir) = path.strip_suffix(b".gitattributes") else {
+                continue;
+            };
+            if dir.is_empty() || dir.ends_with(b"/") {
+                let blob = Object::read(repo, id)?;
+                let source = pathname::to_os(path);
+                let rules = parse_rules(&blob.data, Path::new(&source), dir);
+                attributes.per_dir.insert(dir.to_vec(), rules);
+            }
+        }
+        Ok(attributes)
+    }
+
+    fn dir_rules(&mut self, dir: &[u8]) -> Result<&[Rule]> {
+        if !self.per_dir.contains_key(dir) {
+            let path = pathname::join(&self.root, dir).join(".gitattributes");
+            let rules = read_rules(&path, dir)?;
+            self.per_dir.insert(dir.to_vec(), rules);
+        }
+        Ok(&self.per_dir[dir])
+    }
+
+    /// Value of the attribute for the file, `None` when unspecified. The last matching line
+    /// wins, `.git/info/attributes` and deeper `.gitattributes` files take precedence.
+    pub fn get(&mut self, path: &[u8], name: &str) -> Result<Option<AttrValue>> {
+        self.lookup(path, false, name)
+    }
+
+    /// Value of the attribute for the directory, see [`Attributes::get`]
+    pub fn get_dir(&mut self, path: &[u8], name: &str) -> Result<Option<AttrValue>> {
+        self.lookup(path, true, name)
+    }
+
+    fn lookup(&mut self, path: &[u8], is_dir: bool, name: &str) -> Result<Option<AttrValue>> {
+        let find = |rules: &[Rule]| {
+            rules
+                .iter()
+                .rev()
+                .filter(|rule| rule.pattern.matches(path, is_dir))
+                .find_map(|rule| rule.attrs.iter().rev().find(|(n, _)| n == name))
+                .map(|(_, value)| value.clone())
+        };
+        if let Some(value) = find(&self.info) {
+            return Ok(value);
+        }
+        let mut dirs = vec![&path[..0]];
+        for (i, b) in path.iter().enumerate() {
+            if *b == b'/' {
+                dirs.push(&path[..i + 1]);
+            }
+        }
+        for dir in dirs.iter().rev() {
+            if let Some(value) = find(self.dir_rules(dir)?) {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Forgets cached rules of the directory, e.g. after its `.gitattributes` was written
+    pub fn invalidate(&mut self, dir: &[u8]) {
+        self.per_dir.remove(dir);
+    }
+
+    /// Forgets cached rules of the file's directory when the file is its `.gitattributes`
+    pub fn invalidate_file(&mut self, path: &[u8]) {
+        let dir = match pathname::split_last(path) {
+            Some((dir, name)) => (name == b".gitattributes").then(|| pathname::concat(dir, b"")),
+            None => (path == b".gitattributes").then(Vec::new),
+        };
+        if let Some(dir) = dir {
+            self.per_dir.remove(&dir);
+        }
+    }
+
+    fn text_mode(&mut self, path: &[u8]) -> Result<(TextMode, Option<Eol>)> {
+        let eol = match self.get(path, "eol")? {
+            Some(AttrValue::Value(value)) if value == "lf" => Some(Eol::Lf),
+            Some(AttrValue::Value(value)) if value == "crlf" => Some(Eol::Crlf),
+            _ => None,
+        };
+        let mode = match self.get(path, "text")? {
+            Some(AttrValue::Set) => TextMode::Text,
+            Some(AttrValue::Unset) => TextMode::Binary,
+            Some(AttrValue::Value(value)) if value == "auto" => TextMode::Auto,
+            // Setting eol implies text
+            _ if eol.is_some() => TextMode::Text,
+            _ if self.autocrlf != AutoCrlf::False => TextMode::Auto,
+            _ => TextMode::Binary,
+        };
+        Ok((mode, eol))
+    }
+
+    /// Runs `clean` or `smudge` command of the filter driver set by the `filter`
+    /// attribute. Without configured command, or when a command of a driver which is not
+    /// `required` fails, the data passes unchanged.
+    fn filter(&mut self, path: &[u8], data: Vec<u8>, kind: &str) -> Result<Vec<u8>> {
+        let Some(AttrValue::Value(driver)) = self.get(path, "filter")? else {
+            return Ok(data);
+        };
+        let required = self
+            .config
+            .get_bool(&format!("filter.{}.required", driver))?
+            .unwrap_or_default();
+        let Some(command) = self.config.get(&format!("filter.{}.{}", driver, kind)) else {
+            if required {
+                bail!(
+                    "{}: {} filter '{}' failed",
+                    pathname::display(path),
+                    kind,
+                    driver
+                );
+            }
+            return Ok(data);
+        };
+        match run_filter(&self.root, command, path, &data) {
+            Ok(filtered) => Ok(filtered),
+            Err(err) if required => Err(err.context(format!(
+                "{}: {} filter '{}' failed",
+                pathname::display(path),
+                kind,
+                driver
+            ))),
+            Err(err) => {
+                self.progress.info(&format!("error: {}", err.chain()));
+                Ok(data)
+            }
+        }
+    }
+
+    /// Whether content of the file passes unchanged between the blob and the working
+    /// tree, so it can be streamed without conversion
+    pub fn is_verbatim(&mut self, path: &[u8]) -> Result<bool> {
+        Ok(self.get(path, "filter")?.is_none() && self.text_mode(path)?.0 == TextMode::Binary)
+    }
+
+    /// Converts content of the working tree file to the content stored in blob, running
+    /// the clean filter and normalizing CRLF line endings of text files to LF
+    pub fn to_repo(&mut self, path: &[u8], data: Vec<u8>) -> Result<Vec<u8>> {
+        let data = self.filter(path, data, "clean")?;
+        let (mode, _) = self.text_mode(path)?;
+        if mode == TextMode::Binary
+            || (mode == TextMode::Auto && looks_binary(&data))
+            || !data.windows(2).any(|w| w == b"\r\n")
+        {
+            return Ok(data);
+        }
+        let mut converted = Vec::with_capacity(data.len());
+        for (i, c) in data.iter().enumerate() {
+            if !(*c == b'\r' && data.get(i + 1) == Some(&b'\n')) {
+                converted.push(*c);
+            }
+        }
+        Ok(converted)
+    }
+
+    /// Converts blob content to the content of the working tree file, using CRLF line
+    /// endings for text files when configured and running the smudge filter
+    pub fn to_worktree(&mut self, path: &[u8], data: Vec<u8>) -> Result<Vec<u8>> {
+        let data = self.crlf_to_worktree(path, data)?;
+        self.filter(path, data, "smudge")
+    }
+
+    fn crlf_to_worktree(&mut self, path: &[u8], data: Vec<u8>) -> Result<Vec<u8>> {
+        let (mode, eol) = self.text_mode(path)?;
+        let eol = eol.unwrap_or(match self.autocrlf {
+            AutoCrlf::True => Eol::Crlf,
+            AutoCrlf::Input => Eol::Lf,
+            AutoCrlf::False => self.eol,
+        });
+        if mode == TextMode::Binary
+            || eol == Eol::Lf
+            || (mode == TextMode::Auto && (looks_binary(&data) || data.contains(&b'\r')))
+        {
+            return Ok(data);
+        }
+        let mut converted = Vec::with_capacity(data.len() + data.len() / 16);
+        for (i, c) in data.iter().enumerate() {
+            if *c == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+                converted.push(b'\r');
+            }
+            converted.push(*c);
+        }
+        Ok(converted)
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::commit::Commit;
+use crate::error::{bail, ensure, format_err, Context, Result};
+use crate::hooks;
+use crate::merge::{self, TreeMerge};
+use crate::oid::ObjectId;
+use crate::reflog;
+use crate::refs::{self, RefTransaction, RefValue};
+use crate::revparse;
+use crate::revwalk::{peel_to_commit, RevWalk};
+use crate::sequencer::subject;
+use crate::tree::Tree;
+use crate::GitRepo;
+
+const BAD_REF: &str = "refs/bisect/bad";
+const GOOD_PREFIX: &str = "refs/bisect/good-";
+
+/// Verdict about single commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Term {
+    Good,
+    Bad,
+}
+
+impl Term {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Term::Good => "good",
+            Term::Bad => "bad",
+        }
+    }
+}
+
+/// Where the bisection stands after marking commits
+pub enum BisectStatus {
+    /// No bad commit known yet, number of good commits is given
+    NeedBad(usize),
+    /// Bad commit known but no good ones
+    NeedGood,
+    /// The first bad commit was found
+    Found(ObjectId),
+    /// Candidate checked out for testing, with number of commits left after it and
+    /// estimated number of steps
+    Testing {
+        id: ObjectId,
+        remaining: usize,
+        steps: usize,
+    },
+}
+
+/// State of bisection in progress, stored in `.git/BISECT_*` files and `refs/bisect/`
+pub struct Bisect {
+    /// Branch or commit checked out before the bisection started
+    pub start: String,
+    pub bad: Option<ObjectId>,
+    pub good: Vec<ObjectId>,
+}
+
+/// Estimated number of steps left to test `all` candidates, as computed by git
+fn estimate_steps(all: usize) -> usize {
+    if all < 3 {
+        return 0;
+    }
+    let n = all.ilog2() as usize;
+    let e = 1 << n;
+    if e < 3 * (all - e) {
+        n
+    } else {
+        n - 1
+    }
+}
+
+impl Bisect {
+    fn start_path(repo: &GitRepo) -> PathBuf {
+        repo.git_dir.join("BISECT_START")
+    }
+
+    pub fn in_progress(repo: &GitRepo) -> bool {
+        Self::start_path(repo).is_file()
+    }
+
+    /// Starts bisection from the current HEAD, remembering it for `reset`
+    pub fn start(repo: &GitRepo) -> Result<Self> {
+        if Self::in_progress(repo) {
+            Self::load(repo)?.reset(repo, None)?;
+        }
+        let start = match refs::read(repo, "HEAD")? {
+            Some(RefValue::Symbolic(target)) => target
+                .strip_prefix("refs/heads/")
+                .unwrap_or(&target)
+                .to_string(),
+            Some(RefValue::Direct(id)) => id.to_hex(),
+            None => bail!("Bad HEAD - I need a HEAD"),
+        };
+        fs::write(Self::start_path(repo), format!("{}\n", start))?;
+        fs::write(repo.git_dir.join("BISECT_LOG"), "git bisect start\n")?;
+        Ok(Self {
+            start,
+            bad: None,
+            good: Vec::new(),
+        })
+    }
+
+    pub fn load(repo: &GitRepo) -> Result<Self> {
+        ensure!(
+            Self::in_progress(repo),
+            "You need to start by \"git bisect start\""
+        );
+        let start = fs::read_to_string(Self::start_path(repo))
+            .context("Reading bisect state BISECT_START")?
+            .trim_end()
+            .to_string();
+        let mut good = Vec::new();
+        let mut bad = None;
+        for (name, id) in refs::list(repo)? {
+            if name == BAD_REF {
+                bad = Some(id);
+            } else if name.starts_with(GOOD_PREFIX) {
+                good.push(id);
+            }
+        }
+        Ok(Self { start, bad, good })
+    }
+
+    /// Records verdict about the commit
+    pub fn mark(&mut self, repo: &GitRepo, term: Term, rev: &str) -> Result<()> {
+        let id = peel_to_commit(repo, &revparse::resolve(repo, rev)?)?;
+        match term {
+            Term::Bad => {
+                refs::update(repo, BAD_REF, self.bad.as_ref(), Some(&id), "")?;
+                self.bad = Some(id);
+            }
+            Term::Good => {
+                let name = format!("{}{}", GOOD_PREFIX, id);
+                if !self.good.contains(&id) {
+                    refs::update(repo, &name, None, Some(&id), "")?;
+                    self.good.push(id);
+                }
+            }
+        }
+        let mut log = fs::read_to_string(repo.git_dir.join("BISECT_LOG")).unwrap_or_default();
+        let commit = Commit::read(repo, &id)?;
+        log.push_str(&format!(
+            "# {}: [{}] {}\ngit bisect {} {}\n",
+            term.as_str(),
+            id,
+            subject(&commit),
+            term.as_str(),
+            id
+        ));
+        fs::write(repo.git_dir.join("BISECT_LOG"), log)?;
+        Ok(())
+    }
+
+    /// Commits reachable from the bad commit but not from any good one, newest first
+    fn candidates(&self, repo: &GitRepo, bad: &ObjectId) -> Result<Vec<(ObjectId, Commit)>> {
+        let mut walk = RevWalk::new(repo);
+        walk.push(bad)?;
+        for good in &self.good {
+            walk.hide(good)?;
+        }
+        walk.collect()
+    }
+
+    /// Picks the next commit to test and checks it out, or reports the first bad commit
+    pub fn next(&self, repo: &GitRepo) -> Result<BisectStatus> {
+        let Some(bad) = &self.bad else {
+            return Ok(BisectStatus::NeedBad(self.good.len()));
+        };
+        if self.good.is_empty() {
+            return Ok(BisectStatus::NeedGood);
+        }
+        let candidates = self.candidates(repo, bad)?;
+        ensure!(
+            !candidates.is_empty(),
+            "Some good revs are not ancestors of the bad rev"
+        );
+        if candidates.len() == 1 {
+            return Ok(BisectStatus::Found(*bad));
+        }
+
+        // Number of candidates reachable from each candidate, including itself. Like git,
+        // take the oldest commit reaching about half of them, falling back to the one
+        // closest to half. Commits without candidate parents never count as halfway.
+        let set: HashSet<&ObjectId> = candidates.iter().map(|(id, _)| id).collect();
+        let parents: HashMap<&ObjectId, &Vec<ObjectId>> = candidates
+            .iter()
+            .map(|(id, commit)| (id, &commit.parents))
+            .collect();
+        let all = candidates.len();
+        let mut best: Option<(&ObjectId, usize, usize)> = None;
+        for (id, commit) in candidates.iter().rev() {
+            let mut seen = HashSet::from([id]);
+            let mut stack = vec![id];
+            while let Some(current) = stack.pop() {
+                for parent in parents[current].iter() {
+                    if set.contains(parent) && seen.insert(parent) {
+                        stack.push(parent);
+                    }
+                }
+            }
+            let reaches = seen.len();
+            let has_parents = commit.parents.iter().any(|p| set.contains(p));
+            if has_parents && (2 * reaches).abs_diff(all) <= 1 {
+                best = Some((id, reaches, usize::MAX));
+                break;
+            }
+            let distance = reaches.min(all - reaches);
+            if best.is_none_or(|(_, _, best)| distance > best) {
+                best = Some((id, reaches, distance));
+            }
+        }
+        let (id, reaches, _) = best.ok_or(format_err!("No bisection candidate"))?;
+        self.checkout(repo, id)?;
+        Ok(BisectStatus::Testing {
+            id: *id,
+            remaining: all - reaches - 1,
+            steps: estimate_steps(all),
+        })
+    }
+
+    /// Detaches HEAD at the commit, keeping local changes which do not conflict
+    fn checkout(&self, repo: &GitRepo, id: &ObjectId) -> Result<()> {
+        let head = refs::resolve(repo, "HEAD")?.ok_or(format_err!("HEAD is unborn"))?;
+        let current = Tree::flatten(repo, &Commit::read(repo, &head)?.tree_sha)?;
+        let target = TreeMerge {
+            entries: Tree::flatten(repo, &Commit::read(repo, id)?.tree_sha)?,
+            conflicts: Vec::new(),
+        };
+        merge::update_worktree(repo, &current, &target)?;
+        fs::write(
+            repo.git_dir.join("BISECT_EXPECTED_REV"),
+            format!("{}\n", id),
+        )?;
+        refs::detach_head(repo, id, &reflog::checkout_message(repo, &id.to_hex())?)?;
+        hooks::post_checkout(repo, Some(&head), id, true)
+    }
+
+    /// Ends the bisection, checking out the original branch or the given commit
+    pub fn reset(&self, repo: &GitRepo, rev: Option<&str>) -> Result<()> {
+        let target = match rev {
+            Some(rev) => rev,
+            None => &self.start,
+        };
+        let branch = format!("refs/heads/{}", target);
+        let (id, branch) = match refs::resolve(repo, &branch)? {
+            Some(id) => (id, Some(branch)),
+            None => (
+                peel_to_commit(repo, &revparse::resolve(repo, target)?)?,
+                None,
+            ),
+        };
+        let head = refs::resolve(repo, "HEAD")?.ok_or(format_err!("HEAD is unborn"))?;
+        let current = Tree::flatten(repo, &Commit::read(repo, &head)?.tree_sha)?;
+        let target = TreeMerge {
+            entries: Tree::flatten(repo, &Commit::read(repo, &id)?.tree_sha)?,
+            conflicts: Vec::new(),
+        };
+        merge::update_worktree(repo, &current, &target)?;
+        match branch {
+            Some(branch) => {
+                let message = reflog::checkout_message(repo, &branch)?;
+                refs::set_symbolic(repo, "HEAD", &branch, Some(&message))?
+            }
+            None => refs::detach_head(repo, &id, &reflog::checkout_message(repo, &id.to_hex())?)?,
+        }
+
+        let mut transaction = RefTransaction::new();
+        if let Some(bad) = &self.bad {
+            transaction.update(BAD_REF, Some(bad), None, "");
+        }
+        for good in &self.good {
+            transaction.update(&format!("{}{}", GOOD_PREFIX, good), Some(good), None, "");
+        }
+        transaction.commit(repo)?;
+        let _ = fs::remove_dir(repo.git_dir.join("refs").join("bisect"));
+        for name in ["BISECT_START", "BISECT_LOG", "BISECT_EXPECTED_REV"] {
+            let path = repo.git_dir.join(name);
+            if path.is_file() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::commit::Commit;
+use crate::diff::{self, Renames};
+use crate::error::{format_err, Result};
+use crate::object::Object;
+use crate::oid::ObjectId;
+use crate::pathname;
+use crate::revparse;
+use crate::revwalk::peel_to_commit;
+use crate::tree::Tree;
+use crate::{index, refs, GitRepo};
+
+/// Origin of single line of the blamed file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlameLine {
+    /// Commit introducing the line, or null id for changes in the working tree
+    pub commit: ObjectId,
+    /// Path of the file in that commit
+    pub path: Vec<u8>,
+    /// 0-based line number in that commit
+    pub line: usize,
+    /// The line is at least as old as the root commit it is attributed to
+    pub boundary: bool,
+}
+
+/// Final content of the file and origin of each of its lines
+pub struct Blame {
+    pub content: Vec<u8>,
+    pub lines: Vec<BlameLine>,
+}
+
+/// Lines whose origin is not known yet, as (final line, line in suspected version)
+type Suspects = Vec<(usize, usize)>;
+
+/// Splits suspects into lines unchanged in the parent version of the file, mapped to
+/// the parent line numbers, and lines changed since the parent
+fn pass_to_parent(parent: &[u8], data: &[u8], suspects: Suspects) -> (Suspects, Suspects) {
+    let mut in_parent = vec![None; diff::lines(data).len()];
+    for (i, j) in diff::matching(&diff::lines(parent), &diff::lines(data)) {
+        in_parent[j] = Some(i);
+    }
+    let (mut passed, mut kept) = (Vec::new(), Vec::new());
+    for (line, here) in suspects {
+        match in_parent[here] {
+            Some(there) => passed.push((line, there)),
+            None => kept.push((line, here)),
+        }
+    }
+    (passed, kept)
+}
+
+/// Blob of the file at the path in the tree
+fn file_at(repo: &GitRepo, tree: &ObjectId, path: &[u8]) -> Result<Option<ObjectId>> {
+    Ok(Tree::entry_at(repo, tree, path)?
+        .filter(|(mode, _)| *mode != 0o40000)
+        .map(|(_, id)| id))
+}
+
+/// Path and blob of the file in the parent, following rename when it does not exist there
+fn parent_file(
+    repo: &GitRepo,
+    tree: &ObjectId,
+    parent_tree: &ObjectId,
+    path: &[u8],
+) -> Result<Option<(Vec<u8>, ObjectId)>> {
+    if let Some(blob) = file_at(repo, parent_tree, path)? {
+        return Ok(Some((path.to_vec(), blob)));
+    }
+    let parent_files = Tree::flatten(repo, parent_tree)?;
+    let changes = diff::changes(&parent_files, &Tree::flatten(repo, tree)?);
+    let changes = diff::detect_renames(repo, changes, &Renames::default(), false)?;
+    Ok(changes
+        .into_iter()
+        .find(|change| change.path == path)
+        .and_then(|change| change.rename)
+        .map(|rename| {
+            let blob = parent_files[&rename.from].1;
+            (rename.from, blob)
+        }))
+}
+
+/// Attributes each line of the file to the commit which introduced it, walking history
+/// from `rev` or from the working tree version when not given. Lines are passed from
+/// commit to its parents as long as the diff keeps them unchanged, renames are followed.
+pub fn blame(repo: &GitRepo, rev: Option<&str>, path: &[u8]) -> Result<Blame> {
+    let start = match rev {
+        Some(rev) => Some(peel_to_commit(repo, &revparse::resolve(repo, rev)?)?),
+        None => refs::resolve(repo, "HEAD")?,
+    };
+    let start_blob = match &start {
+        Some(id) => file_at(repo, &Commit::read(repo, id)?.tree_sha, path)?,
+        None => None,
+    };
+    let committed = match start_blob {
+        Some(id) => Some(Object::read(repo, &id)?.data),
+        None => None,
+    };
+    let content = match rev {
+        Some(rev) => committed.clone().ok_or(format_err!(
+            "no such path {} in {}",
+            pathname::display(path),
+            rev
+        ))?,
+        None => index::worktree_file(repo, path)?
+            .map(|(_, data)| data)
+            .ok_or(format_err!(
+                "no such path '{}' in HEAD",
+                pathname::display(path)
+            ))?,
+    };
+
+    let mut result: Vec<Option<BlameLine>> = vec![None; diff::lines(&content).len()];
+    let mut suspects: Suspects = (0..result.len()).map(|i| (i, i)).collect();
+    // Lines changed in the working tree are not committed yet
+    if rev.is_none() && committed.as_ref() != Some(&content) {
+        let kept;
+        (suspects, kept) = match &committed {
+            Some(data) => pass_to_parent(data, &content, suspects),
+            None => (Vec::new(), suspects),
+        };
+        for (line, here) in kept {
+            result[line] = Some(BlameLine {
+                commit: repo.hash_algo().null_id(),
+                path: path.to_vec(),
+                line: here,
+                boundary: false,
+            });
+        }
+    }
+
+    // Newest commits first, so that all lines suspected in a commit are collected
+    // before it is processed
+    let mut pending: HashMap<(ObjectId, Vec<u8>), Suspects> = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    if let (Some(id), false) = (start, suspects.is_empty()) {
+        let commit = Commit::read(repo, &id)?;
+        queue.push((commit.committer.unix_time(), id, path.to_vec()));
+        pending.insert((id, path.to_vec()), suspects);
+    }
+    while let Some((_, id, path)) = queue.pop() {
+        let Some(mut suspects) = pending.remove(&(id, path.clone())) else {
+            continue;
+        };
+        let commit = Commit::read(repo, &id)?;
+        let blob = file_at(repo, &commit.tree_sha, &path)?.ok_or(format_err!(
+            "{} missing in {}",
+            pathname::display(&path),
+            id
+        ))?;
+        let data = Object::read(repo, &blob)?.data;
+        let mut parents = Vec::new();
+        for parent in &commit.parents {
+            let parent_commit = Commit::read(repo, parent)?;
+            if file_at(repo, &parent_commit.tree_sha, &path)? == Some(blob) {
+                // Parent with the same file is the only one the history is followed to
+                parents = vec![(*parent, parent_commit)];
+                break;
+            }
+            parents.push((*parent, parent_commit));
+        }
+        for (parent, parent_commit) in &parents {
+            if suspects.is_empty() {
+                break;
+            }
+            let Some((parent_path, parent_blob)) =
+                parent_file(repo, &commit.tree_sha, &parent_commit.tree_sha, &path)?
+            else {
+                continue;
+            };
+            let passed;
+            (passed, suspects) = if parent_blob == blob {
+                (suspects, Vec::new())
+            } else {
+                let parent_data = Object::read(repo, &parent_blob)?.data;
+                pass_to_parent(&parent_data, &data, suspects)
+            };
+            if passed.is_empty() {
+                continue;
+            }
+            let key = (*parent, parent_path.clone());
+            if !pending.contains_key(&key) {
+                queue.push((parent_commit.committer.unix_time(), *parent, parent_path));
+            }
+            pending.entry(key).or_default().extend(passed);
+        }
+        for (line, here) in suspects {
+            result[line] = Some(BlameLine {
+                commit: id,
+                path: path.clone(),
+                line: here,
+                boundary: commit.parents.is_empty(),
+            });
+        }
+    }
+
+    Ok(Blame {
+        content,
+        lines: result
+            .into_iter()
+            .map(|line| line.ok_or(format_err!("Line origin not found")))
+            .collect::<Result<_>>()?,
+    })
+}
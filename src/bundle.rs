@@ -0,0 +1,209 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use crate::commit::Commit;
+use crate::config::Config;
+use crate::error::{bail, ensure, format_err, Context, Result};
+use crate::fsck;
+use crate::hash::HashAlgo;
+use crate::http_protocol::{Ref, RefInfo};
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::pack;
+use crate::refs;
+use crate::revparse;
+use crate::sequencer::subject;
+use crate::GitRepo;
+
+const V2_SIGNATURE: &str = "# v2 git bundle\n";
+const V3_SIGNATURE: &str = "# v3 git bundle\n";
+
+/// Refs with the pack of objects they need, for transfer without network. Objects of
+/// the prerequisite commits have to be present in the receiving repository.
+pub struct Bundle {
+    pub algo: HashAlgo,
+    /// Commits the pack depends on, with their subjects
+    pub prerequisites: Vec<(ObjectId, String)>,
+    pub refs: Vec<(String, ObjectId)>,
+    pack: Vec<u8>,
+}
+
+impl Bundle {
+    /// Whether the file starts with bundle signature
+    pub fn is_bundle(path: &Path) -> bool {
+        let mut signature = [0; V2_SIGNATURE.len()];
+        let read = fs::File::open(path).and_then(|mut file| file.read_exact(&mut signature));
+        let known = [V2_SIGNATURE, V3_SIGNATURE];
+        read.is_ok() && known.iter().any(|known| known.as_bytes() == signature)
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+        Self::parse(&data).with_context(|| format!("'{}' is not a valid bundle", path.display()))
+    }
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        let version = match data {
+            _ if data.starts_with(V2_SIGNATURE.as_bytes()) => 2,
+            _ if data.starts_with(V3_SIGNATURE.as_bytes()) => 3,
+            _ => bail!("Missing bundle signature"),
+        };
+        let mut bundle = Self {
+            algo: HashAlgo::Sha1,
+            prerequisites: Vec::new(),
+            refs: Vec::new(),
+            pack: Vec::new(),
+        };
+        let mut pos = V2_SIGNATURE.len();
+        loop {
+            let end = data[pos..]
+                .iter()
+                .position(|byte| *byte == b'\n')
+                .ok_or(format_err!("Unterminated bundle header"))?;
+            let line = std::str::from_utf8(&data[pos..pos + end])?;
+            pos += end + 1;
+            if line.is_empty() {
+                break;
+            }
+            if let Some(capability) = line.strip_prefix('@') {
+                ensure!(version == 3, "Capability in v2 bundle");
+                match capability.split_once('=') {
+                    Some(("object-format", algo)) => bundle.algo = algo.parse()?,
+                    _ => bail!("Unsupported bundle capability {}", capability),
+                }
+            } else if let Some(prerequisite) = line.strip_prefix('-') {
+                let (id, comment) = prerequisite.split_once(' ').unwrap_or((prerequisite, ""));
+                bundle
+                    .prerequisites
+                    .push((id.parse()?, comment.to_string()));
+            } else {
+                let (id, name) = line
+                    .split_once(' ')
+                    .ok_or(format_err!("Invalid bundle ref line {}", line))?;
+                bundle.refs.push((name.to_string(), id.parse()?));
+            }
+        }
+        bundle.pack = data[pos..].to_vec();
+        Ok(bundle)
+    }
+
+    /// Refs of the bundle as advertised by a remote
+    pub fn ref_info(&self) -> RefInfo {
+        RefInfo {
+            capabilities: vec![format!("object-format={}", self.algo.name())],
+            refs: self
+                .refs
+                .iter()
+                .map(|(name, id)| Ref {
+                    name: name.clone(),
+                    id: *id,
+                    peeled_ref: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Checks that the repository has all prerequisite commits
+    pub fn verify(&self, repo: &GitRepo) -> Result<()> {
+        ensure!(
+            self.algo == repo.hash_algo(),
+            "Bundle uses {} while the repository uses {}",
+            self.algo.name(),
+            repo.hash_algo().name()
+        );
+        let missing: Vec<String> = self
+            .prerequisites
+            .iter()
+            .filter(|(id, _)| !Object::exists(repo, id))
+            .map(|(id, comment)| format!("{} {}", id, comment).trim_end().to_string())
+            .collect();
+        ensure!(
+            missing.is_empty(),
+            "Repository lacks these prerequisite commits:\n{}",
+            missing.join("\n")
+        );
+        Ok(())
+    }
+
+    /// Stores objects of the bundle in the repository
+    pub fn unbundle(&self, repo: &GitRepo) -> Result<()> {
+        self.verify(repo)?;
+        pack::unpack(repo, &self.pack, fsck::transfer_enabled(repo, "fetch")?)
+    }
+}
+
+/// Writes bundle of the refs named by the revisions with objects they need. Revisions
+/// prefixed with `^` or the left side of `A..B` are excluded, objects reachable from
+/// them become prerequisites. `--all` bundles all refs.
+pub fn create(repo: &GitRepo, path: &Path, revs: &[String]) -> Result<()> {
+    let mut refs = Vec::new();
+    let mut excluded = Vec::new();
+    let mut add_ref = |name: &str| -> Result<()> {
+        let full = refs::dwim(repo, name)?.ok_or(format_err!(
+            "'{}' is not a ref, only refs can be bundled",
+            name
+        ))?;
+        let id = refs::resolve(repo, &full)?.unwrap_or(repo.hash_algo().null_id());
+        if !refs.iter().any(|(name, _)| *name == full) {
+            refs.push((full, id));
+        }
+        Ok(())
+    };
+    for rev in revs {
+        if rev == "--all" {
+            add_ref("HEAD")?;
+            for (name, _) in refs::list(repo)? {
+                add_ref(&name)?;
+            }
+        } else if let Some(rev) = rev.strip_prefix('^') {
+            excluded.push(revparse::resolve(repo, rev)?);
+        } else if let Some((from, to)) = rev.split_once("..") {
+            excluded.push(revparse::resolve(repo, from)?);
+            add_ref(to)?;
+        } else {
+            add_ref(rev)?;
+        }
+    }
+    ensure!(!refs.is_empty(), "Refusing to create empty bundle.");
+
+    let wants: Vec<ObjectId> = refs.iter().map(|(_, id)| *id).collect();
+    let objects = pack::objects_to_send(repo, &wants, &excluded)?
+        .iter()
+        .map(|id| Object::read(repo, id))
+        .collect::<Result<Vec<_>>>()?;
+    // Parents of bundled commits which are not bundled themselves are the prerequisites
+    let sent: HashSet<ObjectId> = objects.iter().map(|obj| obj.hash).collect();
+    let mut prerequisites = Vec::new();
+    for obj in objects
+        .iter()
+        .filter(|obj| obj.header.kind == ObjectKind::Commit)
+    {
+        for parent in Commit::read(repo, &obj.hash)?.parents {
+            if !sent.contains(&parent) && !prerequisites.contains(&parent) {
+                prerequisites.push(parent);
+            }
+        }
+    }
+
+    let algo = repo.hash_algo();
+    let mut data = match algo {
+        HashAlgo::Sha1 => V2_SIGNATURE.to_string(),
+        HashAlgo::Sha256 => format!("{}@object-format={}\n", V3_SIGNATURE, algo.name()),
+    };
+    for id in &prerequisites {
+        let commit = Commit::read(repo, id)?;
+        data.push_str(&format!("-{} {}\n", id, subject(&commit)));
+    }
+    for (name, id) in &refs {
+        data.push_str(&format!("{} {}\n", id, name));
+    }
+    data.push('\n');
+    let mut data = data.into_bytes();
+    let window = Config::load(repo)?.get_int("pack.window")?.unwrap_or(10);
+    let window = usize::try_from(window).context("Invalid delta window")?;
+    let level = repo.write_options()?.pack_compression;
+    data.extend(pack::write_pack_with_deltas(&objects, level, window, algo)?);
+    fs::write(path, data).with_context(|| format!("Writing bundle {}", path.display()))
+}
@@ -0,0 +1,112 @@
+use std::collections::BTreeSet;
+use std::fs;
+
+use crate::error::Result;
+use crate::ignore::Ignore;
+use crate::index::Index;
+use crate::pathname;
+use crate::GitRepo;
+
+/// What `clean` removes besides untracked files
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CleanOptions {
+    /// Remove untracked directories too
+    pub directories: bool,
+    /// Remove ignored files too
+    pub ignored: bool,
+}
+
+struct Walk<'a> {
+    repo: &'a GitRepo,
+    options: CleanOptions,
+    ignore: Ignore,
+    tracked: BTreeSet<&'a [u8]>,
+    /// Directories containing tracked files, with trailing `/`
+    tracked_dirs: BTreeSet<Vec<u8>>,
+}
+
+impl Walk<'_> {
+    /// Collects removable paths in the directory, returns whether everything inside is
+    /// removable so that the directory can go as a whole
+    fn collect(&mut self, prefix: &[u8], removable: &mut Vec<Vec<u8>>) -> Result<bool> {
+        let mut all = true;
+        let mut items = fs::read_dir(self.repo.worktree_path(prefix))?
+            .map(|item| {
+                let item = item?;
+                let name = pathname::from_os(&item.file_name());
+                Ok((name, item.file_type()?.is_dir()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        items.sort();
+        for (name, is_dir) in items {
+            let path = [prefix, &name].concat();
+            if name == b".git" || self.tracked.contains(path.as_slice()) {
+                all = false;
+                continue;
+            }
+            if !self.options.ignored && self.ignore.is_ignored(&path, is_dir)? {
+                all = false;
+                continue;
+            }
+            if !is_dir {
+                removable.push(path);
+                continue;
+            }
+            let dir = pathname::concat(&path, b"");
+            if self.tracked_dirs.contains(&dir) {
+                self.collect(&dir, removable)?;
+                all = false;
+            } else if !self.options.directories
+                || self.repo.worktree_path(&path).join(".git").exists()
+            {
+                // Nested repositories are never removed
+                all = false;
+            } else {
+                let mut inside = Vec::new();
+                if self.collect(&dir, &mut inside)? {
+                    removable.push(dir);
+                } else {
+                    removable.extend(inside);
+                    all = false;
+                }
+            }
+        }
+        Ok(all)
+    }
+}
+
+/// Untracked paths which `clean` would remove, sorted. Directories removed as a whole
+/// have trailing `/`.
+pub fn removable(repo: &GitRepo, options: CleanOptions) -> Result<Vec<Vec<u8>>> {
+    let index = Index::read(repo)?;
+    let tracked: BTreeSet<&[u8]> = index.entries.iter().map(|e| e.path.as_slice()).collect();
+    let mut tracked_dirs = BTreeSet::new();
+    for path in &tracked {
+        for (i, c) in path.iter().enumerate() {
+            if *c == b'/' {
+                tracked_dirs.insert(path[..i + 1].to_vec());
+            }
+        }
+    }
+    let mut walk = Walk {
+        repo,
+        options,
+        ignore: Ignore::load(repo)?,
+        tracked,
+        tracked_dirs,
+    };
+    let mut removable = Vec::new();
+    walk.collect(b"", &mut removable)?;
+    Ok(removable)
+}
+
+/// Removes path returned by `removable`
+pub fn remove(repo: &GitRepo, path: &[u8]) -> Result<()> {
+    let full_path = repo.worktree_path(path);
+    if path.ends_with(b"/") {
+        fs::remove_dir_all(full_path)?;
+    } else {
+        fs::remove_file(full_path)?;
+    }
+    Ok(())
+}
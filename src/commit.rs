@@ -1,6 +1,13 @@
 use std::time::SystemTime;
 
-#[derive(Debug, Clone)]
+use crate::config::Config;
+use crate::encoding::Encoding;
+use crate::error::{bail, ensure, format_err, Context, Error, Result};
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::{date, GitRepo};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Author {
     pub name: String,
     pub email: String,
@@ -8,20 +15,117 @@ pub struct Author {
     pub time_offset: String,
 }
 
+/// Which identity of the commit is being resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Author,
+    Committer,
+}
+
+impl Role {
+    fn env_prefix(&self) -> &'static str {
+        match self {
+            Self::Author => "GIT_AUTHOR",
+            Self::Committer => "GIT_COMMITTER",
+        }
+    }
+
+    fn config_section(&self) -> &'static str {
+        match self {
+            Self::Author => "author",
+            Self::Committer => "committer",
+        }
+    }
+}
+
 impl Author {
-    fn write_to_buf(&self, buf: &mut Vec<u8>) {
+    /// Resolves identity from `GIT_AUTHOR_*`/`GIT_COMMITTER_*` env vars, falling back to
+    /// `author.*`/`committer.*` and `user.*` config. Time defaults to now in local timezone.
+    pub fn from_env(repo: &GitRepo, role: Role) -> Result<Self> {
+        let config = Config::load(repo)?;
+        let lookup = |field: &str| -> Option<String> {
+            std::env::var(format!("{}_{}", role.env_prefix(), field.to_ascii_uppercase()))
+                .ok()
+                .or_else(|| config.get(&format!("{}.{}", role.config_section(), field)).map(String::from))
+                .or_else(|| config.get(&format!("user.{}", field)).map(String::from))
+        };
+        let name = lookup("name").filter(|n| !n.trim().is_empty());
+        let email = lookup("email").or_else(|| std::env::var("EMAIL").ok());
+        let (Some(name), Some(email)) = (name, email) else {
+            bail!(
+                "{:?} identity unknown, set user.name and user.email in config",
+                role
+            );
+        };
+
+        let (time, offset) = match std::env::var(format!("{}_DATE", role.env_prefix())) {
+            Ok(value) => date::parse_date(&value)?,
+            Err(_) => {
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)?
+                    .as_secs() as i64;
+                (now, date::local_offset(now))
+            }
+        };
+        Ok(Self {
+            name: name.trim().to_string(),
+            email: email.trim().trim_start_matches('<').trim_end_matches('>').to_string(),
+            time: date::system_time(time)?,
+            time_offset: date::format_offset(offset),
+        })
+    }
+
+    /// Parses `Name <email> <unix time> <offset>` line of commit or tag header
+    pub fn parse(line: &str) -> Result<Self> {
+        let invalid = || format_err!("Invalid identity {:?}", line);
+        let email_start = line.find('<').ok_or_else(invalid)?;
+        let email_end = line.rfind('>').ok_or_else(invalid)?;
+        ensure!(email_start < email_end, invalid());
+        let (time, offset) = line[email_end + 1..]
+            .trim()
+            .split_once(' ')
+            .ok_or_else(invalid)?;
+        let time: i64 = time.parse().with_context(invalid)?;
+        Ok(Self {
+            name: line[..email_start].trim().to_string(),
+            email: line[email_start + 1..email_end].to_string(),
+            time: date::system_time(time)?,
+            time_offset: offset.to_string(),
+        })
+    }
+
+    /// Seconds since the epoch, negative before it
+    pub fn unix_time(&self) -> i64 {
+        match self.time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        }
+    }
+
+    /// Date in git default format, in the timezone it was recorded in
+    pub fn format_date(&self) -> String {
+        let offset = date::parse_offset(&self.time_offset).unwrap_or(0);
+        date::format_default(self.unix_time(), offset)
+    }
+
+    /// Date like `2005-04-07 22:13:13 +0200`, in the timezone it was recorded in
+    pub fn format_iso_date(&self) -> String {
+        let offset = date::parse_offset(&self.time_offset).unwrap_or(0);
+        date::format_iso(self.unix_time(), offset)
+    }
+
+    /// Date for email headers, in the timezone it was recorded in
+    pub fn format_rfc2822_date(&self) -> String {
+        let offset = date::parse_offset(&self.time_offset).unwrap_or(0);
+        date::format_rfc2822(self.unix_time(), offset)
+    }
+
+    pub(crate) fn write_to_buf(&self, buf: &mut Vec<u8>) {
         buf.extend(self.name.as_bytes());
         buf.extend(b" <");
         buf.extend(self.email.as_bytes());
         buf.extend(b"> ");
-        buf.extend(
-            self.time
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .expect("SystemTime before epoch")
-                .as_secs()
-                .to_string()
-                .as_bytes(),
-        );
+        buf.extend(self.unix_time().to_string().as_bytes());
         buf.push(b' ');
         buf.extend(self.time_offset.as_bytes());
     }
@@ -29,24 +133,214 @@ impl Author {
 
 #[derive(Debug, Clone)]
 pub struct Commit {
-    pub tree_sha: String,
-    pub parent: Option<String>,
+    pub tree_sha: ObjectId,
+    pub parents: Vec<ObjectId>,
     pub author: Author,
-    pub commiter: Author,
+    pub committer: Author,
+    /// Other headers (`encoding`, `mergetag`, `gpgsig`, ...) in original order.
+    /// Multi-line values are stored without the continuation indent.
+    pub extra_headers: Vec<(String, String)>,
+    /// Raw message including trailing newline
     pub message: String,
+    /// Object data the commit was parsed from, `None` for new commits. The fields above
+    /// may not represent it exactly (e.g. messages which are not valid UTF-8), so it is
+    /// written back as is while they are unchanged.
+    pub raw: Option<Vec<u8>>,
+}
+
+/// Encoding named by the `encoding` header of raw commit, `None` when the header is
+/// missing or the encoding unsupported
+fn header_encoding(data: &[u8]) -> Option<Encoding> {
+    let end = data
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .unwrap_or(data.len());
+    let label = data[..end]
+        .split(|byte| *byte == b'\n')
+        .find_map(|line| line.strip_prefix(b"encoding "))?;
+    Encoding::from_label(std::str::from_utf8(label).ok()?)
+}
+
+impl TryFrom<Object> for Commit {
+    type Error = Error;
+
+    fn try_from(object: Object) -> Result<Self> {
+        ensure!(
+            object.header.kind == ObjectKind::Commit,
+            "Invalid object kind"
+        );
+        Ok(Self {
+            raw: Some(object.data.clone()),
+            ..Self::parse(&object.data)?
+        })
+    }
 }
 
 impl Commit {
+    /// Parses commit object data, leaving `raw` empty
+    fn parse(raw: &[u8]) -> Result<Self> {
+        // Commits in other encodings are transcoded whole, including identities
+        let decoded = match header_encoding(raw) {
+            Some(encoding) if !encoding.is_utf8() => Some(encoding.decode(raw).into_bytes()),
+            _ => None,
+        };
+        let data = decoded.as_deref().unwrap_or(raw);
+        let (header, message) = match data.windows(2).position(|w| w == b"\n\n") {
+            Some(pos) => (&data[..=pos], &data[pos + 2..]),
+            None => (data, &[][..]),
+        };
+        // Headers of commits without encoding are UTF-8 by convention only
+        let header = String::from_utf8_lossy(header);
+
+        let mut tree_sha = None;
+        let mut parents = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+        let mut extra_headers: Vec<(String, String)> = Vec::new();
+        let header = header.strip_suffix('\n').unwrap_or(&header);
+        for line in header.split('\n') {
+            // Multi-line values continue on lines starting with space
+            if let Some(continuation) = line.strip_prefix(' ') {
+                let (_, value) = extra_headers
+                    .last_mut()
+                    .ok_or(format_err!("Unexpected continuation line {:?}", line))?;
+                value.push('\n');
+                value.push_str(continuation);
+                continue;
+            }
+            let (key, value) = line
+                .split_once(' ')
+                .ok_or(format_err!("Invalid commit header line {:?}", line))?;
+            match key {
+                "tree" => tree_sha = Some(value.parse()?),
+                "parent" => parents.push(value.parse()?),
+                "author" => author = Some(Author::parse(value)?),
+                "committer" => committer = Some(Author::parse(value)?),
+                _ => extra_headers.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        Ok(Self {
+            tree_sha: tree_sha.ok_or(format_err!("Commit doesn't contain tree reference"))?,
+            parents,
+            author: author.ok_or(format_err!("Commit is missing author"))?,
+            committer: committer.ok_or(format_err!("Commit is missing committer"))?,
+            extra_headers,
+            message: String::from_utf8_lossy(message).into_owned(),
+            raw: None,
+        })
+    }
+
+    /// New commit authored and committed now by the configured identity. Message is
+    /// written in `i18n.commitEncoding`, UTF-8 by default.
+    pub fn new(repo: &GitRepo, tree_sha: ObjectId, parents: Vec<ObjectId>, message: String) -> Result<Self> {
+        let mut extra_headers = Vec::new();
+        if let Some(label) = Config::load(repo)?.get("i18n.commitEncoding") {
+            let Some(encoding) = Encoding::from_label(label) else {
+                bail!("Unsupported commit encoding {}", label);
+            };
+            if !encoding.is_utf8() {
+                extra_headers.push((String::from("encoding"), label.to_string()));
+            }
+        }
+        Ok(Self {
+            tree_sha,
+            parents,
+            author: Author::from_env(repo, Role::Author)?,
+            committer: Author::from_env(repo, Role::Committer)?,
+            extra_headers,
+            message,
+            raw: None,
+        })
+    }
+
+    /// Writes the commit object and returns its id
+    pub fn write(self, repo: &GitRepo) -> Result<ObjectId> {
+        let obj = Object::new(repo.hash_algo(), ObjectKind::Commit, self.to_bytes());
+        obj.write(repo)?;
+        Ok(obj.hash)
+    }
+
+    /// Reads and parses commit object
+    pub fn read(repo: &GitRepo, id: &ObjectId) -> Result<Self> {
+        Object::read(repo, id)?.try_into()
+    }
+
+    /// Value of the first extra header with given key
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.extra_headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Encoding of the message when it is not UTF-8. The message itself is always
+    /// transcoded to UTF-8 when read.
+    pub fn encoding(&self) -> Option<&str> {
+        self.header("encoding")
+    }
+
+    /// Signature of the commit
+    pub fn gpgsig(&self) -> Option<&str> {
+        self.header("gpgsig")
+    }
+
+    /// Splits serialized commit into the data without the first header with given key
+    /// and raw value of that header, `None` when there is no such header. Used to get
+    /// signature of the commit and the payload it was made for.
+    pub fn split_header(&self, key: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+        let data = self.to_bytes();
+        let header_end = data.windows(2).position(|w| w == b"\n\n")? + 1;
+        let mut payload = Vec::with_capacity(data.len());
+        let mut value: Option<Vec<u8>> = None;
+        let mut in_header = false;
+        for line in data[..header_end].split_inclusive(|b| *b == b'\n') {
+            if let Some(continuation) = line.strip_prefix(b" ").filter(|_| in_header) {
+                value.as_mut()?.extend(continuation);
+                continue;
+            }
+            let is_key = line
+                .strip_prefix(key.as_bytes())
+                .is_some_and(|r| r.starts_with(b" "));
+            in_header = is_key && value.is_none();
+            if in_header {
+                value = Some(line[key.len() + 1..].to_vec());
+            } else {
+                payload.extend(line);
+            }
+        }
+        payload.extend(&data[header_end..]);
+        let mut value = value?;
+        value.pop();
+        Some((payload, value))
+    }
+
+    /// Whether the fields match those of the other commit, the raw data is not compared
+    fn same_fields(&self, other: &Commit) -> bool {
+        self.tree_sha == other.tree_sha
+            && self.parents == other.parents
+            && self.author == other.author
+            && self.committer == other.committer
+            && self.extra_headers == other.extra_headers
+            && self.message == other.message
+    }
+
+    /// Serialized commit, the data it was read from unless it was modified since
     pub fn to_bytes(&self) -> Vec<u8> {
+        if let Some(raw) = &self.raw {
+            if Self::parse(raw).is_ok_and(|parsed| parsed.same_fields(self)) {
+                return raw.clone();
+            }
+        }
         let mut data = Vec::new();
 
         data.extend(b"tree ");
-        data.extend(self.tree_sha.as_bytes());
+        data.extend(self.tree_sha.to_hex().as_bytes());
         data.push(b'\n');
 
-        if let Some(ref parent) = self.parent {
+        for parent in &self.parents {
             data.extend(b"parent ");
-            data.extend(parent.as_bytes());
+            data.extend(parent.to_hex().as_bytes());
             data.push(b'\n');
         }
 
@@ -54,15 +348,26 @@ impl Commit {
         self.author.write_to_buf(&mut data);
         data.push(b'\n');
 
-        data.extend(b"commiter ");
-        self.author.write_to_buf(&mut data);
+        data.extend(b"committer ");
+        self.committer.write_to_buf(&mut data);
         data.push(b'\n');
 
+        for (key, value) in &self.extra_headers {
+            data.extend(key.as_bytes());
+            data.push(b' ');
+            data.extend(value.replace('\n', "\n ").as_bytes());
+            data.push(b'\n');
+        }
+
         data.push(b'\n');
 
         data.extend(self.message.as_bytes());
-        data.push(b'\n');
 
-        data
+        match self.encoding().and_then(Encoding::from_label) {
+            Some(encoding) if !encoding.is_utf8() => {
+                encoding.encode(&String::from_utf8_lossy(&data))
+            }
+            _ => data,
+        }
     }
 }
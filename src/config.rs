@@ -0,0 +1,823 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error::{bail, ensure, format_err, Context, Error, Result};
+use crate::GitRepo;
+
+/// Maximal depth of nested includes, protects against include cycles
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConfigScope {
+    System,
+    Global,
+    Local,
+}
+
+impl ConfigScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfigScope::System => "system",
+            ConfigScope::Global => "global",
+            ConfigScope::Local => "local",
+        }
+    }
+}
+
+/// Parsed config key `section[.subsection].name`.
+/// Section and name are case-insensitive and stored lowercase, subsection is case-sensitive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigKey {
+    pub section: String,
+    pub subsection: Option<String>,
+    pub name: String,
+}
+
+impl ConfigKey {
+    pub fn parse(key: &str) -> Result<Self> {
+        let (section, rest) = key
+            .split_once('.')
+            .ok_or(format_err!("Key {:?} does not contain a section", key))?;
+        let (subsection, name) = match rest.rsplit_once('.') {
+            Some((subsection, name)) => (Some(subsection.to_string()), name),
+            None => (None, rest),
+        };
+        ensure!(
+            !section.is_empty() && !name.is_empty(),
+            "Invalid config key {:?}",
+            key
+        );
+        ensure!(
+            name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'),
+            "Invalid config key {:?}",
+            key
+        );
+        Ok(Self {
+            section: section.to_ascii_lowercase(),
+            subsection,
+            name: name.to_ascii_lowercase(),
+        })
+    }
+
+    fn same_section(&self, section: &str, subsection: Option<&str>) -> bool {
+        self.section == section && self.subsection.as_deref() == subsection
+    }
+}
+
+impl std::fmt::Display for ConfigKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.subsection {
+            Some(sub) => write!(f, "{}.{}.{}", self.section, sub, self.name),
+            None => write!(f, "{}.{}", self.section, self.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigEntry {
+    pub key: ConfigKey,
+    /// `None` for keys without `=`, which are treated as boolean true
+    pub value: Option<String>,
+    pub scope: ConfigScope,
+    pub origin: PathBuf,
+}
+
+/// Section header of config file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Section {
+    name: String,
+    subsection: Option<String>,
+}
+
+/// Parses section header line `[section "subsection"]`, returning header and rest of line
+fn parse_section(line: &str) -> Result<(Section, &str)> {
+    let inner = line
+        .strip_prefix('[')
+        .ok_or(format_err!("Invalid section header {:?}", line))?;
+    let end = inner
+        .rfind(']')
+        .ok_or(format_err!("Invalid section header {:?}", line))?;
+    let (inner, rest) = (&inner[..end], &inner[end + 1..]);
+    let section = match inner.split_once(|c: char| c.is_whitespace()) {
+        Some((name, sub)) => {
+            let sub = sub.trim();
+            let sub = sub
+                .strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+                .ok_or(format_err!("Invalid subsection in {:?}", line))?;
+            let mut subsection = String::new();
+            let mut chars = sub.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => subsection.extend(chars.next()),
+                    c => subsection.push(c),
+                }
+            }
+            Section {
+                name: name.to_ascii_lowercase(),
+                subsection: Some(subsection),
+            }
+        }
+        // Deprecated `[section.subsection]` syntax
+        None => match inner.split_once('.') {
+            Some((name, sub)) => Section {
+                name: name.to_ascii_lowercase(),
+                subsection: Some(sub.to_ascii_lowercase()),
+            },
+            None => Section {
+                name: inner.to_ascii_lowercase(),
+                subsection: None,
+            },
+        },
+    };
+    ensure!(
+        !section.name.is_empty()
+            && section
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.'),
+        "Invalid section name in {:?}",
+        line
+    );
+    Ok((section, rest))
+}
+
+/// Parses value part of the line, handling quotes, escapes and comments.
+/// Returns `false` when the value continues on the next line.
+fn parse_value(raw: &str, value: &mut String) -> Result<bool> {
+    let mut in_quotes = false;
+    let mut chars = raw.trim_start().chars().peekable();
+    // Whitespace is kept only when followed by more value characters
+    let mut pending_ws = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                value.push_str(&pending_ws);
+                pending_ws.clear();
+                in_quotes = !in_quotes;
+            }
+            '\\' => {
+                value.push_str(&pending_ws);
+                pending_ws.clear();
+                match chars.next() {
+                    None => return Ok(false),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('b') => {
+                        value.pop();
+                    }
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some(c) => bail!("Invalid escape sequence \\{}", c),
+                }
+            }
+            '#' | ';' if !in_quotes => break,
+            c if c.is_whitespace() && !in_quotes => pending_ws.push(c),
+            c => {
+                value.push_str(&pending_ws);
+                pending_ws.clear();
+                value.push(c);
+            }
+        }
+    }
+    ensure!(!in_quotes, "Unterminated quoted value");
+    Ok(true)
+}
+
+/// Raw entry parsed from single config file, before includes are resolved
+struct RawEntry {
+    key: ConfigKey,
+    value: Option<String>,
+    /// Index of the first and last line of the entry
+    lines: (usize, usize),
+}
+
+struct ParsedContent {
+    entries: Vec<RawEntry>,
+    /// Section headers with their line index
+    sections: Vec<(Section, usize)>,
+    /// Indexes of lines with comments, on their own or after section header
+    comments: Vec<usize>,
+}
+
+/// Part of config file with its own meaning, blank lines are not any
+enum Event<'a> {
+    Section(&'a Section),
+    Entry(&'a RawEntry),
+    Comment,
+}
+
+impl ParsedContent {
+    /// Sections, entries and comments with their first and last line, in file order
+    fn events(&self) -> Vec<(usize, usize, Event<'_>)> {
+        let mut events: Vec<_> = self
+            .sections
+            .iter()
+            .map(|(section, line)| (*line, *line, Event::Section(section)))
+            .chain(
+                self.entries
+                    .iter()
+                    .map(|e| (e.lines.0, e.lines.1, Event::Entry(e))),
+            )
+            .chain(
+                self.comments
+                    .iter()
+                    .map(|line| (*line, *line, Event::Comment)),
+            )
+            .collect();
+        // Section header comes first on its line
+        events.sort_by_key(|(line, _, event)| (*line, !matches!(event, Event::Section(_))));
+        events
+    }
+}
+
+/// Parses config file content keeping track of line positions
+fn parse_content(content: &str) -> Result<ParsedContent> {
+    let mut entries = Vec::new();
+    let mut sections = Vec::new();
+    let mut comments = Vec::new();
+    let mut section: Option<Section> = None;
+    let lines: Vec<&str> = content.lines().collect();
+    let mut i = 0;
+    while i < lines.len() {
+        let start = i;
+        let mut line = lines[i].trim();
+        i += 1;
+        if line.starts_with('[') {
+            let (s, rest) = parse_section(line).with_context(|| format!("line {}", start + 1))?;
+            sections.push((s.clone(), start));
+            section = Some(s);
+            line = rest.trim();
+        }
+        if line.starts_with('#') || line.starts_with(';') {
+            comments.push(start);
+        }
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        let current = section
+            .as_ref()
+            .ok_or(format_err!("Key outside of section at line {}", start + 1))?;
+        let (name, raw_value) = match line.find('=') {
+            Some(pos) => (line[..pos].trim(), Some(&line[pos + 1..])),
+            None => (
+                line.split(['#', ';']).next().unwrap_or_default().trim(),
+                None,
+            ),
+        };
+        ensure!(
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'),
+            "Invalid key {:?} at line {}",
+            name,
+            start + 1
+        );
+        let value = match raw_value {
+            Some(raw) => {
+                let mut value = String::new();
+                let mut raw = raw;
+                while !parse_value(raw, &mut value).with_context(|| format!("line {}", i))? {
+                    ensure!(
+                        i < lines.len(),
+                        "Unexpected end of file after line continuation"
+                    );
+                    raw = lines[i];
+                    i += 1;
+                }
+                Some(value)
+            }
+            None => None,
+        };
+        entries.push(RawEntry {
+            key: ConfigKey {
+                section: current.name.clone(),
+                subsection: current.subsection.clone(),
+                name: name.to_ascii_lowercase(),
+            },
+            value,
+            lines: (start, i - 1),
+        });
+    }
+    Ok(ParsedContent {
+        entries,
+        sections,
+        comments,
+    })
+}
+
+pub(crate) fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => home_dir().map_or_else(|| PathBuf::from(path), |home| home.join(rest)),
+        None => PathBuf::from(path),
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(PathBuf::from)
+}
+
+/// Evaluates `includeIf` condition. Only `gitdir:` and `gitdir/i:` are supported.
+fn include_condition(condition: &str, git_dir: Option<&Path>, origin: &Path) -> bool {
+    let (pattern, case_insensitive) = if let Some(p) = condition.strip_prefix("gitdir:") {
+        (p, false)
+    } else if let Some(p) = condition.strip_prefix("gitdir/i:") {
+        (p, true)
+    } else {
+        return false;
+    };
+    let Some(git_dir) = git_dir else {
+        return false;
+    };
+    let mut pattern = if let Some(rest) = pattern.strip_prefix("./") {
+        origin
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(rest)
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        expand_home(pattern).to_string_lossy().into_owned()
+    };
+    let mut git_dir = git_dir.to_string_lossy().into_owned();
+    if !git_dir.ends_with('/') {
+        git_dir.push('/');
+    }
+    if case_insensitive {
+        pattern = pattern.to_lowercase();
+        git_dir = git_dir.to_lowercase();
+    }
+    // Trailing slash means everything inside, `**` glob in git
+    let pattern = pattern.trim_end_matches("**");
+    if pattern.ends_with('/') {
+        git_dir.starts_with(pattern)
+    } else {
+        git_dir.trim_end_matches('/') == pattern
+    }
+}
+
+/// Layered configuration (system → global → local), later values take precedence
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    entries: Vec<ConfigEntry>,
+}
+
+impl Config {
+    pub fn system_path() -> Option<PathBuf> {
+        if std::env::var_os("GIT_CONFIG_NOSYSTEM").is_some() {
+            return None;
+        }
+        Some(
+            std::env::var_os("GIT_CONFIG_SYSTEM")
+                .map_or_else(|| PathBuf::from("/etc/gitconfig"), PathBuf::from),
+        )
+    }
+
+    /// Paths of global config files. The last one is used for writing.
+    pub fn global_paths() -> Vec<PathBuf> {
+        if let Some(path) = std::env::var_os("GIT_CONFIG_GLOBAL") {
+            return vec![PathBuf::from(path)];
+        }
+        let mut paths = Vec::new();
+        let xdg = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home_dir().map(|h| h.join(".config")));
+        if let Some(xdg) = xdg {
+            paths.push(xdg.join("git").join("config"));
+        }
+        if let Some(home) = home_dir() {
+            paths.push(home.join(".gitconfig"));
+        }
+        paths
+    }
+
+    /// Global config file used for writing: `~/.gitconfig` unless only the XDG one exists
+    pub fn global_write_path() -> Result<PathBuf> {
+        let paths = Self::global_paths();
+        paths
+            .iter()
+            .rev()
+            .find(|p| p.is_file())
+            .or(paths.last())
+            .cloned()
+            .ok_or(format_err!("Cannot locate global config, HOME is not set"))
+    }
+
+    pub fn local_path(repo: &GitRepo) -> PathBuf {
+        repo.common_dir.join("config")
+    }
+
+    /// Loads all config layers applicable to the repository
+    pub fn load(repo: &GitRepo) -> Result<Self> {
+        let mut config = Self::load_global()?;
+        config.read_file(
+            &Self::local_path(repo),
+            ConfigScope::Local,
+            Some(&repo.git_dir),
+            0,
+        )?;
+        Ok(config)
+    }
+
+    /// Loads system and global config only, for use outside of repository
+    pub fn load_global() -> Result<Self> {
+        let mut config = Self::default();
+        if let Some(path) = Self::system_path() {
+            config.read_file(&path, ConfigScope::System, None, 0)?;
+        }
+        for path in Self::global_paths() {
+            config.read_file(&path, ConfigScope::Global, None, 0)?;
+        }
+        Ok(config)
+    }
+
+    /// Loads single config file (with its includes)
+    pub fn load_file(path: &Path, scope: ConfigScope) -> Result<Self> {
+        let mut config = Self::default();
+        config.read_file(path, scope, None, 0)?;
+        Ok(config)
+    }
+
+    fn read_file(
+        &mut self,
+        path: &Path,
+        scope: ConfigScope,
+        git_dir: Option<&Path>,
+        depth: usize,
+    ) -> Result<()> {
+        ensure!(
+            depth <= MAX_INCLUDE_DEPTH,
+            "Config include depth exceeded at {}",
+            path.display()
+        );
+        if !path.is_file() {
+            return Ok(());
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Reading config {}", path.display()))?;
+        let parsed = parse_content(&content)
+            .with_context(|| format!("Parsing config {}", path.display()))?;
+        for entry in parsed.entries {
+            let include = match (entry.key.section.as_str(), &entry.key.subsection) {
+                ("include", None) => true,
+                ("includeif", Some(condition)) => include_condition(condition, git_dir, path),
+                _ => false,
+            };
+            let value = entry.value.clone();
+            self.entries.push(ConfigEntry {
+                key: entry.key,
+                value: entry.value,
+                scope,
+                origin: path.to_path_buf(),
+            });
+            if include && self.entries.last().unwrap().key.name == "path" {
+                let Some(value) = value else {
+                    continue;
+                };
+                let include_path = expand_home(&value);
+                let include_path = if include_path.is_relative() {
+                    path.parent().unwrap_or(Path::new("")).join(include_path)
+                } else {
+                    include_path
+                };
+                self.read_file(&include_path, scope, git_dir, depth + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[ConfigEntry] {
+        &self.entries
+    }
+
+    fn matching<'a>(&'a self, key: &str) -> Result<impl Iterator<Item = &'a ConfigEntry>> {
+        let key = ConfigKey::parse(key)?;
+        Ok(self.entries.iter().filter(move |e| e.key == key))
+    }
+
+    /// Last entry for the key. Value is `None` for the implicit boolean syntax.
+    pub fn get_entry(&self, key: &str) -> Option<&ConfigEntry> {
+        self.matching(key).ok()?.last()
+    }
+
+    /// Last value of the key, keys without value yield empty string
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.get_entry(key)
+            .map(|e| e.value.as_deref().unwrap_or(""))
+    }
+
+    /// All values of multi-valued key in order of appearance
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        match self.matching(key) {
+            Ok(entries) => entries.map(|e| e.value.as_deref().unwrap_or("")).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>> {
+        self.get_entry(key)
+            .map(|e| {
+                parse_bool(e.value.as_deref()).with_context(|| format!("Invalid bool for {}", key))
+            })
+            .transpose()
+    }
+
+    pub fn get_int(&self, key: &str) -> Result<Option<i64>> {
+        self.get(key)
+            .map(|v| parse_int(v).with_context(|| format!("Invalid number for {}", key)))
+            .transpose()
+    }
+
+    pub fn get_path(&self, key: &str) -> Option<PathBuf> {
+        self.get(key).map(expand_home)
+    }
+}
+
+/// Type used to interpret and canonicalize config values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    Bool,
+    Int,
+    Path,
+}
+
+impl FromStr for ValueType {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Ok(match value {
+            "bool" => Self::Bool,
+            "int" => Self::Int,
+            "path" => Self::Path,
+            _ => bail!("Unrecognized config value type {:?}", value),
+        })
+    }
+}
+
+impl ValueType {
+    /// Validates value and converts it to canonical form
+    pub fn canonicalize(&self, value: Option<&str>) -> Result<String> {
+        Ok(match self {
+            ValueType::Bool => parse_bool(value)?.to_string(),
+            ValueType::Int => parse_int(value.unwrap_or_default())?.to_string(),
+            ValueType::Path => expand_home(value.unwrap_or_default())
+                .to_string_lossy()
+                .into_owned(),
+        })
+    }
+}
+
+/// Parses git boolean value, `None` stands for key without `=`
+pub fn parse_bool(value: Option<&str>) -> Result<bool> {
+    let Some(value) = value else {
+        return Ok(true);
+    };
+    Ok(match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" => true,
+        "false" | "no" | "off" | "" => false,
+        other => parse_int(other)? != 0,
+    })
+}
+
+/// Parses integer with optional `k`, `m` or `g` suffix
+pub fn parse_int(value: &str) -> Result<i64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&value[..value.len() - 1], 1024),
+        Some('m') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('g') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    let number: i64 = number
+        .parse()
+        .with_context(|| format!("Invalid number {:?}", value))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or(format_err!("Number {:?} out of range", value))
+}
+
+fn escape_value(value: &str) -> String {
+    let needs_quotes = value.starts_with(char::is_whitespace)
+        || value.ends_with(char::is_whitespace)
+        || value.contains(['#', ';']);
+    let mut escaped = String::with_capacity(value.len() + 2);
+    if needs_quotes {
+        escaped.push('"');
+    }
+    for c in value.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c => escaped.push(c),
+        }
+    }
+    if needs_quotes {
+        escaped.push('"');
+    }
+    escaped
+}
+
+fn section_header(key: &ConfigKey) -> String {
+    match &key.subsection {
+        Some(sub) => format!(
+            "[{} \"{}\"]",
+            key.section,
+            sub.replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+        None => format!("[{}]", key.section),
+    }
+}
+
+/// Lines of the section left empty by removing values of the key, starting with the one
+/// at `events[i]`, as half-open range. Blank lines up to the surrounding entries and
+/// other headers of the same section go too. `None` when the section keeps other
+/// entries or there are comments, which may be about the section.
+fn empty_section(
+    events: &[(usize, usize, Event)],
+    i: usize,
+    key: &ConfigKey,
+    line_count: usize,
+) -> Option<(usize, usize)> {
+    let in_section =
+        |section: &Section| key.same_section(&section.name, section.subsection.as_deref());
+    let mut begin = 0;
+    let mut section_seen = false;
+    for (_, end, event) in events[..i].iter().rev() {
+        match event {
+            Event::Comment => return None,
+            Event::Entry(_) if !section_seen => return None,
+            Event::Section(section) if in_section(section) => {
+                section_seen = true;
+                continue;
+            }
+            _ => {}
+        }
+        begin = end + 1;
+        break;
+    }
+    let mut end = line_count;
+    for (start, _, event) in &events[i + 1..] {
+        match event {
+            Event::Comment => return None,
+            Event::Section(section) if in_section(section) => continue,
+            Event::Entry(entry) if entry.key == *key => continue,
+            Event::Entry(_) => return None,
+            Event::Section(_) => {}
+        }
+        end = *start;
+        break;
+    }
+    Some((begin, end))
+}
+
+/// Editable single config file preserving formatting of untouched lines
+pub struct ConfigFile {
+    path: PathBuf,
+    lines: Vec<String>,
+}
+
+impl ConfigFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let lines = if path.is_file() {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!("Reading config {}", path.display()))?;
+            // Validate before editing so we don't make broken file even worse
+            parse_content(&content)
+                .with_context(|| format!("Parsing config {}", path.display()))?;
+            content.lines().map(String::from).collect()
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            lines,
+        })
+    }
+
+    fn parsed(&self) -> Result<ParsedContent> {
+        parse_content(&self.lines.join("\n"))
+    }
+
+    /// Appends new value for the key, keeping existing ones
+    pub fn add(&mut self, key: &str, value: &str) -> Result<()> {
+        let key = ConfigKey::parse(key)?;
+        let parsed = self.parsed()?;
+        let line = format!("\t{} = {}", key.name, escape_value(value));
+        // Insert after last entry of the section, or right after its header
+        let pos = parsed
+            .entries
+            .iter()
+            .filter(|e| e.key.same_section(&key.section, key.subsection.as_deref()))
+            .map(|e| e.lines.1 + 1)
+            .next_back()
+            .or_else(|| {
+                parsed
+                    .sections
+                    .iter()
+                    .filter(|(s, _)| key.same_section(&s.name, s.subsection.as_deref()))
+                    .map(|(_, line)| line + 1)
+                    .next_back()
+            });
+        match pos {
+            Some(pos) => self.lines.insert(pos, line),
+            None => {
+                self.lines.push(section_header(&key));
+                self.lines.push(line);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the key to single value. Fails when key has multiple values.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let parsed_key = ConfigKey::parse(key)?;
+        let parsed = self.parsed()?;
+        let existing: Vec<&RawEntry> = parsed
+            .entries
+            .iter()
+            .filter(|e| e.key == parsed_key)
+            .collect();
+        match existing.as_slice() {
+            [] => self.add(key, value),
+            [entry] => {
+                let line = format!("\t{} = {}", parsed_key.name, escape_value(value));
+                self.lines.splice(entry.lines.0..=entry.lines.1, [line]);
+                Ok(())
+            }
+            _ => bail!("Cannot overwrite multiple values of {}", key),
+        }
+    }
+
+    /// Removes all values of the key, returning number of removed entries. Section left
+    /// empty is removed as well, unless there are comments around it.
+    pub fn unset(&mut self, key: &str) -> Result<usize> {
+        let key = ConfigKey::parse(key)?;
+        let parsed = self.parsed()?;
+        let events = parsed.events();
+        // Half-open ranges of lines to remove, in file order
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        let mut removed = 0;
+        for (i, (start, end, event)) in events.iter().enumerate() {
+            if !matches!(event, Event::Entry(entry) if entry.key == key) {
+                continue;
+            }
+            removed += 1;
+            if ranges.last().is_some_and(|range| range.1 > *start) {
+                // Already removed with its section
+                continue;
+            }
+            if let Some(range) = empty_section(&events, i, &key, self.lines.len()) {
+                ranges.push(range);
+            } else if self.lines[*start].trim_start().starts_with('[') {
+                // Entry shares line with section header, keep the header
+                let (section, _) = parse_section(self.lines[*start].trim())?;
+                self.lines[*start] = section_header(&ConfigKey {
+                    section: section.name,
+                    subsection: section.subsection,
+                    name: String::new(),
+                });
+                ranges.push((start + 1, end + 1));
+            } else {
+                ranges.push((*start, end + 1));
+            }
+        }
+        for (start, end) in ranges.into_iter().rev() {
+            self.lines.drain(start..end);
+        }
+        Ok(removed)
+    }
+
+    /// Removes all occurrences of the section with their entries, returning number of removed sections
+    pub fn remove_section(&mut self, section: &str, subsection: Option<&str>) -> Result<usize> {
+        let section = section.to_ascii_lowercase();
+        let parsed = self.parsed()?;
+        let mut removed = 0;
+        for (i, (s, start)) in parsed.sections.iter().enumerate().rev() {
+            if s.name != section || s.subsection.as_deref() != subsection {
+                continue;
+            }
+            let end = parsed
+                .sections
+                .get(i + 1)
+                .map_or(self.lines.len(), |(_, line)| *line);
+            self.lines.drain(*start..end);
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).context("Creating config dir")?;
+        }
+        let mut content = self.lines.join("\n");
+        if !self.lines.is_empty() {
+            content.push('\n');
+        }
+        let tmp = self.path.with_extension("lock");
+        fs::write(&tmp, content).context("Writing config")?;
+        fs::rename(&tmp, &self.path).context("Writing config")?;
+        Ok(())
+    }
+}
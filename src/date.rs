@@ -0,0 +1,422 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::error::{bail, ensure, format_err, Context, Result};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// Number of days since 1970-01-01 of given proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Date of given number of days since 1970-01-01 as (year, month, day)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Local date of the time as (weekday, month, day, year, seconds since midnight)
+fn local_date(time: i64, offset: i32) -> (&'static str, String, u32, i64, i64) {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    let local = time + offset as i64 * 60;
+    let days = local.div_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let month = MONTHS[month as usize - 1];
+    let month = format!("{}{}", month[..1].to_ascii_uppercase(), &month[1..]);
+    (
+        DAYS[days.rem_euclid(7) as usize],
+        month,
+        day,
+        year,
+        local.rem_euclid(86400),
+    )
+}
+
+/// Formats time in git default format, e.g. `Thu Apr 7 22:13:13 2005 +0200`
+pub fn format_default(time: i64, offset: i32) -> String {
+    let (weekday, month, day, year, seconds) = local_date(time, offset);
+    format!(
+        "{} {} {} {:02}:{:02}:{:02} {} {}",
+        weekday,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        year,
+        format_offset(offset)
+    )
+}
+
+/// Formats time like `2005-04-07 22:13:13 +0200`
+pub fn format_iso(time: i64, offset: i32) -> String {
+    let local = time + offset as i64 * 60;
+    let (year, month, day) = civil_from_days(local.div_euclid(86400));
+    let seconds = local.rem_euclid(86400);
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02} {}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        format_offset(offset)
+    )
+}
+
+/// Formats time for email headers, e.g. `Thu, 7 Apr 2005 22:13:13 +0200`
+pub fn format_rfc2822(time: i64, offset: i32) -> String {
+    let (weekday, month, day, year, seconds) = local_date(time, offset);
+    format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} {}",
+        weekday,
+        day,
+        month,
+        year,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        format_offset(offset)
+    )
+}
+
+/// Formats offset in minutes as `+hhmm`
+pub fn format_offset(minutes: i32) -> String {
+    let sign = if minutes < 0 { '-' } else { '+' };
+    let minutes = minutes.abs();
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+/// Parses `+hhmm`, `+hh:mm` or `+hh` offset into minutes
+pub fn parse_offset(offset: &str) -> Result<i32> {
+    let (sign, digits) = match offset.as_bytes().first() {
+        Some(b'+') => (1, &offset[1..]),
+        Some(b'-') => (-1, &offset[1..]),
+        _ => bail!("Invalid timezone offset {:?}", offset),
+    };
+    let digits = digits.replace(':', "");
+    ensure!(
+        matches!(digits.len(), 2 | 4) && digits.bytes().all(|b| b.is_ascii_digit()),
+        "Invalid timezone offset {:?}",
+        offset
+    );
+    let hours: i32 = digits[..2].parse()?;
+    let minutes: i32 = digits
+        .get(2..)
+        .filter(|m| !m.is_empty())
+        .map_or(Ok(0), str::parse)?;
+    ensure!(
+        hours <= 14 && minutes < 60,
+        "Invalid timezone offset {:?}",
+        offset
+    );
+    Ok(sign * (hours * 60 + minutes))
+}
+
+/// Reads UTC offset (in seconds) valid at `time` from TZif file
+fn tzif_offset(data: &[u8], time: i64) -> Result<i32> {
+    let header = |data: &[u8]| -> Result<[usize; 6]> {
+        ensure!(
+            data.len() >= 44 && &data[..4] == b"TZif",
+            "Invalid TZif file"
+        );
+        let mut counts = [0usize; 6];
+        for (i, chunk) in data[20..44].chunks_exact(4).enumerate() {
+            counts[i] = u32::from_be_bytes(chunk.try_into()?) as usize;
+        }
+        Ok(counts)
+    };
+    let [isut, isstd, leap, mut times, mut types, chars] = header(data)?;
+    let mut time_size = 4;
+    let mut data = data;
+    // Version 2+ files carry 64-bit times after the legacy block
+    if data[4] >= b'2' {
+        let v1_len = 44 + times * 5 + types * 6 + chars + leap * 8 + isstd + isut;
+        data = data
+            .get(v1_len..)
+            .ok_or(format_err!("Truncated TZif file"))?;
+        [_, _, _, times, types, _] = header(data)?;
+        time_size = 8;
+    }
+    let body = &data[44..];
+    ensure!(
+        body.len() >= times * (time_size + 1) + types * 6 && types > 0,
+        "Truncated TZif file"
+    );
+    let transition = |i: usize| -> i64 {
+        let raw = &body[i * time_size..(i + 1) * time_size];
+        match time_size {
+            4 => i32::from_be_bytes(raw.try_into().unwrap()) as i64,
+            _ => i64::from_be_bytes(raw.try_into().unwrap()),
+        }
+    };
+    let indexes = &body[times * time_size..times * (time_size + 1)];
+    let ttinfo = &body[times * (time_size + 1)..];
+    let utoff = |t: usize| i32::from_be_bytes(ttinfo[t * 6..t * 6 + 4].try_into().unwrap());
+
+    // Times past the last transition keep its type, which is good enough for current dates
+    match (0..times).rev().find(|i| transition(*i) <= time) {
+        Some(i) => {
+            let t = indexes[i] as usize;
+            ensure!(t < types, "Invalid TZif transition type");
+            Ok(utoff(t))
+        }
+        None => Ok(utoff(0)),
+    }
+}
+
+/// Offset of POSIX `TZ` like `CET-1` (sign is inverted), ignoring DST rules
+fn posix_offset(tz: &str) -> Option<i32> {
+    let rest = tz.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '+' | '-' | ':')))
+        .unwrap_or(rest.len());
+    let spec = &rest[..end];
+    if spec.is_empty() {
+        return (rest.len() < tz.len()).then_some(0);
+    }
+    let spec = if spec.starts_with(['+', '-']) {
+        spec.to_string()
+    } else {
+        format!("+{}", spec)
+    };
+    let (hours, minutes) = spec[1..].split_once(':').unwrap_or((&spec[1..], "0"));
+    let minutes = hours.parse::<i32>().ok()? * 60 + minutes.parse::<i32>().ok()?;
+    Some(
+        if spec.starts_with('-') {
+            minutes
+        } else {
+            -minutes
+        } * 60,
+    )
+}
+
+fn zoneinfo_path(name: &str) -> PathBuf {
+    let name = name.strip_prefix(':').unwrap_or(name);
+    if Path::new(name).is_absolute() {
+        PathBuf::from(name)
+    } else {
+        Path::new("/usr/share/zoneinfo").join(name)
+    }
+}
+
+/// Local UTC offset in minutes at given unix time, determined from `TZ` or `/etc/localtime`
+pub fn local_offset(time: i64) -> i32 {
+    let seconds = match std::env::var("TZ") {
+        Ok(tz) if tz.is_empty() => Some(0),
+        Ok(tz) => fs::read(zoneinfo_path(&tz))
+            .ok()
+            .and_then(|data| tzif_offset(&data, time).ok())
+            .or_else(|| posix_offset(&tz)),
+        Err(_) => fs::read("/etc/localtime")
+            .ok()
+            .and_then(|data| tzif_offset(&data, time).ok()),
+    };
+    seconds.unwrap_or(0) / 60
+}
+
+fn parse_time_of_day(time: &str) -> Result<i64> {
+    let time = time.split(['.', ',']).next().unwrap_or(time);
+    let parts: Vec<&str> = time.split(':').collect();
+    ensure!(matches!(parts.len(), 2 | 3), "Invalid time {:?}", time);
+    let mut seconds = 0;
+    for (i, part) in parts.iter().enumerate() {
+        let value: i64 = part
+            .parse()
+            .with_context(|| format!("Invalid time {:?}", time))?;
+        ensure!(
+            value < if i == 0 { 24 } else { 60 },
+            "Invalid time {:?}",
+            time
+        );
+        seconds = seconds * 60 + value;
+    }
+    if parts.len() == 2 {
+        seconds *= 60;
+    }
+    Ok(seconds)
+}
+
+fn parse_ymd(year: &str, month: u32, day: &str) -> Result<i64> {
+    let year: i64 = year.parse().context("Invalid year")?;
+    let day: u32 = day.parse().context("Invalid day")?;
+    ensure!(
+        (1..=12).contains(&month) && (1..=31).contains(&day),
+        "Invalid date"
+    );
+    Ok(days_from_civil(year, month, day))
+}
+
+/// Converts local date and time to unix time, using the zone when given
+fn assemble(days: i64, seconds: i64, offset: Option<i32>) -> (i64, i32) {
+    let local = days * 86400 + seconds;
+    let offset = offset.unwrap_or_else(|| {
+        let guess = local_offset(local);
+        local_offset(local - guess as i64 * 60)
+    });
+    (local - offset as i64 * 60, offset)
+}
+
+/// Splits trailing zone designator (`Z`, `+hh:mm`, `+hhmm`) from ISO 8601 time
+fn split_zone(time: &str) -> Result<(&str, Option<i32>)> {
+    if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        return Ok((time, Some(0)));
+    }
+    match time.rfind(['+', '-']) {
+        Some(pos) => Ok((&time[..pos], Some(parse_offset(&time[pos..])?))),
+        None => Ok((time, None)),
+    }
+}
+
+/// Parses date in formats accepted by `GIT_AUTHOR_DATE`: internal `<unix> <offset>`,
+/// RFC 2822 and ISO 8601. Returns unix time and offset in minutes.
+pub fn parse_date(date: &str) -> Result<(i64, i32)> {
+    let date = date.trim();
+    let parts: Vec<&str> = date.split_whitespace().collect();
+
+    // Internal format, optionally prefixed with `@`
+    let unix = |s: &str| s.strip_prefix('@').unwrap_or(s).parse::<i64>().ok();
+    match parts.as_slice() {
+        [time] if time.starts_with('@') => {
+            if let Some(time) = unix(time) {
+                return Ok((time, local_offset(time)));
+            }
+        }
+        [time, offset] if offset.starts_with(['+', '-']) => {
+            if let Some(time) = unix(time) {
+                return Ok((time, parse_offset(offset)?));
+            }
+        }
+        _ => {}
+    }
+
+    // RFC 2822: `[Thu, ]07 Apr 2005 22:13:13 +0200`
+    let rfc = match parts.first() {
+        Some(day) if day.ends_with(',') => &parts[1..],
+        _ => &parts[..],
+    };
+    if let [day, month, year, time, rest @ ..] = rfc {
+        if let Some(month) = MONTHS
+            .iter()
+            .position(|m| month.get(..3).is_some_and(|p| p.eq_ignore_ascii_case(m)))
+        {
+            let days = parse_ymd(year, month as u32 + 1, day)?;
+            let seconds = parse_time_of_day(time)?;
+            let offset = match rest {
+                [] => None,
+                [offset] => Some(parse_offset(offset)?),
+                _ => bail!("Invalid date {:?}", date),
+            };
+            return Ok(assemble(days, seconds, offset));
+        }
+    }
+
+    // ISO 8601: `2005-04-07T22:13:13+02:00` or `2005-04-07 22:13:13 +0200`
+    let (day, time, offset) = match parts.as_slice() {
+        [datetime] => match datetime.split_once(['T', 't']) {
+            Some((day, time)) => {
+                let (time, offset) = split_zone(time)?;
+                (day, Some(time), offset)
+            }
+            None => (*datetime, None, None),
+        },
+        [day, time] => {
+            let (time, offset) = split_zone(time)?;
+            (*day, Some(time), offset)
+        }
+        [day, time, offset] => (*day, Some(*time), Some(parse_offset(offset)?)),
+        _ => bail!("Invalid date {:?}", date),
+    };
+    let ymd: Vec<&str> = day.split(['-', '.', '/']).collect();
+    let [year, month, day_of_month] = ymd.as_slice() else {
+        bail!("Invalid date {:?}", date);
+    };
+    let month: u32 = month
+        .parse()
+        .with_context(|| format!("Invalid date {:?}", date))?;
+    let days =
+        parse_ymd(year, month, day_of_month).with_context(|| format!("Invalid date {:?}", date))?;
+    let seconds = time.map(parse_time_of_day).transpose()?.unwrap_or(0);
+    Ok(assemble(days, seconds, offset))
+}
+
+/// Parses expiry dates of `gc` and `prune`: `never`, `now` and dates accepted by
+/// `parse_relative`. Returns unix time before which objects expire, `None` for `never`.
+pub fn parse_expiry(expiry: &str, now: i64) -> Result<Option<i64>> {
+    match expiry.trim() {
+        "never" | "false" => Ok(None),
+        "all" => Ok(Some(now)),
+        expiry => Ok(Some(parse_relative(expiry, now)?)),
+    }
+}
+
+/// Parses `now`, relative dates like `2.weeks.ago` or `3 days ago` and dates accepted
+/// by `parse_date`, returning unix time
+pub fn parse_relative(date: &str, now: i64) -> Result<i64> {
+    let date = date.trim();
+    if date == "now" {
+        return Ok(now);
+    }
+    let words: Vec<&str> = date.split(['.', ' ']).filter(|w| !w.is_empty()).collect();
+    if let [count, unit, "ago"] = words.as_slice() {
+        let count: i64 = count
+            .parse()
+            .with_context(|| format!("Invalid date {:?}", date))?;
+        let seconds = match unit.trim_end_matches('s') {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => 24 * 60 * 60,
+            "week" => 7 * 24 * 60 * 60,
+            "month" => 30 * 24 * 60 * 60,
+            "year" => 365 * 24 * 60 * 60,
+            _ => bail!("Invalid date {:?}", date),
+        };
+        return Ok(now - count * seconds);
+    }
+    Ok(parse_date(date)?.0)
+}
+
+/// Converts unix time to `SystemTime`
+pub fn system_time(time: i64) -> Result<SystemTime> {
+    let offset = Duration::from_secs(time.unsigned_abs());
+    let time = if time < 0 {
+        SystemTime::UNIX_EPOCH.checked_sub(offset)
+    } else {
+        SystemTime::UNIX_EPOCH.checked_add(offset)
+    };
+    time.ok_or(format_err!("Date out of range"))
+}
+
+/// Local time in MS-DOS format of zip archives as (time, date). The format cannot
+/// represent times before 1980, they are clamped.
+pub fn dos_time(time: i64) -> (u16, u16) {
+    let local = time + local_offset(time) as i64 * 60;
+    let local = local.max(days_from_civil(1980, 1, 1) * 86400);
+    let (year, month, day) = civil_from_days(local.div_euclid(86400));
+    let seconds = local.rem_euclid(86400);
+    let dos_time = ((seconds / 3600) << 11) | ((seconds / 60 % 60) << 5) | (seconds % 60 / 2);
+    let dos_date = ((year.min(2107) - 1980) << 9) | ((month as i64) << 5) | day as i64;
+    (dos_time as u16, dos_date as u16)
+}
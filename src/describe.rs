@@ -0,0 +1,273 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::commit::Commit;
+use crate::error::{bail, Result};
+use crate::index::Index;
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::refs;
+use crate::revwalk::peel_to_commit;
+use crate::tag::Tag;
+use crate::tree::Tree;
+use crate::GitRepo;
+
+/// Number of tags found on the walk from the described commit which are compared
+const MAX_CANDIDATES: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct DescribeOptions {
+    /// Consider also lightweight tags, not only annotated ones
+    pub tags: bool,
+    /// Always show the number of commits and abbreviated id, even at a tag
+    pub long: bool,
+    /// Minimal length of the abbreviated id
+    pub abbrev: usize,
+    /// Fall back to abbreviated id when no tag describes the commit
+    pub always: bool,
+}
+
+impl Default for DescribeOptions {
+    fn default() -> Self {
+        Self {
+            tags: false,
+            long: false,
+            abbrev: 7,
+            always: false,
+        }
+    }
+}
+
+/// Tag naming a commit. Annotated tags win over lightweight ones, newer annotated over
+/// older ones.
+struct Name {
+    tag: String,
+    annotated: bool,
+    date: i64,
+}
+
+impl Name {
+    fn is_better_than(&self, other: &Name) -> bool {
+        match (self.annotated, other.annotated) {
+            (true, true) => self.date > other.date,
+            (annotated, other) => annotated && !other,
+        }
+    }
+}
+
+/// Tag found on the walk, with number of walked commits it does not reach
+struct Candidate<'n> {
+    name: &'n Name,
+    depth: usize,
+    /// Flag marking commits reachable from the tag
+    within: u32,
+}
+
+/// Flag of commits which were queued
+const SEEN: u32 = 1;
+
+/// Walk in commit date order propagating flags from commits to their parents, the way
+/// git counts the commits since tags, so that the counts match those of git
+struct DepthWalk<'r> {
+    repo: &'r GitRepo,
+    /// Commits to visit with their dates, newest first and in insertion order on ties
+    queue: VecDeque<(i64, ObjectId)>,
+    flags: HashMap<ObjectId, u32>,
+}
+
+impl<'r> DepthWalk<'r> {
+    fn new(repo: &'r GitRepo, id: &ObjectId) -> Result<Self> {
+        let mut walk = Self {
+            repo,
+            queue: VecDeque::new(),
+            flags: HashMap::from([(*id, SEEN)]),
+        };
+        walk.insert_by_date(*id)?;
+        Ok(walk)
+    }
+
+    fn flags(&self, id: &ObjectId) -> u32 {
+        self.flags.get(id).copied().unwrap_or(0)
+    }
+
+    fn pop(&mut self) -> Option<ObjectId> {
+        self.queue.pop_front().map(|(_, id)| id)
+    }
+
+    fn insert_by_date(&mut self, id: ObjectId) -> Result<()> {
+        let time = Commit::read(self.repo, &id)?.committer.unix_time();
+        let pos = self.queue.iter().position(|(t, _)| *t < time);
+        self.queue
+            .insert(pos.unwrap_or(self.queue.len()), (time, id));
+        Ok(())
+    }
+
+    fn visit_parents(&mut self, id: &ObjectId) -> Result<()> {
+        let flags = self.flags(id);
+        for parent in Commit::read(self.repo, id)?.parents {
+            if self.flags(&parent) & SEEN == 0 {
+                self.insert_by_date(parent)?;
+            }
+            *self.flags.entry(parent).or_default() |= flags;
+        }
+        Ok(())
+    }
+
+    /// Continues the walk counting commits not reachable from the best tag, until only
+    /// commits reachable from it remain
+    fn finish_depth(&mut self, within: u32) -> Result<usize> {
+        let mut depth = 0;
+        while let Some(commit) = self.pop() {
+            if self.flags(&commit) & within != 0 {
+                if self
+                    .queue
+                    .iter()
+                    .all(|(_, id)| self.flags(id) & within != 0)
+                {
+                    break;
+                }
+            } else {
+                depth += 1;
+            }
+            self.visit_parents(&commit)?;
+        }
+        Ok(depth)
+    }
+}
+
+/// Best names of tagged commits
+fn tag_names(repo: &GitRepo) -> Result<HashMap<ObjectId, Name>> {
+    let mut names: HashMap<ObjectId, Name> = HashMap::new();
+    for (name, id) in refs::list(repo)? {
+        let Some(tag) = name.strip_prefix("refs/tags/") else {
+            continue;
+        };
+        let obj = Object::read(repo, &id)?;
+        let (annotated, date) = match obj.header.kind {
+            ObjectKind::Tag => {
+                let tagger = Tag::try_from(obj)?.tagger;
+                (true, tagger.map_or(0, |tagger| tagger.unix_time()))
+            }
+            _ => (false, 0),
+        };
+        let Ok(commit) = peel_to_commit(repo, &id) else {
+            continue;
+        };
+        let name = Name {
+            tag: tag.to_string(),
+            annotated,
+            date,
+        };
+        match names.get(&commit) {
+            Some(current) if !name.is_better_than(current) => {}
+            _ => {
+                names.insert(commit, name);
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Names the commit after the nearest tag it descends from as `<tag>-<N>-g<id>`,
+/// where `N` is the number of commits since the tag. Commit at a tag is named just
+/// by the tag, unless `long` is set.
+pub fn describe(repo: &GitRepo, id: &ObjectId, options: &DescribeOptions) -> Result<String> {
+    let id = peel_to_commit(repo, id)?;
+    let names = tag_names(repo)?;
+    if names.is_empty() && !options.always {
+        bail!("No names found, cannot describe anything.");
+    }
+    let suffix = |tag: &str, depth: usize| -> Result<String> {
+        let abbrev = Object::abbreviate(repo, &id, options.abbrev)?;
+        Ok(format!("{}-{}-g{}", tag, depth, abbrev))
+    };
+    // Lightweight tags are only used with `tags`, otherwise just hinted at in errors
+    let usable = |name: &Name| name.annotated || options.tags;
+    if let Some(name) = names.get(&id).filter(|name| usable(name)) {
+        return if options.long {
+            suffix(&name.tag, 0)
+        } else {
+            Ok(name.tag.clone())
+        };
+    }
+
+    // Walk newest commits first, counting for each tag found how many of the walked
+    // commits it does not reach, until the best tag reaches all remaining commits
+    let mut walk = DepthWalk::new(repo, &id)?;
+    let mut candidates: Vec<Candidate> = Vec::new();
+    let mut unannotated = false;
+    let mut seen_commits = 0;
+    let mut gave_up_on = None;
+    while let Some(commit) = walk.pop() {
+        seen_commits += 1;
+        match names.get(&commit) {
+            Some(name) if !usable(name) => unannotated = true,
+            Some(name) if candidates.len() < MAX_CANDIDATES => {
+                let within = 1 << (candidates.len() + 1);
+                candidates.push(Candidate {
+                    name,
+                    depth: seen_commits - 1,
+                    within,
+                });
+                *walk.flags.entry(commit).or_default() |= within;
+            }
+            Some(_) => {
+                gave_up_on = Some(commit);
+                break;
+            }
+            None => {}
+        }
+        let flags = walk.flags(&commit);
+        for candidate in &mut candidates {
+            if flags & candidate.within == 0 {
+                candidate.depth += 1;
+            }
+        }
+        // Stop once the last remaining line is covered by the best candidates
+        if candidates.iter().any(|c| c.name.annotated) && walk.queue.is_empty() {
+            let best_depth = candidates.iter().map(|c| c.depth).min().unwrap_or(0);
+            let best_within = candidates
+                .iter()
+                .filter(|c| c.depth == best_depth)
+                .fold(0, |within, c| within | c.within);
+            if flags & best_within == best_within {
+                break;
+            }
+        }
+        walk.visit_parents(&commit)?;
+    }
+    // Stable sort keeps the order the walk found them for equal depths
+    candidates.sort_by_key(|c| c.depth);
+    let mut best = candidates.into_iter().next();
+    if let Some(best) = &mut best {
+        if let Some(commit) = gave_up_on {
+            walk.insert_by_date(commit)?;
+        }
+        best.depth += walk.finish_depth(best.within)?;
+    }
+    match best {
+        Some(best) => suffix(&best.name.tag, best.depth),
+        None if options.always => Object::abbreviate(repo, &id, options.abbrev),
+        None if unannotated => bail!(
+            "No annotated tags can describe '{}'.\nHowever, there were unannotated tags: try --tags.",
+            id
+        ),
+        None => bail!(
+            "No tags can describe '{}'.\nTry --always, or create some tags.",
+            id
+        ),
+    }
+}
+
+/// Whether the index or tracked files in the working tree differ from HEAD
+pub fn is_dirty(repo: &GitRepo) -> Result<bool> {
+    let index = Index::read(repo)?;
+    if !index.conflicts().is_empty() {
+        return Ok(true);
+    }
+    let head = match refs::resolve(repo, "HEAD")? {
+        Some(id) => Tree::flatten(repo, &Commit::read(repo, &id)?.tree_sha)?,
+        None => Default::default(),
+    };
+    let staged = index.to_map();
+    Ok(staged != head || index.worktree_map(repo)? != staged)
+}
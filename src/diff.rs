@@ -0,0 +1,973 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::attributes::Attributes;
+use crate::config::{self, Config};
+use crate::error::{bail, Result};
+use crate::index::{self, Index};
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::pathname;
+use crate::GitRepo;
+
+/// Splits content into lines, keeping the line terminators
+pub fn lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|b| *b == b'\n').collect()
+}
+
+/// Heuristic used by git: content with NUL byte in the first 8000 bytes is binary
+pub fn is_binary(data: &[u8]) -> bool {
+    data.iter().take(8000).any(|b| *b == 0)
+}
+
+/// Pairs of indexes of matching items in the shortest edit script from `a` to `b`,
+/// computed with Myers' algorithm in linear space
+pub fn matching<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let mut result = Vec::new();
+    compare(a, b, 0, 0, &mut result);
+    result
+}
+
+/// Diagonal path of matching items from `(x, y)` to `(u, v)`
+struct Snake {
+    x: usize,
+    y: usize,
+    u: usize,
+    v: usize,
+}
+
+/// Divide and conquer: splits the problem at the middle snake of the edit path
+fn compare<T: PartialEq>(
+    a: &[T],
+    b: &[T],
+    a_offset: usize,
+    b_offset: usize,
+    result: &mut Vec<(usize, usize)>,
+) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    result.extend((0..prefix).map(|i| (a_offset + i, b_offset + i)));
+    let (mid_a, mid_b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    if !mid_a.is_empty() && !mid_b.is_empty() {
+        let (a_offset, b_offset) = (a_offset + prefix, b_offset + prefix);
+        let Snake { x, y, u, v } = middle_snake(mid_a, mid_b);
+        compare(&mid_a[..x], &mid_b[..y], a_offset, b_offset, result);
+        result.extend((x..u).map(|i| (a_offset + i, b_offset + y + i - x)));
+        compare(&mid_a[u..], &mid_b[v..], a_offset + u, b_offset + v, result);
+    }
+    result.extend((0..suffix).map(|i| {
+        (
+            a_offset + a.len() - suffix + i,
+            b_offset + b.len() - suffix + i,
+        )
+    }));
+}
+
+/// Finds the snake in the middle of the shortest edit path by searching from both ends
+/// at once. Both sequences must be non-empty and differ in the first and last item.
+fn middle_snake<T: PartialEq>(a: &[T], b: &[T]) -> Snake {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let max = (n + m + 1) / 2;
+    // Furthest reaching x on each diagonal k = x - y, indexed with offset. Backward x is
+    // measured from the end of the sequences.
+    let offset = max + 1;
+    let mut forward = vec![0isize; 2 * offset as usize + 1];
+    let mut backward = vec![0isize; 2 * offset as usize + 1];
+    let at = |k: isize| (k + offset) as usize;
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && forward[at(k - 1)] < forward[at(k + 1)]) {
+                forward[at(k + 1)]
+            } else {
+                forward[at(k - 1)] + 1
+            };
+            let (x0, y0) = (x, x - k);
+            let mut y = y0;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[at(k)] = x;
+            let reverse_k = delta - k;
+            if delta % 2 != 0
+                && (-(d - 1)..=d - 1).contains(&reverse_k)
+                && x + backward[at(reverse_k)] >= n
+            {
+                return Snake {
+                    x: x0 as usize,
+                    y: y0 as usize,
+                    u: x as usize,
+                    v: y as usize,
+                };
+            }
+        }
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && backward[at(k - 1)] < backward[at(k + 1)]) {
+                backward[at(k + 1)]
+            } else {
+                backward[at(k - 1)] + 1
+            };
+            let (x0, y0) = (x, x - k);
+            let mut y = y0;
+            while x < n && y < m && a[(n - x - 1) as usize] == b[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[at(k)] = x;
+            let forward_k = delta - k;
+            if delta % 2 == 0 && (-d..=d).contains(&forward_k) && x + forward[at(forward_k)] >= n {
+                return Snake {
+                    x: (n - x) as usize,
+                    y: (m - y) as usize,
+                    u: (n - x0) as usize,
+                    v: (m - y0) as usize,
+                };
+            }
+        }
+    }
+    unreachable!("Edit path has at most n + m steps")
+}
+
+/// Line of a hunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line {
+    /// Index of the line in both old and new content
+    Context(usize, usize),
+    /// Index of the removed line in old content
+    Removed(usize),
+    /// Index of the added line in new content
+    Added(usize),
+}
+
+/// Continuous changed region with surrounding context
+#[derive(Debug, Clone)]
+pub struct Hunk {
+    /// First line of the hunk, 0-based
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub lines: Vec<Line>,
+}
+
+/// Edit script between `a` and `b`, removals preceding additions in each changed block
+pub fn edits<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Line> {
+    let mut lines = Vec::with_capacity(a.len().max(b.len()));
+    let (mut i, mut j) = (0, 0);
+    for (x, y) in matching(a, b).into_iter().chain([(a.len(), b.len())]) {
+        lines.extend((i..x).map(Line::Removed));
+        lines.extend((j..y).map(Line::Added));
+        if x < a.len() {
+            lines.push(Line::Context(x, y));
+        }
+        (i, j) = (x + 1, y + 1);
+    }
+    slide_down(&mut lines, a, b);
+    lines
+}
+
+/// Moves groups of only added or only removed lines as far down as possible when the same
+/// lines follow them, e.g. an inserted function ends with its closing brace like git does
+fn slide_down<T: PartialEq>(lines: &mut [Line], a: &[T], b: &[T]) {
+    let mut start = 0;
+    while start < lines.len() {
+        if matches!(lines[start], Line::Context(..)) {
+            start += 1;
+            continue;
+        }
+        let mut end = start;
+        while end < lines.len() && !matches!(lines[end], Line::Context(..)) {
+            end += 1;
+        }
+        let all_added = lines[start..end]
+            .iter()
+            .all(|l| matches!(l, Line::Added(_)));
+        let all_removed = lines[start..end]
+            .iter()
+            .all(|l| matches!(l, Line::Removed(_)));
+        while end < lines.len() {
+            let Line::Context(x, y) = lines[end] else {
+                break;
+            };
+            let slide = match lines[start] {
+                Line::Added(first) if all_added && b[first] == b[y] => Line::Context(x, first),
+                Line::Removed(first) if all_removed && a[first] == a[x] => Line::Context(first, y),
+                _ => break,
+            };
+            // The group shifts by one line, the following context line takes its first slot
+            for line in &mut lines[start..end] {
+                *line = match *line {
+                    Line::Added(j) => Line::Added(j + 1),
+                    Line::Removed(i) => Line::Removed(i + 1),
+                    context => context,
+                };
+            }
+            lines[start..=end].rotate_right(1);
+            lines[start] = slide;
+            start += 1;
+            end += 1;
+        }
+        start = end;
+    }
+}
+
+/// Groups changes into hunks with `context` unchanged lines around them
+pub fn hunks<T: PartialEq>(a: &[T], b: &[T], context: usize) -> Vec<Hunk> {
+    let lines = edits(a, b);
+    let changes: Vec<usize> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Line::Context(..)))
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut hunks = Vec::new();
+    let mut i = 0;
+    while i < changes.len() {
+        // Changes separated by at most twice the context belong to the same hunk
+        let mut j = i;
+        while j + 1 < changes.len() && changes[j + 1] - changes[j] <= 2 * context + 1 {
+            j += 1;
+        }
+        let start = changes[i].saturating_sub(context);
+        let end = (changes[j] + context + 1).min(lines.len());
+        let (mut old_start, mut new_start) = (0, 0);
+        for line in &lines[..start] {
+            match line {
+                Line::Context(..) => {
+                    old_start += 1;
+                    new_start += 1;
+                }
+                Line::Removed(_) => old_start += 1,
+                Line::Added(_) => new_start += 1,
+            }
+        }
+        let hunk_lines = lines[start..end].to_vec();
+        let old_len = hunk_lines
+            .iter()
+            .filter(|l| !matches!(l, Line::Added(_)))
+            .count();
+        let new_len = hunk_lines
+            .iter()
+            .filter(|l| !matches!(l, Line::Removed(_)))
+            .count();
+        hunks.push(Hunk {
+            old_start,
+            old_len,
+            new_start,
+            new_len,
+            lines: hunk_lines,
+        });
+        i = j + 1;
+    }
+    hunks
+}
+
+/// Range of hunk header, empty ranges start at the line before them
+fn format_range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+/// Last line before the hunk looking like start of a function, the git default heuristic
+fn function_name<'a>(old: &[&'a [u8]], start: usize) -> Option<&'a [u8]> {
+    old[..start.min(old.len())]
+        .iter()
+        .rev()
+        .find(|line| {
+            line.first()
+                .is_some_and(|c| c.is_ascii_alphabetic() || *c == b'_' || *c == b'$')
+        })
+        .map(|line| {
+            let line = line.trim_ascii_end();
+            &line[..line.len().min(80)]
+        })
+}
+
+fn write_line(out: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+    out.push(prefix);
+    out.extend(line);
+    if !line.ends_with(b"\n") {
+        out.extend(b"\n\\ No newline at end of file\n");
+    }
+}
+
+/// Unified diff hunks of two text contents
+pub fn write_unified(out: &mut Vec<u8>, old: &[u8], new: &[u8], context: usize) {
+    let (old, new) = (lines(old), lines(new));
+    for hunk in hunks(&old, &new, context) {
+        out.extend(
+            format!(
+                "@@ -{} +{} @@",
+                format_range(hunk.old_start, hunk.old_len),
+                format_range(hunk.new_start, hunk.new_len)
+            )
+            .as_bytes(),
+        );
+        if let Some(name) = function_name(&old, hunk.old_start) {
+            out.push(b' ');
+            out.extend(name);
+        }
+        out.push(b'\n');
+        for line in hunk.lines {
+            match line {
+                Line::Context(i, _) => write_line(out, b' ', old[i]),
+                Line::Removed(i) => write_line(out, b'-', old[i]),
+                Line::Added(j) => write_line(out, b'+', new[j]),
+            }
+        }
+    }
+}
+
+/// Path whose mode or content differs between two snapshots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// Path on the new side, the old side may differ when renamed
+    pub path: Vec<u8>,
+    pub old: Option<(u32, ObjectId)>,
+    pub new: Option<(u32, ObjectId)>,
+    pub rename: Option<Rename>,
+}
+
+/// Old path of renamed or copied file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rename {
+    pub from: Vec<u8>,
+    /// Percentage of shared content
+    pub similarity: u8,
+    /// Source path still exists
+    pub copy: bool,
+}
+
+/// Changed paths between two path to (mode, id) maps, sorted by path
+pub fn changes(
+    old: &BTreeMap<Vec<u8>, (u32, ObjectId)>,
+    new: &BTreeMap<Vec<u8>, (u32, ObjectId)>,
+) -> Vec<Change> {
+    let paths: BTreeSet<&Vec<u8>> = old.keys().chain(new.keys()).collect();
+    paths
+        .into_iter()
+        .filter(|path| old.get(*path) != new.get(*path))
+        .map(|path| Change {
+            path: path.clone(),
+            old: old.get(path).cloned(),
+            new: new.get(path).cloned(),
+            rename: None,
+        })
+        .collect()
+}
+
+/// Similarity scores are computed in the same units as git to get the same rounding
+const MAX_SCORE: u64 = 60000;
+
+/// Settings of rename detection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Renames {
+    /// Minimal similarity in percent
+    pub threshold: u8,
+    /// Also pair added files with modified ones
+    pub copies: bool,
+}
+
+impl Default for Renames {
+    fn default() -> Self {
+        Self {
+            threshold: 50,
+            copies: false,
+        }
+    }
+}
+
+impl Renames {
+    /// Parses similarity argument of `-M` or `-C`, either percentage `75%` or decimal
+    /// fraction digits like git, so `75` is 75% but `5` is 50%
+    pub fn parse_threshold(value: &str) -> Result<u8> {
+        let threshold = match value.strip_suffix('%') {
+            Some(percent) => percent.parse::<u64>().ok().filter(|p| *p <= 100),
+            None if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                // Fraction digits after implicit decimal point, precision is whole percents
+                let digits = format!("{:0<2}", value);
+                digits[..2].parse::<u64>().ok()
+            }
+            None => None,
+        };
+        match threshold {
+            Some(threshold) => Ok(threshold.min(100) as u8),
+            None => bail!("Invalid similarity {:?}", value),
+        }
+    }
+
+    /// Detection configured by `diff.renames`, enabled by default
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let value = config.get("diff.renames");
+        if matches!(value, Some("copy" | "copies")) {
+            return Ok(Some(Self {
+                copies: true,
+                ..Self::default()
+            }));
+        }
+        let enabled = match config.get_entry("diff.renames") {
+            Some(_) => config::parse_bool(value)?,
+            None => true,
+        };
+        Ok(enabled.then(Self::default))
+    }
+}
+
+/// Counts bytes of content by chunks, which are lines up to 64 bytes long
+fn chunks(data: &[u8]) -> HashMap<&[u8], u64> {
+    let mut chunks = HashMap::new();
+    let mut start = 0;
+    for (i, b) in data.iter().enumerate() {
+        if *b == b'\n' || i + 1 - start == 64 {
+            *chunks.entry(&data[start..=i]).or_default() += (i + 1 - start) as u64;
+            start = i + 1;
+        }
+    }
+    if start < data.len() {
+        *chunks.entry(&data[start..]).or_default() += (data.len() - start) as u64;
+    }
+    chunks
+}
+
+/// Estimates similarity from the amount of source chunks kept in destination.
+/// Returns 0 when sizes alone rule out reaching the `minimum` score.
+fn similarity(src: &[u8], dst: &[u8], minimum: u64) -> u64 {
+    let max_size = src.len().max(dst.len()) as u64;
+    let delta = max_size - src.len().min(dst.len()) as u64;
+    if dst.is_empty() || max_size * (MAX_SCORE - minimum) < delta * MAX_SCORE {
+        return 0;
+    }
+    let dst_chunks = chunks(dst);
+    let copied: u64 = chunks(src)
+        .into_iter()
+        .map(|(chunk, count)| count.min(dst_chunks.get(chunk).copied().unwrap_or_default()))
+        .sum();
+    copied * MAX_SCORE / max_size
+}
+
+/// Index and attributes for reading files of the working tree when `worktree` is set,
+/// loaded once for all changes
+fn load_worktree(repo: &GitRepo, worktree: bool) -> Result<Option<(Index, Attributes)>> {
+    if !worktree {
+        return Ok(None);
+    }
+    Ok(Some((Index::read(repo)?, Attributes::load(repo)?)))
+}
+
+/// Content of one side of the change, from the working tree when it is loaded
+fn read_side(
+    repo: &GitRepo,
+    path: &[u8],
+    id: &ObjectId,
+    worktree: &mut Option<(Index, Attributes)>,
+) -> Result<Vec<u8>> {
+    match worktree {
+        Some((index, attributes)) => Ok(index::worktree_file_with(repo, path, index, attributes)?
+            .map(|(_, data)| data)
+            .unwrap_or_default()),
+        None => Ok(Object::read(repo, id)?.data),
+    }
+}
+
+/// Pairs added paths with similar deleted ones, or also modified ones when looking for
+/// copies. Each added path gets the most similar source, if any reaches the threshold,
+/// deleted source is consumed by its first rename and further pairs become copies.
+/// Content of the new side is read from the working tree when `worktree` is set.
+pub fn detect_renames(
+    repo: &GitRepo,
+    changes: Vec<Change>,
+    options: &Renames,
+    worktree: bool,
+) -> Result<Vec<Change>> {
+    // Only files of the same type can be paired, empty files are too ambiguous
+    let kind = |mode: u32| mode & 0o170000;
+    let sources: Vec<usize> = (0..changes.len())
+        .filter(|i| match (&changes[*i].old, &changes[*i].new) {
+            (Some(_), None) => true,
+            (Some(_), Some(_)) => options.copies,
+            _ => false,
+        })
+        .collect();
+    let destinations: Vec<usize> = (0..changes.len())
+        .filter(|i| changes[*i].old.is_none())
+        .collect();
+    if sources.is_empty() || destinations.is_empty() {
+        return Ok(changes);
+    }
+
+    let minimum = options.threshold as u64 * MAX_SCORE / 100;
+    let empty_blob = Object::new(repo.hash_algo(), ObjectKind::Blob, Vec::new()).hash;
+    let mut worktree = load_worktree(repo, worktree)?;
+    let mut contents = HashMap::new();
+    let mut candidates = Vec::new();
+    for dst in &destinations {
+        let (dst_mode, dst_id) = changes[*dst].new.as_ref().unwrap();
+        let mut dst_data = None;
+        for src in &sources {
+            let (src_mode, src_id) = changes[*src].old.as_ref().unwrap();
+            if kind(*src_mode) != kind(*dst_mode)
+                || kind(*src_mode) == 0o160000
+                || *src_id == empty_blob
+            {
+                continue;
+            }
+            let score = if src_id == dst_id {
+                MAX_SCORE
+            } else {
+                if dst_data.is_none() {
+                    dst_data = Some(read_side(repo, &changes[*dst].path, dst_id, &mut worktree)?);
+                }
+                if !contents.contains_key(src_id) {
+                    contents.insert(*src_id, Object::read(repo, src_id)?.data);
+                }
+                similarity(&contents[src_id], dst_data.as_ref().unwrap(), minimum)
+            };
+            if score >= minimum && score > 0 {
+                candidates.push((score, *dst, *src));
+            }
+        }
+    }
+    // Stable sort keeps path order between equally similar pairs
+    candidates.sort_by_key(|(score, _, _)| std::cmp::Reverse(*score));
+
+    let mut paired: HashMap<usize, (usize, u64, bool)> = HashMap::new();
+    let mut renamed = BTreeSet::new();
+    for (score, dst, src) in candidates {
+        if paired.contains_key(&dst) {
+            continue;
+        }
+        let deleted = changes[src].new.is_none();
+        let copy = !deleted || renamed.contains(&src);
+        if copy && !options.copies {
+            continue;
+        }
+        if !copy {
+            renamed.insert(src);
+        }
+        paired.insert(dst, (src, score, copy));
+    }
+
+    let mut result = Vec::new();
+    for (i, change) in changes.iter().enumerate() {
+        if renamed.contains(&i) {
+            continue;
+        }
+        let mut change = change.clone();
+        if let Some((src, score, copy)) = paired.get(&i) {
+            change.old = changes[*src].old;
+            change.rename = Some(Rename {
+                from: changes[*src].path.clone(),
+                similarity: (score * 100 / MAX_SCORE) as u8,
+                copy: *copy,
+            });
+        }
+        result.push(change);
+    }
+    Ok(result)
+}
+
+/// Side of a changed file: mode, object id and content
+pub struct Side<'a> {
+    pub mode: u32,
+    pub id: &'a ObjectId,
+    pub data: &'a [u8],
+}
+
+/// Path in patch headers with the `a/` or `b/` prefix, quoted as a whole when needed
+fn patch_path(prefix: &str, path: &[u8]) -> String {
+    match pathname::quote(path, true) {
+        Cow::Borrowed(path) => format!("{}{}", prefix, path),
+        Cow::Owned(quoted) => format!("\"{}{}", prefix, &quoted[1..]),
+    }
+}
+
+/// Patch of single file in `git diff` format. Missing side means added or deleted file.
+pub fn write_patch(
+    out: &mut Vec<u8>,
+    path: &[u8],
+    rename: Option<&Rename>,
+    old: Option<&Side>,
+    new: Option<&Side>,
+    context: usize,
+) {
+    let abbrev = |side: Option<&Side>| {
+        side.map_or_else(|| "0".repeat(7), |s| s.id.to_hex()[..7].to_string())
+    };
+    let old_path = rename.map_or(path, |r| r.from.as_slice());
+    let (old_header, new_header) = (patch_path("a/", old_path), patch_path("b/", path));
+    out.extend(format!("diff --git {} {}\n", old_header, new_header).as_bytes());
+    match (old, new) {
+        (None, Some(new)) => out.extend(format!("new file mode {:o}\n", new.mode).as_bytes()),
+        (Some(old), None) => out.extend(format!("deleted file mode {:o}\n", old.mode).as_bytes()),
+        (Some(old), Some(new)) if old.mode != new.mode => {
+            out.extend(format!("old mode {:o}\nnew mode {:o}\n", old.mode, new.mode).as_bytes())
+        }
+        _ => {}
+    }
+    if let Some(rename) = rename {
+        let kind = if rename.copy { "copy" } else { "rename" };
+        out.extend(format!("similarity index {}%\n", rename.similarity).as_bytes());
+        let (from, to) = (pathname::quote(old_path, true), pathname::quote(path, true));
+        out.extend(format!("{} from {}\n{} to {}\n", kind, from, kind, to).as_bytes());
+    }
+    let (old_id, new_id) = (abbrev(old), abbrev(new));
+    if old_id == new_id {
+        // Only the mode or path changed
+        return;
+    }
+    out.extend(format!("index {}..{}", old_id, new_id).as_bytes());
+    match (old, new) {
+        (Some(old), Some(new)) if old.mode == new.mode => {
+            out.extend(format!(" {:o}", old.mode).as_bytes())
+        }
+        _ => {}
+    }
+    out.push(b'\n');
+
+    let old_name = old.map_or(String::from("/dev/null"), |_| old_header);
+    let new_name = new.map_or(String::from("/dev/null"), |_| new_header);
+    let (old_data, new_data) = (
+        old.map_or(&[][..], |s| s.data),
+        new.map_or(&[][..], |s| s.data),
+    );
+    if is_binary(old_data) || is_binary(new_data) {
+        out.extend(format!("Binary files {} and {} differ\n", old_name, new_name).as_bytes());
+        return;
+    }
+    out.extend(format!("--- {}\n+++ {}\n", old_name, new_name).as_bytes());
+    write_unified(out, old_data, new_data, context);
+}
+
+/// Old and new content of the change, empty for missing side
+fn read_change(
+    repo: &GitRepo,
+    change: &Change,
+    worktree: &mut Option<(Index, Attributes)>,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let old_data = match &change.old {
+        Some((_, id)) => Object::read(repo, id)?.data,
+        None => Vec::new(),
+    };
+    let new_data = match &change.new {
+        Some((_, id)) => read_side(repo, &change.path, id, worktree)?,
+        None => Vec::new(),
+    };
+    Ok((old_data, new_data))
+}
+
+/// Patches of changed paths. Content of the new side is read from the working tree when
+/// `worktree` is set, everything else comes from the object database.
+pub fn write_changes(
+    repo: &GitRepo,
+    out: &mut Vec<u8>,
+    changes: &[Change],
+    context: usize,
+    worktree: bool,
+) -> Result<()> {
+    let mut worktree = load_worktree(repo, worktree)?;
+    for change in changes {
+        let (old_data, new_data) = read_change(repo, change, &mut worktree)?;
+        let old_side = change.old.as_ref().map(|(mode, id)| Side {
+            mode: *mode,
+            id,
+            data: &old_data,
+        });
+        let new_side = change.new.as_ref().map(|(mode, id)| Side {
+            mode: *mode,
+            id,
+            data: &new_data,
+        });
+        write_patch(
+            out,
+            &change.path,
+            change.rename.as_ref(),
+            old_side.as_ref(),
+            new_side.as_ref(),
+            context,
+        );
+    }
+    Ok(())
+}
+
+/// Summary of single changed file
+pub struct FileStat {
+    /// Path, or both paths like `dir/{old => new}` for renames
+    pub name: String,
+    /// Added and removed lines, or new and old size in bytes for binary files
+    pub added: usize,
+    pub removed: usize,
+    pub binary: bool,
+}
+
+/// Displayed name of renamed file with common leading and trailing directories
+/// outside of braces, e.g. `src/{old => new}/lib.rs`
+fn rename_name(a: &[u8], b: &[u8]) -> String {
+    let mut prefix = 0;
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        if x != y {
+            break;
+        }
+        if *x == b'/' {
+            prefix = i + 1;
+        }
+    }
+    // Suffix has to start with slash and may share it with the prefix
+    let mut suffix = 0;
+    let (mut i, mut j) = (a.len(), b.len());
+    let floor = prefix.saturating_sub(1);
+    loop {
+        let (x, y) = (a.get(i), b.get(j));
+        if i < floor || j < floor || x != y {
+            break;
+        }
+        if x == Some(&b'/') {
+            suffix = a.len() - i;
+        }
+        if i == 0 || j == 0 {
+            break;
+        }
+        i -= 1;
+        j -= 1;
+    }
+    let a_mid = &a[prefix..a.len().saturating_sub(suffix).max(prefix)];
+    let b_mid = &b[prefix..b.len().saturating_sub(suffix).max(prefix)];
+    let mid = format!(
+        "{} => {}",
+        String::from_utf8_lossy(a_mid),
+        String::from_utf8_lossy(b_mid)
+    );
+    if prefix + suffix == 0 {
+        return mid;
+    }
+    format!(
+        "{}{{{}}}{}",
+        String::from_utf8_lossy(&a[..prefix]),
+        mid,
+        String::from_utf8_lossy(&a[a.len() - suffix..])
+    )
+}
+
+/// Line counts of the changes
+pub fn stats(repo: &GitRepo, changes: &[Change], worktree: bool) -> Result<Vec<FileStat>> {
+    let mut stats = Vec::new();
+    let mut worktree = load_worktree(repo, worktree)?;
+    for change in changes {
+        let (old_data, new_data) = read_change(repo, change, &mut worktree)?;
+        let name = match &change.rename {
+            Some(rename) => rename_name(&rename.from, &change.path),
+            None => pathname::quote(&change.path, true).into_owned(),
+        };
+        if is_binary(&old_data) || is_binary(&new_data) {
+            stats.push(FileStat {
+                name,
+                added: new_data.len(),
+                removed: old_data.len(),
+                binary: true,
+            });
+            continue;
+        }
+        let (old, new) = (lines(&old_data), lines(&new_data));
+        let (mut added, mut removed) = (0, 0);
+        for line in edits(&old, &new) {
+            match line {
+                Line::Added(_) => added += 1,
+                Line::Removed(_) => removed += 1,
+                Line::Context(_, _) => {}
+            }
+        }
+        stats.push(FileStat {
+            name,
+            added,
+            removed,
+            binary: false,
+        });
+    }
+    Ok(stats)
+}
+
+/// Tab separated added and removed lines per file, `-` for binary files
+pub fn write_numstat(out: &mut Vec<u8>, stats: &[FileStat]) {
+    for stat in stats {
+        if stat.binary {
+            out.extend(format!("-\t-\t{}\n", stat.name).as_bytes());
+        } else {
+            out.extend(format!("{}\t{}\t{}\n", stat.added, stat.removed, stat.name).as_bytes());
+        }
+    }
+}
+
+/// Total counts line, e.g. ` 2 files changed, 3 insertions(+), 1 deletion(-)`
+pub fn write_shortstat(out: &mut Vec<u8>, stats: &[FileStat]) {
+    if stats.is_empty() {
+        return;
+    }
+    let text = stats.iter().filter(|stat| !stat.binary);
+    let insertions: usize = text.clone().map(|stat| stat.added).sum();
+    let deletions: usize = text.map(|stat| stat.removed).sum();
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+    let mut line = format!(" {} file{} changed", stats.len(), plural(stats.len()));
+    // Show at least one of the counts even when both are zero
+    if insertions > 0 || deletions == 0 {
+        line.push_str(&format!(
+            ", {} insertion{}(+)",
+            insertions,
+            plural(insertions)
+        ));
+    }
+    if deletions > 0 || insertions == 0 {
+        line.push_str(&format!(", {} deletion{}(-)", deletions, plural(deletions)));
+    }
+    out.extend(line.as_bytes());
+    out.push(b'\n');
+}
+
+/// Scales count to the graph width, keeping non-zero counts visible
+fn scale(count: usize, width: usize, max: usize) -> usize {
+    if count == 0 {
+        0
+    } else {
+        1 + count * (width - 1) / max
+    }
+}
+
+/// Per file histogram of changes fitted into `width` columns, followed by the totals.
+/// Widths of file name and graph columns are assigned the same way as git does.
+pub fn write_stat(out: &mut Vec<u8>, stats: &[FileStat], width: usize) {
+    if stats.is_empty() {
+        return;
+    }
+    let text = stats.iter().filter(|stat| !stat.binary);
+    let max_change = text
+        .clone()
+        .map(|stat| stat.added + stat.removed)
+        .max()
+        .unwrap_or_default();
+    let bin_width = stats
+        .iter()
+        .filter(|stat| stat.binary)
+        .map(|stat| 14 + stat.added.to_string().len() + stat.removed.to_string().len())
+        .max()
+        .unwrap_or_default();
+    // Change counts are aligned with "Bin"
+    let mut number_width = max_change.to_string().len();
+    if bin_width > 0 {
+        number_width = number_width.max(3);
+    }
+    let width = width.max(16 + 6 + number_width);
+    let max_len = stats
+        .iter()
+        .map(|stat| stat.name.chars().count())
+        .max()
+        .unwrap_or_default();
+
+    let mut graph_width = if max_change + 4 > bin_width {
+        max_change
+    } else {
+        bin_width - 4
+    };
+    let mut name_width = max_len;
+    if name_width + number_width + 6 + graph_width > width {
+        let limit = (width * 3 / 8).saturating_sub(number_width + 6);
+        if graph_width > limit {
+            graph_width = limit.max(6);
+        }
+        if name_width > width - number_width - 6 - graph_width {
+            name_width = width - number_width - 6 - graph_width;
+        } else {
+            graph_width = width - number_width - 6 - name_width;
+        }
+    }
+
+    for stat in stats {
+        let chars: Vec<char> = stat.name.chars().collect();
+        let name = if chars.len() > name_width {
+            // Keep the end of the path, starting at directory boundary when possible
+            let tail: String = chars[chars.len() - name_width.saturating_sub(3)..]
+                .iter()
+                .collect();
+            match tail.find('/') {
+                Some(slash) => format!("...{}", &tail[slash..]),
+                None => format!("...{}", tail),
+            }
+        } else {
+            stat.name.clone()
+        };
+        let padding = name_width.saturating_sub(name.chars().count());
+        let mut line = format!(" {}{} |", name, " ".repeat(padding));
+        if stat.binary {
+            line.push_str(&format!(" {:>width$}", "Bin", width = number_width));
+            if stat.added > 0 || stat.removed > 0 {
+                line.push_str(&format!(" {} -> {} bytes", stat.removed, stat.added));
+            }
+        } else {
+            let total = stat.added + stat.removed;
+            line.push_str(&format!(" {:>width$}", total, width = number_width));
+            if total > 0 {
+                line.push(' ');
+            }
+            let (mut add, mut del) = (stat.added, stat.removed);
+            if graph_width <= max_change {
+                let mut scaled = scale(total, graph_width, max_change);
+                if scaled < 2 && add > 0 && del > 0 {
+                    scaled = 2;
+                }
+                if add < del {
+                    add = scale(add, graph_width, max_change);
+                    del = scaled - add;
+                } else {
+                    del = scale(del, graph_width, max_change);
+                    add = scaled - del;
+                }
+            }
+            line.push_str(&"+".repeat(add));
+            line.push_str(&"-".repeat(del));
+        }
+        out.extend(line.as_bytes());
+        out.push(b'\n');
+    }
+    write_shortstat(out, stats);
+}
+
+/// Created, deleted and renamed files and mode changes, as listed by `--summary`
+pub fn write_summary(out: &mut Vec<u8>, changes: &[Change]) {
+    for change in changes {
+        match (&change.old, &change.new, &change.rename) {
+            (None, Some((mode, _)), _) => {
+                let path = pathname::quote(&change.path, true);
+                out.extend(format!(" create mode {:06o} {}\n", mode, path).as_bytes())
+            }
+            (Some((mode, _)), None, _) => {
+                let path = pathname::quote(&change.path, true);
+                out.extend(format!(" delete mode {:06o} {}\n", mode, path).as_bytes())
+            }
+            (Some((old, _)), Some((new, _)), rename) => {
+                if let Some(rename) = rename {
+                    let kind = if rename.copy { "copy" } else { "rename" };
+                    let name = rename_name(&rename.from, &change.path);
+                    out.extend(format!(" {} {} ({}%)\n", kind, name, rename.similarity).as_bytes());
+                }
+                if old != new {
+                    // Like git, the path is not repeated after rename
+                    let path = match rename {
+                        Some(_) => String::new(),
+                        None => format!(" {}", pathname::quote(&change.path, true)),
+                    };
+                    out.extend(
+                        format!(" mode change {:06o} => {:06o}{}\n", old, new, path).as_bytes(),
+                    );
+                }
+            }
+            (None, None, _) => {}
+        }
+    }
+}
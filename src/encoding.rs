@@ -0,0 +1,87 @@
+/// Character encoding of commit messages, named by the `encoding` header and
+/// `i18n.commitEncoding` config. Only UTF-8 and the common single-byte encodings are
+/// supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    /// ISO-8859-1, bytes are the first 256 code points
+    Latin1,
+    /// ISO-8859-15, Latin-1 with the euro sign and few other letters
+    Latin9,
+    /// Windows-1252, Latin-1 with printable characters in place of C1 controls
+    Windows1252,
+}
+
+/// Characters of Windows-1252 bytes 0x80 to 0x9f, undefined ones map to C1 controls
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+/// Bytes of ISO-8859-15 which differ from ISO-8859-1, with their characters
+const LATIN9: [(u8, char); 8] = [
+    (0xa4, '€'),
+    (0xa6, 'Š'),
+    (0xa8, 'š'),
+    (0xb4, 'Ž'),
+    (0xb8, 'ž'),
+    (0xbc, 'Œ'),
+    (0xbd, 'œ'),
+    (0xbe, 'Ÿ'),
+];
+
+impl Encoding {
+    /// Encoding of the name, case-insensitive. `None` for unsupported encodings.
+    pub fn from_label(label: &str) -> Option<Self> {
+        Some(match label.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" | "us-ascii" | "ascii" => Self::Utf8,
+            "iso-8859-1" | "iso8859-1" | "iso_8859-1" | "latin1" | "latin-1" | "l1" => Self::Latin1,
+            "iso-8859-15" | "iso8859-15" | "iso_8859-15" | "latin9" | "latin-9" => Self::Latin9,
+            "windows-1252" | "cp1252" => Self::Windows1252,
+            _ => return None,
+        })
+    }
+
+    pub fn is_utf8(&self) -> bool {
+        *self == Self::Utf8
+    }
+
+    fn decode_byte(&self, byte: u8) -> char {
+        match self {
+            Self::Windows1252 if (0x80..0xa0).contains(&byte) => {
+                WINDOWS_1252[usize::from(byte - 0x80)]
+            }
+            Self::Latin9 => match LATIN9.iter().find(|(b, _)| *b == byte) {
+                Some((_, c)) => *c,
+                None => char::from(byte),
+            },
+            _ => char::from(byte),
+        }
+    }
+
+    /// Text of the bytes, invalid UTF-8 is replaced by replacement characters
+    pub fn decode(&self, data: &[u8]) -> String {
+        match self {
+            Self::Utf8 => String::from_utf8_lossy(data).into_owned(),
+            _ => data.iter().map(|byte| self.decode_byte(*byte)).collect(),
+        }
+    }
+
+    /// Bytes of the text, characters the encoding cannot represent become `?`
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        if self.is_utf8() {
+            return text.as_bytes().to_vec();
+        }
+        text.chars()
+            .map(|c| {
+                let byte = u8::try_from(u32::from(c)).ok();
+                match byte.filter(|byte| self.decode_byte(*byte) == c) {
+                    Some(byte) => byte,
+                    None => (0x80..=0xff)
+                        .find(|byte| self.decode_byte(*byte) == c)
+                        .unwrap_or(b'?'),
+                }
+            })
+            .collect()
+    }
+}
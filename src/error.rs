@@ -0,0 +1,193 @@
+use std::error::Error as StdError;
+use std::fmt;
+
+/// Error of library operations. Common failures have their own variant so callers can
+/// match on them, the rest carry just the message.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Object with the (possibly abbreviated) id is in none of the object stores
+    #[error("Object {0} not found")]
+    ObjectNotFound(String),
+    /// Abbreviated object id matches several objects
+    #[error("short object ID {0} is ambiguous")]
+    AmbiguousObject(String),
+    /// Stored object does not hash to its id or cannot be parsed
+    #[error("Object {id} is corrupt: {message}")]
+    CorruptObject { id: String, message: String },
+    /// Ref or revision does not resolve to anything
+    #[error("unknown revision {0:?}")]
+    RefNotFound(String),
+    /// Remote or client spoke the wire protocol in unexpected way
+    #[error("{message}")]
+    ProtocolError { message: String },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("{0}")]
+    Message(String),
+    /// Error of a lower level operation with description of what was being done
+    #[error("{message}")]
+    Context {
+        message: String,
+        #[source]
+        source: Box<Error>,
+    },
+    /// Error of parsing or conversion done by the standard library or dependencies
+    #[error(transparent)]
+    Other(Box<dyn StdError + Send + Sync>),
+}
+
+impl Error {
+    pub fn protocol(message: impl fmt::Display) -> Self {
+        Self::ProtocolError {
+            message: message.to_string(),
+        }
+    }
+
+    pub fn corrupt(id: impl fmt::Display, message: impl fmt::Display) -> Self {
+        Self::CorruptObject {
+            id: id.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Wraps the error with description of what was being done
+    pub fn context(self, message: impl fmt::Display) -> Self {
+        Self::Context {
+            message: message.to_string(),
+            source: Box::new(self),
+        }
+    }
+
+    /// The innermost error, skipping any context added on the way up
+    pub fn root(&self) -> &Error {
+        match self {
+            Self::Context { source, .. } => source.root(),
+            _ => self,
+        }
+    }
+
+    /// Messages of the error and all underlying ones, separated by colons
+    pub fn chain(&self) -> impl fmt::Display + '_ {
+        Chain(self)
+    }
+}
+
+struct Chain<'e>(&'e Error);
+
+impl fmt::Display for Chain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut source = self.0.source();
+        while let Some(err) = source {
+            write!(f, ": {}", err)?;
+            source = err.source();
+        }
+        Ok(())
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Self::Message(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Self::Message(message.to_string())
+    }
+}
+
+macro_rules! impl_from_other {
+    ($($ty:ty),* $(,)?) => {
+        $(impl From<$ty> for Error {
+            fn from(err: $ty) -> Self {
+                Self::Other(Box::new(err))
+            }
+        })*
+    };
+}
+
+impl_from_other!(
+    std::num::ParseIntError,
+    std::num::TryFromIntError,
+    std::str::Utf8Error,
+    std::string::FromUtf8Error,
+    std::array::TryFromSliceError,
+    std::time::SystemTimeError,
+    std::fmt::Error,
+    hex::FromHexError,
+    reqwest::header::ToStrError,
+    tokio::task::JoinError,
+);
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Adds description of the operation to errors and turns `None` into an error
+pub(crate) trait Context<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T>;
+
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T> {
+        self.map_err(|err| err.into().context(context))
+    }
+
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|err| err.into().context(f()))
+    }
+}
+
+impl<T> Context<T> for Option<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T> {
+        self.ok_or_else(|| Error::Message(context.to_string()))
+    }
+
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.ok_or_else(|| Error::Message(f().to_string()))
+    }
+}
+
+/// Creates [`Error::Message`] from format arguments, or converts single error value
+macro_rules! format_err {
+    ($msg:literal $(,)?) => {
+        $crate::error::Error::Message(format!($msg))
+    };
+    ($err:expr $(,)?) => {
+        $crate::error::Error::from($err)
+    };
+    ($fmt:expr, $($arg:tt)*) => {
+        $crate::error::Error::Message(format!($fmt, $($arg)*))
+    };
+}
+
+/// Returns early with error built by [`format_err!`]
+macro_rules! bail {
+    ($($arg:tt)+) => {
+        return Err($crate::error::format_err!($($arg)+))
+    };
+}
+
+/// Returns early with error built by [`format_err!`] unless the condition holds
+macro_rules! ensure {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            return Err($crate::error::Error::Message(String::from(concat!(
+                "Condition failed: `",
+                stringify!($cond),
+                "`"
+            ))));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::error::bail!($($arg)+);
+        }
+    };
+}
+
+pub(crate) use {bail, ensure, format_err};
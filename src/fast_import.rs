@@ -0,0 +1,648 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+
+use crate::commit::{Author, Commit};
+use crate::encoding::Encoding;
+use crate::error::{bail, ensure, format_err, Context, Result};
+use crate::index::{Index, IndexEntry};
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::pathname;
+use crate::refs::{self, RefTransaction};
+use crate::revparse;
+use crate::revwalk::{self, RevWalk, Sort};
+use crate::tag::Tag;
+use crate::tree::Tree;
+use crate::GitRepo;
+
+const GITLINK_MODE: u32 = 0o160000;
+const TREE_MODE: u32 = 0o040000;
+
+/// Changed path with its new mode and object, `None` when deleted
+type Change<'a> = (&'a [u8], Option<&'a (u32, ObjectId)>);
+
+/// Orders changes of commit the way of git, paths before their prefixes so that
+/// directory is emptied before a file replaces it
+fn depth_first(a: &[u8], b: &[u8]) -> Ordering {
+    let common = a.len().min(b.len());
+    a[..common].cmp(&b[..common]).then(b.len().cmp(&a.len()))
+}
+
+/// Path of file command, quoted when it has special characters or a space
+fn export_path(path: &[u8]) -> String {
+    match pathname::quote(path, true) {
+        quoted if quoted.starts_with('"') => quoted.into_owned(),
+        quoted if path.contains(&b' ') => format!("\"{}\"", quoted),
+        quoted => quoted.into_owned(),
+    }
+}
+
+fn write_data(out: &mut impl Write, data: &[u8]) -> Result<()> {
+    writeln!(out, "data {}", data.len())?;
+    out.write_all(data)?;
+    Ok(())
+}
+
+fn write_identity(out: &mut impl Write, role: &str, identity: &Author) -> Result<()> {
+    let mut line = format!("{} ", role).into_bytes();
+    identity.write_to_buf(&mut line);
+    line.push(b'\n');
+    out.write_all(&line)?;
+    Ok(())
+}
+
+/// Writes fast-import stream of the history named by the revisions. Revisions prefixed
+/// with `^` or the left side of `A..B` are excluded, `--all` exports all refs. Commits
+/// are labelled with the ref they were reached from, other refs pointing to them are
+/// reset at the end. Annotated tags are exported after the commits.
+pub fn export(repo: &GitRepo, revs: &[String], out: &mut impl Write) -> Result<()> {
+    let mut tips: Vec<(String, ObjectId)> = Vec::new();
+    let mut add_tip = |rev: &str| -> Result<()> {
+        let name = refs::dwim(repo, rev)?.unwrap_or_else(|| rev.to_string());
+        if !tips.iter().any(|(tip, _)| *tip == name) {
+            tips.push((name, revparse::resolve(repo, rev)?));
+        }
+        Ok(())
+    };
+    let mut hidden = Vec::new();
+    for rev in revs {
+        if rev == "--all" {
+            for (name, _) in refs::list(repo)? {
+                add_tip(&name)?;
+            }
+        } else if let Some(rev) = rev.strip_prefix('^') {
+            hidden.push(revparse::resolve(repo, rev)?);
+        } else if let Some((from, to)) = rev.split_once("..") {
+            hidden.push(revparse::resolve(repo, from)?);
+            add_tip(to)?;
+        } else {
+            add_tip(rev)?;
+        }
+    }
+
+    let mut walk = RevWalk::new(repo);
+    walk.sort(Sort::Topo);
+    for (name, id) in &tips {
+        match Object::read_header(repo, id)?.kind {
+            ObjectKind::Commit | ObjectKind::Tag => walk.push(id)?,
+            kind => repo
+                .progress()
+                .warning(&format!("{} points to a {:?}, skipping it", name, kind)),
+        }
+    }
+    for id in &hidden {
+        walk.hide(id)?;
+    }
+    let mut commits: Vec<(ObjectId, Commit)> = walk.collect::<Result<_>>()?;
+
+    // Labels propagate from the tips to parents, newest commits first
+    let exported: HashSet<ObjectId> = commits.iter().map(|(id, _)| *id).collect();
+    let mut labels: HashMap<ObjectId, String> = HashMap::new();
+    for (name, id) in &tips {
+        if let Ok(commit) = revwalk::peel_to_commit(repo, id) {
+            if exported.contains(&commit) {
+                labels.entry(commit).or_insert_with(|| name.clone());
+            }
+        }
+    }
+    for (id, commit) in &commits {
+        let label = labels[id].clone();
+        for parent in &commit.parents {
+            if exported.contains(parent) {
+                labels.entry(*parent).or_insert_with(|| label.clone());
+            }
+        }
+    }
+    commits.reverse();
+
+    let mut marks: HashMap<ObjectId, usize> = HashMap::new();
+    for (id, commit) in &commits {
+        let marked: Vec<usize> = commit
+            .parents
+            .iter()
+            .filter_map(|parent| marks.get(parent).copied())
+            .collect();
+        let old = match commit.parents.first().filter(|p| marks.contains_key(*p)) {
+            Some(parent) => Tree::flatten(repo, &Commit::read(repo, parent)?.tree_sha)?,
+            None => BTreeMap::new(),
+        };
+        let new = Tree::flatten(repo, &commit.tree_sha)?;
+        let mut changes: Vec<Change> = old
+            .keys()
+            .filter(|path| !new.contains_key(*path))
+            .map(|path| (path.as_slice(), None))
+            .chain(
+                new.iter()
+                    .filter(|(path, entry)| old.get(*path) != Some(entry))
+                    .map(|(path, entry)| (path.as_slice(), Some(entry))),
+            )
+            .collect();
+        changes.sort_by(|a, b| depth_first(a.0, b.0));
+
+        for (_, entry) in &changes {
+            let Some((mode, blob)) = entry else { continue };
+            if *mode == GITLINK_MODE || marks.contains_key(blob) {
+                continue;
+            }
+            let mark = marks.len() + 1;
+            marks.insert(*blob, mark);
+            writeln!(out, "blob\nmark :{}", mark)?;
+            write_data(out, &Object::read(repo, blob)?.data)?;
+            out.write_all(b"\n")?;
+        }
+
+        let label = &labels[id];
+        if commit.parents.is_empty() {
+            writeln!(out, "reset {}", label)?;
+        }
+        let mark = marks.len() + 1;
+        marks.insert(*id, mark);
+        writeln!(out, "commit {}\nmark :{}", label, mark)?;
+        write_identity(out, "author", &commit.author)?;
+        write_identity(out, "committer", &commit.committer)?;
+        write_data(out, commit.message.as_bytes())?;
+        if !commit.message.ends_with('\n') {
+            out.write_all(b"\n")?;
+        }
+        for (i, parent) in marked.iter().enumerate() {
+            let kind = if i == 0 { "from" } else { "merge" };
+            writeln!(out, "{} :{}", kind, parent)?;
+        }
+        for (path, entry) in changes {
+            let path = export_path(path);
+            match entry {
+                Some((mode, blob)) if *mode == GITLINK_MODE => {
+                    writeln!(out, "M {:06o} {} {}", mode, blob, path)?
+                }
+                Some((mode, blob)) => writeln!(out, "M {:06o} :{} {}", mode, marks[blob], path)?,
+                None => writeln!(out, "D {}", path)?,
+            }
+        }
+        out.write_all(b"\n")?;
+    }
+
+    for (name, id) in &tips {
+        let obj = Object::read(repo, id)?;
+        if obj.header.kind == ObjectKind::Tag {
+            let tag: Tag = obj.try_into()?;
+            let target = match marks.get(&tag.object) {
+                Some(mark) => format!(":{}", mark),
+                None if tag.kind == ObjectKind::Commit => tag.object.to_hex(),
+                None => {
+                    let message = format!("Tag {} tags a {:?}, skipping it", name, tag.kind);
+                    repo.progress().warning(&message);
+                    continue;
+                }
+            };
+            let short = name.strip_prefix("refs/tags/").unwrap_or(name);
+            writeln!(out, "tag {}\nfrom {}", short, target)?;
+            if let Some(tagger) = &tag.tagger {
+                write_identity(out, "tagger", tagger)?;
+            }
+            write_data(out, tag.message.as_bytes())?;
+            out.write_all(b"\n")?;
+        } else if let Some(mark) = marks.get(id) {
+            if labels[id] != *name {
+                write!(out, "reset {}\nfrom :{}\n\n", name, mark)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reader of the lines and data blocks of the stream
+struct Stream<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Stream<'a> {
+    /// Raw bytes of the next line, paths of file changes are not necessarily UTF-8
+    fn peek_raw(&self) -> Option<&'a [u8]> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+        let rest = &self.data[self.pos..];
+        let end = rest.iter().position(|b| *b == b'\n').unwrap_or(rest.len());
+        Some(&rest[..end])
+    }
+
+    fn peek_line(&self) -> Option<String> {
+        let line = self.peek_raw()?;
+        Some(String::from_utf8_lossy(line).into_owned())
+    }
+
+    fn next_line(&mut self) -> Option<&'a [u8]> {
+        let line = self.peek_raw()?;
+        self.pos += line.len() + 1;
+        Some(line)
+    }
+
+    /// Consumes the next line when it starts with the command, returning its argument
+    fn next_command(&mut self, command: &str) -> Option<String> {
+        let line = self.peek_line()?;
+        let argument = match line.strip_prefix(command) {
+            Some("") => "",
+            Some(rest) => rest.strip_prefix(' ')?,
+            None => return None,
+        };
+        let argument = argument.to_string();
+        self.next_line();
+        Some(argument)
+    }
+
+    /// Reads `data <count>` with exactly counted bytes or `data <<<delimiter>` ended by
+    /// line with the delimiter
+    fn read_data(&mut self) -> Result<Vec<u8>> {
+        let spec = self
+            .next_command("data")
+            .ok_or(format_err!("Expected 'data' command"))?;
+        if let Some(delimiter) = spec.strip_prefix("<<") {
+            let mut data = Vec::new();
+            loop {
+                let line = self.next_line().ok_or(format_err!(
+                    "EOF in data (terminator '{}' not found)",
+                    delimiter
+                ))?;
+                if line == delimiter.as_bytes() {
+                    return Ok(data);
+                }
+                data.extend(line);
+                data.push(b'\n');
+            }
+        }
+        let len: usize = spec
+            .parse()
+            .with_context(|| format!("Invalid data length {}", spec))?;
+        let end = self.pos + len;
+        ensure!(
+            end <= self.data.len(),
+            "EOF in data ({} bytes remaining)",
+            len
+        );
+        let data = self.data[self.pos..end].to_vec();
+        self.pos = end;
+        if self.data.get(self.pos) == Some(&b'\n') {
+            self.pos += 1;
+        }
+        Ok(data)
+    }
+
+    fn skip_optional_lf(&mut self) {
+        if self.data.get(self.pos) == Some(&b'\n') {
+            self.pos += 1;
+        }
+    }
+}
+
+/// Splits path argument of file command, quoted or ended by space when `first` of two
+fn split_path(text: &[u8], first: bool) -> Result<(Vec<u8>, &[u8])> {
+    if let Some((path, rest)) = pathname::unquote(text) {
+        return Ok((path, rest.strip_prefix(b" ").unwrap_or(rest)));
+    }
+    if !first {
+        return Ok((text.to_vec(), b""));
+    }
+    split_word(text)
+        .map(|(path, rest)| (path.to_vec(), rest))
+        .ok_or(format_err!(
+            "Missing destination path in {:?}",
+            pathname::display(text)
+        ))
+}
+
+/// Splits the text at the first space
+fn split_word(text: &[u8]) -> Option<(&[u8], &[u8])> {
+    let pos = text.iter().position(|b| *b == b' ')?;
+    Some((&text[..pos], &text[pos + 1..]))
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(mode, 8).with_context(|| format!("Invalid mode {}", mode))?;
+    Ok(match mode {
+        0o644 => 0o100644,
+        0o755 => 0o100755,
+        0o100644 | 0o100755 | 0o120000 | GITLINK_MODE | TREE_MODE => mode,
+        _ => bail!("Invalid mode {:o}", mode),
+    })
+}
+
+/// Removes the path and everything under it
+fn remove_path(files: &mut BTreeMap<Vec<u8>, (u32, ObjectId)>, path: &[u8]) {
+    let prefix = pathname::concat(path, b"");
+    files.retain(|file, _| file != path && !file.starts_with(&prefix));
+}
+
+struct Importer<'a> {
+    repo: &'a GitRepo,
+    marks: HashMap<u64, ObjectId>,
+    /// Branches the stream touched, `None` after reset without commit
+    branches: BTreeMap<String, Option<ObjectId>>,
+    tags: BTreeMap<String, ObjectId>,
+}
+
+impl Importer<'_> {
+    fn parse_mark(&self, argument: &str) -> Result<u64> {
+        let mark = argument
+            .strip_prefix(':')
+            .and_then(|mark| mark.parse().ok());
+        mark.filter(|mark| *mark != 0)
+            .ok_or(format_err!("Invalid mark {:?}", argument))
+    }
+
+    /// Object of mark, branch of the stream or revision of the repository
+    fn resolve(&self, spec: &str) -> Result<ObjectId> {
+        if spec.starts_with(':') {
+            let mark = self.parse_mark(spec)?;
+            return self
+                .marks
+                .get(&mark)
+                .copied()
+                .ok_or(format_err!("mark :{} not declared", mark));
+        }
+        match self.branches.get(spec) {
+            Some(Some(id)) => Ok(*id),
+            Some(None) => bail!("Branch {} has no commits yet", spec),
+            None => revparse::resolve(self.repo, spec),
+        }
+    }
+
+    fn resolve_commit(&self, spec: &str) -> Result<ObjectId> {
+        revwalk::peel_to_commit(self.repo, &self.resolve(spec)?)
+    }
+
+    fn blob(&mut self, stream: &mut Stream) -> Result<()> {
+        let mark = stream.next_command("mark");
+        stream.next_command("original-oid");
+        let data = stream.read_data()?;
+        let obj = Object::new(self.repo.hash_algo(), ObjectKind::Blob, data);
+        obj.write(self.repo)?;
+        if let Some(mark) = mark {
+            self.marks.insert(self.parse_mark(&mark)?, obj.hash);
+        }
+        Ok(())
+    }
+
+    fn file_change(
+        &mut self,
+        stream: &mut Stream,
+        files: &mut BTreeMap<Vec<u8>, (u32, ObjectId)>,
+        line: &[u8],
+    ) -> Result<()> {
+        let invalid = || format_err!("Invalid file change {:?}", pathname::display(line));
+        let (command, rest) = split_word(line).unwrap_or((line, b""));
+        match command {
+            b"M" => {
+                let (mode, rest) = split_word(rest).ok_or_else(invalid)?;
+                let (dataref, path) = split_word(rest).ok_or_else(invalid)?;
+                let mode = parse_mode(&String::from_utf8_lossy(mode))?;
+                let dataref = &*String::from_utf8_lossy(dataref);
+                let (path, _) = split_path(path, false)?;
+                let id = match dataref {
+                    "inline" => {
+                        let obj = Object::new(
+                            self.repo.hash_algo(),
+                            ObjectKind::Blob,
+                            stream.read_data()?,
+                        );
+                        obj.write(self.repo)?;
+                        obj.hash
+                    }
+                    _ if mode == GITLINK_MODE && !dataref.starts_with(':') => dataref.parse()?,
+                    _ => self.resolve(dataref)?,
+                };
+                remove_path(files, &path);
+                if mode == TREE_MODE {
+                    for (file, entry) in Tree::flatten(self.repo, &id)? {
+                        files.insert(pathname::concat(&path, &file), entry);
+                    }
+                } else {
+                    if let Some((dir, _)) = pathname::split_last(&path) {
+                        // Parent directories replace files of the same name
+                        let slashes = (0..dir.len()).filter(|i| dir[*i] == b'/');
+                        for end in slashes.chain([dir.len()]) {
+                            files.remove(&dir[..end]);
+                        }
+                    }
+                    files.insert(path, (mode, id));
+                }
+            }
+            b"D" => remove_path(files, &split_path(rest, false)?.0),
+            b"C" | b"R" => {
+                let (source, rest) = split_path(rest, true)?;
+                let (target, _) = split_path(rest, false)?;
+                let prefix = pathname::concat(&source, b"");
+                let copied: Vec<(Vec<u8>, (u32, ObjectId))> = files
+                    .iter()
+                    .filter_map(|(file, entry)| match file.strip_prefix(prefix.as_slice()) {
+                        _ if *file == source => Some((target.clone(), *entry)),
+                        Some(rest) => Some((pathname::concat(&target, rest), *entry)),
+                        None => None,
+                    })
+                    .collect();
+                ensure!(
+                    !copied.is_empty(),
+                    "Path {} not in branch",
+                    pathname::display(&source)
+                );
+                if command == b"R" {
+                    remove_path(files, &source);
+                }
+                remove_path(files, &target);
+                files.extend(copied);
+            }
+            b"deleteall" => files.clear(),
+            _ => bail!("Unsupported file change {:?}", pathname::display(line)),
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self, stream: &mut Stream, branch: &str) -> Result<()> {
+        let mark = stream.next_command("mark");
+        stream.next_command("original-oid");
+        let author = stream.next_command("author");
+        let committer = stream
+            .next_command("committer")
+            .ok_or(format_err!("Expected committer of {}", branch))?;
+        let committer = Author::parse(&committer)?;
+        let author = match author {
+            Some(author) => Author::parse(&author)?,
+            None => committer.clone(),
+        };
+        let encoding = stream.next_command("encoding");
+        let message = stream.read_data()?;
+
+        let mut parents = Vec::new();
+        match stream.next_command("from") {
+            Some(from) if from.bytes().all(|b| b == b'0') => {}
+            Some(from) => parents.push(self.resolve_commit(&from)?),
+            None => parents.extend(self.branches.get(branch).cloned().flatten()),
+        }
+        while let Some(merge) = stream.next_command("merge") {
+            parents.push(self.resolve_commit(&merge)?);
+        }
+
+        let mut files = match parents.first() {
+            Some(parent) => {
+                let tree = Commit::read(self.repo, parent)?.tree_sha;
+                Tree::flatten(self.repo, &tree)?
+            }
+            None => BTreeMap::new(),
+        };
+        while let Some(line) = stream.peek_raw() {
+            let command = line.split(|b| *b == b' ').next().unwrap_or_default();
+            if !matches!(command, b"M" | b"D" | b"C" | b"R" | b"deleteall") {
+                break;
+            }
+            stream.next_line();
+            self.file_change(stream, &mut files, line)?;
+        }
+        stream.skip_optional_lf();
+
+        let mut index = Index::default();
+        for (path, (mode, id)) in &files {
+            index.add(IndexEntry::new(path, *mode, id, 0));
+        }
+        let mut extra_headers = Vec::new();
+        let message = match encoding {
+            Some(label) => {
+                let decoder = Encoding::from_label(&label)
+                    .ok_or(format_err!("Unsupported commit encoding {}", label))?;
+                extra_headers.push((String::from("encoding"), label));
+                decoder.decode(&message)
+            }
+            None => String::from_utf8_lossy(&message).into_owned(),
+        };
+        let id = Commit {
+            tree_sha: index.write_tree(self.repo)?,
+            parents,
+            author,
+            committer,
+            extra_headers,
+            message,
+            raw: None,
+        }
+        .write(self.repo)?;
+        if let Some(mark) = mark {
+            self.marks.insert(self.parse_mark(&mark)?, id);
+        }
+        self.branches.insert(branch.to_string(), Some(id));
+        Ok(())
+    }
+
+    fn tag(&mut self, stream: &mut Stream, name: &str) -> Result<()> {
+        let mark = stream.next_command("mark");
+        let from = stream
+            .next_command("from")
+            .ok_or(format_err!("Expected from command of tag {}", name))?;
+        stream.next_command("original-oid");
+        let tagger = stream.next_command("tagger");
+        let message = stream.read_data()?;
+        let object = self.resolve(&from)?;
+        let tag = Tag {
+            kind: Object::read_header(self.repo, &object)?.kind,
+            object,
+            name: name.to_string(),
+            tagger: tagger.as_deref().map(Author::parse).transpose()?,
+            message: String::from_utf8_lossy(&message).into_owned(),
+        };
+        let id = tag.write(self.repo)?;
+        if let Some(mark) = mark {
+            self.marks.insert(self.parse_mark(&mark)?, id);
+        }
+        self.tags.insert(format!("refs/tags/{}", name), id);
+        Ok(())
+    }
+
+    /// Points the refs to the imported objects. Branches which would lose commits are
+    /// left alone unless forced, tags are always overwritten.
+    fn update_refs(&self, force: bool) -> Result<()> {
+        let mut transaction = RefTransaction::new();
+        let mut refused = 0;
+        let branches = self
+            .branches
+            .iter()
+            .filter_map(|(name, id)| Some((name, id.as_ref()?)));
+        for (name, id) in branches {
+            let current = refs::resolve(self.repo, name)?;
+            if let Some(current) = current.as_ref().filter(|current| *current != id) {
+                let is_commit = Object::read_header(self.repo, current)
+                    .is_ok_and(|header| header.kind == ObjectKind::Commit);
+                if !force && is_commit && !revwalk::is_ancestor(self.repo, current, id)? {
+                    let message = format!(
+                        "Not updating {} (new tip {} does not contain {})",
+                        name, id, current
+                    );
+                    self.repo.progress().warning(&message);
+                    refused += 1;
+                    continue;
+                }
+            }
+            transaction.update(name, current.as_ref(), Some(id), "fast-import");
+        }
+        for (name, id) in &self.tags {
+            transaction.force_update(name, Some(id), "fast-import");
+        }
+        transaction.commit(self.repo)?;
+        ensure!(refused == 0, "{} refs were not updated", refused);
+        Ok(())
+    }
+}
+
+/// Reads fast-import stream, storing its objects and updating refs to the imported
+/// commits and tags. Output of `progress` commands is written to `out`.
+pub fn import(repo: &GitRepo, input: &[u8], force: bool, out: &mut impl Write) -> Result<()> {
+    let mut stream = Stream {
+        data: input,
+        pos: 0,
+    };
+    let mut importer = Importer {
+        repo,
+        marks: HashMap::new(),
+        branches: BTreeMap::new(),
+        tags: BTreeMap::new(),
+    };
+    let mut force = force;
+    let mut require_done = false;
+    let mut done = false;
+    while let Some(line) = stream.next_line() {
+        let line = String::from_utf8_lossy(line);
+        let (command, argument) = line.split_once(' ').unwrap_or((&line, ""));
+        match command {
+            "" => {}
+            _ if command.starts_with('#') => {}
+            "blob" => importer.blob(&mut stream)?,
+            "commit" => importer.commit(&mut stream, argument)?,
+            "tag" => importer.tag(&mut stream, argument)?,
+            "reset" => {
+                let id = match stream.next_command("from") {
+                    Some(from) => Some(importer.resolve_commit(&from)?),
+                    None => None,
+                };
+                importer.branches.insert(argument.to_string(), id);
+                stream.skip_optional_lf();
+            }
+            "progress" => writeln!(out, "progress {}", argument)?,
+            "checkpoint" => stream.skip_optional_lf(),
+            "feature" => match argument.split_once('=').unwrap_or((argument, "")) {
+                ("done", _) => require_done = true,
+                ("force", _) => force = true,
+                ("date-format", "raw") => {}
+                _ => bail!(
+                    "This version of fast-import does not support feature {}.",
+                    argument
+                ),
+            },
+            // Options of other tools and of git itself only tune messages and limits
+            "option" => {}
+            "done" => {
+                done = true;
+                break;
+            }
+            _ => bail!("Unsupported command: {}", line),
+        }
+    }
+    ensure!(done || !require_done, "stream ends early");
+    importer.update_refs(force)
+}
@@ -0,0 +1,392 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use flate2::read::ZlibDecoder;
+
+use crate::commit::Commit;
+use crate::config::Config;
+use crate::error::{bail, ensure, format_err, Context, Error, Result};
+use crate::hash::HashAlgo;
+use crate::index::Index;
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::pack::{self, index::PackIndex};
+use crate::submodule::GITLINK_MODE;
+use crate::tag::Tag;
+use crate::tree::{FileMode, Tree, TreeEntry};
+use crate::worktree;
+use crate::{reflog, refs, GitRepo};
+
+/// Result of checking the object database
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Broken objects and links in git's wording, e.g. `error in tree <id>: ...`
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+    /// Objects referenced by other objects or refs which are not in the repository
+    pub missing: BTreeMap<ObjectId, ObjectKind>,
+    /// Objects not reachable from refs, reflogs or the index
+    pub unreachable: BTreeMap<ObjectId, ObjectKind>,
+    /// Unreachable objects not referenced even by other unreachable objects
+    pub dangling: BTreeMap<ObjectId, ObjectKind>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Object which passed hash verification, with links to other objects and their
+/// expected kinds
+struct Checked {
+    kind: ObjectKind,
+    links: Vec<(ObjectId, ObjectKind)>,
+}
+
+/// Inflates loose object, checking its header and hash
+fn read_loose(path: &Path, algo: HashAlgo) -> Result<Object> {
+    let mut buf = Vec::new();
+    ZlibDecoder::new(File::open(path)?)
+        .read_to_end(&mut buf)
+        .context("unable to unpack header")?;
+    let split = buf
+        .iter()
+        .position(|c| *c == 0)
+        .ok_or(format_err!("unable to parse header"))?;
+    let header = std::str::from_utf8(&buf[..split]).context("unable to parse header")?;
+    let (kind, size) = header
+        .split_once(' ')
+        .ok_or(format_err!("unable to parse header"))?;
+    let kind: ObjectKind = kind.parse()?;
+    let size: usize = size.parse().context("unable to parse header")?;
+    let data = buf.split_off(split + 1);
+    ensure!(data.len() == size, "object size mismatch");
+    Ok(Object::new(algo, kind, data))
+}
+
+struct Fsck<'a> {
+    repo: &'a GitRepo,
+    report: Report,
+    objects: BTreeMap<ObjectId, Checked>,
+}
+
+impl Fsck<'_> {
+    fn error(&mut self, kind: ObjectKind, id: &ObjectId, message: impl std::fmt::Display) {
+        self.report
+            .errors
+            .push(format!("error in {} {}: {}", kind.as_str(), id, message));
+    }
+
+    fn warning(&mut self, kind: ObjectKind, id: &ObjectId, message: &str) {
+        self.report
+            .warnings
+            .push(format!("warning in {} {}: {}", kind.as_str(), id, message));
+    }
+
+    /// Validates structure of the object and collects its links
+    fn check_object(&mut self, obj: Object) {
+        let (id, kind) = (obj.hash, obj.header.kind);
+        let links = match kind {
+            ObjectKind::Blob => Ok(Vec::new()),
+            ObjectKind::Commit => Commit::try_from(obj).map(|commit| {
+                let mut links = vec![(commit.tree_sha, ObjectKind::Tree)];
+                links.extend(commit.parents.into_iter().map(|p| (p, ObjectKind::Commit)));
+                links
+            }),
+            ObjectKind::Tag => Tag::try_from(obj).map(|tag| vec![(tag.object, tag.kind)]),
+            ObjectKind::Tree => {
+                Tree::raw_entries(&obj).map(|entries| self.check_tree(&id, entries))
+            }
+        };
+        match links {
+            Ok(links) => {
+                self.objects.insert(id, Checked { kind, links });
+            }
+            Err(err) => {
+                self.error(kind, &id, err.chain().to_string());
+                self.objects.insert(
+                    id,
+                    Checked {
+                        kind,
+                        links: Vec::new(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Checks modes, names and order of tree entries, returns links of the tree
+    fn check_tree(
+        &mut self,
+        id: &ObjectId,
+        entries: Vec<(String, Vec<u8>, ObjectId)>,
+    ) -> Vec<(ObjectId, ObjectKind)> {
+        let mut links = Vec::new();
+        let mut previous: Option<TreeEntry> = None;
+        // Duplicates need not be adjacent, `a.b` sorts between file `a` and tree `a`
+        let mut names = HashSet::new();
+        let (mut zero_padded, mut bad_mode, mut bad_name) = (false, false, false);
+        let (mut unsorted, mut duplicate) = (false, false);
+        for (mode, name, reference) in entries {
+            zero_padded |= mode.starts_with('0');
+            let kind = mode.parse().ok().map(FileMode::kind);
+            bad_mode |= kind.is_none();
+            bad_name |= !TreeEntry::is_valid_name(&name);
+            // Only trees are ordered differently, bad modes are sorted as files
+            let entry = TreeEntry {
+                mode: match kind {
+                    Some(ObjectKind::Tree) => FileMode::Tree,
+                    _ => FileMode::Regular,
+                },
+                name,
+                reference,
+            };
+            if let Some(previous) = &previous {
+                unsorted |= previous.tree_order(&entry) != Ordering::Less;
+            }
+            duplicate |= !names.insert(entry.name.clone());
+            // Gitlinks point to commits in other repositories
+            if let Some(kind) = kind.filter(|kind| *kind != ObjectKind::Commit) {
+                links.push((entry.reference, kind));
+            }
+            previous = Some(entry);
+        }
+        if zero_padded {
+            self.warning(
+                ObjectKind::Tree,
+                id,
+                "zeroPaddedFilemode: contains zero-padded file modes",
+            );
+        }
+        if bad_mode {
+            self.error(ObjectKind::Tree, id, "badFilemode: contains bad file modes");
+        }
+        if bad_name {
+            self.error(
+                ObjectKind::Tree,
+                id,
+                "badTreeEntryName: contains '.', '..', '.git' or names with '/'",
+            );
+        }
+        if duplicate {
+            self.error(
+                ObjectKind::Tree,
+                id,
+                "duplicateEntries: contains duplicate file entries",
+            );
+        } else if unsorted {
+            self.error(ObjectKind::Tree, id, "treeNotSorted: not properly sorted");
+        }
+        links
+    }
+
+    /// Checks loose objects of the repository and its alternates
+    fn check_loose(&mut self) -> Result<()> {
+        for objects_dir in self.repo.object_dirs() {
+            for id in Object::loose_ids_in(objects_dir)? {
+                let hex = id.to_hex();
+                let path = objects_dir.join(&hex[..2]).join(&hex[2..]);
+                match read_loose(&path, self.repo.hash_algo()) {
+                    // Treated as missing, objects linking to it are broken
+                    Ok(obj) if obj.hash != id => self.report.errors.push(format!(
+                        "error: {}: hash-path mismatch, found at: {}",
+                        obj.hash,
+                        path.display()
+                    )),
+                    Ok(obj) => self.check_object(obj),
+                    Err(err) => self.report.errors.push(format!(
+                        "error: {}: object corrupt or missing: {}",
+                        id,
+                        err.chain()
+                    )),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks packed objects of the repository and its alternates
+    fn check_packed(&mut self) -> Result<()> {
+        let mut packs = Vec::new();
+        for objects_dir in self.repo.object_dirs() {
+            packs.extend(pack::pack_indexes_in(objects_dir)?);
+        }
+        for idx_path in packs {
+            let mut idx = PackIndex::open(&idx_path, self.repo.hash_algo())?;
+            for i in 0..idx.len() {
+                let id = idx.id_at(i)?;
+                if self.objects.contains_key(&id) {
+                    continue;
+                }
+                match pack::read_object(self.repo, &id) {
+                    Ok(Some((kind, data))) => {
+                        let obj = Object::new(self.repo.hash_algo(), kind, data);
+                        if obj.hash == id {
+                            self.check_object(obj);
+                        } else {
+                            self.report.errors.push(format!(
+                                "error: packed {} from {} is corrupt",
+                                id,
+                                idx_path.with_extension("pack").display()
+                            ));
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(err) => self.report.errors.push(format!(
+                        "error: cannot unpack {} from {}: {}",
+                        id,
+                        idx_path.with_extension("pack").display(),
+                        err.chain()
+                    )),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports links to missing objects or objects of unexpected kind
+    fn check_links(&mut self) {
+        let mut errors = Vec::new();
+        for (id, obj) in &self.objects {
+            for (target, expected) in &obj.links {
+                let broken = match self.objects.get(target) {
+                    None => {
+                        self.report.missing.insert(*target, *expected);
+                        true
+                    }
+                    Some(found) => found.kind != *expected,
+                };
+                if broken {
+                    errors.push(format!(
+                        "broken link from {:>7} {}\n              to {:>7} {}",
+                        obj.kind.as_str(),
+                        id,
+                        expected.as_str(),
+                        target
+                    ));
+                }
+            }
+        }
+        self.report.errors.extend(errors);
+    }
+
+    fn check_reachability(&mut self) -> Result<()> {
+        let mut reachable = BTreeSet::new();
+        let mut stack: Vec<ObjectId> = roots(self.repo)?.into_iter().collect();
+        while let Some(id) = stack.pop() {
+            let Some(obj) = self.objects.get(&id) else {
+                continue;
+            };
+            if reachable.insert(id) {
+                stack.extend(obj.links.iter().map(|(target, _)| *target));
+            }
+        }
+        let referenced: BTreeSet<&ObjectId> = self
+            .objects
+            .values()
+            .flat_map(|obj| obj.links.iter().map(|(target, _)| target))
+            .collect();
+        for (id, obj) in &self.objects {
+            if reachable.contains(id) {
+                continue;
+            }
+            self.report.unreachable.insert(*id, obj.kind);
+            if !referenced.contains(id) {
+                self.report.dangling.insert(*id, obj.kind);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Objects the repository keeps alive: refs, HEADs, reflogs and indexes of all
+/// working trees
+pub fn roots(repo: &GitRepo) -> Result<BTreeSet<ObjectId>> {
+    let mut roots = BTreeSet::new();
+    let add_reflog = |repo: &GitRepo, name: &str, roots: &mut BTreeSet<ObjectId>| {
+        for entry in reflog::read(repo, name)? {
+            roots.extend(
+                [entry.old, entry.new]
+                    .into_iter()
+                    .filter(|id| !id.is_zero()),
+            );
+        }
+        Ok::<_, Error>(())
+    };
+    for (name, id) in refs::list(repo)? {
+        roots.insert(id);
+        add_reflog(repo, &name, &mut roots)?;
+    }
+    let mut repos = vec![repo.clone()];
+    for worktree in worktree::list(repo)?.into_iter().filter(|w| !w.is_main()) {
+        repos.push(GitRepo::with_git_dir(&worktree.path, &worktree.git_dir));
+    }
+    for repo in &repos {
+        roots.extend(refs::resolve(repo, "HEAD")?);
+        add_reflog(repo, "HEAD", &mut roots)?;
+        for entry in Index::read(repo)?.entries {
+            if entry.mode != GITLINK_MODE {
+                roots.insert(entry.id);
+            }
+        }
+    }
+    Ok(roots)
+}
+
+/// Whether objects received by the operation (`fetch` or `receive`) are verified,
+/// `<operation>.fsckObjects` falling back to `transfer.fsckObjects`
+pub fn transfer_enabled(repo: &GitRepo, operation: &str) -> Result<bool> {
+    let config = Config::load(repo)?;
+    let specific = config.get_bool(&format!("{}.fsckObjects", operation))?;
+    Ok(specific
+        .or(config.get_bool("transfer.fsckObjects")?)
+        .unwrap_or(false))
+}
+
+/// Verifies structure of single object, e.g. one received from another repository
+pub fn verify_object(repo: &GitRepo, obj: &Object) -> Result<()> {
+    let mut fsck = Fsck {
+        repo,
+        report: Report::default(),
+        objects: BTreeMap::new(),
+    };
+    fsck.check_object(obj.clone());
+    match fsck.report.errors.into_iter().next() {
+        Some(error) => bail!("{}", error),
+        None => Ok(()),
+    }
+}
+
+/// Checks that all objects reachable from `tips` are present. Walk stops at objects in
+/// `complete`, which are known to have all their history.
+pub fn check_connected(repo: &GitRepo, tips: &[ObjectId], complete: &[ObjectId]) -> Result<()> {
+    let mut seen: HashSet<ObjectId> = complete.iter().copied().collect();
+    let mut stack = tips.to_vec();
+    while let Some(id) = stack.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        let obj = Object::read(repo, &id).with_context(|| format!("missing object {}", id))?;
+        stack.extend(obj.links()?);
+    }
+    Ok(())
+}
+
+/// Verifies hashes and structure of all loose and packed objects, links between them and
+/// finds objects which are not reachable
+pub fn check(repo: &GitRepo) -> Result<Report> {
+    let mut fsck = Fsck {
+        repo,
+        report: Report::default(),
+        objects: BTreeMap::new(),
+    };
+    fsck.check_loose()?;
+    fsck.check_packed()?;
+    fsck.check_links();
+    fsck.check_reachability()?;
+    Ok(fsck.report)
+}
@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::date;
+use crate::error::{Context, Result};
+use crate::fsck;
+use crate::hash::HashAlgo;
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::pack::{self, index::PackIndex};
+use crate::GitRepo;
+
+/// Files accompanying pack, removed together with it
+const PACK_EXTENSIONS: [&str; 4] = ["pack", "idx", "rev", "bitmap"];
+
+/// How `gc` repacks and prunes the repository
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    /// Unreachable objects older than this unix time are removed, `None` keeps all
+    pub prune_expire: Option<i64>,
+    /// Number of preceding objects tried as delta bases
+    pub window: usize,
+    /// Write reachability bitmaps for the new pack, `repack.writeBitmaps`
+    pub write_bitmaps: bool,
+}
+
+impl GcOptions {
+    /// Options from `gc.pruneExpire` (2 weeks by default), `repack.writeBitmaps` and
+    /// `pack.window`, or `gc.aggressiveWindow` when `aggressive`. `prune` overrides the
+    /// configured expiry.
+    pub fn load(repo: &GitRepo, aggressive: bool, prune: Option<&str>) -> Result<Self> {
+        let config = Config::load(repo)?;
+        let expiry = prune
+            .or(config.get("gc.pruneExpire"))
+            .unwrap_or("2.weeks.ago");
+        let window = if aggressive {
+            config.get_int("gc.aggressiveWindow")?.unwrap_or(250)
+        } else {
+            config.get_int("pack.window")?.unwrap_or(10)
+        };
+        Ok(Self {
+            prune_expire: date::parse_expiry(expiry, now()?)?,
+            window: usize::try_from(window).context("Invalid delta window")?,
+            write_bitmaps: config.get_bool("repack.writeBitmaps")?.unwrap_or(false),
+        })
+    }
+}
+
+fn now() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+/// Modification time of the file as unix time
+fn mtime(path: &Path) -> Result<i64> {
+    let modified = fs::metadata(path)?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+fn is_expired(path: &Path, expire: Option<i64>) -> Result<bool> {
+    match expire {
+        Some(expire) => Ok(mtime(path)? <= expire),
+        None => Ok(false),
+    }
+}
+
+/// Removes the pack together with its index and other accompanying files
+fn remove_pack(idx_path: &Path) -> Result<()> {
+    for extension in PACK_EXTENSIONS {
+        let path = idx_path.with_extension(extension);
+        if path.exists() {
+            fs::remove_file(&path).with_context(|| format!("Removing {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Space the file takes on disk
+#[cfg(unix)]
+fn disk_usage(meta: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn disk_usage(meta: &fs::Metadata) -> u64 {
+    meta.len()
+}
+
+/// Objects reachable from refs, reflogs and indexes
+fn reachable_objects(repo: &GitRepo) -> Result<BTreeMap<ObjectId, Object>> {
+    let mut objects = BTreeMap::new();
+    let mut stack: Vec<ObjectId> = fsck::roots(repo)?.into_iter().collect();
+    while let Some(id) = stack.pop() {
+        if objects.contains_key(&id) {
+            continue;
+        }
+        let obj = Object::read(repo, &id).with_context(|| format!("unable to read {}", id))?;
+        stack.extend(obj.links()?);
+        objects.insert(id, obj);
+    }
+    Ok(objects)
+}
+
+/// Packs all reachable objects into single pack with deltas, replacing other packs and
+/// removing loose copies. Unreachable objects older than the expiry are pruned, recent
+/// ones are kept as loose objects. Packs with `.keep` file are left untouched.
+pub fn gc(repo: &GitRepo, options: &GcOptions) -> Result<()> {
+    let (kept, packs): (Vec<_>, Vec<_>) = pack::pack_indexes(repo)?
+        .into_iter()
+        .partition(|idx| idx.with_extension("keep").exists());
+    let mut kept_ids = HashSet::new();
+    for idx_path in &kept {
+        let mut idx = PackIndex::open(idx_path, repo.hash_algo())?;
+        for i in 0..idx.len() {
+            kept_ids.insert(idx.id_at(i)?);
+        }
+    }
+
+    let reachable = reachable_objects(repo)?;
+
+    // Unreachable objects of recent packs survive as loose objects until they expire
+    let mut loosened = Vec::new();
+    for idx_path in &packs {
+        if is_expired(&idx_path.with_extension("pack"), options.prune_expire)? {
+            continue;
+        }
+        let mut idx = PackIndex::open(idx_path, repo.hash_algo())?;
+        for i in 0..idx.len() {
+            let id = idx.id_at(i)?;
+            if !reachable.contains_key(&id) && !kept_ids.contains(&id) {
+                loosened.push(Object::read(repo, &id)?);
+            }
+        }
+    }
+
+    // Objects borrowed from alternates stay there
+    let mut objects = Vec::new();
+    for (id, obj) in reachable {
+        let local =
+            Object::loose_path(repo, &id).is_file() || pack::contains_in(&repo.objects_dir, &id)?;
+        if local && !kept_ids.contains(&id) {
+            objects.push(obj);
+        }
+    }
+    let packed: HashSet<ObjectId> = objects
+        .iter()
+        .map(|obj| obj.hash)
+        .chain(kept_ids.iter().copied())
+        .collect();
+    let new_pack = if objects.is_empty() {
+        None
+    } else {
+        let level = repo.write_options()?.pack_compression;
+        let data = pack::write_pack_with_deltas(&objects, level, options.window, repo.hash_algo())?;
+        let checksum = pack::index::index_pack(repo, &data, false)?.checksum;
+        // Bitmaps need all reachable objects in the single pack, and are SHA-1 only
+        if options.write_bitmaps
+            && kept.is_empty()
+            && repo.object_dirs().len() == 1
+            && repo.hash_algo() == HashAlgo::Sha1
+        {
+            let idx_path = repo
+                .objects_dir
+                .join("pack")
+                .join(format!("pack-{}.idx", checksum));
+            pack::bitmap::write(repo, &idx_path, &objects)?;
+        }
+        Some(checksum)
+    };
+    for idx_path in &packs {
+        let is_new = new_pack.as_ref().is_some_and(|checksum| {
+            idx_path.file_stem().and_then(|s| s.to_str()) == Some(&format!("pack-{}", checksum))
+        });
+        if !is_new {
+            remove_pack(idx_path)?;
+        }
+    }
+    // Multi-pack-index refers to the removed packs, it is rewritten for the new ones
+    if pack::midx::path(repo).exists() {
+        fs::remove_file(pack::midx::path(repo)).context("Removing multi-pack-index")?;
+        if new_pack.is_some() || !kept.is_empty() {
+            pack::midx::write(repo)?;
+        }
+    }
+    for obj in &loosened {
+        obj.write(repo)?;
+    }
+
+    for id in Object::loose_ids(repo)? {
+        let path = Object::loose_path(repo, &id);
+        if packed.contains(&id) || is_expired(&path, options.prune_expire)? {
+            fs::remove_file(&path).with_context(|| format!("Removing {}", path.display()))?;
+        }
+    }
+    // Fan-out directories left empty
+    for item in fs::read_dir(&repo.objects_dir)? {
+        let path = item?.path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if name.len() == 2 && name.bytes().all(|b| b.is_ascii_hexdigit()) {
+            let _ = fs::remove_dir(&path);
+        }
+    }
+    Ok(())
+}
+
+/// Deletes unreachable loose objects older than the expiry, all of them when `expire` is
+/// `None`, and loose objects which are also packed. Objects reachable from reflogs are
+/// kept. Returns the pruned unreachable objects, with `dry_run` nothing is deleted.
+pub fn prune(
+    repo: &GitRepo,
+    expire: Option<i64>,
+    dry_run: bool,
+) -> Result<Vec<(ObjectId, ObjectKind)>> {
+    let reachable = reachable_objects(repo)?;
+    let mut pruned = Vec::new();
+    for id in Object::loose_ids(repo)? {
+        let path = Object::loose_path(repo, &id);
+        let remove = if reachable.contains_key(&id) {
+            pack::contains(repo, &id)?
+        } else if expire.is_none() || is_expired(&path, expire)? {
+            pruned.push((id, Object::read(repo, &id)?.header.kind));
+            true
+        } else {
+            false
+        };
+        if remove && !dry_run {
+            fs::remove_file(&path).with_context(|| format!("Removing {}", path.display()))?;
+        }
+    }
+    Ok(pruned)
+}
+
+/// Statistics of the object database reported by `count-objects`, sizes in bytes
+#[derive(Debug, Default)]
+pub struct ObjectCounts {
+    pub loose: usize,
+    pub loose_size: u64,
+    pub in_pack: usize,
+    pub packs: usize,
+    /// Size of packs together with their indexes
+    pub pack_size: u64,
+    /// Loose objects which are also packed
+    pub prune_packable: usize,
+    /// Files in the object directories which are neither objects nor packs
+    pub garbage: usize,
+    pub garbage_size: u64,
+}
+
+/// Counts loose and packed objects and the space they take on disk
+pub fn count_objects(repo: &GitRepo) -> Result<ObjectCounts> {
+    let mut counts = ObjectCounts::default();
+    for id in Object::loose_ids(repo)? {
+        counts.loose += 1;
+        counts.loose_size += disk_usage(&fs::metadata(Object::loose_path(repo, &id))?);
+        if pack::contains(repo, &id)? {
+            counts.prune_packable += 1;
+        }
+    }
+    for idx_path in pack::pack_indexes(repo)? {
+        let pack_path = idx_path.with_extension("pack");
+        if !pack_path.is_file() {
+            continue;
+        }
+        counts.packs += 1;
+        counts.in_pack += PackIndex::open(&idx_path, repo.hash_algo())?.len();
+        // Packs are counted by their length unlike loose objects, as git does
+        counts.pack_size += fs::metadata(&pack_path)?.len();
+        counts.pack_size += fs::metadata(&idx_path)?.len();
+    }
+    let pack_dir = repo.objects_dir.join("pack");
+    if let Ok(items) = fs::read_dir(&pack_dir) {
+        for item in items {
+            let path = item?.path();
+            let extension = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or_default();
+            let known = match extension {
+                "pack" | "idx" => {
+                    path.with_extension("pack").is_file() && path.with_extension("idx").is_file()
+                }
+                "keep" | "rev" | "bitmap" | "promisor" => true,
+                _ => false,
+            };
+            if !known {
+                counts.garbage += 1;
+                counts.garbage_size += disk_usage(&fs::metadata(&path)?);
+            }
+        }
+    }
+    Ok(counts)
+}
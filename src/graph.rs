@@ -0,0 +1,605 @@
+use crate::oid::ObjectId;
+
+/// ANSI colors of lanes, in the order they are assigned
+const COLUMN_COLORS: [&str; 12] = [
+    "\x1b[31m",
+    "\x1b[32m",
+    "\x1b[33m",
+    "\x1b[34m",
+    "\x1b[35m",
+    "\x1b[36m",
+    "\x1b[1;31m",
+    "\x1b[1;32m",
+    "\x1b[1;33m",
+    "\x1b[1;34m",
+    "\x1b[1;35m",
+    "\x1b[1;36m",
+];
+const COLOR_RESET: &str = "\x1b[m";
+/// Color of lanes drawn without color
+const NO_COLOR: usize = COLUMN_COLORS.len();
+
+/// What the next line of the graph shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Lanes going straight down, after the commit is done
+    Padding,
+    /// Marker of commits left out of the output
+    Skip,
+    /// Widening of lanes right of an octopus merge to make room for it
+    PreCommit,
+    /// The line with the commit itself
+    Commit,
+    /// Edges from a merge to its parents
+    PostMerge,
+    /// Lanes moving left to their new positions
+    Collapsing,
+}
+
+/// Lane of the graph, leading to the commit
+#[derive(Debug, Clone)]
+struct Column {
+    commit: ObjectId,
+    color: usize,
+}
+
+/// Line of graph output with its width on the screen, not counting color codes
+#[derive(Default)]
+struct Line {
+    text: String,
+    width: usize,
+}
+
+impl Line {
+    fn push(&mut self, c: char) {
+        self.text.push(c);
+        self.width += 1;
+    }
+
+    fn push_n(&mut self, c: char, n: usize) {
+        for _ in 0..n {
+            self.push(c);
+        }
+    }
+
+    fn column(&mut self, column: &Column, c: char) {
+        match COLUMN_COLORS.get(column.color) {
+            Some(color) => {
+                self.text.push_str(color);
+                self.text.push(c);
+                self.text.push_str(COLOR_RESET);
+                self.width += 1;
+            }
+            None => self.push(c),
+        }
+    }
+}
+
+/// Lane graph drawn left of commits by `log --graph`, produced line by line with the
+/// same layout as git. Each commit is passed with its parents which are shown too,
+/// in the order the commits are shown, which must be topological.
+#[derive(Debug)]
+pub struct Graph {
+    commit: ObjectId,
+    parents: Vec<ObjectId>,
+    /// Width of the widest line of the current commit
+    width: usize,
+    expansion_row: usize,
+    state: State,
+    prev_state: State,
+    commit_index: usize,
+    prev_commit_index: usize,
+    /// Whether the first parent of a merge is left of it (0) or right below it (1)
+    merge_layout: i32,
+    /// Lanes added right of the commit by a merge
+    edges_added: i32,
+    prev_edges_added: i32,
+    /// Lanes above the current commit
+    columns: Vec<Column>,
+    /// Lanes below the current commit
+    new_columns: Vec<Column>,
+    /// Lane of `new_columns` each screen column of the current line leads to
+    mapping: Vec<i32>,
+    old_mapping: Vec<i32>,
+    mapping_size: usize,
+    default_color: usize,
+    colors: bool,
+}
+
+impl Graph {
+    pub fn new(colors: bool) -> Self {
+        Self {
+            commit: ObjectId::ZERO,
+            parents: Vec::new(),
+            width: 0,
+            expansion_row: 0,
+            state: State::Padding,
+            prev_state: State::Padding,
+            commit_index: 0,
+            prev_commit_index: 0,
+            merge_layout: 0,
+            edges_added: 0,
+            prev_edges_added: 0,
+            columns: Vec::new(),
+            new_columns: Vec::new(),
+            mapping: Vec::new(),
+            old_mapping: Vec::new(),
+            mapping_size: 0,
+            default_color: NO_COLOR - 1,
+            colors,
+        }
+    }
+
+    /// Starts output of the next commit
+    pub fn update(&mut self, commit: &ObjectId, parents: Vec<ObjectId>) {
+        self.commit = *commit;
+        self.parents = parents;
+        self.prev_commit_index = self.commit_index;
+        self.update_columns();
+        self.expansion_row = 0;
+        // Previous commit which did not finish its output left part of the graph out
+        self.state = if self.state != State::Padding {
+            State::Skip
+        } else if self.needs_pre_commit_line() {
+            State::PreCommit
+        } else {
+            State::Commit
+        };
+    }
+
+    /// Whether all lines leading to the commit were shown, so the next commit can be
+    pub fn is_commit_finished(&self) -> bool {
+        self.state == State::Padding
+    }
+
+    fn set_state(&mut self, state: State) {
+        self.prev_state = self.state;
+        self.state = state;
+    }
+
+    fn current_color(&self) -> usize {
+        if self.colors {
+            self.default_color
+        } else {
+            NO_COLOR
+        }
+    }
+
+    fn increment_color(&mut self) {
+        self.default_color = (self.default_color + 1) % NO_COLOR;
+    }
+
+    fn commit_color(&self, commit: &ObjectId) -> usize {
+        self.columns
+            .iter()
+            .find(|column| column.commit == *commit)
+            .map_or_else(|| self.current_color(), |column| column.color)
+    }
+
+    fn new_column_of(&self, commit: &ObjectId) -> Option<usize> {
+        self.new_columns.iter().position(|c| c.commit == *commit)
+    }
+
+    fn mapping_at(&self, i: i32) -> i32 {
+        usize::try_from(i)
+            .ok()
+            .and_then(|i| self.mapping.get(i).copied())
+            .unwrap_or(-1)
+    }
+
+    fn insert_into_new_columns(&mut self, commit: &ObjectId, index: Option<usize>) {
+        let i = match self.new_column_of(commit) {
+            Some(i) => i,
+            None => {
+                let color = self.commit_color(commit);
+                self.new_columns.push(Column {
+                    commit: *commit,
+                    color,
+                });
+                self.new_columns.len() - 1
+            }
+        };
+        let width = self.width as i32;
+        let mapping_index = match index {
+            // First parent of a merge decides where the edges to parents go
+            Some(index) if self.parents.len() > 1 && self.merge_layout == -1 => {
+                let dist = index as i32 - i as i32;
+                let shift = if dist > 1 { 2 * dist - 3 } else { 1 };
+                self.merge_layout = if dist > 0 { 0 } else { 1 };
+                self.edges_added = self.parents.len() as i32 + self.merge_layout - 2;
+                self.width += 2 * self.merge_layout as usize;
+                width + (self.merge_layout - 1) * shift
+            }
+            // Parent in the last lane joins the merge edge right away
+            _ if self.edges_added > 0 && i as i32 == self.mapping_at(width - 2) => {
+                self.edges_added = -1;
+                width - 2
+            }
+            _ => {
+                self.width += 2;
+                width
+            }
+        };
+        self.mapping[mapping_index as usize] = i as i32;
+    }
+
+    fn update_columns(&mut self) {
+        self.columns = std::mem::take(&mut self.new_columns);
+        let max_new_columns = self.columns.len() + self.parents.len();
+        self.mapping_size = 2 * max_new_columns;
+        for mapping in [&mut self.mapping, &mut self.old_mapping] {
+            if mapping.len() < self.mapping_size {
+                mapping.resize(self.mapping_size, -1);
+            }
+        }
+        self.mapping[..self.mapping_size].fill(-1);
+        self.width = 0;
+        self.prev_edges_added = self.edges_added;
+        self.edges_added = 0;
+
+        let mut seen_this = false;
+        let mut in_columns = true;
+        for i in 0..=self.columns.len() {
+            let column_commit = if i == self.columns.len() {
+                if seen_this {
+                    break;
+                }
+                in_columns = false;
+                self.commit
+            } else {
+                self.columns[i].commit
+            };
+            if column_commit == self.commit {
+                seen_this = true;
+                self.commit_index = i;
+                self.merge_layout = -1;
+                for parent in self.parents.clone() {
+                    // Merges and new lanes without children get new color
+                    if self.parents.len() > 1 || !in_columns {
+                        self.increment_color();
+                    }
+                    self.insert_into_new_columns(&parent, Some(i));
+                }
+                // The commit takes at least 2 characters
+                if self.parents.is_empty() {
+                    self.width += 2;
+                }
+            } else {
+                self.insert_into_new_columns(&column_commit, None);
+            }
+        }
+        while self.mapping_size > 1 && self.mapping[self.mapping_size - 1] < 0 {
+            self.mapping_size -= 1;
+        }
+    }
+
+    fn dashed_parents(&self) -> usize {
+        (self.parents.len() as i32 + self.merge_layout - 3).max(0) as usize
+    }
+
+    fn needs_pre_commit_line(&self) -> bool {
+        self.parents.len() >= 3
+            && self.commit_index + 1 < self.columns.len()
+            && self.expansion_row < self.dashed_parents() * 2
+    }
+
+    fn is_mapping_correct(&self) -> bool {
+        self.mapping[..self.mapping_size]
+            .iter()
+            .enumerate()
+            .all(|(i, target)| *target < 0 || *target as usize == i / 2)
+    }
+
+    /// Next line of the graph and whether it is the line of the commit
+    pub fn next_line(&mut self) -> (String, bool) {
+        let mut line = Line::default();
+        let commit_line = self.state == State::Commit;
+        match self.state {
+            State::Padding => self.padding_line(&mut line),
+            State::Skip => self.skip_line(&mut line),
+            State::PreCommit => self.pre_commit_line(&mut line),
+            State::Commit => self.commit_line(&mut line),
+            State::PostMerge => self.post_merge_line(&mut line),
+            State::Collapsing => self.collapsing_line(&mut line),
+        }
+        // All lines of the commit have the same width to keep text right of them aligned
+        line.push_n(' ', self.width.saturating_sub(line.width));
+        (line.text, commit_line)
+    }
+
+    /// Line keeping all lanes unchanged, separating output of commits
+    pub fn padding(&mut self) -> String {
+        if self.state != State::Commit {
+            return self.next_line().0;
+        }
+        let mut line = Line::default();
+        for column in &self.columns {
+            line.column(column, '|');
+            if column.commit == self.commit && self.parents.len() > 2 {
+                line.push_n(' ', (self.parents.len() - 2) * 2);
+            } else {
+                line.push(' ');
+            }
+        }
+        line.push_n(' ', self.width.saturating_sub(line.width));
+        self.prev_state = State::Padding;
+        line.text
+    }
+
+    /// Lines up to and including the line of the commit, the commit line is not
+    /// terminated
+    pub fn commit_lines(&mut self) -> String {
+        let mut out = String::new();
+        if self.is_commit_finished() {
+            return self.padding();
+        }
+        while !self.is_commit_finished() {
+            let (line, commit_line) = self.next_line();
+            out.push_str(&line);
+            if commit_line {
+                break;
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Text of the commit with the graph before each line but the first, followed by
+    /// the rest of the lines of the commit
+    pub fn message(&mut self, text: &str) -> String {
+        let mut out = String::new();
+        let mut lines = text.split_inclusive('\n').peekable();
+        while let Some(line) = lines.next() {
+            out.push_str(line);
+            if lines.peek().is_some() {
+                out.push_str(&self.next_line().0);
+            }
+        }
+        if !self.is_commit_finished() {
+            let terminated = text.ends_with('\n');
+            if !terminated {
+                out.push('\n');
+            }
+            out.push_str(&self.remainder());
+            if terminated {
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// Lines until the commit is finished, separated by newlines
+    fn remainder(&mut self) -> String {
+        let mut lines = Vec::new();
+        while !self.is_commit_finished() {
+            lines.push(self.next_line().0);
+        }
+        lines.join("\n")
+    }
+
+    fn padding_line(&mut self, line: &mut Line) {
+        for column in &self.new_columns {
+            line.column(column, '|');
+            line.push(' ');
+        }
+    }
+
+    fn skip_line(&mut self, line: &mut Line) {
+        line.text.push_str("...");
+        line.width += 3;
+        if self.needs_pre_commit_line() {
+            self.set_state(State::PreCommit)
+        } else {
+            self.set_state(State::Commit)
+        }
+    }
+
+    fn pre_commit_line(&mut self, line: &mut Line) {
+        let mut seen_this = false;
+        for (i, column) in self.columns.iter().enumerate() {
+            if column.commit == self.commit {
+                seen_this = true;
+                line.column(column, '|');
+                line.push_n(' ', self.expansion_row);
+            } else if seen_this && self.expansion_row == 0 {
+                // Lanes drawn as `\` after the previous merge continue so
+                if self.prev_state == State::PostMerge && self.prev_commit_index < i {
+                    line.column(column, '\\');
+                } else {
+                    line.column(column, '|');
+                }
+            } else if seen_this {
+                line.column(column, '\\');
+            } else {
+                line.column(column, '|');
+            }
+            line.push(' ');
+        }
+        self.expansion_row += 1;
+        if !self.needs_pre_commit_line() {
+            self.set_state(State::Commit);
+        }
+    }
+
+    /// Horizontal dashes of an octopus merge, colored by the lanes they lead to
+    fn octopus_merge(&self, line: &mut Line) {
+        let dashed = self.dashed_parents();
+        for i in 0..dashed {
+            let j = self.mapping_at(((self.commit_index + i + 2) * 2) as i32);
+            let column = &self.new_columns[j as usize];
+            line.column(column, '-');
+            line.column(column, if i == dashed - 1 { '.' } else { '-' });
+        }
+    }
+
+    fn commit_line(&mut self, line: &mut Line) {
+        let mut seen_this = false;
+        for i in 0..=self.columns.len() {
+            let is_commit = match self.columns.get(i) {
+                Some(column) => column.commit == self.commit,
+                None if seen_this => break,
+                None => true,
+            };
+            if is_commit {
+                seen_this = true;
+                line.push('*');
+                if self.parents.len() > 2 {
+                    self.octopus_merge(line);
+                }
+            } else {
+                let column = &self.columns[i];
+                let old_mapping = |i: usize| self.old_mapping.get(i).copied().unwrap_or(-1);
+                if seen_this && self.edges_added > 1 {
+                    line.column(column, '\\');
+                } else if seen_this && self.edges_added == 1 {
+                    // Lane drawn as `\` after the previous merge continues so
+                    if self.prev_state == State::PostMerge
+                        && self.prev_edges_added > 0
+                        && self.prev_commit_index < i
+                    {
+                        line.column(column, '\\');
+                    } else {
+                        line.column(column, '|');
+                    }
+                } else if self.prev_state == State::Collapsing
+                    && old_mapping(2 * i + 1) == i as i32
+                    && self.mapping_at(2 * i as i32) < i as i32
+                {
+                    line.column(column, '/');
+                } else {
+                    line.column(column, '|');
+                }
+            }
+            line.push(' ');
+        }
+        if self.parents.len() > 1 {
+            self.set_state(State::PostMerge);
+        } else if self.is_mapping_correct() {
+            self.set_state(State::Padding);
+        } else {
+            self.set_state(State::Collapsing);
+        }
+    }
+
+    fn post_merge_line(&mut self, line: &mut Line) {
+        const MERGE_CHARS: [char; 3] = ['/', '|', '\\'];
+        let mut seen_this = false;
+        let mut parent_column: Option<usize> = None;
+        for i in 0..=self.columns.len() {
+            let column_commit = match self.columns.get(i) {
+                Some(column) => column.commit,
+                None if seen_this => break,
+                None => self.commit,
+            };
+            if column_commit == self.commit {
+                seen_this = true;
+                let mut index = self.merge_layout as usize;
+                for (j, parent) in self.parents.iter().enumerate() {
+                    let parent = self.new_column_of(parent).expect("parent has column");
+                    line.column(&self.new_columns[parent], MERGE_CHARS[index]);
+                    if index == 2 {
+                        if self.edges_added > 0 || j + 1 < self.parents.len() {
+                            line.push(' ');
+                        }
+                    } else {
+                        index += 1;
+                    }
+                }
+                if self.edges_added == 0 {
+                    line.push(' ');
+                }
+            } else if seen_this {
+                let c = if self.edges_added > 0 { '\\' } else { '|' };
+                line.column(&self.columns[i], c);
+                line.push(' ');
+            } else {
+                line.column(&self.columns[i], '|');
+                if self.merge_layout != 0 || i + 1 != self.commit_index {
+                    match parent_column {
+                        Some(parent) => line.column(&self.columns[parent], '_'),
+                        None => line.push(' '),
+                    }
+                }
+            }
+            if self.parents.first() == Some(&column_commit) && i < self.columns.len() {
+                parent_column = Some(i);
+            }
+        }
+        if self.is_mapping_correct() {
+            self.set_state(State::Padding)
+        } else {
+            self.set_state(State::Collapsing)
+        }
+    }
+
+    fn collapsing_line(&mut self, line: &mut Line) {
+        let mut used_horizontal = false;
+        let mut horizontal_edge: i32 = -1;
+        let mut horizontal_edge_target: i32 = -1;
+        std::mem::swap(&mut self.mapping, &mut self.old_mapping);
+        self.mapping[..self.mapping_size].fill(-1);
+
+        for i in 0..self.mapping_size {
+            let target = self.old_mapping[i];
+            if target < 0 {
+                continue;
+            }
+            // Lanes only ever move left, so only one of crossing lanes moves
+            let t = target as usize;
+            if t * 2 == i {
+                self.mapping[i] = target;
+            } else if self.mapping[i - 1] < 0 {
+                self.mapping[i - 1] = target;
+                if horizontal_edge == -1 {
+                    horizontal_edge = i as i32;
+                    horizontal_edge_target = target;
+                    for j in (t * 2 + 3..i.saturating_sub(2)).step_by(2) {
+                        self.mapping[j] = target;
+                    }
+                }
+            } else if self.mapping[i - 1] == target {
+                // Lane to the left leads to the same commit, they merge
+            } else {
+                // Cross the lane to the left
+                self.mapping[i - 2] = target;
+                if horizontal_edge == -1 {
+                    horizontal_edge_target = target;
+                    horizontal_edge = i as i32 - 1;
+                    for j in (t * 2 + 3..i.saturating_sub(2)).step_by(2) {
+                        self.mapping[j] = target;
+                    }
+                }
+            }
+        }
+
+        self.old_mapping[..self.mapping_size].copy_from_slice(&self.mapping[..self.mapping_size]);
+        if self.mapping[self.mapping_size - 1] < 0 {
+            self.mapping_size -= 1;
+        }
+
+        for i in 0..self.mapping_size {
+            let target = self.mapping[i];
+            if target < 0 {
+                line.push(' ');
+            } else if target as usize * 2 == i {
+                line.column(&self.new_columns[target as usize], '|');
+            } else if target == horizontal_edge_target && i as i32 != horizontal_edge - 1 {
+                // Only the first segment of the horizontal edge continues to the next line
+                if i != target as usize * 2 + 3 {
+                    self.mapping[i] = -1;
+                }
+                used_horizontal = true;
+                line.column(&self.new_columns[target as usize], '_');
+            } else {
+                if used_horizontal && (i as i32) < horizontal_edge {
+                    self.mapping[i] = -1;
+                }
+                line.column(&self.new_columns[target as usize], '/');
+            }
+        }
+
+        if self.is_mapping_correct() {
+            self.set_state(State::Padding);
+        }
+    }
+}
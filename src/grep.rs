@@ -0,0 +1,274 @@
+use std::sync::Mutex;
+
+use crate::attributes::Attributes;
+use crate::diff;
+use crate::error::{bail, format_err, Result};
+use crate::index::{self, Index};
+use crate::object::Object;
+use crate::oid::ObjectId;
+use crate::pack;
+use crate::pathname;
+use crate::revparse;
+use crate::tree::Tree;
+use crate::GitRepo;
+
+/// Single element of the pattern
+#[derive(Debug, Clone)]
+enum Atom {
+    Any,
+    Byte(u8),
+    Class {
+        negated: bool,
+        ranges: Vec<(u8, u8)>,
+    },
+}
+
+impl Atom {
+    fn matches(&self, c: u8, ignore_case: bool) -> bool {
+        let candidates = if ignore_case {
+            [c.to_ascii_lowercase(), c.to_ascii_uppercase()]
+        } else {
+            [c, c]
+        };
+        match self {
+            Atom::Any => c != b'\n',
+            Atom::Byte(b) if ignore_case => b.eq_ignore_ascii_case(&c),
+            Atom::Byte(b) => *b == c,
+            Atom::Class { negated, ranges } => {
+                let found = candidates
+                    .iter()
+                    .any(|c| ranges.iter().any(|(lo, hi)| lo <= c && c <= hi));
+                found != *negated
+            }
+        }
+    }
+}
+
+/// Basic regular expression as used by `git grep` by default: literals, `.`, `*`,
+/// bracket expressions, `\` escapes and `^`/`$` anchors
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    /// Atoms with flag whether they may repeat any number of times
+    atoms: Vec<(Atom, bool)>,
+    anchored_start: bool,
+    anchored_end: bool,
+    ignore_case: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str, ignore_case: bool) -> Result<Self> {
+        let mut bytes = pattern.as_bytes();
+        let anchored_start = bytes.first() == Some(&b'^');
+        if anchored_start {
+            bytes = &bytes[1..];
+        }
+        let anchored_end = bytes.ends_with(b"$") && !bytes.ends_with(b"\\$");
+        if anchored_end {
+            bytes = &bytes[..bytes.len() - 1];
+        }
+
+        let mut atoms: Vec<(Atom, bool)> = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let atom = match bytes[i] {
+                // Star repeats the previous atom, at the start it is literal
+                b'*' if !atoms.is_empty() => {
+                    if let Some(last) = atoms.last_mut() {
+                        last.1 = true;
+                    }
+                    i += 1;
+                    continue;
+                }
+                b'.' => Atom::Any,
+                b'\\' => {
+                    i += 1;
+                    match bytes.get(i) {
+                        Some(c) => Atom::Byte(*c),
+                        None => bail!("Trailing backslash in pattern {:?}", pattern),
+                    }
+                }
+                b'[' => {
+                    let (atom, end) = Self::parse_class(bytes, i + 1)
+                        .ok_or_else(|| format_err!("Unmatched [ in pattern {:?}", pattern))?;
+                    i = end;
+                    atom
+                }
+                c => Atom::Byte(c),
+            };
+            atoms.push((atom, false));
+            i += 1;
+        }
+        Ok(Self {
+            atoms,
+            anchored_start,
+            anchored_end,
+            ignore_case,
+        })
+    }
+
+    /// Parses bracket expression starting after `[`, returns it with index of the closing `]`
+    fn parse_class(bytes: &[u8], mut i: usize) -> Option<(Atom, usize)> {
+        let negated = bytes.get(i) == Some(&b'^');
+        if negated {
+            i += 1;
+        }
+        let mut ranges = Vec::new();
+        let start = i;
+        loop {
+            let c = *bytes.get(i)?;
+            // `]` right after the opening bracket is literal
+            if c == b']' && i > start {
+                return Some((Atom::Class { negated, ranges }, i));
+            }
+            match (bytes.get(i + 1), bytes.get(i + 2)) {
+                (Some(b'-'), Some(hi)) if *hi != b']' => {
+                    ranges.push((c, *hi));
+                    i += 3;
+                }
+                _ => {
+                    ranges.push((c, c));
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    /// Marks the position of the atom as reached, together with positions after the
+    /// repeated atoms which may match nothing
+    fn reach(&self, states: &mut [bool], mut i: usize) {
+        while !states[i] {
+            states[i] = true;
+            match self.atoms.get(i) {
+                Some((_, true)) => i += 1,
+                _ => break,
+            }
+        }
+    }
+
+    /// Checks whether the pattern matches anywhere in the line. All positions in the
+    /// pattern reachable so far are advanced together by each byte, so the time is
+    /// linear in the length of the line for any pattern.
+    pub fn is_match(&self, line: &[u8]) -> bool {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let end = self.atoms.len();
+        let mut states = vec![false; end + 1];
+        let mut next = vec![false; end + 1];
+        self.reach(&mut states, 0);
+        for c in line {
+            if states[end] && !self.anchored_end {
+                return true;
+            }
+            next.fill(false);
+            // Unanchored match may start at every byte
+            if !self.anchored_start {
+                self.reach(&mut next, 0);
+            }
+            for (i, (atom, repeat)) in self.atoms.iter().enumerate() {
+                if states[i] && atom.matches(*c, self.ignore_case) {
+                    self.reach(&mut next, if *repeat { i } else { i + 1 });
+                }
+            }
+            std::mem::swap(&mut states, &mut next);
+        }
+        states[end]
+    }
+}
+
+/// Matching lines of single file
+pub struct FileMatches {
+    pub path: Vec<u8>,
+    /// The file is binary, its lines are not reported
+    pub binary: bool,
+    /// 1-based line numbers with line content without the newline
+    pub lines: Vec<(usize, Vec<u8>)>,
+}
+
+/// Checks whether the path is selected by any of the pathspecs, which match the path
+/// itself or everything under a directory. Empty pathspecs match everything.
+pub fn matches_pathspec(path: &[u8], pathspecs: &[impl AsRef<[u8]>]) -> bool {
+    pathspecs.is_empty()
+        || pathspecs.iter().any(|spec| {
+            let spec = pathname::trim_slash(spec.as_ref());
+            spec.is_empty()
+                || spec == b"."
+                || path == spec
+                || path
+                    .strip_prefix(spec)
+                    .is_some_and(|rest| rest.starts_with(b"/"))
+        })
+}
+
+fn grep_data(path: Vec<u8>, data: &[u8], pattern: &Pattern) -> Option<FileMatches> {
+    let lines: Vec<(usize, Vec<u8>)> = diff::lines(data)
+        .into_iter()
+        .enumerate()
+        .filter(|(_, line)| pattern.is_match(line))
+        .map(|(n, line)| (n + 1, line.strip_suffix(b"\n").unwrap_or(line).to_vec()))
+        .collect();
+    if lines.is_empty() {
+        return None;
+    }
+    let binary = diff::is_binary(data);
+    Some(FileMatches {
+        path,
+        binary,
+        lines,
+    })
+}
+
+/// Searches tracked files in the tree of `rev`, or in the working tree when not given.
+/// Files are read and searched in parallel, results are sorted by path.
+pub fn grep(
+    repo: &GitRepo,
+    pattern: &Pattern,
+    rev: Option<&str>,
+    pathspecs: &[Vec<u8>],
+) -> Result<Vec<FileMatches>> {
+    // Index and attributes shared by threads reading files of the working tree
+    let mut worktree = None;
+    // Paths with blob ids, `None` for files read from the working tree
+    let files: Vec<(Vec<u8>, Option<ObjectId>)> = match rev {
+        Some(rev) => {
+            let tree = revparse::resolve(repo, &format!("{}^{{tree}}", rev))?;
+            Tree::flatten(repo, &tree)?
+                .into_iter()
+                .filter(|(_, (mode, _))| *mode != 0o160000)
+                .map(|(path, (_, id))| (path, Some(id)))
+                .collect()
+        }
+        None => {
+            let index = Index::read(repo)?;
+            let files = index
+                .to_map()
+                .into_iter()
+                .filter(|(_, (mode, _))| *mode != 0o160000)
+                .map(|(path, _)| (path, None))
+                .collect();
+            worktree = Some((index, Mutex::new(Attributes::load(repo)?)));
+            files
+        }
+    };
+    let worktree = worktree.as_ref();
+    let files: Vec<_> = files
+        .into_iter()
+        .filter(|(path, _)| matches_pathspec(path, pathspecs))
+        .collect();
+
+    let found = pack::parallel_map(files, |(path, id)| {
+        let data = match id {
+            Some(id) => Object::read(repo, &id)?.data,
+            None => {
+                let (index, attributes) = worktree.unwrap();
+                let mut attributes = attributes
+                    .lock()
+                    .map_err(|_| format_err!("Poisoned lock"))?;
+                match index::worktree_file_with(repo, &path, index, &mut attributes)? {
+                    Some((_, data)) => data,
+                    None => return Ok(None),
+                }
+            }
+        };
+        Ok(grep_data(path, &data, pattern))
+    })?;
+    Ok(found.into_iter().flatten().collect())
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+use std::fs;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+
+use crate::config::Config;
+use crate::error::{bail, format_err, Context, Result};
+use crate::oid::ObjectId;
+use crate::GitRepo;
+
+/// Points where hooks are run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookName {
+    /// Before the commit is created, may abort it
+    PreCommit,
+    /// Gets path of the file with the commit message, may edit it or abort the commit
+    CommitMsg,
+    /// After the commit was created
+    PostCommit,
+    /// Gets remote name and URL as arguments and updated refs on stdin, may abort the push
+    PrePush,
+    /// Gets previous HEAD, new HEAD and whether a branch was checked out
+    PostCheckout,
+}
+
+impl HookName {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::PreCommit => "pre-commit",
+            Self::CommitMsg => "commit-msg",
+            Self::PostCommit => "post-commit",
+            Self::PrePush => "pre-push",
+            Self::PostCheckout => "post-checkout",
+        }
+    }
+
+    /// Hooks run after the operation cannot abort it, their failure is not an error
+    fn can_abort(&self) -> bool {
+        !matches!(self, Self::PostCommit | Self::PostCheckout)
+    }
+}
+
+/// Hook implemented in Rust, added with [`GitRepo::with_hook`]. Returning error aborts the
+/// operation just like non-zero exit status of hook executable.
+pub trait Hook: Send + Sync {
+    fn run(&self, repo: &GitRepo, args: &[String], stdin: &[u8]) -> Result<()>;
+}
+
+impl<F> Hook for F
+where
+    F: Fn(&GitRepo, &[String], &[u8]) -> Result<()> + Send + Sync,
+{
+    fn run(&self, repo: &GitRepo, args: &[String], stdin: &[u8]) -> Result<()> {
+        self(repo, args, stdin)
+    }
+}
+
+/// Rust hooks of the repository in the order they were added
+#[derive(Clone, Default)]
+pub(crate) struct Hooks(Vec<(HookName, Arc<dyn Hook>)>);
+
+impl Hooks {
+    pub(crate) fn push(&mut self, name: HookName, hook: Arc<dyn Hook>) {
+        self.0.push((name, hook));
+    }
+
+    fn get(&self, name: HookName) -> impl Iterator<Item = &dyn Hook> {
+        self.0
+            .iter()
+            .filter(move |(n, _)| *n == name)
+            .map(|(_, hook)| &**hook)
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+/// Directory with hook executables, `core.hooksPath` or `.git/hooks` by default
+fn hooks_dir(repo: &GitRepo) -> Result<PathBuf> {
+    match Config::load(repo)?.get_path("core.hooksPath") {
+        Some(path) if path.is_relative() => Ok(repo.repo_root.join(path)),
+        Some(path) => Ok(path),
+        None => Ok(repo.common_dir.join("hooks")),
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Runs the hook executable from the root of the working tree with the input on stdin
+fn run_executable(repo: &GitRepo, path: &Path, args: &[String], stdin: &[u8]) -> Result<()> {
+    let mut child = Command::new(path)
+        .args(args)
+        .current_dir(&repo.repo_root)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Cannot run hook {}", path.display()))?;
+    let mut input = child
+        .stdin
+        .take()
+        .ok_or(format_err!("Hook stdin not captured"))?;
+    let status = thread::scope(|scope| {
+        // Hooks may exit without reading all input
+        scope.spawn(move || input.write_all(stdin));
+        child.wait()
+    })?;
+    if !status.success() {
+        bail!("Hook '{}' failed ({})", path.display(), status);
+    }
+    Ok(())
+}
+
+fn run_all(repo: &GitRepo, name: HookName, args: &[String], stdin: &[u8]) -> Result<()> {
+    for hook in repo.hooks().get(name) {
+        hook.run(repo, args, stdin)
+            .with_context(|| format!("Hook '{}' failed", name.as_str()))?;
+    }
+    let path = hooks_dir(repo)?.join(name.as_str());
+    if is_executable(&path) {
+        run_executable(repo, &path, args, stdin)?;
+    } else if path.exists() {
+        repo.progress().info(&format!(
+            "hint: The '{}' hook was ignored because it's not set as executable.",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Runs hooks of the repository and the hook executable. Failure of hooks which can abort the
+/// operation is returned as error, other failures are only reported.
+pub fn run(repo: &GitRepo, name: HookName, args: &[String], stdin: &[u8]) -> Result<()> {
+    match run_all(repo, name, args, stdin) {
+        Err(err) if !name.can_abort() => {
+            repo.progress().warning(&err.chain().to_string());
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Runs `commit-msg` hooks on the message, which is passed in `.git/COMMIT_EDITMSG`.
+/// Returns the message as left by the hooks.
+pub fn commit_msg(repo: &GitRepo, message: &str) -> Result<String> {
+    let path = repo.git_dir.join("COMMIT_EDITMSG");
+    fs::write(&path, message).context("Writing COMMIT_EDITMSG")?;
+    let arg = path.to_string_lossy().into_owned();
+    run(repo, HookName::CommitMsg, &[arg], &[])?;
+    fs::read_to_string(&path).context("Reading COMMIT_EDITMSG")
+}
+
+/// Runs `post-checkout` hooks after HEAD moved from `old` to `new`
+pub fn post_checkout(
+    repo: &GitRepo,
+    old: Option<&ObjectId>,
+    new: &ObjectId,
+    branch: bool,
+) -> Result<()> {
+    let args = [
+        old.copied()
+            .unwrap_or_else(|| repo.hash_algo().null_id())
+            .to_hex(),
+        new.to_hex(),
+        String::from(if branch { "1" } else { "0" }),
+    ];
+    run(repo, HookName::PostCheckout, &args, &[])
+}
@@ -1,303 +1,651 @@
 use std::collections::{BTreeSet, VecDeque};
+use std::fs;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, bail, ensure, Context, Result};
-use flate2::read::ZlibDecoder;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
 use reqwest::blocking::{Client, Response};
+use reqwest::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, ETAG,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use reqwest::{StatusCode, Url};
 
-use crate::object::{Object, ObjectKind};
-use crate::GitRepo;
+use crate::error::{bail, ensure, format_err, Context, Error, Result};
+use crate::hash::HashAlgo;
+use crate::oid::ObjectId;
+use crate::pkt_line::{self, PktLine};
+use crate::{fsck, pack, GitRepo};
+
+mod asynchronous;
+pub use asynchronous::AsyncGitHttpClient;
 
 pub struct GitHttpClient<'a> {
     repo: &'a GitRepo,
     client: Client,
     url: String,
+    /// The last ref advertisement was not modified since it was cached
+    advertisement_cached: bool,
 }
 
 impl<'a> GitHttpClient<'a> {
     pub fn new(repo: &'a GitRepo, url: String) -> Self {
         Self {
             repo,
+            // Default client follows up to 10 redirects
             client: Client::new(),
-            url,
+            url: url.trim_end_matches('/').to_string(),
+            advertisement_cached: false,
         }
     }
 
-    fn validate_content_type(&self, resp: &Response, content_type: &str) -> Result<()> {
-        let actual_content_type = resp
-            .headers()
-            .get("Content-Type")
-            .ok_or(anyhow!("Missing Content-Type header"))?
-            .to_str()
-            .context("Cannot convert Content-Type header value to str")?;
-        ensure!(
-            actual_content_type == content_type,
-            "Unexpected Content-Type header value. Got {}", actual_content_type
-        );
-        Ok(())
+    fn read(resp: Response) -> Result<HttpResponse> {
+        let (status, headers, url) = (resp.status(), resp.headers().clone(), resp.url().clone());
+        let raw = resp.bytes().context("Reading response body")?;
+        HttpResponse::new(status, headers, url, &raw)
     }
 
-    fn parse_pkt_lines(&self, mut lines: &[u8]) -> Result<VecDeque<PktLine>> {
-        let mut pkt_lines = VecDeque::new();
-
-        let mut data_len_bytes = [0u8; 2];
-        while lines.len() > 0 {
-            let (prefix, rest) = lines.split_at(4);
-            if prefix == b"PACK" {
-                let (version, rest) = rest.split_at(4);
-                ensure!(version == &[0, 0, 0, 2], "Packfile version should be 2");
-                let (packets_num, mut rest) = rest.split_at(4);
-                let packets_num = u32::from_be_bytes(packets_num.try_into()?);
-                for _i in 0..packets_num {
-                    let mut cont = rest[0] >= 128;
-                    let pack_entry_type = PackEntryType::try_from((rest[0] >> 4) & 7)?;
-                    let mut val = (rest[0] & 15) as u32;
-                    let mut shift = 4;
-                    rest = &rest[1..];
-                    while cont {
-                        cont = rest[0] >= 128;
-                        val += ((rest[0] & 127) as u32) << shift;
-                        shift += 7;
-                        rest = &rest[1..];
-                    }
-                    match pack_entry_type {
-                        PackEntryType::OBJ_COMMIT
-                        | PackEntryType::OBJ_TREE
-                        | PackEntryType::OBJ_BLOB
-                        | PackEntryType::OBJ_TAG => {
-                            let mut decoder = ZlibDecoder::new(rest);
-                            let mut buf = Vec::new();
-                            decoder
-                                .read_to_end(&mut buf)
-                                .context("Reading object file")?;
-                            ensure!(val as usize == buf.len(), "Read incorrect number of bytes");
-                            let read_bytes = decoder.total_in() as usize;
-
-                            let kind = match pack_entry_type {
-                                PackEntryType::OBJ_COMMIT => ObjectKind::Commit,
-                                PackEntryType::OBJ_TREE => ObjectKind::Tree,
-                                PackEntryType::OBJ_BLOB => ObjectKind::Blob,
-                                PackEntryType::OBJ_TAG => todo!(),
-                                PackEntryType::OBJ_OFS_DELTA | PackEntryType::OBJ_REF_DELTA => {
-                                    unreachable!()
-                                }
-                            };
-
-                            let obj = Object::new(kind, buf);
-                            obj.write(self.repo)?;
-                            eprintln!("{:?} {}", pack_entry_type, obj.hash);
-
-                            rest = &rest[read_bytes..];
-                        }
-                        PackEntryType::OBJ_OFS_DELTA => {
-                            todo!("OFS_DELTA object")
-                        }
-                        PackEntryType::OBJ_REF_DELTA => {
-                            let ref_delta = hex::encode(&rest[..20]);
-                            rest = &rest[20..];
-
-                            let mut decoder = ZlibDecoder::new(rest);
-                            let mut buf = Vec::new();
-                            decoder.read_to_end(&mut buf).context("Reading pack diff")?;
-                            let read_bytes = decoder.total_in() as usize;
-
-                            eprintln!("REF_DELTA: {}: {:?}", ref_delta, &buf);
-                            // TODO: resolve the delta file!
-
-                            rest = &rest[read_bytes..];
-                        }
-                    }
-                }
-                let _checksum = &rest[..20];
-                lines = &rest[20..];
-                ensure!(lines.is_empty(), "Unexpected data after pack data");
-            } else {
-                hex::decode_to_slice(std::str::from_utf8(prefix)?, &mut data_len_bytes)
-                    .context("Decoding data len hex")?;
-                let data_len = u16::from_be_bytes(data_len_bytes) as usize;
-                if data_len == 0 {
-                    lines = rest;
-                    pkt_lines.push_back(PktLine::Flush);
-                } else {
-                    ensure!(
-                        data_len >= 4,
-                        "pkt-line length must be at least 4 to compensate for legth bytes"
-                    );
-                    let (data, rest) = rest.split_at(data_len - 4);
-                    lines = rest;
-                    pkt_lines.push_back(PktLine::Data(Box::from(data)));
-                }
-            }
-        }
-        Ok(pkt_lines)
+    fn get(&self, url: &str, headers: HeaderMap) -> Result<HttpResponse> {
+        let resp = self.client.get(url).headers(headers).send();
+        Self::read(resp.context("Requesting info/refs")?)
     }
 
-    pub fn ref_info(&self) -> Result<RefInfo> {
-        let service = "git-upload-pack";
-        let service_bytes = service.as_bytes();
+    /// Posts request to the service, returning body of its result
+    fn post(&self, service: &str, body: Vec<u8>) -> Result<Vec<u8>> {
         let resp = self
             .client
-            .get(format!("{}/info/refs", self.url))
-            .query(&[("service", service)])
+            .post(format!("{}/{}", self.url, service))
+            .headers(service_headers(service))
+            .body(body)
             .send()?;
-        self.validate_content_type(&resp, &format!("application/x-{}-advertisement", service))?;
+        Self::read(resp)?.service_result(service)
+    }
 
-        let mut lines = self.parse_pkt_lines(&resp.bytes()?)?;
-        if let Some(PktLine::Data(data)) = lines.pop_front() {
-            ensure!(
-                data.len() == 10 + service_bytes.len()
-                    || (data.len() == 10 + service_bytes.len() + 1 && data.last() == Some(&b'\n')),
-                "Invalid header line"
-            );
-            ensure!(&data[..10] == b"# service=", "Invalid header prefix");
-            ensure!(
-                &data[10..(10 + service_bytes.len())] == service_bytes,
-                "Invalid header value"
-            );
-        } else {
-            bail!("Invalid header line");
+    /// Requests ref advertisement, see [`InfoRefs`]
+    fn request_info_refs(&mut self, request: &InfoRefs) -> Result<HttpResponse> {
+        let mut resp = self.get(&request.url(&self.url), request.headers())?;
+        if let Some(url) = request.retry_url(&self.url, &resp) {
+            let retry = self.get(&request.url(&url), request.headers())?;
+            if request.usable(&retry) {
+                resp = retry;
+            }
         }
-        ensure!(lines.pop_front() == Some(PktLine::Flush));
+        let (resp, url) = request.finish(resp)?;
+        self.url = url;
+        Ok(resp)
+    }
 
-        let mut refs = Vec::new();
+    pub fn ref_info(&mut self) -> Result<RefInfo> {
+        self.service_ref_info("git-upload-pack")
+    }
 
-        fn parse_line(refs: &mut Vec<Ref>, mut data: &[u8]) -> Result<()> {
-            if data.last() == Some(&b'\n') {
-                data = &data[..data.len() - 1];
-            }
-            let id = &data[..40];
-            let name = &data[41..];
-            ensure!(data[40] == b' ');
-            if name.ends_with(b"^{}") {
-                let l = refs
-                    .last_mut()
-                    .ok_or(anyhow!("Peeled ref cannot be the first entry"))?;
-                ensure!(l.name.as_bytes() == &name[..name.len() - 3]);
-                ensure!(l.peeled_ref.is_none());
-                l.peeled_ref = Some(id.try_into()?);
-            } else {
-                let name = std::str::from_utf8(&data[41..])?.to_string();
-                refs.push(Ref {
-                    name,
-                    id: id.try_into()?,
-                    peeled_ref: None,
-                })
+    /// Ref advertisement of receive-pack service, used for pushing
+    pub fn receive_pack_ref_info(&mut self) -> Result<RefInfo> {
+        self.service_ref_info("git-receive-pack")
+    }
+
+    /// Whether the last ref advertisement came from the cache, as the server said it
+    /// was not modified since
+    pub fn advertisement_cached(&self) -> bool {
+        self.advertisement_cached
+    }
+
+    /// Ref advertisement of the service. Advertisements with validators (`ETag` or
+    /// `Last-Modified`) are cached, so that unchanged refs are not downloaded again.
+    fn service_ref_info(&mut self, service: &str) -> Result<RefInfo> {
+        let cache_path = cache_path(self.repo, &self.url, service);
+        let cached = CachedAdvertisement::load(&cache_path);
+        let resp = self.request_info_refs(&InfoRefs::new(service, cached.as_ref()))?;
+        let (body, from_cache) = advertisement_body(resp, cached, &cache_path)?;
+        self.advertisement_cached = from_cache;
+        parse_ref_info(self.repo, service, &body)
+    }
+
+    pub fn fetch_refs(&self, refs: BTreeSet<&ObjectId>) -> Result<()> {
+        self.fetch_pack(refs, BTreeSet::new())
+    }
+
+    /// Fetches objects for `wants`, telling the server we already have `haves`
+    pub fn fetch_pack(&self, wants: BTreeSet<&ObjectId>, haves: BTreeSet<&ObjectId>) -> Result<()> {
+        let body = self.post("git-upload-pack", upload_pack_request(wants, haves))?;
+        parse_upload_pack_result(self.repo, &body)
+    }
+
+    /// Sends ref update commands with pack to receive-pack, returning status of each ref
+    pub fn send_pack(&self, commands: &[RefUpdate], pack: Option<&[u8]>) -> Result<Vec<RefStatus>> {
+        let mut body = Vec::new();
+        for (i, cmd) in commands.iter().enumerate() {
+            let zero = self.repo.hash_algo().null_id();
+            let mut line = format!(
+                "{} {} {}",
+                cmd.old.unwrap_or(zero),
+                cmd.new.unwrap_or(zero),
+                cmd.name
+            );
+            if i == 0 {
+                line.push_str("\0report-status");
             }
-            Ok(())
+            line.push('\n');
+            pkt_line::write_data(&mut body, line.as_bytes());
         }
+        pkt_line::write_flush(&mut body);
+        if let Some(pack) = pack {
+            body.extend(pack);
+        }
+        parse_report_status(&self.post("git-receive-pack", body)?)
+    }
+}
 
-        let capabilities;
+/// Response read as a whole, with the body decoded according to `Content-Encoding`
+struct HttpResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    url: Url,
+    body: Vec<u8>,
+}
 
-        if let PktLine::Data(data) = lines
-            .pop_front()
-            .ok_or(anyhow!("Missing first data line"))?
-        {
-            let pos = data
-                .iter()
-                .position(|x| *x == b'\0')
-                .ok_or(anyhow!("Missing null-byte in first data line"))?;
-            let (refs_bytes, capabilities_bytes) = data.split_at(pos);
-            let capabilities_bytes = &capabilities_bytes[1..];
-            capabilities = capabilities_bytes
-                .split(|x| *x == b' ')
-                .map(|s| {
-                    std::str::from_utf8(s)
-                        .map(String::from)
-                        .context("Capabilities must be valid strs")
-                })
-                .collect::<Result<_>>()?;
-
-            if data.starts_with(b"0000000000000000000000000000000000000000") {
-                ensure!(
-                    lines.pop_front() == Some(PktLine::Flush),
-                    "Data must end with flush line"
-                );
-                ensure!(lines.is_empty(), "Unexpected data after last flush line");
-                return Ok(RefInfo { capabilities, refs });
-            } else {
-                parse_line(&mut refs, refs_bytes)?;
-            }
-        } else {
-            bail!("Invalid first data line");
+impl HttpResponse {
+    fn new(status: StatusCode, headers: HeaderMap, url: Url, raw: &[u8]) -> Result<Self> {
+        let body = match decode_body(&headers, raw) {
+            Ok(body) => body,
+            // Error responses are reported by their status even with undecodable body
+            Err(_) if !status.is_success() => Vec::new(),
+            Err(err) => return Err(err),
         };
+        Ok(Self {
+            status,
+            headers,
+            url,
+            body,
+        })
+    }
 
-        while let Some(PktLine::Data(data)) = lines.pop_front() {
-            parse_line(&mut refs, &data)?;
+    fn check_status(self) -> Result<Self> {
+        if self.status.is_success() {
+            return Ok(self);
+        }
+        let body = String::from_utf8_lossy(&self.body);
+        if body.trim().is_empty() {
+            bail!(Error::protocol(format!(
+                "Server responded with {} for {}",
+                self.status, self.url
+            )))
         }
-        ensure!(lines.is_empty(), "Unexpected data after last flush line");
+        bail!(Error::protocol(format!(
+            "Server responded with {} for {}: {}",
+            self.status,
+            self.url,
+            body.trim()
+        )))
+    }
 
-        Ok(RefInfo { capabilities, refs })
+    /// Body of successful response of the service with the expected content type
+    fn service_result(self, service: &str) -> Result<Vec<u8>> {
+        let resp = self.check_status()?;
+        validate_content_type(&resp.headers, &format!("application/x-{}-result", service))?;
+        Ok(resp.body)
     }
+}
 
-    pub fn fetch_refs(&self, refs: BTreeSet<&[u8; 40]>) -> Result<()> {
-        let mut body = Vec::with_capacity(refs.len() * 50 + 4 + 9);
-        for r in refs {
-            body.extend(b"0032want ");
-            body.extend_from_slice(r);
-            body.push(b'\n');
+/// Headers of POST request to the service
+fn service_headers(service: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let content_type = format!("application/x-{}-request", service);
+    headers.insert(CONTENT_TYPE, HeaderValue::from_str(&content_type).unwrap());
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, deflate"));
+    headers
+}
+
+/// Request of ref advertisement of the service, shared by both clients. The plain URL
+/// is tried first, then with `.git` suffix when it doesn't lead to a smart HTTP
+/// endpoint. Base URL is then updated to the one we ended up at after redirects so
+/// subsequent requests go to the same place. The request is conditional when there is
+/// cached advertisement, the response may then be `304 Not Modified`.
+struct InfoRefs<'c> {
+    service: &'c str,
+    content_type: String,
+    cached: Option<&'c CachedAdvertisement>,
+}
+
+impl<'c> InfoRefs<'c> {
+    fn new(service: &'c str, cached: Option<&'c CachedAdvertisement>) -> Self {
+        Self {
+            service,
+            content_type: format!("application/x-{}-advertisement", service),
+            cached,
         }
-        body.extend(b"0000");
-        body.extend(b"0009done\n");
+    }
 
-        let resp = self
-            .client
-            .post(format!("{}/git-upload-pack", self.url))
-            .header("Content-Type", "application/x-git-upload-pack-request")
-            .body(body)
-            .send()?;
+    fn url(&self, base: &str) -> String {
+        format!("{}/info/refs?service={}", base, self.service)
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, deflate"));
+        let validators = self.cached.into_iter().flat_map(|cached| {
+            [
+                (IF_NONE_MATCH, cached.etag.as_deref()),
+                (IF_MODIFIED_SINCE, cached.last_modified.as_deref()),
+            ]
+        });
+        for (name, value) in validators {
+            if let Some(value) = value.and_then(|v| HeaderValue::from_str(v).ok()) {
+                headers.insert(name, value);
+            }
+        }
+        headers
+    }
 
-        self.validate_content_type(&resp, "application/x-git-upload-pack-result")?;
+    fn usable(&self, resp: &HttpResponse) -> bool {
+        (self.cached.is_some() && resp.status == StatusCode::NOT_MODIFIED)
+            || (resp.status.is_success()
+                && resp
+                    .headers
+                    .get(CONTENT_TYPE)
+                    .is_some_and(|v| v.as_bytes() == self.content_type.as_bytes()))
+    }
+
+    /// Base URL to retry at when the response is not usable
+    fn retry_url(&self, base: &str, resp: &HttpResponse) -> Option<String> {
+        (!self.usable(resp) && !base.ends_with(".git")).then(|| format!("{}.git", base))
+    }
 
-        let mut lines = self.parse_pkt_lines(&resp.bytes()?)?;
-        ensure!(lines.pop_front() == Some(PktLine::Data(Box::from(*b"NAK\n"))));
-        ensure!(lines.is_empty());
+    /// Checks the final response, returning it with the base URL it came from
+    fn finish(&self, mut resp: HttpResponse) -> Result<(HttpResponse, String)> {
+        if resp.status != StatusCode::NOT_MODIFIED {
+            resp = resp.check_status()?;
+            validate_content_type(&resp.headers, &self.content_type)?;
+        }
+        let url = base_url(&resp.url)?;
+        Ok((resp, url))
+    }
+}
+
+/// Cache file of the advertisement of the service at the URL
+fn cache_path(repo: &GitRepo, url: &str, service: &str) -> PathBuf {
+    let key = HashAlgo::Sha1.digest(format!("{}\n{}", url, service).as_bytes());
+    repo.common_dir.join("http-cache").join(key.to_hex())
+}
+
+/// Body of the advertisement response, taken from the cache when it was not modified.
+/// Other responses replace the cached one. Returns whether the cache was used.
+fn advertisement_body(
+    resp: HttpResponse,
+    cached: Option<CachedAdvertisement>,
+    cache_path: &Path,
+) -> Result<(Vec<u8>, bool)> {
+    match cached {
+        Some(cached) if resp.status == StatusCode::NOT_MODIFIED => Ok((cached.body, true)),
+        _ => {
+            let header = |name| resp.headers.get(name)?.to_str().ok().map(String::from);
+            let advertisement = CachedAdvertisement {
+                etag: header(ETAG),
+                last_modified: header(LAST_MODIFIED),
+                body: resp.body,
+            };
+            advertisement.store(cache_path)?;
+            Ok((advertisement.body, false))
+        }
+    }
+}
+
+/// Ref advertisement kept from the last request, with validators of its response
+struct CachedAdvertisement {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body: Vec<u8>,
+}
 
+impl CachedAdvertisement {
+    fn load(path: &Path) -> Option<Self> {
+        Self::parse(&fs::read(path).ok()?)
+    }
+
+    /// Parses `etag` and `last-modified` header lines, then empty line and the body
+    fn parse(data: &[u8]) -> Option<Self> {
+        let end = data.windows(2).position(|w| w == b"\n\n")?;
+        let mut cached = Self {
+            etag: None,
+            last_modified: None,
+            body: data[end + 2..].to_vec(),
+        };
+        for line in std::str::from_utf8(&data[..end]).ok()?.lines() {
+            match line.split_once(' ')? {
+                ("etag", value) => cached.etag = Some(value.to_string()),
+                ("last-modified", value) => cached.last_modified = Some(value.to_string()),
+                _ => return None,
+            }
+        }
+        Some(cached)
+    }
+
+    /// Writes the advertisement to the cache file, or removes the file when the
+    /// response cannot be validated later
+    fn store(&self, path: &Path) -> Result<()> {
+        if self.etag.is_none() && self.last_modified.is_none() {
+            if path.exists() {
+                fs::remove_file(path).context("Removing cached ref advertisement")?;
+            }
+            return Ok(());
+        }
+        let mut data = String::new();
+        if let Some(etag) = &self.etag {
+            data.push_str(&format!("etag {}\n", etag));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            data.push_str(&format!("last-modified {}\n", last_modified));
+        }
+        data.push('\n');
+        let mut data = data.into_bytes();
+        data.extend(&self.body);
+        fs::create_dir_all(path.parent().unwrap()).context("Creating HTTP cache dir")?;
+        fs::write(path, data).context("Caching ref advertisement")
+    }
+}
+
+/// Strips the `info/refs` endpoint from the (possibly redirected) advertisement URL.
+fn base_url(advertisement_url: &Url) -> Result<String> {
+    let mut url = advertisement_url.clone();
+    url.set_query(None);
+    Ok(url
+        .as_str()
+        .strip_suffix("/info/refs")
+        .ok_or(format_err!("Unexpected redirect target {}", url))?
+        .to_string())
+}
+
+/// Decompresses response body according to `Content-Encoding` header.
+fn decode_body(headers: &HeaderMap, raw: &[u8]) -> Result<Vec<u8>> {
+    let encoding = headers
+        .get(CONTENT_ENCODING)
+        .map(|v| v.to_str().map(|s| s.trim().to_ascii_lowercase()))
+        .transpose()
+        .context("Cannot convert Content-Encoding header value to str")?;
+    let mut buf = Vec::new();
+    match encoding.as_deref() {
+        None | Some("identity") => return Ok(raw.to_vec()),
+        Some("gzip") | Some("x-gzip") => {
+            GzDecoder::new(raw)
+                .read_to_end(&mut buf)
+                .context("Decoding gzip response")?;
+        }
+        Some("deflate") => {
+            // Spec says zlib wrapped, but some servers send raw deflate stream
+            if ZlibDecoder::new(raw).read_to_end(&mut buf).is_err() {
+                buf.clear();
+                DeflateDecoder::new(raw)
+                    .read_to_end(&mut buf)
+                    .context("Decoding deflate response")?;
+            }
+        }
+        Some(other) => bail!("Unsupported Content-Encoding {}", other),
+    }
+    Ok(buf)
+}
+
+fn validate_content_type(headers: &HeaderMap, content_type: &str) -> Result<()> {
+    let actual_content_type = headers
+        .get(CONTENT_TYPE)
+        .ok_or(Error::protocol("Missing Content-Type header"))?
+        .to_str()
+        .context("Cannot convert Content-Type header value to str")?;
+    ensure!(
+        actual_content_type == content_type,
+        Error::protocol(format!(
+            "Unexpected Content-Type header value. Got {}",
+            actual_content_type
+        ))
+    );
+    Ok(())
+}
+
+/// Parses pkt-lines, unpacking packfile data if present.
+fn parse_pkt_lines(repo: &GitRepo, lines: &[u8]) -> Result<VecDeque<PktLine>> {
+    let (pkt_lines, pack_data) = pkt_line::parse(lines)?;
+    if !pack_data.is_empty() {
+        pack::unpack(repo, pack_data, fsck::transfer_enabled(repo, "fetch")?)?;
+    }
+    Ok(pkt_lines)
+}
+
+fn parse_ref_info(repo: &GitRepo, service: &str, body: &[u8]) -> Result<RefInfo> {
+    let service_bytes = service.as_bytes();
+    let mut lines = parse_pkt_lines(repo, body)?;
+    if let Some(PktLine::Data(data)) = lines.pop_front() {
+        ensure!(
+            data.len() == 10 + service_bytes.len()
+                || (data.len() == 10 + service_bytes.len() + 1 && data.last() == Some(&b'\n')),
+            Error::protocol("Invalid header line")
+        );
+        ensure!(
+            &data[..10] == b"# service=",
+            Error::protocol("Invalid header prefix")
+        );
+        ensure!(
+            &data[10..(10 + service_bytes.len())] == service_bytes,
+            Error::protocol("Invalid header value")
+        );
+    } else {
+        bail!(Error::protocol("Invalid header line"));
+    }
+    ensure!(
+        lines.pop_front() == Some(PktLine::Flush),
+        Error::protocol("Missing flush after service header")
+    );
+
+    let mut refs = Vec::new();
+
+    fn parse_line(refs: &mut Vec<Ref>, mut data: &[u8]) -> Result<()> {
+        if data.last() == Some(&b'\n') {
+            data = &data[..data.len() - 1];
+        }
+        let split = data
+            .iter()
+            .position(|b| *b == b' ')
+            .ok_or(Error::protocol("Invalid ref line"))?;
+        let id = ObjectId::from_hex(&data[..split])?;
+        let name = &data[split + 1..];
+        ensure!(!name.is_empty(), Error::protocol("Invalid ref line"));
+        if name.ends_with(b"^{}") {
+            let l = refs
+                .last_mut()
+                .ok_or(Error::protocol("Peeled ref cannot be the first entry"))?;
+            ensure!(
+                l.name.as_bytes() == &name[..name.len() - 3],
+                Error::protocol("Peeled ref does not follow its ref")
+            );
+            ensure!(l.peeled_ref.is_none(), Error::protocol("Ref peeled twice"));
+            l.peeled_ref = Some(id);
+        } else {
+            let name = std::str::from_utf8(name)?.to_string();
+            refs.push(Ref {
+                name,
+                id,
+                peeled_ref: None,
+            })
+        }
         Ok(())
     }
+
+    let capabilities;
+
+    if let PktLine::Data(data) = lines
+        .pop_front()
+        .ok_or(Error::protocol("Missing first data line"))?
+    {
+        let pos = data
+            .iter()
+            .position(|x| *x == b'\0')
+            .ok_or(Error::protocol("Missing null-byte in first data line"))?;
+        let (refs_bytes, capabilities_bytes) = data.split_at(pos);
+        let capabilities_bytes = &capabilities_bytes[1..];
+        let capabilities_bytes = capabilities_bytes
+            .strip_suffix(b"\n")
+            .unwrap_or(capabilities_bytes);
+        capabilities = capabilities_bytes
+            .split(|x| *x == b' ')
+            .map(|s| {
+                std::str::from_utf8(s)
+                    .map(String::from)
+                    .context("Capabilities must be valid strs")
+            })
+            .collect::<Result<_>>()?;
+
+        if data.starts_with(b"0000000000000000000000000000000000000000") {
+            ensure!(
+                lines.pop_front() == Some(PktLine::Flush),
+                Error::protocol("Data must end with flush line")
+            );
+            ensure!(
+                lines.is_empty(),
+                Error::protocol("Unexpected data after last flush line")
+            );
+            return Ok(RefInfo { capabilities, refs });
+        } else {
+            parse_line(&mut refs, refs_bytes)?;
+        }
+    } else {
+        bail!(Error::protocol("Invalid first data line"));
+    };
+
+    while let Some(PktLine::Data(data)) = lines.pop_front() {
+        parse_line(&mut refs, &data)?;
+    }
+    ensure!(
+        lines.is_empty(),
+        Error::protocol("Unexpected data after last flush line")
+    );
+
+    Ok(RefInfo { capabilities, refs })
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum PktLine {
-    Data(Box<[u8]>),
-    Flush,
+fn upload_pack_request(wants: BTreeSet<&ObjectId>, haves: BTreeSet<&ObjectId>) -> Vec<u8> {
+    let mut body = Vec::with_capacity((wants.len() + haves.len()) * 74 + 4 + 9);
+    for r in wants {
+        pkt_line::write_data(&mut body, format!("want {}\n", r).as_bytes());
+    }
+    pkt_line::write_flush(&mut body);
+    for r in haves {
+        pkt_line::write_data(&mut body, format!("have {}\n", r).as_bytes());
+    }
+    body.extend(b"0009done\n");
+    body
 }
 
-#[allow(non_camel_case_types)]
+fn parse_upload_pack_result(repo: &GitRepo, body: &[u8]) -> Result<()> {
+    let mut lines = parse_pkt_lines(repo, body)?;
+    let first = lines.pop_front();
+    let acknowledgement = first.as_ref().and_then(|l| l.text());
+    if let Some(error) = acknowledgement.and_then(|l| l.strip_prefix(b"ERR ")) {
+        bail!(Error::protocol(format!(
+            "remote error: {}",
+            String::from_utf8_lossy(error)
+        )));
+    }
+    ensure!(
+        acknowledgement == Some(b"NAK") || acknowledgement.is_some_and(|l| l.starts_with(b"ACK ")),
+        Error::protocol(format!("Unexpected upload-pack response {:?}", first))
+    );
+    ensure!(
+        lines.is_empty(),
+        Error::protocol("Unexpected data after upload-pack response")
+    );
+    Ok(())
+}
+
+/// Single ref update command sent to receive-pack
+pub struct RefUpdate {
+    pub name: String,
+    /// Current remote value, `None` when creating the ref
+    pub old: Option<ObjectId>,
+    /// New value, `None` when deleting the ref
+    pub new: Option<ObjectId>,
+}
+
+/// Result of ref update as reported by receive-pack
 #[derive(Debug)]
-pub enum PackEntryType {
-    OBJ_COMMIT,
-    OBJ_TREE,
-    OBJ_BLOB,
-    OBJ_TAG,
-    OBJ_OFS_DELTA,
-    OBJ_REF_DELTA,
+pub struct RefStatus {
+    pub name: String,
+    /// Reason given by the server when the update was rejected
+    pub error: Option<String>,
 }
 
-impl TryFrom<u8> for PackEntryType {
-    type Error = anyhow::Error;
-
-    fn try_from(value: u8) -> Result<Self> {
-        Ok(match value {
-            0 => bail!("Forbidden value"),
-            1 => Self::OBJ_COMMIT,
-            2 => Self::OBJ_TREE,
-            3 => Self::OBJ_BLOB,
-            4 => Self::OBJ_TAG,
-            5 => bail!("Reserved value"),
-            6 => Self::OBJ_OFS_DELTA,
-            7 => Self::OBJ_REF_DELTA,
-            _ => unreachable!(),
+fn parse_report_status(body: &[u8]) -> Result<Vec<RefStatus>> {
+    let (lines, rest) = pkt_line::parse(body)?;
+    ensure!(
+        rest.is_empty(),
+        Error::protocol("Unexpected data in receive-pack response")
+    );
+    let mut lines = lines.iter().filter_map(|l| l.text());
+    let unpack = lines
+        .next()
+        .ok_or(Error::protocol("Missing unpack status"))?;
+    let unpack = std::str::from_utf8(unpack)?;
+    ensure!(
+        unpack == "unpack ok",
+        Error::protocol(format!("Remote failed to {}", unpack))
+    );
+    lines
+        .map(|line| {
+            let line = std::str::from_utf8(line)?;
+            if let Some(name) = line.strip_prefix("ok ") {
+                Ok(RefStatus {
+                    name: name.to_string(),
+                    error: None,
+                })
+            } else if let Some(rest) = line.strip_prefix("ng ") {
+                let (name, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+                Ok(RefStatus {
+                    name: name.to_string(),
+                    error: Some(reason.to_string()),
+                })
+            } else {
+                bail!(Error::protocol(format!(
+                    "Invalid report-status line {:?}",
+                    line
+                )))
+            }
         })
-    }
+        .collect()
 }
 
 pub struct Ref {
     pub name: String,
-    pub id: [u8; 40],
-    pub peeled_ref: Option<[u8; 40]>,
+    pub id: ObjectId,
+    pub peeled_ref: Option<ObjectId>,
 }
 
 pub struct RefInfo {
     pub capabilities: Vec<String>,
     pub refs: Vec<Ref>,
 }
+
+impl RefInfo {
+    /// Hash function of the remote repository from `object-format` capability, servers
+    /// not advertising it use SHA-1
+    pub fn object_format(&self) -> Result<HashAlgo> {
+        match self
+            .capabilities
+            .iter()
+            .find_map(|c| c.strip_prefix("object-format="))
+        {
+            Some(name) => name.parse(),
+            None => Ok(HashAlgo::Sha1),
+        }
+    }
+
+    /// Fails unless the remote names objects with the same hash function as the repository
+    pub fn ensure_object_format(&self, repo: &GitRepo) -> Result<()> {
+        let remote = self.object_format()?;
+        ensure!(
+            remote == repo.hash_algo(),
+            Error::protocol(format!(
+                "mismatched algorithms: client {}; server {}",
+                repo.hash_algo(),
+                remote
+            ))
+        );
+        Ok(())
+    }
+}
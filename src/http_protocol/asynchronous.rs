@@ -0,0 +1,88 @@
+use std::collections::BTreeSet;
+
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Response};
+
+use super::{
+    advertisement_body, cache_path, parse_ref_info, parse_upload_pack_result, service_headers,
+    upload_pack_request, CachedAdvertisement, HttpResponse, InfoRefs, RefInfo,
+};
+use crate::error::{Context, Result};
+use crate::oid::ObjectId;
+use crate::GitRepo;
+
+/// Async counterpart of [`super::GitHttpClient`].
+///
+/// Network IO runs on the async runtime, while decoding the pack and writing objects
+/// is moved to a blocking thread so large clones don't stall the runtime.
+pub struct AsyncGitHttpClient {
+    repo: GitRepo,
+    client: Client,
+    url: String,
+}
+
+impl AsyncGitHttpClient {
+    pub fn new(repo: &GitRepo, url: String) -> Self {
+        Self {
+            repo: repo.clone(),
+            // Default client follows up to 10 redirects
+            client: Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    async fn read(resp: Response) -> Result<HttpResponse> {
+        let (status, headers, url) = (resp.status(), resp.headers().clone(), resp.url().clone());
+        let raw = resp.bytes().await.context("Reading response body")?;
+        HttpResponse::new(status, headers, url, &raw)
+    }
+
+    async fn get(&self, url: &str, headers: HeaderMap) -> Result<HttpResponse> {
+        let resp = self.client.get(url).headers(headers).send().await;
+        Self::read(resp.context("Requesting info/refs")?).await
+    }
+
+    /// Posts request to the service, returning body of its result
+    async fn post(&self, service: &str, body: Vec<u8>) -> Result<Vec<u8>> {
+        let resp = self
+            .client
+            .post(format!("{}/{}", self.url, service))
+            .headers(service_headers(service))
+            .body(body)
+            .send()
+            .await?;
+        Self::read(resp).await?.service_result(service)
+    }
+
+    async fn request_info_refs(&mut self, request: &InfoRefs<'_>) -> Result<HttpResponse> {
+        let mut resp = self.get(&request.url(&self.url), request.headers()).await?;
+        if let Some(url) = request.retry_url(&self.url, &resp) {
+            let retry = self.get(&request.url(&url), request.headers()).await?;
+            if request.usable(&retry) {
+                resp = retry;
+            }
+        }
+        let (resp, url) = request.finish(resp)?;
+        self.url = url;
+        Ok(resp)
+    }
+
+    pub async fn ref_info(&mut self) -> Result<RefInfo> {
+        let service = "git-upload-pack";
+        let cache_path = cache_path(&self.repo, &self.url, service);
+        let cached = CachedAdvertisement::load(&cache_path);
+        let request = InfoRefs::new(service, cached.as_ref());
+        let resp = self.request_info_refs(&request).await?;
+        let (body, _) = advertisement_body(resp, cached, &cache_path)?;
+        parse_ref_info(&self.repo, service, &body)
+    }
+
+    pub async fn fetch_refs(&self, refs: BTreeSet<&ObjectId>) -> Result<()> {
+        let body = upload_pack_request(refs, BTreeSet::new());
+        let body = self.post("git-upload-pack", body).await?;
+        let repo = self.repo.clone();
+        tokio::task::spawn_blocking(move || parse_upload_pack_result(&repo, &body))
+            .await
+            .context("Pack processing task failed")?
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::error::{Context, Result};
+use crate::pathname;
+use crate::GitRepo;
+
+/// Matches path against glob pattern the way git's wildmatch does with `/` being
+/// special: `*`, `?` and `[...]` never match it, while `**` as a whole path component
+/// matches any number of directories
+pub fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                let double = pattern.get(p + 1) == Some(&b'*');
+                let component_start = p == 0 || pattern[p - 1] == b'/';
+                if double && component_start && p + 2 == pattern.len() {
+                    return true;
+                }
+                if double && component_start && pattern[p + 2] == b'/' {
+                    // `**/` matches zero or more leading directories
+                    let rest = &pattern[p + 3..];
+                    return wildmatch(rest, &text[t..])
+                        || (t..text.len())
+                            .any(|i| text[i] == b'/' && wildmatch(rest, &text[i + 1..]));
+                }
+                while pattern.get(p) == Some(&b'*') {
+                    p += 1;
+                }
+                let rest = &pattern[p..];
+                for i in t..=text.len() {
+                    if wildmatch(rest, &text[i..]) {
+                        return true;
+                    }
+                    if i < text.len() && text[i] == b'/' {
+                        break;
+                    }
+                }
+                return false;
+            }
+            b'?' => {
+                if t >= text.len() || text[t] == b'/' {
+                    return false;
+                }
+            }
+            b'[' => match match_class(&pattern[p + 1..], text.get(t).copied()) {
+                Some((matched, len)) => {
+                    if !matched {
+                        return false;
+                    }
+                    p += len;
+                }
+                // Unterminated bracket is literal
+                None => {
+                    if text.get(t) != Some(&b'[') {
+                        return false;
+                    }
+                }
+            },
+            b'\\' if p + 1 < pattern.len() => {
+                p += 1;
+                if text.get(t) != Some(&pattern[p]) {
+                    return false;
+                }
+            }
+            c => {
+                if text.get(t) != Some(&c) {
+                    return false;
+                }
+            }
+        }
+        p += 1;
+        t += 1;
+    }
+    t == text.len()
+}
+
+/// Matches character against bracket expression following `[`. Returns whether it
+/// matched and length of the expression, `None` when there is no closing bracket.
+fn match_class(class: &[u8], c: Option<u8>) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = matches!(class.first(), Some(b'!' | b'^'));
+    if negated {
+        i += 1;
+    }
+    let start = i;
+    let mut matched = false;
+    loop {
+        let mut lo = *class.get(i)?;
+        if lo == b']' && i > start {
+            break;
+        }
+        if lo == b'\\' {
+            i += 1;
+            lo = *class.get(i)?;
+        }
+        let mut hi = lo;
+        if class.get(i + 1) == Some(&b'-') && class.get(i + 2).is_some_and(|c| *c != b']') {
+            i += 2;
+            hi = class[i];
+            if hi == b'\\' {
+                i += 1;
+                hi = *class.get(i)?;
+            }
+        }
+        if c.is_some_and(|c| lo <= c && c <= hi) {
+            matched = true;
+        }
+        i += 1;
+    }
+    let matched = c.is_some_and(|c| c != b'/') && matched != negated;
+    Some((matched, i + 1))
+}
+
+/// Single line of ignore file
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    /// The line as written in the file
+    pub original: String,
+    glob: Vec<u8>,
+    /// Starts with `!`, re-includes paths excluded by previous patterns
+    pub negated: bool,
+    /// Ends with `/`, matches only directories
+    dir_only: bool,
+    /// Contains `/`, matched against the whole path instead of the file name
+    anchored: bool,
+    /// Directory of the ignore file relative to the repository root, with trailing `/`
+    base: Vec<u8>,
+    /// File the pattern comes from
+    pub source: PathBuf,
+    /// 1-based line number in the source
+    pub line: usize,
+}
+
+impl Pattern {
+    /// Parses line of ignore file, `None` for blank lines and comments
+    pub fn parse(line: &[u8], base: &[u8], source: &Path, line_number: usize) -> Option<Self> {
+        if line.is_empty() || line.starts_with(b"#") {
+            return None;
+        }
+        // Trailing spaces are ignored unless escaped with backslash
+        let mut end = line.iter().rposition(|b| *b != b' ').map_or(0, |i| i + 1);
+        if line[..end].ends_with(b"\\") && end < line.len() {
+            end += 1;
+        }
+        let mut glob = &line[..end];
+        let negated = glob.starts_with(b"!");
+        if negated {
+            glob = &glob[1..];
+        }
+        let dir_only = glob.ends_with(b"/");
+        glob = pathname::trim_slash(glob);
+        if glob.is_empty() {
+            return None;
+        }
+        let anchored = glob.contains(&b'/');
+        let start = glob.iter().position(|b| *b != b'/').unwrap_or(glob.len());
+        Some(Self {
+            original: pathname::display(line).into_owned(),
+            glob: glob[start..].to_vec(),
+            negated,
+            dir_only,
+            anchored,
+            base: base.to_vec(),
+            source: source.to_path_buf(),
+            line: line_number,
+        })
+    }
+
+    /// Checks whether the path relative to the repository root matches the pattern
+    pub fn matches(&self, path: &[u8], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let Some(relative) = path.strip_prefix(self.base.as_slice()) else {
+            return false;
+        };
+        if self.anchored {
+            wildmatch(&self.glob, relative)
+        } else {
+            let name = pathname::split_last(relative).map_or(relative, |(_, name)| name);
+            wildmatch(&self.glob, name)
+        }
+    }
+}
+
+/// Reads patterns from ignore file, missing file has no patterns
+fn read_patterns(path: &Path, base: &[u8]) -> Result<Vec<Pattern>> {
+    let Ok(content) = fs::read(path) else {
+        return Ok(Vec::new());
+    };
+    Ok(lines(&content)
+        .enumerate()
+        .filter_map(|(i, line)| Pattern::parse(line, base, path, i + 1))
+        .collect())
+}
+
+/// Lines of ignore or attributes file without the line endings
+pub(crate) fn lines(content: &[u8]) -> impl Iterator<Item = &[u8]> {
+    content
+        .strip_suffix(b"\n")
+        .unwrap_or(content)
+        .split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+/// Decides which untracked files are ignored using `.gitignore` files of the working
+/// tree directories, `.git/info/exclude` and the file given by `core.excludesFile`
+pub struct Ignore {
+    root: PathBuf,
+    /// Patterns of exclude files, lowest priority first
+    exclude: Vec<Pattern>,
+    /// Patterns of `.gitignore` files keyed by their directory, loaded on demand
+    per_dir: HashMap<Vec<u8>, Vec<Pattern>>,
+}
+
+/// User exclude file, `core.excludesFile` or `$XDG_CONFIG_HOME/git/ignore` by default
+fn excludes_file(config: &Config) -> Option<PathBuf> {
+    if let Some(path) = config.get_path("core.excludesFile") {
+        return Some(path);
+    }
+    let xdg = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(xdg.join("git").join("ignore"))
+}
+
+impl Ignore {
+    pub fn load(repo: &GitRepo) -> Result<Self> {
+        let mut exclude = Vec::new();
+        if let Some(path) = excludes_file(&Config::load(repo)?) {
+            exclude = read_patterns(&path, b"").context("Reading core.excludesFile")?;
+        }
+        exclude.extend(
+            read_patterns(&repo.common_dir.join("info").join("exclude"), b"")
+                .context("Reading info/exclude")?,
+        );
+        Ok(Self {
+            root: repo.repo_root.clone(),
+            exclude,
+            per_dir: HashMap::new(),
+        })
+    }
+
+    fn dir_patterns(&mut self, dir: &[u8]) -> Result<&[Pattern]> {
+        if !self.per_dir.contains_key(dir) {
+            let path = pathname::join(&self.root, dir).join(".gitignore");
+            let patterns = read_patterns(&path, dir)?;
+            self.per_dir.insert(dir.to_vec(), patterns);
+        }
+        Ok(&self.per_dir[dir])
+    }
+
+    /// Last pattern matching the path itself, ignoring excluded parent directories.
+    /// Patterns of deeper `.gitignore` files take precedence.
+    fn matching_pattern(&mut self, path: &[u8], is_dir: bool) -> Result<Option<Pattern>> {
+        let mut dirs = vec![&path[..0]];
+        for (i, b) in path.iter().enumerate() {
+            if *b == b'/' {
+                dirs.push(&path[..i + 1]);
+            }
+        }
+        for dir in dirs.iter().rev() {
+            let found = self
+                .dir_patterns(dir)?
+                .iter()
+                .rev()
+                .find(|p| p.matches(path, is_dir));
+            if let Some(pattern) = found {
+                return Ok(Some(pattern.clone()));
+            }
+        }
+        Ok(self
+            .exclude
+            .iter()
+            .rev()
+            .find(|p| p.matches(path, is_dir))
+            .cloned())
+    }
+
+    /// Pattern deciding about the path, which may be a negated one. Paths inside excluded
+    /// directories are excluded by the pattern of the directory.
+    pub fn pattern_for(&mut self, path: &[u8], is_dir: bool) -> Result<Option<Pattern>> {
+        for (i, b) in path.iter().enumerate() {
+            if *b == b'/' {
+                if let Some(pattern) = self.matching_pattern(&path[..i], true)? {
+                    if !pattern.negated {
+                        return Ok(Some(pattern));
+                    }
+                }
+            }
+        }
+        self.matching_pattern(path, is_dir)
+    }
+
+    /// Checks whether the path relative to the repository root is ignored
+    pub fn is_ignored(&mut self, path: &[u8], is_dir: bool) -> Result<bool> {
+        Ok(self
+            .pattern_for(path, is_dir)?
+            .is_some_and(|pattern| !pattern.negated))
+    }
+}
@@ -0,0 +1,683 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use crate::attributes::Attributes;
+use crate::error::{bail, ensure, format_err, Context, Result};
+use crate::hash::HashAlgo;
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::pathname;
+use crate::platform;
+use crate::refs::LockFile;
+use crate::storage::FsyncComponent;
+use crate::submodule::{self, GITLINK_MODE};
+use crate::tree::{FileMode, Tree, TreeEntry};
+use crate::GitRepo;
+
+const SIGNATURE: &[u8; 4] = b"DIRC";
+/// Size of the stat fields preceding the object id of entry
+const ENTRY_STAT_LEN: usize = 40;
+const FLAG_EXTENDED: u16 = 0x4000;
+const NAME_MASK: u16 = 0x0fff;
+
+/// Single entry of the index (dircache), paths are relative to the repository root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub ctime: (u32, u32),
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    /// Git file mode, e.g. `0o100644`
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub id: ObjectId,
+    /// 0 for merged entries, 1-3 for base, ours and theirs version of conflicted path
+    pub stage: u8,
+    /// Raw bytes of the path, not necessarily UTF-8
+    pub path: Vec<u8>,
+}
+
+impl IndexEntry {
+    /// Entry without stat information, as if the file was never checked out
+    pub fn new(path: &[u8], mode: u32, id: &ObjectId, stage: u8) -> Self {
+        Self {
+            ctime: (0, 0),
+            mtime: (0, 0),
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            id: *id,
+            stage,
+            path: path.to_vec(),
+        }
+    }
+
+    /// Entry with stat information of the checked out file
+    pub fn from_metadata(path: &[u8], mode: u32, id: &ObjectId, metadata: &fs::Metadata) -> Self {
+        let mut entry = Self::new(path, mode, id, 0);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            entry.ctime = (metadata.ctime() as u32, metadata.ctime_nsec() as u32);
+            entry.mtime = (metadata.mtime() as u32, metadata.mtime_nsec() as u32);
+            entry.dev = metadata.dev() as u32;
+            entry.ino = metadata.ino() as u32;
+            entry.uid = metadata.uid();
+            entry.gid = metadata.gid();
+        }
+        entry.size = metadata.len() as u32;
+        entry
+    }
+
+    fn parse(data: &[u8], version: u32, algo: HashAlgo) -> Result<(Self, usize)> {
+        let id_end = ENTRY_STAT_LEN + algo.id_len();
+        ensure!(data.len() >= id_end + 2, "Truncated index entry");
+        let word = |i: usize| u32::from_be_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+        let flags = u16::from_be_bytes([data[id_end], data[id_end + 1]]);
+        let mut start = id_end + 2;
+        if flags & FLAG_EXTENDED != 0 {
+            ensure!(
+                version >= 3,
+                "Extended index entry in version {} index",
+                version
+            );
+            // Extended flags (skip-worktree, intent-to-add) are not supported and are dropped
+            start += 2;
+        }
+        let len = data[start..]
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or(format_err!("Unterminated index entry path"))?;
+        let path = data[start..start + len].to_vec();
+        let entry = Self {
+            ctime: (word(0), word(1)),
+            mtime: (word(2), word(3)),
+            dev: word(4),
+            ino: word(5),
+            mode: word(6),
+            uid: word(7),
+            gid: word(8),
+            size: word(9),
+            id: ObjectId::from_slice(&data[ENTRY_STAT_LEN..id_end])?,
+            stage: ((flags >> 12) & 0x3) as u8,
+            path,
+        };
+        // Entries are padded with 1-8 NUL bytes to multiple of 8
+        Ok((entry, (start + len + 8) & !7))
+    }
+
+    fn write_to(&self, data: &mut Vec<u8>) -> Result<()> {
+        let start = data.len();
+        for word in [
+            self.ctime.0,
+            self.ctime.1,
+            self.mtime.0,
+            self.mtime.1,
+            self.dev,
+            self.ino,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.size,
+        ] {
+            data.extend(word.to_be_bytes());
+        }
+        data.extend(self.id.as_bytes());
+        let len = self.path.len().min(NAME_MASK as usize) as u16;
+        let flags = ((self.stage as u16) << 12) | len;
+        data.extend(flags.to_be_bytes());
+        data.extend(&self.path);
+        let len = data.len() - start;
+        data.resize(start + ((len + 8) & !7), 0);
+        Ok(())
+    }
+}
+
+/// The staging area stored in `.git/index`, entries sorted by path and stage
+#[derive(Debug, Clone, Default)]
+pub struct Index {
+    pub entries: Vec<IndexEntry>,
+}
+
+impl Index {
+    /// Reads the index, missing index file is the same as empty one
+    pub fn read(repo: &GitRepo) -> Result<Self> {
+        let path = repo.git_dir.join("index");
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::parse(&fs::read(path).context("Reading index")?, repo.hash_algo())
+    }
+
+    /// Parses index with object ids and trailing checksum of the algorithm
+    pub fn parse(data: &[u8], algo: HashAlgo) -> Result<Self> {
+        ensure!(data.len() >= 12 + algo.id_len(), "Index file is too short");
+        let (content, checksum) = data.split_at(data.len() - algo.id_len());
+        ensure!(
+            algo.digest(content).as_bytes() == checksum,
+            "Index checksum mismatch"
+        );
+        ensure!(&content[..4] == SIGNATURE, "Invalid index signature");
+        let version = u32::from_be_bytes(content[4..8].try_into().unwrap());
+        ensure!(
+            version == 2 || version == 3,
+            "Unsupported index version {}",
+            version
+        );
+        let count = u32::from_be_bytes(content[8..12].try_into().unwrap());
+
+        let mut rest = &content[12..];
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let (entry, len) = IndexEntry::parse(rest, version, algo)?;
+            ensure!(len <= rest.len(), "Truncated index entry");
+            rest = &rest[len..];
+            entries.push(entry);
+        }
+        while !rest.is_empty() {
+            ensure!(rest.len() >= 8, "Truncated index extension");
+            let size = u32::from_be_bytes(rest[4..8].try_into().unwrap()) as usize;
+            // Uppercase extensions are optional caches which we can drop
+            ensure!(
+                rest[0].is_ascii_uppercase(),
+                "Unsupported index extension {}",
+                String::from_utf8_lossy(&rest[..4])
+            );
+            ensure!(rest.len() >= 8 + size, "Truncated index extension");
+            rest = &rest[8 + size..];
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn to_bytes(&self, algo: HashAlgo) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        data.extend(SIGNATURE);
+        data.extend(2u32.to_be_bytes());
+        data.extend((self.entries.len() as u32).to_be_bytes());
+        for entry in &self.entries {
+            entry.write_to(&mut data)?;
+        }
+        let checksum = algo.digest(&data);
+        data.extend(checksum.as_bytes());
+        Ok(data)
+    }
+
+    pub fn write(&self, repo: &GitRepo) -> Result<()> {
+        let lock = LockFile::acquire(repo.git_dir.join("index"))?;
+        let fsync = repo.write_options()?.fsync(FsyncComponent::Index);
+        lock.commit(&self.to_bytes(repo.hash_algo())?, fsync)
+    }
+
+    fn position(&self, path: &[u8], stage: u8) -> std::result::Result<usize, usize> {
+        self.entries
+            .binary_search_by(|e| e.path.as_slice().cmp(path).then(e.stage.cmp(&stage)))
+    }
+
+    pub fn get(&self, path: &[u8], stage: u8) -> Option<&IndexEntry> {
+        self.position(path, stage).ok().map(|i| &self.entries[i])
+    }
+
+    /// Inserts entry, replacing entry with the same path and stage
+    pub fn add(&mut self, entry: IndexEntry) {
+        match self.position(&entry.path, entry.stage) {
+            Ok(i) => self.entries[i] = entry,
+            Err(i) => self.entries.insert(i, entry),
+        }
+    }
+
+    /// Removes all stages of given path
+    pub fn remove(&mut self, path: &[u8]) {
+        self.entries.retain(|e| e.path != path);
+    }
+
+    /// Paths which have unmerged entries
+    pub fn conflicts(&self) -> Vec<&[u8]> {
+        let mut paths: Vec<&[u8]> = self
+            .entries
+            .iter()
+            .filter(|e| e.stage != 0)
+            .map(|e| e.path.as_slice())
+            .collect();
+        paths.dedup();
+        paths
+    }
+
+    /// Index with content of given tree and no stat information, like `git read-tree`
+    pub fn from_tree(repo: &GitRepo, tree_id: &ObjectId) -> Result<Self> {
+        let entries = Tree::flatten(repo, tree_id)?
+            .into_iter()
+            .map(|(path, (mode, id))| IndexEntry::new(&path, mode, &id, 0))
+            .collect();
+        Ok(Self { entries })
+    }
+
+    /// Stage 0 entry for the path, reusing stat information of the current entry when it
+    /// has the same content
+    fn entry_for(&self, path: &[u8], mode: u32, id: &ObjectId) -> IndexEntry {
+        match self.get(path, 0) {
+            Some(old) if old.mode == mode && old.id == *id => old.clone(),
+            _ => IndexEntry::new(path, mode, id, 0),
+        }
+    }
+
+    fn ensure_merged(&self) -> Result<()> {
+        match self.conflicts().first() {
+            Some(path) => bail!(
+                "{}: you need to resolve your current index first",
+                pathname::display(path)
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Index with content of the tree keeping stat information of unchanged entries, like
+    /// `git read-tree -m <tree>`
+    pub fn read_tree(&self, repo: &GitRepo, tree_id: &ObjectId) -> Result<Self> {
+        self.ensure_merged()?;
+        let entries = Tree::flatten(repo, tree_id)?
+            .into_iter()
+            .map(|(path, (mode, id))| self.entry_for(&path, mode, &id))
+            .collect();
+        Ok(Self { entries })
+    }
+
+    /// Index moved from tree `from` to tree `to` keeping changes staged on top of `from`,
+    /// like `git read-tree -m <from> <to>`. Fails for paths changed both in the index and
+    /// between the trees.
+    pub fn two_way_merge(&self, repo: &GitRepo, from: &ObjectId, to: &ObjectId) -> Result<Self> {
+        self.ensure_merged()?;
+        let staged = self.to_map();
+        let from = Tree::flatten(repo, from)?;
+        let to = Tree::flatten(repo, to)?;
+        let paths: BTreeSet<&Vec<u8>> = staged.keys().chain(from.keys()).chain(to.keys()).collect();
+        let mut result = Self::default();
+        for path in paths {
+            let (i, f, t) = (staged.get(path), from.get(path), to.get(path));
+            let entry = if f == t || i == t {
+                i
+            } else if i == f {
+                t
+            } else {
+                bail!(
+                    "Entry '{}' would be overwritten by merge. Cannot merge.",
+                    pathname::display(path)
+                );
+            };
+            if let Some((mode, id)) = entry {
+                result.add(self.entry_for(path, *mode, id));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Three-way merge of trees resolving only paths changed on at most one side, the
+    /// rest is left as unmerged stages 1-3, like `git read-tree -m <base> <ours> <theirs>`.
+    /// Paths deleted on either side are resolved only when `aggressive`. The index
+    /// has to match `ours` at paths the merge changes.
+    pub fn three_way_merge(
+        &self,
+        repo: &GitRepo,
+        [base, ours, theirs]: [&ObjectId; 3],
+        aggressive: bool,
+    ) -> Result<Self> {
+        self.ensure_merged()?;
+        let staged = self.to_map();
+        let base = Tree::flatten(repo, base)?;
+        let ours = Tree::flatten(repo, ours)?;
+        let theirs = Tree::flatten(repo, theirs)?;
+        let paths: BTreeSet<&Vec<u8>> = staged
+            .keys()
+            .chain(base.keys())
+            .chain(ours.keys())
+            .chain(theirs.keys())
+            .collect();
+        let mut result = Self::default();
+        for path in paths {
+            let (i, b, o, t) = (
+                staged.get(path),
+                base.get(path),
+                ours.get(path),
+                theirs.get(path),
+            );
+            let merged = if o == t && (aggressive || o.is_some()) {
+                Some(o)
+            } else if b == o && (aggressive || t.is_some()) {
+                Some(t)
+            } else if b == t && (aggressive || o.is_some()) {
+                Some(o)
+            } else {
+                None
+            };
+            if merged == Some(o) {
+                // Untouched by the merge, keep what is staged
+                if let Some((mode, id)) = i {
+                    result.add(self.entry_for(path, *mode, id));
+                }
+                continue;
+            }
+            ensure!(
+                i == o,
+                "Entry '{}' would be overwritten by merge. Cannot merge.",
+                pathname::display(path)
+            );
+            match merged {
+                Some(Some((mode, id))) => result.add(self.entry_for(path, *mode, id)),
+                Some(None) => {}
+                None => {
+                    for (stage, entry) in [(1, b), (2, o), (3, t)] {
+                        if let Some((mode, id)) = entry {
+                            result.add(IndexEntry::new(path, *mode, id, stage));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Writes tree objects for the index content and returns id of the root tree
+    pub fn write_tree(&self, repo: &GitRepo) -> Result<ObjectId> {
+        self.write_subtree(repo, b"")
+    }
+
+    /// Writes tree objects for entries under the directory and returns id of its tree,
+    /// like `git write-tree --prefix`. Empty prefix is the root.
+    pub fn write_subtree(&self, repo: &GitRepo, prefix: &[u8]) -> Result<ObjectId> {
+        if let Some(path) = self.conflicts().first() {
+            bail!(
+                "{}: unmerged entry, cannot write tree",
+                pathname::display(path)
+            );
+        }
+        let dir = pathname::trim_slash(prefix);
+        let prefix = pathname::concat(dir, b"");
+        let entries: Vec<(&[u8], u32, &ObjectId)> = self
+            .entries
+            .iter()
+            .filter_map(|e| {
+                let path = e.path.strip_prefix(prefix.as_slice())?;
+                Some((path, e.mode, &e.id))
+            })
+            .collect();
+        ensure!(
+            prefix.is_empty() || !entries.is_empty(),
+            "prefix {} not found",
+            pathname::display(dir)
+        );
+        write_tree(repo, &entries)
+    }
+
+    /// Content of the index as path to (mode, id) map, ignoring unmerged entries
+    pub fn to_map(&self) -> BTreeMap<Vec<u8>, (u32, ObjectId)> {
+        self.entries
+            .iter()
+            .filter(|e| e.stage == 0)
+            .map(|e| (e.path.clone(), (e.mode, e.id)))
+            .collect()
+    }
+
+    /// Tracked files in the working tree as path to (mode, id) map. Deleted files are
+    /// missing, unmerged paths are skipped. Ids of modified files are computed without
+    /// writing the blobs.
+    pub fn worktree_map(&self, repo: &GitRepo) -> Result<BTreeMap<Vec<u8>, (u32, ObjectId)>> {
+        let mut map = BTreeMap::new();
+        let mut attributes = Attributes::load(repo)?;
+        for entry in self.entries.iter().filter(|e| e.stage == 0) {
+            if entry.mode == GITLINK_MODE {
+                let id = submodule::head(repo, &entry.path)?.unwrap_or(entry.id);
+                map.insert(entry.path.clone(), (entry.mode, id));
+            } else if Self::is_fresh(entry, &repo.worktree_path(&entry.path)) {
+                map.insert(entry.path.clone(), (entry.mode, entry.id));
+            } else if let Some((mode, data)) =
+                worktree_file_with(repo, &entry.path, self, &mut attributes)?
+            {
+                let id = Object::new(repo.hash_algo(), ObjectKind::Blob, data).hash;
+                map.insert(entry.path.clone(), (mode, id));
+            }
+        }
+        Ok(map)
+    }
+
+    /// Checks whether the file in the working tree is deleted or differs from the entry
+    /// of the index
+    pub fn is_modified(
+        repo: &GitRepo,
+        entry: &IndexEntry,
+        index: &Index,
+        attributes: &mut Attributes,
+    ) -> Result<bool> {
+        if entry.mode == GITLINK_MODE {
+            // Uninitialized submodule is not a change
+            let head = submodule::head(repo, &entry.path)?;
+            return Ok(head.is_some_and(|id| id != entry.id));
+        }
+        if Self::is_fresh(entry, &repo.worktree_path(&entry.path)) {
+            return Ok(false);
+        }
+        Ok(match worktree_file_with(repo, &entry.path, index, attributes)? {
+            Some((mode, data)) => {
+                entry.stage != 0
+                    || mode != entry.mode
+                    || Object::new(repo.hash_algo(), ObjectKind::Blob, data).hash != entry.id
+            }
+            None => true,
+        })
+    }
+
+    /// Stages the file of the working tree, writing its blob. Directory of checked out
+    /// submodule is staged as its HEAD commit. Returns `false` when there is nothing to
+    /// stage at the path.
+    pub fn stage_file(
+        &mut self,
+        repo: &GitRepo,
+        path: &[u8],
+        attributes: &mut Attributes,
+    ) -> Result<bool> {
+        // The tracked path keeps its case when the file is staged under another one
+        let tracked = match self.get(path, 0) {
+            None if platform::ignore_case(repo)? => {
+                let folded = path.to_ascii_lowercase();
+                self.entries
+                    .iter()
+                    .find(|e| e.stage == 0 && e.path.to_ascii_lowercase() == folded)
+                    .map(|e| e.path.clone())
+            }
+            _ => None,
+        };
+        let path = tracked.as_deref().unwrap_or(path);
+        let full_path = repo.worktree_path(path);
+        if full_path.is_dir() {
+            let Some(head) = submodule::head(repo, path)? else {
+                return Ok(false);
+            };
+            self.remove(path);
+            self.add(IndexEntry::new(path, GITLINK_MODE, &head, 0));
+            return Ok(true);
+        }
+        let Some((mode, data)) = worktree_file_with(repo, path, self, attributes)? else {
+            return Ok(false);
+        };
+        let obj = Object::new(repo.hash_algo(), ObjectKind::Blob, data);
+        obj.write(repo)?;
+        let metadata = fs::symlink_metadata(&full_path)?;
+        self.remove(path);
+        self.add(IndexEntry::from_metadata(path, mode, &obj.hash, &metadata));
+        Ok(true)
+    }
+
+    /// Records current stat information of entries whose files are unchanged, so later
+    /// checks don't need to hash them. Returns paths of modified or unmerged entries.
+    pub fn refresh(&mut self, repo: &GitRepo) -> Result<Vec<Vec<u8>>> {
+        let mut modified = Vec::new();
+        let mut attributes = Attributes::load(repo)?;
+        for i in 0..self.entries.len() {
+            let entry = &self.entries[i];
+            let full_path = repo.worktree_path(&entry.path);
+            if entry.mode == GITLINK_MODE || Self::is_fresh(entry, &full_path) {
+                continue;
+            }
+            if Self::is_modified(repo, entry, self, &mut attributes)? {
+                modified.push(entry.path.clone());
+                continue;
+            }
+            let metadata = fs::symlink_metadata(&full_path)?;
+            self.entries[i] =
+                IndexEntry::from_metadata(&entry.path, entry.mode, &entry.id, &metadata);
+        }
+        modified.dedup();
+        Ok(modified)
+    }
+
+    /// Files in the working tree which are not tracked, sorted by path. Directories
+    /// containing another repository are listed with trailing slash instead of their files.
+    /// With `core.ignoreCase` files differing from tracked ones only in case are tracked.
+    pub fn untracked(&self, repo: &GitRepo) -> Result<Vec<Vec<u8>>> {
+        let ignore_case = platform::ignore_case(repo)?;
+        let tracked: BTreeSet<Cow<[u8]>> = self
+            .entries
+            .iter()
+            .map(|e| fold_case(&e.path, ignore_case))
+            .collect();
+        let mut untracked = Vec::new();
+        collect_untracked(&repo.repo_root, b"", &tracked, ignore_case, &mut untracked)?;
+        untracked.sort();
+        Ok(untracked)
+    }
+
+    /// Checks whether stat information of checked out file matches the entry
+    pub fn is_fresh(entry: &IndexEntry, path: &Path) -> bool {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) => {
+                let current =
+                    IndexEntry::from_metadata(&entry.path, entry.mode, &entry.id, &metadata);
+                entry.mtime != (0, 0) && current == *entry
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Lowercase path when case is ignored
+fn fold_case(path: &[u8], ignore_case: bool) -> Cow<'_, [u8]> {
+    if ignore_case {
+        Cow::Owned(path.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(path)
+    }
+}
+
+fn collect_untracked(
+    dir: &Path,
+    prefix: &[u8],
+    tracked: &BTreeSet<Cow<[u8]>>,
+    ignore_case: bool,
+    untracked: &mut Vec<Vec<u8>>,
+) -> Result<()> {
+    for item in fs::read_dir(dir)? {
+        let item = item?;
+        let name = pathname::from_os(&item.file_name());
+        let path = pathname::concat(prefix, &name);
+        // `.git` is a file in linked working trees and submodules
+        if name == b".git" {
+            continue;
+        }
+        let is_tracked = tracked.contains(&fold_case(&path, ignore_case));
+        if item.file_type()?.is_dir() {
+            if is_tracked {
+                continue;
+            }
+            if item.path().join(".git").exists() {
+                untracked.push(pathname::concat(&path, b""));
+            } else {
+                collect_untracked(&item.path(), &path, tracked, ignore_case, untracked)?;
+            }
+        } else if !is_tracked {
+            untracked.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Mode and content of the file in the working tree as it would be stored in blob,
+/// `None` when the file doesn't exist or is a directory
+pub fn worktree_file(repo: &GitRepo, path: &[u8]) -> Result<Option<(u32, Vec<u8>)>> {
+    worktree_file_with(repo, path, &Index::read(repo)?, &mut Attributes::load(repo)?)
+}
+
+/// Same as `worktree_file`, reusing already loaded index and attributes of the
+/// repository, so that they are not read again for every file
+pub fn worktree_file_with(
+    repo: &GitRepo,
+    path: &[u8],
+    index: &Index,
+    attributes: &mut Attributes,
+) -> Result<Option<(u32, Vec<u8>)>> {
+    let full_path = repo.worktree_path(path);
+    let Ok(metadata) = fs::symlink_metadata(&full_path) else {
+        return Ok(None);
+    };
+    if metadata.is_dir() {
+        return Ok(None);
+    }
+    if metadata.is_symlink() {
+        return Ok(Some((0o120000, Tree::read_symlink(&full_path)?)));
+    }
+    // Without symlinks tracked symlinks are files holding the target
+    if !platform::symlinks(repo)?
+        && index.get(path, 0).is_some_and(|e| e.mode == 0o120000)
+    {
+        return Ok(Some((0o120000, fs::read(&full_path)?)));
+    }
+    let mode = if platform::is_executable(&metadata) {
+        0o100755
+    } else {
+        0o100644
+    };
+    let data = attributes.to_repo(path, fs::read(&full_path)?)?;
+    Ok(Some((mode, data)))
+}
+
+/// Writes nested trees for entries sorted by path, paths relative to the tree
+fn write_tree(repo: &GitRepo, entries: &[(&[u8], u32, &ObjectId)]) -> Result<ObjectId> {
+    let mut tree = Tree {
+        entries: Vec::new(),
+    };
+    let mut i = 0;
+    while i < entries.len() {
+        let (path, mode, id) = entries[i];
+        if let Some((dir, _)) = pathname::split_first(path) {
+            let prefix = pathname::concat(dir, b"");
+            let end = i + entries[i..]
+                .iter()
+                .take_while(|e| e.0.starts_with(&prefix))
+                .count();
+            let children: Vec<_> = entries[i..end]
+                .iter()
+                .map(|(path, mode, id)| (&path[prefix.len()..], *mode, *id))
+                .collect();
+            tree.entries.push(TreeEntry {
+                mode: FileMode::Tree,
+                name: dir.to_vec(),
+                reference: write_tree(repo, &children)?,
+            });
+            i = end;
+        } else {
+            tree.entries.push(TreeEntry {
+                mode: FileMode::try_from(mode)?,
+                name: path.to_vec(),
+                reference: *id,
+            });
+            i += 1;
+        }
+    }
+    let obj = tree.into_object(repo.hash_algo());
+    obj.write(repo)?;
+    Ok(obj.hash)
+}
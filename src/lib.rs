@@ -1,54 +1,530 @@
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::{Path, PathBuf};
-
-use anyhow::{Context, Result, ensure, anyhow};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 
+use crate::commit::Commit;
+use crate::error::{bail, ensure, format_err, Context, Result};
+use crate::hash::HashAlgo;
+use crate::hooks::{Hook, HookName, Hooks};
+use crate::index::{Index, IndexEntry};
+use crate::odb::Odb;
+use crate::oid::ObjectId;
+use crate::progress::{NoProgress, Progress};
+use crate::storage::WriteOptions;
 use crate::tree::Tree;
 
+pub mod archive;
+pub mod attributes;
+pub mod bisect;
+pub mod blame;
+pub mod bundle;
+pub mod clean;
 pub mod commit;
+pub mod config;
+pub mod date;
+pub mod describe;
+pub mod diff;
+pub mod encoding;
+pub mod error;
+pub mod fast_import;
+pub mod fsck;
+pub mod gc;
+pub mod graph;
+pub mod grep;
+pub mod hash;
+pub mod hooks;
 pub mod http_protocol;
+pub mod ignore;
+pub mod index;
+pub mod mail;
+pub mod mailmap;
+pub mod merge;
+pub mod message;
+pub mod mmap;
 pub mod object;
+pub mod odb;
+pub mod oid;
+pub mod pack;
+pub mod patch;
+pub mod pathname;
+pub mod pkt_line;
+pub mod platform;
+pub mod pretty;
+pub mod progress;
+pub mod reflog;
+pub mod refs;
+pub mod remote;
+pub mod revparse;
+pub mod revwalk;
+pub mod sequencer;
+pub mod serve;
+pub mod signature;
+pub mod stash;
+pub mod storage;
+pub mod submodule;
+pub mod tag;
 pub mod tree;
+pub mod worktree;
 
+#[derive(Debug, Clone)]
 pub struct GitRepo {
     pub repo_root: PathBuf,
+    /// Directory with HEAD and index of this working tree
     pub git_dir: PathBuf,
+    /// Directory with objects, refs and config shared by all working trees, same as
+    /// `git_dir` for the main working tree
+    pub common_dir: PathBuf,
     pub objects_dir: PathBuf,
     pub refs_dir: PathBuf,
+    /// Repository without working tree, `repo_root` is the git directory itself
+    pub is_bare: bool,
+    /// Loaded from the config on first write
+    write_options: OnceLock<WriteOptions>,
+    /// Loaded from the config on first use
+    hash_algo: OnceLock<HashAlgo>,
+    /// Loaded from the config on first use
+    worktree_options: OnceLock<platform::WorktreeOptions>,
+    /// Loaded on first object lookup
+    alternates: OnceLock<Vec<PathBuf>>,
+    /// Object store used instead of the object directory
+    odb: Option<Arc<dyn Odb>>,
+    /// Receives output of operations instead of printing it
+    progress: Arc<dyn Progress>,
+    /// Rust hooks run before hook executables
+    hooks: Hooks,
+    /// Mapped packs and delta bases read from them
+    pack_cache: Arc<pack::PackCache>,
+}
+
+/// Alternates chains longer than this are ignored, as git does
+const MAX_ALTERNATES_DEPTH: usize = 5;
+
+/// Object directories listed in `info/alternates` of the object directory, followed by
+/// their own alternates. Relative paths are relative to the object directory.
+fn read_alternates(objects_dir: &Path, depth: usize, dirs: &mut Vec<PathBuf>) {
+    if depth > MAX_ALTERNATES_DEPTH {
+        return;
+    }
+    let Ok(content) = fs::read_to_string(objects_dir.join("info").join("alternates")) else {
+        return;
+    };
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let dir = objects_dir.join(line);
+        let dir = fs::canonicalize(&dir).unwrap_or(dir);
+        if dir.is_dir() && !dirs.contains(&dir) {
+            dirs.push(dir.clone());
+            read_alternates(&dir, depth + 1, dirs);
+        }
+    }
 }
 
 impl GitRepo {
+    /// Repository of the working tree, `.git` is either directory or file with `gitdir:`
+    /// pointing to the git directory of a linked working tree or a submodule
     pub fn new(repo_root: &Path) -> Self {
+        let dot_git = repo_root.join(".git");
+        let git_dir = match fs::read_to_string(&dot_git) {
+            Ok(content) => match content.trim_end().strip_prefix("gitdir: ") {
+                Some(git_dir) => repo_root.join(git_dir),
+                None => dot_git,
+            },
+            Err(_) => dot_git,
+        };
+        Self::with_git_dir(repo_root, &git_dir)
+    }
+
+    /// Repository with explicit git directory, its `commondir` file leads to the shared one
+    pub fn with_git_dir(repo_root: &Path, git_dir: &Path) -> Self {
+        let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(common_dir) => {
+                let common_dir = git_dir.join(common_dir.trim_end());
+                fs::canonicalize(&common_dir).unwrap_or(common_dir)
+            }
+            Err(_) => git_dir.to_path_buf(),
+        };
         Self {
             repo_root: repo_root.to_path_buf(),
-            git_dir: repo_root.join(".git"),
-            objects_dir: repo_root.join(".git").join("objects"),
-            refs_dir: repo_root.join(".git").join("refs"),
+            git_dir: git_dir.to_path_buf(),
+            objects_dir: common_dir.join("objects"),
+            refs_dir: common_dir.join("refs"),
+            common_dir,
+            is_bare: false,
+            write_options: OnceLock::new(),
+            hash_algo: OnceLock::new(),
+            worktree_options: OnceLock::new(),
+            alternates: OnceLock::new(),
+            odb: None,
+            progress: Arc::new(NoProgress),
+            hooks: Hooks::default(),
+            pack_cache: Arc::default(),
+        }
+    }
+
+    /// Bare repository in the directory
+    pub fn new_bare(git_dir: &Path) -> Self {
+        Self {
+            is_bare: true,
+            ..Self::with_git_dir(git_dir, git_dir)
+        }
+    }
+
+    /// Keeps objects in the store instead of the object directory, e.g. [`odb::MemoryOdb`]
+    /// for tests. Refs, config and the index stay in the git directory.
+    pub fn with_odb(self, odb: Arc<dyn Odb>) -> Self {
+        Self {
+            odb: Some(odb),
+            ..self
+        }
+    }
+
+    /// Reports output and warnings of operations to the receiver, they are discarded
+    /// by default
+    pub fn with_progress(self, progress: Arc<dyn Progress>) -> Self {
+        Self { progress, ..self }
+    }
+
+    /// Runs the hook before the executable from the hooks directory, after hooks with
+    /// the same name added earlier.
+    pub fn with_hook(mut self, name: HookName, hook: Arc<dyn Hook>) -> Self {
+        self.hooks.push(name, hook);
+        self
+    }
+
+    pub(crate) fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    pub fn progress(&self) -> &dyn Progress {
+        &*self.progress
+    }
+
+    /// Object store replacing the object directory, if any
+    pub fn odb(&self) -> Option<&dyn Odb> {
+        self.odb.as_deref()
+    }
+
+    /// Settings for writing objects, packs, refs and the index
+    pub fn write_options(&self) -> Result<&WriteOptions> {
+        if let Some(options) = self.write_options.get() {
+            return Ok(options);
+        }
+        let options = WriteOptions::load(&config::Config::load(self)?)?;
+        Ok(self.write_options.get_or_init(|| options))
+    }
+
+    /// Representation of files in the working tree, see [`platform::symlinks`] and
+    /// [`platform::ignore_case`]
+    pub fn worktree_options(&self) -> Result<&platform::WorktreeOptions> {
+        if let Some(options) = self.worktree_options.get() {
+            return Ok(options);
+        }
+        let options = platform::WorktreeOptions::load(&config::Config::load(self)?)?;
+        Ok(self.worktree_options.get_or_init(|| options))
+    }
+
+    /// Hash function naming objects, `extensions.objectFormat` of repositories with
+    /// format version 1, SHA-1 otherwise. Unreadable config falls back to SHA-1.
+    pub fn hash_algo(&self) -> HashAlgo {
+        *self.hash_algo.get_or_init(|| {
+            let Ok(config) = config::Config::load(self) else {
+                return HashAlgo::Sha1;
+            };
+            let version = config.get_int("core.repositoryformatversion").ok().flatten();
+            match config.get("extensions.objectformat") {
+                Some(name) if version == Some(1) => name.parse().unwrap_or_default(),
+                _ => HashAlgo::Sha1,
+            }
+        })
+    }
+
+    /// The object directory followed by alternate ones from `objects/info/alternates` and
+    /// `GIT_ALTERNATE_OBJECT_DIRECTORIES`, whose objects are available to this repository
+    pub fn object_dirs(&self) -> Vec<&Path> {
+        let alternates = self.alternates.get_or_init(|| {
+            let own = fs::canonicalize(&self.objects_dir).unwrap_or(self.objects_dir.clone());
+            let mut dirs = vec![own];
+            read_alternates(&self.objects_dir, 1, &mut dirs);
+            if let Some(paths) = std::env::var_os("GIT_ALTERNATE_OBJECT_DIRECTORIES") {
+                for dir in std::env::split_paths(&paths) {
+                    if dir.is_dir() && !dirs.contains(&dir) {
+                        dirs.push(dir.clone());
+                        read_alternates(&dir, 1, &mut dirs);
+                    }
+                }
+            }
+            dirs.remove(0);
+            dirs
+        });
+        let mut dirs = vec![self.objects_dir.as_path()];
+        dirs.extend(alternates.iter().map(PathBuf::as_path));
+        dirs
+    }
+
+    /// Checks whether the directory looks like git directory
+    fn is_git_dir(dir: &Path) -> bool {
+        dir.join("HEAD").is_file() && dir.join("objects").is_dir() && dir.join("refs").is_dir()
+    }
+
+    /// Fails for bare repository, for commands using the working tree
+    pub fn require_worktree(&self) -> Result<()> {
+        ensure!(!self.is_bare, "This operation must be run in a work tree");
+        Ok(())
+    }
+
+    /// Path of the ref file. HEAD, pseudo refs and `refs/bisect/` belong to the working
+    /// tree, other refs are shared.
+    pub fn ref_path(&self, name: &str) -> PathBuf {
+        if name.starts_with("refs/") && !name.starts_with("refs/bisect/") {
+            self.common_dir.join(name)
+        } else {
+            self.git_dir.join(name)
         }
     }
 
+    /// Repository containing the current directory. `GIT_DIR` and `GIT_WORK_TREE`
+    /// environment variables override the discovery.
     pub fn new_in_cwd() -> Result<Self> {
-        Ok(Self::new(&std::env::current_dir()?))
+        let cwd = std::env::current_dir()?;
+        let work_tree = std::env::var_os("GIT_WORK_TREE")
+            .map(|dir| fs::canonicalize(cwd.join(dir)).context("Opening GIT_WORK_TREE"))
+            .transpose()?;
+        if let Some(git_dir) = std::env::var_os("GIT_DIR") {
+            let git_dir = fs::canonicalize(cwd.join(git_dir)).context("Opening GIT_DIR")?;
+            if let Some(work_tree) = work_tree {
+                return Ok(Self::with_git_dir(&work_tree, &git_dir));
+            }
+            // Without explicit working tree the current directory is used unless
+            // `core.bare` says there is none
+            let bare = Self::new_bare(&git_dir);
+            if config::Config::load(&bare)?.get_bool("core.bare")? == Some(true) {
+                return Ok(bare);
+            }
+            return Ok(Self::with_git_dir(&cwd, &git_dir));
+        }
+        let repo = Self::discover(&cwd)?;
+        Ok(match work_tree {
+            Some(work_tree) => Self::with_git_dir(&work_tree, &repo.git_dir),
+            None => repo,
+        })
     }
 
-    pub fn init(&self) -> Result<()> {
-        fs::create_dir(&self.git_dir).context("Create git dir")?;
-        fs::create_dir(&self.objects_dir).context("Create objects dir")?;
-        fs::create_dir(&self.refs_dir).context("Create refs dir")?;
-        fs::write(self.git_dir.join("HEAD"), "ref: refs/heads/master\n").context("Write HEAD")?;
+    /// Finds repository containing the directory by looking for `.git` in it and its
+    /// parents. Directory which is git directory itself is a bare repository.
+    pub fn discover(dir: &Path) -> Result<Self> {
+        let mut current = Some(dir);
+        while let Some(dir) = current {
+            if dir.join(".git").exists() {
+                return Ok(Self::new(dir));
+            }
+            if Self::is_git_dir(dir) {
+                return Ok(Self::new_bare(dir));
+            }
+            current = dir.parent();
+        }
+        bail!("Not a git repository (or any of the parent directories): .git")
+    }
+
+    /// Path of the current directory relative to the repository root with trailing `/`,
+    /// empty at the root
+    pub fn cwd_prefix(&self) -> Result<Vec<u8>> {
+        let cwd = std::env::current_dir()?;
+        Ok(self.relative_path(&cwd)?.map_or(Vec::new(), |p| pathname::concat(&p, b"")))
+    }
+
+    /// Converts path given on the command line, relative to the current directory, to path
+    /// relative to the repository root. `.` and `..` components are resolved.
+    pub fn path_from_cwd(&self, path: impl AsRef<Path>) -> Result<Vec<u8>> {
+        let full_path = std::env::current_dir()?.join(path);
+        Ok(self.relative_path(&full_path)?.unwrap_or_default())
+    }
+
+    /// Path relative to the repository root, `None` for the root itself
+    fn relative_path(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                Component::CurDir => {}
+                component => normalized.push(component),
+            }
+        }
+        let relative = normalized.strip_prefix(&self.repo_root).map_err(|_| {
+            format_err!(
+                "{}: '{}' is outside repository at '{}'",
+                path.display(),
+                normalized.display(),
+                self.repo_root.display()
+            )
+        })?;
+        let relative = pathname::from_os(relative.as_os_str());
+        Ok((!relative.is_empty()).then_some(relative))
+    }
+
+    /// File system path of the path relative to the repository root
+    pub fn worktree_path(&self, path: &[u8]) -> PathBuf {
+        pathname::join(&self.repo_root, path)
+    }
+
+    /// Converts path relative to the repository root to path relative to the current
+    /// directory with given `cwd_prefix`, as shown to the user
+    pub fn path_to_cwd(prefix: &[u8], path: &[u8]) -> Vec<u8> {
+        let mut prefix = prefix;
+        let mut path = path;
+        // Skip common leading directories
+        while let Some((dir, rest)) = pathname::split_first(prefix) {
+            match path.strip_prefix(dir).and_then(|p| p.strip_prefix(b"/")) {
+                Some(remaining) => {
+                    path = remaining;
+                    prefix = rest;
+                }
+                None => break,
+            }
+        }
+        let ups = prefix.iter().filter(|b| **b == b'/').count();
+        [b"../".repeat(ups).as_slice(), path].concat()
+    }
+
+    /// Creates the repository with HEAD pointing to the initial branch, which defaults to
+    /// `init.defaultBranch` or `master`. Existing repository is left as it is, only missing
+    /// directories are created. Returns whether the repository was reinitialized.
+    pub fn init(&self, initial_branch: Option<&str>) -> Result<bool> {
+        self.init_with_format(initial_branch, None)
+    }
+
+    /// Like [`Self::init`], objects of new repository are named by the hash function,
+    /// `GIT_DEFAULT_HASH` or SHA-1 by default
+    pub fn init_with_format(
+        &self,
+        initial_branch: Option<&str>,
+        object_format: Option<HashAlgo>,
+    ) -> Result<bool> {
+        let head = self.git_dir.join("HEAD");
+        let exists = head.is_file();
+        for dir in [
+            self.git_dir.clone(),
+            self.objects_dir.clone(),
+            self.refs_dir.join("heads"),
+            self.refs_dir.join("tags"),
+            self.git_dir.join("info"),
+            self.git_dir.join("hooks"),
+        ] {
+            fs::create_dir_all(&dir).with_context(|| format!("Create {}", dir.display()))?;
+        }
+        if exists {
+            if let Some(branch) = initial_branch {
+                self.progress
+                    .warning(&format!("re-init: ignored --initial-branch={}", branch));
+            }
+            if object_format.is_some_and(|format| format != self.hash_algo()) {
+                bail!("attempt to reinitialize repository with different hash");
+            }
+            return Ok(true);
+        }
+        let object_format = match object_format {
+            Some(format) => format,
+            None => match std::env::var("GIT_DEFAULT_HASH") {
+                Ok(name) => name.parse()?,
+                Err(_) => HashAlgo::Sha1,
+            },
+        };
+        let branch = match initial_branch {
+            Some(branch) => branch.to_string(),
+            None => config::Config::load_global()?
+                .get("init.defaultBranch")
+                .unwrap_or("master")
+                .to_string(),
+        };
+        ensure!(
+            !branch.is_empty() && !branch.contains(['~', '^', ':', ' ', '\\']),
+            "'{}' is not a valid branch name",
+            branch
+        );
+        fs::write(&head, format!("ref: refs/heads/{}\n", branch)).context("Write HEAD")?;
+        fs::write(
+            self.git_dir.join("description"),
+            "Unnamed repository; edit this file 'description' to name the repository.\n",
+        )
+        .context("Write description")?;
+        let ignore_case = platform::is_case_insensitive(&head);
+        if self.is_bare || object_format != HashAlgo::Sha1 || ignore_case {
+            let mut file = config::ConfigFile::open(&config::Config::local_path(self))?;
+            if object_format != HashAlgo::Sha1 {
+                file.set("core.repositoryformatversion", "1")?;
+                file.set("extensions.objectformat", object_format.name())?;
+            }
+            if self.is_bare {
+                file.set("core.bare", "true")?;
+            }
+            if ignore_case {
+                file.set("core.ignorecase", "true")?;
+            }
+            file.save()?;
+        }
+        // Config written just now decides the hash
+        let _ = self.hash_algo.set(object_format);
+        Ok(false)
+    }
+
+    /// Writes content of the commit to the empty working tree, recording the files with
+    /// their stat information in the index
+    pub fn checkout(&self, commit_hash: &ObjectId) -> Result<()> {
+        self.progress
+            .debug(&format!("Checkout commit at {}", commit_hash));
+        let commit = Commit::read(self, commit_hash)?;
+        self.progress
+            .debug(&format!("Checkout tree at {}", commit.tree_sha));
+        let tree: Tree = object::Object::read(self, &commit.tree_sha)?.try_into()?;
+        tree.checkout(self, &self.repo_root)?;
+        let mut index = Index::default();
+        for (path, (mode, id)) in Tree::flatten(self, &commit.tree_sha)? {
+            let entry = match fs::symlink_metadata(self.worktree_path(&path)) {
+                Ok(metadata) if mode != submodule::GITLINK_MODE => {
+                    IndexEntry::from_metadata(&path, mode, &id, &metadata)
+                }
+                _ => IndexEntry::new(&path, mode, &id, 0),
+            };
+            index.add(entry);
+        }
+        index.write(self)?;
+        if platform::ignore_case(self)? {
+            self.warn_collisions(&commit.tree_sha)?;
+        }
         Ok(())
     }
 
-    // TODO: move to commit
-    pub fn checkout(&self, commit_hash: String) -> Result<()> {
-        eprintln!("Checkout commit at {}", commit_hash);
-        let commit_obj = object::Object::read(&self, commit_hash)?;
-        ensure!(commit_obj.header.kind == object::ObjectKind::Commit, "Given hash does not represent commit");
-        let tree_hash = commit_obj.data.split(|c| *c == b'\n').find_map(|line| line.strip_prefix(b"tree ")).ok_or(anyhow!("Commit doesn't contain tree reference"))?;
-        let tree_hash = String::from_utf8(tree_hash.to_vec())?;
-        eprintln!("Checkout tree at {}", tree_hash);
-        let tree: Tree = object::Object::read(&self, tree_hash)?.try_into()?;
-        tree.checkout(&self, &self.repo_root)?;
+    /// Warns about paths of the tree differing only in case, only one of them is in the
+    /// working tree on case-insensitive file system
+    fn warn_collisions(&self, tree: &ObjectId) -> Result<()> {
+        let mut groups: BTreeMap<Vec<u8>, Vec<Vec<u8>>> = BTreeMap::new();
+        for path in Tree::flatten(self, tree)?.into_keys() {
+            groups
+                .entry(path.to_ascii_lowercase())
+                .or_default()
+                .push(path);
+        }
+        let collided: Vec<Vec<u8>> = groups
+            .into_values()
+            .filter(|paths| paths.len() > 1)
+            .flatten()
+            .collect();
+        if collided.is_empty() {
+            return Ok(());
+        }
+        let mut message = String::from(
+            "the following paths have collided (e.g. case-sensitive paths\n\
+             on a case-insensitive filesystem) and only one from the same\n\
+             colliding group is in the working tree:\n",
+        );
+        for path in collided {
+            message.push_str(&format!("\n  '{}'", pathname::display(&path)));
+        }
+        self.progress.warning(&message);
         Ok(())
     }
 }
@@ -0,0 +1,398 @@
+use crate::commit::{Author, Commit};
+use crate::diff::{self, Renames};
+use crate::error::{bail, format_err, Result};
+use crate::oid::ObjectId;
+use crate::tree::Tree;
+use crate::{config, date, GitRepo};
+
+/// Width where header lines are folded
+const HEADER_WIDTH: usize = 78;
+/// Maximal line length with RFC 2047 encoded words
+const ENCODED_WIDTH: usize = 76;
+/// Total width of the diffstat in the email
+const STAT_WIDTH: usize = 72;
+/// Maximal length of patch file name without `.patch`
+const NAME_MAX: usize = 64;
+
+/// Commit parsed from email created by `format-patch`
+pub struct Mail {
+    pub author: Author,
+    /// Subject without `[PATCH]` prefix plus the body, including trailing newline
+    pub message: String,
+    /// Text following the message, with diffstat and the patch
+    pub patch: Vec<u8>,
+}
+
+/// First paragraph of the message joined into single line, and the rest of the message
+pub fn split_message(message: &str) -> (String, &str) {
+    let message = message.trim_start_matches('\n');
+    let (title, body) = message.split_once("\n\n").unwrap_or((message, ""));
+    let title: Vec<&str> = title.lines().map(str::trim).collect();
+    (title.join(" "), body.trim_matches('\n'))
+}
+
+/// File name for numbered patch, e.g. `0001-Fix-typo.patch`
+pub fn file_name(number: usize, commit: &Commit) -> String {
+    let (title, _) = split_message(&commit.message);
+    let mut name = String::new();
+    let mut separate = false;
+    let mut chars = title.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            if separate && !name.is_empty() {
+                name.push('-');
+            }
+            separate = false;
+            name.push(c);
+            if c == '.' {
+                while chars.next_if_eq(&'.').is_some() {}
+            }
+        } else {
+            separate = true;
+        }
+    }
+    let name = name.trim_end_matches(['.', '-']);
+    let prefix = format!("{:04}-", number);
+    let max = NAME_MAX - prefix.len() - ".patch".len() - 1;
+    let name = &name[..name.len().min(max)];
+    format!("{}{}.patch", prefix, name)
+}
+
+fn needs_encoding(text: &str) -> bool {
+    !text.is_ascii() || text.contains("=?")
+}
+
+/// RFC 2047 `Q` encoded words of the text, continuing the header line of given length
+fn encode_words(text: &str, mut line_len: usize, address: bool) -> String {
+    const START: &str = "=?UTF-8?q?";
+    let mut out = String::from(START);
+    line_len += START.len();
+    for c in text.chars() {
+        let mut buf = [0; 4];
+        let bytes = c.encode_utf8(&mut buf).as_bytes();
+        let special = !c.is_ascii_graphic()
+            || matches!(c, '=' | '?' | '_')
+            || address && !(c.is_ascii_alphanumeric() || matches!(c, '!' | '*' | '+' | '-' | '/'));
+        let encoded: String = if special {
+            bytes.iter().map(|b| format!("={:02X}", b)).collect()
+        } else {
+            c.to_string()
+        };
+        // Characters are not split between encoded words
+        if line_len + encoded.len() + 2 > ENCODED_WIDTH {
+            out.push_str("?=\n ");
+            out.push_str(START);
+            line_len = START.len() + 1;
+        }
+        line_len += encoded.len();
+        out.push_str(&encoded);
+    }
+    out.push_str("?=");
+    out
+}
+
+/// Name for `From` header, quoted or encoded when necessary
+fn encode_name(name: &str) -> String {
+    if needs_encoding(name) {
+        return encode_words(name, "From: ".len(), true);
+    }
+    if name.contains([
+        '(', ')', '<', '>', '@', ',', ';', ':', '\\', '"', '.', '[', ']',
+    ]) {
+        let escaped = name.replace('\\', "\\\\").replace('"', "\\\"");
+        return format!("\"{}\"", escaped);
+    }
+    name.to_string()
+}
+
+/// Folds words of the header value so that lines fit into `HEADER_WIDTH`
+fn fold(value: &str, mut line_len: usize) -> String {
+    let mut out = String::new();
+    for word in value.split(' ') {
+        if out.is_empty() {
+            line_len += word.len();
+        } else if line_len + 1 + word.len() > HEADER_WIDTH {
+            out.push_str("\n ");
+            line_len = 1 + word.len();
+        } else {
+            out.push(' ');
+            line_len += 1 + word.len();
+        }
+        out.push_str(word);
+    }
+    out
+}
+
+/// Renders commit as email with diffstat and patch against its first parent.
+/// `number` is position of the patch in series and the series length.
+pub fn format_patch(
+    repo: &GitRepo,
+    id: &ObjectId,
+    commit: &Commit,
+    number: Option<(usize, usize)>,
+) -> Result<Vec<u8>> {
+    let (title, body) = split_message(&commit.message);
+    let prefix = match number {
+        Some((n, total)) => format!("[PATCH {}/{}] ", n, total),
+        None => String::from("[PATCH] "),
+    };
+    let subject_start = "Subject: ".len() + prefix.len();
+    let subject = if needs_encoding(&title) {
+        encode_words(&title, subject_start, false)
+    } else {
+        fold(&title, subject_start)
+    };
+
+    let mut out = Vec::new();
+    // Fixed date marks the line as mbox separator of patch, not of real email
+    out.extend(format!("From {} Mon Sep 17 00:00:00 2001\n", id).as_bytes());
+    out.extend(
+        format!(
+            "From: {} <{}>\n",
+            encode_name(&commit.author.name),
+            commit.author.email
+        )
+        .as_bytes(),
+    );
+    out.extend(format!("Date: {}\n", commit.author.format_rfc2822_date()).as_bytes());
+    out.extend(format!("Subject: {}{}\n", prefix, subject).as_bytes());
+    if !commit.message.is_ascii() {
+        out.extend(b"MIME-Version: 1.0\nContent-Type: text/plain; charset=UTF-8\n");
+        out.extend(b"Content-Transfer-Encoding: 8bit\n");
+    }
+    out.push(b'\n');
+    if !body.is_empty() {
+        out.extend(body.as_bytes());
+        out.push(b'\n');
+    }
+    out.extend(b"---\n");
+
+    let old = match commit.parents.first() {
+        Some(parent) => Tree::flatten(repo, &Commit::read(repo, parent)?.tree_sha)?,
+        None => Default::default(),
+    };
+    let new = Tree::flatten(repo, &commit.tree_sha)?;
+    let mut changes = diff::changes(&old, &new);
+    if let Some(renames) = Renames::from_config(&config::Config::load(repo)?)? {
+        changes = diff::detect_renames(repo, changes, &renames, false)?;
+    }
+    diff::write_stat(&mut out, &diff::stats(repo, &changes, false)?, STAT_WIDTH);
+    diff::write_summary(&mut out, &changes);
+    out.push(b'\n');
+    diff::write_changes(repo, &mut out, &changes, 3, false)?;
+    out.extend(format!("-- \n{}\n\n", env!("CARGO_PKG_VERSION")).as_bytes());
+    Ok(out)
+}
+
+fn base64_decode(text: &str) -> Result<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for b in text.bytes().filter(|b| *b != b'=') {
+        let value = ALPHABET
+            .iter()
+            .position(|a| *a == b)
+            .ok_or(format_err!("Invalid base64 {:?}", text))?;
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Ok(out)
+}
+
+/// Decodes RFC 2047 encoded words in unfolded header value
+fn decode_header(value: &str) -> Result<String> {
+    let mut out = Vec::new();
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        // =?charset?encoding?text?=
+        let mut parts = rest[start + 2..].splitn(3, '?');
+        let (Some(_), Some(encoding), Some(tail)) = (parts.next(), parts.next(), parts.next())
+        else {
+            break;
+        };
+        let Some(end) = tail.find("?=") else {
+            break;
+        };
+        // Whitespace between adjacent encoded words is dropped
+        let before = &rest[..start];
+        if !(after_word && before.trim().is_empty()) {
+            out.extend(before.as_bytes());
+        }
+        let text = &tail[..end];
+        match encoding {
+            "q" | "Q" => {
+                let mut i = 0;
+                while i < text.len() {
+                    match text.as_bytes()[i] {
+                        b'_' => out.push(b' '),
+                        b'=' => {
+                            let byte = text
+                                .get(i + 1..i + 3)
+                                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                                .ok_or(format_err!("Invalid encoded word in {:?}", value))?;
+                            out.push(byte);
+                            i += 2;
+                        }
+                        b => out.push(b),
+                    }
+                    i += 1;
+                }
+            }
+            "b" | "B" => out.extend(base64_decode(text)?),
+            _ => bail!("Unknown encoding of {:?}", value),
+        }
+        rest = &tail[end + 2..];
+        after_word = true;
+    }
+    out.extend(rest.as_bytes());
+    Ok(String::from_utf8_lossy(&out).to_string())
+}
+
+/// Removes `Re:` and bracketed prefixes like `[PATCH 1/2]` from subject
+fn clean_subject(subject: &str) -> &str {
+    let mut subject = subject.trim();
+    loop {
+        if subject.starts_with('[') {
+            if let Some(end) = subject.find(']') {
+                subject = subject[end + 1..].trim_start();
+                continue;
+            }
+        }
+        if subject.len() >= 3 && subject[..3].eq_ignore_ascii_case("re:") {
+            subject = subject[3..].trim_start();
+            continue;
+        }
+        return subject;
+    }
+}
+
+/// Parses `Name <email>` of `From` header
+fn parse_address(value: &str) -> Result<(String, String)> {
+    let value = decode_header(value)?;
+    let (name, email) = match (value.find('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => {
+            (value[..start].trim(), value[start + 1..end].trim())
+        }
+        _ => ("", value.trim()),
+    };
+    let name = match name.strip_prefix('"').and_then(|n| n.strip_suffix('"')) {
+        Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => name.to_string(),
+    };
+    // Without name use the local part of the address like git
+    let name = if name.is_empty() {
+        email.split('@').next().unwrap_or_default().to_string()
+    } else {
+        name
+    };
+    Ok((name, email.to_string()))
+}
+
+/// Parses single email, headers are expected to be UTF-8
+fn parse_mail(data: &[u8]) -> Result<Mail> {
+    let lines = diff::lines(data);
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut i = 0;
+    if lines.first().is_some_and(|line| line.starts_with(b"From ")) {
+        i += 1;
+    }
+    while let Some(line) = lines.get(i) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches(['\n', '\r']);
+        i += 1;
+        if line.is_empty() {
+            break;
+        }
+        match (line.starts_with([' ', '\t']), headers.last_mut()) {
+            (true, Some((_, value))) => {
+                value.push(' ');
+                value.push_str(line.trim_start());
+            }
+            _ => {
+                let (key, value) = line
+                    .split_once(':')
+                    .ok_or(format_err!("Invalid email header {:?}", line))?;
+                headers.push((key.to_ascii_lowercase(), value.trim().to_string()));
+            }
+        }
+    }
+    let header = |key: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let (name, email) = parse_address(header("from").ok_or(format_err!("Missing From header"))?)?;
+    let (time, offset) = match header("date") {
+        Some(value) => date::parse_date(value)?,
+        None => bail!("Missing Date header"),
+    };
+    let author = Author {
+        name,
+        email,
+        time: date::system_time(time)?,
+        time_offset: date::format_offset(offset),
+    };
+    let subject = decode_header(header("subject").unwrap_or_default())?;
+
+    // Message ends at the `---` separator or where the diff starts
+    let mut body = String::new();
+    while let Some(line) = lines.get(i) {
+        if line.trim_ascii_end() == b"---"
+            || line.starts_with(b"diff -")
+            || line.starts_with(b"Index: ")
+        {
+            break;
+        }
+        body.push_str(&String::from_utf8_lossy(line));
+        i += 1;
+    }
+    let patch = lines[i..].concat();
+    let body = body.trim_matches(['\n', '\r']);
+    let mut message = clean_subject(&subject).to_string();
+    message.push('\n');
+    if !body.is_empty() {
+        message.push('\n');
+        message.push_str(body);
+        message.push('\n');
+    }
+    Ok(Mail {
+        author,
+        message,
+        patch,
+    })
+}
+
+/// Splits mbox into emails and parses them. Emails start with `From ` line at the
+/// beginning or after an empty line, followed by headers.
+pub fn parse_mbox(data: &[u8]) -> Result<Vec<Mail>> {
+    let lines = diff::lines(data);
+    let mut starts = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let after_blank = i == 0 || lines[i - 1].trim_ascii().is_empty();
+        let header_follows = lines.get(i + 1).is_some_and(|next| {
+            next.split(|b| *b == b' ')
+                .next()
+                .is_some_and(|w| w.ends_with(b":"))
+        });
+        if line.starts_with(b"From ") && after_blank && header_follows {
+            starts.push(i);
+        }
+    }
+    if starts.first() != Some(&0) {
+        // Single email without mbox separator
+        starts.insert(0, 0);
+    }
+    let mut mails = Vec::new();
+    for (n, start) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).copied().unwrap_or(lines.len());
+        mails.push(parse_mail(&lines[*start..end].concat())?);
+    }
+    Ok(mails)
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::commit::Author;
+use crate::config::Config;
+use crate::error::{ensure, Context, Result};
+use crate::object::{Object, ObjectKind};
+use crate::revparse;
+use crate::GitRepo;
+
+/// Proper identity replacing the commit one, `None` parts are kept as in the commit
+#[derive(Debug, Clone, Default)]
+struct Replacement {
+    name: Option<String>,
+    email: Option<String>,
+}
+
+/// Replacements of identities with the same commit email. Entries with commit name
+/// apply only to that name and win over the one for any name.
+#[derive(Debug, Clone, Default)]
+struct EmailEntry {
+    any_name: Option<Replacement>,
+    by_name: HashMap<String, Replacement>,
+}
+
+/// Mapping of author and committer identities to canonical ones, as listed in
+/// `.mailmap`. Names and emails are matched case-insensitively.
+#[derive(Debug, Clone, Default)]
+pub struct Mailmap {
+    entries: HashMap<String, EmailEntry>,
+}
+
+/// Splits `Name <email>` at the start of the text, returning the rest after it
+fn parse_identity(text: &str) -> Option<(Option<&str>, &str, &str)> {
+    let (name, rest) = text.split_once('<')?;
+    let (email, rest) = rest.split_once('>')?;
+    let name = Some(name.trim()).filter(|name| !name.is_empty());
+    Some((name, email.trim(), rest))
+}
+
+impl Mailmap {
+    /// Parses lines of forms `Proper Name <commit@email>`, `<proper@email>
+    /// <commit@email>` and `Proper Name <proper@email> Commit Name <commit@email>`
+    pub fn parse(content: &str) -> Self {
+        let mut mailmap = Self::default();
+        mailmap.extend(content);
+        mailmap
+    }
+
+    /// Adds entries of the lines to the map
+    fn extend(&mut self, content: &str) {
+        for line in content.lines() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line);
+            let Some((proper_name, proper_email, rest)) = parse_identity(line) else {
+                continue;
+            };
+            let (name, email, replacement) = match parse_identity(rest) {
+                Some((commit_name, commit_email, _)) => (
+                    commit_name,
+                    commit_email,
+                    Replacement {
+                        name: proper_name.map(str::to_string),
+                        email: Some(proper_email.to_string()),
+                    },
+                ),
+                None => (
+                    None,
+                    proper_email,
+                    Replacement {
+                        name: proper_name.map(str::to_string),
+                        email: None,
+                    },
+                ),
+            };
+            self.add(name, email, replacement);
+        }
+    }
+
+    /// Later lines complete what earlier ones for the same identity set
+    fn add(&mut self, name: Option<&str>, email: &str, replacement: Replacement) {
+        let entry = self.entries.entry(email.to_lowercase()).or_default();
+        let current = match name {
+            Some(name) => entry.by_name.entry(name.to_lowercase()).or_default(),
+            None => entry.any_name.get_or_insert_with(Replacement::default),
+        };
+        if replacement.name.is_some() {
+            current.name = replacement.name;
+        }
+        if replacement.email.is_some() {
+            current.email = replacement.email;
+        }
+    }
+
+    /// Reads `.mailmap` in the root of the working tree, then the blob of
+    /// `mailmap.blob` (`HEAD:.mailmap` in bare repositories) and the file of
+    /// `mailmap.file`, later sources overriding earlier ones. Missing sources are
+    /// skipped.
+    pub fn load(repo: &GitRepo) -> Result<Self> {
+        let config = Config::load(repo)?;
+        let mut mailmap = Self::default();
+        if !repo.is_bare {
+            mailmap.read_file(&repo.repo_root.join(".mailmap"))?;
+        }
+        let blob = match config.get("mailmap.blob") {
+            Some(blob) => Some(blob),
+            None if repo.is_bare => Some("HEAD:.mailmap"),
+            None => None,
+        };
+        if let Some(id) = blob.and_then(|blob| revparse::resolve(repo, blob).ok()) {
+            let obj = Object::read(repo, &id)?;
+            ensure!(
+                obj.header.kind == ObjectKind::Blob,
+                "mailmap.blob is not a blob: {}",
+                blob.unwrap_or_default()
+            );
+            mailmap.extend(&String::from_utf8_lossy(&obj.data));
+        }
+        if let Some(path) = config.get_path("mailmap.file") {
+            mailmap.read_file(&path)?;
+        }
+        Ok(mailmap)
+    }
+
+    fn read_file(&mut self, path: &Path) -> Result<()> {
+        match fs::read_to_string(path) {
+            Ok(content) => self.extend(&content),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).context(format!("Reading {}", path.display())),
+        }
+        Ok(())
+    }
+
+    /// Canonical name and email of the identity
+    pub fn map(&self, name: &str, email: &str) -> (String, String) {
+        let replacement = self.entries.get(&email.to_lowercase()).and_then(|entry| {
+            entry
+                .by_name
+                .get(&name.to_lowercase())
+                .or(entry.any_name.as_ref())
+        });
+        let Some(replacement) = replacement else {
+            return (name.to_string(), email.to_string());
+        };
+        (
+            replacement.name.as_deref().unwrap_or(name).to_string(),
+            replacement.email.as_deref().unwrap_or(email).to_string(),
+        )
+    }
+
+    /// The identity with canonical name and email
+    pub fn map_author(&self, author: &Author) -> Author {
+        let (name, email) = self.map(&author.name, &author.email);
+        Author {
+            name,
+            email,
+            ..author.clone()
+        }
+    }
+}
@@ -1,8 +1,12 @@
-use std::fs::File;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use anyhow::{ensure, Context, Result, bail};
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, ensure, Context, Result};
+use clap::{Args, Parser, Subcommand};
 use git_starter_rust::*;
 
 #[derive(Parser)]
@@ -13,111 +17,4725 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Init,
-    CatFile {
-        #[arg(short = 'p')]
-        pretty_print: bool,
-        object: String,
+    /// Create empty repository or reinitialize existing one
+    Init {
+        /// Create repository without working tree
+        #[arg(long)]
+        bare: bool,
+        /// Name of the branch HEAD points to
+        #[arg(short = 'b', long)]
+        initial_branch: Option<String>,
+        /// Hash function naming objects, sha1 or sha256
+        #[arg(long)]
+        object_format: Option<hash::HashAlgo>,
+        /// Directory of the repository, created when missing, the current one by default
+        directory: Option<PathBuf>,
     },
-    HashObject {
-        #[arg(short)]
-        write: bool,
-        file: PathBuf,
+    /// Show content, type or size of object
+    CatFile(CatFileArgs),
+    /// Compute object id of file, optionally storing it as blob
+    HashObject(HashObjectArgs),
+    /// List entries of tree object
+    LsTree(LsTreeArgs),
+    /// Write tree object of the index
+    WriteTree {
+        /// Write tree of this directory of the index rather than of the root
+        #[arg(long, conflicts_with = "worktree")]
+        prefix: Option<String>,
+        /// Snapshot the working tree instead of the index
+        #[arg(long)]
+        worktree: bool,
+    },
+    /// Read tree into the index, without touching the working tree
+    ReadTree {
+        /// Merge with the index: keep stat information of one tree, move from the first
+        /// tree to the second one, or merge the last two with the first as base
+        #[arg(short = 'm')]
+        merge: bool,
+        /// Resolve also paths deleted on either side in three-way merge
+        #[arg(long, requires = "merge")]
+        aggressive: bool,
+        #[arg(required = true, num_args = 1..=3)]
+        tree_ish: Vec<String>,
+    },
+    /// Write files from the index into the working tree
+    CheckoutIndex {
+        /// Check out all files of the index
+        #[arg(short, long, conflicts_with = "paths")]
+        all: bool,
+        /// Overwrite existing files
+        #[arg(short, long)]
+        force: bool,
+        /// Record stat information of written files in the index
+        #[arg(short = 'u', long)]
+        index: bool,
+        /// Prepend to paths of the written files, e.g. directory ending with `/`
+        #[arg(long)]
+        prefix: Option<String>,
+        paths: Vec<OsString>,
     },
-    LsTree {
+    /// Modify the index directly
+    UpdateIndex(UpdateIndexArgs),
+    /// Point ref to object, or delete it, optionally verifying its current value.
+    /// Symbolic refs are followed, so updating `HEAD` updates the current branch.
+    UpdateRef {
+        /// Delete the ref, the only value is the old one to verify
+        #[arg(short = 'd')]
+        delete: bool,
+        /// Reason of the update recorded in the reflog
+        #[arg(short = 'm')]
+        message: Option<String>,
+        reference: String,
+        /// New value and the value the ref has to have now, empty or null id for none
+        #[arg(num_args = 0..=2)]
+        values: Vec<String>,
+    },
+    /// List refs with the objects they point to
+    ShowRef(ShowRefArgs),
+    /// List refs in the given format
+    ForEachRef(ForEachRefArgs),
+    /// Read symbolic ref, or point it to another ref
+    SymbolicRef {
+        /// Exit with non-zero status without message when the ref is not symbolic
+        #[arg(short, long)]
+        quiet: bool,
+        /// Print the target without `refs/heads/` and similar prefixes
         #[arg(long)]
-        name_only: bool,
-        tree_sha: String,
+        short: bool,
+        /// Reason of the update recorded in the reflog
+        #[arg(short = 'm')]
+        message: Option<String>,
+        name: String,
+        target: Option<String>,
     },
-    WriteTree,
+    /// Create commit object of the tree, printing its id
     CommitTree {
-        tree_sha: String,
+        tree_sha: oid::ObjectId,
         #[arg(short)]
-        parent: Option<String>,
+        parent: Vec<oid::ObjectId>,
         #[arg(short)]
         message: String,
+        /// Sign the commit, with `user.signingKey` unless key is given
+        #[arg(short = 'S', long, value_name = "KEY", num_args = 0..=1, require_equals = true, default_missing_value = "")]
+        gpg_sign: Option<String>,
+    },
+    /// Record the index as new commit on the current branch
+    Commit(CommitArgs),
+    /// Switch to branch or detach HEAD at commit, updating the working tree and index
+    Checkout {
+        /// Discard local changes of files which differ between the commits
+        #[arg(short, long)]
+        force: bool,
+        /// Create new branch at the commit and switch to it
+        #[arg(short = 'b', value_name = "NEW_BRANCH")]
+        new_branch: Option<String>,
+        /// Branch or commit to check out, HEAD by default with `-b`. With paths the commit
+        /// to restore them from, the index by default.
+        target: Option<String>,
+        /// Restore files at the paths instead of switching branches
+        #[arg(last = true)]
+        paths: Vec<OsString>,
     },
+    /// Restore files in the working tree or the index from the index or a commit
+    Restore {
+        /// Commit to restore from, the index by default or HEAD with `--staged`
+        #[arg(short, long)]
+        source: Option<String>,
+        /// Restore the index, unstaging changes
+        #[arg(short = 'S', long)]
+        staged: bool,
+        /// Restore the working tree, the default unless `--staged` is given
+        #[arg(short = 'W', long)]
+        worktree: bool,
+        #[arg(required = true)]
+        paths: Vec<OsString>,
+    },
+    /// Clone repository into new directory and check out its HEAD
     Clone {
+        /// Initialize and clone submodules recursively after checkout
+        #[arg(long, conflicts_with = "bare")]
+        recurse_submodules: bool,
+        /// Create bare repository with branches of the remote as local branches
+        #[arg(long)]
+        bare: bool,
+        /// Create bare repository mirroring all refs of the remote
+        #[arg(long, conflicts_with = "recurse_submodules")]
+        mirror: bool,
+        /// Borrow objects from the local repository instead of fetching them
+        #[arg(long, value_name = "REPOSITORY")]
+        reference: Option<PathBuf>,
         repo_url: String,
         dest: PathBuf,
+    },
+    /// Serve repositories of the current directory over smart HTTP. Pushing is
+    /// allowed only to repositories with `http.receivepack` enabled.
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+    },
+    /// Get and set repository or user options
+    Config(ConfigArgs),
+    /// List, add, remove or inspect remotes
+    Remote {
+        #[arg(short, long)]
+        verbose: bool,
+        #[command(subcommand)]
+        command: Option<RemoteCommand>,
+    },
+    /// Download objects and refs from remote, updating its remote-tracking branches
+    Fetch {
+        #[arg(default_value = "origin")]
+        remote: String,
+    },
+    /// Update refs of remote with local ones, sending objects they need
+    Push {
+        #[arg(short, long)]
+        force: bool,
+        remote: Option<String>,
+        refspecs: Vec<String>,
+    },
+    /// Show commit history
+    Log(LogArgs),
+    /// Summarize history by author, listing subjects of their commits
+    Shortlog(ShortlogArgs),
+    /// List commits reachable from revisions, newest first
+    RevList(RevListArgs),
+    /// Print object ids the revisions name
+    RevParse {
+        /// Abbreviate object ids to unique prefix of given minimal length
+        #[arg(long, num_args = 0..=1, default_missing_value = "7", require_equals = true)]
+        short: Option<usize>,
+        revs: Vec<String>,
+    },
+    /// Join history of branch into the current branch
+    Merge {
+        /// Refuse to merge unless the current branch can be fast-forwarded
+        #[arg(long, conflicts_with = "no_ff")]
+        ff_only: bool,
+        /// Create merge commit even when fast-forward is possible
+        #[arg(long)]
+        no_ff: bool,
+        /// Branch or commit to merge into the current branch
+        branch: String,
+    },
+    /// Write tar or zip archive of the files of a commit or tree
+    Archive {
+        /// `tar` or `zip`, implied by the output file extension and tar by default
+        #[arg(long)]
+        format: Option<archive::ArchiveFormat>,
+        /// Prepend the prefix to paths of all files, ending with `/` for a directory
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// Write the archive to the file instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        tree_ish: String,
+    },
+    /// Move the current branch to commit, resetting the index and working tree by mode
+    Reset {
+        /// Keep the index and working tree
+        #[arg(long, conflicts_with_all = ["mixed", "hard"])]
+        soft: bool,
+        /// Reset the index but not the working tree, the default
+        #[arg(long, conflicts_with = "hard")]
+        mixed: bool,
+        /// Reset the index and working tree, discarding local changes
+        #[arg(long)]
+        hard: bool,
+        /// Commit to reset to, HEAD by default
+        rev: Option<String>,
+    },
+    /// Apply changes of the commit as new commit on the current branch
+    CherryPick {
+        commit: String,
+    },
+    /// Write commits as patch files ready for email
+    FormatPatch {
+        /// Directory to write the patch files to instead of the current one
+        #[arg(short = 'o', long = "output-directory")]
+        output_directory: Option<PathBuf>,
+        /// Print the patches to standard output in mbox format
+        #[arg(long, conflicts_with = "output_directory")]
+        stdout: bool,
+        /// Format all commits reachable from the revision instead of the ones since it
+        #[arg(long)]
+        root: bool,
+        /// Format only the topmost commits, `-<n>` is accepted too. Single revision is
+        /// then the tip to start from.
+        #[arg(long)]
+        max_count: Option<usize>,
+        /// Commits since the revision up to HEAD, or `A..B` range
+        rev: Option<String>,
+    },
+    /// Apply patches from mailbox as commits on the current branch
+    Am {
+        /// Mailbox files with patches, standard input when none given
+        mboxes: Vec<PathBuf>,
+    },
+    /// Apply patches to the working tree or the index
+    Apply {
+        /// Apply to the index only, leaving the working tree untouched
+        #[arg(long, conflicts_with = "index")]
+        cached: bool,
+        /// Apply to both the index and the working tree
+        #[arg(long)]
+        index: bool,
+        /// Only verify that the patches apply
+        #[arg(long)]
+        check: bool,
+        /// Undo the changes described by the patches
+        #[arg(short = 'R', long)]
+        reverse: bool,
+        /// Allow matching hunks with as few as N lines of leading and trailing context
+        #[arg(short = 'C', value_name = "N")]
+        context: Option<usize>,
+        /// Patch files, standard input when none given
+        patches: Vec<PathBuf>,
+    },
+    /// Reapply commits of the current branch on top of upstream
+    Rebase {
+        /// Restore the branch as it was before the rebase
+        #[arg(long, conflicts_with_all = ["continue_", "upstream"])]
+        abort: bool,
+        /// Commit resolved conflicts and pick the remaining commits
+        #[arg(long = "continue", conflicts_with = "upstream")]
+        continue_: bool,
+        #[arg(required_unless_present_any = ["abort", "continue_"])]
+        upstream: Option<String>,
+    },
+    /// Show commit with its changes
+    Show {
+        #[command(flatten)]
+        renames: RenameArgs,
+        #[arg(default_value = "HEAD")]
+        rev: String,
+    },
+    /// Name commits after the nearest tags they descend from
+    Describe(DescribeArgs),
+    /// List, create or delete tags
+    Tag(TagArgs),
+    /// Check signatures of commits
+    VerifyCommit {
+        #[arg(required = true)]
+        commits: Vec<String>,
+    },
+    /// Check signatures of annotated tags
+    VerifyTag {
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Show changes between the working tree, the index and commits
+    Diff {
+        /// Compare the index with HEAD or given commit instead of the working tree
+        #[arg(long, alias = "staged")]
+        cached: bool,
+        /// Number of context lines
+        #[arg(short = 'U', long = "unified", default_value = "3")]
+        unified: usize,
+        #[command(flatten)]
+        renames: RenameArgs,
+        #[command(flatten)]
+        stat: StatArgs,
+        /// Up to two commits or trees, or `A..B` range
+        #[arg(num_args = 0..=2)]
+        revs: Vec<String>,
+    },
+    /// Show the commit which last changed each line of the file
+    Blame {
+        /// Optional commit followed by the path
+        #[arg(num_args = 1..=2, required = true)]
+        args: Vec<String>,
+    },
+    /// Save local changes away and restore them later, `list` by default
+    Stash {
+        #[command(subcommand)]
+        command: Option<StashCommand>,
+    },
+    /// Create and inspect bundles, files with refs and objects for offline transfer
+    Bundle {
+        #[command(subcommand)]
+        command: BundleCommand,
+    },
+    /// Write history of the revisions as fast-import stream to standard output
+    FastExport {
+        /// Revisions to export, `^rev` and `rev..` exclude history, `--all` exports all refs
+        #[arg(required = true, allow_hyphen_values = true)]
+        revs: Vec<String>,
+    },
+    /// Read fast-import stream from standard input, storing objects and updating refs
+    FastImport {
+        /// Update branches even when they would lose commits
+        #[arg(long)]
+        force: bool,
+    },
+    /// List files in the index and the working tree
+    LsFiles {
+        /// Show tracked files, the default when nothing else is requested
+        #[arg(short, long)]
+        cached: bool,
+        /// Show mode, object id and stage of tracked files
+        #[arg(short, long)]
+        stage: bool,
+        /// Show untracked files
+        #[arg(short, long)]
+        others: bool,
+        /// Skip untracked files ignored by `.gitignore` and exclude files
+        #[arg(long)]
+        exclude_standard: bool,
+        /// Show tracked files which differ from the index in the working tree
+        #[arg(short, long)]
+        modified: bool,
+    },
+    /// Remove files from the working tree and the index
+    Rm {
+        /// Remove only from the index, keeping the files
+        #[arg(long)]
+        cached: bool,
+        /// Remove even files with changes that would be lost
+        #[arg(short, long)]
+        force: bool,
+        /// Allow removing directories recursively
+        #[arg(short)]
+        r: bool,
+        #[arg(required = true)]
+        paths: Vec<OsString>,
+    },
+    /// Move or rename files and directories, updating the index
+    Mv {
+        /// Overwrite existing destination
+        #[arg(short, long)]
+        force: bool,
+        /// Sources followed by the destination, which must be a directory for more sources
+        #[arg(num_args = 2.., required = true)]
+        paths: Vec<OsString>,
+    },
+    /// Remove untracked files from the working tree
+    Clean {
+        /// Only show what would be removed
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        #[arg(short, long)]
+        force: bool,
+        /// Remove untracked directories too
+        #[arg(short)]
+        d: bool,
+        /// Remove ignored files too
+        #[arg(short)]
+        x: bool,
+    },
+    /// Show which of the paths are ignored
+    CheckIgnore {
+        /// Show the pattern which decided about each path and where it comes from
+        #[arg(short, long)]
+        verbose: bool,
+        /// Show also paths matching no pattern, requires `--verbose`
+        #[arg(short, long, requires = "verbose")]
+        non_matching: bool,
+        #[arg(required = true)]
+        paths: Vec<OsString>,
+    },
+    /// Search tracked files of the working tree or given commit for lines matching pattern
+    Grep {
+        /// Prefix matching lines with their line numbers
+        #[arg(short = 'n', long)]
+        line_number: bool,
+        #[arg(short, long)]
+        ignore_case: bool,
+        pattern: String,
+        rev: Option<String>,
+        /// Limit the search to these paths
+        #[arg(last = true)]
+        pathspecs: Vec<OsString>,
+    },
+    /// Binary search for the commit which introduced a bug
+    Bisect {
+        #[command(subcommand)]
+        command: BisectCommand,
+    },
+    /// Manage submodules recorded as gitlinks
+    Submodule {
+        #[command(subcommand)]
+        command: SubmoduleCommand,
+    },
+    /// Manage working trees linked to the repository
+    Worktree {
+        #[command(subcommand)]
+        command: WorktreeCommand,
+    },
+    /// Show history of ref updates, of HEAD by default
+    #[command(args_conflicts_with_subcommands = true)]
+    Reflog {
+        #[command(subcommand)]
+        command: Option<ReflogCommand>,
+        reference: Option<String>,
+    },
+    /// Verify objects of the repository and report unreachable ones
+    Fsck {
+        /// List all unreachable objects, not only dangling ones
+        #[arg(long)]
+        unreachable: bool,
+        /// Do not report dangling objects
+        #[arg(long)]
+        no_dangling: bool,
+    },
+    /// Verify pack and write its index, the pack comes from a file or standard input
+    IndexPack {
+        /// Read the pack from standard input and store it in the repository
+        #[arg(long, conflicts_with = "pack_file")]
+        stdin: bool,
+        /// Complete thin pack from standard input with bases from the repository
+        #[arg(long, requires = "stdin")]
+        fix_thin: bool,
+        /// Write the index of the pack file to this file instead of next to the pack
+        #[arg(short, conflicts_with = "stdin")]
+        output: Option<PathBuf>,
+        /// Also verify the structure of every object
+        #[arg(long)]
+        strict: bool,
+        #[arg(required_unless_present = "stdin")]
+        pack_file: Option<PathBuf>,
+    },
+    /// Write pack of objects listed on standard input, or of revisions with `--revs`
+    PackObjects {
+        /// Write the pack to standard output instead of storing it
+        #[arg(long, conflicts_with = "base_name")]
+        stdout: bool,
+        /// Read revisions instead of objects, `^rev` and `--not` exclude history
+        #[arg(long)]
+        revs: bool,
+        /// Pack history of all refs, implies `--revs`
+        #[arg(long)]
+        all: bool,
+        /// Store objects as deltas against objects the receiver has, with `--revs`
+        #[arg(long, requires = "stdout")]
+        thin: bool,
+        /// Try this many objects as delta bases, `pack.window` by default
+        #[arg(long)]
+        window: Option<usize>,
+        /// Longest delta chain, `pack.depth` by default
+        #[arg(long)]
+        depth: Option<usize>,
+        /// Store the pack as `<base-name>-<checksum>.pack`, in the repository by default
+        base_name: Option<PathBuf>,
+    },
+    /// Verify packs against their indexes
+    VerifyPack {
+        /// List objects of the packs with their delta chains
+        #[arg(short, long)]
+        verbose: bool,
+        /// Only show the histogram of delta chain lengths
+        #[arg(short, long)]
+        stat_only: bool,
+        /// Index or pack files
+        #[arg(required = true)]
+        packs: Vec<PathBuf>,
+    },
+    /// Store objects of pack from standard input as loose objects
+    UnpackObjects {
+        /// Only verify the pack, without writing any object
+        #[arg(short = 'n')]
+        dry_run: bool,
+        /// Accepted for compatibility, nothing is reported anyway
+        #[arg(short)]
+        quiet: bool,
+        /// Also verify the structure of every object
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Pack reachable objects into single pack and prune old unreachable ones
+    Gc {
+        /// Search for deltas more thoroughly
+        #[arg(long)]
+        aggressive: bool,
+        /// Prune unreachable objects older than the date, `gc.pruneExpire` by default
+        #[arg(long, value_name = "DATE", require_equals = true)]
+        prune: Option<String>,
+        /// Keep all unreachable objects
+        #[arg(long, conflicts_with = "prune")]
+        no_prune: bool,
+    },
+    /// Delete unreachable loose objects
+    Prune {
+        /// Only report what would be removed
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Report removed objects
+        #[arg(short, long)]
+        verbose: bool,
+        /// Only prune objects older than the date
+        #[arg(long)]
+        expire: Option<String>,
+    },
+    /// Manage the index of objects in all packs
+    MultiPackIndex {
+        #[command(subcommand)]
+        command: MultiPackIndexCommand,
+    },
+    /// Count loose and packed objects and the disk space they take
+    CountObjects {
+        /// Report details including packs and garbage
+        #[arg(short, long)]
+        verbose: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum MultiPackIndexCommand {
+    /// Write multi-pack-index covering all packs
+    Write,
+}
+
+#[derive(Subcommand)]
+enum RemoteCommand {
+    /// Add remote fetching all its branches
+    Add {
+        name: String,
+        url: String,
+    },
+    /// Remove remote with its remote-tracking branches and config
+    #[command(alias = "rm")]
+    Remove {
+        name: String,
+    },
+    /// Show URLs and branches of remote
+    Show {
+        /// Do not query the remote, show only configured values
+        #[arg(short = 'n')]
+        no_query: bool,
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum BundleCommand {
+    /// Bundle refs named by the revisions, `^rev` and `rev..` exclude objects the
+    /// receiving repository has, `--all` bundles all refs
+    Create {
+        file: PathBuf,
+        #[arg(required = true, allow_hyphen_values = true)]
+        revs: Vec<String>,
+    },
+    /// Check that the bundle can be applied to the current repository
+    Verify { file: PathBuf },
+    /// List refs of the bundle
+    ListHeads { file: PathBuf },
+}
+
+#[derive(Subcommand)]
+enum StashCommand {
+    /// Save local changes and reset the working tree to HEAD
+    Push {
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    /// List stashed changes
+    List,
+    /// Apply stashed changes and remove them from the stash
+    Pop {
+        #[arg(default_value = "0")]
+        stash: String,
+    },
+    /// Apply stashed changes keeping them in the stash
+    Apply {
+        #[arg(default_value = "0")]
+        stash: String,
+    },
+    /// Remove stashed changes without applying them
+    Drop {
+        #[arg(default_value = "0")]
+        stash: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum BisectCommand {
+    /// Start bisecting, optionally with known bad and good commits
+    Start {
+        bad: Option<String>,
+        good: Vec<String>,
+    },
+    /// Mark commits as not containing the bug
+    Good { revs: Vec<String> },
+    /// Mark commit as containing the bug
+    Bad { rev: Option<String> },
+    /// End bisection and check out the original branch or given commit
+    Reset { commit: Option<String> },
+}
+
+#[derive(Subcommand)]
+enum SubmoduleCommand {
+    /// Clone missing submodules and check out the commits recorded in the superproject
+    Update {
+        /// Register submodules from `.gitmodules` which are not registered yet
+        #[arg(long)]
+        init: bool,
+        /// Update submodules inside submodules too
+        #[arg(long)]
+        recursive: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum WorktreeCommand {
+    /// Create working tree at the path and check out the commit there
+    Add {
+        /// Create new branch for the working tree
+        #[arg(short = 'b')]
+        new_branch: Option<String>,
+        #[arg(long, conflicts_with = "new_branch")]
+        detach: bool,
+        path: PathBuf,
+        commit: Option<String>,
+    },
+    /// List working trees with their HEADs
+    List {
+        #[arg(long)]
+        porcelain: bool,
+    },
+    /// Remove linked working tree
+    Remove {
+        /// Remove even with local modifications or untracked files
+        #[arg(short, long)]
+        force: bool,
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum ReflogCommand {
+    /// Show reflog of the ref
+    Show {
+        #[arg(default_value = "HEAD")]
+        reference: String,
+    },
+    /// Check whether the ref has a reflog
+    Exists { reference: String },
+}
+
+#[derive(Args)]
+struct RenameArgs {
+    /// Detect renames, optionally with minimal similarity like `-M=60%`
+    #[arg(short = 'M', long = "find-renames", num_args = 0..=1, require_equals = true, default_missing_value = "50%")]
+    find_renames: Option<String>,
+    /// Detect renames and copies of modified files
+    #[arg(short = 'C', long = "find-copies", num_args = 0..=1, require_equals = true, default_missing_value = "50%")]
+    find_copies: Option<String>,
+    #[arg(long, conflicts_with_all = ["find_renames", "find_copies"])]
+    no_renames: bool,
+}
+
+impl RenameArgs {
+    /// Detection requested by arguments, `diff.renames` config otherwise
+    fn resolve(&self, repo: &GitRepo) -> Result<Option<diff::Renames>> {
+        if self.no_renames {
+            return Ok(None);
+        }
+        let copies = self.find_copies.is_some();
+        match self.find_copies.as_ref().or(self.find_renames.as_ref()) {
+            Some(threshold) => Ok(Some(diff::Renames {
+                threshold: diff::Renames::parse_threshold(threshold)?,
+                copies,
+            })),
+            None => Ok(diff::Renames::from_config(&config::Config::load(repo)?)?),
+        }
     }
 }
 
-fn main() -> Result<()> {
-    match Cli::parse().command {
-        Commands::Init => {
-            GitRepo::new_in_cwd()?.init()?;
-            println!("Initialized git directory")
-        }
-        Commands::CatFile {
-            pretty_print,
-            object,
-        } => {
-            ensure!(pretty_print, "Only pretty-print is supported!");
-            let repo = GitRepo::new_in_cwd()?;
-            let obj = object::Object::read(&repo, object)?;
-            obj.print_pretty()?;
-        }
-        Commands::HashObject { write, file } => {
-            let repo = GitRepo::new_in_cwd()?;
-            let obj: object::Object = File::open(file).context("Open input file")?.try_into()?;
-            if write {
-                obj.write(&repo)?;
-            }
-            println!("{}", obj.hash);
-        }
-        Commands::LsTree {
-            name_only,
-            tree_sha,
-        } => {
-            ensure!(name_only, "Only name-only mode is supported!");
-            let repo = GitRepo::new_in_cwd()?;
-            let obj = object::Object::read(&repo, tree_sha)?;
-            let t = tree::Tree::try_from(obj)?;
-            for entry in t.entries {
-                println!("{}", entry.name);
-            }
-        }
-        Commands::WriteTree => {
-            let repo = GitRepo::new_in_cwd()?;
-            let obj = tree::Tree::write(&repo, &repo.repo_root)?;
-            println!("{}", obj.hash);
-        }
-        Commands::CommitTree { tree_sha, parent, message } => {
-            let repo = GitRepo::new_in_cwd()?;
-            let author = commit::Author {
-                name: String::from("Bob"),
-                email: String::from("bob@example.com"),
-                time: std::time::SystemTime::now(),
-                time_offset: String::from("+0200"),
-            };
-            let c = commit::Commit {
-                tree_sha,
-                parent,
-                author: author.clone(),
-                commiter: author,
-                message,
-            };
-            let obj: object::Object = c.try_into()?;
-            obj.write(&repo)?;
-            println!("{}", obj.hash);
-        },
-        Commands::Clone { repo_url, dest } => {
-            std::fs::create_dir_all(&dest)?;
-            let repo = GitRepo::new(&dest);
-            repo.init()?;
-            let http_client = http_protocol::GitHttpClient::new(&repo, repo_url);
-            let ref_info = http_client.ref_info()?;
-            http_client.fetch_refs(ref_info.refs.iter().map(|r| &r.id).collect())?;
-
-            if let Some(r) = ref_info.refs.first() {
-                if r.name == "HEAD" {
-                    repo.checkout(String::from_utf8(r.id.to_vec())?)?;
+#[derive(Args)]
+struct StatArgs {
+    /// Show histogram of changed lines instead of the patch, optionally with total width
+    #[arg(long, num_args = 0..=1, require_equals = true, value_name = "WIDTH")]
+    stat: Option<Option<usize>>,
+    /// Show added and removed line counts per file instead of the patch
+    #[arg(long)]
+    numstat: bool,
+    /// Show only the totals line instead of the patch
+    #[arg(long)]
+    shortstat: bool,
+}
+
+impl StatArgs {
+    fn any(&self) -> bool {
+        self.stat.is_some() || self.numstat || self.shortstat
+    }
+
+    /// Requested summaries in the order git prints them
+    fn write(&self, out: &mut Vec<u8>, stats: &[diff::FileStat]) {
+        if self.numstat {
+            diff::write_numstat(out, stats);
+        }
+        if let Some(width) = self.stat {
+            // Like git, use terminal width from the environment when not given
+            let width = width
+                .or(std::env::var("COLUMNS").ok().and_then(|c| c.parse().ok()))
+                .filter(|width| *width > 0)
+                .unwrap_or(80);
+            diff::write_stat(out, stats, width);
+        } else if self.shortstat {
+            diff::write_shortstat(out, stats);
+        }
+    }
+}
+
+/// Prints output of library operations to stderr, their traces only with `GIT_TRACE`
+#[derive(Debug)]
+struct StderrProgress {
+    trace: bool,
+}
+
+impl progress::Progress for StderrProgress {
+    fn info(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+
+    fn warning(&self, message: &str) {
+        eprintln!("warning: {}", message);
+    }
+
+    fn debug(&self, message: &str) {
+        if self.trace {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+fn stderr_progress() -> Arc<dyn progress::Progress> {
+    let trace = std::env::var("GIT_TRACE").is_ok_and(|v| !v.is_empty() && v != "0");
+    Arc::new(StderrProgress { trace })
+}
+
+/// Repository of the current directory reporting to stderr
+fn repo_in_cwd() -> Result<GitRepo> {
+    Ok(GitRepo::new_in_cwd()?.with_progress(stderr_progress()))
+}
+
+#[derive(Args)]
+#[command(group(clap::ArgGroup::new("mode").required(true)))]
+struct CatFileArgs {
+    /// Print the content, trees in the format of ls-tree
+    #[arg(short = 'p', group = "mode")]
+    pretty_print: bool,
+    /// Print the kind of the object
+    #[arg(short = 't', group = "mode")]
+    show_type: bool,
+    /// Print the size of the object content
+    #[arg(short = 's', group = "mode")]
+    show_size: bool,
+    /// Print nothing, exit with non-zero status when the object does not exist
+    #[arg(short = 'e', group = "mode")]
+    exists: bool,
+    /// Print header and content of each object named on stdin, the header in the format
+    /// given
+    #[arg(
+        long,
+        group = "mode",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = BATCH_FORMAT
+    )]
+    batch: Option<String>,
+    /// Like --batch, but print only the header
+    #[arg(
+        long,
+        group = "mode",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = BATCH_FORMAT
+    )]
+    batch_check: Option<String>,
+    #[arg(
+        required_unless_present_any = ["batch", "batch_check"],
+        conflicts_with_all = ["batch", "batch_check"]
+    )]
+    object: Option<String>,
+}
+
+const BATCH_FORMAT: &str = "%(objectname) %(objecttype) %(objectsize)";
+
+fn run_cat_file(args: CatFileArgs) -> Result<()> {
+    use object::{Object, ObjectKind};
+
+    let repo = repo_in_cwd()?;
+    if let Some(format) = &args.batch {
+        return run_cat_file_batch(&repo, format, true);
+    }
+    if let Some(format) = &args.batch_check {
+        return run_cat_file_batch(&repo, format, false);
+    }
+    let object = args.object.ok_or(anyhow!("Missing object"))?;
+    if args.exists {
+        let found = revparse::resolve(&repo, &object).map(|id| Object::exists(&repo, &id));
+        if !matches!(found, Ok(true)) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let id = revparse::resolve(&repo, &object)?;
+    let mut reader = Object::reader(&repo, &id)?;
+    if args.show_type {
+        println!("{}", reader.header.kind.as_str());
+    } else if args.show_size {
+        println!("{}", reader.header.data_length);
+    } else if reader.header.kind == ObjectKind::Tree {
+        let tree = tree::Tree::try_from(Object::read(&repo, &id)?)?;
+        let quote = quote_path(&repo)?;
+        for entry in tree.entries {
+            println!(
+                "{:0>6} {} {}\t{}",
+                entry.mode,
+                entry.kind().as_str(),
+                entry.reference,
+                pathname::quote(&entry.name, quote)
+            );
+        }
+    } else {
+        std::io::copy(&mut reader, &mut std::io::stdout().lock()).context("Writing result")?;
+    }
+    Ok(())
+}
+
+fn run_read_tree(merge: bool, aggressive: bool, tree_ish: Vec<String>) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let trees = tree_ish
+        .iter()
+        .map(|t| revparse::resolve(&repo, &format!("{}^{{tree}}", t)))
+        .collect::<Result<Vec<_>, _>>()?;
+    ensure!(
+        merge || trees.len() == 1,
+        "Reading multiple trees requires -m"
+    );
+    let index = index::Index::read(&repo)?;
+    let index = match &trees[..] {
+        [tree] if merge => index.read_tree(&repo, tree)?,
+        [tree] => index::Index::from_tree(&repo, tree)?,
+        [from, to] => index.two_way_merge(&repo, from, to)?,
+        [base, ours, theirs] => index.three_way_merge(&repo, [base, ours, theirs], aggressive)?,
+        _ => unreachable!("Clap limits number of trees"),
+    };
+    Ok(index.write(&repo)?)
+}
+
+fn run_checkout_index(
+    all: bool,
+    force: bool,
+    update_index: bool,
+    prefix: Option<String>,
+    paths: Vec<OsString>,
+) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    let mut index = index::Index::read(&repo)?;
+    let mut targets = Vec::new();
+    if all {
+        // Unmerged and existing files are skipped silently
+        targets.extend(index.entries.iter().filter(|e| e.stage == 0).cloned());
+    } else {
+        for path in &paths {
+            let path = pathname::from_os(path);
+            match index.get(&path, 0) {
+                Some(entry) => targets.push(entry.clone()),
+                None if index.entries.iter().any(|e| e.path == path) => {
+                    eprintln!("{} is unmerged", pathname::display(&path))
                 }
+                None => anyhow::bail!("{} is not in the cache", pathname::display(&path)),
+            }
+        }
+    }
+    let prefix = prefix.unwrap_or_default();
+    let mut attributes = attributes::Attributes::load(&repo)?;
+    let mut updated = false;
+    for entry in targets {
+        if entry.mode == submodule::GITLINK_MODE {
+            continue;
+        }
+        let path = repo.worktree_path(&[prefix.as_bytes(), &entry.path].concat());
+        if !force && fs::symlink_metadata(&path).is_ok() {
+            if !all {
+                eprintln!(
+                    "{} already exists, no checkout",
+                    pathname::display(&entry.path)
+                );
+            }
+            continue;
+        }
+        if force && path.is_dir() {
+            fs::remove_dir_all(&path)?;
+        }
+        tree::Tree::checkout_blob(&repo, &path, entry.mode, &entry.id, &mut attributes)?;
+        if update_index && prefix.is_empty() {
+            let metadata = fs::symlink_metadata(&path)?;
+            index.add(index::IndexEntry::from_metadata(
+                &entry.path,
+                entry.mode,
+                &entry.id,
+                &metadata,
+            ));
+            updated = true;
+        }
+    }
+    if updated {
+        index.write(&repo)?;
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct UpdateIndexArgs {
+    /// Add files not yet in the index
+    #[arg(long)]
+    add: bool,
+    /// Remove files missing in the working tree from the index
+    #[arg(long)]
+    remove: bool,
+    /// Stage object at the path without looking at the working tree
+    #[arg(long, value_name = "MODE,ID,PATH")]
+    cacheinfo: Vec<String>,
+    /// Record stat information of unchanged files, report the changed ones
+    #[arg(long)]
+    refresh: bool,
+    paths: Vec<OsString>,
+}
+
+fn run_update_index(args: UpdateIndexArgs) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let mut idx = index::Index::read(&repo)?;
+    let missing_add = |path: &[u8]| {
+        anyhow!(
+            "{}: cannot add to the index - missing --add option?",
+            pathname::display(path)
+        )
+    };
+    for info in &args.cacheinfo {
+        let mut parts = info.splitn(3, ',');
+        let (Some(mode), Some(id), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+            anyhow::bail!("option 'cacheinfo' expects <mode>,<sha1>,<path>");
+        };
+        let mode = u32::from_str_radix(mode, 8)
+            .map_err(|_| anyhow!("git update-index: --cacheinfo cannot add {}", path))?;
+        let id: oid::ObjectId = id.parse()?;
+        let path = path.as_bytes();
+        ensure!(
+            args.add || idx.entries.iter().any(|e| e.path == path),
+            missing_add(path)
+        );
+        idx.remove(path);
+        idx.add(index::IndexEntry::new(path, mode, &id, 0));
+    }
+    if !args.paths.is_empty() {
+        repo.require_worktree()?;
+    }
+    let mut attributes = attributes::Attributes::load(&repo)?;
+    for path in &args.paths {
+        let path = repo.path_from_cwd(path)?;
+        let tracked = idx.entries.iter().any(|e| e.path == path);
+        ensure!(args.add || tracked, missing_add(&path));
+        if !idx.stage_file(&repo, &path, &mut attributes)? {
+            ensure!(
+                args.remove,
+                "{}: does not exist and --remove not passed",
+                pathname::display(&path)
+            );
+            idx.remove(&path);
+        }
+    }
+    let modified = if args.refresh {
+        idx.refresh(&repo)?
+    } else {
+        Vec::new()
+    };
+    idx.write(&repo)?;
+    if !modified.is_empty() {
+        for path in modified {
+            println!("{}: needs update", pathname::display(&path));
+        }
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_update_ref(
+    delete: bool,
+    message: Option<String>,
+    reference: String,
+    values: Vec<String>,
+) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    // Value the ref must have, `None` for must not exist
+    let parse_old = |value: &str| -> Result<Option<oid::ObjectId>> {
+        if value.is_empty() || value.bytes().all(|b| b == b'0') {
+            Ok(None)
+        } else {
+            Ok(Some(revparse::resolve(&repo, value)?))
+        }
+    };
+    let (new, old) = match (delete, &values[..]) {
+        (true, []) => (None, None),
+        (true, [old]) => (None, Some(parse_old(old)?)),
+        (false, [new]) => (Some(revparse::resolve(&repo, new)?), None),
+        (false, [new, old]) => (Some(revparse::resolve(&repo, new)?), Some(parse_old(old)?)),
+        (false, []) => anyhow::bail!("Missing new value of {}", reference),
+        (true, _) => anyhow::bail!("Deleting {} takes only the old value", reference),
+        _ => unreachable!("Clap limits number of values"),
+    };
+    if let Some(new) = &new {
+        ensure!(
+            object::Object::exists(&repo, new),
+            "{}: not a valid SHA1",
+            new
+        );
+    }
+    let name = refs::deref(&repo, &reference)?;
+    let message = message.unwrap_or_default();
+    let mut transaction = refs::RefTransaction::new();
+    match old {
+        Some(old) => transaction.update(&name, old.as_ref(), new.as_ref(), &message),
+        None => transaction.force_update(&name, new.as_ref(), &message),
+    };
+    Ok(transaction.commit(&repo)?)
+}
+
+fn run_symbolic_ref(
+    quiet: bool,
+    short: bool,
+    message: Option<String>,
+    name: String,
+    target: Option<String>,
+) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    if let Some(target) = target {
+        refs::set_symbolic(&repo, &name, &target, message.as_deref())?;
+        return Ok(());
+    }
+    match refs::read(&repo, &name)? {
+        Some(refs::RefValue::Symbolic(target)) if short => println!("{}", refs::shorten(&target)),
+        Some(refs::RefValue::Symbolic(target)) => println!("{}", target),
+        _ if quiet => std::process::exit(1),
+        _ => anyhow::bail!("ref {} is not a symbolic ref", name),
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct ShowRefArgs {
+    /// Only branches
+    #[arg(long)]
+    heads: bool,
+    /// Only tags
+    #[arg(long)]
+    tags: bool,
+    /// Also print objects annotated tags point to, as `<tag>^{}`
+    #[arg(short, long)]
+    dereference: bool,
+    /// Print only object ids
+    #[arg(short = 's', long)]
+    hash: bool,
+    /// Patterns are exact ref names which all have to exist
+    #[arg(long)]
+    verify: bool,
+    /// Print nothing, only exit with non-zero status when nothing matches
+    #[arg(short, long)]
+    quiet: bool,
+    /// Match ref names ending with these components, e.g. `main` or `heads/main`
+    patterns: Vec<String>,
+}
+
+fn run_show_ref(args: ShowRefArgs) -> Result<()> {
+    let ShowRefArgs {
+        heads,
+        tags,
+        dereference,
+        hash,
+        verify,
+        quiet,
+        patterns,
+    } = args;
+    let repo = repo_in_cwd()?;
+    let refs = if verify {
+        let mut refs = Vec::new();
+        for name in &patterns {
+            let valid = name == "HEAD" || name.starts_with("refs/");
+            match refs::resolve(&repo, name)? {
+                Some(id) if valid => refs.push((name.clone(), id)),
+                _ if quiet => std::process::exit(1),
+                _ => anyhow::bail!("'{}' - not a valid ref", name),
+            }
+        }
+        refs
+    } else {
+        let mut refs = refs::list(&repo)?;
+        refs.retain(|(name, _)| {
+            let kind_matches = !(heads || tags)
+                || heads && name.starts_with("refs/heads/")
+                || tags && name.starts_with("refs/tags/");
+            let pattern_matches = patterns.is_empty()
+                || patterns
+                    .iter()
+                    .any(|p| name == p || name.ends_with(&format!("/{}", p)));
+            kind_matches && pattern_matches
+        });
+        refs
+    };
+    if refs.is_empty() {
+        std::process::exit(1);
+    }
+    if quiet {
+        return Ok(());
+    }
+    for (name, id) in refs {
+        let print = |id: &oid::ObjectId, name: &str| {
+            if hash {
+                println!("{}", id);
             } else {
-                bail!("Missing HEAD reference");
+                println!("{} {}", id, name);
             }
-        },
+        };
+        print(&id, &name);
+        if dereference {
+            let obj = object::Object::read(&repo, &id)?;
+            if obj.header.kind == object::ObjectKind::Tag {
+                let peeled = revparse::resolve(&repo, &format!("{}^{{}}", id))?;
+                print(&peeled, &format!("{}^{{}}", name));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct ForEachRefArgs {
+    /// Format of each line, with `%(refname)`, `%(refname:short)`, `%(objectname)`,
+    /// `%(objectname:short)`, `%(objecttype)`, `%(objectsize)`, `%(creatordate)` and
+    /// `%(subject)` placeholders
+    #[arg(long, default_value = "%(objectname) %(objecttype)\t%(refname)")]
+    format: String,
+    /// Sort by the field, descending with `-` prefix. The last key is the primary one.
+    #[arg(long)]
+    sort: Vec<String>,
+    /// Print at most this many refs
+    #[arg(long)]
+    count: Option<usize>,
+    /// List only refs with these prefixes, or matching these globs
+    patterns: Vec<String>,
+}
+
+/// Ref with the object it points to, as listed by for-each-ref
+struct ListedRef {
+    name: String,
+    object: object::Object,
+}
+
+impl ListedRef {
+    /// Commit date of commits, tagger date of tags
+    fn creator(&self) -> Result<Option<commit::Author>> {
+        Ok(match self.object.header.kind {
+            object::ObjectKind::Commit => {
+                Some(commit::Commit::try_from(self.object.clone())?.committer)
+            }
+            object::ObjectKind::Tag => tag::Tag::try_from(self.object.clone())?.tagger,
+            _ => None,
+        })
+    }
+
+    fn atom(&self, repo: &GitRepo, atom: &str) -> Result<String> {
+        let obj = &self.object;
+        Ok(match atom {
+            "refname" => self.name.clone(),
+            "refname:short" => refs::shorten(&self.name).to_string(),
+            "objectname" => obj.hash.to_hex(),
+            "objectname:short" => object::Object::abbreviate(repo, &obj.hash, 7)?,
+            "objecttype" => obj.header.kind.as_str().to_string(),
+            "objectsize" => obj.header.data_length.to_string(),
+            "creatordate" => self
+                .creator()?
+                .map(|author| author.format_date())
+                .unwrap_or_default(),
+            "subject" => {
+                let message = match obj.header.kind {
+                    object::ObjectKind::Commit => commit::Commit::try_from(obj.clone())?.message,
+                    object::ObjectKind::Tag => tag::Tag::try_from(obj.clone())?.message,
+                    _ => String::new(),
+                };
+                message.lines().next().unwrap_or_default().to_string()
+            }
+            _ => anyhow::bail!("unknown field name: {}", atom),
+        })
+    }
+
+    /// Numeric fields compare as numbers, the rest as text
+    fn sort_key(&self, repo: &GitRepo, key: &str) -> Result<(i64, String)> {
+        Ok(match key {
+            "creatordate" => (self.creator()?.map_or(0, |a| a.unix_time()), String::new()),
+            "objectsize" => (self.object.header.data_length as i64, String::new()),
+            _ => (0, self.atom(repo, key)?),
+        })
+    }
+}
+
+fn run_for_each_ref(args: ForEachRefArgs) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let mut listed = Vec::new();
+    for (name, id) in refs::list(&repo)? {
+        let matches = args.patterns.is_empty()
+            || args.patterns.iter().any(|p| {
+                let prefix = format!("{}/", p.trim_end_matches('/'));
+                name == *p
+                    || name.starts_with(&prefix)
+                    || ignore::wildmatch(p.as_bytes(), name.as_bytes())
+            });
+        if matches {
+            let object = object::Object::read(&repo, &id)?;
+            listed.push(ListedRef { name, object });
+        }
+    }
+    for key in &args.sort {
+        let (descending, key) = match key.strip_prefix('-') {
+            Some(key) => (true, key),
+            None => (false, key.as_str()),
+        };
+        let mut keyed = listed
+            .into_iter()
+            .map(|r| Ok((r.sort_key(&repo, key)?, r)))
+            .collect::<Result<Vec<_>>>()?;
+        keyed.sort_by(|(a, _), (b, _)| if descending { b.cmp(a) } else { a.cmp(b) });
+        listed = keyed.into_iter().map(|(_, r)| r).collect();
+    }
+    for r in listed.iter().take(args.count.unwrap_or(usize::MAX)) {
+        let mut line = String::new();
+        let mut remaining = args.format.as_str();
+        while let Some(start) = remaining.find("%(") {
+            line.push_str(&remaining[..start].replace("%%", "%"));
+            let end = remaining[start..]
+                .find(')')
+                .ok_or(anyhow!("malformed format string {}", args.format))?;
+            line.push_str(&r.atom(&repo, &remaining[start + 2..start + end])?);
+            remaining = &remaining[start + end + 1..];
+        }
+        line.push_str(&remaining.replace("%%", "%"));
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct HashObjectArgs {
+    /// Store the object in the repository
+    #[arg(short)]
+    write: bool,
+    /// Kind of the objects
+    #[arg(short = 't', default_value = "blob")]
+    kind: object::ObjectKind,
+    /// Hash content read from stdin, before the files
+    #[arg(long)]
+    stdin: bool,
+    /// Skip checking that trees, commits and tags are well formed
+    #[arg(long)]
+    literally: bool,
+    #[arg(required_unless_present = "stdin")]
+    files: Vec<PathBuf>,
+}
+
+fn run_hash_object(args: HashObjectArgs) -> Result<()> {
+    use std::io::Read;
+
+    use object::{Object, ObjectKind};
+
+    let repo = repo_in_cwd()?;
+    let hash_data = |data: Vec<u8>| -> Result<_> {
+        let obj = Object::new(repo.hash_algo(), args.kind, data);
+        if args.kind != ObjectKind::Blob && !args.literally {
+            fsck::verify_object(&repo, &obj)?;
+        }
+        if args.write {
+            obj.write(&repo)?;
+        }
+        Ok(obj.hash)
+    };
+    if args.stdin {
+        let mut data = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut data)
+            .context("Reading stdin")?;
+        println!("{}", hash_data(data)?);
+    }
+    let mut attributes = attributes::Attributes::load(&repo)?;
+    let root = fs::canonicalize(&repo.repo_root)?;
+    for file in &args.files {
+        if args.kind != ObjectKind::Blob {
+            let data = fs::read(file).with_context(|| format!("Reading {}", file.display()))?;
+            println!("{}", hash_data(data)?);
+            continue;
+        }
+        let input = fs::File::open(file).context("Open input file")?;
+        // Files of the working tree get the same conversion as when they are staged,
+        // others are hashed as they are without reading them whole
+        let full_path = fs::canonicalize(file)?;
+        let path = full_path.strip_prefix(&root).ok();
+        let path = path.map(|path| pathname::from_os(path.as_os_str()));
+        let id = match path {
+            Some(path) if !attributes.is_verbatim(&path)? => {
+                hash_data(attributes.to_repo(&path, fs::read(file)?)?)?
+            }
+            _ => {
+                let size = input.metadata()?.len();
+                if args.write {
+                    Object::write_from_reader(&repo, args.kind, size, input)?
+                } else {
+                    Object::hash_from_reader(repo.hash_algo(), args.kind, size, input)?
+                }
+            }
+        };
+        println!("{}", id);
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct LsTreeArgs {
+    /// Print only paths of the entries
+    #[arg(long)]
+    name_only: bool,
+    /// Recurse into subtrees, listing only their entries
+    #[arg(short = 'r')]
+    recursive: bool,
+    /// List only trees
+    #[arg(short = 'd')]
+    trees_only: bool,
+    /// Print size of blobs
+    #[arg(short = 'l', long = "long", conflicts_with = "name_only")]
+    long: bool,
+    /// Tree, or commit or tag of it
+    tree_ish: String,
+    /// List only entries at these paths, contents of trees for paths ending with `/`
+    paths: Vec<String>,
+}
+
+fn run_ls_tree(args: LsTreeArgs) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let id = revparse::resolve(&repo, &format!("{}^{{tree}}", args.tree_ish))?;
+    let quote = quote_path(&repo)?;
+    ls_tree(&repo, &args, &id, b"", quote)
+}
+
+/// Whether paths are shown with non-ASCII bytes escaped, `core.quotePath`
+fn quote_path(repo: &GitRepo) -> Result<bool> {
+    let config = config::Config::load(repo)?;
+    Ok(config.get_bool("core.quotePath")?.unwrap_or(true))
+}
+
+fn ls_tree(
+    repo: &GitRepo,
+    args: &LsTreeArgs,
+    id: &oid::ObjectId,
+    prefix: &[u8],
+    quote: bool,
+) -> Result<()> {
+    use object::ObjectKind;
+
+    let tree = tree::Tree::try_from(object::Object::read(repo, id)?)?;
+    for entry in tree.entries {
+        let path = [prefix, &entry.name].concat();
+        // Entry is at or below some of the paths, or on the way to them
+        let selected = args.paths.is_empty()
+            || args
+                .paths
+                .iter()
+                .any(|p| match p.as_bytes().strip_suffix(b"/") {
+                    Some(dir) => path.starts_with(&pathname::concat(dir, b"")),
+                    None => {
+                        path == p.as_bytes()
+                            || path.starts_with(&pathname::concat(p.as_bytes(), b""))
+                    }
+                });
+        let leads_to = args
+            .paths
+            .iter()
+            .any(|p| p.as_bytes().starts_with(&pathname::concat(&path, b"")));
+        let kind = entry.kind();
+        let recurse =
+            kind == ObjectKind::Tree && (leads_to && !selected || selected && args.recursive);
+        // Trees recursed into are listed only when listing trees alone
+        let shown = selected
+            && (kind == ObjectKind::Tree || !args.trees_only)
+            && (!recurse || args.trees_only);
+        if shown {
+            print_ls_tree_entry(repo, args, &entry, &pathname::quote(&path, quote))?;
+        }
+        if recurse {
+            let prefix = pathname::concat(&path, b"");
+            ls_tree(repo, args, &entry.reference, &prefix, quote)?;
+        }
+    }
+    Ok(())
+}
+
+fn print_ls_tree_entry(
+    repo: &GitRepo,
+    args: &LsTreeArgs,
+    entry: &tree::TreeEntry,
+    path: &str,
+) -> Result<()> {
+    let (mode, kind, id) = (&entry.mode, entry.kind(), entry.reference);
+    if args.name_only {
+        println!("{}", path);
+    } else if args.long {
+        let size = match kind {
+            object::ObjectKind::Blob => object::Object::read_header(repo, &id)?
+                .data_length
+                .to_string(),
+            _ => String::from("-"),
+        };
+        let kind = kind.as_str();
+        println!("{:0>6} {} {} {:>7}\t{}", mode, kind, id, size, path);
+    } else {
+        println!("{:0>6} {} {}\t{}", mode, kind.as_str(), id, path);
+    }
+    Ok(())
+}
+
+/// Answers object names read from stdin line by line. With `%(rest)` in the format only
+/// the first word names the object and the rest of the line is echoed.
+fn run_cat_file_batch(repo: &GitRepo, format: &str, contents: bool) -> Result<()> {
+    use std::io::{BufRead, Write};
+
+    let split_rest = format.contains("%(rest)");
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    for line in std::io::stdin().lock().lines() {
+        let line = line.context("Reading stdin")?;
+        let (name, rest) = match line.trim_start().split_once(char::is_whitespace) {
+            Some((name, rest)) if split_rest => (name, rest.trim_start()),
+            _ => (line.trim(), ""),
+        };
+        let reader = revparse::resolve(repo, name).and_then(|id| object::Object::reader(repo, &id));
+        let mut reader = match reader {
+            Ok(reader) => reader,
+            Err(err) if matches!(err.root(), error::Error::AmbiguousObject(_)) => {
+                writeln!(out, "{} ambiguous", name)?;
+                out.flush()?;
+                continue;
+            }
+            Err(_) => {
+                writeln!(out, "{} missing", name)?;
+                out.flush()?;
+                continue;
+            }
+        };
+        writeln!(out, "{}", expand_batch_format(format, &reader, rest)?)?;
+        if contents {
+            std::io::copy(&mut reader, &mut out).context("Writing result")?;
+            writeln!(out)?;
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn expand_batch_format(format: &str, reader: &object::ObjectReader, rest: &str) -> Result<String> {
+    let mut result = String::new();
+    let mut remaining = format;
+    while let Some(start) = remaining.find("%(") {
+        result.push_str(&remaining[..start]);
+        let end = remaining[start..]
+            .find(')')
+            .ok_or(anyhow!("Unterminated format atom in {:?}", format))?;
+        match &remaining[start + 2..start + end] {
+            "objectname" => result.push_str(&reader.hash.to_hex()),
+            "objecttype" => result.push_str(reader.header.kind.as_str()),
+            "objectsize" => result.push_str(&reader.header.data_length.to_string()),
+            "rest" => result.push_str(rest),
+            atom => anyhow::bail!("Unknown format atom %({})", atom),
+        }
+        remaining = &remaining[start + end + 1..];
+    }
+    result.push_str(remaining);
+    Ok(result)
+}
+
+#[derive(Args)]
+struct ConfigArgs {
+    #[arg(long, conflicts_with_all = ["local", "system", "file"])]
+    global: bool,
+    #[arg(long, conflicts_with_all = ["system", "file"])]
+    local: bool,
+    #[arg(long, conflicts_with = "file")]
+    system: bool,
+    #[arg(short, long)]
+    file: Option<PathBuf>,
+    #[arg(long, group = "action")]
+    get: bool,
+    #[arg(long, group = "action")]
+    get_all: bool,
+    #[arg(short, long, group = "action")]
+    list: bool,
+    #[arg(long, group = "action")]
+    unset: bool,
+    #[arg(long, group = "action")]
+    unset_all: bool,
+    #[arg(long, group = "action")]
+    add: bool,
+    #[arg(long = "type")]
+    value_type: Option<config::ValueType>,
+    #[arg(long)]
+    show_origin: bool,
+    #[arg(long)]
+    show_scope: bool,
+    name: Option<String>,
+    value: Option<String>,
+}
+
+fn run_config(args: ConfigArgs) -> Result<()> {
+    use config::{Config, ConfigFile, ConfigScope};
+
+    let repo = repo_in_cwd()?;
+    let (scope, path) = if let Some(file) = args.file {
+        (ConfigScope::Local, Some(file))
+    } else if args.global {
+        (ConfigScope::Global, Some(Config::global_write_path()?))
+    } else if args.system {
+        let path = Config::system_path().ok_or(anyhow!("System config is disabled"))?;
+        (ConfigScope::System, Some(path))
+    } else if args.local {
+        (ConfigScope::Local, Some(Config::local_path(&repo)))
+    } else {
+        (ConfigScope::Local, None)
+    };
+    let format = |value: Option<&str>| -> Result<String> {
+        match args.value_type {
+            Some(t) => Ok(t.canonicalize(value)?),
+            None => Ok(value.unwrap_or_default().to_string()),
+        }
+    };
+    let prefix = |entry: &config::ConfigEntry| {
+        let mut prefix = String::new();
+        if args.show_scope {
+            prefix.push_str(entry.scope.as_str());
+            prefix.push('\t');
+        }
+        if args.show_origin {
+            prefix.push_str(&format!("file:{}\t", entry.origin.display()));
+        }
+        prefix
+    };
+
+    let is_write = args.unset || args.unset_all || args.add || (!args.get && !args.get_all && args.value.is_some());
+    if is_write {
+        let name = args.name.ok_or(anyhow!("Missing key name"))?;
+        let path = path.unwrap_or_else(|| Config::local_path(&repo));
+        let mut file = ConfigFile::open(&path)?;
+        if args.unset || args.unset_all {
+            let removed = file.unset(&name)?;
+            if removed == 0 {
+                std::process::exit(5);
+            }
+            ensure!(args.unset_all || removed == 1, "{} has multiple values", name);
+        } else {
+            let value = args.value.ok_or(anyhow!("Missing value for {}", name))?;
+            let value = match args.value_type {
+                Some(t) => t.canonicalize(Some(&value))?,
+                None => value,
+            };
+            if args.add {
+                file.add(&name, &value)?;
+            } else {
+                file.set(&name, &value)?;
+            }
+        }
+        return Ok(file.save()?);
+    }
+
+    let config = match path {
+        Some(path) => Config::load_file(&path, scope)?,
+        None => Config::load(&repo)?,
+    };
+    if args.list {
+        for entry in config.entries() {
+            match &entry.value {
+                Some(value) => println!("{}{}={}", prefix(entry), entry.key, value),
+                None => println!("{}{}", prefix(entry), entry.key),
+            }
+        }
+        return Ok(());
+    }
+
+    let key = config::ConfigKey::parse(&args.name.ok_or(anyhow!("Missing key name"))?)?;
+    let entries: Vec<_> = config.entries().iter().filter(|e| e.key == key).collect();
+    if entries.is_empty() {
+        std::process::exit(1);
+    }
+    let entries = if args.get_all {
+        &entries[..]
+    } else {
+        &entries[entries.len() - 1..]
+    };
+    for entry in entries {
+        println!("{}{}", prefix(entry), format(entry.value.as_deref())?);
+    }
+    Ok(())
+}
+
+fn run_remote(verbose: bool, command: Option<RemoteCommand>) -> Result<()> {
+    use remote::Remote;
+
+    let repo = repo_in_cwd()?;
+    match command {
+        None => {
+            for name in Remote::list(&repo)? {
+                if verbose {
+                    let remote = Remote::load(&repo, &name)?;
+                    println!("{}\t{} (fetch)", name, remote.url);
+                    println!("{}\t{} (push)", name, remote.push_url());
+                } else {
+                    println!("{}", name);
+                }
+            }
+        }
+        Some(RemoteCommand::Add { name, url }) => {
+            Remote::add(&repo, &name, &url)?;
+        }
+        Some(RemoteCommand::Remove { name }) => Remote::remove(&repo, &name)?,
+        Some(RemoteCommand::Show { no_query, name }) => {
+            ensure!(Remote::list(&repo)?.contains(&name), "No such remote '{}'", name);
+            let remote = Remote::load(&repo, &name)?;
+            println!("* remote {}", name);
+            println!("  Fetch URL: {}", remote.url);
+            println!("  Push  URL: {}", remote.push_url());
+            if no_query {
+                println!("  HEAD branch: (not queried)");
+                return Ok(());
+            }
+            let ref_info = remote.ref_info(&repo)?;
+            let head = ref_info
+                .capabilities
+                .iter()
+                .find_map(|c| c.strip_prefix("symref=HEAD:refs/heads/"));
+            println!("  HEAD branch: {}", head.unwrap_or("(unknown)"));
+            let branches: Vec<_> = ref_info
+                .refs
+                .iter()
+                .filter_map(|r| r.name.strip_prefix("refs/heads/").map(|short| (short, &r.name)))
+                .collect();
+            if !branches.is_empty() {
+                println!("  Remote branch{}:", if branches.len() == 1 { "" } else { "es" });
+            }
+            for (short, name) in branches {
+                let state = match remote.tracking_ref(name) {
+                    Some((tracking, _)) if refs::read(&repo, &tracking)?.is_some() => {
+                        String::from("tracked")
+                    }
+                    Some((tracking, _)) => format!(
+                        "new (next fetch will store in {})",
+                        tracking.strip_prefix("refs/").unwrap_or(&tracking)
+                    ),
+                    None => String::from("skipped"),
+                };
+                println!("    {} {}", short, state);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_clone(
+    repo_url: String,
+    dest: PathBuf,
+    bare: bool,
+    mirror: bool,
+    reference: Option<PathBuf>,
+    recurse_submodules: bool,
+) -> Result<()> {
+    let is_empty_dir = fs::read_dir(&dest).is_ok_and(|mut items| items.next().is_none());
+    ensure!(
+        !dest.exists() || is_empty_dir,
+        "destination path '{}' already exists and is not an empty directory",
+        dest.display()
+    );
+    std::fs::create_dir_all(&dest)?;
+    let bare = bare || mirror;
+    let repo = if bare {
+        GitRepo::new_bare(&dest)
+    } else {
+        GitRepo::new(&dest)
+    }
+    .with_progress(stderr_progress());
+    // Bundles are remembered by absolute path, so that later fetches find them too
+    let is_bundle = bundle::Bundle::is_bundle(std::path::Path::new(&repo_url));
+    let repo_url = if is_bundle {
+        fs::canonicalize(&repo_url)?.to_string_lossy().into_owned()
+    } else {
+        repo_url
+    };
+    // The new repository names objects with the same hash function as the remote. The
+    // advertisement telling it is kept for fetching, so that it is requested only once.
+    let (object_format, advertised) = if is_bundle {
+        (bundle::Bundle::open(std::path::Path::new(&repo_url))?.algo, None)
+    } else {
+        let mut client = http_protocol::GitHttpClient::new(&repo, repo_url.clone());
+        let ref_info = client.ref_info()?;
+        (ref_info.object_format()?, Some((client, ref_info)))
+    };
+    repo.init_with_format(None, Some(object_format))?;
+    if let Some(reference) = reference {
+        let not_local = || {
+            format!(
+                "reference repository '{}' is not a local repository",
+                reference.display()
+            )
+        };
+        let reference_repo = fs::canonicalize(&reference)
+            .map_err(error::Error::from)
+            .and_then(|dir| GitRepo::discover(&dir))
+            .with_context(not_local)?;
+        let objects_dir = fs::canonicalize(&reference_repo.objects_dir)?;
+        let info_dir = repo.objects_dir.join("info");
+        fs::create_dir_all(&info_dir)?;
+        fs::write(
+            info_dir.join("alternates"),
+            format!("{}\n", objects_dir.display()),
+        )?;
+    }
+    let remote = if mirror {
+        let fetch = remote::RefSpec::parse("+refs/*:refs/*")?;
+        let remote = remote::Remote::add_with_fetch(&repo, "origin", &repo_url, Some(fetch))?;
+        let mut file = config::ConfigFile::open(&config::Config::local_path(&repo))?;
+        file.set("remote.origin.mirror", "true")?;
+        file.save()?;
+        remote
+    } else if bare {
+        // Branches are fetched as they are, but later fetches are not configured
+        let mut remote = remote::Remote::add_with_fetch(&repo, "origin", &repo_url, None)?;
+        remote.fetch = vec![remote::RefSpec::parse("+refs/heads/*:refs/heads/*")?];
+        remote
+    } else {
+        remote::Remote::add(&repo, "origin", &repo_url)?
+    };
+    let ref_info = match advertised {
+        Some((client, ref_info)) => remote.fetch_advertised(&repo, client, ref_info)?,
+        None => remote.fetch(&repo)?,
+    };
+
+    // Bundles need not have HEAD, there is nothing to check out then
+    let Some(head) = ref_info.refs.iter().find(|r| r.name == "HEAD") else {
+        eprintln!("warning: remote HEAD refers to nonexistent ref, unable to checkout");
+        return Ok(());
+    };
+    let id = head.id;
+    // Servers without symref capability don't tell us the branch name, guess it from the ids
+    let branch = ref_info
+        .capabilities
+        .iter()
+        .find_map(|c| c.strip_prefix("symref=HEAD:"))
+        .map(String::from)
+        .or_else(|| {
+            ref_info
+                .refs
+                .iter()
+                .find(|r| r.name.starts_with("refs/heads/") && r.id == head.id)
+                .map(|r| r.name.clone())
+        });
+    if bare {
+        if let Some(branch) = branch {
+            refs::set_symbolic(&repo, "HEAD", &branch, None)?;
+        }
+        return Ok(());
+    }
+    if let Some(branch) = branch {
+        // HEAD is set first so that its reflog gets the entry together with the branch
+        refs::set_symbolic(&repo, "HEAD", &branch, None)?;
+        refs::update(
+            &repo,
+            &branch,
+            None,
+            Some(&id),
+            &format!("clone: from {}", repo_url),
+        )?;
+        if let Some((tracking, _)) = remote.tracking_ref(&branch) {
+            refs::set_symbolic(&repo, "refs/remotes/origin/HEAD", &tracking, None)?;
+        }
+        let short = branch.strip_prefix("refs/heads/").unwrap_or(&branch);
+        let mut file = config::ConfigFile::open(&config::Config::local_path(&repo))?;
+        file.set(&format!("branch.{}.remote", short), &remote.name)?;
+        file.set(&format!("branch.{}.merge", short), &branch)?;
+        file.save()?;
+    }
+    repo.checkout(&id)?;
+    hooks::post_checkout(&repo, None, &id, true)?;
+    if recurse_submodules {
+        run_submodule_update(&repo, "", true, true)?;
+    }
+    Ok(())
+}
+
+/// Updates submodules of the repository, `prefix` is its path in the top superproject
+fn run_submodule_update(repo: &GitRepo, prefix: &str, init: bool, recursive: bool) -> Result<()> {
+    let recorded = submodule::recorded(repo)?;
+    for sub in submodule::list(repo)? {
+        let Some(id) = recorded.get(sub.path.as_bytes()) else {
+            continue;
+        };
+        let display_path = format!("{}{}", prefix, sub.path);
+        if init {
+            if let Some(url) = submodule::init(repo, &sub)? {
+                eprintln!(
+                    "Submodule '{}' ({}) registered for path '{}'",
+                    sub.name, url, display_path
+                );
+            }
+        }
+        // Submodules which are not registered are skipped
+        let config = config::Config::load(repo)?;
+        let Some(url) = config.get(&format!("submodule.{}.url", sub.name)) else {
+            continue;
+        };
+
+        let path = repo.worktree_path(sub.path.as_bytes());
+        let sub_repo = match submodule::open(repo, sub.path.as_bytes())? {
+            Some(sub_repo) => sub_repo,
+            None => {
+                eprintln!("Cloning into '{}'...", path.display());
+                if path.is_dir() {
+                    // Empty directory left by checkout of the superproject
+                    fs::remove_dir(&path)
+                        .with_context(|| format!("Directory '{}' is not empty", display_path))?;
+                }
+                run_clone(url.to_string(), path.clone(), false, false, None, false)?;
+                GitRepo::new(&path).with_progress(stderr_progress())
+            }
+        };
+        let head = refs::resolve(&sub_repo, "HEAD")?;
+        if head.as_ref() != Some(id) {
+            if !object::Object::exists(&sub_repo, id) {
+                remote::Remote::load(&sub_repo, "origin")?.fetch(&sub_repo)?;
+            }
+            ensure!(
+                object::Object::exists(&sub_repo, id),
+                "Unable to find current revision {} in submodule path '{}'",
+                id,
+                display_path
+            );
+            let current = match &head {
+                Some(head) => {
+                    let tree = commit::Commit::read(&sub_repo, head)?.tree_sha;
+                    tree::Tree::flatten(&sub_repo, &tree)?
+                }
+                None => BTreeMap::new(),
+            };
+            let tree = commit::Commit::read(&sub_repo, id)?.tree_sha;
+            let target = tree::Tree::flatten(&sub_repo, &tree)?;
+            merge::reset_worktree(&sub_repo, &current, &target)?;
+            let message = reflog::checkout_message(&sub_repo, &id.to_hex())?;
+            refs::detach_head(&sub_repo, id, &message)?;
+            println!("Submodule path '{}': checked out '{}'", display_path, id);
+        }
+        if recursive {
+            run_submodule_update(&sub_repo, &format!("{}/", display_path), init, true)?;
+        }
+    }
+    Ok(())
+}
+
+fn run_bundle(command: BundleCommand) -> Result<()> {
+    match command {
+        BundleCommand::Create { file, revs } => {
+            let repo = repo_in_cwd()?;
+            bundle::create(&repo, &file, &revs)?;
+        }
+        BundleCommand::Verify { file } => {
+            let repo = repo_in_cwd()?;
+            bundle::Bundle::open(&file)?.verify(&repo)?;
+            eprintln!("{} is okay", file.display());
+        }
+        BundleCommand::ListHeads { file } => {
+            for (name, id) in bundle::Bundle::open(&file)?.refs {
+                println!("{} {}", id, name);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_reflog(command: ReflogCommand) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    match command {
+        ReflogCommand::Show { reference } => {
+            let name = match reference.as_str() {
+                "HEAD" | "@" => String::from("HEAD"),
+                _ => refs::dwim(&repo, &reference)?.ok_or(anyhow!(
+                    "ambiguous argument '{}': unknown revision",
+                    reference
+                ))?,
+            };
+            let entries = reflog::read(&repo, &name)?;
+            for (i, entry) in entries.iter().rev().enumerate() {
+                println!(
+                    "{} {}@{{{}}}: {}",
+                    object::Object::abbreviate(&repo, &entry.new, 7)?,
+                    reference,
+                    i,
+                    entry.message
+                );
+            }
+        }
+        ReflogCommand::Exists { reference } => {
+            if !reflog::exists(&repo, &reference) {
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_index_pack(
+    stdin: bool,
+    fix_thin: bool,
+    output: Option<PathBuf>,
+    strict: bool,
+    pack_file: Option<PathBuf>,
+) -> Result<()> {
+    use std::io::Read;
+
+    let repo = repo_in_cwd()?;
+    if stdin {
+        let mut data = Vec::new();
+        std::io::stdin().read_to_end(&mut data)?;
+        let built = pack::index::build_index(&repo, &data, strict, fix_thin)?;
+        let checksum = pack::index::store(&repo, built)?.checksum;
+        println!("pack\t{}", checksum);
+    } else {
+        let pack_file = pack_file.ok_or(anyhow!("pack file is required"))?;
+        let checksum = pack::index::index_pack_file(&repo, &pack_file, output.as_deref(), strict)?;
+        println!("{}", checksum);
+    }
+    Ok(())
+}
+
+fn run_pack_objects(
+    stdout: bool,
+    revs: bool,
+    all: bool,
+    thin: bool,
+    window: Option<usize>,
+    depth: Option<usize>,
+    base_name: Option<PathBuf>,
+) -> Result<()> {
+    use std::io::{Read, Write};
+
+    let repo = repo_in_cwd()?;
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input)?;
+    let objects = if revs {
+        let mut wants = Vec::new();
+        let mut haves = Vec::new();
+        if all {
+            wants.extend(refs::list(&repo)?.into_iter().map(|(_, id)| id));
+        }
+        let mut not = false;
+        for line in input.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            if line == "--not" {
+                not = !not;
+                continue;
+            }
+            let (rev, negative) = match line.strip_prefix('^') {
+                Some(rev) => (rev, !not),
+                None => (line, not),
+            };
+            let id = revparse::resolve(&repo, rev)?;
+            if negative {
+                haves.push(id);
+            } else {
+                wants.push(id);
+            }
+        }
+        pack::objects_to_send(&repo, &wants, &haves)?
+            .iter()
+            .map(|id| object::Object::read(&repo, id))
+            .collect::<error::Result<Vec<_>>>()?
+    } else {
+        let mut seen = HashSet::new();
+        let mut objects = Vec::new();
+        for line in input.lines().filter(|line| !line.is_empty()) {
+            let id: oid::ObjectId = line.split(' ').next().unwrap_or_default().parse()?;
+            if seen.insert(id) {
+                objects.push(object::Object::read(&repo, &id)?);
+            }
+        }
+        objects
+    };
+    ensure!(!thin || revs, "--thin requires --revs");
+    let bases = if thin {
+        pack::thin_bases(&repo, &objects)?
+    } else {
+        Vec::new()
+    };
+
+    let config = config::Config::load(&repo)?;
+    let window = match window {
+        Some(window) => window,
+        None => usize::try_from(config.get_int("pack.window")?.unwrap_or(10))?,
+    };
+    let depth = match depth {
+        Some(depth) => depth,
+        None => usize::try_from(config.get_int("pack.depth")?.unwrap_or(50))?,
+    };
+    let level = repo.write_options()?.pack_compression;
+    let algo = repo.hash_algo();
+    let data = pack::write_thin_pack(&objects, &bases, level, window, depth, algo)?;
+    if stdout {
+        let mut out = std::io::stdout().lock();
+        out.write_all(&data)?;
+        out.flush()?;
+        return Ok(());
+    }
+    let built = pack::index::build_index(&repo, &data, false, false)?;
+    let stored = match base_name {
+        Some(base_name) => pack::index::store_as(&repo, built, &base_name)?,
+        None => pack::index::store(&repo, built)?,
+    };
+    println!("{}", stored.checksum);
+    Ok(())
+}
+
+fn run_verify_pack(verbose: bool, stat_only: bool, packs: Vec<PathBuf>) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let mut failed = false;
+    for path in packs {
+        let pack_path = path.with_extension("pack");
+        let entries = match pack::index::verify_pack(&repo, &path.with_extension("idx")) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("error: {:#}", e);
+                if verbose {
+                    println!("{}: bad", pack_path.display());
+                }
+                failed = true;
+                continue;
+            }
+        };
+        if verbose && !stat_only {
+            for entry in &entries {
+                let kind = entry.kind.as_str();
+                let sizes = format!("{} {} {}", entry.size, entry.packed_size, entry.offset);
+                match entry.delta {
+                    Some((depth, base)) => {
+                        println!("{} {:<6} {} {} {}", entry.id, kind, sizes, depth, base)
+                    }
+                    None => println!("{} {:<6} {}", entry.id, kind, sizes),
+                }
+            }
+        }
+        if verbose || stat_only {
+            let mut chains: BTreeMap<usize, usize> = BTreeMap::new();
+            for entry in &entries {
+                let depth = entry.delta.map_or(0, |(depth, _)| depth);
+                *chains.entry(depth).or_default() += 1;
+            }
+            for (depth, count) in chains {
+                let objects = if count == 1 { "object" } else { "objects" };
+                match depth {
+                    0 => println!("non delta: {} {}", count, objects),
+                    _ => println!("chain length = {}: {} {}", depth, count, objects),
+                }
+            }
+        }
+        if verbose {
+            println!("{}: ok", pack_path.display());
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_unpack_objects(dry_run: bool, strict: bool) -> Result<()> {
+    use std::io::Read;
+
+    let repo = repo_in_cwd()?;
+    let mut data = Vec::new();
+    std::io::stdin().read_to_end(&mut data)?;
+    if dry_run {
+        pack::read_objects(&repo, &data, strict)?;
+    } else {
+        pack::unpack(&repo, &data, strict)?;
+    }
+    Ok(())
+}
+
+fn run_fsck(unreachable: bool, no_dangling: bool) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let report = fsck::check(&repo)?;
+    for message in report.warnings.iter().chain(&report.errors) {
+        eprintln!("{}", message);
+    }
+    for (id, kind) in &report.missing {
+        println!("missing {} {}", kind.as_str(), id);
+    }
+    let (label, objects) = if unreachable {
+        ("unreachable", &report.unreachable)
+    } else {
+        ("dangling", &report.dangling)
+    };
+    if unreachable || !no_dangling {
+        for (id, kind) in objects {
+            println!("{} {} {}", label, kind.as_str(), id);
+        }
+    }
+    if refs::resolve(&repo, "HEAD")?.is_none() {
+        eprintln!("notice: HEAD points to an unborn branch");
+    }
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_prune(dry_run: bool, verbose: bool, expire: Option<&str>) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let expire = match expire {
+        Some(expire) => {
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+            // `never` keeps everything, which is the same as expiring nothing
+            match date::parse_expiry(expire, now.as_secs() as i64)? {
+                Some(time) => Some(time),
+                None => return Ok(()),
+            }
+        }
+        None => None,
+    };
+    for (id, kind) in gc::prune(&repo, expire, dry_run)? {
+        if dry_run || verbose {
+            println!("{} {}", id, kind.as_str());
+        }
+    }
+    Ok(())
+}
+
+fn run_count_objects(verbose: bool) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let counts = gc::count_objects(&repo)?;
+    if !verbose {
+        println!(
+            "{} objects, {} kilobytes",
+            counts.loose,
+            counts.loose_size / 1024
+        );
+        return Ok(());
+    }
+    println!("count: {}", counts.loose);
+    println!("size: {}", counts.loose_size / 1024);
+    println!("in-pack: {}", counts.in_pack);
+    println!("packs: {}", counts.packs);
+    println!("size-pack: {}", counts.pack_size / 1024);
+    println!("prune-packable: {}", counts.prune_packable);
+    println!("garbage: {}", counts.garbage);
+    println!("size-garbage: {}", counts.garbage_size / 1024);
+    Ok(())
+}
+
+fn run_worktree(command: WorktreeCommand) -> Result<()> {
+    use worktree::Checkout;
+
+    let repo = repo_in_cwd()?;
+    match command {
+        WorktreeCommand::Add {
+            new_branch,
+            detach,
+            path,
+            commit,
+        } => {
+            let checkout = Checkout::resolve(&repo, &path, new_branch, detach, commit.as_deref())?;
+            match &checkout {
+                Checkout::Branch(branch) => eprintln!(
+                    "Preparing worktree (checking out '{}')",
+                    branch.strip_prefix("refs/heads/").unwrap_or(branch)
+                ),
+                Checkout::NewBranch(branch, _) => {
+                    eprintln!("Preparing worktree (new branch '{}')", branch)
+                }
+                Checkout::Detached(id) => eprintln!(
+                    "Preparing worktree (detached HEAD {})",
+                    object::Object::abbreviate(&repo, id, 7)?
+                ),
+            }
+            let worktree = worktree::add(&repo, &path, &checkout)?;
+            let id = refs::resolve(&worktree, "HEAD")?.ok_or(anyhow!("HEAD is unborn"))?;
+            hooks::post_checkout(&worktree, None, &id, true)?;
+            let commit = commit::Commit::read(&repo, &id)?;
+            println!(
+                "HEAD is now at {} {}",
+                object::Object::abbreviate(&repo, &id, 7)?,
+                sequencer::subject(&commit)
+            );
+        }
+        WorktreeCommand::List { porcelain } => {
+            let worktrees = worktree::list(&repo)?;
+            let width = worktrees.iter().map(|w| w.path.as_os_str().len()).max();
+            for worktree in worktrees {
+                if worktree.bare && porcelain {
+                    println!("worktree {}\nbare\n", worktree.path.display());
+                    continue;
+                } else if worktree.bare {
+                    println!(
+                        "{:<width$} (bare)",
+                        worktree.path.display().to_string(),
+                        width = width.unwrap_or_default() + 1
+                    );
+                    continue;
+                }
+                // Unborn branch shows null id
+                let head = worktree.head.unwrap_or(repo.hash_algo().null_id());
+                if porcelain {
+                    println!("worktree {}", worktree.path.display());
+                    println!("HEAD {}", head);
+                    match &worktree.branch {
+                        Some(branch) => println!("branch {}", branch),
+                        None => println!("detached"),
+                    }
+                    println!();
+                    continue;
+                }
+                let branch = match &worktree.branch {
+                    Some(branch) => {
+                        format!("[{}]", branch.strip_prefix("refs/heads/").unwrap_or(branch))
+                    }
+                    None => String::from("(detached HEAD)"),
+                };
+                let short = match worktree.head {
+                    Some(_) => object::Object::abbreviate(&repo, &head, 7)?,
+                    None => head.to_hex()[..7].to_string(),
+                };
+                println!(
+                    "{:<width$} {} {}",
+                    worktree.path.display().to_string(),
+                    short,
+                    branch,
+                    width = width.unwrap_or_default() + 1
+                );
+            }
+        }
+        WorktreeCommand::Remove { force, path } => worktree::remove(&repo, &path, force)?,
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct RevListArgs {
+    /// Print only the number of listed commits
+    #[arg(long)]
+    count: bool,
+    /// List at most this many commits
+    #[arg(short = 'n', long)]
+    max_count: Option<usize>,
+    /// Also list trees and blobs of the commits which are not reachable from excluded
+    /// commits, with their paths
+    #[arg(long)]
+    objects: bool,
+    #[arg(long, conflicts_with = "date_order")]
+    topo_order: bool,
+    #[arg(long)]
+    date_order: bool,
+    #[arg(long)]
+    reverse: bool,
+    /// Commits to start from, `^rev` excludes commits reachable from `rev`, `A..B` lists
+    /// those reachable from `B` but not from `A`
+    #[arg(required = true, allow_hyphen_values = true)]
+    revs: Vec<String>,
+}
+
+fn run_rev_list(args: RevListArgs) -> Result<()> {
+    use std::io::Write;
+
+    let repo = repo_in_cwd()?;
+    let mut walk = revwalk::RevWalk::new(&repo);
+    walk.sort(if args.topo_order {
+        revwalk::Sort::Topo
+    } else if args.date_order {
+        revwalk::Sort::Date
+    } else {
+        revwalk::Sort::Chronological
+    });
+    for rev in &args.revs {
+        match rev.strip_prefix('^') {
+            Some(excluded) => walk.hide(&revparse::resolve(&repo, excluded)?)?,
+            None => revparse::parse(&repo, rev)?.push_to(&mut walk)?,
+        }
+    }
+    let limit = args.max_count.unwrap_or(usize::MAX);
+    // Objects are listed relative to the excluded commits, found only by the whole walk
+    let mut commits = if args.objects {
+        walk.collect::<Result<Vec<_>, _>>()?
+    } else {
+        walk.take(limit).collect::<Result<Vec<_>, _>>()?
+    };
+    let walked: HashSet<oid::ObjectId> = commits.iter().map(|(id, _)| *id).collect();
+    commits.truncate(limit);
+    if args.reverse {
+        commits.reverse();
+    }
+    if args.count {
+        println!("{}", commits.len());
+        return Ok(());
+    }
+    for (id, _) in &commits {
+        println!("{}", id);
+    }
+    if args.objects {
+        // Trees of excluded commits the listed ones build on have the objects which are
+        // not new in the listed commits
+        let mut excluded = Vec::new();
+        for (_, commit) in &commits {
+            for parent in &commit.parents {
+                if !walked.contains(parent) {
+                    excluded.push(commit::Commit::read(&repo, parent)?.tree_sha);
+                }
+            }
+        }
+        let trees: Vec<oid::ObjectId> = commits.iter().map(|(_, c)| c.tree_sha).collect();
+        let mut out = std::io::stdout().lock();
+        for (id, path) in revwalk::tree_objects(&repo, &trees, &excluded)? {
+            write!(out, "{} ", id)?;
+            out.write_all(&path)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct LogArgs {
+    #[arg(long, conflicts_with = "date_order")]
+    topo_order: bool,
+    #[arg(long)]
+    date_order: bool,
+    #[arg(long)]
+    reverse: bool,
+    /// Continue listing history of the file beyond renames
+    #[arg(long)]
+    follow: bool,
+    /// Draw the history as graph of lanes left of the commits
+    #[arg(long, conflicts_with = "reverse")]
+    graph: bool,
+    /// Color the graph lanes: `always`, `never`, or `auto` when writing to terminal
+    #[arg(
+        long,
+        value_name = "WHEN",
+        value_parser = ["always", "never", "auto"],
+        num_args = 0..=1,
+        require_equals = true,
+        default_value = "auto",
+        default_missing_value = "always"
+    )]
+    color: String,
+    /// Show each commit as abbreviated id and subject
+    #[arg(long, conflicts_with = "pretty")]
+    oneline: bool,
+    /// `oneline`, `short`, `medium`, `full`, `fuller`, `format:<format>` or
+    /// `tformat:<format>` with placeholders like `%H`, `%h`, `%an`, `%s` and `%d`
+    #[arg(long, visible_alias = "format")]
+    pretty: Option<pretty::Pretty>,
+    /// Show at most this many commits
+    #[arg(short = 'n', long)]
+    max_count: Option<usize>,
+    /// Show only commits committed at the date or later, e.g. `2.weeks.ago`
+    #[arg(long, visible_alias = "after")]
+    since: Option<String>,
+    /// Show only commits committed at the date or earlier
+    #[arg(long, visible_alias = "before")]
+    until: Option<String>,
+    /// Show only commits with author name or email matching the pattern
+    #[arg(long)]
+    author: Option<String>,
+    /// Show identities canonicalized by `.mailmap`, the default unless `log.mailmap`
+    /// is false
+    #[arg(long, visible_alias = "mailmap")]
+    use_mailmap: bool,
+    #[arg(long, visible_alias = "no-mailmap", conflicts_with = "use_mailmap")]
+    no_use_mailmap: bool,
+    /// Revision to start from, `HEAD` by default
+    rev: Option<String>,
+    /// Paths given without `--`, they have to exist in the working tree
+    #[arg(value_name = "PATH")]
+    files: Vec<String>,
+    /// Show only commits changing any of the paths
+    #[arg(last = true)]
+    paths: Vec<String>,
+}
+
+/// Splits revision and paths of log given without `--`, like git: the first argument
+/// which is not a revision starts the paths, every one of them has to exist
+fn log_rev_and_paths(repo: &GitRepo, args: &mut LogArgs) -> Result<String> {
+    let mut files = std::mem::take(&mut args.files);
+    let rev = match args.rev.take() {
+        Some(rev) if revparse::parse(repo, &rev).is_ok() => rev,
+        Some(path) => {
+            files.insert(0, path);
+            String::from("HEAD")
+        }
+        None => String::from("HEAD"),
+    };
+    for path in &files {
+        ensure!(
+            repo.worktree_path(path.as_bytes())
+                .symlink_metadata()
+                .is_ok(),
+            "ambiguous argument '{}': unknown revision or path not in the working tree",
+            path
+        );
+    }
+    files.append(&mut args.paths);
+    args.paths = files;
+    Ok(rev)
+}
+
+fn run_log(mut args: LogArgs) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let rev = log_rev_and_paths(&repo, &mut args)?;
+    ensure!(
+        !args.follow || args.paths.len() == 1,
+        "--follow requires exactly one pathspec"
+    );
+    let mut walk = revwalk::RevWalk::new(&repo);
+    // Graph is drawn for topologically sorted commits connected by shown commits
+    walk.sort(if args.date_order {
+        revwalk::Sort::Date
+    } else if args.topo_order || args.graph {
+        revwalk::Sort::Topo
+    } else {
+        revwalk::Sort::Chronological
+    });
+    walk.paths(args.paths.into_iter().map(String::into_bytes).collect());
+    walk.follow(args.follow);
+    walk.rewrite_parents(args.graph);
+    revparse::parse(&repo, &rev)?.push_to(&mut walk)?;
+
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    let now = now.as_secs() as i64;
+    let parse_date = |date: Option<String>| date.map(|d| date::parse_relative(&d, now));
+    let since = parse_date(args.since).transpose()?;
+    let until = parse_date(args.until).transpose()?;
+    let author = args
+        .author
+        .map(|pattern| grep::Pattern::new(&pattern, false))
+        .transpose()?;
+    let pretty = match args.pretty {
+        Some(pretty) => pretty,
+        None if args.oneline => pretty::Pretty::Oneline,
+        None => pretty::Pretty::Medium,
+    };
+    let decorations = pretty::Decorations::load(&repo)?;
+    let use_mailmap = match (args.use_mailmap, args.no_use_mailmap) {
+        (false, false) => None,
+        (use_mailmap, _) => Some(use_mailmap),
+    };
+    let mailmap = log_mailmap(&repo, use_mailmap)?;
+
+    let mut shown = 0;
+    let mut show = |id: &oid::ObjectId, commit: &commit::Commit| -> Result<()> {
+        if shown > 0 {
+            print!("{}", pretty.separator());
+        }
+        shown += 1;
+        let formatted = pretty.format(&repo, id, commit, &decorations, &mailmap)?;
+        print!("{}", formatted);
+        Ok(())
+    };
+    let limit = args.max_count.unwrap_or(usize::MAX);
+    let mut selected = Vec::new();
+    let mut count = 0;
+    for item in walk {
+        let (id, commit) = item?;
+        let time = commit.committer.unix_time();
+        if since.is_some_and(|since| time < since) || until.is_some_and(|until| time > until) {
+            continue;
+        }
+        if let Some(author) = &author {
+            let (name, email) = mailmap.map(&commit.author.name, &commit.author.email);
+            let identity = format!("{} <{}>", name, email);
+            if !author.is_match(identity.as_bytes()) {
+                continue;
+            }
+        }
+        // Graph needs all shown commits to know lanes of which parents continue
+        if count == limit && !args.graph {
+            break;
+        }
+        count += 1;
+        // The limit applies before reversing
+        if args.reverse || args.graph {
+            selected.push((id, commit));
+        } else {
+            show(&id, &commit)?;
+        }
+    }
+    if args.graph {
+        let colors = match args.color.as_str() {
+            "auto" => std::io::IsTerminal::is_terminal(&std::io::stdout()),
+            color => color == "always",
+        };
+        return print_log_graph(
+            &repo,
+            &pretty,
+            &decorations,
+            &mailmap,
+            &selected,
+            limit,
+            colors,
+        );
+    }
+    for (id, commit) in selected.iter().rev() {
+        show(id, commit)?;
+    }
+    if count > 0 {
+        print!("{}", pretty.terminator());
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct ShortlogArgs {
+    /// Show only the number of commits of each author
+    #[arg(short, long)]
+    summary: bool,
+    /// Sort authors by the number of their commits instead of by name
+    #[arg(short, long)]
+    numbered: bool,
+    /// Show also email of the authors
+    #[arg(short, long)]
+    email: bool,
+    #[arg(default_value = "HEAD")]
+    rev: String,
+}
+
+fn run_shortlog(args: ShortlogArgs) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let mailmap = mailmap::Mailmap::load(&repo)?;
+    let mut walk = revwalk::RevWalk::new(&repo);
+    revparse::parse(&repo, &args.rev)?.push_to(&mut walk)?;
+    let mut authors: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for item in walk {
+        let (_, commit) = item?;
+        let author = mailmap.map_author(&commit.author);
+        let author = if args.email {
+            format!("{} <{}>", author.name, author.email)
+        } else {
+            author.name
+        };
+        let (mut subject, _) = mail::split_message(&commit.message);
+        if subject.is_empty() {
+            subject = "<none>".to_string();
+        }
+        authors.entry(author).or_default().push(subject);
+    }
+    let mut authors: Vec<_> = authors.into_iter().collect();
+    if args.numbered {
+        authors.sort_by_key(|(_, subjects)| std::cmp::Reverse(subjects.len()));
+    }
+    for (author, subjects) in authors {
+        if args.summary {
+            println!("{:6}\t{}", subjects.len(), author);
+            continue;
+        }
+        println!("{} ({}):", author, subjects.len());
+        // Oldest commits first
+        for subject in subjects.iter().rev() {
+            println!("      {}", subject);
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Shows at most `limit` of the commits with the graph left of them. Parents which are
+/// not among the commits get no lanes.
+fn print_log_graph(
+    repo: &GitRepo,
+    pretty: &pretty::Pretty,
+    decorations: &pretty::Decorations,
+    mailmap: &mailmap::Mailmap,
+    commits: &[(oid::ObjectId, commit::Commit)],
+    limit: usize,
+    colors: bool,
+) -> Result<()> {
+    let shown: HashSet<&oid::ObjectId> = commits.iter().map(|(id, _)| id).collect();
+    let mut graph = graph::Graph::new(colors);
+    let mut missing_newline = false;
+    for (i, (id, commit)) in commits.iter().take(limit).enumerate() {
+        let parents = commit.parents.iter().filter(|p| shown.contains(p));
+        graph.update(id, parents.cloned().collect());
+        let mut out = String::new();
+        // Separating line continues the lanes unless the previous entry ended mid-line
+        if i > 0 && !pretty.is_terminated() {
+            if !missing_newline {
+                out.push_str(&graph.padding());
+            }
+            out.push('\n');
+        }
+        out.push_str(&graph.commit_lines());
+        let text = pretty.format(repo, id, commit, decorations, mailmap)?;
+        let message = match (pretty.has_header(), text.split_once('\n')) {
+            (true, Some((header, rest))) => {
+                out.push_str(header);
+                out.push('\n');
+                out.push_str(&graph.next_line().0);
+                format!("{}\n", rest)
+            }
+            _ => text,
+        };
+        missing_newline = !message.ends_with('\n');
+        out.push_str(&graph.message(&message));
+        if pretty.is_terminated() {
+            if !missing_newline {
+                out.push_str(&graph.padding());
+            }
+            out.push('\n');
+        }
+        print!("{}", out);
+    }
+    Ok(())
+}
+
+/// Commit header and indented message in the default `log` format
+fn print_commit(repo: &GitRepo, id: &oid::ObjectId, commit: &commit::Commit) -> Result<()> {
+    let mailmap = log_mailmap(repo, None)?;
+    let decorations = Default::default();
+    let formatted = pretty::Pretty::Medium.format(repo, id, commit, &decorations, &mailmap)?;
+    println!("{}", formatted);
+    Ok(())
+}
+
+/// Mailmap for identities shown by `log` and `show`, empty when disabled by the
+/// argument or by `log.mailmap`
+fn log_mailmap(repo: &GitRepo, use_mailmap: Option<bool>) -> Result<mailmap::Mailmap> {
+    let use_mailmap = match use_mailmap {
+        Some(use_mailmap) => use_mailmap,
+        None => {
+            let config = config::Config::load(repo)?;
+            config.get_bool("log.mailmap")?.unwrap_or(true)
+        }
+    };
+    if use_mailmap {
+        Ok(mailmap::Mailmap::load(repo)?)
+    } else {
+        Ok(Default::default())
+    }
+}
+
+#[derive(Args)]
+struct DescribeArgs {
+    /// Use also lightweight tags
+    #[arg(long)]
+    tags: bool,
+    /// Show the number of commits and abbreviated id even for tagged commits
+    #[arg(long)]
+    long: bool,
+    /// Show abbreviated id of commits no tag describes
+    #[arg(long)]
+    always: bool,
+    /// Minimal length of abbreviated ids
+    #[arg(long, default_value = "7")]
+    abbrev: usize,
+    /// Append the mark when the working tree has local changes
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "-dirty",
+        conflicts_with = "commits"
+    )]
+    dirty: Option<String>,
+    /// Commits to describe, HEAD by default
+    commits: Vec<String>,
+}
+
+fn run_describe(args: DescribeArgs) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let options = describe::DescribeOptions {
+        tags: args.tags,
+        long: args.long,
+        abbrev: args.abbrev,
+        always: args.always,
+    };
+    if args.commits.is_empty() {
+        let head = revparse::resolve(&repo, "HEAD")?;
+        let mut name = describe::describe(&repo, &head, &options)?;
+        if let Some(mark) = args.dirty {
+            if describe::is_dirty(&repo)? {
+                name.push_str(&mark);
+            }
+        }
+        println!("{}", name);
+    }
+    for commit in args.commits {
+        let id = revparse::resolve(&repo, &commit)?;
+        println!("{}", describe::describe(&repo, &id, &options)?);
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct TagArgs {
+    /// Create annotated tag object
+    #[arg(short, long)]
+    annotate: bool,
+    /// Create signed annotated tag, with `user.signingKey`
+    #[arg(short, long)]
+    sign: bool,
+    /// Create signed annotated tag with the key
+    #[arg(short = 'u', long, value_name = "KEY")]
+    local_user: Option<String>,
+    /// Message of annotated tag, implies `--annotate`
+    #[arg(short, long)]
+    message: Option<String>,
+    /// Replace existing tag
+    #[arg(short, long)]
+    force: bool,
+    /// Delete the tags
+    #[arg(short, long, conflicts_with_all = ["annotate", "sign", "local_user", "message", "force"])]
+    delete: bool,
+    /// Tag to create, optionally followed by the object, or tags to delete. All tags
+    /// are listed without it.
+    names: Vec<String>,
+}
+
+fn run_tag(args: TagArgs) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    if args.delete {
+        for name in &args.names {
+            let ref_name = format!("refs/tags/{}", name);
+            let Some(old) = refs::resolve(&repo, &ref_name)? else {
+                anyhow::bail!("tag '{}' not found.", name);
+            };
+            refs::update(&repo, &ref_name, Some(&old), None, "")?;
+            let abbrev = object::Object::abbreviate(&repo, &old, 7)?;
+            println!("Deleted tag '{}' (was {})", name, abbrev);
+        }
+        return Ok(());
+    }
+    let (name, target) = match args.names.as_slice() {
+        [] => {
+            for (name, _) in refs::list(&repo)? {
+                if let Some(tag) = name.strip_prefix("refs/tags/") {
+                    println!("{}", tag);
+                }
+            }
+            return Ok(());
+        }
+        [name] => (name, "HEAD"),
+        [name, target] => (name, target.as_str()),
+        _ => anyhow::bail!("too many arguments"),
+    };
+    let ref_name = format!("refs/tags/{}", name);
+    let old = refs::resolve(&repo, &ref_name)?;
+    ensure!(old.is_none() || args.force, "tag '{}' already exists", name);
+    let mut id = revparse::resolve(&repo, target)?;
+    let sign = args.sign || args.local_user.is_some();
+    if args.annotate || sign || args.message.is_some() {
+        let Some(message) = args.message else {
+            anyhow::bail!("no tag message given, use -m");
+        };
+        let kind = object::Object::read(&repo, &id)?.header.kind;
+        let message = format!("{}\n", message.trim_end());
+        let mut tag = tag::Tag::new(&repo, id, kind, name.clone(), message)?;
+        if sign {
+            let key = args.local_user.as_deref();
+            let signature = signature::sign(&repo, &tag.to_bytes(), key)?;
+            tag.message.push_str(&signature);
+        }
+        id = tag.write(&repo)?;
+    }
+    refs::update(&repo, &ref_name, old.as_ref(), Some(&id), "")?;
+    if let Some(old) = old.filter(|old| *old != id) {
+        let abbrev = object::Object::abbreviate(&repo, &old, 7)?;
+        println!("Updated tag '{}' (was {})", name, abbrev);
+    }
+    Ok(())
+}
+
+/// Prints report of the signature check, returns whether the signature is good
+fn report_verification(repo: &GitRepo, payload: &[u8], signature: &str) -> Result<bool> {
+    let verification = signature::verify(repo, payload, signature)?;
+    eprint!("{}", verification.output);
+    Ok(verification.good)
+}
+
+fn run_verify_commit(commits: Vec<String>) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let mut good = true;
+    for rev in commits {
+        let id = revparse::resolve(&repo, &rev)?;
+        let commit = commit::Commit::read(&repo, &id)?;
+        match signature::commit_signature(&repo, &commit) {
+            Some((payload, sig)) => good &= report_verification(&repo, &payload, &sig)?,
+            None => good = false,
+        }
+    }
+    if !good {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_verify_tag(tags: Vec<String>) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    let mut good = true;
+    for rev in tags {
+        let id = revparse::resolve(&repo, &rev)?;
+        let obj = object::Object::read(&repo, &id)?;
+        let kind = obj.header.kind;
+        ensure!(
+            kind == object::ObjectKind::Tag,
+            "{}: cannot verify a non-tag object of type {}.",
+            rev,
+            kind.as_str()
+        );
+        match signature::tag_signature(&obj.data) {
+            Some((payload, sig)) => good &= report_verification(&repo, payload, sig)?,
+            None => {
+                eprintln!("error: no signature found");
+                good = false;
+            }
+        }
+    }
+    if !good {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_show(renames: RenameArgs, rev: String) -> Result<()> {
+    use std::io::Write;
+
+    let repo = repo_in_cwd()?;
+    let renames = renames.resolve(&repo)?;
+    let mut id = revparse::resolve(&repo, &rev)?;
+    loop {
+        let obj = object::Object::read(&repo, &id)?;
+        match obj.header.kind {
+            object::ObjectKind::Tag => {
+                let tag = tag::Tag::try_from(obj)?;
+                println!("tag {}", tag.name);
+                if let Some(tagger) = &tag.tagger {
+                    println!("Tagger: {} <{}>", tagger.name, tagger.email);
+                    println!("Date:   {}", tagger.format_date());
+                }
+                println!();
+                println!("{}", tag.message.trim_end());
+                println!();
+                id = tag.object;
+            }
+            object::ObjectKind::Commit => {
+                let commit = commit::Commit::try_from(obj)?;
+                print_commit(&repo, &id, &commit)?;
+                // Merges would need combined diff, show them without patch
+                if commit.parents.len() > 1 {
+                    println!();
+                    return Ok(());
+                }
+                let old = match commit.parents.first() {
+                    Some(parent) => {
+                        let parent = commit::Commit::read(&repo, parent)?;
+                        tree::Tree::flatten(&repo, &parent.tree_sha)?
+                    }
+                    None => Default::default(),
+                };
+                let new = tree::Tree::flatten(&repo, &commit.tree_sha)?;
+                let mut changes = diff::changes(&old, &new);
+                if let Some(renames) = &renames {
+                    changes = diff::detect_renames(&repo, changes, renames, false)?;
+                }
+                if !changes.is_empty() {
+                    println!();
+                }
+                let mut out = Vec::new();
+                diff::write_changes(&repo, &mut out, &changes, 3, false)?;
+                std::io::stdout().lock().write_all(&out)?;
+                return Ok(());
+            }
+            object::ObjectKind::Tree => {
+                println!("tree {}", rev);
+                println!();
+                let mut out = std::io::stdout().lock();
+                for entry in tree::Tree::try_from(obj)?.entries {
+                    let is_tree = entry.mode == tree::FileMode::Tree;
+                    let suffix = if is_tree { "/" } else { "" };
+                    out.write_all(&entry.name)?;
+                    writeln!(out, "{}", suffix)?;
+                }
+                return Ok(());
+            }
+            object::ObjectKind::Blob => return Ok(obj.print_pretty()?),
+        }
+    }
+}
+
+/// Default merge commit message, e.g. `Merge branch 'topic' into dev`
+fn merge_message(repo: &GitRepo, name: &str) -> Result<String> {
+    let full = refs::dwim(repo, name)?.unwrap_or_default();
+    let mut message = if let Some(branch) = full.strip_prefix("refs/heads/") {
+        format!("Merge branch '{}'", branch)
+    } else if let Some(branch) = full.strip_prefix("refs/remotes/") {
+        format!("Merge remote-tracking branch '{}'", branch)
+    } else if let Some(tag) = full.strip_prefix("refs/tags/") {
+        format!("Merge tag '{}'", tag)
+    } else {
+        format!("Merge commit '{}'", name)
+    };
+    let head = refs::head_ref(repo)?;
+    if let Some(current) = head.strip_prefix("refs/heads/") {
+        if current != "master" && current != "main" {
+            message.push_str(&format!(" into {}", current));
+        }
+    }
+    message.push('\n');
+    Ok(message)
+}
+
+fn run_merge(branch: String, ff: merge::FastForward) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    ensure!(
+        !repo.git_dir.join("MERGE_HEAD").exists(),
+        "You have not concluded your merge (MERGE_HEAD exists)."
+    );
+    let head = refs::resolve(&repo, "HEAD")?.ok_or(anyhow!("Cannot merge into unborn branch"))?;
+    let theirs = revwalk::peel_to_commit(&repo, &revparse::resolve(&repo, &branch)?)?;
+    if revwalk::is_ancestor(&repo, &theirs, &head)? {
+        println!("Already up to date.");
+        return Ok(());
+    }
+    let can_fast_forward = revwalk::is_ancestor(&repo, &head, &theirs)?;
+    ensure!(
+        can_fast_forward || ff != merge::FastForward::Only,
+        "Not possible to fast-forward, aborting."
+    );
+    if can_fast_forward && ff != merge::FastForward::Never {
+        let ours_tree = commit::Commit::read(&repo, &head)?.tree_sha;
+        let theirs_tree = commit::Commit::read(&repo, &theirs)?.tree_sha;
+        let target = merge::TreeMerge {
+            entries: tree::Tree::flatten(&repo, &theirs_tree)?,
+            conflicts: Vec::new(),
+        };
+        merge::update_worktree(&repo, &tree::Tree::flatten(&repo, &ours_tree)?, &target)?;
+        std::fs::write(repo.git_dir.join("ORIG_HEAD"), format!("{}\n", head))?;
+        let message = format!("merge {}: Fast-forward", branch);
+        refs::update(
+            &repo,
+            &refs::head_ref(&repo)?,
+            Some(&head),
+            Some(&theirs),
+            &message,
+        )?;
+        println!(
+            "Updating {}..{}",
+            &head.to_hex()[..7],
+            &theirs.to_hex()[..7]
+        );
+        println!("Fast-forward");
+        return Ok(());
+    }
+    // Criss-cross merges have several bases, we simply use the first one
+    let base = revwalk::merge_bases(&repo, &head, &theirs)?
+        .into_iter()
+        .next()
+        .ok_or(anyhow!("refusing to merge unrelated histories"))?;
+
+    let ours_tree = commit::Commit::read(&repo, &head)?.tree_sha;
+    let labels = merge::Labels {
+        ours: "HEAD",
+        theirs: &branch,
+    };
+    let result = merge::merge_trees(
+        &repo,
+        Some(&commit::Commit::read(&repo, &base)?.tree_sha),
+        &ours_tree,
+        &commit::Commit::read(&repo, &theirs)?.tree_sha,
+        &labels,
+    )?;
+    merge::update_worktree(&repo, &tree::Tree::flatten(&repo, &ours_tree)?, &result)?;
+    std::fs::write(repo.git_dir.join("ORIG_HEAD"), format!("{}\n", head))?;
+
+    let message = merge_message(&repo, &branch)?;
+    if !result.conflicts.is_empty() {
+        let mut merge_msg = format!("{}\n# Conflicts:\n", message);
+        for conflict in &result.conflicts {
+            println!("{}", conflict.message(&labels));
+            merge_msg.push_str(&format!("#\t{}\n", pathname::display(&conflict.path)));
+        }
+        std::fs::write(repo.git_dir.join("MERGE_HEAD"), format!("{}\n", theirs))?;
+        std::fs::write(repo.git_dir.join("MERGE_MSG"), merge_msg)?;
+        std::fs::write(repo.git_dir.join("MERGE_MODE"), "")?;
+        println!("Automatic merge failed; fix conflicts and then commit the result.");
+        std::process::exit(1);
+    }
+
+    hooks::run(&repo, hooks::HookName::PreCommit, &[], &[])?;
+    let message = hooks::commit_msg(&repo, &message)?;
+    let tree = index::Index::read(&repo)?.write_tree(&repo)?;
+    let id = commit::Commit::new(&repo, tree, vec![head, theirs], message)?.write(&repo)?;
+    let message = format!("merge {}: Merge made by the 'three-way' strategy.", branch);
+    refs::update(
+        &repo,
+        &refs::head_ref(&repo)?,
+        Some(&head),
+        Some(&id),
+        &message,
+    )?;
+    println!("Merge made by the 'three-way' strategy.");
+    hooks::run(&repo, hooks::HookName::PostCommit, &[], &[])?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct CommitArgs {
+    /// Commit message, multiple messages are separate paragraphs
+    #[arg(short, long)]
+    message: Vec<String>,
+    /// Read the message from the file, `-` for standard input
+    #[arg(short = 'F', long, conflicts_with = "message")]
+    file: Option<PathBuf>,
+    /// Replace the tip of the current branch, keeping its author and by default its
+    /// message
+    #[arg(long)]
+    amend: bool,
+    /// `strip`, `whitespace`, `verbatim`, `scissors` or `default`, `commit.cleanup`
+    /// config otherwise
+    #[arg(long, value_name = "MODE")]
+    cleanup: Option<message::Cleanup>,
+    /// Add `Signed-off-by` trailer of the committer
+    #[arg(short, long)]
+    signoff: bool,
+    /// Add trailer given as `key=value` or `key: value`
+    #[arg(long, value_name = "TRAILER")]
+    trailer: Vec<String>,
+    /// Allow commit with the same tree as its parent
+    #[arg(long)]
+    allow_empty: bool,
+    /// Allow commit with empty message
+    #[arg(long)]
+    allow_empty_message: bool,
+    /// Sign the commit, with `user.signingKey` unless key is given
+    #[arg(
+        short = 'S',
+        long,
+        value_name = "KEY",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ""
+    )]
+    gpg_sign: Option<String>,
+}
+
+fn run_checkout(force: bool, new_branch: Option<String>, target: Option<String>) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    let target = target
+        .or(new_branch.as_ref().map(|_| String::from("HEAD")))
+        .ok_or(anyhow!("Branch or commit to check out is required"))?;
+    let head = refs::resolve(&repo, "HEAD")?;
+    // Name of the current branch, `HEAD` itself when detached
+    let on_branch = refs::head_ref(&repo)?;
+    let branch = match &new_branch {
+        Some(name) => {
+            let branch = format!("refs/heads/{}", name);
+            ensure!(
+                refs::resolve(&repo, &branch)?.is_none(),
+                "a branch named '{}' already exists",
+                name
+            );
+            Some(branch)
+        }
+        None => {
+            let branch = format!("refs/heads/{}", target);
+            refs::resolve(&repo, &branch)?.map(|_| branch)
+        }
+    };
+    let id = match (&branch, &new_branch) {
+        (Some(branch), None) => {
+            refs::resolve(&repo, branch)?.ok_or(anyhow!("Branch {} is unborn", branch))?
+        }
+        _ => revwalk::peel_to_commit(&repo, &revparse::resolve(&repo, &target)?)?,
+    };
+
+    let current = match &head {
+        Some(head) => {
+            let tree_sha = commit::Commit::read(&repo, head)?.tree_sha;
+            tree::Tree::flatten(&repo, &tree_sha)?
+        }
+        None => Default::default(),
+    };
+    let commit = commit::Commit::read(&repo, &id)?;
+    let wanted = tree::Tree::flatten(&repo, &commit.tree_sha)?;
+    merge::checkout_worktree(&repo, &current, &wanted, force)?;
+
+    let message = reflog::checkout_message(&repo, branch.as_deref().unwrap_or(&target))?;
+    match &branch {
+        Some(branch) => {
+            if new_branch.is_some() {
+                let created = format!("branch: Created from {}", target);
+                refs::update(&repo, branch, None, Some(&id), &created)?;
+            }
+            let name = branch.strip_prefix("refs/heads/").unwrap_or(branch);
+            if on_branch == *branch {
+                eprintln!("Already on '{}'", name);
+            } else {
+                refs::set_symbolic(&repo, "HEAD", branch, Some(&message))?;
+                match new_branch {
+                    Some(_) => eprintln!("Switched to a new branch '{}'", name),
+                    None => eprintln!("Switched to branch '{}'", name),
+                }
+            }
+        }
+        None => {
+            let subject = |commit: &commit::Commit| commit.message.lines().next().map(String::from);
+            let detached = on_branch == "HEAD";
+            let previous = head.as_ref().filter(|old| detached && **old != id);
+            if let Some(old) = previous {
+                let old_commit = commit::Commit::read(&repo, old)?;
+                let abbrev = object::Object::abbreviate(&repo, old, 7)?;
+                let subject = subject(&old_commit).unwrap_or_default();
+                eprintln!("Previous HEAD position was {} {}", abbrev, subject);
+            }
+            refs::detach_head(&repo, &id, &message)?;
+            let abbrev = object::Object::abbreviate(&repo, &id, 7)?;
+            let subject = subject(&commit).unwrap_or_default();
+            eprintln!("HEAD is now at {} {}", abbrev, subject);
+        }
+    }
+    hooks::post_checkout(&repo, head.as_ref(), &id, true)?;
+    Ok(())
+}
+
+/// Restores the paths from the commit, from HEAD into the index or from the index into
+/// the working tree without it
+fn restore_paths(
+    repo: &GitRepo,
+    source: Option<&str>,
+    paths: &[OsString],
+    staged: bool,
+    worktree: bool,
+    overlay: bool,
+) -> Result<()> {
+    if worktree {
+        repo.require_worktree()?;
+    }
+    let commit = match source {
+        Some(rev) => {
+            let id = revparse::resolve(repo, rev)?;
+            Some(revwalk::peel_to_commit(repo, &id)?)
+        }
+        None => refs::resolve(repo, "HEAD")?,
+    };
+    let source = match commit {
+        _ if source.is_none() && !staged => index::Index::read(repo)?.to_map(),
+        Some(id) => tree::Tree::flatten(repo, &commit::Commit::read(repo, &id)?.tree_sha)?,
+        None => Default::default(),
+    };
+    let paths = paths
+        .iter()
+        .map(|path| repo.path_from_cwd(path))
+        .collect::<error::Result<Vec<_>>>()?;
+    merge::restore(repo, &source, &paths, staged, worktree, overlay)?;
+    Ok(())
+}
+
+fn run_commit(args: CommitArgs) -> Result<()> {
+    use std::io::Read;
+
+    let repo = repo_in_cwd()?;
+    let config = config::Config::load(&repo)?;
+    let index = index::Index::read(&repo)?;
+    ensure!(
+        index.conflicts().is_empty(),
+        "Committing is not possible because you have unmerged files."
+    );
+    let head = refs::resolve(&repo, "HEAD")?;
+    let head_commit = match &head {
+        Some(id) => Some(commit::Commit::read(&repo, id)?),
+        None => None,
+    };
+    let merge_head = repo.git_dir.join("MERGE_HEAD");
+    let merging = merge_head.exists();
+    let mut parents: Vec<oid::ObjectId> = head.iter().copied().collect();
+    if args.amend {
+        ensure!(
+            !merging,
+            "You are in the middle of a merge -- cannot amend."
+        );
+        let Some(head_commit) = &head_commit else {
+            anyhow::bail!("You have nothing to amend.");
+        };
+        parents = head_commit.parents.clone();
+    }
+    if merging {
+        let content = fs::read_to_string(&merge_head)?;
+        for line in content.lines() {
+            parents.push(line.parse()?);
+        }
+    }
+
+    let mut message = if let Some(file) = &args.file {
+        let mut content = String::new();
+        if file.as_os_str() == "-" {
+            std::io::stdin().read_to_string(&mut content)?;
+        } else {
+            let error = || format!("could not read log file '{}'", file.display());
+            content = fs::read_to_string(file).with_context(error)?;
+        }
+        content
+    } else if !args.message.is_empty() {
+        let paragraphs: Vec<&str> = args.message.iter().map(|m| m.trim_end()).collect();
+        format!("{}\n", paragraphs.join("\n\n"))
+    } else if let (true, Some(head_commit)) = (args.amend, &head_commit) {
+        head_commit.message.clone()
+    } else if merging {
+        fs::read_to_string(repo.git_dir.join("MERGE_MSG"))?
+    } else {
+        anyhow::bail!("no commit message given, use -m or -F");
+    };
+    let committer = commit::Author::from_env(&repo, commit::Role::Committer)?;
+    if args.signoff {
+        let signoff = format!("{} <{}>", committer.name, committer.email);
+        message = message::add_trailer(&message, "Signed-off-by", &signoff);
+    }
+    for trailer in &args.trailer {
+        let (key, value) = message::parse_trailer(trailer)?;
+        message = message::add_trailer(&message, &key, &value);
+    }
+
+    let tree = index.write_tree(&repo)?;
+    let parent_tree = match &head_commit {
+        Some(commit) if args.amend => match commit.parents.first() {
+            Some(parent) => Some(commit::Commit::read(&repo, parent)?.tree_sha),
+            None => None,
+        },
+        Some(commit) => Some(commit.tree_sha),
+        None => None,
+    };
+    // Initial commit of empty tree is empty too
+    let empty_tree = object::Object::new(repo.hash_algo(), object::ObjectKind::Tree, Vec::new());
+    let parent_tree = parent_tree.unwrap_or(empty_tree.hash);
+    if tree == parent_tree && !(args.allow_empty || args.amend || merging) {
+        if describe::is_dirty(&repo)? {
+            println!("no changes added to commit");
+        } else {
+            println!("nothing to commit, working tree clean");
+        }
+        std::process::exit(1);
+    }
+
+    hooks::run(&repo, hooks::HookName::PreCommit, &[], &[])?;
+    let message = hooks::commit_msg(&repo, &message)?;
+    let cleanup = match args.cleanup {
+        Some(cleanup) => cleanup,
+        None => match config.get("commit.cleanup") {
+            Some(cleanup) => cleanup.parse()?,
+            None => message::Cleanup::Default,
+        },
+    };
+    let comment_char = config.get("core.commentChar").unwrap_or("#");
+    let comment_char = comment_char.chars().next().unwrap_or('#');
+    let message = message::cleanup(&message, cleanup, comment_char);
+    ensure!(
+        !message.trim().is_empty() || args.allow_empty_message,
+        "Aborting commit due to empty commit message."
+    );
+
+    let mut commit = commit::Commit::new(&repo, tree, parents, message)?;
+    commit.committer = committer;
+    if let (Some(head_commit), true) = (&head_commit, args.amend) {
+        commit.author = head_commit.author.clone();
+    }
+    let gpg_sign = match args.gpg_sign {
+        Some(key) => Some(key),
+        None => {
+            let sign = config.get_bool("commit.gpgSign")?.unwrap_or(false);
+            sign.then(String::new)
+        }
+    };
+    if let Some(key) = gpg_sign {
+        let key = Some(key.as_str()).filter(|key| !key.is_empty());
+        signature::sign_commit(&repo, &mut commit, key)?;
+    }
+    let subject = sequencer::subject(&commit).to_string();
+    let id = commit.write(&repo)?;
+    let action = if head.is_none() {
+        "commit (initial)"
+    } else if args.amend {
+        "commit (amend)"
+    } else if merging {
+        "commit (merge)"
+    } else {
+        "commit"
+    };
+    let message = format!("{}: {}", action, subject);
+    let head_ref = refs::head_ref(&repo)?;
+    refs::update(&repo, &head_ref, head.as_ref(), Some(&id), &message)?;
+    for file in ["MERGE_HEAD", "MERGE_MSG", "MERGE_MODE"] {
+        let _ = fs::remove_file(repo.git_dir.join(file));
+    }
+    let root = if head.is_none() { " (root-commit)" } else { "" };
+    let abbrev = object::Object::abbreviate(&repo, &id, 7)?;
+    println!("[{}{} {}] {}", head_name(&repo)?, root, abbrev, subject);
+    hooks::run(&repo, hooks::HookName::PostCommit, &[], &[])?;
+    Ok(())
+}
+
+/// Short name of the current branch for messages
+fn head_name(repo: &GitRepo) -> Result<String> {
+    let head = refs::head_ref(repo)?;
+    Ok(match head.strip_prefix("refs/heads/") {
+        Some(branch) => branch.to_string(),
+        None => String::from("detached HEAD"),
+    })
+}
+
+fn run_format_patch(
+    dir: Option<PathBuf>,
+    stdout: bool,
+    root: bool,
+    max_count: Option<usize>,
+    rev: Option<String>,
+) -> Result<()> {
+    use std::io::Write;
+
+    let repo = repo_in_cwd()?;
+    let mut walk = revwalk::RevWalk::new(&repo);
+    walk.sort(revwalk::Sort::Topo);
+    let rev = rev.unwrap_or_else(|| String::from("HEAD"));
+    match revparse::parse(&repo, &rev)? {
+        revparse::Revision::Single(id) if !root && max_count.is_none() => {
+            walk.push(&revparse::resolve(&repo, "HEAD")?)?;
+            walk.hide(&id)?;
+        }
+        revision => revision.push_to(&mut walk)?,
+    }
+    let mut commits = Vec::new();
+    for item in walk {
+        if max_count.is_some_and(|count| commits.len() >= count) {
+            break;
+        }
+        let (id, commit) = item?;
+        // Merges cannot be represented as single patch
+        if commit.parents.len() <= 1 {
+            commits.push((id, commit));
+        }
+    }
+    commits.reverse();
+
+    let dir = dir.unwrap_or_default();
+    if !stdout && !commits.is_empty() {
+        std::fs::create_dir_all(&dir).context("Creating output directory")?;
+    }
+    let total = commits.len();
+    for (i, (id, commit)) in commits.iter().enumerate() {
+        let number = (total > 1).then_some((i + 1, total));
+        let patch = mail::format_patch(&repo, id, commit, number)?;
+        if stdout {
+            std::io::stdout().lock().write_all(&patch)?;
+        } else {
+            let path = dir.join(mail::file_name(i + 1, commit));
+            std::fs::write(&path, patch)
+                .with_context(|| format!("Writing {}", path.display()))?;
+            println!("{}", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn run_am(mboxes: Vec<PathBuf>) -> Result<()> {
+    use std::io::Read;
+
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    let mut data = Vec::new();
+    if mboxes.is_empty() {
+        std::io::stdin().read_to_end(&mut data)?;
+    }
+    for path in &mboxes {
+        data.extend(std::fs::read(path).with_context(|| format!("Reading {}", path.display()))?);
+    }
+    for (i, mail) in mail::parse_mbox(&data)?.into_iter().enumerate() {
+        let subject = mail.message.lines().next().unwrap_or_default().to_string();
+        println!("Applying: {}", subject);
+        let head = refs::resolve(&repo, "HEAD")?;
+        let current = match &head {
+            Some(id) => tree::Tree::flatten(&repo, &commit::Commit::read(&repo, id)?.tree_sha)?,
+            None => BTreeMap::new(),
+        };
+        // Whole index is committed, it may contain only the applied changes
+        ensure!(
+            index::Index::read(&repo)?.to_map() == current,
+            "Dirty index: cannot apply patches"
+        );
+        let applied = patch::parse(&mail.patch).and_then(|patches| {
+            patch::apply(&repo, &patches, patch::Target::Both, None, false)
+        });
+        if let Err(err) = applied {
+            println!("error: {:#}", err);
+            println!("Patch failed at {:04} {}", i + 1, subject);
+            std::process::exit(1);
+        }
+
+        let commit = commit::Commit {
+            tree_sha: index::Index::read(&repo)?.write_tree(&repo)?,
+            parents: head.iter().cloned().collect(),
+            author: mail.author,
+            committer: commit::Author::from_env(&repo, commit::Role::Committer)?,
+            extra_headers: Vec::new(),
+            message: mail.message,
+            raw: None,
+        };
+        let id = commit.write(&repo)?;
+        let message = format!("am: {}", subject);
+        refs::update(
+            &repo,
+            &refs::head_ref(&repo)?,
+            head.as_ref(),
+            Some(&id),
+            &message,
+        )?;
+    }
+    Ok(())
+}
+
+fn run_apply(
+    target: patch::Target,
+    check: bool,
+    reverse: bool,
+    context: Option<usize>,
+    paths: Vec<PathBuf>,
+) -> Result<()> {
+    use std::io::Read;
+
+    let repo = repo_in_cwd()?;
+    if target != patch::Target::Index {
+        repo.require_worktree()?;
+    }
+    let mut data = Vec::new();
+    if paths.is_empty() {
+        std::io::stdin().read_to_end(&mut data)?;
+    }
+    for path in &paths {
+        data.extend(std::fs::read(path).with_context(|| format!("Reading {}", path.display()))?);
+    }
+    let mut patches = patch::parse(&data)?;
+    ensure!(!patches.is_empty(), "No valid patches in input");
+    if reverse {
+        patches = patches.iter().rev().map(patch::FilePatch::reverse).collect::<error::Result<_>>()?;
+    }
+    Ok(patch::apply(&repo, &patches, target, context, check)?)
+}
+
+fn run_archive(
+    format: Option<archive::ArchiveFormat>,
+    prefix: String,
+    output: Option<PathBuf>,
+    tree_ish: String,
+) -> Result<()> {
+    use archive::ArchiveFormat;
+
+    let repo = repo_in_cwd()?;
+    let implied = output.as_deref().and_then(ArchiveFormat::from_path);
+    let format = format.or(implied).unwrap_or(ArchiveFormat::Tar);
+    match output {
+        Some(path) => {
+            let file = fs::File::create(&path)
+                .with_context(|| format!("could not create archive file '{}'", path.display()))?;
+            let out = std::io::BufWriter::new(file);
+            archive::write(&repo, &tree_ish, format, &prefix, out)?;
+        }
+        None => {
+            let out = std::io::BufWriter::new(std::io::stdout().lock());
+            archive::write(&repo, &tree_ish, format, &prefix, out)?;
+        }
+    }
+    Ok(())
+}
+
+fn run_fast_export(revs: Vec<String>) -> Result<()> {
+    use std::io::Write;
+
+    let repo = repo_in_cwd()?;
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    fast_import::export(&repo, &revs, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn run_fast_import(force: bool) -> Result<()> {
+    use std::io::Read;
+
+    let repo = repo_in_cwd()?;
+    let mut input = Vec::new();
+    std::io::stdin().read_to_end(&mut input)?;
+    fast_import::import(&repo, &input, force, &mut std::io::stdout().lock())?;
+    Ok(())
+}
+
+fn run_reset(rev: Option<String>, mode: merge::ResetMode) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    if mode != merge::ResetMode::Soft {
+        repo.require_worktree()?;
+    }
+    let rev = rev.unwrap_or_else(|| String::from("HEAD"));
+    let id = revwalk::peel_to_commit(&repo, &revparse::resolve(&repo, &rev)?)?;
+    let head = refs::resolve(&repo, "HEAD")?;
+    let merging = repo.git_dir.join("MERGE_HEAD").exists();
+    ensure!(
+        !(merging && mode == merge::ResetMode::Soft),
+        "Cannot do a soft reset in the middle of a merge."
+    );
+    let commit = commit::Commit::read(&repo, &id)?;
+    let target = tree::Tree::flatten(&repo, &commit.tree_sha)?;
+    match mode {
+        merge::ResetMode::Soft => {}
+        merge::ResetMode::Mixed => merge::reset_index(&repo, &target)?,
+        merge::ResetMode::Hard => {
+            let current = match &head {
+                Some(head) => {
+                    let tree_sha = commit::Commit::read(&repo, head)?.tree_sha;
+                    tree::Tree::flatten(&repo, &tree_sha)?
+                }
+                None => Default::default(),
+            };
+            merge::reset_worktree(&repo, &current, &target)?;
+        }
+    }
+
+    if let Some(head) = &head {
+        fs::write(repo.git_dir.join("ORIG_HEAD"), format!("{}\n", head))?;
+    }
+    let message = format!("reset: moving to {}", rev);
+    let head_ref = refs::head_ref(&repo)?;
+    refs::update(&repo, &head_ref, head.as_ref(), Some(&id), &message)?;
+    if mode != merge::ResetMode::Soft {
+        for file in ["MERGE_HEAD", "MERGE_MSG", "MERGE_MODE", "CHERRY_PICK_HEAD"] {
+            let _ = fs::remove_file(repo.git_dir.join(file));
+        }
+    }
+    match mode {
+        merge::ResetMode::Soft => {}
+        merge::ResetMode::Mixed => {
+            let mut index = index::Index::read(&repo)?;
+            let modified = index.refresh(&repo)?;
+            index.write(&repo)?;
+            if !modified.is_empty() {
+                println!("Unstaged changes after reset:");
+                for path in modified {
+                    let status = match repo.worktree_path(&path).symlink_metadata() {
+                        Ok(_) => 'M',
+                        Err(_) => 'D',
+                    };
+                    println!("{}\t{}", status, pathname::display(&path));
+                }
+            }
+        }
+        merge::ResetMode::Hard => {
+            let abbrev = object::Object::abbreviate(&repo, &id, 7)?;
+            println!("HEAD is now at {} {}", abbrev, sequencer::subject(&commit));
+        }
+    }
+    Ok(())
+}
+
+fn run_cherry_pick(rev: String) -> Result<()> {
+    use sequencer::Picked;
+
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    for pseudo_ref in ["MERGE_HEAD", "CHERRY_PICK_HEAD"] {
+        ensure!(
+            !repo.git_dir.join(pseudo_ref).exists(),
+            "{} exists, conclude the operation in progress first",
+            pseudo_ref
+        );
+    }
+    let id = revwalk::peel_to_commit(&repo, &revparse::resolve(&repo, &rev)?)?;
+    let original = commit::Commit::read(&repo, &id)?;
+    match sequencer::pick(&repo, &id, "cherry-pick")? {
+        Picked::Committed(new_id) => {
+            println!(
+                "[{} {}] {}",
+                head_name(&repo)?,
+                &new_id.to_hex()[..7],
+                sequencer::subject(&original)
+            );
+        }
+        Picked::Empty => {
+            println!("The previous cherry-pick is now empty, possibly due to conflict resolution.");
+            std::process::exit(1);
+        }
+        Picked::Conflicts(conflicts) => {
+            let short = id.to_hex()[..7].to_string();
+            let labels = merge::Labels {
+                ours: "HEAD",
+                theirs: &short,
+            };
+            let mut merge_msg = format!("{}\n\n# Conflicts:\n", original.message.trim_end());
+            for conflict in &conflicts {
+                println!("{}", conflict.message(&labels));
+                merge_msg.push_str(&format!("#\t{}\n", pathname::display(&conflict.path)));
+            }
+            std::fs::write(repo.git_dir.join("CHERRY_PICK_HEAD"), format!("{}\n", id))?;
+            std::fs::write(repo.git_dir.join("MERGE_MSG"), merge_msg)?;
+            println!("error: could not apply {}", sequencer::describe(&id, &original));
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+fn run_rebase(upstream: Option<String>, abort: bool) -> Result<()> {
+    use sequencer::{Rebase, RebaseStatus};
+
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    let mut rebase = match upstream {
+        Some(upstream) => {
+            let onto = revparse::resolve(&repo, &upstream)?;
+            match Rebase::start(&repo, &onto)? {
+                Some(rebase) => rebase,
+                None => {
+                    println!("Current branch {} is up to date.", head_name(&repo)?);
+                    return Ok(());
+                }
+            }
+        }
+        None => Rebase::load(&repo)?,
+    };
+    if abort {
+        return Ok(rebase.abort(&repo)?);
+    }
+    match rebase.run(&repo)? {
+        RebaseStatus::Finished => {
+            println!("Successfully rebased and updated {}.", rebase.head_name);
+        }
+        RebaseStatus::Stopped(id, conflicts) => {
+            let short = id.to_hex()[..7].to_string();
+            let labels = merge::Labels {
+                ours: "HEAD",
+                theirs: &short,
+            };
+            for conflict in &conflicts {
+                println!("{}", conflict.message(&labels));
+            }
+            let original = commit::Commit::read(&repo, &id)?;
+            println!("error: could not apply {}", sequencer::describe(&id, &original));
+            println!("Resolve all conflicts manually, mark them as resolved and run \"rebase --continue\".");
+            println!("To abort and get back to the state before the rebase, run \"rebase --abort\".");
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+fn run_blame(rev: Option<String>, path: String) -> Result<()> {
+    use std::io::Write;
+
+    let repo = repo_in_cwd()?;
+    let path = repo.path_from_cwd(&path)?;
+    let blame = blame::blame(&repo, rev.as_deref(), &path)?;
+    let mailmap = mailmap::Mailmap::load(&repo)?;
+    let mut authors = BTreeMap::new();
+    for line in &blame.lines {
+        if authors.contains_key(&line.commit) {
+            continue;
+        }
+        let (name, date) = if line.commit.is_zero() {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs() as i64;
+            let offset = date::local_offset(now);
+            ("Not Committed Yet".to_string(), date::format_iso(now, offset))
+        } else {
+            let commit = commit::Commit::read(&repo, &line.commit)?;
+            let (name, _) = mailmap.map(&commit.author.name, &commit.author.email);
+            (name, commit.author.format_iso_date())
+        };
+        authors.insert(line.commit, (name, date));
+    }
+    let author_width = authors.values().map(|(name, _)| name.chars().count()).max();
+    let author_width = author_width.unwrap_or_default();
+    let line_width = blame.lines.len().to_string().len();
+    // Paths are shown only when some lines come from before a rename
+    let path_width = blame
+        .lines
+        .iter()
+        .any(|line| line.path != path)
+        .then(|| {
+            let widths = blame.lines.iter();
+            widths
+                .map(|line| pathname::display(&line.path).chars().count())
+                .max()
+        })
+        .flatten();
+
+    let mut out = Vec::new();
+    for (n, (line, content)) in blame.lines.iter().zip(diff::lines(&blame.content)).enumerate() {
+        if line.boundary {
+            write!(out, "^{}", &line.commit.to_hex()[..7])?;
+        } else {
+            write!(out, "{}", &line.commit.to_hex()[..8])?;
+        }
+        if let Some(width) = path_width {
+            write!(out, " {:width$}", pathname::display(&line.path))?;
+        }
+        let (name, date) = &authors[&line.commit];
+        write!(out, " ({:author_width$} {} {:>line_width$}) ", name, date, n + 1)?;
+        out.extend_from_slice(content);
+        if !content.ends_with(b"\n") {
+            out.push(b'\n');
+        }
+    }
+    std::io::stdout().write_all(&out)?;
+    Ok(())
+}
+
+fn run_diff(
+    cached: bool,
+    context: usize,
+    renames: RenameArgs,
+    stat: StatArgs,
+    revs: Vec<String>,
+) -> Result<()> {
+    use std::io::Write;
+
+    let repo = repo_in_cwd()?;
+    let renames = renames.resolve(&repo)?;
+    let tree_map = |rev: &str| -> Result<_> {
+        let id = revparse::resolve(&repo, &format!("{}^{{tree}}", rev))?;
+        Ok(tree::Tree::flatten(&repo, &id)?)
+    };
+    let mut revs: Vec<String> = revs;
+    if let [rev] = &revs[..] {
+        if let revparse::Revision::Range { from, to } = revparse::parse(&repo, rev)? {
+            revs = vec![from.to_hex(), to.to_hex()];
+        }
+    }
+    let index = index::Index::read(&repo)?;
+    let mut out = Vec::new();
+    // Working tree is always the new side, its content is read from files
+    let (old, new, worktree) = match &revs[..] {
+        [from, to] => (tree_map(from)?, tree_map(to)?, false),
+        [rev] if cached => (tree_map(rev)?, index.to_map(), false),
+        [rev] => {
+            repo.require_worktree()?;
+            (tree_map(rev)?, index.worktree_map(&repo)?, true)
+        }
+        _ if cached => {
+            let head = match refs::resolve(&repo, "HEAD")? {
+                Some(_) => tree_map("HEAD")?,
+                None => Default::default(),
+            };
+            (head, index.to_map(), false)
+        }
+        _ => {
+            repo.require_worktree()?;
+            for path in index.conflicts() {
+                let path = pathname::quote(path, true);
+                out.extend(format!("* Unmerged path {}\n", path).as_bytes());
+            }
+            (index.to_map(), index.worktree_map(&repo)?, true)
+        }
+    };
+
+    let mut changes = diff::changes(&old, &new);
+    if let Some(renames) = &renames {
+        changes = diff::detect_renames(&repo, changes, renames, worktree)?;
+    }
+    if stat.any() {
+        stat.write(&mut out, &diff::stats(&repo, &changes, worktree)?);
+    } else {
+        diff::write_changes(&repo, &mut out, &changes, context, worktree)?;
+    }
+    std::io::stdout().lock().write_all(&out)?;
+    Ok(())
+}
+
+fn run_ls_files(
+    cached: bool,
+    stage: bool,
+    others: bool,
+    exclude_standard: bool,
+    modified: bool,
+) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    let idx = index::Index::read(&repo)?;
+    // Only files under the current directory are listed, relative to it
+    let prefix = repo.cwd_prefix()?;
+    let quote = quote_path(&repo)?;
+    let show = |entry: &index::IndexEntry| {
+        let path = pathname::quote(&entry.path[prefix.len()..], quote);
+        if stage {
+            println!("{:06o} {} {}\t{}", entry.mode, entry.id, entry.stage, path);
+        } else {
+            println!("{}", path);
+        }
+    };
+    // Untracked files first, then tracked ones like git does
+    if others {
+        let mut ignore = exclude_standard
+            .then(|| ignore::Ignore::load(&repo))
+            .transpose()?;
+        for path in idx.untracked(&repo)? {
+            let Some(relative) = path.strip_prefix(prefix.as_slice()) else {
+                continue;
+            };
+            if let Some(ignore) = &mut ignore {
+                let dir = path.strip_suffix(b"/");
+                if ignore.is_ignored(dir.unwrap_or(&path), dir.is_some())? {
+                    continue;
+                }
+            }
+            println!("{}", pathname::quote(relative, quote));
+        }
+    }
+    let cached = cached || stage || !(others || modified);
+    let mut attributes = attributes::Attributes::load(&repo)?;
+    for entry in idx.entries.iter().filter(|e| e.path.starts_with(&prefix)) {
+        if cached {
+            show(entry);
+        }
+        if modified && index::Index::is_modified(&repo, entry, &idx, &mut attributes)? {
+            show(entry);
+        }
+    }
+    Ok(())
+}
+
+fn run_rm(cached: bool, force: bool, recursive: bool, paths: Vec<OsString>) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    let mut idx = index::Index::read(&repo)?;
+    let mut selected: Vec<Vec<u8>> = Vec::new();
+    for path in &paths {
+        let path = repo.path_from_cwd(path)?;
+        let dir = pathname::concat(&path, b"");
+        let before = selected.len();
+        for entry in &idx.entries {
+            if entry.path == path || entry.path.starts_with(&dir) {
+                ensure!(
+                    recursive || entry.path == path,
+                    "not removing '{}' recursively without -r",
+                    pathname::display(&path)
+                );
+                selected.push(entry.path.clone());
+            }
+        }
+        ensure!(
+            selected.len() > before,
+            "pathspec '{}' did not match any files",
+            pathname::display(&path)
+        );
+    }
+    selected.sort();
+    selected.dedup();
+
+    if !force {
+        let head = match refs::resolve(&repo, "HEAD")? {
+            Some(id) => tree::Tree::flatten(&repo, &commit::Commit::read(&repo, &id)?.tree_sha)?,
+            None => BTreeMap::new(),
+        };
+        let (mut both, mut staged, mut local) = (Vec::new(), Vec::new(), Vec::new());
+        let mut attributes = attributes::Attributes::load(&repo)?;
+        for path in &selected {
+            // Unmerged paths and files already deleted can always be removed
+            let Some(entry) = idx.get(path, 0) else {
+                continue;
+            };
+            if index::worktree_file_with(&repo, path, &idx, &mut attributes)?.is_none() {
+                continue;
+            }
+            let staged_changes = head.get(path) != Some(&(entry.mode, entry.id));
+            let local_changes = index::Index::is_modified(&repo, entry, &idx, &mut attributes)?;
+            if staged_changes && local_changes {
+                both.push(pathname::display(path));
+            } else if !cached {
+                if staged_changes {
+                    staged.push(pathname::display(path));
+                }
+                if local_changes {
+                    local.push(pathname::display(path));
+                }
+            }
+        }
+        let mut errors = Vec::new();
+        let list = |paths: &[Cow<str>], what: &str, hint: &str| {
+            let (subject, verb) = if paths.len() == 1 {
+                ("file", "has")
+            } else {
+                ("files", "have")
+            };
+            let mut message = format!("the following {} {} {}:\n", subject, verb, what);
+            for path in paths {
+                message.push_str(&format!("    {}\n", path));
+            }
+            message.push_str(hint);
+            message
+        };
+        if !both.is_empty() {
+            errors.push(list(
+                &both,
+                "staged content different from both the\nfile and the HEAD",
+                "(use -f to force removal)",
+            ));
+        }
+        let hint = "(use --cached to keep the file, or -f to force removal)";
+        if !staged.is_empty() {
+            errors.push(list(&staged, "changes staged in the index", hint));
+        }
+        if !local.is_empty() {
+            errors.push(list(&local, "local modifications", hint));
+        }
+        if !errors.is_empty() {
+            anyhow::bail!("{}", errors.join("\n"));
+        }
+    }
+
+    for path in &selected {
+        println!("rm '{}'", pathname::display(path));
+        idx.remove(path);
+        if !cached {
+            merge::remove_file(&repo, path)?;
+        }
+    }
+    Ok(idx.write(&repo)?)
+}
+
+fn run_mv(force: bool, sources: Vec<OsString>, destination: OsString) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    let mut idx = index::Index::read(&repo)?;
+    let destination = repo.path_from_cwd(&destination)?;
+    let into_dir = repo.worktree_path(&destination).is_dir();
+    ensure!(
+        into_dir || sources.len() == 1,
+        "destination '{}' is not a directory",
+        pathname::display(&destination)
+    );
+    for source in &sources {
+        let source = repo.path_from_cwd(source)?;
+        let target = match (into_dir, pathname::split_last(&source)) {
+            (true, Some((_, name))) => pathname::concat(&destination, name),
+            (true, None) => pathname::concat(&destination, &source),
+            (false, _) => destination.clone(),
+        };
+        let (source_name, target_name) = (pathname::display(&source), pathname::display(&target));
+        let bad = |reason: &str| {
+            anyhow!(
+                "{}, source={}, destination={}",
+                reason,
+                source_name,
+                target_name
+            )
+        };
+        let dir = pathname::concat(&source, b"");
+        let moved: Vec<index::IndexEntry> = idx
+            .entries
+            .iter()
+            .filter(|e| e.path == source || e.path.starts_with(&dir))
+            .cloned()
+            .collect();
+        if fs::symlink_metadata(repo.worktree_path(&source)).is_err() {
+            return Err(bad("bad source"));
+        }
+        if moved.is_empty() {
+            return Err(bad("not under version control"));
+        }
+        if moved.iter().any(|e| e.stage != 0) {
+            return Err(bad("conflicted"));
+        }
+        let target_path = repo.worktree_path(&target);
+        if fs::symlink_metadata(&target_path).is_ok() {
+            let is_file = moved.len() == 1 && moved[0].path == source;
+            if !(force && is_file && !target_path.is_dir()) {
+                return Err(bad("destination exists"));
+            }
+        }
+        if target_path.parent().is_some_and(|parent| !parent.is_dir()) {
+            return Err(bad("destination directory does not exist"));
+        }
+        // Stat information is kept only for files unchanged since staged
+        let fresh: Vec<bool> = moved
+            .iter()
+            .map(|e| index::Index::is_fresh(e, &repo.worktree_path(&e.path)))
+            .collect();
+        fs::rename(repo.worktree_path(&source), &target_path)
+            .with_context(|| format!("Renaming {} to {}", source_name, target_name))?;
+        for (entry, fresh) in moved.into_iter().zip(fresh) {
+            let path = [&target, &entry.path[source.len()..]].concat();
+            idx.remove(&entry.path);
+            idx.remove(&path);
+            idx.add(if fresh {
+                let metadata = fs::symlink_metadata(repo.worktree_path(&path))?;
+                index::IndexEntry::from_metadata(&path, entry.mode, &entry.id, &metadata)
+            } else {
+                index::IndexEntry::new(&path, entry.mode, &entry.id, 0)
+            });
+        }
+    }
+    Ok(idx.write(&repo)?)
+}
+
+fn run_clean(dry_run: bool, force: bool, directories: bool, ignored: bool) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    if !(dry_run || force) {
+        let config = config::Config::load(&repo)?;
+        match config.get_bool("clean.requireForce")? {
+            Some(false) => {}
+            Some(true) => anyhow::bail!(
+                "clean.requireForce set to true and neither -n nor -f given; refusing to clean"
+            ),
+            None => anyhow::bail!(
+                "clean.requireForce defaults to true and neither -n nor -f given; refusing to clean"
+            ),
+        }
+    }
+    let options = clean::CleanOptions {
+        directories,
+        ignored,
+    };
+    // Only the current directory is cleaned, paths are shown relative to it
+    let prefix = repo.cwd_prefix()?;
+    let quote = quote_path(&repo)?;
+    for path in clean::removable(&repo, options)? {
+        let Some(relative) = path.strip_prefix(prefix.as_slice()) else {
+            continue;
+        };
+        let relative = pathname::quote(relative, quote);
+        if dry_run {
+            println!("Would remove {}", relative);
+        } else {
+            println!("Removing {}", relative);
+            clean::remove(&repo, &path)?;
+        }
+    }
+    Ok(())
+}
+
+fn run_check_ignore(verbose: bool, non_matching: bool, paths: Vec<OsString>) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    let idx = index::Index::read(&repo)?;
+    let mut ignore = ignore::Ignore::load(&repo)?;
+    let quote = quote_path(&repo)?;
+    let mut any = false;
+    for path in &paths {
+        let relative = repo.path_from_cwd(path)?;
+        let relative = relative.as_slice();
+        let raw = pathname::from_os(path);
+        let path = pathname::quote(&raw, quote);
+        // Tracked files are not subject to ignore rules
+        let pattern = if !relative.is_empty() && idx.entries.iter().all(|e| e.path != relative) {
+            let is_dir = raw.ends_with(b"/") || repo.worktree_path(relative).is_dir();
+            ignore.pattern_for(relative, is_dir)?
+        } else {
+            None
+        };
+        match pattern {
+            Some(pattern) if verbose => {
+                any |= !pattern.negated;
+                let source = pattern.source.strip_prefix(&repo.repo_root).unwrap_or(&pattern.source);
+                println!(
+                    "{}:{}:{}\t{}",
+                    source.display(),
+                    pattern.line,
+                    pattern.original,
+                    path
+                );
+            }
+            Some(pattern) if !pattern.negated => {
+                any = true;
+                println!("{}", path);
+            }
+            _ if non_matching => println!("::\t{}", path),
+            _ => {}
+        }
+    }
+    if !any {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_grep(
+    line_number: bool,
+    ignore_case: bool,
+    pattern: String,
+    rev: Option<String>,
+    pathspecs: Vec<OsString>,
+) -> Result<()> {
+    use std::io::Write;
+
+    let repo = repo_in_cwd()?;
+    let pattern = grep::Pattern::new(&pattern, ignore_case)?;
+    // Search is limited to the current directory unless paths are given
+    let cwd = repo.cwd_prefix()?;
+    let pathspecs = if pathspecs.is_empty() {
+        vec![cwd.clone()]
+    } else {
+        pathspecs.iter().map(|p| repo.path_from_cwd(p)).collect::<error::Result<_>>()?
+    };
+    if rev.is_none() {
+        repo.require_worktree()?;
+    }
+    let found = grep::grep(&repo, &pattern, rev.as_deref(), &pathspecs)?;
+    if found.is_empty() {
+        std::process::exit(1);
+    }
+    let prefix = rev.map(|rev| format!("{}:", rev)).unwrap_or_default();
+    let quote = quote_path(&repo)?;
+    let mut out = Vec::new();
+    for file in found {
+        let path = GitRepo::path_to_cwd(&cwd, &file.path);
+        let path = pathname::quote(&path, quote);
+        if file.binary {
+            writeln!(out, "Binary file {}{} matches", prefix, path)?;
+            continue;
+        }
+        for (n, line) in file.lines {
+            write!(out, "{}{}:", prefix, path)?;
+            if line_number {
+                write!(out, "{}:", n)?;
+            }
+            out.extend_from_slice(&line);
+            out.push(b'\n');
+        }
+    }
+    std::io::stdout().write_all(&out)?;
+    Ok(())
+}
+
+fn run_bisect(command: BisectCommand) -> Result<()> {
+    use bisect::{Bisect, BisectStatus, Term};
+
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    let bisect = match command {
+        BisectCommand::Start { bad, good } => {
+            let mut bisect = Bisect::start(&repo)?;
+            if let Some(bad) = bad {
+                bisect.mark(&repo, Term::Bad, &bad)?;
+            }
+            for good in good {
+                bisect.mark(&repo, Term::Good, &good)?;
+            }
+            bisect
+        }
+        BisectCommand::Good { revs } => {
+            let mut bisect = Bisect::load(&repo)?;
+            if revs.is_empty() {
+                bisect.mark(&repo, Term::Good, "HEAD")?;
+            }
+            for rev in revs {
+                bisect.mark(&repo, Term::Good, &rev)?;
+            }
+            bisect
+        }
+        BisectCommand::Bad { rev } => {
+            let mut bisect = Bisect::load(&repo)?;
+            bisect.mark(&repo, Term::Bad, rev.as_deref().unwrap_or("HEAD"))?;
+            bisect
+        }
+        BisectCommand::Reset { commit } => {
+            if !Bisect::in_progress(&repo) {
+                println!("We are not bisecting.");
+                return Ok(());
+            }
+            return Ok(Bisect::load(&repo)?.reset(&repo, commit.as_deref())?);
+        }
+    };
+    if bisect.bad.is_none() && bisect.good.is_empty() {
+        println!("status: waiting for both good and bad commits");
+        return Ok(());
+    }
+    match bisect.next(&repo)? {
+        BisectStatus::NeedBad(good) => println!(
+            "status: waiting for bad commit, {} good commit{} known",
+            good,
+            if good == 1 { "" } else { "s" }
+        ),
+        BisectStatus::NeedGood => println!("status: waiting for good commit(s), bad commit known"),
+        BisectStatus::Testing { id, remaining, steps } => {
+            let plural = |n: usize| if n == 1 { "" } else { "s" };
+            println!(
+                "Bisecting: {} revision{} left to test after this (roughly {} step{})",
+                remaining,
+                plural(remaining),
+                steps,
+                plural(steps)
+            );
+            let commit = commit::Commit::read(&repo, &id)?;
+            println!("[{}] {}", id, sequencer::subject(&commit));
+        }
+        BisectStatus::Found(id) => {
+            use std::io::Write;
+
+            println!("{} is the first bad commit", id);
+            let commit = commit::Commit::read(&repo, &id)?;
+            print_commit(&repo, &id, &commit)?;
+            let old = match commit.parents.first() {
+                Some(parent) => {
+                    let parent = commit::Commit::read(&repo, parent)?;
+                    tree::Tree::flatten(&repo, &parent.tree_sha)?
+                }
+                None => Default::default(),
+            };
+            let new = tree::Tree::flatten(&repo, &commit.tree_sha)?;
+            let changes = diff::changes(&old, &new);
+            let mut out = Vec::new();
+            if !changes.is_empty() {
+                out.push(b'\n');
+                diff::write_stat(&mut out, &diff::stats(&repo, &changes, false)?, 80);
+                diff::write_summary(&mut out, &changes);
+            }
+            std::io::stdout().write_all(&out)?;
+        }
+    }
+    Ok(())
+}
+
+fn run_stash(command: StashCommand) -> Result<()> {
+    let repo = repo_in_cwd()?;
+    repo.require_worktree()?;
+    let (n, pop) = match command {
+        StashCommand::Push { message } => {
+            match stash::push(&repo, message.as_deref())? {
+                Some(message) => println!("Saved working directory and index state {}", message),
+                None => println!("No local changes to save"),
+            }
+            return Ok(());
+        }
+        StashCommand::List => {
+            for (i, entry) in stash::list(&repo)?.iter().enumerate() {
+                println!("stash@{{{}}}: {}", i, entry.message);
+            }
+            return Ok(());
+        }
+        StashCommand::Drop { stash } => {
+            let n = stash::parse_selector(&stash)?;
+            let id = stash::drop(&repo, n)?;
+            println!("Dropped refs/stash@{{{}}} ({})", n, id);
+            return Ok(());
+        }
+        StashCommand::Pop { stash } => (stash::parse_selector(&stash)?, true),
+        StashCommand::Apply { stash } => (stash::parse_selector(&stash)?, false),
+    };
+    let conflicts = stash::apply(&repo, n)?;
+    if !conflicts.is_empty() {
+        let labels = merge::Labels {
+            ours: "Updated upstream",
+            theirs: "Stashed changes",
+        };
+        for conflict in &conflicts {
+            println!("{}", conflict.message(&labels));
+        }
+        if pop {
+            println!("The stash entry is kept in case you need it again.");
+        }
+        std::process::exit(1);
+    }
+    if pop {
+        let id = stash::drop(&repo, n)?;
+        println!("Dropped refs/stash@{{{}}} ({})", n, id);
+    }
+    Ok(())
+}
+
+/// Commands accepting `-<n>` as a short form of `--max-count=<n>`
+const COUNT_COMMANDS: &[&str] = &["format-patch", "log"];
+
+/// Rewrites `-<n>` options of commands taking them into `--max-count=<n>`, as clap
+/// cannot declare options named by a number
+fn expand_count_args(args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let mut args: Vec<OsString> = args.collect();
+    let Some(command) = args.get(1).and_then(|arg| arg.to_str()) else {
+        return args;
+    };
+    if !COUNT_COMMANDS.contains(&command) {
+        return args;
+    }
+    for arg in args.iter_mut().skip(2) {
+        let Some(text) = arg.to_str() else { continue };
+        if text == "--" {
+            break;
+        }
+        if let Some(count) = text
+            .strip_prefix('-')
+            .filter(|count| !count.is_empty() && count.bytes().all(|b| b.is_ascii_digit()))
+        {
+            *arg = OsString::from(format!("--max-count={}", count));
+        }
+    }
+    args
+}
+
+fn main() -> Result<()> {
+    match Cli::parse_from(expand_count_args(std::env::args_os())).command {
+        Commands::Init {
+            bare,
+            initial_branch,
+            object_format,
+            directory,
+        } => {
+            let dir = std::env::current_dir()?.join(directory.unwrap_or_default());
+            fs::create_dir_all(&dir).context("Create repository directory")?;
+            let repo = if bare {
+                GitRepo::new_bare(&dir)
+            } else {
+                GitRepo::new(&dir)
+            }
+            .with_progress(stderr_progress());
+            if repo.init_with_format(initial_branch.as_deref(), object_format)? {
+                println!("Reinitialized existing git directory")
+            } else {
+                println!("Initialized git directory")
+            }
+        }
+        Commands::CatFile(args) => run_cat_file(args)?,
+        Commands::HashObject(args) => run_hash_object(args)?,
+        Commands::LsTree(args) => run_ls_tree(args)?,
+        Commands::WriteTree { prefix, worktree } => {
+            let repo = repo_in_cwd()?;
+            if worktree {
+                repo.require_worktree()?;
+                let obj = tree::Tree::write(&repo, &repo.repo_root)?;
+                println!("{}", obj.hash);
+            } else {
+                let index = index::Index::read(&repo)?;
+                let prefix = prefix.as_deref().unwrap_or("");
+                println!("{}", index.write_subtree(&repo, prefix.as_bytes())?);
+            }
+        }
+        Commands::ReadTree {
+            merge,
+            aggressive,
+            tree_ish,
+        } => run_read_tree(merge, aggressive, tree_ish)?,
+        Commands::CheckoutIndex {
+            all,
+            force,
+            index,
+            prefix,
+            paths,
+        } => run_checkout_index(all, force, index, prefix, paths)?,
+        Commands::UpdateIndex(args) => run_update_index(args)?,
+        Commands::UpdateRef {
+            delete,
+            message,
+            reference,
+            values,
+        } => run_update_ref(delete, message, reference, values)?,
+        Commands::ShowRef(args) => run_show_ref(args)?,
+        Commands::ForEachRef(args) => run_for_each_ref(args)?,
+        Commands::SymbolicRef {
+            quiet,
+            short,
+            message,
+            name,
+            target,
+        } => run_symbolic_ref(quiet, short, message, name, target)?,
+        Commands::CommitTree { tree_sha, parent, message, gpg_sign } => {
+            let repo = repo_in_cwd()?;
+            for p in &parent {
+                let kind = object::Object::read(&repo, p)?.header.kind;
+                ensure!(kind == object::ObjectKind::Commit, "{} is not a valid commit", p);
+            }
+            let mut c = commit::Commit::new(&repo, tree_sha, parent, format!("{}\n", message))?;
+            if let Some(key) = gpg_sign {
+                let key = Some(key.as_str()).filter(|key| !key.is_empty());
+                signature::sign_commit(&repo, &mut c, key)?;
+            }
+            println!("{}", c.write(&repo)?);
+        },
+        Commands::Commit(args) => run_commit(args)?,
+        Commands::Checkout {
+            force,
+            new_branch,
+            target,
+            paths,
+        } if !paths.is_empty() => {
+            ensure!(
+                !force && new_branch.is_none(),
+                "options cannot be used with updating paths"
+            );
+            let repo = repo_in_cwd()?;
+            let overlay = target.is_some();
+            restore_paths(&repo, target.as_deref(), &paths, overlay, true, overlay)?
+        }
+        Commands::Checkout {
+            force,
+            new_branch,
+            target,
+            paths: _,
+        } => run_checkout(force, new_branch, target)?,
+        Commands::Restore {
+            source,
+            staged,
+            worktree,
+            paths,
+        } => {
+            let repo = repo_in_cwd()?;
+            let worktree = worktree || !staged;
+            restore_paths(&repo, source.as_deref(), &paths, staged, worktree, false)?
+        }
+        Commands::Clone {
+            recurse_submodules,
+            bare,
+            mirror,
+            reference,
+            repo_url,
+            dest,
+        } => run_clone(repo_url, dest, bare, mirror, reference, recurse_submodules)?,
+        Commands::Serve { addr } => {
+            let repo = repo_in_cwd()?;
+            eprintln!("Serving {} on http://{}", repo.repo_root.display(), addr);
+            serve::http::serve(&repo, &addr)?;
+        }
+        Commands::Config(args) => run_config(args)?,
+        Commands::Remote { verbose, command } => run_remote(verbose, command)?,
+        Commands::RevList(args) => run_rev_list(args)?,
+        Commands::Log(args) => run_log(args)?,
+        Commands::Shortlog(args) => run_shortlog(args)?,
+        Commands::RevParse { short, revs } => {
+            let repo = repo_in_cwd()?;
+            for rev in revs {
+                for line in revparse::parse(&repo, &rev)?.to_lines() {
+                    match short {
+                        Some(len) => {
+                            let (prefix, id) = match line.strip_prefix('^') {
+                                Some(id) => ("^", id),
+                                None => ("", line.as_str()),
+                            };
+                            let id = id.parse()?;
+                            println!("{}{}", prefix, object::Object::abbreviate(&repo, &id, len)?);
+                        }
+                        None => println!("{}", line),
+                    }
+                }
+            }
+        }
+        Commands::Merge {
+            ff_only,
+            no_ff,
+            branch,
+        } => {
+            let ff = if ff_only {
+                merge::FastForward::Only
+            } else if no_ff {
+                merge::FastForward::Never
+            } else {
+                merge::FastForward::Allow
+            };
+            run_merge(branch, ff)?
+        }
+        Commands::Archive {
+            format,
+            prefix,
+            output,
+            tree_ish,
+        } => run_archive(format, prefix, output, tree_ish)?,
+        Commands::Reset {
+            soft,
+            mixed: _,
+            hard,
+            rev,
+        } => {
+            let mode = if soft {
+                merge::ResetMode::Soft
+            } else if hard {
+                merge::ResetMode::Hard
+            } else {
+                merge::ResetMode::Mixed
+            };
+            run_reset(rev, mode)?
+        }
+        Commands::CherryPick { commit } => run_cherry_pick(commit)?,
+        Commands::FormatPatch {
+            output_directory,
+            stdout,
+            root,
+            max_count,
+            rev,
+        } => run_format_patch(output_directory, stdout, root, max_count, rev)?,
+        Commands::Am { mboxes } => run_am(mboxes)?,
+        Commands::Apply {
+            cached,
+            index,
+            check,
+            reverse,
+            context,
+            patches,
+        } => {
+            let target = if cached {
+                patch::Target::Index
+            } else if index {
+                patch::Target::Both
+            } else {
+                patch::Target::Worktree
+            };
+            run_apply(target, check, reverse, context, patches)?
+        }
+        Commands::Rebase {
+            abort,
+            continue_: _,
+            upstream,
+        } => run_rebase(upstream, abort)?,
+        Commands::Show { renames, rev } => run_show(renames, rev)?,
+        Commands::Describe(args) => run_describe(args)?,
+        Commands::Tag(args) => run_tag(args)?,
+        Commands::VerifyCommit { commits } => run_verify_commit(commits)?,
+        Commands::VerifyTag { tags } => run_verify_tag(tags)?,
+        Commands::Diff {
+            cached,
+            unified,
+            renames,
+            stat,
+            revs,
+        } => run_diff(cached, unified, renames, stat, revs)?,
+        Commands::Blame { mut args } => {
+            let path = args.pop().unwrap_or_default();
+            run_blame(args.pop(), path)?
+        }
+        Commands::Bundle { command } => run_bundle(command)?,
+        Commands::FastExport { revs } => run_fast_export(revs)?,
+        Commands::FastImport { force } => run_fast_import(force)?,
+        Commands::Stash { command } => {
+            run_stash(command.unwrap_or(StashCommand::Push { message: None }))?
+        }
+        Commands::LsFiles {
+            cached,
+            stage,
+            others,
+            exclude_standard,
+            modified,
+        } => run_ls_files(cached, stage, others, exclude_standard, modified)?,
+        Commands::Rm {
+            cached,
+            force,
+            r,
+            paths,
+        } => run_rm(cached, force, r, paths)?,
+        Commands::Mv { force, mut paths } => {
+            let destination = paths.pop().unwrap_or_default();
+            run_mv(force, paths, destination)?
+        }
+        Commands::Clean {
+            dry_run,
+            force,
+            d,
+            x,
+        } => run_clean(dry_run, force, d, x)?,
+        Commands::CheckIgnore {
+            verbose,
+            non_matching,
+            paths,
+        } => run_check_ignore(verbose, non_matching, paths)?,
+        Commands::Grep {
+            line_number,
+            ignore_case,
+            pattern,
+            rev,
+            pathspecs,
+        } => run_grep(line_number, ignore_case, pattern, rev, pathspecs)?,
+        Commands::Bisect { command } => run_bisect(command)?,
+        Commands::Submodule {
+            command: SubmoduleCommand::Update { init, recursive },
+        } => {
+            let repo = repo_in_cwd()?;
+            repo.require_worktree()?;
+            run_submodule_update(&repo, "", init, recursive)?
+        }
+        Commands::Worktree { command } => run_worktree(command)?,
+        Commands::Reflog { command, reference } => {
+            run_reflog(command.unwrap_or(ReflogCommand::Show {
+                reference: reference.unwrap_or_else(|| String::from("HEAD")),
+            }))?
+        }
+        Commands::Fsck {
+            unreachable,
+            no_dangling,
+        } => run_fsck(unreachable, no_dangling)?,
+        Commands::IndexPack {
+            stdin,
+            fix_thin,
+            output,
+            strict,
+            pack_file,
+        } => run_index_pack(stdin, fix_thin, output, strict, pack_file)?,
+        Commands::PackObjects {
+            stdout,
+            revs,
+            all,
+            thin,
+            window,
+            depth,
+            base_name,
+        } => run_pack_objects(stdout, revs || all, all, thin, window, depth, base_name)?,
+        Commands::VerifyPack {
+            verbose,
+            stat_only,
+            packs,
+        } => run_verify_pack(verbose, stat_only, packs)?,
+        Commands::UnpackObjects {
+            dry_run,
+            quiet: _,
+            strict,
+        } => run_unpack_objects(dry_run, strict)?,
+        Commands::Gc {
+            aggressive,
+            prune,
+            no_prune,
+        } => {
+            let repo = repo_in_cwd()?;
+            let prune = if no_prune {
+                Some("never")
+            } else {
+                prune.as_deref()
+            };
+            gc::gc(&repo, &gc::GcOptions::load(&repo, aggressive, prune)?)?;
+        }
+        Commands::Prune {
+            dry_run,
+            verbose,
+            expire,
+        } => run_prune(dry_run, verbose, expire.as_deref())?,
+        Commands::CountObjects { verbose } => run_count_objects(verbose)?,
+        Commands::MultiPackIndex { command } => match command {
+            MultiPackIndexCommand::Write => pack::midx::write(&repo_in_cwd()?)?,
+        },
+        Commands::Fetch { remote } => {
+            let repo = repo_in_cwd()?;
+            remote::Remote::load(&repo, &remote)?.fetch(&repo)?;
+        }
+        Commands::Push {
+            force,
+            remote,
+            refspecs,
+        } => {
+            let repo = repo_in_cwd()?;
+            let remote = match remote {
+                Some(remote) => remote,
+                None => {
+                    // Remote of the current branch, falling back to origin
+                    let config = config::Config::load(&repo)?;
+                    let branch = match refs::read(&repo, "HEAD")? {
+                        Some(refs::RefValue::Symbolic(head)) => head,
+                        _ => String::new(),
+                    };
+                    let branch = branch.strip_prefix("refs/heads/").unwrap_or_default();
+                    config
+                        .get(&format!("branch.{}.remote", branch))
+                        .unwrap_or("origin")
+                        .to_string()
+                }
+            };
+            let refspecs = refspecs
+                .iter()
+                .map(|r| remote::RefSpec::parse(r))
+                .collect::<error::Result<Vec<_>>>()?;
+            remote::Remote::load(&repo, &remote)?.push(&repo, &refspecs, force)?;
+        }
     }
     Ok(())
 }
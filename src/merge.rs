@@ -0,0 +1,744 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+use crate::attributes::Attributes;
+use crate::diff;
+use crate::error::{bail, Result};
+use crate::grep;
+use crate::index::{self, Index, IndexEntry};
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::pathname;
+use crate::submodule::{self, GITLINK_MODE};
+use crate::tree::Tree;
+use crate::GitRepo;
+
+/// Mode and object id of a tree entry
+pub type Entry = (u32, ObjectId);
+
+const MARKER_LEN: usize = 7;
+
+/// Whether merge may just move the branch when merged commit is its descendant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FastForward {
+    /// Fast-forward when possible, create merge commit otherwise
+    #[default]
+    Allow,
+    /// Fail unless fast-forward is possible
+    Only,
+    /// Always create merge commit
+    Never,
+}
+
+/// What `reset` updates besides the current branch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResetMode {
+    /// Only move the branch, keeping the index and working tree
+    Soft,
+    /// Reset the index too, keeping changes in the working tree
+    #[default]
+    Mixed,
+    /// Reset the index and working tree, discarding all local changes
+    Hard,
+}
+
+/// Result of line based three-way merge of file content
+pub struct BlobMerge {
+    pub content: Vec<u8>,
+    /// Number of conflicting hunks marked in the content
+    pub conflicts: usize,
+}
+
+/// Labels shown after conflict markers
+pub struct Labels<'a> {
+    pub ours: &'a str,
+    pub theirs: &'a str,
+}
+
+fn push_lines(out: &mut Vec<u8>, lines: &[&[u8]]) {
+    for line in lines {
+        out.extend(*line);
+    }
+}
+
+fn push_marker(out: &mut Vec<u8>, marker: u8, label: &str) {
+    if out.last().is_some_and(|b| *b != b'\n') {
+        out.push(b'\n');
+    }
+    out.extend(std::iter::repeat_n(marker, MARKER_LEN));
+    if !label.is_empty() {
+        out.push(b' ');
+        out.extend(label.as_bytes());
+    }
+    out.push(b'\n');
+}
+
+/// Merges changes from `base` to `ours` and from `base` to `theirs` (diff3 algorithm).
+/// Overlapping different changes are wrapped in conflict markers.
+pub fn merge_blobs(base: &[u8], ours: &[u8], theirs: &[u8], labels: &Labels) -> BlobMerge {
+    let (base, ours, theirs) = (diff::lines(base), diff::lines(ours), diff::lines(theirs));
+    // For every base line index of the matching line on each side
+    let mut in_ours = vec![None; base.len()];
+    for (b, o) in diff::matching(&base, &ours) {
+        in_ours[b] = Some(o);
+    }
+    let mut in_theirs = vec![None; base.len()];
+    for (b, t) in diff::matching(&base, &theirs) {
+        in_theirs[b] = Some(t);
+    }
+
+    let mut content = Vec::new();
+    let mut conflicts = 0;
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    loop {
+        // Stable region where all three versions agree
+        while b < base.len() && in_ours[b] == Some(o) && in_theirs[b] == Some(t) {
+            content.extend(base[b]);
+            b += 1;
+            o += 1;
+            t += 1;
+        }
+        // Next base line present in both sides ends the unstable region
+        let (next_b, next_o, next_t) = (b..base.len())
+            .find_map(|i| Some((i, in_ours[i]?, in_theirs[i]?)))
+            .unwrap_or((base.len(), ours.len(), theirs.len()));
+        let (base_chunk, ours_chunk, theirs_chunk) =
+            (&base[b..next_b], &ours[o..next_o], &theirs[t..next_t]);
+        if ours_chunk == base_chunk || ours_chunk == theirs_chunk {
+            push_lines(&mut content, theirs_chunk);
+        } else if theirs_chunk == base_chunk {
+            push_lines(&mut content, ours_chunk);
+        } else {
+            conflicts += 1;
+            push_marker(&mut content, b'<', labels.ours);
+            push_lines(&mut content, ours_chunk);
+            push_marker(&mut content, b'=', "");
+            push_lines(&mut content, theirs_chunk);
+            push_marker(&mut content, b'>', labels.theirs);
+        }
+        (b, o, t) = (next_b, next_o, next_t);
+        if b == base.len() && o == ours.len() && t == theirs.len() {
+            break;
+        }
+    }
+    BlobMerge { content, conflicts }
+}
+
+/// Kind of conflict, determines the message shown to the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Both sides changed the same lines, or binary content
+    Content,
+    /// Both sides added the path with different content
+    AddAdd,
+    /// One side modified the path and the other deleted it
+    ModifyDelete,
+}
+
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub path: Vec<u8>,
+    pub kind: ConflictKind,
+    pub base: Option<Entry>,
+    pub ours: Option<Entry>,
+    pub theirs: Option<Entry>,
+    /// Content to leave in the working tree, e.g. with conflict markers
+    pub worktree: Option<Entry>,
+}
+
+impl Conflict {
+    /// Message in the format used by git
+    pub fn message(&self, labels: &Labels) -> String {
+        let path = pathname::display(&self.path);
+        match self.kind {
+            ConflictKind::Content => format!("CONFLICT (content): Merge conflict in {}", path),
+            ConflictKind::AddAdd => format!("CONFLICT (add/add): Merge conflict in {}", path),
+            ConflictKind::ModifyDelete => {
+                let (deleted, modified) = if self.ours.is_none() {
+                    (labels.ours, labels.theirs)
+                } else {
+                    (labels.theirs, labels.ours)
+                };
+                format!(
+                    "CONFLICT (modify/delete): {} deleted in {} and modified in {}.  Version {} of {} left in tree.",
+                    path, deleted, modified, modified, path
+                )
+            }
+        }
+    }
+}
+
+/// Result of three-way tree merge, nothing is written to the working tree yet
+pub struct TreeMerge {
+    /// Cleanly merged entries
+    pub entries: BTreeMap<Vec<u8>, Entry>,
+    pub conflicts: Vec<Conflict>,
+}
+
+fn read_blob(repo: &GitRepo, id: &ObjectId) -> Result<Vec<u8>> {
+    Ok(Object::read(repo, id)?.data)
+}
+
+fn write_blob(repo: &GitRepo, content: Vec<u8>) -> Result<ObjectId> {
+    let obj = Object::new(repo.hash_algo(), ObjectKind::Blob, content);
+    obj.write(repo)?;
+    Ok(obj.hash)
+}
+
+fn is_regular(mode: u32) -> bool {
+    mode == 0o100644 || mode == 0o100755
+}
+
+/// Merges single path changed on both sides since the base
+fn merge_entry(
+    repo: &GitRepo,
+    path: &[u8],
+    base: Option<&Entry>,
+    ours: Option<&Entry>,
+    theirs: Option<&Entry>,
+    labels: &Labels,
+) -> Result<std::result::Result<Entry, Conflict>> {
+    let conflict = |kind, worktree| Conflict {
+        path: path.to_vec(),
+        kind,
+        base: base.cloned(),
+        ours: ours.cloned(),
+        theirs: theirs.cloned(),
+        worktree,
+    };
+    let (ours_entry, theirs_entry) = match (ours, theirs) {
+        (Some(ours), Some(theirs)) => (ours, theirs),
+        // Deleted on one side, modified on the other
+        (Some(kept), None) | (None, Some(kept)) => {
+            return Ok(Err(conflict(ConflictKind::ModifyDelete, Some(*kept))));
+        }
+        (None, None) => unreachable!("Unchanged deletion is resolved trivially"),
+    };
+    let kind = if base.is_some() {
+        ConflictKind::Content
+    } else {
+        ConflictKind::AddAdd
+    };
+    let mode = match base {
+        Some((base_mode, _)) if *base_mode == ours_entry.0 => theirs_entry.0,
+        _ => ours_entry.0,
+    };
+    if !is_regular(ours_entry.0) || !is_regular(theirs_entry.0) {
+        return Ok(Err(conflict(kind, Some(*ours_entry))));
+    }
+    if ours_entry.1 == theirs_entry.1 {
+        // Only the mode differs
+        return Ok(Ok((mode, ours_entry.1)));
+    }
+
+    let base_content = match base {
+        Some((base_mode, id)) if is_regular(*base_mode) => read_blob(repo, id)?,
+        _ => Vec::new(),
+    };
+    let ours_content = read_blob(repo, &ours_entry.1)?;
+    let theirs_content = read_blob(repo, &theirs_entry.1)?;
+    if [&base_content, &ours_content, &theirs_content]
+        .iter()
+        .any(|c| diff::is_binary(c))
+    {
+        return Ok(Err(conflict(kind, Some(*ours_entry))));
+    }
+
+    let merged = merge_blobs(&base_content, &ours_content, &theirs_content, labels);
+    let id = write_blob(repo, merged.content)?;
+    if merged.conflicts > 0 {
+        Ok(Err(conflict(kind, Some((mode, id)))))
+    } else {
+        Ok(Ok((mode, id)))
+    }
+}
+
+/// Three-way merge of trees. Missing base tree means histories without common ancestor.
+pub fn merge_trees(
+    repo: &GitRepo,
+    base: Option<&ObjectId>,
+    ours: &ObjectId,
+    theirs: &ObjectId,
+    labels: &Labels,
+) -> Result<TreeMerge> {
+    let mut base = match base {
+        Some(id) => Tree::flatten(repo, id)?,
+        None => BTreeMap::new(),
+    };
+    let mut ours = Tree::flatten(repo, ours)?;
+    let mut theirs = Tree::flatten(repo, theirs)?;
+
+    // Move entries to new names so that changes to the old path from one side merge with
+    // the rename on the other side
+    let our_renames = renames(repo, &base, &ours)?;
+    let their_renames = renames(repo, &base, &theirs)?;
+    follow_renames(&mut base, &mut theirs, &our_renames, &their_renames);
+    follow_renames(&mut base, &mut ours, &their_renames, &our_renames);
+    let paths: BTreeSet<&Vec<u8>> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+
+    let mut result = TreeMerge {
+        entries: BTreeMap::new(),
+        conflicts: Vec::new(),
+    };
+    for path in paths {
+        let (b, o, t) = (base.get(path), ours.get(path), theirs.get(path));
+        let merged = if o == t || b == t {
+            o.cloned()
+        } else if b == o {
+            t.cloned()
+        } else {
+            match merge_entry(repo, path, b, o, t, labels)? {
+                Ok(entry) => Some(entry),
+                Err(conflict) => {
+                    result.conflicts.push(conflict);
+                    None
+                }
+            }
+        };
+        if let Some(entry) = merged {
+            result.entries.insert(path.clone(), entry);
+        }
+    }
+
+    let worktree_paths: BTreeSet<&[u8]> = result
+        .entries
+        .keys()
+        .map(Vec::as_slice)
+        .chain(result.conflicts.iter().map(|c| c.path.as_slice()))
+        .collect();
+    for path in &worktree_paths {
+        let dir = pathname::concat(path, b"");
+        if worktree_paths
+            .range(dir.as_slice()..)
+            .next()
+            .is_some_and(|p| p.starts_with(&dir))
+        {
+            bail!(
+                "Merge of {}: directory/file conflicts are not supported",
+                pathname::display(path)
+            );
+        }
+    }
+    Ok(result)
+}
+
+/// Renamed paths between base and one side, old name to new name
+fn renames(
+    repo: &GitRepo,
+    base: &BTreeMap<Vec<u8>, Entry>,
+    side: &BTreeMap<Vec<u8>, Entry>,
+) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let changes = diff::changes(base, side);
+    let changes = diff::detect_renames(repo, changes, &diff::Renames::default(), false)?;
+    Ok(changes
+        .into_iter()
+        .filter_map(|change| Some((change.rename?.from, change.path)))
+        .collect())
+}
+
+/// Applies renames made on one side to base and the other side, unless the other side
+/// renamed the path differently or already has a file with the new name
+fn follow_renames(
+    base: &mut BTreeMap<Vec<u8>, Entry>,
+    other: &mut BTreeMap<Vec<u8>, Entry>,
+    renames: &BTreeMap<Vec<u8>, Vec<u8>>,
+    other_renames: &BTreeMap<Vec<u8>, Vec<u8>>,
+) {
+    for (from, to) in renames {
+        let applies = match other_renames.get(from) {
+            Some(other_to) => other_to == to,
+            None => !other.contains_key(to),
+        };
+        if !applies {
+            continue;
+        }
+        if let Some(entry) = base.remove(from) {
+            base.insert(to.clone(), entry);
+        }
+        if !other_renames.contains_key(from) {
+            if let Some(entry) = other.remove(from) {
+                other.insert(to.clone(), entry);
+            }
+        }
+    }
+}
+
+fn worktree_matches(
+    repo: &GitRepo,
+    index: &Index,
+    attributes: &mut Attributes,
+    path: &[u8],
+    expected: Option<&Entry>,
+) -> Result<bool> {
+    let full_path = repo.worktree_path(path);
+    let Some((mode, id)) = expected else {
+        return Ok(fs::symlink_metadata(&full_path).is_err());
+    };
+    if *mode == GITLINK_MODE {
+        return Ok(submodule::head(repo, path)?.is_none_or(|head| head == *id));
+    }
+    match index.get(path, 0) {
+        Some(entry) if entry.id != *id => return Ok(false),
+        Some(entry) if Index::is_fresh(entry, &full_path) => return Ok(true),
+        _ => {}
+    }
+    Ok(
+        match index::worktree_file_with(repo, path, index, attributes)? {
+            Some((_, data)) => Object::new(repo.hash_algo(), ObjectKind::Blob, data).hash == *id,
+            None => false,
+        },
+    )
+}
+
+/// Removes file from the working tree together with directories left empty. Directory
+/// of submodule is removed only when empty.
+pub fn remove_file(repo: &GitRepo, path: &[u8]) -> Result<()> {
+    let full_path = repo.worktree_path(path);
+    match fs::symlink_metadata(&full_path) {
+        Ok(metadata) if metadata.is_dir() => {
+            if fs::remove_dir(&full_path).is_err() {
+                return Ok(());
+            }
+        }
+        Ok(_) => fs::remove_file(&full_path)?,
+        Err(_) => {}
+    }
+    let mut dir = full_path.parent();
+    while let Some(d) = dir {
+        if d == repo.repo_root || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+    Ok(())
+}
+
+/// Moves working tree and index from `current` tree content to the merge result.
+/// Fails without touching anything if local changes would be overwritten.
+pub fn update_worktree(
+    repo: &GitRepo,
+    current: &BTreeMap<Vec<u8>, Entry>,
+    merge: &TreeMerge,
+) -> Result<()> {
+    let old_index = Index::read(repo)?;
+    if let Some(path) = old_index.conflicts().first() {
+        bail!(
+            "{}: needs merge, resolve conflicts first",
+            pathname::display(path)
+        );
+    }
+    // Missing index (e.g. right after clone) has nothing staged
+    let staged = old_index.to_map();
+    if !old_index.entries.is_empty() && staged != *current {
+        let paths: BTreeSet<&Vec<u8>> = staged
+            .iter()
+            .chain(current.iter())
+            .filter(|(path, entry)| {
+                staged.get(*path) != Some(*entry) || current.get(*path) != Some(*entry)
+            })
+            .map(|(path, _)| path)
+            .collect();
+        let paths: Vec<_> = paths.into_iter().map(|p| pathname::display(p)).collect();
+        bail!(
+            "Your index contains uncommitted changes:\n\t{}\nPlease commit your changes or stash them before you merge.",
+            paths.join("\n\t")
+        );
+    }
+
+    let mut target: BTreeMap<&[u8], Option<&Entry>> = BTreeMap::new();
+    for path in current.keys() {
+        target.insert(path, None);
+    }
+    for (path, entry) in &merge.entries {
+        target.insert(path, Some(entry));
+    }
+    for conflict in &merge.conflicts {
+        target.insert(&conflict.path, conflict.worktree.as_ref());
+    }
+    let changed: Vec<(&[u8], Option<&Entry>)> = target
+        .into_iter()
+        .filter(|(path, entry)| current.get(*path) != *entry)
+        .collect();
+
+    let mut attributes = Attributes::load(repo)?;
+    let mut dirty = Vec::new();
+    for (path, _) in &changed {
+        if !worktree_matches(repo, &old_index, &mut attributes, path, current.get(*path))? {
+            dirty.push(pathname::display(path));
+        }
+    }
+    if !dirty.is_empty() {
+        bail!(
+            "Your local changes to the following files would be overwritten by merge:\n\t{}\nPlease commit your changes or stash them before you merge.",
+            dirty.join("\n\t")
+        );
+    }
+
+    let mut written = BTreeSet::new();
+    for (path, entry) in &changed {
+        match entry {
+            Some((mode, id)) => {
+                let full_path = repo.worktree_path(path);
+                Tree::checkout_blob(repo, &full_path, *mode, id, &mut attributes)?;
+                written.insert(*path);
+            }
+            None => remove_file(repo, path)?,
+        }
+    }
+
+    let mut index = Index::default();
+    for (path, (mode, id)) in &merge.entries {
+        let entry = match old_index.get(path, 0) {
+            Some(old)
+                if !written.contains(path.as_slice()) && old.mode == *mode && old.id == *id =>
+            {
+                old.clone()
+            }
+            _ => match fs::symlink_metadata(repo.worktree_path(path)) {
+                Ok(metadata) if written.contains(path.as_slice()) => {
+                    IndexEntry::from_metadata(path, *mode, id, &metadata)
+                }
+                _ => IndexEntry::new(path, *mode, id, 0),
+            },
+        };
+        index.add(entry);
+    }
+    for conflict in &merge.conflicts {
+        for (stage, entry) in [
+            (1, &conflict.base),
+            (2, &conflict.ours),
+            (3, &conflict.theirs),
+        ] {
+            if let Some((mode, id)) = entry {
+                index.add(IndexEntry::new(&conflict.path, *mode, id, stage));
+            }
+        }
+    }
+    index.write(repo)
+}
+
+/// Whether there is untracked file at the path or, for directory of tracked files, below
+/// it
+fn in_the_way(repo: &GitRepo, path: &[u8], tracked: &BTreeMap<Vec<u8>, Entry>) -> Result<bool> {
+    let full_path = repo.worktree_path(path);
+    match fs::symlink_metadata(&full_path) {
+        Ok(metadata) if metadata.is_dir() => {
+            for item in fs::read_dir(&full_path)? {
+                let name = pathname::from_os(&item?.file_name());
+                let path = pathname::concat(path, &name);
+                if !tracked.contains_key(&path) && in_the_way(repo, &path, tracked)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        Ok(_) => Ok(!tracked.contains_key(path)),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Switches working tree and index from `current` tree content to `target`. Local
+/// changes to paths which are the same in both trees are kept, ones which would be
+/// overwritten or deleted make it fail without touching anything, as do untracked files
+/// in the way. Forced checkout discards all local changes.
+pub fn checkout_worktree(
+    repo: &GitRepo,
+    current: &BTreeMap<Vec<u8>, Entry>,
+    target: &BTreeMap<Vec<u8>, Entry>,
+    force: bool,
+) -> Result<()> {
+    if force {
+        return reset_worktree(repo, current, target);
+    }
+    let mut index = Index::read(repo)?;
+    if let Some(path) = index.conflicts().first() {
+        bail!(
+            "{}: needs merge, you need to resolve your current index first",
+            pathname::display(path)
+        );
+    }
+    // Missing index (e.g. right after clone) has nothing staged
+    let staged = if index.entries.is_empty() {
+        current.clone()
+    } else {
+        index.to_map()
+    };
+    let paths: BTreeSet<&Vec<u8>> = current.keys().chain(target.keys()).collect();
+    let mut attributes = Attributes::load(repo)?;
+    let mut changed = Vec::new();
+    let (mut modified, mut untracked) = (Vec::new(), Vec::new());
+    for path in paths {
+        let (old, new) = (current.get(path), target.get(path));
+        // Staged content equal to the target is already in place
+        if old == new || staged.get(path) == new {
+            continue;
+        }
+        if staged.get(path) != old {
+            modified.push(pathname::display(path));
+        } else if old.is_none() {
+            if in_the_way(repo, path, current)? {
+                untracked.push(pathname::display(path));
+            }
+        } else if !worktree_matches(repo, &index, &mut attributes, path, old)? {
+            modified.push(pathname::display(path));
+        }
+        changed.push((path.as_slice(), new));
+    }
+    if !modified.is_empty() {
+        bail!(
+            "Your local changes to the following files would be overwritten by checkout:\n\t{}\nPlease commit your changes or stash them before you switch branches.\nAborting",
+            modified.join("\n\t")
+        );
+    }
+    if !untracked.is_empty() {
+        bail!(
+            "The following untracked working tree files would be overwritten by checkout:\n\t{}\nPlease move or remove them before you switch branches.\nAborting",
+            untracked.join("\n\t")
+        );
+    }
+
+    if index.entries.is_empty() {
+        for (path, (mode, id)) in current {
+            index.add(IndexEntry::new(path, *mode, id, 0));
+        }
+    }
+    // Removed first, so that files can replace directories
+    for (path, _) in changed.iter().filter(|(_, entry)| entry.is_none()) {
+        index.remove(path);
+        remove_file(repo, path)?;
+    }
+    for (path, entry) in changed {
+        let Some((mode, id)) = entry else {
+            continue;
+        };
+        let full_path = repo.worktree_path(path);
+        Tree::checkout_blob(repo, &full_path, *mode, id, &mut attributes)?;
+        let metadata = fs::symlink_metadata(&full_path)?;
+        index.remove(path);
+        index.add(IndexEntry::from_metadata(path, *mode, id, &metadata));
+    }
+    index.write(repo)
+}
+
+/// Replaces index content with `target`, leaving the working tree untouched. Stat
+/// information of entries which stay the same is kept.
+pub fn reset_index(repo: &GitRepo, target: &BTreeMap<Vec<u8>, Entry>) -> Result<()> {
+    let old_index = Index::read(repo)?;
+    let mut index = Index::default();
+    for (path, (mode, id)) in target {
+        match old_index.get(path, 0) {
+            Some(old) if old.mode == *mode && old.id == *id => index.add(old.clone()),
+            _ => index.add(IndexEntry::new(path, *mode, id, 0)),
+        }
+    }
+    index.write(repo)
+}
+
+/// Forcefully replaces working tree and index content with `target`, discarding local
+/// changes and conflicts. Files tracked in `current` tree or in the index are removed
+/// when missing in the target.
+pub fn reset_worktree(
+    repo: &GitRepo,
+    current: &BTreeMap<Vec<u8>, Entry>,
+    target: &BTreeMap<Vec<u8>, Entry>,
+) -> Result<()> {
+    let old_index = Index::read(repo)?;
+    let tracked: BTreeSet<&[u8]> = current
+        .keys()
+        .map(Vec::as_slice)
+        .chain(old_index.entries.iter().map(|e| e.path.as_slice()))
+        .collect();
+    for path in tracked {
+        if !target.contains_key(path) {
+            remove_file(repo, path)?;
+        }
+    }
+
+    let mut attributes = Attributes::load(repo)?;
+    let mut index = Index::default();
+    for (path, (mode, id)) in target {
+        let full_path = repo.worktree_path(path);
+        match old_index.get(path, 0) {
+            Some(old) if old.mode == *mode && old.id == *id && Index::is_fresh(old, &full_path) => {
+                index.add(old.clone());
+            }
+            _ => {
+                Tree::checkout_blob(repo, &full_path, *mode, id, &mut attributes)?;
+                let metadata = fs::symlink_metadata(&full_path)?;
+                index.add(IndexEntry::from_metadata(path, *mode, id, &metadata));
+            }
+        }
+    }
+    index.write(repo)
+}
+
+/// Restores files at the pathspecs from `source`, into the index when `staged` and into
+/// the working tree when `worktree`. Tracked files missing in the source are removed,
+/// unless in overlay mode which only writes files of the source.
+pub fn restore(
+    repo: &GitRepo,
+    source: &BTreeMap<Vec<u8>, Entry>,
+    pathspecs: &[Vec<u8>],
+    staged: bool,
+    worktree: bool,
+    overlay: bool,
+) -> Result<()> {
+    let mut index = Index::read(repo)?;
+    let mut candidates: BTreeSet<Vec<u8>> = source.keys().cloned().collect();
+    if !overlay {
+        candidates.extend(index.entries.iter().map(|e| e.path.clone()));
+    }
+    for spec in pathspecs {
+        let spec = std::slice::from_ref(spec);
+        if !candidates
+            .iter()
+            .any(|path| grep::matches_pathspec(path, spec))
+        {
+            bail!(
+                "pathspec '{}' did not match any file(s) known to git",
+                pathname::display(&spec[0])
+            );
+        }
+    }
+    let selected = candidates
+        .into_iter()
+        .filter(|path| grep::matches_pathspec(path, pathspecs));
+    let mut attributes = Attributes::load(repo)?;
+
+    for path in selected {
+        let unmerged =
+            index.get(&path, 0).is_none() && index.entries.iter().any(|e| e.path == path);
+        if unmerged && !staged {
+            bail!("path '{}' is unmerged", pathname::display(&path));
+        }
+        let Some((mode, id)) = source.get(&path) else {
+            if worktree {
+                remove_file(repo, &path)?;
+            }
+            if staged {
+                index.remove(&path);
+            }
+            continue;
+        };
+        let mut entry = IndexEntry::new(&path, *mode, id, 0);
+        if worktree && *mode != GITLINK_MODE {
+            let full_path = repo.worktree_path(&path);
+            Tree::checkout_blob(repo, &full_path, *mode, id, &mut attributes)?;
+            let metadata = fs::symlink_metadata(&full_path)?;
+            entry = IndexEntry::from_metadata(&path, *mode, id, &metadata);
+        }
+        let same = |old: &IndexEntry| old.mode == *mode && old.id == *id;
+        if staged {
+            index.remove(&path);
+            index.add(entry);
+        } else if index.get(&path, 0).is_some_and(same) {
+            // Stat of the rewritten file, so that it is not reported as modified
+            index.add(entry);
+        }
+    }
+    index.write(repo)
+}
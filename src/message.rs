@@ -0,0 +1,171 @@
+use std::str::FromStr;
+
+use crate::error::{bail, Error, Result};
+
+/// Line marking the rest of the message as not part of it, under `scissors` cleanup
+const SCISSORS: &str = "------------------------ >8 ------------------------";
+
+/// Trailers git adds itself, their presence makes looser paragraph a trailer block
+const GIT_TRAILER_PREFIXES: [&str; 2] = ["Signed-off-by: ", "(cherry picked from commit "];
+
+/// How commit messages are cleaned up, `--cleanup` of `commit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cleanup {
+    /// Strip whitespace and remove comment lines
+    Strip,
+    /// Strip trailing whitespace of lines, leading and trailing blank lines and collapse
+    /// consecutive blank lines
+    Whitespace,
+    /// Keep the message as is
+    Verbatim,
+    /// Like whitespace, also removes everything from the scissors line
+    Scissors,
+    /// Whitespace, as messages are not edited
+    #[default]
+    Default,
+}
+
+impl FromStr for Cleanup {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        Ok(match mode {
+            "strip" => Self::Strip,
+            "whitespace" => Self::Whitespace,
+            "verbatim" => Self::Verbatim,
+            "scissors" => Self::Scissors,
+            "default" => Self::Default,
+            _ => bail!("Invalid cleanup mode {}", mode),
+        })
+    }
+}
+
+/// Cleans up the message, lines starting with the comment char are comments. Non-empty
+/// result ends with newline.
+pub fn cleanup(message: &str, mode: Cleanup, comment_char: char) -> String {
+    let mut message = message;
+    match mode {
+        Cleanup::Verbatim => return message.to_string(),
+        Cleanup::Scissors => {
+            let scissors = format!("{} {}", comment_char, SCISSORS);
+            let mut pos = 0;
+            for line in message.split_inclusive('\n') {
+                if line.trim_end() == scissors {
+                    message = &message[..pos];
+                    break;
+                }
+                pos += line.len();
+            }
+        }
+        _ => {}
+    }
+    let mut out = String::new();
+    let mut blank = false;
+    for line in message.lines() {
+        if mode == Cleanup::Strip && line.starts_with(comment_char) {
+            continue;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if blank {
+            out.push('\n');
+            blank = false;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Key of the trailer line, e.g. `Signed-off-by` of `Signed-off-by: A U Thor <a@b>`
+fn trailer_key(line: &str) -> Option<&str> {
+    let (key, _) = line.split_once(':')?;
+    let key = key.trim_end();
+    let valid = !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '-');
+    valid.then_some(key)
+}
+
+/// Byte range of the trailer block, the last paragraph of the message body made of
+/// `Key: value` lines. Paragraph with some other lines still counts when it contains
+/// trailer git adds itself and at least quarter of its lines are trailers.
+fn trailer_block(message: &str) -> Option<(usize, usize)> {
+    let end = message.trim_end().len();
+    let start = message[..end].rfind("\n\n")? + 2;
+    // The first paragraph is the subject
+    if message[..start].trim().is_empty() {
+        return None;
+    }
+    let (mut trailers, mut others, mut recognized) = (0, 0, false);
+    for line in message[start..end].lines() {
+        // Indented lines continue the previous one
+        if line.starts_with([' ', '\t']) {
+            continue;
+        }
+        if GIT_TRAILER_PREFIXES.iter().any(|p| line.starts_with(p)) {
+            recognized = true;
+            trailers += 1;
+        } else if trailer_key(line).is_some() {
+            trailers += 1;
+        } else {
+            others += 1;
+        }
+    }
+    let is_block = trailers > 0 && (others == 0 || (recognized && trailers * 3 >= others));
+    is_block.then_some((start, end))
+}
+
+/// Trailers of the message as keys and values
+pub fn trailers(message: &str) -> Vec<(String, String)> {
+    let Some((start, end)) = trailer_block(message) else {
+        return Vec::new();
+    };
+    let mut trailers: Vec<(String, String)> = Vec::new();
+    for line in message[start..end].lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = trailers.last_mut() {
+                value.push('\n');
+                value.push_str(line);
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            if trailer_key(line).is_some() {
+                trailers.push((key.trim_end().to_string(), value.trim().to_string()));
+            }
+        }
+    }
+    trailers
+}
+
+/// Parses trailer given as `key=value` or `key: value`
+pub fn parse_trailer(trailer: &str) -> Result<(String, String)> {
+    let Some(pos) = trailer.find([':', '=']) else {
+        return Ok((trailer.trim().to_string(), String::new()));
+    };
+    let key = trailer[..pos].trim();
+    if key.is_empty() || trailer_key(&format!("{}:", key)).is_none() {
+        bail!("invalid trailer '{}'", trailer);
+    }
+    Ok((key.to_string(), trailer[pos + 1..].trim().to_string()))
+}
+
+/// Appends the trailer to the trailer block, starting one after the message when there
+/// is none. Trailer equal to the last one is not added again.
+pub fn add_trailer(message: &str, key: &str, value: &str) -> String {
+    let line = format!("{}: {}", key, value);
+    let line = line.trim_end();
+    let block = trailer_block(message);
+    let Some((start, end)) = block else {
+        let body = message.trim_end();
+        return if body.is_empty() {
+            format!("{}\n", line)
+        } else {
+            format!("{}\n\n{}\n", body, line)
+        };
+    };
+    if message[start..end].lines().last() == Some(line) {
+        return message.to_string();
+    }
+    format!("{}\n{}\n", &message[..end], line)
+}
@@ -1,40 +1,46 @@
-use std::fs::{self, File};
+use std::collections::BTreeSet;
+use std::fs;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use anyhow::{anyhow, bail, ensure, Context, Result};
-use flate2::read::ZlibDecoder;
-use flate2::write::ZlibEncoder;
-use flate2::Compression;
-use sha1::{Digest, Sha1};
+use crate::error::{bail, ensure, format_err, Context, Error, Result};
+use crate::hash::{HashAlgo, Hasher};
+use crate::odb::{LooseOdb, Odb, PackOdb};
+use crate::oid::ObjectId;
+use crate::storage::WriteOptions;
+use crate::{pack, GitRepo};
+use crate::tree::{FileMode, Tree};
 
-use crate::GitRepo;
-use crate::commit::Commit;
-use crate::tree::Tree;
+/// Minimal length of abbreviated object hash
+pub const MIN_ABBREV: usize = 4;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ObjectKind {
     Blob,
     Commit,
     Tree,
+    Tag,
 }
 impl ObjectKind {
-    fn as_str(&self) -> &'static str {
+    pub fn as_str(&self) -> &'static str {
         match self {
             ObjectKind::Blob => "blob",
             ObjectKind::Commit => "commit",
             ObjectKind::Tree => "tree",
+            ObjectKind::Tag => "tag",
         }
     }
 }
 impl FromStr for ObjectKind {
-    type Err = anyhow::Error;
+    type Err = Error;
 
     fn from_str(value: &str) -> Result<Self> {
         Ok(match value {
             "blob" => Self::Blob,
             "commit" => Self::Commit,
             "tree" => Self::Tree,
+            "tag" => Self::Tag,
             _ => bail!("Unrecognized object kind {:?}", value),
         })
     }
@@ -46,101 +52,351 @@ pub struct ObjectHeader {
     pub data_length: usize,
 }
 impl TryFrom<Vec<u8>> for ObjectHeader {
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn try_from(value: Vec<u8>) -> Result<Self> {
         let header_str = std::str::from_utf8(&value).context("Parsing header bytes")?;
         let (data_type, data_length) = header_str
             .rsplit_once(' ')
-            .ok_or(anyhow!("Invalid header format"))?;
+            .ok_or(format_err!("Invalid header format"))?;
         let kind = data_type.parse()?;
         let data_length = data_length.parse()?;
         Ok(Self { kind, data_length })
     }
 }
 impl ObjectHeader {
-    fn as_str(&self) -> String {
+    pub(crate) fn as_str(&self) -> String {
         format!("{} {}\0", self.kind.as_str(), self.data_length)
     }
 }
 
-#[derive(Debug)]
-pub struct Object {
-    pub hash: String,
+/// Content of an object read incrementally, for blobs too large to hold in memory
+pub struct ObjectReader {
+    pub hash: ObjectId,
     pub header: ObjectHeader,
-    pub data: Vec<u8>,
+    content: Box<dyn Read + Send>,
+}
+
+impl ObjectReader {
+    pub(crate) fn new(hash: ObjectId, header: ObjectHeader, content: Box<dyn Read + Send>) -> Self {
+        Self {
+            hash,
+            header,
+            content,
+        }
+    }
 }
 
-impl From<Tree> for Object {
-    fn from(tree: Tree) -> Self {
-        Self::new(ObjectKind::Tree, tree.to_bytes())
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.content.read(buf)
     }
 }
-impl From<Commit> for Object {
-    fn from(commit: Commit) -> Self {
-        Self::new(ObjectKind::Commit, commit.to_bytes())
+
+impl std::fmt::Debug for ObjectReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObjectReader")
+            .field("hash", &self.hash)
+            .field("header", &self.header)
+            .finish_non_exhaustive()
     }
 }
-impl TryFrom<File> for Object {
-    type Error = anyhow::Error;
 
-    fn try_from(mut file: File) -> Result<Self> {
-        let mut data = Vec::new();
-        file.read_to_end(&mut data).context("Reading input file")?;
-        Ok(Self::new(ObjectKind::Blob, data))
+/// Copies exactly `size` bytes from the reader to the writer, hashing them on the way
+pub(crate) fn copy_hashing(
+    mut reader: impl Read,
+    writer: &mut impl Write,
+    hasher: &mut Hasher,
+    size: u64,
+) -> Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = buf.len().min(remaining as usize);
+        let read = reader.read(&mut buf[..len])?;
+        ensure!(read > 0, "Content is shorter than {} bytes", size);
+        hasher.update(&buf[..read]);
+        writer.write_all(&buf[..read])?;
+        remaining -= read as u64;
     }
+    ensure!(
+        reader.read(&mut buf[..1])? == 0,
+        "Content is longer than {} bytes",
+        size
+    );
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub hash: ObjectId,
+    pub header: ObjectHeader,
+    pub data: Vec<u8>,
 }
 
 impl Object {
-    pub fn new(kind: ObjectKind, data: Vec<u8>) -> Self {
+    /// Object named by its hash computed with the algorithm
+    pub fn new(algo: HashAlgo, kind: ObjectKind, data: Vec<u8>) -> Self {
         let header = ObjectHeader {
             kind,
             data_length: data.len(),
         };
 
-        let mut hasher = Sha1::new();
+        let mut hasher = algo.hasher();
         hasher.update(header.as_str());
         hasher.update(&data);
 
-        let hash = hex::encode(hasher.finalize());
+        let hash = hasher.finalize();
 
         Self { hash, header, data }
     }
 
-    pub fn read(repo: &GitRepo, hash: String) -> Result<Self> {
+    /// Reads object by its id, use [`Object::expand_prefix`] for abbreviated hashes
+    pub fn read(repo: &GitRepo, id: &ObjectId) -> Result<Self> {
+        let found = match repo.odb() {
+            Some(odb) => odb.read(id)?,
+            None => Self::read_from_disk(repo, id)?,
+        };
+        found.ok_or(Error::ObjectNotFound(id.to_hex()))
+    }
+
+    /// Opens object for reading its content incrementally. Loose objects are streamed,
+    /// packed ones and objects of custom object store are read whole first.
+    pub fn reader(repo: &GitRepo, id: &ObjectId) -> Result<ObjectReader> {
+        if repo.odb().is_none() {
+            for objects_dir in repo.object_dirs() {
+                let loose = LooseOdb::new(objects_dir, WriteOptions::default());
+                if let Some(reader) = loose.reader(id)? {
+                    return Ok(reader);
+                }
+            }
+        }
+        let obj = Self::read(repo, id)?;
+        let content = std::io::Cursor::new(obj.data);
+        Ok(ObjectReader::new(obj.hash, obj.header, Box::new(content)))
+    }
+
+    /// Kind and size of the object without reading its content whole
+    pub fn read_header(repo: &GitRepo, id: &ObjectId) -> Result<ObjectHeader> {
+        Ok(Self::reader(repo, id)?.header)
+    }
+
+    /// Hash of object with `size` bytes of content read from the reader
+    pub fn hash_from_reader(
+        algo: HashAlgo,
+        kind: ObjectKind,
+        size: u64,
+        reader: impl Read,
+    ) -> Result<ObjectId> {
+        let header = ObjectHeader {
+            kind,
+            data_length: size as usize,
+        };
+        let mut hasher = algo.hasher();
+        hasher.update(header.as_str());
+        copy_hashing(reader, &mut std::io::sink(), &mut hasher, size)?;
+        Ok(hasher.finalize())
+    }
+
+    /// Stores object with `size` bytes of content read from the reader as loose file,
+    /// without holding the content in memory. Custom object store gets it whole.
+    pub fn write_from_reader(
+        repo: &GitRepo,
+        kind: ObjectKind,
+        size: u64,
+        mut reader: impl Read,
+    ) -> Result<ObjectId> {
+        if repo.odb().is_some() {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            ensure!(
+                data.len() as u64 == size,
+                "Content is not {} bytes long",
+                size
+            );
+            let obj = Self::new(repo.hash_algo(), kind, data);
+            obj.write(repo)?;
+            return Ok(obj.hash);
+        }
+        LooseOdb::new(&repo.objects_dir, *repo.write_options()?).write_from_reader(
+            repo.hash_algo(),
+            kind,
+            size,
+            reader,
+        )
+    }
+
+    /// Loose objects of the repository and its alternates are tried before packs
+    fn read_from_disk(repo: &GitRepo, id: &ObjectId) -> Result<Option<Self>> {
+        for objects_dir in repo.object_dirs() {
+            let loose = LooseOdb::new(objects_dir, WriteOptions::default());
+            if let Some(obj) = loose.read(id)? {
+                return Ok(Some(obj));
+            }
+        }
+        PackOdb::new(repo).read(id)
+    }
+
+    pub fn exists(repo: &GitRepo, id: &ObjectId) -> bool {
+        if let Some(odb) = repo.odb() {
+            return odb.contains(id).unwrap_or(false);
+        }
+        let hash = id.to_hex();
         let (prefix, filename) = hash.split_at(2);
-        let file = File::open(repo.objects_dir.join(prefix).join(filename))
-            .context("Opening object file")?;
-        let mut decoder = ZlibDecoder::new(file);
-        let mut buf = Vec::new();
-        decoder
-            .read_to_end(&mut buf)
-            .context("Reading object file")?;
+        repo.object_dirs()
+            .iter()
+            .any(|dir| dir.join(prefix).join(filename).is_file())
+            || pack::contains(repo, id).unwrap_or(false)
+    }
 
-        let mut buf = buf.into_iter();
+    /// Resolves abbreviated hash to full id, failing when it is not unique. Full hashes
+    /// are only parsed, the object may not exist.
+    pub fn expand_prefix(repo: &GitRepo, prefix: &str) -> Result<ObjectId> {
+        if prefix.len() == repo.hash_algo().hex_len() {
+            return prefix.parse();
+        }
+        ensure!(
+            prefix.len() >= MIN_ABBREV,
+            "Object hash {:?} is too short",
+            prefix
+        );
+        let mut ids = Self::find_prefix(repo, prefix)?;
+        ensure!(ids.len() <= 1, Error::AmbiguousObject(prefix.to_string()));
+        ids.pop().ok_or(Error::ObjectNotFound(prefix.to_string()))
+    }
+
+    /// Shortest unique abbreviation of the id which is at least `min_len` long
+    pub fn abbreviate(repo: &GitRepo, id: &ObjectId, min_len: usize) -> Result<String> {
+        let hash = id.to_hex();
+        let mut len = min_len.clamp(MIN_ABBREV, hash.len());
+        while len < hash.len() && Self::find_prefix(repo, &hash[..len])?.len() > 1 {
+            len += 1;
+        }
+        Ok(hash[..len].to_string())
+    }
+
+    /// Ids of all objects starting with given hex prefix (at least 2 characters long)
+    pub fn find_prefix(repo: &GitRepo, prefix: &str) -> Result<Vec<ObjectId>> {
+        let prefix = prefix.to_ascii_lowercase();
+        ensure!(
+            prefix.len() >= 2
+                && prefix.len() <= repo.hash_algo().hex_len()
+                && prefix.bytes().all(|b| b.is_ascii_hexdigit()),
+            "Invalid object hash prefix {:?}",
+            prefix
+        );
+        if let Some(odb) = repo.odb() {
+            return Ok(odb
+                .iter()?
+                .filter(|id| id.to_hex().starts_with(&prefix))
+                .collect());
+        }
+        let (dir, rest) = prefix.split_at(2);
+        let mut ids = BTreeSet::new();
+        for objects_dir in repo.object_dirs() {
+            let dir_path = objects_dir.join(dir);
+            if !dir_path.is_dir() {
+                continue;
+            }
+            for item in fs::read_dir(dir_path)? {
+                let name = item?.file_name();
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+                if name.len() == repo.hash_algo().hex_len() - 2 && name.starts_with(rest) {
+                    if let Ok(id) = format!("{}{}", dir, name).parse() {
+                        ids.insert(id);
+                    }
+                }
+            }
+        }
+        ids.extend(pack::find_prefix(repo, &prefix)?);
+        Ok(ids.into_iter().collect())
+    }
 
-        let header = buf
-            .by_ref()
-            .take_while(|c| *c != b'\0')
-            .collect::<Vec<_>>()
-            .try_into()?;
+    /// Ids of all loose objects, sorted
+    pub fn loose_ids(repo: &GitRepo) -> Result<Vec<ObjectId>> {
+        Self::loose_ids_in(&repo.objects_dir)
+    }
 
-        let data = buf.collect();
+    /// Ids of all loose objects in the object directory, sorted
+    pub fn loose_ids_in(objects_dir: &Path) -> Result<Vec<ObjectId>> {
+        let Ok(dirs) = fs::read_dir(objects_dir) else {
+            return Ok(Vec::new());
+        };
+        let mut ids = BTreeSet::new();
+        for dir in dirs {
+            let dir = dir?;
+            let prefix = dir.file_name().to_string_lossy().into_owned();
+            if prefix.len() != 2 || !prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+                continue;
+            }
+            for item in fs::read_dir(dir.path())? {
+                let name = item?.file_name().to_string_lossy().into_owned();
+                // Either SHA-1 or SHA-256 id without the directory prefix
+                if let Ok(id) = format!("{}{}", prefix, name).parse() {
+                    ids.insert(id);
+                }
+            }
+        }
+        Ok(ids.into_iter().collect())
+    }
 
-        Ok(Self { hash, header, data })
+    /// Path of the loose object file
+    pub fn loose_path(repo: &GitRepo, id: &ObjectId) -> PathBuf {
+        let hash = id.to_hex();
+        repo.objects_dir.join(&hash[..2]).join(&hash[2..])
     }
 
+    /// Stores the object as loose file unless the repository already has it
     pub fn write(&self, repo: &GitRepo) -> Result<()> {
-        let (prefix, filename) = self.hash.split_at(2);
-        fs::create_dir_all(repo.objects_dir.join(prefix)).context("Creating object dirs")?;
-        let file = File::create(repo.objects_dir.join(prefix).join(filename))
-            .context("Creating object file")?;
-        let mut encoder = ZlibEncoder::new(file, Compression::default());
-        encoder
-            .write_all(self.header.as_str().as_bytes())
-            .context("Writing header")?;
-        encoder.write_all(&self.data).context("Writing data")?;
-        Ok(())
+        if let Some(odb) = repo.odb() {
+            return odb.write(self);
+        }
+        if Self::exists(repo, &self.hash) {
+            return Ok(());
+        }
+        LooseOdb::new(&repo.objects_dir, *repo.write_options()?).write(self)
+    }
+
+    /// Id of the object referenced by tag
+    pub fn tag_target(&self) -> Result<ObjectId> {
+        ensure!(self.header.kind == ObjectKind::Tag, "Object is not a tag");
+        self.data
+            .split(|c| *c == b'\n')
+            .find_map(|line| line.strip_prefix(b"object "))
+            .ok_or(format_err!("Tag doesn't contain object reference"))
+            .and_then(ObjectId::from_hex)
+    }
+
+    /// Ids of objects directly referenced by this object
+    pub fn links(&self) -> Result<Vec<ObjectId>> {
+        Ok(match self.header.kind {
+            ObjectKind::Blob => Vec::new(),
+            ObjectKind::Tag => vec![self.tag_target()?],
+            ObjectKind::Commit => {
+                let mut links = Vec::new();
+                for line in self.data.split(|c| *c == b'\n') {
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(id) = line
+                        .strip_prefix(b"tree ")
+                        .or_else(|| line.strip_prefix(b"parent "))
+                    {
+                        links.push(ObjectId::from_hex(id)?);
+                    }
+                }
+                links
+            }
+            ObjectKind::Tree => Tree::try_from(self.clone())?
+                .entries
+                .into_iter()
+                // Gitlinks point to commits in other repositories
+                .filter(|e| e.mode != FileMode::Gitlink)
+                .map(|e| e.reference)
+                .collect(),
+        })
     }
 
     pub fn print_pretty(&self) -> Result<()> {
@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{prelude::*, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use crate::error::{ensure, format_err, Context, Error, Result};
+use crate::hash::HashAlgo;
+use crate::mmap::Mmap;
+use crate::object::{self, Object, ObjectHeader, ObjectKind, ObjectReader};
+use crate::oid::ObjectId;
+use crate::pack::{self, index::PackIndex};
+use crate::storage::{FsyncComponent, WriteOptions};
+use crate::GitRepo;
+
+/// Loose object files at least this large are mapped rather than read
+const MMAP_THRESHOLD: u64 = 1024 * 1024;
+
+/// Makes names of temporary object files unique within the process
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Storage of objects addressed by their full hash
+pub trait Odb: Debug + Send + Sync {
+    /// Reads the object, `None` when it is not stored
+    fn read(&self, id: &ObjectId) -> Result<Option<Object>>;
+    /// Stores the object, storing already present object is no-op
+    fn write(&self, obj: &Object) -> Result<()>;
+    fn contains(&self, id: &ObjectId) -> Result<bool>;
+    /// Ids of all stored objects
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>>;
+}
+
+/// Loose objects of single object directory, each zlib compressed in its own file
+#[derive(Debug, Clone)]
+pub struct LooseOdb {
+    objects_dir: PathBuf,
+    options: WriteOptions,
+}
+
+impl LooseOdb {
+    pub fn new(objects_dir: &Path, options: WriteOptions) -> Self {
+        Self {
+            objects_dir: objects_dir.to_path_buf(),
+            options,
+        }
+    }
+
+    fn path(&self, id: &ObjectId) -> PathBuf {
+        let hash = id.to_hex();
+        self.objects_dir.join(&hash[..2]).join(&hash[2..])
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        self.objects_dir.join(format!(
+            "tmp_obj_{}_{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    fn create_file(&self, path: &Path) -> Result<ZlibEncoder<File>> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .context("Creating object file")?;
+        Ok(ZlibEncoder::new(file, self.options.loose_compression))
+    }
+
+    fn finish_file(&self, encoder: ZlibEncoder<File>, path: &Path) -> Result<()> {
+        let file = encoder.finish().context("Compressing object")?;
+        self.options.sync(&file, FsyncComponent::LooseObject)?;
+        let mut permissions = file.metadata()?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(path, permissions).context("Making object file read-only")
+    }
+
+    fn write_file(&self, obj: &Object, path: &Path) -> Result<()> {
+        let mut encoder = self.create_file(path)?;
+        encoder
+            .write_all(obj.header.as_str().as_bytes())
+            .context("Writing header")?;
+        encoder.write_all(&obj.data).context("Writing data")?;
+        self.finish_file(encoder, path)
+    }
+
+    /// Renames the written temporary file into place, removing it on failure
+    fn install(&self, written: Result<()>, tmp_path: &Path, path: &Path) -> Result<()> {
+        let written = written.and_then(|()| {
+            let dir = path.parent().ok_or(format_err!("Invalid object path"))?;
+            fs::create_dir_all(dir).context("Creating object dirs")?;
+            fs::rename(tmp_path, path).context("Renaming object file")
+        });
+        if written.is_err() {
+            let _ = fs::remove_file(tmp_path);
+            // Object written concurrently by another process is as good as ours
+            if path.is_file() {
+                return Ok(());
+            }
+        }
+        written
+    }
+
+    /// Opens the object for reading its content incrementally, `None` when it is not
+    /// stored. Only the header is decompressed up front.
+    pub fn reader(&self, id: &ObjectId) -> Result<Option<ObjectReader>> {
+        let file = match File::open(self.path(id)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("Opening object file"),
+        };
+        let input: Box<dyn Read + Send> = if file.metadata()?.len() >= MMAP_THRESHOLD {
+            Box::new(Cursor::new(
+                Mmap::map(&file).context("Mapping object file")?,
+            ))
+        } else {
+            Box::new(file)
+        };
+        let mut decoder = ZlibDecoder::new(input);
+        let mut header = Vec::new();
+        let mut byte = [0];
+        loop {
+            decoder
+                .read_exact(&mut byte)
+                .context("Reading object file")?;
+            if byte[0] == 0 {
+                break;
+            }
+            ensure!(
+                header.len() < 32,
+                Error::corrupt(id, "invalid header format")
+            );
+            header.push(byte[0]);
+        }
+        let header: ObjectHeader = header.try_into()?;
+        let content = decoder.take(header.data_length as u64);
+        Ok(Some(ObjectReader::new(*id, header, Box::new(content))))
+    }
+
+    /// Stores object with `size` bytes of content read from the reader. The content is
+    /// hashed while it is compressed, it is never held in memory whole.
+    pub fn write_from_reader(
+        &self,
+        algo: HashAlgo,
+        kind: ObjectKind,
+        size: u64,
+        reader: impl Read,
+    ) -> Result<ObjectId> {
+        let header = ObjectHeader {
+            kind,
+            data_length: size as usize,
+        }
+        .as_str();
+        let mut hasher = algo.hasher();
+        hasher.update(&header);
+        fs::create_dir_all(&self.objects_dir).context("Creating objects dir")?;
+        let tmp_path = self.tmp_path();
+        let written = self.create_file(&tmp_path).and_then(|mut encoder| {
+            encoder
+                .write_all(header.as_bytes())
+                .context("Writing header")?;
+            object::copy_hashing(reader, &mut encoder, &mut hasher, size)?;
+            self.finish_file(encoder, &tmp_path)
+        });
+        let id = hasher.finalize();
+        let path = self.path(&id);
+        if written.is_ok() && path.is_file() {
+            let _ = fs::remove_file(&tmp_path);
+            return Ok(id);
+        }
+        self.install(written, &tmp_path, &path)?;
+        Ok(id)
+    }
+}
+
+impl Odb for LooseOdb {
+    fn read(&self, id: &ObjectId) -> Result<Option<Object>> {
+        let Some(mut reader) = self.reader(id)? else {
+            return Ok(None);
+        };
+        let mut data = Vec::with_capacity(reader.header.data_length);
+        reader
+            .read_to_end(&mut data)
+            .context("Reading object file")?;
+        ensure!(
+            data.len() == reader.header.data_length,
+            Error::corrupt(id, "content is shorter than its header says")
+        );
+        Ok(Some(Object {
+            hash: *id,
+            header: reader.header,
+            data,
+        }))
+    }
+
+    /// The content is written to temporary file in the objects dir, synced and renamed
+    /// into place, so an object file is never seen partially written.
+    fn write(&self, obj: &Object) -> Result<()> {
+        let path = self.path(&obj.hash);
+        if path.is_file() {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.objects_dir).context("Creating object dirs")?;
+        let tmp_path = self.tmp_path();
+        let written = self.write_file(obj, &tmp_path);
+        self.install(written, &tmp_path, &path)
+    }
+
+    fn contains(&self, id: &ObjectId) -> Result<bool> {
+        Ok(self.path(id).is_file())
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let ids = Object::loose_ids_in(&self.objects_dir)?;
+        Ok(Box::new(ids.into_iter()))
+    }
+}
+
+/// Packed objects of the repository and its alternates
+#[derive(Debug, Clone, Copy)]
+pub struct PackOdb<'r> {
+    repo: &'r GitRepo,
+}
+
+impl<'r> PackOdb<'r> {
+    pub fn new(repo: &'r GitRepo) -> Self {
+        Self { repo }
+    }
+}
+
+impl Odb for PackOdb<'_> {
+    fn read(&self, id: &ObjectId) -> Result<Option<Object>> {
+        let Some((kind, data)) = pack::read_object(self.repo, id)? else {
+            return Ok(None);
+        };
+        let header = ObjectHeader {
+            kind,
+            data_length: data.len(),
+        };
+        Ok(Some(Object {
+            hash: *id,
+            header,
+            data,
+        }))
+    }
+
+    /// Stores the object as a new pack of its own
+    fn write(&self, obj: &Object) -> Result<()> {
+        if self.contains(&obj.hash)? {
+            return Ok(());
+        }
+        let level = self.repo.write_options()?.pack_compression;
+        let data = pack::write_pack(std::iter::once(obj), level, self.repo.hash_algo())?;
+        pack::index::index_pack(self.repo, &data, false)?;
+        Ok(())
+    }
+
+    fn contains(&self, id: &ObjectId) -> Result<bool> {
+        pack::contains(self.repo, id)
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let mut ids = Vec::new();
+        for objects_dir in self.repo.object_dirs() {
+            for idx_path in pack::pack_indexes_in(objects_dir)? {
+                let mut idx = PackIndex::open(&idx_path, self.repo.hash_algo())?;
+                for i in 0..idx.len() {
+                    ids.push(idx.id_at(i)?);
+                }
+            }
+        }
+        ids.sort();
+        ids.dedup();
+        Ok(Box::new(ids.into_iter()))
+    }
+}
+
+/// Objects kept only in memory, for tests and embedders without `.git` directory
+#[derive(Debug, Default)]
+pub struct MemoryOdb {
+    objects: RwLock<BTreeMap<ObjectId, Object>>,
+}
+
+impl MemoryOdb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Odb for MemoryOdb {
+    fn read(&self, id: &ObjectId) -> Result<Option<Object>> {
+        let objects = self
+            .objects
+            .read()
+            .map_err(|_| format_err!("Poisoned lock"))?;
+        Ok(objects.get(id).cloned())
+    }
+
+    fn write(&self, obj: &Object) -> Result<()> {
+        let mut objects = self
+            .objects
+            .write()
+            .map_err(|_| format_err!("Poisoned lock"))?;
+        objects.entry(obj.hash).or_insert_with(|| obj.clone());
+        Ok(())
+    }
+
+    fn contains(&self, id: &ObjectId) -> Result<bool> {
+        let objects = self
+            .objects
+            .read()
+            .map_err(|_| format_err!("Poisoned lock"))?;
+        Ok(objects.contains_key(id))
+    }
+
+    fn iter(&self) -> Result<Box<dyn Iterator<Item = ObjectId> + '_>> {
+        let objects = self
+            .objects
+            .read()
+            .map_err(|_| format_err!("Poisoned lock"))?;
+        let ids: Vec<ObjectId> = objects.keys().copied().collect();
+        Ok(Box::new(ids.into_iter()))
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::{ensure, format_err, Error, Result};
+use crate::hash::HashAlgo;
+
+/// Length of the longest binary object id, SHA-256
+pub const MAX_LEN: usize = 32;
+
+/// Object id stored in binary, formatted and parsed as lowercase hex. Its length tells
+/// the hash algorithm, SHA-1 or SHA-256.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ObjectId {
+    bytes: [u8; MAX_LEN],
+    len: u8,
+}
+
+impl ObjectId {
+    /// SHA-1 id of all zeros, used for missing side of ref updates
+    pub const ZERO: Self = Self {
+        bytes: [0; MAX_LEN],
+        len: 20,
+    };
+
+    /// SHA-1 id
+    pub fn from_bytes(bytes: [u8; 20]) -> Self {
+        let mut id = Self::ZERO;
+        id.bytes[..20].copy_from_slice(&bytes);
+        id
+    }
+
+    /// Parses raw id as stored in trees and pack indexes
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        ensure!(
+            bytes.len() == HashAlgo::Sha1.id_len() || bytes.len() == HashAlgo::Sha256.id_len(),
+            "Invalid object id length {}",
+            bytes.len()
+        );
+        let mut id = Self {
+            bytes: [0; MAX_LEN],
+            len: bytes.len() as u8,
+        };
+        id.bytes[..bytes.len()].copy_from_slice(bytes);
+        Ok(id)
+    }
+
+    /// Parses hex encoded id, either as text or ASCII bytes as in the pkt-line protocol
+    pub fn from_hex(hex: impl AsRef<[u8]>) -> Result<Self> {
+        let hex = hex.as_ref();
+        let invalid = || format_err!("Invalid object id {:?}", String::from_utf8_lossy(hex));
+        ensure!(
+            hex.len() == HashAlgo::Sha1.hex_len() || hex.len() == HashAlgo::Sha256.hex_len(),
+            invalid()
+        );
+        let mut bytes = [0; MAX_LEN];
+        hex::decode_to_slice(hex, &mut bytes[..hex.len() / 2]).map_err(|_| invalid())?;
+        Self::from_slice(&bytes[..hex.len() / 2])
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    /// Algorithm the id was computed with, told by its length
+    pub fn algo(&self) -> HashAlgo {
+        if self.len as usize == HashAlgo::Sha256.id_len() {
+            HashAlgo::Sha256
+        } else {
+            HashAlgo::Sha1
+        }
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.as_bytes())
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.as_bytes().iter().all(|b| *b == 0)
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_hex(s)
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ObjectId({})", self.to_hex())
+    }
+}
+
+impl From<[u8; 20]> for ObjectId {
+    fn from(bytes: [u8; 20]) -> Self {
+        Self::from_bytes(bytes)
+    }
+}
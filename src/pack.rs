@@ -0,0 +1,1008 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::commit::Commit;
+use crate::error::{bail, ensure, format_err, Context, Error, Result};
+use crate::hash::HashAlgo;
+use crate::mmap::Mmap;
+use crate::object::{Object, ObjectHeader, ObjectKind};
+use crate::oid::ObjectId;
+use crate::tag::Tag;
+use crate::{revwalk, GitRepo};
+
+pub mod bitmap;
+pub mod delta;
+pub mod index;
+pub mod midx;
+
+use index::PackIndex;
+
+/// Length of pack header (signature, version, object count)
+pub const HEADER_LEN: usize = 12;
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackEntryType {
+    OBJ_COMMIT,
+    OBJ_TREE,
+    OBJ_BLOB,
+    OBJ_TAG,
+    OBJ_OFS_DELTA,
+    OBJ_REF_DELTA,
+}
+
+impl TryFrom<u8> for PackEntryType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            0 => bail!("Forbidden value"),
+            1 => Self::OBJ_COMMIT,
+            2 => Self::OBJ_TREE,
+            3 => Self::OBJ_BLOB,
+            4 => Self::OBJ_TAG,
+            5 => bail!("Reserved value"),
+            6 => Self::OBJ_OFS_DELTA,
+            7 => Self::OBJ_REF_DELTA,
+            _ => unreachable!(),
+        })
+    }
+}
+
+impl From<ObjectKind> for PackEntryType {
+    fn from(kind: ObjectKind) -> Self {
+        match kind {
+            ObjectKind::Commit => Self::OBJ_COMMIT,
+            ObjectKind::Tree => Self::OBJ_TREE,
+            ObjectKind::Blob => Self::OBJ_BLOB,
+            ObjectKind::Tag => Self::OBJ_TAG,
+        }
+    }
+}
+
+impl PackEntryType {
+    fn as_u8(&self) -> u8 {
+        match self {
+            Self::OBJ_COMMIT => 1,
+            Self::OBJ_TREE => 2,
+            Self::OBJ_BLOB => 3,
+            Self::OBJ_TAG => 4,
+            Self::OBJ_OFS_DELTA => 6,
+            Self::OBJ_REF_DELTA => 7,
+        }
+    }
+
+    /// Object kind of non-delta entries
+    pub fn object_kind(&self) -> Option<ObjectKind> {
+        match self {
+            Self::OBJ_COMMIT => Some(ObjectKind::Commit),
+            Self::OBJ_TREE => Some(ObjectKind::Tree),
+            Self::OBJ_BLOB => Some(ObjectKind::Blob),
+            Self::OBJ_TAG => Some(ObjectKind::Tag),
+            Self::OBJ_OFS_DELTA | Self::OBJ_REF_DELTA => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeltaBase {
+    /// Absolute offset of the base entry within the same pack
+    Offset(u64),
+    /// Id of the base object
+    Ref(ObjectId),
+}
+
+/// Header of single pack entry
+#[derive(Debug, Clone)]
+pub struct EntryHeader {
+    pub kind: PackEntryType,
+    /// Size of the inflated entry data
+    pub size: usize,
+    pub base: Option<DeltaBase>,
+    /// Number of header bytes preceding compressed data
+    pub header_len: usize,
+}
+
+impl EntryHeader {
+    /// Parses entry header from data starting at entry located at `offset` within pack,
+    /// `OBJ_REF_DELTA` base ids being of the algorithm's length
+    pub fn parse(data: &[u8], offset: u64, algo: HashAlgo) -> Result<Self> {
+        let mut pos = 0;
+        let mut next = || -> Result<u8> {
+            let byte = *data
+                .get(pos)
+                .ok_or(format_err!("Truncated pack entry header"))?;
+            pos += 1;
+            Ok(byte)
+        };
+        let mut byte = next()?;
+        let kind = PackEntryType::try_from((byte >> 4) & 7)?;
+        let mut size = (byte & 15) as usize;
+        let mut shift = 4;
+        while byte >= 128 {
+            byte = next()?;
+            ensure!(shift < usize::BITS, "Pack entry size overflows");
+            size |= ((byte & 127) as usize) << shift;
+            shift += 7;
+        }
+        let base = match kind {
+            PackEntryType::OBJ_OFS_DELTA => {
+                let mut byte = next()?;
+                let mut distance = (byte & 127) as u64;
+                while byte >= 128 {
+                    byte = next()?;
+                    distance = distance
+                        .checked_add(1)
+                        .and_then(|distance| distance.checked_mul(128))
+                        .ok_or(format_err!("Delta base offset overflows"))?
+                        | (byte & 127) as u64;
+                }
+                ensure!(distance <= offset, "Delta base offset out of pack");
+                Some(DeltaBase::Offset(offset - distance))
+            }
+            PackEntryType::OBJ_REF_DELTA => {
+                ensure!(
+                    data.len() >= pos + algo.id_len(),
+                    "Truncated pack entry header"
+                );
+                let id = ObjectId::from_slice(&data[pos..pos + algo.id_len()])?;
+                pos += algo.id_len();
+                Some(DeltaBase::Ref(id))
+            }
+            _ => None,
+        };
+        Ok(Self {
+            kind,
+            size,
+            base,
+            header_len: pos,
+        })
+    }
+
+    fn write(kind: PackEntryType, mut size: usize, buf: &mut Vec<u8>) {
+        let mut byte = (kind.as_u8() << 4) | (size & 15) as u8;
+        size >>= 4;
+        while size > 0 {
+            buf.push(byte | 128);
+            byte = (size & 127) as u8;
+            size >>= 7;
+        }
+        buf.push(byte);
+    }
+}
+
+/// Buffers for sizes declared by packs and deltas are preallocated up to this size,
+/// larger ones grow with the data actually produced
+pub(crate) const MAX_PREALLOC: usize = 1 << 24;
+
+/// Inflates zlib stream of the declared size, returning data and number of compressed
+/// bytes consumed. Streams inflating to more data are not read past the size.
+pub fn inflate(data: &[u8], size: usize) -> Result<(Vec<u8>, usize)> {
+    let mut decoder = ZlibDecoder::new(data);
+    let mut buf = Vec::with_capacity(size.min(MAX_PREALLOC));
+    (&mut decoder)
+        .take(size as u64 + 1)
+        .read_to_end(&mut buf)
+        .context("Inflating pack entry")?;
+    ensure!(size == buf.len(), "Read incorrect number of bytes");
+    Ok((buf, decoder.total_in() as usize))
+}
+
+/// Validates pack header returning number of entries
+pub fn parse_header(data: &[u8]) -> Result<u32> {
+    ensure!(
+        data.len() >= HEADER_LEN && &data[..4] == b"PACK",
+        "Missing pack signature"
+    );
+    ensure!(data[4..8] == [0, 0, 0, 2], "Packfile version should be 2");
+    Ok(u32::from_be_bytes(data[8..12].try_into()?))
+}
+
+/// Checks the trailing checksum of pack data, returning the pack body
+pub fn verify_checksum(data: &[u8], algo: HashAlgo) -> Result<&[u8]> {
+    ensure!(data.len() >= HEADER_LEN + algo.id_len(), "Truncated pack");
+    let (body, trailer) = data.split_at(data.len() - algo.id_len());
+    ensure!(
+        algo.digest(body).as_bytes() == trailer,
+        "Pack checksum mismatch"
+    );
+    Ok(body)
+}
+
+/// Reads delta base stored outside of the pack, checking it hashes to the id the delta
+/// refers to
+pub fn read_base(repo: &GitRepo, id: &ObjectId) -> Result<Object> {
+    let (kind, data) = read_ref_base(repo, id)?;
+    let header = ObjectHeader {
+        kind,
+        data_length: data.len(),
+    };
+    Ok(Object {
+        hash: *id,
+        header,
+        data: data.to_vec(),
+    })
+}
+
+/// Reads base of `REF_DELTA` through the cache, so deltas sharing the base read it once
+fn read_ref_base(repo: &GitRepo, id: &ObjectId) -> Result<(ObjectKind, Arc<Vec<u8>>)> {
+    let key = BaseKey::Id(*id);
+    if let Some(found) = repo.pack_cache.base(&key)? {
+        return Ok(found);
+    }
+    let base = Object::read(repo, id).with_context(|| format!("Missing delta base {}", id))?;
+    let actual = Object::new(id.algo(), base.header.kind, base.data);
+    ensure!(
+        actual.hash == *id,
+        Error::corrupt(id, "delta base hashes to different id")
+    );
+    let data = Arc::new(actual.data);
+    repo.pack_cache
+        .insert_base(key, actual.header.kind, data.clone())?;
+    Ok((actual.header.kind, data))
+}
+
+/// Number of threads resolving, verifying and writing objects, one per core
+fn worker_count() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Applies the function to the items on worker threads, results are in the items' order
+pub(crate) fn parallel_map<T: Send, R: Send>(
+    items: Vec<T>,
+    f: impl Fn(T) -> Result<R> + Sync,
+) -> Result<Vec<R>> {
+    let chunk_len = items.len().div_ceil(worker_count()).max(1);
+    let mut chunks = Vec::new();
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        chunks.push(items.by_ref().take(chunk_len).collect::<Vec<T>>());
+    }
+    let f = &f;
+    thread::scope(|scope| {
+        let workers: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| scope.spawn(move || chunk.into_iter().map(f).collect::<Result<Vec<R>>>()))
+            .collect();
+        let mut results = Vec::new();
+        for worker in workers {
+            results.extend(
+                worker
+                    .join()
+                    .map_err(|_| format_err!("Worker thread panicked"))??,
+            );
+        }
+        Ok(results)
+    })
+}
+
+/// Objects of pack entries with resolved deltas
+pub(crate) struct ResolvedEntries {
+    /// Objects in the order of their entries
+    pub objects: Vec<Object>,
+    /// Offset and length of every entry within the pack
+    pub spans: Vec<(u64, usize)>,
+    /// Objects from outside of the pack that deltas are based on
+    pub external: Vec<Object>,
+}
+
+/// Where the base of a delta is, once it is resolved
+#[derive(Clone, Copy)]
+enum BaseLocation {
+    Entry(usize),
+    External(usize),
+}
+
+/// Parses pack body (data without the trailing checksum) and resolves its deltas. Entries
+/// are inflated in order by the calling thread, as only inflating tells where the next
+/// one starts. Objects are then hashed on worker threads, a round for each level of
+/// delta chains, with all objects resolved so far shared by the workers as bases.
+pub(crate) fn resolve_entries(repo: &GitRepo, body: &[u8]) -> Result<ResolvedEntries> {
+    let algo = repo.hash_algo();
+    let count = parse_header(body)? as usize;
+    // Every entry takes at least a byte, the count is not trusted beyond that
+    let mut spans = Vec::with_capacity(count.min(body.len()));
+    let mut whole = Vec::new();
+    let mut deltas = Vec::new();
+    let mut offset = HEADER_LEN;
+    for i in 0..count {
+        ensure!(offset < body.len(), "Truncated pack");
+        let header = EntryHeader::parse(&body[offset..], offset as u64, algo)?;
+        let (data, read_bytes) = inflate(&body[offset + header.header_len..], header.size)?;
+        let len = header.header_len + read_bytes;
+        match header.base {
+            None => whole.push((i, header.kind.object_kind().unwrap(), data)),
+            Some(base) => deltas.push((i, base, data)),
+        }
+        spans.push((offset as u64, len));
+        offset += len;
+    }
+    ensure!(offset == body.len(), "Unexpected data after pack data");
+
+    let by_offset: HashMap<u64, usize> = spans
+        .iter()
+        .enumerate()
+        .map(|(i, (offset, _))| (*offset, i))
+        .collect();
+    let mut resolved: Vec<Option<Object>> = (0..count).map(|_| None).collect();
+    let mut by_id: HashMap<ObjectId, BaseLocation> = HashMap::new();
+    let hashed = parallel_map(whole, |(i, kind, data)| {
+        Ok((i, Object::new(algo, kind, data)))
+    })?;
+    for (i, obj) in hashed {
+        by_id.insert(obj.hash, BaseLocation::Entry(i));
+        resolved[i] = Some(obj);
+    }
+
+    let mut external: Vec<Object> = Vec::new();
+    while !deltas.is_empty() {
+        let mut ready = Vec::new();
+        let mut waiting = Vec::new();
+        for (i, base, data) in deltas {
+            let location = match base {
+                DeltaBase::Offset(offset) => {
+                    let idx = *by_offset
+                        .get(&offset)
+                        .ok_or(format_err!("Invalid delta base offset {}", offset))?;
+                    resolved[idx].as_ref().map(|_| BaseLocation::Entry(idx))
+                }
+                DeltaBase::Ref(id) => by_id.get(&id).copied(),
+            };
+            match location {
+                Some(location) => ready.push((i, location, data)),
+                None => waiting.push((i, base, data)),
+            }
+        }
+        if ready.is_empty() {
+            // Remaining deltas have to be based on objects we already have, all of them
+            // are loaded at once rather than a round for each
+            let missing: BTreeSet<ObjectId> = waiting
+                .iter()
+                .filter_map(|(_, base, _)| match base {
+                    DeltaBase::Ref(id) if !by_id.contains_key(id) && Object::exists(repo, id) => {
+                        Some(*id)
+                    }
+                    _ => None,
+                })
+                .collect();
+            ensure!(!missing.is_empty(), "Unresolvable deltas in pack");
+            for id in missing {
+                by_id.insert(id, BaseLocation::External(external.len()));
+                external.push(read_base(repo, &id)?);
+            }
+        } else {
+            let (resolved_ref, external_ref) = (&resolved, &external);
+            let applied = parallel_map(ready, |(i, location, data)| {
+                let base = match location {
+                    BaseLocation::Entry(idx) => resolved_ref[idx].as_ref().unwrap(),
+                    BaseLocation::External(idx) => &external_ref[idx],
+                };
+                let obj = Object::new(algo, base.header.kind, delta::apply(&base.data, &data)?);
+                Ok((i, obj))
+            })?;
+            for (i, obj) in applied {
+                by_id.insert(obj.hash, BaseLocation::Entry(i));
+                resolved[i] = Some(obj);
+            }
+        }
+        deltas = waiting;
+    }
+
+    Ok(ResolvedEntries {
+        objects: resolved.into_iter().map(Option::unwrap).collect(),
+        spans,
+        external,
+    })
+}
+
+/// Objects of packfile data with resolved deltas, after verifying the whole pack. With
+/// `fsck` also the structure of every object is verified, on worker threads.
+pub fn read_objects(repo: &GitRepo, data: &[u8], fsck: bool) -> Result<Vec<Object>> {
+    let body = verify_checksum(data, repo.hash_algo())?;
+    let entries = resolve_entries(repo, body)?;
+    for obj in &entries.objects {
+        repo.progress()
+            .debug(&format!("{:?} {}", obj.header.kind, obj.hash));
+    }
+    if fsck {
+        parallel_map(entries.objects.iter().collect(), |obj| {
+            crate::fsck::verify_object(repo, obj)
+        })?;
+    }
+    Ok(entries.objects)
+}
+
+/// Unpacks packfile data into loose objects. The whole pack is verified before any
+/// object is written, see [`read_objects`]. Objects are written on worker threads.
+pub fn unpack(repo: &GitRepo, data: &[u8], fsck: bool) -> Result<()> {
+    let objects = read_objects(repo, data, fsck)?;
+    parallel_map(objects.iter().collect(), |obj| obj.write(repo))?;
+    Ok(())
+}
+
+/// Serializes objects into packfile (version 2) without deltas, compressed with the level
+/// and with trailing checksum of the algorithm
+pub fn write_pack<'o>(
+    objects: impl ExactSizeIterator<Item = &'o Object>,
+    level: Compression,
+    algo: HashAlgo,
+) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    write_entries(objects.len(), objects.map(Ok), level, algo, &mut data)?;
+    Ok(data)
+}
+
+/// Writes the same packfile as [`write_pack`] of the objects with the ids to `out`. Each
+/// object is read only when its entry is written, which is as soon as it is compressed.
+pub fn stream_pack(
+    repo: &GitRepo,
+    ids: &[ObjectId],
+    level: Compression,
+    out: &mut impl Write,
+) -> Result<()> {
+    let objects = ids.iter().map(|id| Object::read(repo, id));
+    write_entries(ids.len(), objects, level, repo.hash_algo(), out)
+}
+
+fn write_entries<O: Borrow<Object>>(
+    count: usize,
+    objects: impl Iterator<Item = Result<O>>,
+    level: Compression,
+    algo: HashAlgo,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut hasher = algo.hasher();
+    let mut emit = |data: &[u8]| -> Result<()> {
+        hasher.update(data);
+        out.write_all(data).context("Writing pack")
+    };
+    let mut header = b"PACK".to_vec();
+    header.extend(2u32.to_be_bytes());
+    header.extend(u32::try_from(count)?.to_be_bytes());
+    emit(&header)?;
+    let mut entry = Vec::new();
+    for obj in objects {
+        entry.clear();
+        write_entry(&mut entry, obj?.borrow(), level)?;
+        emit(&entry)?;
+    }
+    out.write_all(hasher.finalize().as_bytes())
+        .context("Writing pack")?;
+    Ok(())
+}
+
+/// Longest chain of deltas written by `write_pack_with_deltas`, git's `pack.depth` default
+const MAX_DELTA_DEPTH: usize = 50;
+
+/// Number of packs kept mapped by [`PackCache`]
+const MAX_MAPPED_PACKS: usize = 64;
+/// Total size of delta bases kept by [`PackCache`], the default of git's
+/// `core.deltaBaseCacheLimit`
+const DELTA_BASE_CACHE_LIMIT: usize = 96 * 1024 * 1024;
+
+/// Recently used mapped packs and inflated delta bases of the repository, evicting the
+/// least recently used ones. Clones of the repository share it.
+#[derive(Debug, Default)]
+pub(crate) struct PackCache {
+    inner: Mutex<PackCacheInner>,
+}
+
+#[derive(Debug, Default)]
+struct PackCacheInner {
+    /// Least recently used first
+    packs: Vec<(PathBuf, Arc<Mmap>)>,
+    bases: HashMap<BaseKey, CachedBase>,
+    bases_size: usize,
+    /// Incremented on every base lookup, orders bases by their use
+    tick: u64,
+}
+
+/// Delta base is found by offset of its entry for `OFS_DELTA`, by its id for `REF_DELTA`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BaseKey {
+    Offset(PathBuf, u64),
+    Id(ObjectId),
+}
+
+#[derive(Debug)]
+struct CachedBase {
+    kind: ObjectKind,
+    data: Arc<Vec<u8>>,
+    last_used: u64,
+}
+
+impl PackCache {
+    fn lock(&self) -> Result<MutexGuard<'_, PackCacheInner>> {
+        self.inner.lock().map_err(|_| format_err!("Poisoned lock"))
+    }
+
+    /// Data of the pack, mapped on first use
+    fn pack(&self, path: &Path) -> Result<Arc<Mmap>> {
+        let mut inner = self.lock()?;
+        if let Some(i) = inner.packs.iter().position(|(p, _)| p == path) {
+            let entry = inner.packs.remove(i);
+            let data = entry.1.clone();
+            inner.packs.push(entry);
+            return Ok(data);
+        }
+        let data = Mmap::open(path).with_context(|| format!("Mapping pack {}", path.display()))?;
+        let data = Arc::new(data);
+        if inner.packs.len() >= MAX_MAPPED_PACKS {
+            inner.packs.remove(0);
+        }
+        inner.packs.push((path.to_path_buf(), data.clone()));
+        Ok(data)
+    }
+
+    fn base(&self, key: &BaseKey) -> Result<Option<(ObjectKind, Arc<Vec<u8>>)>> {
+        let mut inner = self.lock()?;
+        inner.tick += 1;
+        let tick = inner.tick;
+        Ok(inner.bases.get_mut(key).map(|base| {
+            base.last_used = tick;
+            (base.kind, base.data.clone())
+        }))
+    }
+
+    fn insert_base(&self, key: BaseKey, kind: ObjectKind, data: Arc<Vec<u8>>) -> Result<()> {
+        // Single huge base would flush everything else
+        if data.len() > DELTA_BASE_CACHE_LIMIT / 4 {
+            return Ok(());
+        }
+        let mut inner = self.lock()?;
+        inner.bases_size += data.len();
+        let last_used = inner.tick;
+        let replaced = inner.bases.insert(
+            key,
+            CachedBase {
+                kind,
+                data,
+                last_used,
+            },
+        );
+        if let Some(replaced) = replaced {
+            inner.bases_size -= replaced.data.len();
+        }
+        while inner.bases_size > DELTA_BASE_CACHE_LIMIT {
+            let Some(oldest) = inner
+                .bases
+                .iter()
+                .min_by_key(|(_, base)| base.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = inner.bases.remove(&oldest) {
+                inner.bases_size -= evicted.data.len();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serializes objects into packfile (version 2), storing objects as `OBJ_OFS_DELTA` against
+/// similar objects of the same kind when it saves space. Objects are sorted by kind and
+/// size and each is tried against `window` preceding ones.
+pub fn write_pack_with_deltas(
+    objects: &[Object],
+    level: Compression,
+    window: usize,
+    algo: HashAlgo,
+) -> Result<Vec<u8>> {
+    write_thin_pack(objects, &[], level, window, MAX_DELTA_DEPTH, algo)
+}
+
+/// Serializes objects into packfile like [`write_pack_with_deltas`], with delta chains
+/// at most `depth` long. Objects may also be stored as `OBJ_REF_DELTA` against `bases`,
+/// which are not written themselves, so the receiver has to have them.
+pub fn write_thin_pack(
+    objects: &[Object],
+    bases: &[Object],
+    level: Compression,
+    window: usize,
+    depth: usize,
+    algo: HashAlgo,
+) -> Result<Vec<u8>> {
+    // Bases are sorted among the objects, so that they are tried as their neighbours
+    let mut order: Vec<(&Object, bool)> = objects
+        .iter()
+        .map(|obj| (obj, false))
+        .chain(bases.iter().map(|obj| (obj, true)))
+        .collect();
+    order.sort_by(|(a, _), (b, _)| {
+        (a.header.kind as u8, b.data.len()).cmp(&(b.header.kind as u8, a.data.len()))
+    });
+
+    let mut data = Vec::new();
+    data.extend(b"PACK");
+    data.extend(2u32.to_be_bytes());
+    data.extend(u32::try_from(objects.len())?.to_be_bytes());
+    // Offset and delta depth of written entries, bases have no offset
+    let mut written: Vec<(Option<u64>, usize)> = vec![(None, 0); order.len()];
+    for (i, (obj, is_base)) in order.iter().enumerate() {
+        if *is_base {
+            continue;
+        }
+        // Bases are not written, so also those sorted after the object can be used
+        let following_bases = (i + 1..order.len().min(i + 1 + window)).filter(|j| order[*j].1);
+        let mut best: Option<(usize, Vec<u8>)> = None;
+        for j in (i.saturating_sub(window)..i).chain(following_bases) {
+            let base = order[j].0;
+            if base.header.kind != obj.header.kind || written[j].1 >= depth {
+                continue;
+            }
+            let delta = delta::create(&base.data, &obj.data);
+            let limit = best.as_ref().map_or(obj.data.len() / 2, |(_, d)| d.len());
+            if delta.len() < limit {
+                best = Some((j, delta));
+            }
+        }
+        let offset = data.len() as u64;
+        match best {
+            Some((j, delta)) => {
+                let (base_offset, base_depth) = written[j];
+                match base_offset {
+                    Some(base_offset) => {
+                        EntryHeader::write(PackEntryType::OBJ_OFS_DELTA, delta.len(), &mut data);
+                        write_base_distance(offset - base_offset, &mut data);
+                    }
+                    None => {
+                        EntryHeader::write(PackEntryType::OBJ_REF_DELTA, delta.len(), &mut data);
+                        data.extend(order[j].0.hash.as_bytes());
+                    }
+                }
+                let mut encoder = ZlibEncoder::new(&mut data, level);
+                encoder.write_all(&delta).context("Compressing delta")?;
+                encoder.finish().context("Compressing delta")?;
+                written[i] = (Some(offset), base_depth + 1);
+            }
+            None => {
+                write_entry(&mut data, obj, level)?;
+                written[i] = (Some(offset), 0);
+            }
+        }
+    }
+    let checksum = algo.digest(&data);
+    data.extend(checksum.as_bytes());
+    Ok(data)
+}
+
+/// Encodes distance to `OBJ_OFS_DELTA` base, inverse of parsing in `EntryHeader::parse`
+fn write_base_distance(mut distance: u64, data: &mut Vec<u8>) {
+    let mut bytes = vec![(distance & 127) as u8];
+    distance >>= 7;
+    while distance > 0 {
+        distance -= 1;
+        bytes.push(128 | (distance & 127) as u8);
+        distance >>= 7;
+    }
+    data.extend(bytes.iter().rev());
+}
+
+/// Appends single non-delta pack entry
+pub fn write_entry(data: &mut Vec<u8>, obj: &Object, level: Compression) -> Result<()> {
+    EntryHeader::write(PackEntryType::from(obj.header.kind), obj.data.len(), data);
+    let mut encoder = ZlibEncoder::new(data, level);
+    encoder.write_all(&obj.data).context("Compressing object")?;
+    encoder.finish().context("Compressing object")?;
+    Ok(())
+}
+
+/// Ids of objects reachable from `wants` which are not reachable from `haves`. Objects
+/// in `haves` have to be present in the repository. Pack bitmaps are used when available.
+/// Otherwise only commits not reachable from `haves` are walked, see
+/// [`commits_to_send`], and trees and blobs of the commits they build on are left out, as
+/// git does. Objects the receiver has only in older history may be included.
+pub fn objects_to_send(
+    repo: &GitRepo,
+    wants: &[ObjectId],
+    haves: &[ObjectId],
+) -> Result<Vec<ObjectId>> {
+    if let Some(ids) = bitmap::objects_to_send(repo, wants, haves)? {
+        return Ok(ids);
+    }
+    let mut ids = Vec::new();
+    let mut seen = HashSet::new();
+    // Kinds and ids of the objects the tips peel to, tags are sent themselves
+    let mut peel = |tips: &[ObjectId], send: bool| -> Result<Vec<(ObjectKind, ObjectId)>> {
+        let mut peeled = Vec::new();
+        for tip in tips {
+            let mut obj = Object::read(repo, tip)?;
+            while obj.header.kind == ObjectKind::Tag {
+                if send && seen.insert(obj.hash) {
+                    ids.push(obj.hash);
+                }
+                obj = Object::read(repo, &Tag::try_from(obj)?.object)?;
+            }
+            peeled.push((obj.header.kind, obj.hash));
+        }
+        Ok(peeled)
+    };
+    let haves = peel(haves, false)?;
+    let wants = peel(wants, true)?;
+    let of_kind = |tips: &[(ObjectKind, ObjectId)], wanted: ObjectKind| -> Vec<ObjectId> {
+        tips.iter()
+            .filter(|(kind, _)| *kind == wanted)
+            .map(|(_, id)| *id)
+            .collect()
+    };
+    let commits = of_kind(&wants, ObjectKind::Commit);
+    let (sent, boundary) = commits_to_send(repo, &commits, &of_kind(&haves, ObjectKind::Commit))?;
+
+    let mut trees = of_kind(&wants, ObjectKind::Tree);
+    for id in sent {
+        ids.push(id);
+        trees.push(Commit::read(repo, &id)?.tree_sha);
+    }
+    let mut excluded = of_kind(&haves, ObjectKind::Tree);
+    for id in boundary {
+        excluded.push(Commit::read(repo, &id)?.tree_sha);
+    }
+    seen.extend(of_kind(&haves, ObjectKind::Blob));
+    let blobs = of_kind(&wants, ObjectKind::Blob).into_iter();
+    let objects = revwalk::tree_objects(repo, &trees, &excluded)?;
+    for id in blobs.chain(objects.into_iter().map(|(id, _)| id)) {
+        if seen.insert(id) {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+/// Commits reachable from `wants` but not from `haves`, and the commits reachable from
+/// `haves` which are parents of them. Commits are walked newest first until all left in
+/// the queue are reachable from `haves`, so that history both have is not walked. With
+/// skewed commit dates some commits the receiver has may be included.
+fn commits_to_send(
+    repo: &GitRepo,
+    wants: &[ObjectId],
+    haves: &[ObjectId],
+) -> Result<(Vec<ObjectId>, Vec<ObjectId>)> {
+    let mut queue = BinaryHeap::new();
+    // Parents of the queued commits
+    let mut queued = HashMap::new();
+    let enqueue = |id: ObjectId,
+                   queue: &mut BinaryHeap<(i64, ObjectId)>,
+                   queued: &mut HashMap<ObjectId, Vec<ObjectId>>|
+     -> Result<()> {
+        let commit = Commit::read(repo, &id)?;
+        queue.push((commit.committer.unix_time(), id));
+        queued.insert(id, commit.parents);
+        Ok(())
+    };
+    let mut seen = HashSet::new();
+    let mut hidden = HashSet::new();
+    for id in haves {
+        if seen.insert(*id) {
+            hidden.insert(*id);
+            enqueue(*id, &mut queue, &mut queued)?;
+        }
+    }
+    // Queued commits not known to be reachable from `haves`
+    let mut interesting = 0;
+    for id in wants {
+        if seen.insert(*id) {
+            enqueue(*id, &mut queue, &mut queued)?;
+            interesting += 1;
+        }
+    }
+
+    let mut sent = Vec::new();
+    while interesting > 0 {
+        let Some((_, id)) = queue.pop() else {
+            break;
+        };
+        let parents = queued.remove(&id).unwrap_or_default();
+        if hidden.contains(&id) {
+            for parent in parents {
+                if !hidden.insert(parent) {
+                    continue;
+                }
+                if seen.insert(parent) {
+                    enqueue(parent, &mut queue, &mut queued)?;
+                } else if queued.contains_key(&parent) {
+                    interesting -= 1;
+                }
+            }
+        } else {
+            interesting -= 1;
+            for parent in &parents {
+                if seen.insert(*parent) {
+                    enqueue(*parent, &mut queue, &mut queued)?;
+                    interesting += 1;
+                }
+            }
+            sent.push((id, parents));
+        }
+    }
+    // Commits found to be reachable from `haves` after they were walked
+    sent.retain(|(id, _)| !hidden.contains(id));
+    let mut boundary = Vec::new();
+    for (_, parents) in &sent {
+        for parent in parents {
+            if hidden.contains(parent) && !boundary.contains(parent) {
+                boundary.push(*parent);
+            }
+        }
+    }
+    Ok((sent.into_iter().map(|(id, _)| id).collect(), boundary))
+}
+
+/// Objects the receiver of a thin pack with the objects is known to have, trees and
+/// blobs of the commits at the boundary of the sent history
+pub fn thin_bases(repo: &GitRepo, objects: &[Object]) -> Result<Vec<Object>> {
+    let sent: HashSet<ObjectId> = objects.iter().map(|obj| obj.hash).collect();
+    let mut boundary = Vec::new();
+    for obj in objects
+        .iter()
+        .filter(|obj| obj.header.kind == ObjectKind::Commit)
+    {
+        for parent in Commit::read(repo, &obj.hash)?.parents {
+            if !sent.contains(&parent) && !boundary.contains(&parent) {
+                boundary.push(parent);
+            }
+        }
+    }
+    let mut seen = HashSet::new();
+    let mut bases = Vec::new();
+    let mut stack = Vec::new();
+    for commit in boundary {
+        stack.push(Commit::read(repo, &commit)?.tree_sha);
+    }
+    while let Some(id) = stack.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        let obj = Object::read(repo, &id)?;
+        stack.extend(obj.links()?);
+        if !sent.contains(&obj.hash) {
+            bases.push(obj);
+        }
+    }
+    Ok(bases)
+}
+
+/// Paths of all pack indexes in the repository, without packs of alternates
+pub fn pack_indexes(repo: &GitRepo) -> Result<Vec<PathBuf>> {
+    pack_indexes_in(&repo.objects_dir)
+}
+
+/// Paths of all pack indexes in the object directory
+pub fn pack_indexes_in(objects_dir: &Path) -> Result<Vec<PathBuf>> {
+    let pack_dir = objects_dir.join("pack");
+    if !pack_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut paths = Vec::new();
+    for item in fs::read_dir(pack_dir)? {
+        let path = item?.path();
+        if path.extension().is_some_and(|ext| ext == "idx") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Reads entry located at `offset` in pack, resolving deltas
+fn read_entry(
+    repo: &GitRepo,
+    pack_path: &Path,
+    pack: &[u8],
+    offset: u64,
+) -> Result<(ObjectKind, Vec<u8>)> {
+    let start = usize::try_from(offset)?;
+    ensure!(
+        start < pack.len(),
+        "Pack entry offset {} out of bounds",
+        offset
+    );
+    let header = EntryHeader::parse(&pack[start..], offset, repo.hash_algo())?;
+    let (buf, _) = inflate(&pack[start + header.header_len..], header.size)?;
+
+    match header.base {
+        None => Ok((header.kind.object_kind().unwrap(), buf)),
+        Some(DeltaBase::Offset(base_offset)) => {
+            let (kind, base) = read_base_entry(repo, pack_path, pack, base_offset)?;
+            Ok((kind, delta::apply(&base, &buf)?))
+        }
+        Some(DeltaBase::Ref(id)) => {
+            let (kind, base) = read_ref_base(repo, &id)?;
+            Ok((kind, delta::apply(&base, &buf)?))
+        }
+    }
+}
+
+/// Reads entry which is a delta base, through the cache as other deltas are likely to
+/// share the base
+fn read_base_entry(
+    repo: &GitRepo,
+    pack_path: &Path,
+    pack: &[u8],
+    offset: u64,
+) -> Result<(ObjectKind, Arc<Vec<u8>>)> {
+    let key = BaseKey::Offset(pack_path.to_path_buf(), offset);
+    if let Some(found) = repo.pack_cache.base(&key)? {
+        return Ok(found);
+    }
+    let (kind, data) = read_entry(repo, pack_path, pack, offset)?;
+    let data = Arc::new(data);
+    repo.pack_cache.insert_base(key, kind, data.clone())?;
+    Ok((kind, data))
+}
+
+/// Finds pack containing the object and offset of its entry, in the repository and then
+/// its alternates
+fn locate(repo: &GitRepo, id: &ObjectId) -> Result<Option<(PathBuf, u64)>> {
+    for objects_dir in repo.object_dirs() {
+        if let Some(found) = locate_in(objects_dir, id)? {
+            return Ok(Some(found));
+        }
+    }
+    Ok(None)
+}
+
+/// Finds pack of the object directory containing the object. The multi-pack-index is
+/// searched first, then packs it does not cover.
+fn locate_in(objects_dir: &Path, id: &ObjectId) -> Result<Option<(PathBuf, u64)>> {
+    let pack_dir = objects_dir.join("pack");
+    let mut covered = HashSet::new();
+    if let Some(mut midx) = midx::open(objects_dir)? {
+        if let Some((pack, offset)) = midx.find(id)? {
+            let idx_path = pack_dir.join(&midx.pack_names()[pack]);
+            return Ok(Some((idx_path.with_extension("pack"), offset)));
+        }
+        covered.extend(midx.pack_names().iter().map(|name| pack_dir.join(name)));
+    }
+    for idx_path in pack_indexes_in(objects_dir)? {
+        if covered.contains(&idx_path) {
+            continue;
+        }
+        if let Some(offset) = PackIndex::open(&idx_path, id.algo())?.find(id)? {
+            return Ok(Some((idx_path.with_extension("pack"), offset)));
+        }
+    }
+    Ok(None)
+}
+
+/// Looks up object in repository packs
+pub fn read_object(repo: &GitRepo, id: &ObjectId) -> Result<Option<(ObjectKind, Vec<u8>)>> {
+    match locate(repo, id)? {
+        Some((pack_path, offset)) => {
+            let pack = repo.pack_cache.pack(&pack_path)?;
+            read_entry(repo, &pack_path, &pack, offset).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Checks whether object is present in some pack of the repository or its alternates
+pub fn contains(repo: &GitRepo, id: &ObjectId) -> Result<bool> {
+    Ok(locate(repo, id)?.is_some())
+}
+
+/// Checks whether object is present in some pack of the object directory
+pub fn contains_in(objects_dir: &Path, id: &ObjectId) -> Result<bool> {
+    Ok(locate_in(objects_dir, id)?.is_some())
+}
+
+/// Ids of packed objects starting with given hex prefix
+pub fn find_prefix(repo: &GitRepo, prefix: &str) -> Result<Vec<ObjectId>> {
+    let mut ids = Vec::new();
+    for objects_dir in repo.object_dirs() {
+        for idx_path in pack_indexes_in(objects_dir)? {
+            let mut idx = PackIndex::open(&idx_path, repo.hash_algo())?;
+            ids.extend(idx.ids_with_prefix(prefix)?);
+        }
+    }
+    Ok(ids)
+}
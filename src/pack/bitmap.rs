@@ -0,0 +1,414 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sha1::{Digest, Sha1};
+
+use super::index::PackIndex;
+use super::pack_indexes;
+use crate::error::{ensure, format_err, Context, Result};
+use crate::hash::HashAlgo;
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::revwalk::peel_to_commit;
+use crate::storage::{self, FsyncComponent};
+use crate::{refs, GitRepo};
+
+const BITMAP_SIGNATURE: &[u8; 4] = b"BITM";
+const BITMAP_HEADER_LEN: usize = 32;
+/// Bitmaps cover the whole closure of their commits
+const BITMAP_OPT_FULL_DAG: u16 = 1;
+const BITMAP_OPT_HASH_CACHE: u16 = 4;
+/// Commits between two selected ones when generating bitmaps
+const SELECTION_INTERVAL: usize = 100;
+
+/// Uncompressed bitmap, bit `i` is object at position `i` in pack order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    pub fn get(&self, i: usize) -> bool {
+        self.words
+            .get(i / 64)
+            .is_some_and(|w| w & (1 << (i % 64)) != 0)
+    }
+
+    pub fn set(&mut self, i: usize) {
+        if self.words.len() <= i / 64 {
+            self.words.resize(i / 64 + 1, 0);
+        }
+        self.words[i / 64] |= 1 << (i % 64);
+    }
+
+    pub fn or(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    fn xor(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word ^= other;
+        }
+    }
+
+    /// Positions of set bits
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.words.len() * 64).filter(|i| self.get(*i))
+    }
+
+    /// Parses EWAH compressed bitmap, returning it and number of bytes consumed. Each run
+    /// length word holds the running bit, 32 bits of run length and 31 bits of number of
+    /// literal words following it.
+    fn read_ewah(data: &[u8]) -> Result<(Self, usize)> {
+        let u32_at = |pos: usize| -> Result<u32> {
+            let bytes = data
+                .get(pos..pos + 4)
+                .ok_or(format_err!("Truncated bitmap"))?;
+            Ok(u32::from_be_bytes(bytes.try_into()?))
+        };
+        let word_count = u32_at(4)? as usize;
+        let words_end = 8 + word_count * 8;
+        ensure!(data.len() >= words_end + 4, "Truncated bitmap");
+        let compressed: Vec<u64> = data[8..words_end]
+            .chunks_exact(8)
+            .map(|w| u64::from_be_bytes(w.try_into().unwrap()))
+            .collect();
+        let mut words = Vec::new();
+        let mut i = 0;
+        while i < compressed.len() {
+            let rlw = compressed[i];
+            let run = if rlw & 1 == 0 { 0 } else { u64::MAX };
+            let run_len = ((rlw >> 1) & 0xffff_ffff) as usize;
+            let literals = (rlw >> 33) as usize;
+            ensure!(i + 1 + literals <= compressed.len(), "Truncated bitmap");
+            words.extend(std::iter::repeat_n(run, run_len));
+            words.extend(&compressed[i + 1..i + 1 + literals]);
+            i += 1 + literals;
+        }
+        Ok((Self { words }, words_end + 4))
+    }
+
+    fn write_ewah(&self, out: &mut Vec<u8>) {
+        let mut compressed = Vec::new();
+        let mut last_rlw = 0;
+        let mut i = 0;
+        while i < self.words.len() {
+            let run_word = self.words[i];
+            let mut run_len = 0;
+            if run_word == 0 || run_word == u64::MAX {
+                while i < self.words.len() && self.words[i] == run_word && run_len < u32::MAX {
+                    run_len += 1;
+                    i += 1;
+                }
+            }
+            let start = i;
+            while i < self.words.len()
+                && self.words[i] != 0
+                && self.words[i] != u64::MAX
+                && i - start < 0x7fff_ffff
+            {
+                i += 1;
+            }
+            let running_bit = (run_len > 0 && run_word == u64::MAX) as u64;
+            last_rlw = compressed.len();
+            compressed.push(running_bit | (run_len as u64) << 1 | ((i - start) as u64) << 33);
+            compressed.extend(&self.words[start..i]);
+        }
+        let bit_size = self.words.len() * 64;
+        out.extend((bit_size as u32).to_be_bytes());
+        out.extend((compressed.len() as u32).to_be_bytes());
+        for word in compressed {
+            out.extend(word.to_be_bytes());
+        }
+        out.extend((last_rlw as u32).to_be_bytes());
+    }
+}
+
+/// Object ids of the pack sorted by their offset, the order bitmap bits refer to
+fn pack_order(idx: &mut PackIndex) -> Result<Vec<ObjectId>> {
+    let mut entries = Vec::with_capacity(idx.len());
+    for i in 0..idx.len() {
+        entries.push((idx.offset_at(i)?, idx.id_at(i)?));
+    }
+    entries.sort_unstable();
+    Ok(entries.into_iter().map(|(_, id)| id).collect())
+}
+
+/// Checksum of the pack recorded in its index
+fn pack_checksum(idx_path: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(idx_path).context("Reading pack index")?;
+    ensure!(data.len() >= 40, "Truncated pack index");
+    Ok(data[data.len() - 40..data.len() - 20].to_vec())
+}
+
+/// Reachability bitmaps of pack (`.bitmap` file), giving closures of selected commits
+pub struct PackBitmap {
+    /// Object ids in pack order
+    objects: Vec<ObjectId>,
+    positions: HashMap<ObjectId, usize>,
+    commits: HashMap<ObjectId, Bitmap>,
+}
+
+impl PackBitmap {
+    /// Loads bitmap of the pack with the index, `None` when the pack has none
+    pub fn open(idx_path: &Path) -> Result<Option<Self>> {
+        let path = idx_path.with_extension("bitmap");
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let data = fs::read(&path).context("Reading pack bitmap")?;
+        ensure!(
+            data.len() >= BITMAP_HEADER_LEN + 20 && &data[..4] == BITMAP_SIGNATURE,
+            "Invalid pack bitmap signature"
+        );
+        ensure!(
+            data[4..6] == [0, 1],
+            "Only pack bitmap version 1 is supported"
+        );
+        let (body, trailer) = data.split_at(data.len() - 20);
+        ensure!(
+            Sha1::digest(body).as_slice() == trailer,
+            "Pack bitmap checksum mismatch"
+        );
+        let flags = u16::from_be_bytes(data[6..8].try_into()?);
+        let count = u32::from_be_bytes(data[8..12].try_into()?) as usize;
+        ensure!(
+            data[12..32] == pack_checksum(idx_path)?[..],
+            "Pack bitmap does not match its pack"
+        );
+
+        let mut idx = PackIndex::open(idx_path, HashAlgo::Sha1)?;
+        let objects = pack_order(&mut idx)?;
+        let positions = objects.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+
+        let mut pos = BITMAP_HEADER_LEN;
+        // Bitmaps of commits, trees, blobs and tags are not needed for walks
+        for _ in 0..4 {
+            pos += Bitmap::read_ewah(&body[pos..])?.1;
+        }
+        let mut entries: Vec<(ObjectId, Bitmap)> = Vec::with_capacity(count);
+        for i in 0..count {
+            ensure!(body.len() >= pos + 6, "Truncated pack bitmap");
+            let index_pos = u32::from_be_bytes(body[pos..pos + 4].try_into()?) as usize;
+            let xor_offset = body[pos + 4] as usize;
+            let (mut bitmap, len) = Bitmap::read_ewah(&body[pos + 6..])?;
+            pos += 6 + len;
+            if xor_offset > 0 {
+                ensure!(xor_offset <= i, "Invalid pack bitmap xor offset");
+                bitmap.xor(&entries[i - xor_offset].1);
+            }
+            ensure!(index_pos < idx.len(), "Invalid pack bitmap commit position");
+            entries.push((idx.id_at(index_pos)?, bitmap));
+        }
+        if flags & BITMAP_OPT_HASH_CACHE != 0 {
+            ensure!(
+                body.len() >= pos + 4 * objects.len(),
+                "Truncated pack bitmap"
+            );
+        }
+        Ok(Some(Self {
+            objects,
+            positions,
+            commits: entries.into_iter().collect(),
+        }))
+    }
+
+    /// Objects reachable from the tips, as bitmap of packed objects and ids of objects
+    /// outside of the pack. Walk stops at commits with bitmaps.
+    fn closure(&self, repo: &GitRepo, tips: &[ObjectId]) -> Result<(Bitmap, HashSet<ObjectId>)> {
+        let mut bitmap = Bitmap::default();
+        let mut outside = HashSet::new();
+        let mut stack = tips.to_vec();
+        while let Some(id) = stack.pop() {
+            match self.positions.get(&id) {
+                Some(&pos) if bitmap.get(pos) => continue,
+                Some(&pos) => match self.commits.get(&id) {
+                    Some(commit) => {
+                        bitmap.or(commit);
+                        continue;
+                    }
+                    None => bitmap.set(pos),
+                },
+                None if !outside.insert(id) => continue,
+                None => {}
+            }
+            stack.extend(Object::read(repo, &id)?.links()?);
+        }
+        Ok((bitmap, outside))
+    }
+}
+
+/// Ids of objects reachable from `wants` which are not reachable from `haves`, computed
+/// with the first pack having bitmaps. `None` when no pack has them, bitmaps are read
+/// only in SHA-1 repositories.
+pub fn objects_to_send(
+    repo: &GitRepo,
+    wants: &[ObjectId],
+    haves: &[ObjectId],
+) -> Result<Option<Vec<ObjectId>>> {
+    if repo.hash_algo() != HashAlgo::Sha1 {
+        return Ok(None);
+    }
+    let mut bitmap = None;
+    for idx_path in pack_indexes(repo)? {
+        bitmap = PackBitmap::open(&idx_path)?;
+        if bitmap.is_some() {
+            break;
+        }
+    }
+    let Some(bitmap) = bitmap else {
+        return Ok(None);
+    };
+    let (want_bits, want_outside) = bitmap.closure(repo, wants)?;
+    let (have_bits, have_outside) = bitmap.closure(repo, haves)?;
+    let mut ids: Vec<ObjectId> = want_bits
+        .ones()
+        .filter(|i| !have_bits.get(*i))
+        .map(|i| bitmap.objects[i])
+        .collect();
+    ids.extend(want_outside.difference(&have_outside).cloned());
+    Ok(Some(ids))
+}
+
+/// Commits of the pack in topological order, parents first
+fn topo_order(objects: &HashMap<ObjectId, &Object>) -> Result<Vec<ObjectId>> {
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    let mut commits: Vec<_> = objects
+        .iter()
+        .filter(|(_, obj)| obj.header.kind == ObjectKind::Commit)
+        .map(|(id, _)| *id)
+        .collect();
+    commits.sort_unstable();
+    for start in commits {
+        // Stack of commits with flag whether their parents were already pushed
+        let mut stack = vec![(start, false)];
+        while let Some((id, expanded)) = stack.pop() {
+            if expanded {
+                order.push(id);
+                continue;
+            }
+            if !visited.insert(id) {
+                continue;
+            }
+            stack.push((id, true));
+            for parent in objects[&id].links()?.into_iter().skip(1) {
+                if objects.contains_key(&parent) && !visited.contains(&parent) {
+                    stack.push((parent, false));
+                }
+            }
+        }
+    }
+    Ok(order)
+}
+
+/// Writes bitmaps for the pack with the index, which has to contain all objects
+/// reachable from its commits. Bitmaps are stored for commits refs point to and every
+/// hundredth commit in topological order.
+pub fn write(repo: &GitRepo, idx_path: &Path, objects: &[Object]) -> Result<PathBuf> {
+    let mut idx = PackIndex::open(idx_path, HashAlgo::Sha1)?;
+    let order = pack_order(&mut idx)?;
+    let positions: HashMap<ObjectId, usize> =
+        order.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+    let mut by_id = HashMap::new();
+    for obj in objects {
+        let id = obj.hash;
+        ensure!(
+            positions.contains_key(&id),
+            "Object {} is not in the pack",
+            obj.hash
+        );
+        by_id.insert(id, obj);
+    }
+
+    let mut types = [
+        Bitmap::default(),
+        Bitmap::default(),
+        Bitmap::default(),
+        Bitmap::default(),
+    ];
+    for (id, obj) in &by_id {
+        let kind = match obj.header.kind {
+            ObjectKind::Commit => 0,
+            ObjectKind::Tree => 1,
+            ObjectKind::Blob => 2,
+            ObjectKind::Tag => 3,
+        };
+        types[kind].set(positions[id]);
+    }
+
+    let topo = topo_order(&by_id)?;
+    let mut tips = HashSet::new();
+    for (_, id) in refs::list(repo)? {
+        if let Ok(commit) = peel_to_commit(repo, &id) {
+            tips.insert(commit);
+        }
+    }
+    let selected: Vec<ObjectId> = topo
+        .iter()
+        .enumerate()
+        .filter(|(i, id)| tips.contains(*id) || (i + 1) % SELECTION_INTERVAL == 0)
+        .map(|(_, id)| *id)
+        .collect();
+
+    // Closures of selected commits, older ones are reused by walks of newer ones
+    let mut bitmaps: HashMap<ObjectId, Bitmap> = HashMap::new();
+    let mut entries = Vec::with_capacity(selected.len());
+    for commit in &selected {
+        let mut bitmap = Bitmap::default();
+        let mut stack = vec![*commit];
+        while let Some(id) = stack.pop() {
+            let pos = *positions
+                .get(&id)
+                .ok_or(format_err!("Pack is not closed, {} is missing", id))?;
+            if bitmap.get(pos) {
+                continue;
+            }
+            if let Some(done) = bitmaps.get(&id) {
+                bitmap.or(done);
+                continue;
+            }
+            bitmap.set(pos);
+            let obj = by_id
+                .get(&id)
+                .ok_or(format_err!("Object {} was not given", id))?;
+            stack.extend(obj.links()?);
+        }
+        bitmaps.insert(*commit, bitmap.clone());
+        let index_pos = idx
+            .find_position(commit)?
+            .ok_or(format_err!("Commit {} is not in the pack", commit))?;
+        entries.push((index_pos, bitmap));
+    }
+
+    let mut data = Vec::new();
+    data.extend(BITMAP_SIGNATURE);
+    data.extend(1u16.to_be_bytes());
+    data.extend(BITMAP_OPT_FULL_DAG.to_be_bytes());
+    data.extend((entries.len() as u32).to_be_bytes());
+    data.extend(pack_checksum(idx_path)?);
+    for bitmap in &types {
+        bitmap.write_ewah(&mut data);
+    }
+    for (index_pos, bitmap) in &entries {
+        data.extend((*index_pos as u32).to_be_bytes());
+        // Neither XOR compression nor flags are used
+        data.extend([0, 0]);
+        bitmap.write_ewah(&mut data);
+    }
+    let checksum = Sha1::digest(&data);
+    data.extend(checksum);
+
+    let path = idx_path.with_extension("bitmap");
+    storage::write_file(repo, &path, &data, FsyncComponent::PackMetadata)?;
+    Ok(path)
+}
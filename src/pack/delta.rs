@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use crate::error::{bail, ensure, format_err, Result};
+
+/// Reads size encoded as little-endian base-128 varint used in delta headers
+fn read_size(data: &mut &[u8]) -> Result<usize> {
+    let mut size = 0usize;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = data
+            .split_first()
+            .ok_or(format_err!("Truncated delta header"))?;
+        *data = rest;
+        ensure!(shift < usize::BITS, "Delta size overflows");
+        size |= ((byte & 127) as usize) << shift;
+        shift += 7;
+        if byte < 128 {
+            return Ok(size);
+        }
+    }
+}
+
+/// Applies git delta instructions onto base object data
+pub fn apply(base: &[u8], mut delta: &[u8]) -> Result<Vec<u8>> {
+    let base_size = read_size(&mut delta)?;
+    ensure!(base_size == base.len(), "Delta base size mismatch");
+    let result_size = read_size(&mut delta)?;
+    let mut result = Vec::with_capacity(result_size.min(super::MAX_PREALLOC));
+
+    while let Some((&cmd, rest)) = delta.split_first() {
+        delta = rest;
+        if cmd & 128 != 0 {
+            // Copy from base, bits of cmd say which offset/size bytes are present
+            let mut offset = 0usize;
+            let mut size = 0usize;
+            for i in 0..4 {
+                if cmd & (1 << i) != 0 {
+                    let (&b, rest) = delta.split_first().ok_or(format_err!("Truncated delta"))?;
+                    delta = rest;
+                    offset |= (b as usize) << (8 * i);
+                }
+            }
+            for i in 0..3 {
+                if cmd & (16 << i) != 0 {
+                    let (&b, rest) = delta.split_first().ok_or(format_err!("Truncated delta"))?;
+                    delta = rest;
+                    size |= (b as usize) << (8 * i);
+                }
+            }
+            if size == 0 {
+                size = 0x10000;
+            }
+            ensure!(offset + size <= base.len(), "Delta copy out of base bounds");
+            ensure!(
+                result.len() + size <= result_size,
+                "Delta result size mismatch"
+            );
+            result.extend(&base[offset..offset + size]);
+        } else if cmd != 0 {
+            let size = cmd as usize;
+            ensure!(size <= delta.len(), "Truncated delta insert");
+            ensure!(
+                result.len() + size <= result_size,
+                "Delta result size mismatch"
+            );
+            result.extend(&delta[..size]);
+            delta = &delta[size..];
+        } else {
+            bail!("Reserved delta instruction");
+        }
+    }
+    ensure!(result.len() == result_size, "Delta result size mismatch");
+    Ok(result)
+}
+
+/// Length of base blocks indexed when searching for copies
+const BLOCK: usize = 16;
+/// Largest copy encodable in single instruction, git limits it for compatibility
+const MAX_COPY: usize = 0x10000;
+/// Largest insert instruction
+const MAX_INSERT: usize = 127;
+
+fn write_size(mut size: usize, out: &mut Vec<u8>) {
+    while size >= 128 {
+        out.push((size & 127) as u8 | 128);
+        size >>= 7;
+    }
+    out.push(size as u8);
+}
+
+fn flush_insert(insert: &mut Vec<u8>, out: &mut Vec<u8>) {
+    for chunk in insert.chunks(MAX_INSERT) {
+        out.push(chunk.len() as u8);
+        out.extend(chunk);
+    }
+    insert.clear();
+}
+
+fn write_copy(mut offset: usize, mut size: usize, out: &mut Vec<u8>) {
+    while size > 0 {
+        let len = size.min(MAX_COPY);
+        let mut cmd = 128u8;
+        let mut args = Vec::with_capacity(7);
+        for i in 0..4 {
+            let byte = (offset >> (8 * i)) as u8;
+            if byte != 0 {
+                cmd |= 1 << i;
+                args.push(byte);
+            }
+        }
+        // Size 0x10000 is encoded without any size bytes
+        let encoded = if len == MAX_COPY { 0 } else { len };
+        for i in 0..3 {
+            let byte = (encoded >> (8 * i)) as u8;
+            if byte != 0 {
+                cmd |= 16 << i;
+                args.push(byte);
+            }
+        }
+        out.push(cmd);
+        out.extend(args);
+        offset += len;
+        size -= len;
+    }
+}
+
+/// Creates git delta instructions producing `target` from `base`. Copies are found by
+/// looking up blocks of the target in index of non-overlapping base blocks.
+pub fn create(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_size(base.len(), &mut out);
+    write_size(target.len(), &mut out);
+
+    let mut index: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for start in (0..base.len().saturating_sub(BLOCK - 1)).step_by(BLOCK) {
+        index
+            .entry(&base[start..start + BLOCK])
+            .or_default()
+            .push(start);
+    }
+
+    let mut insert = Vec::new();
+    let mut pos = 0;
+    while pos < target.len() {
+        let best = target
+            .get(pos..pos + BLOCK)
+            .and_then(|block| index.get(block))
+            .into_iter()
+            .flatten()
+            .map(|&start| {
+                let len = base[start..]
+                    .iter()
+                    .zip(&target[pos..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (start, len)
+            })
+            .max_by_key(|(_, len)| *len);
+        match best {
+            Some((mut start, len)) => {
+                pos += len;
+                // Extend the copy backwards over bytes queued for insertion
+                let mut copy = len;
+                while start > 0 && insert.last() == Some(&base[start - 1]) {
+                    insert.pop();
+                    start -= 1;
+                    copy += 1;
+                }
+                flush_insert(&mut insert, &mut out);
+                write_copy(start, copy, &mut out);
+            }
+            None => {
+                insert.push(target[pos]);
+                pos += 1;
+            }
+        }
+    }
+    flush_insert(&mut insert, &mut out);
+    out
+}
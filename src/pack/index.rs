@@ -0,0 +1,462 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use flate2::Crc;
+
+use super::{
+    parallel_map, resolve_entries, verify_checksum, write_entry, DeltaBase, EntryHeader,
+    ResolvedEntries,
+};
+use crate::error::{ensure, format_err, Context, Result};
+use crate::fsck;
+use crate::hash::HashAlgo;
+use crate::mmap::Mmap;
+use crate::object::ObjectKind;
+use crate::oid::ObjectId;
+use crate::storage::{self, FsyncComponent};
+use crate::GitRepo;
+
+const IDX_SIGNATURE: [u8; 4] = [0xff, b't', b'O', b'c'];
+const IDX_HEADER_LEN: u64 = 8;
+const FANOUT_LEN: u64 = 256 * 4;
+
+/// Pack index (version 2) backed by mapped file, entries are looked up lazily
+pub struct PackIndex {
+    data: Mmap,
+    fanout: [u32; 256],
+    /// Object ids are named by the hash of the repository, the format doesn't say
+    algo: HashAlgo,
+}
+
+impl PackIndex {
+    pub fn open(path: &Path, algo: HashAlgo) -> Result<Self> {
+        let data = Mmap::open(path).context("Opening pack index")?;
+        let header = data
+            .get(..(IDX_HEADER_LEN + FANOUT_LEN) as usize)
+            .ok_or(format_err!("Truncated pack index header"))?;
+        ensure!(header[..4] == IDX_SIGNATURE, "Invalid pack index signature");
+        ensure!(
+            header[4..8] == [0, 0, 0, 2],
+            "Only pack index version 2 is supported"
+        );
+        let mut fanout = [0u32; 256];
+        for (i, chunk) in header[8..].chunks_exact(4).enumerate() {
+            fanout[i] = u32::from_be_bytes(chunk.try_into()?);
+        }
+        Ok(Self { data, fanout, algo })
+    }
+
+    pub fn len(&self) -> usize {
+        self.fanout[255] as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<()> {
+        let start = usize::try_from(pos)?;
+        let data = self
+            .data
+            .get(start..start + buf.len())
+            .ok_or(format_err!("Truncated pack index"))?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Object id at given position in sorted order
+    pub fn id_at(&mut self, i: usize) -> Result<ObjectId> {
+        let len = self.algo.id_len();
+        let mut id = [0u8; 32];
+        self.read_at(
+            IDX_HEADER_LEN + FANOUT_LEN + (len * i) as u64,
+            &mut id[..len],
+        )?;
+        ObjectId::from_slice(&id[..len])
+    }
+
+    /// Pack offset of object at given position in sorted order
+    pub fn offset_at(&mut self, i: usize) -> Result<u64> {
+        let n = self.len() as u64;
+        let offsets_start = IDX_HEADER_LEN + FANOUT_LEN + n * (self.algo.id_len() as u64 + 4);
+        let mut buf = [0u8; 4];
+        self.read_at(offsets_start + 4 * i as u64, &mut buf)?;
+        let offset = u32::from_be_bytes(buf);
+        if offset & 0x8000_0000 == 0 {
+            return Ok(offset as u64);
+        }
+        let mut buf = [0u8; 8];
+        let large_idx = (offset & 0x7fff_ffff) as u64;
+        self.read_at(offsets_start + 4 * n + 8 * large_idx, &mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    /// Object ids starting with given hex prefix
+    pub fn ids_with_prefix(&mut self, prefix: &str) -> Result<Vec<ObjectId>> {
+        let Some(first) = prefix.get(..2).and_then(|b| u8::from_str_radix(b, 16).ok()) else {
+            return Ok(Vec::new());
+        };
+        let first = first as usize;
+        let lo = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1] as usize
+        };
+        let hi = self.fanout[first] as usize;
+        let mut ids = Vec::new();
+        for i in lo..hi {
+            let id = self.id_at(i)?;
+            if id.to_hex().starts_with(prefix) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Finds position of given object in sorted order
+    pub fn find_position(&mut self, id: &ObjectId) -> Result<Option<usize>> {
+        let first = id.as_bytes()[0] as usize;
+        let mut lo = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1] as usize
+        };
+        let mut hi = self.fanout[first] as usize;
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.id_at(mid)?.cmp(id) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(mid)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Finds pack offset of given object
+    pub fn find(&mut self, id: &ObjectId) -> Result<Option<u64>> {
+        match self.find_position(id)? {
+            Some(i) => self.offset_at(i).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// CRC-32 of the packed entry at given position in sorted order
+    pub fn crc_at(&mut self, i: usize) -> Result<u32> {
+        let n = self.len() as u64;
+        let mut buf = [0u8; 4];
+        let crcs_start = IDX_HEADER_LEN + FANOUT_LEN + n * self.algo.id_len() as u64;
+        self.read_at(crcs_start + 4 * i as u64, &mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    /// Checksum of the pack the index belongs to, after checking the index's own one
+    pub fn verified_pack_checksum(&self) -> Result<&[u8]> {
+        let len = self.algo.id_len();
+        ensure!(self.data.len() >= 2 * len, "Truncated pack index");
+        let (content, checksum) = self.data.split_at(self.data.len() - len);
+        ensure!(
+            self.algo.digest(content).as_bytes() == checksum,
+            "Pack index checksum mismatch"
+        );
+        Ok(&content[content.len() - len..])
+    }
+}
+
+/// Single indexed entry of a pack
+pub struct IndexEntry {
+    pub id: ObjectId,
+    pub offset: u64,
+    pub crc32: u32,
+}
+
+/// Serializes pack index version 2, checksummed with the hash of the repository
+pub fn write_index(entries: &mut [IndexEntry], pack_checksum: &[u8], algo: HashAlgo) -> Vec<u8> {
+    entries.sort_unstable_by_key(|e| e.id);
+    let mut data = Vec::with_capacity(1100 + entries.len() * 28);
+    data.extend(IDX_SIGNATURE);
+    data.extend(2u32.to_be_bytes());
+    let mut count = 0u32;
+    for byte in 0..=255u8 {
+        count += entries[count as usize..]
+            .iter()
+            .take_while(|e| e.id.as_bytes()[0] == byte)
+            .count() as u32;
+        data.extend(count.to_be_bytes());
+    }
+    for entry in entries.iter() {
+        data.extend(entry.id.as_bytes());
+    }
+    for entry in entries.iter() {
+        data.extend(entry.crc32.to_be_bytes());
+    }
+    let mut large_offsets = Vec::new();
+    for entry in entries.iter() {
+        if entry.offset < 0x8000_0000 {
+            data.extend((entry.offset as u32).to_be_bytes());
+        } else {
+            data.extend((0x8000_0000 | large_offsets.len() as u32).to_be_bytes());
+            large_offsets.push(entry.offset);
+        }
+    }
+    for offset in large_offsets {
+        data.extend(offset.to_be_bytes());
+    }
+    data.extend(pack_checksum);
+    let checksum = algo.digest(&data);
+    data.extend(checksum.as_bytes());
+    data
+}
+
+/// Result of indexing a pack
+pub struct IndexedPack {
+    /// Hex checksum used in the pack file name
+    pub checksum: String,
+    /// Ids of all objects stored in the pack
+    pub objects: HashSet<ObjectId>,
+}
+
+/// Pack completed to stand on its own, with its index
+pub struct BuiltIndex {
+    pub pack: Vec<u8>,
+    pub index: Vec<u8>,
+    /// Hex checksum used in the pack file name
+    pub checksum: String,
+    pub objects: HashSet<ObjectId>,
+}
+
+/// Parses pack data, resolves deltas and builds index of the pack. Thin packs are
+/// completed by appending delta bases found in the repository when `fix_thin` is set,
+/// otherwise they are refused. With `fsck` the structure of every object is verified.
+pub fn build_index(repo: &GitRepo, data: &[u8], fsck: bool, fix_thin: bool) -> Result<BuiltIndex> {
+    let algo = repo.hash_algo();
+    let body = verify_checksum(data, algo)?;
+    let ResolvedEntries {
+        mut objects,
+        spans,
+        external,
+    } = resolve_entries(repo, body)?;
+    ensure!(
+        fix_thin || external.is_empty(),
+        "pack has deltas against {} objects outside of it",
+        external.len()
+    );
+    let mut offsets = Vec::with_capacity(spans.len());
+    let mut crcs = Vec::with_capacity(spans.len());
+    for (offset, len) in spans {
+        let mut crc = Crc::new();
+        crc.update(&body[offset as usize..offset as usize + len]);
+        offsets.push(offset);
+        crcs.push(crc.sum());
+    }
+
+    let mut pack = data.to_vec();
+    if !external.is_empty() {
+        // Complete thin pack so it doesn't depend on objects outside of it
+        pack.truncate(body.len());
+        for obj in external {
+            let start = pack.len();
+            write_entry(&mut pack, &obj, repo.write_options()?.pack_compression)?;
+            let mut crc = Crc::new();
+            crc.update(&pack[start..]);
+            offsets.push(start as u64);
+            crcs.push(crc.sum());
+            objects.push(obj);
+        }
+        let count = u32::try_from(objects.len())?;
+        pack[8..12].copy_from_slice(&count.to_be_bytes());
+        let checksum = algo.digest(&pack);
+        pack.extend(checksum.as_bytes());
+    }
+    let pack_checksum = pack[pack.len() - algo.id_len()..].to_vec();
+
+    if fsck {
+        parallel_map(objects.iter().collect(), |obj| {
+            fsck::verify_object(repo, obj)
+        })?;
+    }
+
+    let mut index_entries = Vec::with_capacity(objects.len());
+    let mut ids = HashSet::with_capacity(objects.len());
+    for ((obj, offset), crc32) in objects.into_iter().zip(offsets).zip(crcs) {
+        index_entries.push(IndexEntry {
+            id: obj.hash,
+            offset,
+            crc32,
+        });
+        ids.insert(obj.hash);
+    }
+    let index = write_index(&mut index_entries, &pack_checksum, algo);
+    Ok(BuiltIndex {
+        pack,
+        index,
+        checksum: hex::encode(&pack_checksum),
+        objects: ids,
+    })
+}
+
+/// Parses pack data, resolves deltas and stores pack with its index under `objects/pack`.
+/// Thin packs are completed by appending delta bases found in the repository. With `fsck`
+/// the structure of every object is verified before the pack is stored.
+pub fn index_pack(repo: &GitRepo, data: &[u8], fsck: bool) -> Result<IndexedPack> {
+    store(repo, build_index(repo, data, fsck, true)?)
+}
+
+/// Stores built pack with its index under `objects/pack`
+pub fn store(repo: &GitRepo, built: BuiltIndex) -> Result<IndexedPack> {
+    let pack_dir = repo.objects_dir.join("pack");
+    fs::create_dir_all(&pack_dir).context("Creating pack dir")?;
+    store_as(repo, built, &pack_dir.join("pack"))
+}
+
+/// Stores built pack with its index as `<base_name>-<checksum>.pack` and `.idx`
+pub fn store_as(repo: &GitRepo, built: BuiltIndex, base_name: &Path) -> Result<IndexedPack> {
+    let path = |extension: &str| {
+        let mut name = base_name.as_os_str().to_owned();
+        name.push(format!("-{}.{}", built.checksum, extension));
+        PathBuf::from(name)
+    };
+    storage::write_file(repo, &path("pack"), &built.pack, FsyncComponent::Pack)?;
+    // Index is written last, pack becomes visible only once it is complete
+    let tmp_index = path("idx.tmp");
+    storage::write_file(repo, &tmp_index, &built.index, FsyncComponent::PackMetadata)?;
+    fs::rename(&tmp_index, path("idx")).context("Writing pack index")?;
+
+    Ok(IndexedPack {
+        checksum: built.checksum,
+        objects: built.objects,
+    })
+}
+
+/// Writes index of the pack file to `index_path`, next to the pack by default, and
+/// returns the pack checksum. The pack itself is left as it is, so it may not be thin.
+pub fn index_pack_file(
+    repo: &GitRepo,
+    pack_path: &Path,
+    index_path: Option<&Path>,
+    fsck: bool,
+) -> Result<String> {
+    let data = fs::read(pack_path).with_context(|| format!("Reading {}", pack_path.display()))?;
+    let built = build_index(repo, &data, fsck, false)?;
+    let index_path = match index_path {
+        Some(path) => path.to_path_buf(),
+        None => pack_path.with_extension("idx"),
+    };
+    storage::write_file(
+        repo,
+        &index_path,
+        &built.index,
+        FsyncComponent::PackMetadata,
+    )?;
+    Ok(built.checksum)
+}
+
+/// Entry of verified pack
+pub struct VerifiedEntry {
+    pub id: ObjectId,
+    pub kind: ObjectKind,
+    /// Size of the inflated entry data, the delta for deltified objects
+    pub size: usize,
+    pub packed_size: usize,
+    pub offset: u64,
+    /// Length of the delta chain and the base of the delta
+    pub delta: Option<(usize, ObjectId)>,
+}
+
+/// Verifies checksums of the pack and its index and that every entry of the pack is
+/// indexed with its offset and CRC. Returns the entries in pack order.
+pub fn verify_pack(repo: &GitRepo, idx_path: &Path) -> Result<Vec<VerifiedEntry>> {
+    let algo = repo.hash_algo();
+    let mut idx = PackIndex::open(idx_path, algo)?;
+    let pack_path = idx_path.with_extension("pack");
+    let data = fs::read(&pack_path).with_context(|| format!("Reading {}", pack_path.display()))?;
+    let body = verify_checksum(&data, algo)?;
+    ensure!(
+        idx.verified_pack_checksum()? == &data[body.len()..],
+        "Pack {} does not match its index",
+        pack_path.display()
+    );
+    let entries = resolve_entries(repo, body)?;
+    ensure!(
+        entries.external.is_empty(),
+        "Pack has deltas against objects outside of it"
+    );
+    ensure!(
+        idx.len() == entries.objects.len(),
+        "Index has {} objects, pack {}",
+        idx.len(),
+        entries.objects.len()
+    );
+
+    let mut by_offset = HashMap::new();
+    let mut by_id = HashMap::new();
+    let mut headers = Vec::with_capacity(entries.spans.len());
+    for (i, (obj, (offset, len))) in entries.objects.iter().zip(&entries.spans).enumerate() {
+        let position = idx
+            .find_position(&obj.hash)?
+            .ok_or(format_err!("Object {} is missing from index", obj.hash))?;
+        ensure!(
+            idx.offset_at(position)? == *offset,
+            "Index has wrong offset of {}",
+            obj.hash
+        );
+        let start = *offset as usize;
+        let mut crc = Crc::new();
+        crc.update(&body[start..start + len]);
+        ensure!(
+            idx.crc_at(position)? == crc.sum(),
+            "CRC mismatch of {}",
+            obj.hash
+        );
+        by_offset.insert(*offset, i);
+        by_id.insert(obj.hash, i);
+        headers.push(EntryHeader::parse(&body[start..], *offset, algo)?);
+    }
+
+    let base_of = |i: usize| match &headers[i].base {
+        Some(DeltaBase::Offset(offset)) => Some(by_offset[offset]),
+        Some(DeltaBase::Ref(id)) => Some(by_id[id]),
+        None => None,
+    };
+    let mut depths: Vec<Option<usize>> = vec![None; headers.len()];
+    for i in 0..headers.len() {
+        // Chain is followed to a known depth, then depths are filled in backwards
+        let mut chain = vec![i];
+        let mut depth = loop {
+            let last = *chain.last().unwrap();
+            if let Some(depth) = depths[last] {
+                chain.pop();
+                break depth;
+            }
+            match base_of(last) {
+                Some(base) => {
+                    ensure!(chain.len() <= headers.len(), "Delta chain loop in pack");
+                    chain.push(base)
+                }
+                None => {
+                    chain.pop();
+                    depths[last] = Some(0);
+                    break 0;
+                }
+            }
+        };
+        for entry in chain.into_iter().rev() {
+            depth += 1;
+            depths[entry] = Some(depth);
+        }
+    }
+
+    let mut verified = Vec::with_capacity(headers.len());
+    for (i, (obj, (offset, len))) in entries.objects.iter().zip(entries.spans).enumerate() {
+        verified.push(VerifiedEntry {
+            id: obj.hash,
+            kind: obj.header.kind,
+            size: headers[i].size,
+            packed_size: len,
+            offset,
+            delta: base_of(i).map(|base| (depths[i].unwrap(), entries.objects[base].hash)),
+        });
+    }
+    Ok(verified)
+}
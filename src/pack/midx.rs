@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{prelude::*, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use sha1::{Digest, Sha1};
+
+use super::{index::PackIndex, pack_indexes};
+use crate::error::{bail, ensure, format_err, Context, Result};
+use crate::hash::HashAlgo;
+use crate::oid::ObjectId;
+use crate::storage::{self, FsyncComponent};
+use crate::GitRepo;
+
+const MIDX_SIGNATURE: &[u8; 4] = b"MIDX";
+const MIDX_HEADER_LEN: usize = 12;
+const CHUNK_PACK_NAMES: &[u8; 4] = b"PNAM";
+const CHUNK_OID_FANOUT: &[u8; 4] = b"OIDF";
+const CHUNK_OID_LOOKUP: &[u8; 4] = b"OIDL";
+const CHUNK_OFFSETS: &[u8; 4] = b"OOFF";
+const CHUNK_LARGE_OFFSETS: &[u8; 4] = b"LOFF";
+
+/// Path of the multi-pack-index of the repository
+pub fn path(repo: &GitRepo) -> PathBuf {
+    repo.objects_dir.join("pack").join("multi-pack-index")
+}
+
+/// Multi-pack-index (version 1) covering several packs with single sorted list of object
+/// ids, backed by file and looked up lazily like `PackIndex`
+pub struct MultiPackIndex {
+    file: File,
+    fanout: [u32; 256],
+    /// File names of the pack indexes, position is the pack id used by entries
+    pack_names: Vec<String>,
+    oid_lookup: u64,
+    offsets: u64,
+    large_offsets: Option<u64>,
+}
+
+impl MultiPackIndex {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path).context("Opening multi-pack-index")?;
+        let mut header = [0u8; MIDX_HEADER_LEN];
+        file.read_exact(&mut header)
+            .context("Reading multi-pack-index header")?;
+        ensure!(
+            &header[..4] == MIDX_SIGNATURE,
+            "Invalid multi-pack-index signature"
+        );
+        ensure!(
+            header[4] == 1,
+            "Only multi-pack-index version 1 is supported"
+        );
+        ensure!(header[5] == 1, "Only SHA-1 multi-pack-index is supported");
+        ensure!(
+            header[7] == 0,
+            "Incremental multi-pack-index is not supported"
+        );
+        let chunk_count = header[6] as usize;
+        let pack_count = u32::from_be_bytes(header[8..12].try_into()?) as usize;
+
+        let mut table = vec![0u8; (chunk_count + 1) * 12];
+        file.read_exact(&mut table)
+            .context("Reading multi-pack-index chunks")?;
+        let mut chunks = BTreeMap::new();
+        for (i, entry) in table.chunks_exact(12).take(chunk_count).enumerate() {
+            let id: [u8; 4] = entry[..4].try_into()?;
+            let start = u64::from_be_bytes(entry[4..].try_into()?);
+            let end = u64::from_be_bytes(table[(i + 1) * 12 + 4..(i + 2) * 12].try_into()?);
+            ensure!(start <= end, "Invalid multi-pack-index chunk table");
+            chunks.insert(id, (start, end));
+        }
+        let chunk = |id: &[u8; 4]| {
+            chunks.get(id).copied().ok_or(format_err!(
+                "Multi-pack-index is missing {} chunk",
+                String::from_utf8_lossy(id)
+            ))
+        };
+
+        let (names_start, names_end) = chunk(CHUNK_PACK_NAMES)?;
+        let mut names = vec![0u8; (names_end - names_start) as usize];
+        file.seek(SeekFrom::Start(names_start))?;
+        file.read_exact(&mut names)?;
+        let pack_names: Vec<String> = names
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        ensure!(
+            pack_names.len() == pack_count,
+            "Multi-pack-index pack count mismatch"
+        );
+
+        let mut fanout_buf = [0u8; 256 * 4];
+        file.seek(SeekFrom::Start(chunk(CHUNK_OID_FANOUT)?.0))?;
+        file.read_exact(&mut fanout_buf)?;
+        let mut fanout = [0u32; 256];
+        for (i, item) in fanout_buf.chunks_exact(4).enumerate() {
+            fanout[i] = u32::from_be_bytes(item.try_into()?);
+        }
+        Ok(Self {
+            file,
+            fanout,
+            pack_names,
+            oid_lookup: chunk(CHUNK_OID_LOOKUP)?.0,
+            offsets: chunk(CHUNK_OFFSETS)?.0,
+            large_offsets: chunks.get(CHUNK_LARGE_OFFSETS).map(|(start, _)| *start),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.fanout[255] as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// File names of pack indexes covered by the multi-pack-index
+    pub fn pack_names(&self) -> &[String] {
+        &self.pack_names
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(pos))?;
+        self.file
+            .read_exact(buf)
+            .context("Reading multi-pack-index")?;
+        Ok(())
+    }
+
+    /// Object id at given position in sorted order
+    pub fn id_at(&mut self, i: usize) -> Result<[u8; 20]> {
+        let mut id = [0u8; 20];
+        self.read_at(self.oid_lookup + 20 * i as u64, &mut id)?;
+        Ok(id)
+    }
+
+    /// Pack id and offset within the pack of object at given position in sorted order
+    pub fn entry_at(&mut self, i: usize) -> Result<(usize, u64)> {
+        let mut buf = [0u8; 8];
+        self.read_at(self.offsets + 8 * i as u64, &mut buf)?;
+        let pack = u32::from_be_bytes(buf[..4].try_into()?) as usize;
+        ensure!(
+            pack < self.pack_names.len(),
+            "Invalid multi-pack-index pack id"
+        );
+        let offset = u32::from_be_bytes(buf[4..].try_into()?);
+        if offset & 0x8000_0000 == 0 {
+            return Ok((pack, offset as u64));
+        }
+        let large_offsets = self
+            .large_offsets
+            .ok_or(format_err!("Multi-pack-index is missing LOFF chunk"))?;
+        let mut buf = [0u8; 8];
+        let large_idx = (offset & 0x7fff_ffff) as u64;
+        self.read_at(large_offsets + 8 * large_idx, &mut buf)?;
+        Ok((pack, u64::from_be_bytes(buf)))
+    }
+
+    fn fanout_range(&self, first: u8) -> (usize, usize) {
+        let first = first as usize;
+        let lo = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1] as usize
+        };
+        (lo, self.fanout[first] as usize)
+    }
+
+    /// Object ids starting with given hex prefix
+    pub fn ids_with_prefix(&mut self, prefix: &str) -> Result<Vec<[u8; 20]>> {
+        let Some(first) = prefix.get(..2).and_then(|b| u8::from_str_radix(b, 16).ok()) else {
+            return Ok(Vec::new());
+        };
+        let (lo, hi) = self.fanout_range(first);
+        let mut ids = Vec::new();
+        for i in lo..hi {
+            let id = self.id_at(i)?;
+            if hex::encode(id).starts_with(prefix) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Finds pack id and pack offset of given object with single binary search
+    pub fn find(&mut self, id: &ObjectId) -> Result<Option<(usize, u64)>> {
+        let (mut lo, mut hi) = self.fanout_range(id.as_bytes()[0]);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.id_at(mid)?.as_slice().cmp(id.as_bytes()) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return self.entry_at(mid).map(Some),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Opens multi-pack-index of the object directory. Missing index, or one referring to
+/// packs which no longer exist, is ignored and packs are searched one by one.
+pub fn open(objects_dir: &Path) -> Result<Option<MultiPackIndex>> {
+    let pack_dir = objects_dir.join("pack");
+    let path = pack_dir.join("multi-pack-index");
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let midx = MultiPackIndex::open(&path)?;
+    let complete = midx
+        .pack_names()
+        .iter()
+        .all(|name| pack_dir.join(name).with_extension("pack").is_file());
+    Ok(complete.then_some(midx))
+}
+
+fn write_chunk_table(data: &mut Vec<u8>, chunks: &[(&[u8; 4], &[u8])]) {
+    let mut offset = (MIDX_HEADER_LEN + (chunks.len() + 1) * 12) as u64;
+    for (id, content) in chunks {
+        data.extend(*id);
+        data.extend(offset.to_be_bytes());
+        offset += content.len() as u64;
+    }
+    data.extend([0u8; 4]);
+    data.extend(offset.to_be_bytes());
+}
+
+/// Writes multi-pack-index covering all packs of the repository. Objects stored in
+/// several packs are taken from the most recently modified one.
+pub fn write(repo: &GitRepo) -> Result<()> {
+    if repo.hash_algo() != HashAlgo::Sha1 {
+        bail!("multi-pack-index is supported only in SHA-1 repositories");
+    }
+    let mut packs = Vec::new();
+    for idx_path in pack_indexes(repo)? {
+        if !idx_path.with_extension("pack").is_file() {
+            continue;
+        }
+        let name = idx_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(format_err!("Invalid pack name {}", idx_path.display()))?
+            .to_string();
+        let mtime = fs::metadata(idx_path.with_extension("pack"))?.modified()?;
+        packs.push((name, mtime, idx_path));
+    }
+    if packs.is_empty() {
+        bail!("no pack files to index.");
+    }
+    packs.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // Object id to pack id, offset and mtime of the pack
+    let mut objects = BTreeMap::new();
+    for (pack_id, (_, mtime, idx_path)) in packs.iter().enumerate() {
+        let mut idx = PackIndex::open(idx_path, HashAlgo::Sha1)?;
+        for i in 0..idx.len() {
+            let entry = (pack_id as u32, idx.offset_at(i)?, *mtime);
+            objects
+                .entry(idx.id_at(i)?)
+                .and_modify(|existing: &mut (u32, u64, _)| {
+                    if mtime > &existing.2 {
+                        *existing = entry;
+                    }
+                })
+                .or_insert(entry);
+        }
+    }
+
+    let mut names = Vec::new();
+    for (name, _, _) in &packs {
+        names.extend(name.as_bytes());
+        names.push(0);
+    }
+    names.resize(names.len().next_multiple_of(4), 0);
+    let mut counts = [0u32; 256];
+    for id in objects.keys() {
+        counts[id.as_bytes()[0] as usize] += 1;
+    }
+    let mut fanout = Vec::with_capacity(256 * 4);
+    let mut total = 0u32;
+    for count in counts {
+        total += count;
+        fanout.extend(total.to_be_bytes());
+    }
+    let mut lookup = Vec::with_capacity(objects.len() * 20);
+    let mut offsets = Vec::with_capacity(objects.len() * 8);
+    let mut large_offsets = Vec::new();
+    for (id, (pack_id, offset, _)) in &objects {
+        lookup.extend(id.as_bytes());
+        offsets.extend(pack_id.to_be_bytes());
+        if *offset < 0x8000_0000 {
+            offsets.extend((*offset as u32).to_be_bytes());
+        } else {
+            let large_idx = (large_offsets.len() / 8) as u32;
+            offsets.extend((0x8000_0000 | large_idx).to_be_bytes());
+            large_offsets.extend(offset.to_be_bytes());
+        }
+    }
+
+    let mut chunks: Vec<(&[u8; 4], &[u8])> = vec![
+        (CHUNK_PACK_NAMES, &names),
+        (CHUNK_OID_FANOUT, &fanout),
+        (CHUNK_OID_LOOKUP, &lookup),
+        (CHUNK_OFFSETS, &offsets),
+    ];
+    if !large_offsets.is_empty() {
+        chunks.push((CHUNK_LARGE_OFFSETS, &large_offsets));
+    }
+    let mut data = Vec::new();
+    data.extend(MIDX_SIGNATURE);
+    data.extend([1, 1, chunks.len() as u8, 0]);
+    data.extend((packs.len() as u32).to_be_bytes());
+    write_chunk_table(&mut data, &chunks);
+    for (_, content) in &chunks {
+        data.extend(*content);
+    }
+    let checksum = Sha1::digest(&data);
+    data.extend(checksum);
+
+    let path = path(repo);
+    let tmp = path.with_extension("tmp");
+    storage::write_file(repo, &tmp, &data, FsyncComponent::PackMetadata)?;
+    fs::rename(&tmp, &path).context("Writing multi-pack-index")?;
+    Ok(())
+}
@@ -0,0 +1,593 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::attributes::Attributes;
+use crate::error::{bail, ensure, format_err, Context, Result};
+use crate::index::{self, Index, IndexEntry};
+use crate::object::{Object, ObjectKind};
+use crate::pathname;
+use crate::tree::{Tree, TreeEntry};
+use crate::{diff, merge, GitRepo};
+
+/// Line of a hunk without its prefix, content keeps the line terminator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HunkLine {
+    Context(Vec<u8>),
+    Removed(Vec<u8>),
+    Added(Vec<u8>),
+}
+
+/// Hunk of unified diff, line numbers are 1-based like in the hunk header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub lines: Vec<HunkLine>,
+}
+
+impl Hunk {
+    /// Lines the hunk expects in the original file and lines it replaces them with,
+    /// without given number of leading and trailing context lines
+    pub fn images(&self, skip_leading: usize, skip_trailing: usize) -> (Vec<&[u8]>, Vec<&[u8]>) {
+        let (mut preimage, mut postimage) = (Vec::new(), Vec::new());
+        for line in &self.lines[skip_leading..self.lines.len() - skip_trailing] {
+            match line {
+                HunkLine::Context(data) => {
+                    preimage.push(data.as_slice());
+                    postimage.push(data.as_slice());
+                }
+                HunkLine::Removed(data) => preimage.push(data.as_slice()),
+                HunkLine::Added(data) => postimage.push(data.as_slice()),
+            }
+        }
+        (preimage, postimage)
+    }
+
+    /// Number of context lines before and after the changed lines
+    fn context_len(&self) -> (usize, usize) {
+        let is_context = |line: &&HunkLine| matches!(line, HunkLine::Context(_));
+        let leading = self.lines.iter().take_while(is_context).count();
+        if leading == self.lines.len() {
+            return (leading, 0);
+        }
+        (
+            leading,
+            self.lines.iter().rev().take_while(is_context).count(),
+        )
+    }
+
+    /// Hunk undoing this one
+    fn reverse(&self) -> Self {
+        Self {
+            old_start: self.new_start,
+            old_len: self.new_len,
+            new_start: self.old_start,
+            new_len: self.old_len,
+            lines: self
+                .lines
+                .iter()
+                .map(|line| match line {
+                    HunkLine::Context(data) => HunkLine::Context(data.clone()),
+                    HunkLine::Removed(data) => HunkLine::Added(data.clone()),
+                    HunkLine::Added(data) => HunkLine::Removed(data.clone()),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Changes of single file described by patch
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilePatch {
+    /// Path before the change, `None` when the file is created
+    pub old_path: Option<Vec<u8>>,
+    /// Path after the change, `None` when the file is deleted
+    pub new_path: Option<Vec<u8>>,
+    pub old_mode: Option<u32>,
+    pub new_mode: Option<u32>,
+    /// The old path stays, new path is created as its modified copy
+    pub copy: bool,
+    /// Content change is not described by hunks
+    pub binary: bool,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    /// Patch undoing this one
+    pub fn reverse(&self) -> Result<Self> {
+        ensure!(
+            !self.copy,
+            "Reversing copy of {} is not supported",
+            pathname::display(self.path())
+        );
+        Ok(Self {
+            old_path: self.new_path.clone(),
+            new_path: self.old_path.clone(),
+            old_mode: self.new_mode,
+            new_mode: self.old_mode,
+            copy: false,
+            binary: self.binary,
+            hunks: self.hunks.iter().map(Hunk::reverse).collect(),
+        })
+    }
+
+    /// Path the patch is reported under
+    pub fn path(&self) -> &[u8] {
+        self.new_path
+            .as_deref()
+            .or(self.old_path.as_deref())
+            .unwrap_or_default()
+    }
+}
+
+/// Line without the line ending
+fn trim_eol(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .rposition(|b| *b != b'\n' && *b != b'\r')
+        .map_or(0, |i| i + 1);
+    &line[..end]
+}
+
+fn text(line: &[u8]) -> String {
+    String::from_utf8_lossy(trim_eol(line)).into_owned()
+}
+
+/// Path from `---`/`+++` line with the leading directory stripped, `None` for /dev/null
+fn header_path(name: &[u8]) -> Option<Vec<u8>> {
+    // Plain diffs may have timestamp after tab
+    if let Some((name, _)) = pathname::unquote(name) {
+        return Some(pathname::split_first(&name).map_or(name.clone(), |(_, rest)| rest.to_vec()));
+    }
+    let name = name.split(|b| *b == b'\t').next().unwrap_or_default();
+    let name = name.trim_ascii_end();
+    if name == b"/dev/null" {
+        return None;
+    }
+    Some(
+        pathname::split_first(name)
+            .map_or(name, |(_, rest)| rest)
+            .to_vec(),
+    )
+}
+
+/// Path of `rename from` and similar lines, which is quoted when needed
+fn unquote_path(path: &[u8]) -> Vec<u8> {
+    pathname::unquote(path).map_or(path.to_vec(), |(path, _)| path)
+}
+
+/// Old and new path from `diff --git a/<old> b/<new>`, assuming equal paths when ambiguous
+fn git_header_paths(rest: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    // Paths with special characters are quoted with their prefix
+    let quoted = |path: &[u8], prefix: &[u8]| -> Option<Vec<u8>> {
+        let (path, _) = pathname::unquote(path)?;
+        path.strip_prefix(prefix).map(<[u8]>::to_vec)
+    };
+    if let Some((old, new)) = pathname::unquote(rest) {
+        let new = new.strip_prefix(b" ")?;
+        let new = quoted(new, b"b/").or(new.strip_prefix(b"b/").map(<[u8]>::to_vec))?;
+        return Some((old.strip_prefix(b"a/")?.to_vec(), new));
+    }
+    if rest.ends_with(b"\"") {
+        let pos = rest.windows(2).rposition(|w| w == b" \"")?;
+        let old = rest[..pos].strip_prefix(b"a/")?;
+        return Some((old.to_vec(), quoted(&rest[pos + 1..], b"b/")?));
+    }
+    let rest = rest.strip_prefix(b"a/")?;
+    if rest.len() % 2 == 1 {
+        let (old, new) = rest.split_at(rest.len() / 2);
+        if new.strip_prefix(b" b/") == Some(old) {
+            return Some((old.to_vec(), old.to_vec()));
+        }
+    }
+    let pos = rest.windows(3).position(|w| w == b" b/")?;
+    Some((rest[..pos].to_vec(), rest[pos + 3..].to_vec()))
+}
+
+/// Whether every component of the path is a valid tree entry name, so that the patch
+/// writes neither outside of the working tree nor into `.git`
+fn is_valid_path(path: &[u8]) -> bool {
+    path.split(|b| *b == b'/').all(TreeEntry::is_valid_name)
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode.trim(), 8).with_context(|| format!("Invalid mode {:?}", mode))
+}
+
+/// Parses `@@ -a,b +c,d @@` header
+fn parse_hunk_header(line: &str) -> Result<(usize, usize, usize, usize)> {
+    let invalid = || format_err!("Invalid hunk header {:?}", line);
+    let ranges = line
+        .strip_prefix("@@ -")
+        .and_then(|rest| rest.split(" @@").next())
+        .ok_or_else(invalid)?;
+    let (old, new) = ranges.split_once(" +").ok_or_else(invalid)?;
+    let range = |range: &str| -> Result<(usize, usize)> {
+        let (start, len) = range.split_once(',').unwrap_or((range, "1"));
+        Ok((
+            start.parse().with_context(invalid)?,
+            len.parse().with_context(invalid)?,
+        ))
+    };
+    let (old_start, old_len) = range(old)?;
+    let (new_start, new_len) = range(new)?;
+    Ok((old_start, old_len, new_start, new_len))
+}
+
+/// Parses hunks starting at `lines[i]`, returns them with index of the first following line
+fn parse_hunks(lines: &[&[u8]], mut i: usize) -> Result<(Vec<Hunk>, usize)> {
+    let mut hunks = Vec::new();
+    while i < lines.len() && lines[i].starts_with(b"@@ ") {
+        let (old_start, old_len, new_start, new_len) = parse_hunk_header(&text(lines[i]))?;
+        i += 1;
+        let mut hunk = Hunk {
+            old_start,
+            old_len,
+            new_start,
+            new_len,
+            lines: Vec::new(),
+        };
+        let (mut old_left, mut new_left) = (old_len, new_len);
+        while old_left > 0 || new_left > 0 {
+            let Some(line) = lines.get(i) else {
+                bail!("Patch ends in the middle of hunk");
+            };
+            let content = line.get(1..).unwrap_or_default().to_vec();
+            match line.first() {
+                Some(b' ') if old_left > 0 && new_left > 0 => {
+                    hunk.lines.push(HunkLine::Context(content));
+                    old_left -= 1;
+                    new_left -= 1;
+                }
+                // Some mailers strip trailing whitespace, empty line was empty context
+                Some(b'\n' | b'\r') if old_left > 0 && new_left > 0 => {
+                    hunk.lines.push(HunkLine::Context(line.to_vec()));
+                    old_left -= 1;
+                    new_left -= 1;
+                }
+                Some(b'-') if old_left > 0 => {
+                    hunk.lines.push(HunkLine::Removed(content));
+                    old_left -= 1;
+                }
+                Some(b'+') if new_left > 0 => {
+                    hunk.lines.push(HunkLine::Added(content));
+                    new_left -= 1;
+                }
+                Some(b'\\') => strip_newline(&mut hunk.lines),
+                _ => bail!("Corrupt patch at line {:?}", text(line)),
+            }
+            i += 1;
+        }
+        if lines.get(i).is_some_and(|line| line.starts_with(b"\\")) {
+            strip_newline(&mut hunk.lines);
+            i += 1;
+        }
+        hunks.push(hunk);
+    }
+    Ok((hunks, i))
+}
+
+/// Handles `\ No newline at end of file` marker of the previous line
+fn strip_newline(lines: &mut [HunkLine]) {
+    if let Some(HunkLine::Context(data) | HunkLine::Removed(data) | HunkLine::Added(data)) =
+        lines.last_mut()
+    {
+        if data.ends_with(b"\n") {
+            data.pop();
+        }
+    }
+}
+
+/// Parses patch in git or plain unified diff format, ignoring text before, between and
+/// after the file diffs like the commit message of emailed patches
+pub fn parse(data: &[u8]) -> Result<Vec<FilePatch>> {
+    let lines = diff::lines(data);
+    let mut patches = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let (line, raw) = (text(lines[i]), trim_eol(lines[i]));
+        let mut patch = FilePatch::default();
+        if let Some(rest) = raw.strip_prefix(b"diff --git ") {
+            let (old, new) = git_header_paths(rest)
+                .ok_or_else(|| format_err!("Invalid patch header {:?}", line))?;
+            patch.old_path = Some(old);
+            patch.new_path = Some(new);
+            i += 1;
+            while let Some(next) = lines.get(i) {
+                let (line, raw) = (text(next), trim_eol(next));
+                if let Some(mode) = line.strip_prefix("new file mode ") {
+                    patch.old_path = None;
+                    patch.new_mode = Some(parse_mode(mode)?);
+                } else if let Some(mode) = line.strip_prefix("deleted file mode ") {
+                    patch.new_path = None;
+                    patch.old_mode = Some(parse_mode(mode)?);
+                } else if let Some(mode) = line.strip_prefix("old mode ") {
+                    patch.old_mode = Some(parse_mode(mode)?);
+                } else if let Some(mode) = line.strip_prefix("new mode ") {
+                    patch.new_mode = Some(parse_mode(mode)?);
+                } else if let Some(path) = raw
+                    .strip_prefix(b"rename from ")
+                    .or(raw.strip_prefix(b"copy from "))
+                {
+                    patch.old_path = Some(unquote_path(path));
+                    patch.copy = line.starts_with("copy");
+                } else if let Some(path) = raw
+                    .strip_prefix(b"rename to ")
+                    .or(raw.strip_prefix(b"copy to "))
+                {
+                    patch.new_path = Some(unquote_path(path));
+                } else if let Some(rest) = line.strip_prefix("index ") {
+                    if let Some((_, mode)) = rest.split_once(' ') {
+                        let mode = parse_mode(mode)?;
+                        patch.old_mode = Some(mode);
+                        patch.new_mode = Some(mode);
+                    }
+                } else if line.starts_with("Binary files ") || line == "GIT binary patch" {
+                    patch.binary = true;
+                } else if !line.starts_with("similarity index ")
+                    && !line.starts_with("dissimilarity index ")
+                {
+                    break;
+                }
+                i += 1;
+            }
+            // Paths in `---`/`+++` lines are redundant with the git header
+            if lines.get(i).is_some_and(|line| line.starts_with(b"--- ")) {
+                i += 2;
+            }
+        } else if let (Some(old), Some(new)) = (
+            raw.strip_prefix(b"--- "),
+            lines
+                .get(i + 1)
+                .and_then(|next| trim_eol(next).strip_prefix(b"+++ ")),
+        ) {
+            patch.old_path = header_path(old);
+            patch.new_path = header_path(new);
+            i += 2;
+        } else {
+            i += 1;
+            continue;
+        }
+        let (hunks, next) = parse_hunks(&lines, i)?;
+        patch.hunks = hunks;
+        i = next;
+        ensure!(
+            patch.old_path.is_some() || patch.new_path.is_some(),
+            "Patch without file name"
+        );
+        for path in patch.old_path.iter().chain(&patch.new_path) {
+            ensure!(
+                is_valid_path(path),
+                "invalid path '{}'",
+                pathname::display(path)
+            );
+        }
+        patches.push(patch);
+    }
+    Ok(patches)
+}
+
+/// Position of `preimage` in `lines` closest to `expected`, not before `from`
+fn find_preimage(
+    lines: &[&[u8]],
+    preimage: &[&[u8]],
+    from: usize,
+    expected: usize,
+) -> Option<usize> {
+    if lines.len() < preimage.len() {
+        return None;
+    }
+    let last = lines.len() - preimage.len();
+    let matches =
+        |pos: usize| pos >= from && pos <= last && lines[pos..pos + preimage.len()] == *preimage;
+    let expected = expected.clamp(from, last.max(from));
+    (0..=lines.len()).find_map(|distance| {
+        [
+            expected.checked_add(distance),
+            expected.checked_sub(distance),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|pos| matches(*pos))
+    })
+}
+
+/// Applies hunks to the content. Hunks are located at their line numbers adjusted by
+/// offset of the previous hunks, searching the closest matching position otherwise.
+/// With `min_context`, hunks that do not match are retried with less context lines, but
+/// at least that many on each side.
+pub fn apply_hunks(content: &[u8], hunks: &[Hunk], min_context: Option<usize>) -> Result<Vec<u8>> {
+    let lines = diff::lines(content);
+    let mut result = Vec::new();
+    let mut pos = 0;
+    let mut offset: isize = 0;
+    for (n, hunk) in hunks.iter().enumerate() {
+        let failed = || format_err!("Hunk #{} at line {} does not apply", n + 1, hunk.old_start);
+        let (leading, trailing) = hunk.context_len();
+        let (mut skip_leading, mut skip_trailing) = (0, 0);
+        // Hunk without preimage is placed after the line it starts at
+        let start = if hunk.old_len == 0 {
+            hunk.old_start
+        } else {
+            hunk.old_start.saturating_sub(1)
+        };
+        let (found, preimage, postimage) = loop {
+            let (preimage, postimage) = hunk.images(skip_leading, skip_trailing);
+            let expected = (start as isize + skip_leading as isize + offset).max(0) as usize;
+            if let Some(found) = find_preimage(&lines, &preimage, pos, expected) {
+                break (found, preimage, postimage);
+            }
+            // Like git, reduce the larger context first, both when they are equal
+            let min_context = min_context.ok_or_else(failed)?;
+            let (lead, trail) = (leading - skip_leading, trailing - skip_trailing);
+            if lead <= min_context && trail <= min_context {
+                return Err(failed());
+            }
+            if lead >= trail {
+                skip_leading += 1;
+            }
+            if trail > leading - skip_leading {
+                skip_trailing += 1;
+            }
+        };
+        for line in &lines[pos..found] {
+            result.extend(*line);
+        }
+        for line in postimage {
+            result.extend(line);
+        }
+        pos = found + preimage.len();
+        offset = found as isize - (start + skip_leading) as isize;
+    }
+    for line in &lines[pos..] {
+        result.extend(*line);
+    }
+    Ok(result)
+}
+
+/// Where patches are applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Worktree,
+    Index,
+    /// Index and working tree, which have to match for patched files
+    Both,
+}
+
+/// Mode and content of the file, from the index or from the working tree
+fn read_file(
+    repo: &GitRepo,
+    index: &Index,
+    attributes: &mut Attributes,
+    path: &[u8],
+    target: Target,
+) -> Result<Option<(u32, Vec<u8>)>> {
+    if target == Target::Worktree {
+        return index::worktree_file_with(repo, path, index, attributes);
+    }
+    let staged = match index.get(path, 0) {
+        Some(entry) => Some((entry.mode, Object::read(repo, &entry.id)?.data)),
+        None => None,
+    };
+    if target == Target::Both {
+        ensure!(
+            index::worktree_file_with(repo, path, index, attributes)? == staged,
+            "{}: does not match index",
+            pathname::display(path)
+        );
+    }
+    Ok(staged)
+}
+
+/// Applies patches to the index or the working tree. All patches are verified before
+/// anything is written, nothing is changed when any of them fails or with `check`.
+pub fn apply(
+    repo: &GitRepo,
+    patches: &[FilePatch],
+    target: Target,
+    min_context: Option<usize>,
+    check: bool,
+) -> Result<()> {
+    let mut index = Index::read(repo)?;
+    if let Some(path) = index.conflicts().first() {
+        ensure!(
+            target == Target::Worktree,
+            "{}: needs merge",
+            pathname::display(path)
+        );
+    }
+    let mut attributes = Attributes::load(repo)?;
+    // Results for every touched path, `None` when the file is removed
+    let mut files: BTreeMap<Vec<u8>, Option<(u32, Vec<u8>)>> = BTreeMap::new();
+    for patch in patches {
+        let path = pathname::display(patch.path());
+        if target != Target::Index {
+            for path in patch.old_path.iter().chain(&patch.new_path) {
+                ensure!(
+                    !Tree::is_beyond_symlink(repo, path),
+                    "affected file '{}' is beyond a symbolic link",
+                    pathname::display(path)
+                );
+            }
+        }
+        let mut lookup = |path: &Vec<u8>| -> Result<Option<(u32, Vec<u8>)>> {
+            match files.get(path) {
+                Some(file) => Ok(file.clone()),
+                None => read_file(repo, &index, &mut attributes, path, target),
+            }
+        };
+        let (old_mode, data) = match &patch.old_path {
+            Some(old_path) => lookup(old_path)?
+                .map(|(mode, data)| (Some(mode), data))
+                .ok_or(format_err!(
+                    "{}: does not exist",
+                    pathname::display(old_path)
+                ))?,
+            None => {
+                let new_path = patch.new_path.as_ref().unwrap();
+                ensure!(lookup(new_path)?.is_none(), "{}: already exists", path);
+                (None, Vec::new())
+            }
+        };
+        ensure!(
+            !patch.binary,
+            "cannot apply binary patch to '{}' without full index line",
+            path
+        );
+        let data = apply_hunks(&data, &patch.hunks, min_context)
+            .with_context(|| format!("patch failed: {}", path))?;
+        if let (Some(old_path), false) = (&patch.old_path, patch.copy) {
+            files.insert(old_path.clone(), None);
+        }
+        if let Some(new_path) = &patch.new_path {
+            let mode = patch.new_mode.or(old_mode).unwrap_or(0o100644);
+            files.insert(new_path.clone(), Some((mode, data)));
+        }
+    }
+    if check {
+        return Ok(());
+    }
+
+    // Removals first, so that files can replace removed directories
+    let (removed, written): (Vec<_>, Vec<_>) = files.into_iter().partition(|(_, f)| f.is_none());
+    for (path, _) in removed {
+        if target != Target::Index {
+            merge::remove_file(repo, &path)?;
+            attributes.invalidate_file(&path);
+        }
+        index.remove(&path);
+    }
+    for (path, file) in written {
+        let (mode, data) = file.unwrap();
+        let full_path = repo.worktree_path(&path);
+        if target != Target::Index {
+            let worktree_data = if mode == 0o120000 {
+                data.clone()
+            } else {
+                attributes.to_worktree(&path, data.clone())?
+            };
+            Tree::checkout_data(repo, &full_path, mode, &worktree_data)?;
+            attributes.invalidate_file(&path);
+        }
+        if target != Target::Worktree {
+            let obj = Object::new(repo.hash_algo(), ObjectKind::Blob, data);
+            obj.write(repo)?;
+            index.remove(&path);
+            index.add(match target {
+                Target::Both => IndexEntry::from_metadata(
+                    &path,
+                    mode,
+                    &obj.hash,
+                    &fs::symlink_metadata(&full_path)?,
+                ),
+                _ => IndexEntry::new(&path, mode, &obj.hash, 0),
+            });
+        }
+    }
+    if target != Target::Worktree {
+        index.write(repo)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+// Paths are kept as the raw bytes stored by git, which are not necessarily UTF-8.
+// They are converted to file system names only when touching the working tree, and
+// quoted only for display.
+
+/// Path for messages, bytes which are not valid UTF-8 are replaced
+pub fn display(path: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(path)
+}
+
+/// Path of the parts joined by `/`, the prefix may be empty or end with `/` already
+pub fn concat(prefix: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut path = Vec::with_capacity(prefix.len() + name.len() + 1);
+    path.extend(prefix);
+    if !prefix.is_empty() && !prefix.ends_with(b"/") {
+        path.push(b'/');
+    }
+    path.extend(name);
+    path
+}
+
+/// Directory and the rest of the path split at the first `/`
+pub fn split_first(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    let i = path.iter().position(|b| *b == b'/')?;
+    Some((&path[..i], &path[i + 1..]))
+}
+
+/// Parent directory and the file name split at the last `/`
+pub fn split_last(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    let i = path.iter().rposition(|b| *b == b'/')?;
+    Some((&path[..i], &path[i + 1..]))
+}
+
+/// Names of the path components, empty ones skipped
+pub fn components(path: &[u8]) -> impl Iterator<Item = &[u8]> {
+    path.split(|b| *b == b'/').filter(|name| !name.is_empty())
+}
+
+/// Path with trailing `/` removed
+pub fn trim_slash(path: &[u8]) -> &[u8] {
+    let end = path.iter().rposition(|b| *b != b'/').map_or(0, |i| i + 1);
+    &path[..end]
+}
+
+/// Path of the file name. Names which are not valid Unicode outside of unix are
+/// replaced lossily.
+pub fn from_os(name: &OsStr) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        std::os::unix::ffi::OsStrExt::as_bytes(name).to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        name.to_string_lossy().into_owned().into_bytes()
+    }
+}
+
+/// File system name of the path. Paths which are not valid UTF-8 outside of unix are
+/// replaced lossily.
+pub fn to_os(path: &[u8]) -> OsString {
+    #[cfg(unix)]
+    {
+        std::os::unix::ffi::OsStringExt::from_vec(path.to_vec())
+    }
+    #[cfg(not(unix))]
+    {
+        OsString::from(String::from_utf8_lossy(path).into_owned())
+    }
+}
+
+/// Path relative to the directory, `None` when outside of it
+pub fn relative_to(path: &Path, dir: &Path) -> Option<Vec<u8>> {
+    path.strip_prefix(dir)
+        .ok()
+        .map(|relative| from_os(relative.as_os_str()))
+}
+
+/// File system path of the repository path under the directory
+pub fn join(dir: &Path, path: &[u8]) -> PathBuf {
+    crate::platform::long_path(dir.join(to_os(path)))
+}
+
+/// Path for display, quoted the way of `core.quotePath`. Paths with control characters,
+/// quotes or backslashes are put in double quotes with C-style escapes, bytes outside
+/// of ASCII are escaped as octal unless `quote_path` is off. Bytes which are not valid
+/// UTF-8 are always escaped.
+pub fn quote(path: &[u8], quote_path: bool) -> Cow<'_, str> {
+    let needs_quote = |b: u8| b < b' ' || b == b'"' || b == b'\\' || b == 0x7f;
+    if let Ok(text) = std::str::from_utf8(path) {
+        if !path
+            .iter()
+            .any(|b| needs_quote(*b) || (quote_path && !b.is_ascii()))
+        {
+            return Cow::Borrowed(text);
+        }
+    }
+    let mut quoted = String::from("\"");
+    for chunk in path.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\x07' => quoted.push_str("\\a"),
+                '\x08' => quoted.push_str("\\b"),
+                '\t' => quoted.push_str("\\t"),
+                '\n' => quoted.push_str("\\n"),
+                '\x0b' => quoted.push_str("\\v"),
+                '\x0c' => quoted.push_str("\\f"),
+                '\r' => quoted.push_str("\\r"),
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                c if c < ' ' || c == '\x7f' || (quote_path && !c.is_ascii()) => {
+                    for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                        quoted.push_str(&format!("\\{:03o}", byte));
+                    }
+                }
+                c => quoted.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            quoted.push_str(&format!("\\{:03o}", byte));
+        }
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
+
+/// Parses path quoted by [`quote`] at the start of the text, returning the rest after
+/// the closing quote. `None` when the text does not start with a quoted path.
+pub fn unquote(text: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    let rest = text.strip_prefix(b"\"")?;
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while let Some(b) = rest.get(i) {
+        i += 1;
+        let escaped = match b {
+            b'"' => return Some((bytes, &rest[i..])),
+            b'\\' => {
+                i += 1;
+                *rest.get(i - 1)?
+            }
+            b => {
+                bytes.push(*b);
+                continue;
+            }
+        };
+        let byte = match escaped {
+            b'a' => b'\x07',
+            b'b' => b'\x08',
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'v' => b'\x0b',
+            b'f' => b'\x0c',
+            b'r' => b'\r',
+            b'0'..=b'3' => {
+                let mut byte = escaped - b'0';
+                for digit in rest.get(i..i + 2)? {
+                    if !(b'0'..=b'7').contains(digit) {
+                        return None;
+                    }
+                    byte = byte * 8 + (digit - b'0');
+                }
+                i += 2;
+                byte
+            }
+            c if c.is_ascii() => c,
+            _ => return None,
+        };
+        bytes.push(byte);
+    }
+    None
+}
@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+
+use crate::error::{ensure, Context, Error, Result};
+
+/// Maximal length of pkt-line including 4 length bytes
+pub const MAX_PKT_LEN: usize = 65520;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PktLine {
+    Data(Box<[u8]>),
+    Flush,
+}
+
+impl PktLine {
+    /// Data of the line with trailing LF stripped. `None` for flush line.
+    pub fn text(&self) -> Option<&[u8]> {
+        match self {
+            PktLine::Data(data) => Some(data.strip_suffix(b"\n").unwrap_or(data)),
+            PktLine::Flush => None,
+        }
+    }
+}
+
+pub fn write_data(buf: &mut Vec<u8>, data: &[u8]) {
+    debug_assert!(data.len() + 4 <= MAX_PKT_LEN);
+    buf.extend(format!("{:04x}", data.len() + 4).as_bytes());
+    buf.extend(data);
+}
+
+pub fn write_flush(buf: &mut Vec<u8>) {
+    buf.extend(b"0000");
+}
+
+/// Parses pkt-lines until the end of input or start of packfile data.
+/// Returns parsed lines and the remaining pack data (possibly empty).
+pub fn parse(mut lines: &[u8]) -> Result<(VecDeque<PktLine>, &[u8])> {
+    let mut pkt_lines = VecDeque::new();
+
+    let mut data_len_bytes = [0u8; 2];
+    while !lines.is_empty() {
+        ensure!(lines.len() >= 4, Error::protocol("Truncated pkt-line"));
+        let (prefix, rest) = lines.split_at(4);
+        if prefix == b"PACK" {
+            break;
+        }
+        hex::decode_to_slice(std::str::from_utf8(prefix)?, &mut data_len_bytes)
+            .context("Decoding data len hex")?;
+        let data_len = u16::from_be_bytes(data_len_bytes) as usize;
+        if data_len == 0 {
+            lines = rest;
+            pkt_lines.push_back(PktLine::Flush);
+        } else {
+            ensure!(
+                data_len >= 4,
+                Error::protocol("pkt-line length must be at least 4 to compensate for legth bytes")
+            );
+            ensure!(
+                rest.len() >= data_len - 4,
+                Error::protocol("Truncated pkt-line")
+            );
+            let (data, rest) = rest.split_at(data_len - 4);
+            lines = rest;
+            pkt_lines.push_back(PktLine::Data(Box::from(data)));
+        }
+    }
+    Ok((pkt_lines, lines))
+}
@@ -0,0 +1,108 @@
+use std::fs::{File, Metadata};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::GitRepo;
+
+/// Whether the file is executable by its owner, never on platforms without the bits
+#[cfg(unix)]
+pub fn is_executable(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o100 != 0
+}
+
+#[cfg(not(unix))]
+pub fn is_executable(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Sets or clears executable bits of the file. Executable bits are set where the file
+/// is readable, so that the umask applied on creation is respected.
+#[cfg(unix)]
+pub fn set_executable(file: &File, executable: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = file.metadata()?.permissions();
+    let mode = permissions.mode();
+    let mode = if executable {
+        mode | (mode & 0o444) >> 2
+    } else {
+        mode & !0o111
+    };
+    if mode != permissions.mode() {
+        permissions.set_mode(mode);
+        file.set_permissions(permissions)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_executable(_file: &File, _executable: bool) -> Result<()> {
+    Ok(())
+}
+
+/// How files of the working tree are represented, from `core.symlinks` and
+/// `core.ignoreCase`
+#[derive(Debug, Clone, Copy)]
+pub struct WorktreeOptions {
+    symlinks: bool,
+    ignore_case: bool,
+}
+
+impl WorktreeOptions {
+    pub fn load(config: &Config) -> Result<Self> {
+        Ok(Self {
+            symlinks: config.get_bool("core.symlinks")?.unwrap_or(cfg!(unix)),
+            ignore_case: config.get_bool("core.ignoreCase")?.unwrap_or(false),
+        })
+    }
+}
+
+/// Whether symlinks are checked out as symlinks, `core.symlinks`. Otherwise they are
+/// files holding the target.
+pub fn symlinks(repo: &GitRepo) -> Result<bool> {
+    Ok(repo.worktree_options()?.symlinks)
+}
+
+/// Whether the file system treats names differing in case as the same, probed by
+/// looking up the existing file with case of its name swapped
+pub fn is_case_insensitive(file: &Path) -> bool {
+    let Some(name) = file.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let swapped: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_uppercase() {
+                c.to_ascii_lowercase()
+            } else {
+                c.to_ascii_uppercase()
+            }
+        })
+        .collect();
+    swapped != name && file.with_file_name(swapped).exists()
+}
+
+/// Whether paths differing only in case name the same file, `core.ignoreCase`
+pub fn ignore_case(repo: &GitRepo) -> Result<bool> {
+    Ok(repo.worktree_options()?.ignore_case)
+}
+
+/// Absolute paths longer than the legacy limit get the extended-length prefix, so that
+/// deep working trees can be checked out
+#[cfg(windows)]
+pub fn long_path(path: PathBuf) -> PathBuf {
+    const MAX_PATH: usize = 260;
+    let text = path.to_string_lossy().into_owned();
+    if text.len() < MAX_PATH || !path.is_absolute() || text.starts_with(r"\\") {
+        return path;
+    }
+    let mut long = std::ffi::OsString::from(r"\\?\");
+    long.push(text.replace('/', r"\"));
+    PathBuf::from(long)
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: PathBuf) -> PathBuf {
+    path
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::commit::{Author, Commit};
+use crate::error::{Error, Result};
+use crate::mail::split_message;
+use crate::mailmap::Mailmap;
+use crate::object::Object;
+use crate::oid::ObjectId;
+use crate::refs::{self, RefValue};
+use crate::revwalk::peel_to_commit;
+use crate::GitRepo;
+
+/// Minimal length of abbreviated ids
+const ABBREV: usize = 7;
+
+/// Format of commits listed by `log`, as given to `--pretty`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pretty {
+    Oneline,
+    Short,
+    Medium,
+    Full,
+    Fuller,
+    /// `format:`, placeholders expanded and entries separated by newlines
+    Format(String),
+    /// `tformat:` or bare format with placeholders, each entry terminated by newline
+    TFormat(String),
+}
+
+impl FromStr for Pretty {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        Ok(match format {
+            "oneline" => Self::Oneline,
+            "short" => Self::Short,
+            "medium" => Self::Medium,
+            "full" => Self::Full,
+            "fuller" => Self::Fuller,
+            _ => {
+                if let Some(format) = format.strip_prefix("format:") {
+                    Self::Format(format.to_string())
+                } else if let Some(format) = format.strip_prefix("tformat:") {
+                    Self::TFormat(format.to_string())
+                } else if format.contains('%') {
+                    Self::TFormat(format.to_string())
+                } else {
+                    return Err(format!("invalid --pretty format: {}", format).into());
+                }
+            }
+        })
+    }
+}
+
+impl Pretty {
+    /// Whether each formatted commit is followed by newline, rather than newline
+    /// separating them
+    pub fn is_terminated(&self) -> bool {
+        matches!(self, Self::Oneline | Self::TFormat(_))
+    }
+
+    /// Whether the format starts with `commit <id>` line followed by details of the commit
+    pub fn has_header(&self) -> bool {
+        matches!(self, Self::Short | Self::Medium | Self::Full | Self::Fuller)
+    }
+
+    /// Text printed between two formatted commits
+    pub fn separator(&self) -> &'static str {
+        match self {
+            Self::Short | Self::Medium | Self::Full | Self::Fuller => "\n\n",
+            Self::Oneline | Self::Format(_) | Self::TFormat(_) => "\n",
+        }
+    }
+
+    /// Text printed after the last formatted commit
+    pub fn terminator(&self) -> &'static str {
+        match self {
+            Self::Format(_) => "",
+            _ => "\n",
+        }
+    }
+
+    /// The commit formatted without trailing separator
+    pub fn format(
+        &self,
+        repo: &GitRepo,
+        id: &ObjectId,
+        commit: &Commit,
+        decorations: &Decorations,
+        mailmap: &Mailmap,
+    ) -> Result<String> {
+        let header = |out: &mut String| -> Result<()> {
+            writeln!(out, "commit {}", id)?;
+            if commit.parents.len() > 1 {
+                let parents = commit
+                    .parents
+                    .iter()
+                    .map(|p| Object::abbreviate(repo, p, ABBREV))
+                    .collect::<Result<Vec<_>>>()?;
+                writeln!(out, "Merge: {}", parents.join(" "))?;
+            }
+            Ok(())
+        };
+        let identity = |author: &Author| {
+            let (name, email) = mailmap.map(&author.name, &author.email);
+            format!("{} <{}>", name, email)
+        };
+        let mut out = String::new();
+        match self {
+            Self::Oneline => {
+                let (subject, _) = split_message(&commit.message);
+                let id = Object::abbreviate(repo, id, ABBREV)?;
+                write!(out, "{} {}", id, subject)?;
+                return Ok(out);
+            }
+            Self::Format(format) | Self::TFormat(format) => {
+                return expand(repo, id, commit, decorations, mailmap, format);
+            }
+            Self::Short => {
+                header(&mut out)?;
+                writeln!(out, "Author: {}", identity(&commit.author))?;
+            }
+            Self::Medium => {
+                header(&mut out)?;
+                writeln!(out, "Author: {}", identity(&commit.author))?;
+                writeln!(out, "Date:   {}", commit.author.format_date())?;
+            }
+            Self::Full => {
+                header(&mut out)?;
+                writeln!(out, "Author: {}", identity(&commit.author))?;
+                writeln!(out, "Commit: {}", identity(&commit.committer))?;
+            }
+            Self::Fuller => {
+                header(&mut out)?;
+                writeln!(out, "Author:     {}", identity(&commit.author))?;
+                writeln!(out, "AuthorDate: {}", commit.author.format_date())?;
+                writeln!(out, "Commit:     {}", identity(&commit.committer))?;
+                writeln!(out, "CommitDate: {}", commit.committer.format_date())?;
+            }
+        }
+        out.push('\n');
+        let message = match self {
+            Self::Short => split_message(&commit.message).0,
+            _ => commit.message.trim_end().to_string(),
+        };
+        let lines: Vec<String> = message
+            .lines()
+            .map(|line| format!("    {}", line))
+            .collect();
+        out.push_str(&lines.join("\n"));
+        Ok(out)
+    }
+}
+
+/// Expands `%` placeholders of `--pretty=format:`. Unknown placeholders are kept as is.
+/// Uppercase `%aN`, `%aE` and `%aL` show the identity canonicalized by the mailmap.
+pub fn expand(
+    repo: &GitRepo,
+    id: &ObjectId,
+    commit: &Commit,
+    decorations: &Decorations,
+    mailmap: &Mailmap,
+    format: &str,
+) -> Result<String> {
+    let abbrev = |id: &ObjectId| Object::abbreviate(repo, id, ABBREV);
+    let (subject, body) = split_message(&commit.message);
+    let mut out = String::new();
+    let mut rest = format;
+    while let Some(pos) = rest.find('%') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        let person = match rest.as_bytes().first() {
+            Some(b'a') => Some(&commit.author),
+            Some(b'c') => Some(&commit.committer),
+            _ => None,
+        };
+        let (expanded, len) = if let Some(person) = person {
+            let (name, email) = mailmap.map(&person.name, &person.email);
+            let local = |email: &str| email.split('@').next().unwrap_or_default().to_string();
+            let value = match rest.as_bytes().get(1) {
+                Some(b'n') => Some(person.name.clone()),
+                Some(b'N') => Some(name),
+                Some(b'e') => Some(person.email.clone()),
+                Some(b'E') => Some(email.clone()),
+                Some(b'l') => Some(local(&person.email)),
+                Some(b'L') => Some(local(&email)),
+                Some(b'd') => Some(person.format_date()),
+                Some(b'D') => Some(person.format_rfc2822_date()),
+                Some(b'i') => Some(person.format_iso_date()),
+                Some(b't') => Some(person.unix_time().to_string()),
+                _ => None,
+            };
+            (value, 2)
+        } else if let Some(hex) = rest.strip_prefix('x').and_then(|hex| hex.get(..2)) {
+            let byte = u8::from_str_radix(hex, 16).ok();
+            (byte.map(|byte| char::from(byte).to_string()), 3)
+        } else {
+            let value = match rest.chars().next() {
+                Some('H') => Some(id.to_hex()),
+                Some('h') => Some(abbrev(id)?),
+                Some('T') => Some(commit.tree_sha.to_hex()),
+                Some('t') => Some(abbrev(&commit.tree_sha)?),
+                Some('P') => Some(
+                    commit
+                        .parents
+                        .iter()
+                        .map(|p| p.to_hex())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ),
+                Some('p') => Some(
+                    commit
+                        .parents
+                        .iter()
+                        .map(abbrev)
+                        .collect::<Result<Vec<_>>>()?
+                        .join(" "),
+                ),
+                Some('s') => Some(subject.clone()),
+                Some('b') if body.is_empty() => Some(String::new()),
+                Some('b') => Some(format!("{}\n", body)),
+                Some('B') => Some(commit.message.clone()),
+                Some('d') => Some(match decorations.get(id).as_slice() {
+                    [] => String::new(),
+                    names => format!(" ({})", names.join(", ")),
+                }),
+                Some('D') => Some(decorations.get(id).join(", ")),
+                Some('n') => Some(String::from("\n")),
+                Some('%') => Some(String::from("%")),
+                _ => None,
+            };
+            (value, 1)
+        };
+        match expanded {
+            Some(expanded) => {
+                out.push_str(&expanded);
+                rest = &rest[len..];
+            }
+            None => out.push('%'),
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Names of refs pointing to commits, shown by `%d` and `%D`
+#[derive(Debug, Default)]
+pub struct Decorations {
+    names: HashMap<ObjectId, Vec<String>>,
+    /// Commit at HEAD with the current branch, `None` when detached
+    head: Option<(ObjectId, Option<String>)>,
+}
+
+impl Decorations {
+    pub fn load(repo: &GitRepo) -> Result<Self> {
+        let mut names: HashMap<ObjectId, Vec<String>> = HashMap::new();
+        for (name, id) in refs::list(repo)? {
+            let short = match name.strip_prefix("refs/tags/") {
+                Some(tag) => format!("tag: {}", tag),
+                None => refs::shorten(&name).to_string(),
+            };
+            // Tags decorate also the commits they point to
+            let Ok(target) = peel_to_commit(repo, &id) else {
+                continue;
+            };
+            if target != id {
+                names.entry(id).or_default().push(short.clone());
+            }
+            names.entry(target).or_default().push(short);
+        }
+        let head = match refs::read(repo, "HEAD")? {
+            Some(RefValue::Symbolic(branch)) => refs::resolve(repo, &branch)?
+                .map(|id| (id, Some(refs::shorten(&branch).to_string()))),
+            Some(RefValue::Direct(id)) => Some((id, None)),
+            None => None,
+        };
+        Ok(Self { names, head })
+    }
+
+    /// Decorations of the object, HEAD with the current branch first and then the rest
+    /// in reverse order of ref names
+    pub fn get(&self, id: &ObjectId) -> Vec<String> {
+        let mut result = Vec::new();
+        let mut branch = None;
+        if let Some((head, current)) = &self.head {
+            if head == id {
+                branch = current.as_deref();
+                result.push(match branch {
+                    Some(branch) => format!("HEAD -> {}", branch),
+                    None => String::from("HEAD"),
+                });
+            }
+        }
+        if let Some(names) = self.names.get(id) {
+            let others = names
+                .iter()
+                .rev()
+                .filter(|name| Some(name.as_str()) != branch);
+            result.extend(others.cloned());
+        }
+        result
+    }
+}
@@ -0,0 +1,22 @@
+use std::fmt::Debug;
+
+/// Receiver of output and diagnostics of library operations, the library itself never
+/// prints. Install it with [`crate::GitRepo::with_progress`].
+pub trait Progress: Debug + Send + Sync {
+    /// Line of the operation output, e.g. summary of updated refs
+    fn info(&self, message: &str);
+    /// Problem which does not fail the operation
+    fn warning(&self, message: &str);
+    /// Detailed trace of what the operation does
+    fn debug(&self, _message: &str) {}
+}
+
+/// Discards everything, the default of repositories
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn info(&self, _message: &str) {}
+
+    fn warning(&self, _message: &str) {}
+}
@@ -0,0 +1,147 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::commit::{Author, Role};
+use crate::error::{format_err, Context, Result};
+use crate::oid::ObjectId;
+use crate::refs::{self, RefValue};
+use crate::{date, GitRepo};
+
+/// Single recorded update of a ref
+#[derive(Debug, Clone)]
+pub struct ReflogEntry {
+    pub old: ObjectId,
+    pub new: ObjectId,
+    pub committer: Author,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    fn parse(line: &str) -> Result<Self> {
+        let invalid = || format_err!("Invalid reflog line {:?}", line);
+        let (header, message) = line.split_once('\t').unwrap_or((line, ""));
+        let (old, rest) = header.split_once(' ').ok_or_else(invalid)?;
+        let (new, identity) = rest.split_once(' ').ok_or_else(invalid)?;
+        Ok(Self {
+            old: old.parse().map_err(|_| invalid())?,
+            new: new.parse().map_err(|_| invalid())?,
+            committer: Author::parse(identity)?,
+            message: message.to_string(),
+        })
+    }
+
+    fn to_line(&self) -> Vec<u8> {
+        let mut line = format!("{} {} ", self.old, self.new).into_bytes();
+        self.committer.write_to_buf(&mut line);
+        line.push(b'\t');
+        line.extend(self.message.as_bytes());
+        line.push(b'\n');
+        line
+    }
+}
+
+fn log_path(repo: &GitRepo, name: &str) -> PathBuf {
+    match repo.ref_path(name).strip_prefix(&repo.git_dir) {
+        Ok(_) => repo.git_dir.join("logs").join(name),
+        Err(_) => repo.common_dir.join("logs").join(name),
+    }
+}
+
+/// Whether the ref has a reflog
+pub fn exists(repo: &GitRepo, name: &str) -> bool {
+    log_path(repo, name).is_file()
+}
+
+/// Committer identity, or identity made of the user name when none is configured so that
+/// updates of refs do not fail on it
+fn identity(repo: &GitRepo) -> Result<Author> {
+    if let Ok(committer) = Author::from_env(repo, Role::Committer) {
+        return Ok(committer);
+    }
+    let user = std::env::var("USER").unwrap_or_else(|_| String::from("unknown"));
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs() as i64;
+    Ok(Author {
+        email: format!("{}@localhost", user),
+        name: user,
+        time: date::system_time(now)?,
+        time_offset: date::format_offset(date::local_offset(now)),
+    })
+}
+
+/// Entries of the ref log, oldest first. Missing log has no entries.
+pub fn read(repo: &GitRepo, name: &str) -> Result<Vec<ReflogEntry>> {
+    let path = log_path(repo, name);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    fs::read_to_string(&path)
+        .with_context(|| format!("Reading reflog of {}", name))?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(ReflogEntry::parse)
+        .collect()
+}
+
+/// Records update of the ref done by the current committer
+pub fn append(
+    repo: &GitRepo,
+    name: &str,
+    old: Option<&ObjectId>,
+    new: &ObjectId,
+    message: &str,
+) -> Result<()> {
+    let entry = ReflogEntry {
+        old: old.copied().unwrap_or_else(|| repo.hash_algo().null_id()),
+        new: *new,
+        committer: identity(repo)?,
+        message: message.to_string(),
+    };
+    let path = log_path(repo, name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context("Creating reflog dirs")?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&entry.to_line()))
+        .with_context(|| format!("Writing reflog of {}", name))
+}
+
+/// Replaces the whole log, removing it when there are no entries
+pub fn write(repo: &GitRepo, name: &str, entries: &[ReflogEntry]) -> Result<()> {
+    if entries.is_empty() {
+        return delete(repo, name);
+    }
+    let path = log_path(repo, name);
+    let content: Vec<u8> = entries.iter().flat_map(ReflogEntry::to_line).collect();
+    fs::write(&path, content).with_context(|| format!("Writing reflog of {}", name))
+}
+
+/// Removes the log together with the ref
+pub fn delete(repo: &GitRepo, name: &str) -> Result<()> {
+    let path = log_path(repo, name);
+    if path.exists() {
+        fs::remove_file(&path).with_context(|| format!("Removing reflog of {}", name))?;
+    }
+    Ok(())
+}
+
+/// Message of HEAD reflog entry for checkout of the branch or commit, naming what was
+/// checked out before
+pub fn checkout_message(repo: &GitRepo, to: &str) -> Result<String> {
+    let from = match refs::read(repo, "HEAD")? {
+        Some(RefValue::Symbolic(branch)) => branch
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&branch)
+            .to_string(),
+        Some(RefValue::Direct(id)) => id.to_hex(),
+        None => String::from("HEAD"),
+    };
+    let to = to.strip_prefix("refs/heads/").unwrap_or(to);
+    Ok(format!("checkout: moving from {} to {}", from, to))
+}
@@ -0,0 +1,485 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::config::{self, Config};
+use crate::error::{bail, ensure, Context, Result};
+use crate::oid::ObjectId;
+use crate::storage::FsyncComponent;
+use crate::{reflog, GitRepo};
+
+/// Maximal depth of symbolic ref chain we are willing to follow
+const MAX_SYMREF_DEPTH: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefValue {
+    /// Id of the referenced object
+    Direct(ObjectId),
+    /// Name of another ref
+    Symbolic(String),
+}
+
+impl RefValue {
+    fn parse(content: &str) -> Result<Self> {
+        let content = content.lines().next().unwrap_or_default().trim_end();
+        match content.strip_prefix("ref: ") {
+            Some(target) => Ok(Self::Symbolic(target.to_string())),
+            // Pseudo refs like FETCH_HEAD carry more data after the id
+            None => Ok(Self::Direct(
+                content.split('\t').next().unwrap_or_default().parse()?,
+            )),
+        }
+    }
+}
+
+fn packed_refs(repo: &GitRepo) -> Result<BTreeMap<String, ObjectId>> {
+    let path = repo.common_dir.join("packed-refs");
+    let mut refs = BTreeMap::new();
+    if !path.exists() {
+        return Ok(refs);
+    }
+    let content = fs::read_to_string(&path).context("Reading packed-refs")?;
+    for line in content.lines() {
+        // Comments hold traits and `^` lines hold peeled values
+        if line.starts_with('#') || line.starts_with('^') || line.is_empty() {
+            continue;
+        }
+        match line.split_once(' ') {
+            Some((id, name)) => refs.insert(name.to_string(), id.parse()?),
+            None => bail!("Invalid packed-refs line {:?}", line),
+        };
+    }
+    Ok(refs)
+}
+
+/// Whether the ref name is well formed, following the rules of `git check-ref-format`.
+/// Such names stay inside the refs directory and are not confused with revision syntax.
+pub fn is_valid_name(name: &str) -> bool {
+    name != "@"
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name.ends_with('.')
+        && !name
+            .bytes()
+            .any(|b| b < 0x20 || b == 0x7f || b" ~^:?*[\\".contains(&b))
+        && name
+            .split('/')
+            .all(|c| !c.is_empty() && !c.starts_with('.') && !c.ends_with(".lock"))
+}
+
+/// Reads the value of given ref without following symbolic refs
+pub fn read(repo: &GitRepo, name: &str) -> Result<Option<RefValue>> {
+    let path = repo.ref_path(name);
+    if path.is_file() {
+        let content = fs::read_to_string(&path).with_context(|| format!("Reading ref {}", name))?;
+        let value = RefValue::parse(&content).with_context(|| format!("Reading ref {}", name))?;
+        return Ok(Some(value));
+    }
+    Ok(packed_refs(repo)?.remove(name).map(RefValue::Direct))
+}
+
+/// Resolves ref to object id following symbolic refs. Returns `None` for unborn refs.
+pub fn resolve(repo: &GitRepo, name: &str) -> Result<Option<ObjectId>> {
+    let mut name = name.to_string();
+    for _ in 0..MAX_SYMREF_DEPTH {
+        match read(repo, &name)? {
+            Some(RefValue::Direct(id)) => return Ok(Some(id)),
+            Some(RefValue::Symbolic(target)) => name = target,
+            None => return Ok(None),
+        }
+    }
+    bail!("Symbolic ref chain too deep at {}", name)
+}
+
+/// Name of the ref moved by new commits: the current branch, or `HEAD` itself when detached
+pub fn head_ref(repo: &GitRepo) -> Result<String> {
+    deref(repo, "HEAD")
+}
+
+/// Name of the ref at the end of symbolic ref chain starting at `name`, which is the ref
+/// updates of `name` actually change. The ref itself when it is not symbolic.
+pub fn deref(repo: &GitRepo, name: &str) -> Result<String> {
+    let mut name = name.to_string();
+    for _ in 0..MAX_SYMREF_DEPTH {
+        match read(repo, &name)? {
+            Some(RefValue::Symbolic(target)) => name = target,
+            _ => return Ok(name),
+        }
+    }
+    bail!("Symbolic ref chain too deep at {}", name)
+}
+
+/// Ref name without the `refs/heads/`, `refs/tags/`, `refs/remotes/` or `refs/` prefix
+pub fn shorten(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+/// Expands short ref name to full name using the same rules as git, e.g. `main` to
+/// `refs/heads/main`. Returns `None` if no such ref exists.
+pub fn dwim(repo: &GitRepo, name: &str) -> Result<Option<String>> {
+    let candidates = [
+        name.to_string(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+        format!("refs/remotes/{}/HEAD", name),
+    ];
+    for full in candidates {
+        let valid = full == "HEAD" || full.starts_with("refs/") || full.ends_with("_HEAD");
+        if valid && read(repo, &full)?.is_some() {
+            return Ok(Some(full));
+        }
+    }
+    Ok(None)
+}
+
+fn collect_loose(dir: &Path, prefix: &str, refs: &mut BTreeMap<String, RefValue>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for item in fs::read_dir(dir)? {
+        let item = item?;
+        let Ok(name) = item.file_name().into_string() else {
+            continue;
+        };
+        let full_name = format!("{}/{}", prefix, name);
+        if item.file_type()?.is_dir() {
+            collect_loose(&item.path(), &full_name, refs)?;
+        } else if !name.ends_with(".lock") {
+            let value = fs::read_to_string(item.path())
+                .context("Reading ref")
+                .and_then(|content| RefValue::parse(&content))
+                .with_context(|| format!("Reading ref {}", full_name))?;
+            refs.insert(full_name, value);
+        }
+    }
+    Ok(())
+}
+
+/// Lists all refs under `refs/` with their resolved object ids, sorted by name
+pub fn list(repo: &GitRepo) -> Result<Vec<(String, ObjectId)>> {
+    let mut all: BTreeMap<String, RefValue> = packed_refs(repo)?
+        .into_iter()
+        .map(|(name, id)| (name, RefValue::Direct(id)))
+        .collect();
+    collect_loose(&repo.refs_dir, "refs", &mut all)?;
+    if repo.git_dir != repo.common_dir {
+        // Refs of other working trees are not visible, only of this one
+        all.retain(|name, _| !name.starts_with("refs/bisect/"));
+        let bisect = repo.git_dir.join("refs").join("bisect");
+        collect_loose(&bisect, "refs/bisect", &mut all)?;
+    }
+
+    let mut refs = Vec::with_capacity(all.len());
+    for (name, value) in all {
+        let id = match value {
+            RefValue::Direct(id) => Some(id),
+            RefValue::Symbolic(target) => resolve(repo, &target)?,
+        };
+        if let Some(id) = id {
+            refs.push((name, id));
+        }
+    }
+    Ok(refs)
+}
+
+/// Lock file guarding update of single file, removed on drop unless committed
+pub(crate) struct LockFile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    committed: bool,
+}
+
+impl LockFile {
+    pub(crate) fn acquire(path: PathBuf) -> Result<Self> {
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        if let Some(parent) = lock_path.parent() {
+            fs::create_dir_all(parent).context("Creating ref dirs")?;
+        }
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .with_context(|| format!("Unable to lock {}", path.display()))?;
+        Ok(Self {
+            path,
+            lock_path,
+            committed: false,
+        })
+    }
+
+    /// Replaces the file with the content, flushing it to disk first with `fsync`
+    pub(crate) fn commit(mut self, content: &[u8], fsync: bool) -> Result<()> {
+        let mut file = File::create(&self.lock_path).context("Writing lock file")?;
+        file.write_all(content).context("Writing lock file")?;
+        if fsync {
+            file.sync_all().context("Syncing lock file")?;
+        }
+        fs::rename(&self.lock_path, &self.path).context("Renaming lock file")?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
+
+/// Removes the refs from `packed-refs` guarded by the already acquired lock
+fn remove_packed(lock: LockFile, names: &BTreeSet<&str>, fsync: bool) -> Result<()> {
+    let content = fs::read_to_string(&lock.path).context("Reading packed-refs")?;
+    let mut result = String::with_capacity(content.len());
+    let mut skip_peeled = false;
+    for line in content.lines() {
+        if line.starts_with('^') && skip_peeled {
+            continue;
+        }
+        skip_peeled = line.split_once(' ').is_some_and(|(_, n)| names.contains(n));
+        if !skip_peeled {
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+    lock.commit(result.as_bytes(), fsync)
+}
+
+/// Whether updates of the ref are recorded in its reflog. With `core.logAllRefUpdates`,
+/// which is on by default in repositories with working tree, branches, remote-tracking
+/// refs, notes and HEAD are logged. Refs with existing log are always logged.
+fn should_log(repo: &GitRepo, name: &str) -> Result<bool> {
+    if reflog::exists(repo, name) {
+        return Ok(true);
+    }
+    let enabled = match Config::load(repo)?.get("core.logAllRefUpdates") {
+        Some("always") => return Ok(true),
+        Some(value) => config::parse_bool(Some(value))?,
+        None => !repo.is_bare,
+    };
+    let logged = name == "HEAD"
+        || name == "refs/stash"
+        || ["refs/heads/", "refs/remotes/", "refs/notes/"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix));
+    Ok(enabled && logged)
+}
+
+/// Records update of the ref in its reflog and, when it is the current branch, in the
+/// reflog of HEAD
+fn log_update(
+    repo: &GitRepo,
+    name: &str,
+    old: Option<&ObjectId>,
+    new: &ObjectId,
+    message: &str,
+) -> Result<()> {
+    if should_log(repo, name)? {
+        reflog::append(repo, name, old, new, message)?;
+    }
+    if name != "HEAD" && head_ref(repo)? == name && should_log(repo, "HEAD")? {
+        reflog::append(repo, "HEAD", old, new, message)?;
+    }
+    Ok(())
+}
+
+/// Change of single ref queued in a transaction
+#[derive(Debug, Clone)]
+struct RefChange {
+    name: String,
+    /// Whether the ref has to have the expected value
+    verify: bool,
+    expected_old: Option<ObjectId>,
+    new: Option<ObjectId>,
+    message: String,
+}
+
+/// Updates of several refs applied all or none. Every ref is locked and checked against
+/// its expected value before any of them changes, so concurrent writers cannot interleave.
+#[derive(Debug, Clone, Default)]
+pub struct RefTransaction {
+    changes: Vec<RefChange>,
+}
+
+impl RefTransaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues update of the ref to `new` (or its deletion when `None`), which happens only
+    /// if the ref points to `expected_old`. Expected `None` means the ref must not exist.
+    pub fn update(
+        &mut self,
+        name: &str,
+        expected_old: Option<&ObjectId>,
+        new: Option<&ObjectId>,
+        message: &str,
+    ) -> &mut Self {
+        self.changes.push(RefChange {
+            name: name.to_string(),
+            verify: true,
+            expected_old: expected_old.copied(),
+            new: new.copied(),
+            message: message.to_string(),
+        });
+        self
+    }
+
+    /// Queues update of the ref to `new` (or its deletion when `None`) whatever it
+    /// currently points to
+    pub fn force_update(&mut self, name: &str, new: Option<&ObjectId>, message: &str) -> &mut Self {
+        self.changes.push(RefChange {
+            name: name.to_string(),
+            verify: false,
+            expected_old: None,
+            new: new.copied(),
+            message: message.to_string(),
+        });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Applies all queued updates. When any ref cannot be locked or does not have the
+    /// expected value, nothing is changed. Updates are recorded in reflogs with their
+    /// messages, deleting a ref deletes its reflog.
+    pub fn commit(self, repo: &GitRepo) -> Result<()> {
+        let mut changes = self.changes;
+        // Locks are always taken in the same order
+        changes.sort_by(|a, b| a.name.cmp(&b.name));
+        for (i, change) in changes.iter().enumerate() {
+            ensure!(
+                change.name == "HEAD"
+                    || (change.name.starts_with("refs/") && is_valid_name(&change.name)),
+                "refusing to update ref with bad name '{}'",
+                change.name
+            );
+            ensure!(
+                i == 0 || changes[i - 1].name != change.name,
+                "Multiple updates of ref {} in one transaction",
+                change.name
+            );
+        }
+
+        let fsync = repo.write_options()?.fsync(FsyncComponent::Reference);
+        let mut locks = Vec::with_capacity(changes.len());
+        for change in &changes {
+            locks.push(LockFile::acquire(repo.ref_path(&change.name))?);
+        }
+        let deleted: BTreeSet<&str> = changes
+            .iter()
+            .filter(|change| change.new.is_none())
+            .map(|change| change.name.as_str())
+            .collect();
+        let packed_path = repo.common_dir.join("packed-refs");
+        let packed_lock = if !deleted.is_empty() && packed_path.exists() {
+            Some(LockFile::acquire(packed_path)?)
+        } else {
+            None
+        };
+        let mut old_values = Vec::with_capacity(changes.len());
+        for change in &changes {
+            let current = match read(repo, &change.name)? {
+                Some(RefValue::Direct(id)) => Some(id),
+                Some(RefValue::Symbolic(target)) => {
+                    bail!("Cannot update symbolic ref {} -> {}", change.name, target)
+                }
+                None => None,
+            };
+            ensure!(
+                !change.verify || current == change.expected_old,
+                "Ref {} is at {} but expected {}",
+                change.name,
+                current.map_or_else(|| String::from("nothing"), |id| id.to_string()),
+                change
+                    .expected_old
+                    .map_or_else(|| String::from("nothing"), |id| id.to_string())
+            );
+            old_values.push(current);
+        }
+
+        for (change, lock) in changes.iter().zip(locks) {
+            match &change.new {
+                Some(id) => lock.commit(format!("{}\n", id).as_bytes(), fsync)?,
+                None => {
+                    let path = repo.ref_path(&change.name);
+                    if path.is_file() {
+                        fs::remove_file(&path).context("Removing ref")?;
+                    }
+                }
+            }
+        }
+        if let Some(lock) = packed_lock {
+            remove_packed(lock, &deleted, fsync)?;
+        }
+        for (change, old) in changes.iter().zip(old_values) {
+            match &change.new {
+                Some(id) => log_update(repo, &change.name, old.as_ref(), id, &change.message)?,
+                None => reflog::delete(repo, &change.name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Updates ref to `new` (or deletes it when `None`) if it currently points to `expected_old`.
+/// Expected `None` means the ref must not exist yet. It is a transaction with single update.
+pub fn update(
+    repo: &GitRepo,
+    name: &str,
+    expected_old: Option<&ObjectId>,
+    new: Option<&ObjectId>,
+    message: &str,
+) -> Result<()> {
+    let mut transaction = RefTransaction::new();
+    transaction.update(name, expected_old, new, message);
+    transaction.commit(repo)
+}
+
+/// Points `HEAD` directly to commit, leaving the current branch untouched
+pub fn detach_head(repo: &GitRepo, id: &ObjectId, message: &str) -> Result<()> {
+    let old = resolve(repo, "HEAD")?;
+    let lock = LockFile::acquire(repo.ref_path("HEAD"))?;
+    let fsync = repo.write_options()?.fsync(FsyncComponent::Reference);
+    lock.commit(format!("{}\n", id).as_bytes(), fsync)?;
+    if should_log(repo, "HEAD")? {
+        reflog::append(repo, "HEAD", old.as_ref(), id, message)?;
+    }
+    Ok(())
+}
+
+/// Points symbolic ref (usually `HEAD`) to another ref. With message the change is
+/// recorded in the reflog of the symbolic ref unless the target is unborn.
+pub fn set_symbolic(repo: &GitRepo, name: &str, target: &str, message: Option<&str>) -> Result<()> {
+    ensure!(
+        name == "HEAD" || (name.starts_with("refs/") && is_valid_name(name)),
+        "Invalid symbolic ref name {}",
+        name
+    );
+    ensure!(
+        target.starts_with("refs/") && is_valid_name(target),
+        "Invalid symbolic ref target {}",
+        target
+    );
+    let old = resolve(repo, name)?;
+    let lock = LockFile::acquire(repo.ref_path(name))?;
+    let fsync = repo.write_options()?.fsync(FsyncComponent::Reference);
+    lock.commit(format!("ref: {}\n", target).as_bytes(), fsync)?;
+    match (message, resolve(repo, target)?) {
+        (Some(message), Some(new)) if should_log(repo, name)? => {
+            reflog::append(repo, name, old.as_ref(), &new, message)
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,600 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+
+use crate::bundle::Bundle;
+use crate::config::{Config, ConfigFile};
+use crate::error::{bail, ensure, format_err, Context, Result};
+use crate::fsck;
+use crate::hooks::{self, HookName};
+use crate::http_protocol::{GitHttpClient, RefInfo, RefUpdate};
+use crate::object::Object;
+use crate::oid::ObjectId;
+use crate::revwalk::is_ancestor;
+use crate::{pack, refs, GitRepo};
+
+/// Mapping between remote and local refs, e.g. `+refs/heads/*:refs/remotes/origin/*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefSpec {
+    /// Update the destination even if it is not a fast-forward
+    pub force: bool,
+    pub src: String,
+    /// `None` when the source should not be stored anywhere
+    pub dst: Option<String>,
+}
+
+impl RefSpec {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (force, spec) = match spec.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+        let (src, dst) = match spec.split_once(':') {
+            Some((src, dst)) => (src, (!dst.is_empty()).then(|| dst.to_string())),
+            None => (spec, None),
+        };
+        let globs = |s: &str| s.matches('*').count();
+        ensure!(
+            globs(src) <= 1 && dst.as_deref().is_none_or(|dst| globs(dst) == globs(src)),
+            "Invalid refspec {:?}",
+            spec
+        );
+        Ok(Self {
+            force,
+            src: src.to_string(),
+            dst,
+        })
+    }
+
+    /// Maps source ref name to destination, `None` if the refspec doesn't match
+    pub fn map(&self, name: &str) -> Option<String> {
+        let dst = self.dst.as_ref()?;
+        match self.src.split_once('*') {
+            Some((prefix, suffix)) => {
+                let matched = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+                Some(dst.replacen('*', matched, 1))
+            }
+            None => (name == self.src).then(|| dst.clone()),
+        }
+    }
+}
+
+impl std::fmt::Display for RefSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.force {
+            write!(f, "+")?;
+        }
+        write!(f, "{}", self.src)?;
+        if let Some(dst) = &self.dst {
+            write!(f, ":{}", dst)?;
+        }
+        Ok(())
+    }
+}
+
+/// Remote repository configured in `remote.<name>.*` config section
+#[derive(Debug, Clone)]
+pub struct Remote {
+    pub name: String,
+    pub url: String,
+    pub push_url: Option<String>,
+    pub fetch: Vec<RefSpec>,
+}
+
+/// Abbreviated ref name as shown to the user
+fn short_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+fn short_id(id: &ObjectId) -> String {
+    id.to_hex()[..7].to_string()
+}
+
+/// Ref tips of repositories whose object directories are our alternates, offered as
+/// haves so the remote does not send objects we can already borrow
+fn alternate_tips(repo: &GitRepo) -> Result<Vec<ObjectId>> {
+    let mut tips = Vec::new();
+    for objects_dir in repo.object_dirs().into_iter().skip(1) {
+        let Some(git_dir) = objects_dir.parent() else {
+            continue;
+        };
+        if !git_dir.join("refs").is_dir() {
+            continue;
+        }
+        let alternate = GitRepo::new_bare(git_dir);
+        tips.extend(refs::list(&alternate)?.into_iter().map(|(_, id)| id));
+    }
+    Ok(tips)
+}
+
+impl Remote {
+    /// Default fetch refspec of newly added remote
+    pub fn default_fetch(name: &str) -> RefSpec {
+        RefSpec {
+            force: true,
+            src: String::from("refs/heads/*"),
+            dst: Some(format!("refs/remotes/{}/*", name)),
+        }
+    }
+
+    /// Names of all configured remotes in order of appearance
+    pub fn list(repo: &GitRepo) -> Result<Vec<String>> {
+        let config = Config::load(repo)?;
+        let mut names: Vec<String> = Vec::new();
+        for entry in config.entries() {
+            if entry.key.section != "remote" {
+                continue;
+            }
+            if let Some(name) = &entry.key.subsection {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// Resolves remote by its name. URLs are accepted as well, yielding remote without refspecs.
+    pub fn load(repo: &GitRepo, name: &str) -> Result<Self> {
+        let config = Config::load(repo)?;
+        match config.get(&format!("remote.{}.url", name)) {
+            Some(url) => Ok(Self {
+                name: name.to_string(),
+                url: url.to_string(),
+                push_url: config
+                    .get(&format!("remote.{}.pushurl", name))
+                    .map(String::from),
+                fetch: config
+                    .get_all(&format!("remote.{}.fetch", name))
+                    .into_iter()
+                    .map(RefSpec::parse)
+                    .collect::<Result<_>>()?,
+            }),
+            None if name.contains("://") => Ok(Self {
+                name: name.to_string(),
+                url: name.to_string(),
+                push_url: None,
+                fetch: Vec::new(),
+            }),
+            None => bail!("'{}' does not appear to be a git repository", name),
+        }
+    }
+
+    /// Adds remote with default fetch refspec to repository config
+    pub fn add(repo: &GitRepo, name: &str, url: &str) -> Result<Self> {
+        Self::add_with_fetch(repo, name, url, Some(Self::default_fetch(name)))
+    }
+
+    /// Adds remote with given fetch refspec, or without any, to repository config
+    pub fn add_with_fetch(
+        repo: &GitRepo,
+        name: &str,
+        url: &str,
+        fetch: Option<RefSpec>,
+    ) -> Result<Self> {
+        ensure!(
+            !name.is_empty() && !name.contains(['/', '*', ':', ' ']) && !name.contains("://"),
+            "'{}' is not a valid remote name",
+            name
+        );
+        ensure!(
+            !Self::list(repo)?.iter().any(|n| n == name),
+            "remote {} already exists.",
+            name
+        );
+        let remote = Self {
+            name: name.to_string(),
+            url: url.to_string(),
+            push_url: None,
+            fetch: fetch.into_iter().collect(),
+        };
+        let mut file = ConfigFile::open(&Config::local_path(repo))?;
+        file.set(&format!("remote.{}.url", name), url)?;
+        for fetch in &remote.fetch {
+            file.set(&format!("remote.{}.fetch", name), &fetch.to_string())?;
+        }
+        file.save()?;
+        Ok(remote)
+    }
+
+    /// Removes remote from config together with its remote-tracking refs
+    pub fn remove(repo: &GitRepo, name: &str) -> Result<()> {
+        ensure!(
+            Self::list(repo)?.iter().any(|n| n == name),
+            "No such remote: '{}'",
+            name
+        );
+        let remote = Self::load(repo, name)?;
+        let prefixes: Vec<String> = remote
+            .fetch
+            .iter()
+            .filter_map(|spec| spec.dst.as_deref())
+            .map(|dst| dst.split('*').next().unwrap_or(dst).to_string())
+            .collect();
+        for (ref_name, id) in refs::list(repo)? {
+            if prefixes.iter().any(|p| ref_name.starts_with(p.as_str())) {
+                if let Some(refs::RefValue::Symbolic(_)) = refs::read(repo, &ref_name)? {
+                    fs::remove_file(repo.ref_path(&ref_name)).context("Removing ref")?;
+                } else {
+                    refs::update(repo, &ref_name, Some(&id), None, "")?;
+                }
+            }
+        }
+        let mut file = ConfigFile::open(&Config::local_path(repo))?;
+        file.remove_section("remote", Some(name))?;
+        for branch in Self::branches_of(repo, name)? {
+            file.unset(&format!("branch.{}.remote", branch))?;
+            file.unset(&format!("branch.{}.merge", branch))?;
+        }
+        file.save()
+    }
+
+    /// Local branches configured to track given remote
+    fn branches_of(repo: &GitRepo, name: &str) -> Result<Vec<String>> {
+        let config = Config::load(repo)?;
+        Ok(config
+            .entries()
+            .iter()
+            .filter(|e| {
+                e.key.section == "branch"
+                    && e.key.name == "remote"
+                    && e.value.as_deref() == Some(name)
+            })
+            .filter_map(|e| e.key.subsection.clone())
+            .collect())
+    }
+
+    pub fn push_url(&self) -> &str {
+        self.push_url.as_deref().unwrap_or(&self.url)
+    }
+
+    /// Local ref tracking given remote ref according to fetch refspecs
+    pub fn tracking_ref(&self, name: &str) -> Option<(String, bool)> {
+        self.fetch
+            .iter()
+            .find_map(|spec| spec.map(name).map(|dst| (dst, spec.force)))
+    }
+
+    /// Bundle file the URL points to, `None` for remote repositories
+    fn bundle(&self) -> Result<Option<Bundle>> {
+        let path = Path::new(&self.url);
+        if Bundle::is_bundle(path) {
+            Ok(Some(Bundle::open(path)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Queries ref advertisement of the remote
+    pub fn ref_info(&self, repo: &GitRepo) -> Result<RefInfo> {
+        match self.bundle()? {
+            Some(bundle) => Ok(bundle.ref_info()),
+            None => GitHttpClient::new(repo, self.url.clone()).ref_info(),
+        }
+    }
+
+    /// Fetches refs matched by fetch refspecs and updates remote-tracking refs.
+    /// Returns the ref advertisement of the remote.
+    pub fn fetch(&self, repo: &GitRepo) -> Result<RefInfo> {
+        let mut client = GitHttpClient::new(repo, self.url.clone());
+        let bundle = self.bundle()?;
+        let ref_info = match &bundle {
+            Some(bundle) => bundle.ref_info(),
+            None => client.ref_info()?,
+        };
+        self.fetch_from(repo, bundle, client, ref_info)
+    }
+
+    /// Like [`Remote::fetch`], reusing the ref advertisement the client already
+    /// received from the remote instead of requesting it again
+    pub fn fetch_advertised(
+        &self,
+        repo: &GitRepo,
+        client: GitHttpClient,
+        ref_info: RefInfo,
+    ) -> Result<RefInfo> {
+        self.fetch_from(repo, None, client, ref_info)
+    }
+
+    fn fetch_from(
+        &self,
+        repo: &GitRepo,
+        bundle: Option<Bundle>,
+        client: GitHttpClient,
+        ref_info: RefInfo,
+    ) -> Result<RefInfo> {
+        ref_info.ensure_object_format(repo)?;
+
+        // Without refspecs (plain URL) only the remote HEAD is fetched
+        let fetched: Vec<_> = ref_info
+            .refs
+            .iter()
+            .filter_map(|r| {
+                let tracking = if self.fetch.is_empty() {
+                    (r.name == "HEAD").then(|| (String::new(), true))
+                } else {
+                    self.tracking_ref(&r.name)
+                };
+                Some((r, tracking?))
+            })
+            .collect();
+
+        let mut wants = BTreeSet::new();
+        for (r, _) in &fetched {
+            if !Object::exists(repo, &r.id) {
+                wants.insert(&r.id);
+            }
+        }
+        let mut local: Vec<ObjectId> = refs::list(repo)?.into_iter().map(|(_, id)| id).collect();
+        local.extend(alternate_tips(repo)?);
+        if !wants.is_empty() {
+            let tips: Vec<ObjectId> = wants.iter().map(|id| **id).collect();
+            match &bundle {
+                Some(bundle) => bundle.unbundle(repo)?,
+                None => client.fetch_pack(wants, local.iter().collect())?,
+            }
+            // Refs are updated only once everything they point to is present
+            fsck::check_connected(repo, &tips, &local)
+                .with_context(|| format!("{} did not send all necessary objects", self.url))?;
+        }
+
+        // Advertisement not modified since the last fetch has nothing new when tracking
+        // refs still match it
+        let mut up_to_date = client.advertisement_cached();
+        for (r, (dst, _)) in &fetched {
+            if up_to_date && !dst.is_empty() {
+                up_to_date = refs::resolve(repo, dst)? == Some(r.id);
+            }
+        }
+        let mut fetch_head = String::new();
+        let mut errors = 0;
+        if up_to_date {
+            repo.progress().info("Already up to date.");
+        } else {
+            repo.progress().info(&format!("From {}", self.url));
+        }
+        for (r, (dst, force)) in &fetched {
+            let id = &r.id;
+            let kind = if r.name.starts_with("refs/tags/") {
+                "tag"
+            } else {
+                "branch"
+            };
+            let not_for_merge = if r.name == "HEAD" || self.fetch.is_empty() {
+                ""
+            } else {
+                "not-for-merge"
+            };
+            let description = if r.name == "HEAD" {
+                String::new()
+            } else {
+                format!("{} '{}' of ", kind, short_name(&r.name))
+            };
+            fetch_head.push_str(&format!(
+                "{}\t{}\t{}{}\n",
+                id, not_for_merge, description, self.url
+            ));
+            if dst.is_empty() {
+                continue;
+            }
+
+            let current = refs::resolve(repo, dst)?;
+            let summary = match current.as_ref() {
+                Some(old) if old == id => continue,
+                None => format!(" * [new {}]", kind),
+                Some(old) if is_ancestor(repo, old, id)? => {
+                    format!("   {}..{}", short_id(old), short_id(id))
+                }
+                Some(old) if *force => format!(" + {}...{}", short_id(old), short_id(id)),
+                Some(_) => {
+                    repo.progress().info(&format!(
+                        " ! [rejected] {} -> {} (non-fast-forward)",
+                        short_name(&r.name),
+                        short_name(dst)
+                    ));
+                    errors += 1;
+                    continue;
+                }
+            };
+            let message = match current.as_ref() {
+                None => String::from("fetch: storing head"),
+                Some(old) if is_ancestor(repo, old, id)? => String::from("fetch: fast-forward"),
+                Some(_) => String::from("fetch: forced-update"),
+            };
+            refs::update(repo, dst, current.as_ref(), Some(id), &message)?;
+            repo.progress().info(&format!(
+                "{} {} -> {}",
+                summary,
+                short_name(&r.name),
+                short_name(dst)
+            ));
+        }
+        fs::write(repo.git_dir.join("FETCH_HEAD"), fetch_head).context("Writing FETCH_HEAD")?;
+        ensure!(errors == 0, "Some refs from {} were rejected", self.url);
+        Ok(ref_info)
+    }
+
+    /// Pushes local refs to the remote. Refspecs default to the current branch.
+    pub fn push(&self, repo: &GitRepo, refspecs: &[RefSpec], force: bool) -> Result<()> {
+        let url = self.push_url().to_string();
+        let mut client = GitHttpClient::new(repo, url.clone());
+        let ref_info = client.receive_pack_ref_info()?;
+        ref_info.ensure_object_format(repo)?;
+        let remote_refs: HashMap<&str, ObjectId> = ref_info
+            .refs
+            .iter()
+            .map(|r| (r.name.as_str(), r.id))
+            .collect();
+
+        let refspecs = if refspecs.is_empty() {
+            let Some(refs::RefValue::Symbolic(head)) = refs::read(repo, "HEAD")? else {
+                bail!("You are not currently on a branch");
+            };
+            vec![RefSpec {
+                force: false,
+                src: head.clone(),
+                dst: Some(head),
+            }]
+        } else {
+            refspecs.to_vec()
+        };
+
+        let mut commands = Vec::new();
+        // Source names for each command, used in the summary
+        let mut sources = Vec::new();
+        let mut rejected = Vec::new();
+        // Lines of `pre-push` hook input: local ref and id, remote ref and id
+        let mut hook_input = String::new();
+        for spec in &refspecs {
+            let (new, dst, local) = if spec.src.is_empty() {
+                let dst = spec
+                    .dst
+                    .clone()
+                    .ok_or(format_err!("Invalid refspec {}", spec))?;
+                let dst = if dst.starts_with("refs/") {
+                    dst
+                } else {
+                    format!("refs/heads/{}", dst)
+                };
+                (None, dst, String::from("(delete)"))
+            } else {
+                let src = refs::dwim(repo, &spec.src)?
+                    .ok_or(format_err!("src refspec {} does not match any", spec.src))?;
+                let id = refs::resolve(repo, &src)?
+                    .ok_or(format_err!("src refspec {} does not match any", spec.src))?;
+                let dst = match &spec.dst {
+                    Some(dst) if dst.starts_with("refs/") => dst.clone(),
+                    // Short destination is placed the same namespace as the source
+                    Some(dst) => match src.rfind('/') {
+                        Some(pos) if src.starts_with("refs/") => format!("{}/{}", &src[..pos], dst),
+                        _ => format!("refs/heads/{}", dst),
+                    },
+                    None if src == "HEAD" => match refs::read(repo, "HEAD")? {
+                        Some(refs::RefValue::Symbolic(head)) => head,
+                        _ => bail!("You are not currently on a branch"),
+                    },
+                    None => src.clone(),
+                };
+                (Some(id), dst, src)
+            };
+            let old = remote_refs.get(dst.as_str()).copied();
+            if old == new {
+                continue;
+            }
+            if let (Some(old), Some(new)) = (&old, &new) {
+                let fast_forward = Object::exists(repo, old) && is_ancestor(repo, old, new)?;
+                if !fast_forward && !force && !spec.force {
+                    let reason = if Object::exists(repo, old) {
+                        "non-fast-forward"
+                    } else {
+                        "fetch first"
+                    };
+                    rejected.push(format!(
+                        " ! [rejected] {} -> {} ({})",
+                        short_name(&spec.src),
+                        short_name(&dst),
+                        reason
+                    ));
+                    continue;
+                }
+            }
+            let null_id = repo.hash_algo().null_id();
+            hook_input.push_str(&format!(
+                "{} {} {} {}\n",
+                local,
+                new.unwrap_or(null_id),
+                dst,
+                old.unwrap_or(null_id)
+            ));
+            sources.push(spec.src.clone());
+            commands.push(RefUpdate {
+                name: dst,
+                old,
+                new,
+            });
+        }
+
+        if commands.is_empty() && rejected.is_empty() {
+            repo.progress().info("Everything up-to-date");
+            return Ok(());
+        }
+        if !commands.is_empty() {
+            let args = [self.name.clone(), url.clone()];
+            hooks::run(repo, HookName::PrePush, &args, hook_input.as_bytes())
+                .with_context(|| format!("failed to push some refs to '{}'", url))?;
+        }
+        repo.progress().info(&format!("To {}", url));
+        let statuses = if commands.is_empty() {
+            Vec::new()
+        } else {
+            let wants: Vec<ObjectId> = commands.iter().filter_map(|c| c.new).collect();
+            let haves: Vec<ObjectId> = remote_refs
+                .values()
+                .filter(|id| Object::exists(repo, id))
+                .copied()
+                .collect();
+            let pack = if wants.is_empty() {
+                None
+            } else {
+                let ids = pack::objects_to_send(repo, &wants, &haves)?;
+                let mut data = Vec::new();
+                let level = repo.write_options()?.pack_compression;
+                pack::stream_pack(repo, &ids, level, &mut data)?;
+                Some(data)
+            };
+            client.send_pack(&commands, pack.as_deref())?
+        };
+
+        for (cmd, src) in commands.iter().zip(&sources) {
+            let status = statuses.iter().find(|s| s.name == cmd.name);
+            let kind = if cmd.name.starts_with("refs/tags/") {
+                "tag"
+            } else {
+                "branch"
+            };
+            let summary = match (&cmd.old, &cmd.new) {
+                (None, _) => format!(" * [new {}]", kind),
+                (_, None) => String::from(" - [deleted]"),
+                (Some(old), Some(new)) if is_ancestor(repo, old, new)? => {
+                    format!("   {}..{}", short_id(old), short_id(new))
+                }
+                (Some(old), Some(new)) => format!(" + {}...{}", short_id(old), short_id(new)),
+            };
+            let names = match cmd.new {
+                Some(_) => format!("{} -> {}", short_name(src), short_name(&cmd.name)),
+                None => short_name(&cmd.name).to_string(),
+            };
+            match status {
+                Some(s) if s.error.is_none() => {
+                    repo.progress().info(&format!("{} {}", summary, names));
+                    if let Some((tracking, _)) = self.tracking_ref(&cmd.name) {
+                        let current = refs::resolve(repo, &tracking)?;
+                        if current != cmd.new {
+                            refs::update(
+                                repo,
+                                &tracking,
+                                current.as_ref(),
+                                cmd.new.as_ref(),
+                                "update by push",
+                            )?;
+                        }
+                    }
+                }
+                Some(s) => rejected.push(format!(
+                    " ! [remote rejected] {} ({})",
+                    names,
+                    s.error.as_deref().unwrap_or_default()
+                )),
+                None => rejected.push(format!(" ! [no status] {}", names)),
+            }
+        }
+        for line in &rejected {
+            repo.progress().info(line);
+        }
+        ensure!(rejected.is_empty(), "failed to push some refs to '{}'", url);
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use crate::commit::Commit;
+use crate::error::{bail, ensure, format_err, Context, Error, Result};
+use crate::object::{Object, ObjectKind, MIN_ABBREV};
+use crate::oid::ObjectId;
+use crate::revwalk::{merge_bases, peel_to_commit, RevWalk};
+use crate::tree::Tree;
+use crate::{reflog, refs, GitRepo};
+
+/// Parsed revision argument
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Revision {
+    Single(ObjectId),
+    /// `A..B`: commits reachable from `to` but not from `from`
+    Range {
+        from: ObjectId,
+        to: ObjectId,
+    },
+    /// `A...B`: commits reachable from either side but not from both
+    Symmetric {
+        left: ObjectId,
+        right: ObjectId,
+        bases: Vec<ObjectId>,
+    },
+}
+
+impl Revision {
+    /// Adds the revision to walk, hiding excluded commits
+    pub fn push_to(&self, walk: &mut RevWalk) -> Result<()> {
+        match self {
+            Self::Single(id) => walk.push(id),
+            Self::Range { from, to } => {
+                walk.push(to)?;
+                walk.hide(from)
+            }
+            Self::Symmetric { left, right, bases } => {
+                walk.push(left)?;
+                walk.push(right)?;
+                bases.iter().try_for_each(|base| walk.hide(base))
+            }
+        }
+    }
+
+    /// Object ids in `rev-parse` output format, excluded ones prefixed with `^`
+    pub fn to_lines(&self) -> Vec<String> {
+        match self {
+            Self::Single(id) => vec![id.to_hex()],
+            Self::Range { from, to } => vec![to.to_hex(), format!("^{}", from)],
+            Self::Symmetric { left, right, bases } => {
+                let mut lines = vec![right.to_hex(), left.to_hex()];
+                lines.extend(bases.iter().map(|base| format!("^{}", base)));
+                lines
+            }
+        }
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Resolves `ref@{n}`, the value of the ref before its n-th last update. Without the ref
+/// the current branch is used.
+fn resolve_reflog(repo: &GitRepo, name: &str, selector: &str) -> Result<ObjectId> {
+    let n: usize = selector
+        .parse()
+        .map_err(|_| format_err!("Reflog selector @{{{}}} is not supported", selector))?;
+    let full = match name {
+        "" => refs::head_ref(repo)?,
+        "HEAD" => String::from("HEAD"),
+        _ => refs::dwim(repo, name)?.ok_or(Error::RefNotFound(name.to_string()))?,
+    };
+    let entries = reflog::read(repo, &full)?;
+    ensure!(
+        n < entries.len(),
+        "log for '{}' only has {} entries",
+        if name.is_empty() { &full } else { name },
+        entries.len()
+    );
+    Ok(entries[entries.len() - 1 - n].new)
+}
+
+/// Resolves ref name or (abbreviated) object hash, optionally with reflog selector
+fn resolve_base(repo: &GitRepo, name: &str) -> Result<ObjectId> {
+    if let Some((name, rest)) = name.split_once("@{") {
+        let selector = rest
+            .strip_suffix('}')
+            .ok_or(format_err!("Invalid revision {:?}", name))?;
+        return resolve_reflog(repo, name, selector);
+    }
+    let name = if name == "@" { "HEAD" } else { name };
+    if name.len() == repo.hash_algo().hex_len() && is_hex(name) {
+        return name.parse();
+    }
+    if let Some(full) = refs::dwim(repo, name)? {
+        return refs::resolve(repo, &full)?
+            .ok_or(format_err!("{} does not point to any commit yet", full));
+    }
+    if name.len() >= MIN_ABBREV && is_hex(name) {
+        return Object::expand_prefix(repo, name);
+    }
+    Err(Error::RefNotFound(name.to_string()))
+}
+
+/// Peels object until reaching given kind. `None` peels tags only.
+fn peel(repo: &GitRepo, mut id: ObjectId, kind: Option<ObjectKind>) -> Result<ObjectId> {
+    loop {
+        let obj = Object::read(repo, &id)?;
+        if Some(obj.header.kind) == kind || (kind.is_none() && obj.header.kind != ObjectKind::Tag) {
+            return Ok(id);
+        }
+        id = match obj.header.kind {
+            ObjectKind::Tag => obj.tag_target()?,
+            ObjectKind::Commit if kind == Some(ObjectKind::Tree) => Commit::try_from(obj)?.tree_sha,
+            found => bail!(
+                "{} is a {:?}, cannot peel to {:?}",
+                id,
+                found,
+                kind.unwrap()
+            ),
+        };
+    }
+}
+
+/// Splits leading number of `~N`/`^N` suffix, defaulting to 1
+fn take_number(rest: &str) -> Result<(usize, &str)> {
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let n = match &rest[..end] {
+        "" => 1,
+        digits => digits.parse().context("Invalid revision number")?,
+    };
+    Ok((n, &rest[end..]))
+}
+
+/// Finds object at path inside tree
+fn lookup_path(repo: &GitRepo, mut id: ObjectId, path: &str) -> Result<ObjectId> {
+    for name in path.split('/').filter(|c| !c.is_empty()) {
+        let tree: Tree = Object::read(repo, &id)?
+            .try_into()
+            .with_context(|| format!("Path {:?} goes through non-tree object", path))?;
+        id = tree
+            .entries
+            .iter()
+            .find(|entry| entry.name == name.as_bytes())
+            .map(|entry| entry.reference)
+            .ok_or(format_err!("Path {:?} does not exist", path))?;
+    }
+    Ok(id)
+}
+
+/// Resolves single revision like `HEAD~2^2^{tree}` or `HEAD:src/main.rs` to object id
+pub fn resolve(repo: &GitRepo, spec: &str) -> Result<ObjectId> {
+    if let Some((rev, path)) = spec.split_once(':') {
+        ensure!(!rev.is_empty(), "Index lookup {:?} is not supported", spec);
+        let tree = peel(repo, resolve(repo, rev)?, Some(ObjectKind::Tree))?;
+        return lookup_path(repo, tree, path);
+    }
+    let base_end = spec.find(['~', '^']).unwrap_or(spec.len());
+    let (base, mut rest) = spec.split_at(base_end);
+    ensure!(!base.is_empty(), "Invalid revision {:?}", spec);
+    let mut id = resolve_base(repo, base)?;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('~') {
+            let (n, after) = take_number(after)?;
+            id = peel_to_commit(repo, &id)?;
+            for _ in 0..n {
+                id = Commit::read(repo, &id)?
+                    .parents
+                    .into_iter()
+                    .next()
+                    .ok_or(format_err!("Revision {:?} goes beyond root commit", spec))?;
+            }
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("^{") {
+            let end = after
+                .find('}')
+                .ok_or(format_err!("Invalid revision {:?}", spec))?;
+            id = match &after[..end] {
+                "" => peel(repo, id, None)?,
+                "object" => id,
+                kind => peel(repo, id, Some(kind.parse()?))?,
+            };
+            rest = &after[end + 1..];
+        } else if let Some(after) = rest.strip_prefix('^') {
+            let (n, after) = take_number(after)?;
+            id = peel_to_commit(repo, &id)?;
+            if n > 0 {
+                id = Commit::read(repo, &id)?
+                    .parents
+                    .into_iter()
+                    .nth(n - 1)
+                    .ok_or(format_err!("Commit has no parent {} in {:?}", n, spec))?;
+            }
+            rest = after;
+        } else {
+            bail!("Invalid revision {:?}", spec);
+        }
+    }
+    Ok(id)
+}
+
+/// Parses revision or range of revisions. Empty side of range means `HEAD`.
+pub fn parse(repo: &GitRepo, spec: &str) -> Result<Revision> {
+    let side = |s: &str| resolve(repo, if s.is_empty() { "HEAD" } else { s });
+    if let Some((left, right)) = spec.split_once("...") {
+        let (left, right) = (side(left)?, side(right)?);
+        let bases = merge_bases(repo, &left, &right)?;
+        return Ok(Revision::Symmetric { left, right, bases });
+    }
+    if let Some((from, to)) = spec.split_once("..") {
+        return Ok(Revision::Range {
+            from: side(from)?,
+            to: side(to)?,
+        });
+    }
+    Ok(Revision::Single(resolve(repo, spec)?))
+}
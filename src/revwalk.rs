@@ -0,0 +1,520 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+
+use crate::commit::Commit;
+use crate::diff::{self, Renames};
+use crate::error::{bail, Result};
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::pathname;
+use crate::tree::Tree;
+use crate::GitRepo;
+
+/// Follows tags until reaching a commit
+pub fn peel_to_commit(repo: &GitRepo, id: &ObjectId) -> Result<ObjectId> {
+    let mut id = *id;
+    loop {
+        let obj = Object::read(repo, &id)?;
+        match obj.header.kind {
+            ObjectKind::Commit => return Ok(id),
+            ObjectKind::Tag => id = obj.tag_target()?,
+            kind => bail!("{} is a {:?}, not a commit", id, kind),
+        }
+    }
+}
+
+/// Order in which the walk yields commits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sort {
+    /// Newest commit date first, parents may precede children when clocks are skewed
+    #[default]
+    Chronological,
+    /// Newest commit date first, but never show parent before all its children
+    Date,
+    /// Children before parents, keeping lines of history together
+    Topo,
+}
+
+/// Commit waiting in the queue, ordered by commit date and then by insertion order
+struct Pending {
+    time: i64,
+    generation: u32,
+    seq: u64,
+    id: ObjectId,
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.time
+            .cmp(&other.time)
+            .then(self.generation.cmp(&other.generation))
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+/// Walked commit with parents the walk continues to, and whether it changed the paths
+type Walked = (ObjectId, Commit, Vec<ObjectId>, bool);
+
+/// Iterator over commits reachable from pushed commits, visiting each commit once.
+/// Commits reachable from hidden commits are skipped. When limited to paths, only
+/// commits changing them are yielded.
+pub struct RevWalk<'a> {
+    repo: &'a GitRepo,
+    queue: BinaryHeap<Pending>,
+    /// Commits in the queue, read when they were enqueued
+    pending: HashMap<ObjectId, Commit>,
+    seen: HashSet<ObjectId>,
+    hidden: HashSet<ObjectId>,
+    seq: u64,
+    sort: Sort,
+    reverse: bool,
+    paths: Vec<Vec<u8>>,
+    follow: bool,
+    rewrite_parents: bool,
+    /// Result of walks which have to see the whole history before yielding anything
+    sorted: Option<VecDeque<(ObjectId, Commit)>>,
+}
+
+impl<'a> RevWalk<'a> {
+    pub fn new(repo: &'a GitRepo) -> Self {
+        Self {
+            repo,
+            queue: BinaryHeap::new(),
+            pending: HashMap::new(),
+            seen: HashSet::new(),
+            hidden: HashSet::new(),
+            seq: 0,
+            sort: Sort::default(),
+            reverse: false,
+            paths: Vec::new(),
+            follow: false,
+            rewrite_parents: false,
+            sorted: None,
+        }
+    }
+
+    pub fn sort(&mut self, sort: Sort) {
+        self.sort = sort;
+    }
+
+    /// Yields commits in reverse of the selected order
+    pub fn reverse(&mut self, reverse: bool) {
+        self.reverse = reverse;
+    }
+
+    /// Limits the walk to commits changing any of the paths. History is simplified: when
+    /// merge did not change the paths compared to one of its parents, only that parent
+    /// is followed, so side branches not touching the paths are not walked at all.
+    pub fn paths(&mut self, paths: Vec<Vec<u8>>) {
+        self.paths = paths
+            .into_iter()
+            .map(|path| pathname::trim_slash(&path).to_vec())
+            .collect();
+    }
+
+    /// Switches the single limiting path to its old name at commits which renamed it.
+    /// History is not simplified then.
+    pub fn follow(&mut self, follow: bool) {
+        self.follow = follow;
+    }
+
+    /// Replaces parents of yielded commits which are not yielded by the closest yielded
+    /// ancestors, so the commits form a graph of their own
+    pub fn rewrite_parents(&mut self, rewrite: bool) {
+        self.rewrite_parents = rewrite;
+    }
+
+    fn enqueue(&mut self, id: ObjectId) -> Result<()> {
+        let commit = Commit::read(self.repo, &id)?;
+        self.queue.push(Pending {
+            time: commit.committer.unix_time(),
+            generation: 0,
+            seq: self.seq,
+            id,
+        });
+        self.seq += 1;
+        self.pending.insert(id, commit);
+        Ok(())
+    }
+
+    /// Adds starting point of the walk. Tags are peeled to commits.
+    pub fn push(&mut self, id: &ObjectId) -> Result<()> {
+        let id = peel_to_commit(self.repo, id)?;
+        if self.seen.insert(id) {
+            self.enqueue(id)?;
+        }
+        Ok(())
+    }
+
+    /// Excludes given commit and all its ancestors from the walk
+    pub fn hide(&mut self, id: &ObjectId) -> Result<()> {
+        let mut stack = vec![peel_to_commit(self.repo, id)?];
+        while let Some(id) = stack.pop() {
+            if self.hidden.insert(id) {
+                stack.extend(Commit::read(self.repo, &id)?.parents);
+                self.seen.insert(id);
+            }
+        }
+        let hidden = &self.hidden;
+        self.queue.retain(|p| !hidden.contains(&p.id));
+        self.pending.retain(|id, _| !hidden.contains(id));
+        Ok(())
+    }
+
+    /// Entries at the limiting paths in the tree
+    fn path_entries(&self, tree: &ObjectId) -> Result<Vec<Option<(u32, ObjectId)>>> {
+        self.paths
+            .iter()
+            .map(|path| Tree::entry_at(self.repo, tree, path))
+            .collect()
+    }
+
+    /// Parents to continue the walk with and whether the commit changed the paths
+    fn simplify(&mut self, commit: &Commit) -> Result<(Vec<ObjectId>, bool)> {
+        if self.paths.is_empty() {
+            return Ok((commit.parents.clone(), true));
+        }
+        let entries = self.path_entries(&commit.tree_sha)?;
+        let mut parent_trees = Vec::new();
+        for parent in &commit.parents {
+            let tree = Commit::read(self.repo, parent)?.tree_sha;
+            if self.path_entries(&tree)? == entries {
+                // Renames make the path differ between lines of history, which are
+                // therefore all walked when following them
+                let parents = if self.follow {
+                    commit.parents.clone()
+                } else {
+                    vec![*parent]
+                };
+                return Ok((parents, false));
+            }
+            parent_trees.push(tree);
+        }
+        if let ([parent_tree], true) = (&parent_trees[..], self.follow) {
+            self.follow_rename(&commit.tree_sha, parent_tree)?;
+        }
+        // Root commit changes the paths only by adding them
+        let changed = !commit.parents.is_empty() || entries.iter().any(Option::is_some);
+        Ok((commit.parents.clone(), changed))
+    }
+
+    fn follow_rename(&mut self, tree: &ObjectId, parent_tree: &ObjectId) -> Result<()> {
+        let [path] = &self.paths[..] else {
+            return Ok(());
+        };
+        if Tree::entry_at(self.repo, parent_tree, path)?.is_some() {
+            return Ok(());
+        }
+        let changes = diff::changes(
+            &Tree::flatten(self.repo, parent_tree)?,
+            &Tree::flatten(self.repo, tree)?,
+        );
+        let changes = diff::detect_renames(self.repo, changes, &Renames::default(), false)?;
+        if let Some(rename) = changes
+            .into_iter()
+            .find(|change| change.path == *path)
+            .and_then(|change| change.rename)
+        {
+            self.paths = vec![rename.from];
+        }
+        Ok(())
+    }
+
+    fn next_walked(&mut self) -> Result<Option<Walked>> {
+        let Some(Pending { id, .. }) = self.queue.pop() else {
+            return Ok(None);
+        };
+        let commit = self.pending.remove(&id).unwrap();
+        let (parents, changed) = self.simplify(&commit)?;
+        for parent in &parents {
+            if self.seen.insert(*parent) {
+                self.enqueue(*parent)?;
+            }
+        }
+        Ok(Some((id, commit, parents, changed)))
+    }
+
+    fn next_chronological(&mut self) -> Result<Option<(ObjectId, Commit)>> {
+        while let Some((id, commit, _, changed)) = self.next_walked()? {
+            if changed {
+                return Ok(Some((id, commit)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Walks whole history and orders it according to sort mode. Commits not changing
+    /// the paths are sorted too, keeping order of their ancestors and descendants.
+    fn limit(&mut self) -> Result<VecDeque<(ObjectId, Commit)>> {
+        let mut commits = Vec::new();
+        let mut unchanged = HashMap::new();
+        while let Some((id, commit, parents, changed)) = self.next_walked()? {
+            if !changed {
+                unchanged.insert(id, parents);
+            }
+            commits.push((id, commit));
+        }
+        if self.sort != Sort::Chronological {
+            commits = topo_sort(commits, self.sort);
+        }
+        commits.retain(|(id, _)| !unchanged.contains_key(id));
+        if self.rewrite_parents {
+            for (_, commit) in &mut commits {
+                commit.parents = rewritten_parents(&commit.parents, &unchanged);
+            }
+        }
+        if self.reverse {
+            commits.reverse();
+        }
+        Ok(commits.into())
+    }
+
+    fn next_commit(&mut self) -> Result<Option<(ObjectId, Commit)>> {
+        if self.sort == Sort::Chronological && !self.reverse && !self.rewrite_parents {
+            return self.next_chronological();
+        }
+        if self.sorted.is_none() {
+            self.sorted = Some(self.limit()?);
+        }
+        Ok(self.sorted.as_mut().unwrap().pop_front())
+    }
+}
+
+/// Parents with commits not changing the paths replaced by their parents, recursively
+fn rewritten_parents(
+    parents: &[ObjectId],
+    unchanged: &HashMap<ObjectId, Vec<ObjectId>>,
+) -> Vec<ObjectId> {
+    let mut result: Vec<ObjectId> = Vec::new();
+    let mut stack: Vec<&ObjectId> = parents.iter().rev().collect();
+    let mut visited = HashSet::new();
+    while let Some(parent) = stack.pop() {
+        if !visited.insert(parent) {
+            continue;
+        }
+        match unchanged.get(parent) {
+            Some(grandparents) => stack.extend(grandparents.iter().rev()),
+            None => result.push(*parent),
+        }
+    }
+    result
+}
+
+/// Generation numbers of commits within the walked set: one more than the maximum of parents.
+/// Unlike commit dates these are always consistent with the graph.
+fn generations(commits: &[(ObjectId, Commit)], index: &HashMap<ObjectId, usize>) -> Vec<u32> {
+    let mut generations = vec![0u32; commits.len()];
+    for start in 0..commits.len() {
+        if generations[start] != 0 {
+            continue;
+        }
+        let mut stack = vec![start];
+        while let Some(&i) = stack.last() {
+            let parents: Vec<usize> = commits[i]
+                .1
+                .parents
+                .iter()
+                .filter_map(|p| index.get(p).copied())
+                .collect();
+            let missing: Vec<usize> = parents
+                .iter()
+                .copied()
+                .filter(|p| generations[*p] == 0)
+                .collect();
+            if missing.is_empty() {
+                generations[i] = 1 + parents.iter().map(|p| generations[*p]).max().unwrap_or(0);
+                stack.pop();
+            } else {
+                stack.extend(missing);
+            }
+        }
+    }
+    generations
+}
+
+/// Orders commits so that no parent is shown before all its children
+fn topo_sort(commits: Vec<(ObjectId, Commit)>, sort: Sort) -> Vec<(ObjectId, Commit)> {
+    let index: HashMap<ObjectId, usize> = commits
+        .iter()
+        .enumerate()
+        .map(|(i, (id, _))| (*id, i))
+        .collect();
+    let parents: Vec<Vec<usize>> = commits
+        .iter()
+        .map(|(_, c)| {
+            c.parents
+                .iter()
+                .filter_map(|p| index.get(p).copied())
+                .collect()
+        })
+        .collect();
+    let generations = generations(&commits, &index);
+    let mut children = vec![0usize; commits.len()];
+    for p in parents.iter().flatten() {
+        children[*p] += 1;
+    }
+
+    let mut order = Vec::with_capacity(commits.len());
+    match sort {
+        Sort::Topo => {
+            // Stack keeps following the most recently completed line before switching to others
+            let mut ready: Vec<usize> = (0..commits.len())
+                .rev()
+                .filter(|i| children[*i] == 0)
+                .collect();
+            while let Some(i) = ready.pop() {
+                order.push(i);
+                for p in &parents[i] {
+                    children[*p] -= 1;
+                    if children[*p] == 0 {
+                        ready.push(*p);
+                    }
+                }
+            }
+        }
+        Sort::Date | Sort::Chronological => {
+            let pending = |i: usize| Pending {
+                time: commits[i].1.committer.unix_time(),
+                generation: generations[i],
+                seq: i as u64,
+                id: ObjectId::ZERO,
+            };
+            let mut ready: BinaryHeap<Pending> = (0..commits.len())
+                .filter(|i| children[*i] == 0)
+                .map(pending)
+                .collect();
+            while let Some(Pending { seq, .. }) = ready.pop() {
+                let i = seq as usize;
+                order.push(i);
+                for p in &parents[i] {
+                    children[*p] -= 1;
+                    if children[*p] == 0 {
+                        ready.push(pending(*p));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut commits: Vec<Option<(ObjectId, Commit)>> = commits.into_iter().map(Some).collect();
+    order
+        .into_iter()
+        .map(|i| commits[i].take().unwrap())
+        .collect()
+}
+
+impl Iterator for RevWalk<'_> {
+    type Item = Result<(ObjectId, Commit)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_commit().transpose()
+    }
+}
+
+/// Checks whether `ancestor` is reachable from `descendant`. Non-commits are never ancestors.
+pub fn is_ancestor(repo: &GitRepo, ancestor: &ObjectId, descendant: &ObjectId) -> Result<bool> {
+    let (Ok(ancestor), Ok(descendant)) = (
+        peel_to_commit(repo, ancestor),
+        peel_to_commit(repo, descendant),
+    ) else {
+        return Ok(false);
+    };
+    let mut walk = RevWalk::new(repo);
+    walk.push(&descendant)?;
+    for item in walk {
+        if item?.0 == ancestor {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Best common ancestors of two commits, i.e. common ancestors which are not ancestors
+/// of other common ancestors
+pub fn merge_bases(repo: &GitRepo, a: &ObjectId, b: &ObjectId) -> Result<Vec<ObjectId>> {
+    let mut walk = RevWalk::new(repo);
+    walk.push(a)?;
+    let reachable_from_a: HashSet<ObjectId> = walk
+        .map(|item| item.map(|(id, _)| id))
+        .collect::<Result<_>>()?;
+
+    let mut walk = RevWalk::new(repo);
+    walk.push(b)?;
+    let mut common = Vec::new();
+    for item in walk {
+        let (id, commit) = item?;
+        if reachable_from_a.contains(&id) {
+            common.push((id, commit.parents));
+        }
+    }
+    // Every ancestor of common commit is common too, so it is parent of some common commit
+    let not_best: HashSet<&ObjectId> = common.iter().flat_map(|(_, parents)| parents).collect();
+    Ok(common
+        .iter()
+        .filter(|(id, _)| !not_best.contains(id))
+        .map(|(id, _)| *id)
+        .collect())
+}
+
+/// Trees and blobs reachable from the trees, each once with the path it was first seen
+/// at, in the order `rev-list --objects` lists them: every tree before its entries.
+/// Objects reachable from `excluded` trees are skipped.
+pub fn tree_objects(
+    repo: &GitRepo,
+    trees: &[ObjectId],
+    excluded: &[ObjectId],
+) -> Result<Vec<(ObjectId, Vec<u8>)>> {
+    let mut seen = HashSet::new();
+    let mut skipped = Vec::new();
+    for tree in excluded {
+        collect_tree(repo, tree, Vec::new(), &mut seen, &mut skipped)?;
+    }
+    let mut objects = Vec::new();
+    for tree in trees {
+        collect_tree(repo, tree, Vec::new(), &mut seen, &mut objects)?;
+    }
+    Ok(objects)
+}
+
+fn collect_tree(
+    repo: &GitRepo,
+    id: &ObjectId,
+    path: Vec<u8>,
+    seen: &mut HashSet<ObjectId>,
+    objects: &mut Vec<(ObjectId, Vec<u8>)>,
+) -> Result<()> {
+    if !seen.insert(*id) {
+        return Ok(());
+    }
+    let tree = Tree::try_from(Object::read(repo, id)?)?;
+    objects.push((*id, path.clone()));
+    for entry in tree.entries {
+        let entry_path = pathname::concat(&path, &entry.name);
+        let entry_id = entry.reference;
+        match entry.kind() {
+            ObjectKind::Tree => collect_tree(repo, &entry_id, entry_path, seen, objects)?,
+            // Gitlinks point to commits in other repositories
+            ObjectKind::Commit => {}
+            _ => {
+                if seen.insert(entry_id) {
+                    objects.push((entry_id, entry_path));
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,296 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::commit::{Author, Commit, Role};
+use crate::error::{bail, ensure, format_err, Context, Result};
+use crate::hooks::{self, HookName};
+use crate::index::Index;
+use crate::merge::{self, Conflict, Labels, TreeMerge};
+use crate::oid::ObjectId;
+use crate::pathname;
+use crate::revwalk::{merge_bases, peel_to_commit, RevWalk, Sort};
+use crate::tree::Tree;
+use crate::{refs, GitRepo};
+
+/// Outcome of applying single commit on top of HEAD
+pub enum Picked {
+    /// New commit created and HEAD moved to it
+    Committed(ObjectId),
+    /// Changes are already present in HEAD, nothing was committed
+    Empty,
+    /// Working tree and index contain conflicts to be resolved by the user
+    Conflicts(Vec<Conflict>),
+}
+
+/// First line of the commit message
+pub fn subject(commit: &Commit) -> &str {
+    commit.message.lines().next().unwrap_or_default()
+}
+
+/// Commit description used in messages, e.g. `1a2b3c4... Fix typo`
+pub fn describe(id: &ObjectId, commit: &Commit) -> String {
+    format!("{}... {}", &id.to_hex()[..7], subject(commit))
+}
+
+/// Applies changes introduced by the commit onto HEAD as three-way merge with the parent
+/// of the commit as base, and commits the result preserving the original author. The
+/// action names the operation in the reflog.
+pub fn pick(repo: &GitRepo, id: &ObjectId, action: &str) -> Result<Picked> {
+    let head =
+        refs::resolve(repo, "HEAD")?.ok_or(format_err!("Cannot cherry-pick onto unborn branch"))?;
+    let commit = Commit::read(repo, id)?;
+    ensure!(
+        commit.parents.len() <= 1,
+        "commit {} is a merge but no -m option was given.",
+        id
+    );
+    let base_tree = match commit.parents.first() {
+        Some(parent) => Some(Commit::read(repo, parent)?.tree_sha),
+        None => None,
+    };
+    let head_tree = Commit::read(repo, &head)?.tree_sha;
+    let theirs = format!("{} ({})", &id.to_hex()[..7], subject(&commit));
+    let labels = Labels {
+        ours: "HEAD",
+        theirs: &theirs,
+    };
+    let result = merge::merge_trees(
+        repo,
+        base_tree.as_ref(),
+        &head_tree,
+        &commit.tree_sha,
+        &labels,
+    )?;
+    merge::update_worktree(repo, &Tree::flatten(repo, &head_tree)?, &result)?;
+    if !result.conflicts.is_empty() {
+        return Ok(Picked::Conflicts(result.conflicts));
+    }
+    commit_picked(repo, &commit, &head, action)
+}
+
+/// Commits the index on top of `head` with author and message of the picked commit
+pub fn commit_picked(
+    repo: &GitRepo,
+    original: &Commit,
+    head: &ObjectId,
+    action: &str,
+) -> Result<Picked> {
+    let tree_sha = Index::read(repo)?.write_tree(repo)?;
+    if tree_sha == Commit::read(repo, head)?.tree_sha {
+        return Ok(Picked::Empty);
+    }
+    let commit = Commit {
+        tree_sha,
+        parents: vec![*head],
+        author: original.author.clone(),
+        committer: Author::from_env(repo, Role::Committer)?,
+        // Signature would not match, encoding still describes the message
+        extra_headers: original
+            .extra_headers
+            .iter()
+            .filter(|(key, _)| key == "encoding")
+            .cloned()
+            .collect(),
+        message: original.message.clone(),
+        raw: None,
+    };
+    let id = commit.write(repo)?;
+    let message = format!("{}: {}", action, subject(original));
+    refs::update(
+        repo,
+        &refs::head_ref(repo)?,
+        Some(head),
+        Some(&id),
+        &message,
+    )?;
+    hooks::run(repo, HookName::PostCommit, &[], &[])?;
+    Ok(Picked::Committed(id))
+}
+
+/// State of rebase in progress, stored in `.git/rebase-merge` between invocations
+pub struct Rebase {
+    /// Branch being rebased, or `HEAD` when rebasing detached HEAD
+    pub head_name: String,
+    pub onto: ObjectId,
+    pub orig_head: ObjectId,
+    /// Commits still to be picked, oldest first
+    pub todo: VecDeque<ObjectId>,
+    /// Commit which stopped the rebase with conflicts
+    pub stopped: Option<ObjectId>,
+}
+
+/// Outcome of replaying commits of rebase
+pub enum RebaseStatus {
+    Finished,
+    /// Conflicts when picking the commit, user has to resolve them and continue
+    Stopped(ObjectId, Vec<Conflict>),
+}
+
+impl Rebase {
+    fn dir(repo: &GitRepo) -> PathBuf {
+        repo.git_dir.join("rebase-merge")
+    }
+
+    pub fn in_progress(repo: &GitRepo) -> bool {
+        Self::dir(repo).is_dir()
+    }
+
+    /// Prepares rebase of current branch onto `upstream` and detaches HEAD at it.
+    /// Returns `None` when the branch already contains upstream.
+    pub fn start(repo: &GitRepo, upstream: &ObjectId) -> Result<Option<Self>> {
+        ensure!(
+            !Self::in_progress(repo),
+            "It seems that there is already a rebase-merge directory"
+        );
+        let orig_head =
+            refs::resolve(repo, "HEAD")?.ok_or(format_err!("Cannot rebase unborn branch"))?;
+        let onto = peel_to_commit(repo, upstream)?;
+        if merge_bases(repo, &orig_head, &onto)?.contains(&onto) {
+            return Ok(None);
+        }
+
+        // Commits unique to the branch, merges are dropped like git does by default
+        let mut walk = RevWalk::new(repo);
+        walk.sort(Sort::Topo);
+        walk.reverse(true);
+        walk.push(&orig_head)?;
+        walk.hide(&onto)?;
+        let mut todo = VecDeque::new();
+        for item in walk {
+            let (id, commit) = item?;
+            if commit.parents.len() <= 1 {
+                todo.push_back(id);
+            }
+        }
+
+        let rebase = Self {
+            head_name: refs::head_ref(repo)?,
+            onto,
+            orig_head,
+            todo,
+            stopped: None,
+        };
+        let current = Tree::flatten(repo, &Commit::read(repo, &orig_head)?.tree_sha)?;
+        let target = TreeMerge {
+            entries: Tree::flatten(repo, &Commit::read(repo, &onto)?.tree_sha)?,
+            conflicts: Vec::new(),
+        };
+        merge::update_worktree(repo, &current, &target)?;
+        fs::create_dir(Self::dir(repo)).context("Creating rebase state")?;
+        rebase.save(repo)?;
+        fs::write(repo.git_dir.join("ORIG_HEAD"), format!("{}\n", orig_head))?;
+        refs::detach_head(repo, &onto, &format!("rebase (start): checkout {}", onto))?;
+        Ok(Some(rebase))
+    }
+
+    pub fn load(repo: &GitRepo) -> Result<Self> {
+        ensure!(Self::in_progress(repo), "No rebase in progress?");
+        let dir = Self::dir(repo);
+        let read = |name: &str| -> Result<String> {
+            Ok(fs::read_to_string(dir.join(name))
+                .with_context(|| format!("Reading rebase state {}", name))?
+                .trim_end()
+                .to_string())
+        };
+        let todo = read("git-rebase-todo")?
+            .lines()
+            .filter_map(|line| line.strip_prefix("pick "))
+            .map(|rest| rest.split(' ').next().unwrap_or_default().parse())
+            .collect::<Result<_>>()?;
+        let stopped = if dir.join("stopped-sha").exists() {
+            Some(read("stopped-sha")?.parse()?)
+        } else {
+            None
+        };
+        Ok(Self {
+            head_name: read("head-name")?,
+            onto: read("onto")?.parse()?,
+            orig_head: read("orig-head")?.parse()?,
+            todo,
+            stopped,
+        })
+    }
+
+    fn save(&self, repo: &GitRepo) -> Result<()> {
+        let dir = Self::dir(repo);
+        fs::write(dir.join("head-name"), format!("{}\n", self.head_name))?;
+        fs::write(dir.join("onto"), format!("{}\n", self.onto))?;
+        fs::write(dir.join("orig-head"), format!("{}\n", self.orig_head))?;
+        let mut todo = String::new();
+        for id in &self.todo {
+            let commit = Commit::read(repo, id)?;
+            todo.push_str(&format!("pick {} {}\n", id, subject(&commit)));
+        }
+        fs::write(dir.join("git-rebase-todo"), todo)?;
+        match &self.stopped {
+            Some(id) => fs::write(dir.join("stopped-sha"), format!("{}\n", id))?,
+            None => {
+                if dir.join("stopped-sha").exists() {
+                    fs::remove_file(dir.join("stopped-sha"))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Picks remaining commits one by one, stopping at the first conflict
+    pub fn run(&mut self, repo: &GitRepo) -> Result<RebaseStatus> {
+        if let Some(id) = &self.stopped {
+            // Commit the conflict resolution prepared by the user
+            let head = refs::resolve(repo, "HEAD")?.ok_or(format_err!("HEAD is unborn"))?;
+            if let Some(path) = Index::read(repo)?.conflicts().first() {
+                bail!(
+                    "{}: needs merge, resolve conflicts first",
+                    pathname::display(path)
+                );
+            }
+            let commit = Commit::read(repo, id)?;
+            commit_picked(repo, &commit, &head, "rebase (continue)")?;
+            self.stopped = None;
+        }
+        while let Some(id) = self.todo.pop_front() {
+            if let Picked::Conflicts(conflicts) = pick(repo, &id, "rebase (pick)")? {
+                self.stopped = Some(id);
+                self.save(repo)?;
+                return Ok(RebaseStatus::Stopped(id, conflicts));
+            }
+        }
+        self.finish(repo)?;
+        Ok(RebaseStatus::Finished)
+    }
+
+    /// Moves the rebased branch to the new commits and checks it out again
+    fn finish(&self, repo: &GitRepo) -> Result<()> {
+        let head = refs::resolve(repo, "HEAD")?.ok_or(format_err!("HEAD is unborn"))?;
+        if self.head_name != "HEAD" {
+            let message = format!("rebase (finish): {} onto {}", self.head_name, self.onto);
+            refs::update(
+                repo,
+                &self.head_name,
+                Some(&self.orig_head),
+                Some(&head),
+                &message,
+            )?;
+            let message = format!("rebase (finish): returning to {}", self.head_name);
+            refs::set_symbolic(repo, "HEAD", &self.head_name, Some(&message))?;
+        }
+        fs::remove_dir_all(Self::dir(repo)).context("Removing rebase state")
+    }
+
+    /// Restores the branch and working tree to the state before the rebase
+    pub fn abort(&self, repo: &GitRepo) -> Result<()> {
+        let head = refs::resolve(repo, "HEAD")?.ok_or(format_err!("HEAD is unborn"))?;
+        let current = Tree::flatten(repo, &Commit::read(repo, &head)?.tree_sha)?;
+        let target = Tree::flatten(repo, &Commit::read(repo, &self.orig_head)?.tree_sha)?;
+        merge::reset_worktree(repo, &current, &target)?;
+        if self.head_name == "HEAD" {
+            let message = format!("rebase (abort): returning to {}", self.orig_head);
+            refs::detach_head(repo, &self.orig_head, &message)?;
+        } else {
+            let message = format!("rebase (abort): returning to {}", self.head_name);
+            refs::set_symbolic(repo, "HEAD", &self.head_name, Some(&message))?;
+        }
+        fs::remove_dir_all(Self::dir(repo)).context("Removing rebase state")
+    }
+}
@@ -0,0 +1,139 @@
+use std::io::{self, Write};
+
+use crate::error::Result;
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::pkt_line;
+use crate::{refs, GitRepo};
+
+pub mod http;
+mod receive_pack;
+mod upload_pack;
+
+pub use receive_pack::ReceivePack;
+pub use upload_pack::UploadPack;
+
+const AGENT: &str = concat!("agent=git-starter-rust/", env!("CARGO_PKG_VERSION"));
+
+/// Writer wrapping data into side-band channel 1 pkt-lines of at most `chunk_size`
+/// bytes, sent as soon as they are full
+struct Sideband<W: Write> {
+    out: W,
+    chunk_size: usize,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Sideband<W> {
+    fn new(out: W, chunk_size: usize) -> Self {
+        Self {
+            out,
+            chunk_size,
+            buf: Vec::with_capacity(chunk_size),
+        }
+    }
+
+    fn send(&mut self, len: usize) -> io::Result<()> {
+        let mut line = Vec::with_capacity(len + 5);
+        let mut chunk = vec![1];
+        chunk.extend(self.buf.drain(..len));
+        pkt_line::write_data(&mut line, &chunk);
+        self.out.write_all(&line)
+    }
+
+    /// Sends remaining data and the terminating flush
+    fn finish(mut self) -> io::Result<W> {
+        self.flush()?;
+        let mut line = Vec::new();
+        pkt_line::write_flush(&mut line);
+        self.out.write_all(&line)?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for Sideband<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend(data);
+        while self.buf.len() >= self.chunk_size {
+            self.send(self.chunk_size)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send(self.buf.len())?;
+        }
+        self.out.flush()
+    }
+}
+
+/// Follows annotated tags until non-tag object is reached
+fn peel(repo: &GitRepo, mut id: ObjectId) -> Result<Option<ObjectId>> {
+    let mut peeled = false;
+    loop {
+        let obj = Object::read(repo, &id)?;
+        if obj.header.kind != ObjectKind::Tag {
+            return Ok(peeled.then_some(id));
+        }
+        id = obj.tag_target()?;
+        peeled = true;
+    }
+}
+
+/// Refs advertised to clients, `HEAD` first when it resolves
+fn advertised_refs(repo: &GitRepo) -> Result<Vec<(String, ObjectId)>> {
+    let mut advertised = Vec::new();
+    if let Some(head) = refs::resolve(repo, "HEAD")? {
+        advertised.push((String::from("HEAD"), head));
+    }
+    advertised.extend(refs::list(repo)?);
+    Ok(advertised)
+}
+
+/// Writes ref advertisement as defined by protocol v0.
+/// The first line carries capabilities, empty repositories advertise `capabilities^{}`.
+fn advertise_refs(repo: &GitRepo, service: Option<&str>, capabilities: &[&str]) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if let Some(service) = service {
+        pkt_line::write_data(&mut buf, format!("# service={}\n", service).as_bytes());
+        pkt_line::write_flush(&mut buf);
+    }
+
+    let mut capabilities: Vec<String> = capabilities.iter().map(|c| c.to_string()).collect();
+    let advertised = advertised_refs(repo)?;
+    if advertised.first().is_some_and(|(name, _)| name == "HEAD") {
+        if let Some(refs::RefValue::Symbolic(target)) = refs::read(repo, "HEAD")? {
+            capabilities.push(format!("symref=HEAD:{}", target));
+        }
+    }
+    capabilities.push(format!("object-format={}", repo.hash_algo()));
+    capabilities.push(AGENT.to_string());
+    let capabilities = capabilities.join(" ");
+
+    if advertised.is_empty() {
+        pkt_line::write_data(
+            &mut buf,
+            format!(
+                "{} capabilities^{{}}\0{}\n",
+                repo.hash_algo().null_id(),
+                capabilities
+            )
+            .as_bytes(),
+        );
+    }
+    for (i, (name, id)) in advertised.into_iter().enumerate() {
+        let line = if i == 0 {
+            format!("{} {}\0{}\n", id, name, capabilities)
+        } else {
+            format!("{} {}\n", id, name)
+        };
+        pkt_line::write_data(&mut buf, line.as_bytes());
+        if name.starts_with("refs/tags/") {
+            if let Some(peeled) = peel(repo, id)? {
+                pkt_line::write_data(&mut buf, format!("{} {}^{{}}\n", peeled, name).as_bytes());
+            }
+        }
+    }
+    pkt_line::write_flush(&mut buf);
+    Ok(buf)
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use flate2::read::GzDecoder;
+
+use super::{ReceivePack, UploadPack};
+use crate::config::Config;
+use crate::error::{bail, ensure, format_err, Context, Result};
+use crate::GitRepo;
+
+/// Largest request body accepted, after decompression
+const MAX_BODY_LEN: u64 = 1 << 30;
+
+/// Socket read and write timeout, so that a stalled client doesn't block the server
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Response of the smart HTTP handler, independent of the HTTP server used
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Body,
+}
+
+/// Writes streamed response body
+pub type BodyWriter = Box<dyn FnOnce(&mut dyn Write) -> Result<()>>;
+
+pub enum Body {
+    Full(Vec<u8>),
+    /// Written to the client as it is produced, its length is not known upfront
+    Streamed(BodyWriter),
+}
+
+impl HttpResponse {
+    fn ok(content_type: &'static str, body: Vec<u8>) -> Self {
+        Self {
+            status: 200,
+            content_type,
+            body: Body::Full(body),
+        }
+    }
+
+    fn text(status: u16, text: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: Body::Full(text.as_bytes().to_vec()),
+        }
+    }
+}
+
+/// Handles smart HTTP request for repository. Repository is served at any path prefix,
+/// only the endpoint suffix (`/info/refs`, `/git-upload-pack`, `/git-receive-pack`) is considered.
+pub fn handle_request(
+    repo: &GitRepo,
+    method: &str,
+    path: &str,
+    query: &str,
+    body: &[u8],
+) -> HttpResponse {
+    let service = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("service="));
+    let service = match (method, path) {
+        ("GET", p) if p.ends_with("/info/refs") => service,
+        ("POST", p) if p.ends_with("/git-upload-pack") => Some("git-upload-pack"),
+        ("POST", p) if p.ends_with("/git-receive-pack") => Some("git-receive-pack"),
+        _ => None,
+    };
+    match service.map(|service| is_enabled(repo, service)) {
+        Some(Ok(false)) => return HttpResponse::text(403, "Service not enabled"),
+        Some(Err(e)) => return HttpResponse::text(500, &e.chain().to_string()),
+        _ => {}
+    }
+    let result = match (method, path) {
+        ("GET", p) if p.ends_with("/info/refs") => match service {
+            Some("git-upload-pack") => UploadPack::new(repo)
+                .advertise_refs(true)
+                .map(|b| HttpResponse::ok("application/x-git-upload-pack-advertisement", b)),
+            Some("git-receive-pack") => ReceivePack::new(repo)
+                .advertise_refs(true)
+                .map(|b| HttpResponse::ok("application/x-git-receive-pack-advertisement", b)),
+            _ => Ok(HttpResponse::text(403, "Unsupported service")),
+        },
+        ("POST", p) if p.ends_with("/git-upload-pack") => UploadPack::new(repo)
+            .upload_pack(body)
+            .map(|resp| HttpResponse {
+                status: 200,
+                content_type: "application/x-git-upload-pack-result",
+                body: Body::Streamed(Box::new(move |out| resp.write_to(out))),
+            }),
+        ("POST", p) if p.ends_with("/git-receive-pack") => ReceivePack::new(repo)
+            .receive_pack(body)
+            .map(|b| HttpResponse::ok("application/x-git-receive-pack-result", b)),
+        _ => Ok(HttpResponse::text(404, "Not found")),
+    };
+    result.unwrap_or_else(|e| HttpResponse::text(500, &e.chain().to_string()))
+}
+
+/// Whether the service is enabled by `http.uploadpack` or `http.receivepack`. Clients of
+/// this server are never authenticated, so pushing has to be enabled explicitly, as with
+/// anonymous clients of git-http-backend.
+fn is_enabled(repo: &GitRepo, service: &str) -> Result<bool> {
+    let config = Config::load(repo)?;
+    Ok(match service {
+        "git-receive-pack" => config.get_bool("http.receivepack")?.unwrap_or(false),
+        _ => config.get_bool("http.uploadpack")?.unwrap_or(true),
+    })
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Reads exactly `len` bytes without allocating them upfront
+fn read_len(reader: &mut impl Read, len: u64, body: &mut Vec<u8>) -> Result<()> {
+    let read = reader.take(len).read_to_end(body)?;
+    ensure!(read as u64 == len, "Truncated request body");
+    Ok(())
+}
+
+/// Reads chunked body, `None` when it is larger than [`MAX_BODY_LEN`]
+fn read_chunked(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let size = line.trim().split(';').next().unwrap_or_default();
+        let size = u64::from_str_radix(size, 16).context("Invalid chunk size")?;
+        if size == 0 {
+            // Skip trailers
+            loop {
+                line.clear();
+                reader.read_line(&mut line)?;
+                if line.trim().is_empty() {
+                    return Ok(Some(body));
+                }
+            }
+        }
+        if body.len() as u64 + size > MAX_BODY_LEN {
+            return Ok(None);
+        }
+        read_len(reader, size, &mut body)?;
+        let mut crlf = [0u8; 2];
+        reader.read_exact(&mut crlf)?;
+    }
+}
+
+/// Reads request body as given by the headers, `None` when it is larger than
+/// [`MAX_BODY_LEN`]
+fn read_body(
+    reader: &mut impl BufRead,
+    headers: &HashMap<String, String>,
+) -> Result<Option<Vec<u8>>> {
+    let mut body = Vec::new();
+    if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+        match read_chunked(reader)? {
+            Some(chunked) => body = chunked,
+            None => return Ok(None),
+        }
+    } else if let Some(len) = headers.get("content-length") {
+        let len: u64 = len.parse().context("Invalid Content-Length")?;
+        if len > MAX_BODY_LEN {
+            return Ok(None);
+        }
+        read_len(reader, len, &mut body)?;
+    }
+    if headers.get("content-encoding").map(String::as_str) == Some("gzip") {
+        let mut decoded = Vec::new();
+        GzDecoder::new(&*body)
+            .take(MAX_BODY_LEN + 1)
+            .read_to_end(&mut decoded)?;
+        if decoded.len() as u64 > MAX_BODY_LEN {
+            return Ok(None);
+        }
+        body = decoded;
+    }
+    Ok(Some(body))
+}
+
+/// Writer sending each write as one chunk of chunked transfer encoding
+struct ChunkedWriter<W: Write>(W);
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if !data.is_empty() {
+            write!(self.0, "{:x}\r\n", data.len())?;
+            self.0.write_all(data)?;
+            self.0.write_all(b"\r\n")?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn handle_connection(repo: &GitRepo, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => bail!("Invalid request line {:?}", request_line),
+    };
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(format_err!("Invalid header line {:?}", line))?;
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let resp = match read_body(&mut reader, &headers)? {
+        Some(body) => handle_request(repo, &method, path, query, &body),
+        None => HttpResponse::text(413, "Request body too large"),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n",
+        resp.status,
+        reason(resp.status),
+        resp.content_type,
+    )?;
+    match resp.body {
+        Body::Full(body) => {
+            write!(stream, "Content-Length: {}\r\n\r\n", body.len())?;
+            stream.write_all(&body)?;
+        }
+        Body::Streamed(write_body) => {
+            write!(stream, "Transfer-Encoding: chunked\r\n\r\n")?;
+            let mut chunked = BufWriter::with_capacity(64 * 1024, ChunkedWriter(&mut stream));
+            write_body(&mut chunked)?;
+            chunked.flush()?;
+            drop(chunked);
+            stream.write_all(b"0\r\n\r\n")?;
+        }
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// Minimal blocking HTTP server serving the repository over smart HTTP protocol
+pub fn serve(repo: &GitRepo, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("Binding {}", addr))?;
+    for stream in listener.incoming() {
+        let result = stream
+            .context("Accepting connection")
+            .and_then(|stream| handle_connection(repo, stream));
+        if let Err(e) = result {
+            repo.progress()
+                .warning(&format!("Failed to handle request: {}", e.chain()));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,222 @@
+use std::collections::HashSet;
+use std::io::Write;
+
+use super::{advertise_refs, Sideband};
+use crate::error::{bail, ensure, format_err, Error, Result};
+use crate::fsck;
+use crate::hash::HashAlgo;
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::pack::index::{index_pack, IndexedPack};
+use crate::pkt_line::{self, MAX_PKT_LEN};
+use crate::refs::{self, RefTransaction};
+use crate::GitRepo;
+
+const CAPABILITIES: &[&str] = &[
+    "report-status",
+    "delete-refs",
+    "side-band-64k",
+    "quiet",
+    "ofs-delta",
+    "atomic",
+];
+
+/// Server side of `git-receive-pack` (protocol v0 as used by smart HTTP)
+pub struct ReceivePack<'a> {
+    repo: &'a GitRepo,
+}
+
+struct Command {
+    old: Option<ObjectId>,
+    new: Option<ObjectId>,
+    name: String,
+}
+
+impl Command {
+    /// Parses command with ids of the algorithm, zero id standing for missing side
+    fn parse(line: &str, algo: HashAlgo) -> Result<Self> {
+        let mut parts = line.splitn(3, ' ');
+        let mut id = || -> Result<Option<ObjectId>> {
+            let id = parts
+                .next()
+                .ok_or(Error::protocol(format!("Invalid command {:?}", line)))?;
+            let id = ObjectId::from_hex(id)
+                .ok()
+                .filter(|id| id.algo() == algo)
+                .ok_or(Error::protocol(format!(
+                    "Invalid object id in command {:?}",
+                    line
+                )))?;
+            Ok((!id.is_zero()).then_some(id))
+        };
+        let old = id()?;
+        let new = id()?;
+        let name = parts
+            .next()
+            .ok_or(Error::protocol(format!("Invalid command {:?}", line)))?
+            .to_string();
+        Ok(Self { old, new, name })
+    }
+}
+
+impl<'a> ReceivePack<'a> {
+    pub fn new(repo: &'a GitRepo) -> Self {
+        Self { repo }
+    }
+
+    /// Ref advertisement. With `http` set, the smart HTTP service header is prepended.
+    pub fn advertise_refs(&self, http: bool) -> Result<Vec<u8>> {
+        advertise_refs(self.repo, http.then_some("git-receive-pack"), CAPABILITIES)
+    }
+
+    /// Verifies all objects reachable from `tip` are present. Objects which were in the
+    /// repository before the push are assumed to be connected already.
+    fn check_connectivity(&self, tip: &ObjectId, pack: Option<&IndexedPack>) -> Result<()> {
+        let mut seen = HashSet::new();
+        let mut stack = vec![*tip];
+        while let Some(id) = stack.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            if pack.is_some_and(|p| p.objects.contains(&id)) {
+                stack.extend(Object::read(self.repo, &id)?.links()?);
+            } else {
+                ensure!(Object::exists(self.repo, &id), "missing object {}", id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the command can be applied, refs are not touched
+    fn check(&self, cmd: &Command, pack: Option<&IndexedPack>) -> Result<()> {
+        ensure!(
+            cmd.name.starts_with("refs/") && refs::is_valid_name(&cmd.name),
+            "funny refname"
+        );
+        // Bare repositories have no working tree to get out of sync
+        if let Some(refs::RefValue::Symbolic(head)) = refs::read(self.repo, "HEAD")? {
+            ensure!(
+                self.repo.is_bare || head != cmd.name,
+                "branch is currently checked out"
+            );
+        }
+        if let Some(new) = &cmd.new {
+            self.check_connectivity(new, pack)?;
+            if cmd.name.starts_with("refs/heads/") {
+                let kind = Object::read(self.repo, new)?.header.kind;
+                ensure!(kind == ObjectKind::Commit, "branch must point to commit");
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self, cmd: &Command, pack: Option<&IndexedPack>) -> Result<()> {
+        self.check(cmd, pack)?;
+        refs::update(
+            self.repo,
+            &cmd.name,
+            cmd.old.as_ref(),
+            cmd.new.as_ref(),
+            "push",
+        )
+    }
+
+    /// Applies all commands in single transaction, the first failure rejects all of them
+    fn apply_atomic(&self, commands: &[Command], pack: Option<&IndexedPack>) -> Vec<Result<()>> {
+        let mut transaction = RefTransaction::new();
+        for (i, cmd) in commands.iter().enumerate() {
+            if let Err(err) = self.check(cmd, pack) {
+                return Self::atomic_failure(commands.len(), i, err);
+            }
+            transaction.update(&cmd.name, cmd.old.as_ref(), cmd.new.as_ref(), "push");
+        }
+        match transaction.commit(self.repo) {
+            Ok(()) => commands.iter().map(|_| Ok(())).collect(),
+            Err(err) => commands
+                .iter()
+                .map(|_| Err(format_err!("{}", err.chain())))
+                .collect(),
+        }
+    }
+
+    /// Results of atomic push where the command at index `failed` failed with the error
+    fn atomic_failure(count: usize, failed: usize, err: Error) -> Vec<Result<()>> {
+        let mut results: Vec<Result<()>> = (0..count)
+            .map(|_| Err(format_err!("atomic push failure")))
+            .collect();
+        results[failed] = Err(err);
+        results
+    }
+
+    /// Handles receive-pack request body, returning the response body
+    pub fn receive_pack(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let (lines, pack_data) = pkt_line::parse(request)?;
+        let mut commands = Vec::new();
+        let mut capabilities = Vec::new();
+        for line in lines.iter().filter_map(|l| l.text()) {
+            let line = std::str::from_utf8(line)?;
+            let line = match line.split_once('\0') {
+                Some((line, caps)) => {
+                    capabilities = caps.split(' ').map(String::from).collect();
+                    line
+                }
+                None => line,
+            };
+            if line.starts_with("shallow ") {
+                bail!(Error::protocol(
+                    "Pushing from shallow repositories is not supported"
+                ));
+            }
+            commands.push(Command::parse(line, self.repo.hash_algo())?);
+        }
+        ensure!(
+            !commands.is_empty(),
+            Error::protocol("No commands in receive-pack request")
+        );
+
+        let unpacked = if pack_data.is_empty() {
+            Ok(None)
+        } else {
+            let fsck = fsck::transfer_enabled(self.repo, "receive")?;
+            index_pack(self.repo, pack_data, fsck).map(Some)
+        };
+
+        let mut report = Vec::new();
+        match &unpacked {
+            Ok(_) => pkt_line::write_data(&mut report, b"unpack ok\n"),
+            Err(e) => {
+                pkt_line::write_data(&mut report, format!("unpack {}\n", e.chain()).as_bytes())
+            }
+        }
+        let atomic = capabilities.iter().any(|c| c == "atomic");
+        let results: Vec<Result<()>> = match &unpacked {
+            Ok(pack) if atomic => self.apply_atomic(&commands, pack.as_ref()),
+            Ok(pack) => commands
+                .iter()
+                .map(|cmd| self.apply(cmd, pack.as_ref()))
+                .collect(),
+            Err(_) => commands
+                .iter()
+                .map(|_| Err(format_err!("unpacker error")))
+                .collect(),
+        };
+        for (cmd, result) in commands.iter().zip(results) {
+            let line = match result {
+                Ok(()) => format!("ok {}\n", cmd.name),
+                Err(e) => format!("ng {} {}\n", cmd.name, e.chain()),
+            };
+            pkt_line::write_data(&mut report, line.as_bytes());
+        }
+        pkt_line::write_flush(&mut report);
+
+        if !capabilities.iter().any(|c| c == "report-status") {
+            return Ok(Vec::new());
+        }
+        if !capabilities.iter().any(|c| c == "side-band-64k") {
+            return Ok(report);
+        }
+        let mut sideband = Sideband::new(Vec::new(), MAX_PKT_LEN - 5);
+        sideband.write_all(&report)?;
+        Ok(sideband.finish()?)
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+use std::io::Write;
+
+use flate2::Compression;
+
+use super::{advertise_refs, advertised_refs, peel, Sideband};
+use crate::commit::Commit;
+use crate::error::{bail, ensure, Context, Error, Result};
+use crate::object::Object;
+use crate::oid::ObjectId;
+use crate::pkt_line::{self, MAX_PKT_LEN};
+use crate::{pack, revwalk, GitRepo};
+
+const CAPABILITIES: &[&str] = &["side-band", "side-band-64k", "no-progress"];
+
+/// Server side of `git-upload-pack` (protocol v0 as used by smart HTTP)
+pub struct UploadPack<'a> {
+    repo: &'a GitRepo,
+}
+
+#[derive(Default)]
+struct Request {
+    wants: Vec<ObjectId>,
+    haves: Vec<ObjectId>,
+    capabilities: Vec<String>,
+    done: bool,
+}
+
+impl Request {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let (lines, rest) = pkt_line::parse(data)?;
+        ensure!(
+            rest.is_empty(),
+            Error::protocol("Unexpected pack data in upload-pack request")
+        );
+        let mut req = Self::default();
+        let parse_id = |id: &str| {
+            ObjectId::from_hex(id)
+                .map_err(|_| Error::protocol(format!("Invalid object id {:?}", id)))
+        };
+        for line in lines.iter().filter_map(|l| l.text()) {
+            let line = std::str::from_utf8(line)?;
+            let (cmd, arg) = line.split_once(' ').unwrap_or((line, ""));
+            match cmd {
+                "want" => {
+                    let mut parts = arg.split(' ');
+                    let id = parts.next().ok_or(Error::protocol("Missing want id"))?;
+                    if req.wants.is_empty() {
+                        req.capabilities = parts.map(String::from).collect();
+                    }
+                    req.wants.push(parse_id(id)?);
+                }
+                "have" => req.haves.push(parse_id(arg)?),
+                "done" => req.done = true,
+                _ => bail!(Error::protocol(format!(
+                    "Unsupported upload-pack command {:?}",
+                    cmd
+                ))),
+            }
+        }
+        Ok(req)
+    }
+}
+
+impl<'a> UploadPack<'a> {
+    pub fn new(repo: &'a GitRepo) -> Self {
+        Self { repo }
+    }
+
+    /// Ref advertisement. With `http` set, the smart HTTP service header is prepended.
+    pub fn advertise_refs(&self, http: bool) -> Result<Vec<u8>> {
+        advertise_refs(self.repo, http.then_some("git-upload-pack"), CAPABILITIES)
+    }
+
+    /// The first of `wants` which is neither an advertised ref (or its peeled value) nor
+    /// a commit reachable from one
+    fn not_our_ref<'w>(&self, wants: &'w [ObjectId]) -> Result<Option<&'w ObjectId>> {
+        let mut tips = HashSet::new();
+        for (_, id) in advertised_refs(self.repo)? {
+            if let Some(peeled) = peel(self.repo, id)? {
+                tips.insert(peeled);
+            }
+            tips.insert(id);
+        }
+        let mut pending: Vec<&ObjectId> = wants.iter().filter(|id| !tips.contains(*id)).collect();
+        let mut stack: Vec<ObjectId> = tips
+            .iter()
+            .filter_map(|id| revwalk::peel_to_commit(self.repo, id).ok())
+            .collect();
+        let mut seen = HashSet::new();
+        while !pending.is_empty() {
+            let Some(id) = stack.pop() else { break };
+            if !seen.insert(id) {
+                continue;
+            }
+            pending.retain(|want| **want != id);
+            stack.extend(Commit::read(self.repo, &id)?.parents);
+        }
+        Ok(pending.first().copied())
+    }
+
+    /// Handles upload-pack request body. The returned response writes the pack as it
+    /// is produced.
+    pub fn upload_pack(&self, request: &[u8]) -> Result<UploadPackResponse> {
+        let req = Request::parse(request)?;
+        ensure!(!req.wants.is_empty(), "No wants in upload-pack request");
+        let mut resp = UploadPackResponse {
+            head: Vec::new(),
+            objects: None,
+            sideband: None,
+            level: self.repo.write_options()?.pack_compression,
+            repo: self.repo.clone(),
+        };
+        if let Some(id) = self.not_our_ref(&req.wants)? {
+            let line = format!("ERR upload-pack: not our ref {}", id);
+            pkt_line::write_data(&mut resp.head, line.as_bytes());
+            return Ok(resp);
+        }
+
+        let common: Vec<ObjectId> = req
+            .haves
+            .iter()
+            .filter(|id| Object::exists(self.repo, id))
+            .copied()
+            .collect();
+        match common.first() {
+            Some(id) => pkt_line::write_data(&mut resp.head, format!("ACK {}\n", id).as_bytes()),
+            None => pkt_line::write_data(&mut resp.head, b"NAK\n"),
+        }
+        if !req.done {
+            return Ok(resp);
+        }
+
+        resp.objects = Some(pack::objects_to_send(self.repo, &req.wants, &common)?);
+        let has_cap = |cap: &str| req.capabilities.iter().any(|c| c == cap);
+        resp.sideband = if has_cap("side-band-64k") {
+            Some(MAX_PKT_LEN - 5)
+        } else if has_cap("side-band") {
+            Some(1000 - 5)
+        } else {
+            None
+        };
+        Ok(resp)
+    }
+}
+
+/// Negotiation lines followed by the pack, when the client is done
+pub struct UploadPackResponse {
+    head: Vec<u8>,
+    /// Ids of the objects to pack, read only while the pack is written
+    objects: Option<Vec<ObjectId>>,
+    /// Size of side-band chunks, raw pack is sent without side-band
+    sideband: Option<usize>,
+    level: Compression,
+    repo: GitRepo,
+}
+
+impl UploadPackResponse {
+    pub fn write_to(self, mut out: &mut dyn Write) -> Result<()> {
+        out.write_all(&self.head)
+            .context("Writing upload-pack response")?;
+        let Some(objects) = self.objects else {
+            return Ok(());
+        };
+        match self.sideband {
+            Some(chunk_size) => {
+                let mut sideband = Sideband::new(out, chunk_size);
+                pack::stream_pack(&self.repo, &objects, self.level, &mut sideband)?;
+                sideband.finish()?;
+            }
+            None => pack::stream_pack(&self.repo, &objects, self.level, &mut out)?,
+        }
+        Ok(())
+    }
+}
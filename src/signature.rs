@@ -0,0 +1,305 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::commit::{Author, Commit, Role};
+use crate::config::Config;
+use crate::error::{bail, ensure, format_err, Context, Result};
+use crate::hash::HashAlgo;
+use crate::GitRepo;
+
+/// Makes names of temporary signature files unique within the process
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Kind of signatures, `gpg.format` config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureFormat {
+    /// Made by `gpg`, the default
+    OpenPgp,
+    /// Made by `ssh-keygen -Y sign`
+    Ssh,
+    /// Made by `gpgsm`
+    X509,
+}
+
+impl SignatureFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::OpenPgp => "openpgp",
+            Self::Ssh => "ssh",
+            Self::X509 => "x509",
+        }
+    }
+
+    fn default_program(&self) -> &'static str {
+        match self {
+            Self::OpenPgp => "gpg",
+            Self::Ssh => "ssh-keygen",
+            Self::X509 => "gpgsm",
+        }
+    }
+
+    /// Format of the signature by its armor
+    fn detect(signature: &str) -> Option<Self> {
+        if signature.starts_with("-----BEGIN PGP SIGNATURE-----") {
+            Some(Self::OpenPgp)
+        } else if signature.starts_with("-----BEGIN SSH SIGNATURE-----") {
+            Some(Self::Ssh)
+        } else if signature.starts_with("-----BEGIN SIGNED MESSAGE-----") {
+            Some(Self::X509)
+        } else {
+            None
+        }
+    }
+
+    fn from_config(config: &Config) -> Result<Self> {
+        Ok(match config.get("gpg.format") {
+            None | Some("openpgp") => Self::OpenPgp,
+            Some("ssh") => Self::Ssh,
+            Some("x509") => Self::X509,
+            Some(format) => bail!("invalid value for 'gpg.format': '{}'", format),
+        })
+    }
+
+    /// Program of `gpg.<format>.program`, `gpg.program` is used for OpenPGP too
+    fn program(&self, config: &Config) -> PathBuf {
+        let key = format!("gpg.{}.program", self.as_str());
+        let program = config.get_path(&key);
+        let program = match self {
+            Self::OpenPgp => program.or_else(|| config.get_path("gpg.program")),
+            _ => program,
+        };
+        program.unwrap_or_else(|| PathBuf::from(self.default_program()))
+    }
+}
+
+/// Temporary file removed when dropped
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn new(content: &[u8]) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            ".git_signing_buffer_tmp_{}_{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, content).context("Writing temporary signing file")?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Runs the program with the data on its standard input
+fn run(program: &Path, args: &[&str], stdin: &[u8]) -> Result<Output> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Cannot run {}", program.display()))?;
+    let mut input = child
+        .stdin
+        .take()
+        .ok_or(format_err!("Signing program stdin not captured"))?;
+    // Feed the input from another thread so that the program cannot block on full output
+    let output = thread::scope(|scope| {
+        let writer = scope.spawn(move || input.write_all(stdin));
+        let output = child.wait_with_output();
+        let _ = writer.join();
+        output
+    })?;
+    Ok(output)
+}
+
+/// Signs the payload with `user.signingKey`, or with the configured committer identity
+/// as the OpenPGP key. Returns the armored signature.
+pub fn sign(repo: &GitRepo, payload: &[u8], key: Option<&str>) -> Result<String> {
+    let config = Config::load(repo)?;
+    let format = SignatureFormat::from_config(&config)?;
+    let program = format.program(&config);
+    let key = match key.or(config.get("user.signingKey")) {
+        Some(key) => key.to_string(),
+        None if format == SignatureFormat::Ssh => {
+            bail!("user.signingkey needs to be set for ssh signing")
+        }
+        None => {
+            let committer = Author::from_env(repo, Role::Committer)?;
+            format!("{} <{}>", committer.name, committer.email)
+        }
+    };
+    let signature = match format {
+        SignatureFormat::OpenPgp | SignatureFormat::X509 => {
+            let output = run(&program, &["--status-fd=2", "-bsau", &key], payload)?;
+            let status = String::from_utf8_lossy(&output.stderr);
+            ensure!(
+                output.status.success() && status.contains("[GNUPG:] SIG_CREATED "),
+                "gpg failed to sign the data:\n{}",
+                status.trim_end()
+            );
+            output.stdout
+        }
+        SignatureFormat::Ssh => {
+            // Public key itself is given with `key::` prefix, the private one is in agent
+            let literal = key
+                .strip_prefix("key::")
+                .or(key.starts_with("ssh-").then_some(key.as_str()));
+            let key_file = match literal {
+                Some(literal) => Some(TempFile::new(literal.as_bytes())?),
+                None => None,
+            };
+            let key_path = match &key_file {
+                Some(file) => file.path.clone(),
+                None => crate::config::expand_home(&key),
+            };
+            let buffer = TempFile::new(payload)?;
+            let mut args = vec!["-Y", "sign", "-n", "git", "-f"];
+            let key_path = key_path.to_string_lossy();
+            args.push(&key_path);
+            if key_file.is_some() {
+                args.push("-U");
+            }
+            let buffer_path = buffer.path.to_string_lossy();
+            args.push(&buffer_path);
+            let output = run(&program, &args, &[])?;
+            let sig_path = buffer.path.with_extension("sig");
+            let signature = fs::read(&sig_path);
+            let _ = fs::remove_file(&sig_path);
+            ensure!(
+                output.status.success(),
+                "Couldn't sign the data with ssh-keygen:\n{}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+            signature.context("Reading ssh signature")?
+        }
+    };
+    let signature = String::from_utf8(signature).context("Signature is not UTF-8")?;
+    ensure!(!signature.is_empty(), "gpg failed to sign the data");
+    Ok(signature)
+}
+
+/// Result of checking signature
+#[derive(Debug, Clone)]
+pub struct Verification {
+    /// Signature is valid and made by trusted key
+    pub good: bool,
+    /// Human-readable report of the verifying program
+    pub output: String,
+}
+
+/// Checks that the signature is valid for the payload. SSH signatures are checked
+/// against signers of `gpg.ssh.allowedSignersFile`.
+pub fn verify(repo: &GitRepo, payload: &[u8], signature: &str) -> Result<Verification> {
+    let config = Config::load(repo)?;
+    let Some(format) = SignatureFormat::detect(signature) else {
+        bail!("unknown signature format");
+    };
+    let program = format.program(&config);
+    let sig_file = TempFile::new(signature.as_bytes())?;
+    let sig_path = sig_file.path.to_string_lossy();
+    if format != SignatureFormat::Ssh {
+        let args = [
+            "--keyid-format=long",
+            "--status-fd=1",
+            "--verify",
+            &sig_path,
+            "-",
+        ];
+        let output = run(&program, &args, payload)?;
+        let status = String::from_utf8_lossy(&output.stdout);
+        let good = output.status.success() && status.contains("[GNUPG:] GOODSIG ");
+        return Ok(Verification {
+            good,
+            output: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+
+    let allowed = config
+        .get_path("gpg.ssh.allowedSignersFile")
+        .filter(|path| path.is_file());
+    let Some(allowed) = allowed else {
+        bail!("gpg.ssh.allowedSignersFile needs to be configured and exist for ssh signature verification");
+    };
+    let allowed = allowed.to_string_lossy();
+    let args = ["-Y", "find-principals", "-f", &allowed, "-s", &sig_path];
+    let principals = run(&program, &args, &[])?;
+    let principals = String::from_utf8_lossy(&principals.stdout).into_owned();
+    let mut output = String::new();
+    for principal in principals.lines() {
+        let args = [
+            "-Y", "verify", "-n", "git", "-f", &allowed, "-I", principal, "-s", &sig_path,
+        ];
+        let verified = run(&program, &args, payload)?;
+        output.push_str(&String::from_utf8_lossy(&verified.stdout));
+        output.push_str(&String::from_utf8_lossy(&verified.stderr));
+        if verified.status.success() {
+            return Ok(Verification { good: true, output });
+        }
+    }
+    // Valid signature by unknown key is still reported, but not trusted
+    let args = ["-Y", "check-novalidate", "-n", "git", "-s", &sig_path];
+    let checked = run(&program, &args, payload)?;
+    output.push_str(&String::from_utf8_lossy(&checked.stdout));
+    output.push_str(&String::from_utf8_lossy(&checked.stderr));
+    if checked.status.success() {
+        output.push_str("No principal matched.\n");
+    }
+    Ok(Verification {
+        good: false,
+        output,
+    })
+}
+
+/// Header holding the signature of commits in repositories with the hash algorithm
+fn commit_header(algo: HashAlgo) -> &'static str {
+    match algo {
+        HashAlgo::Sha1 => "gpgsig",
+        HashAlgo::Sha256 => "gpgsig-sha256",
+    }
+}
+
+/// Signs the commit, adding the signature header after all other headers
+pub fn sign_commit(repo: &GitRepo, commit: &mut Commit, key: Option<&str>) -> Result<()> {
+    let header = commit_header(repo.hash_algo());
+    commit.extra_headers.retain(|(k, _)| k != header);
+    let signature = sign(repo, &commit.to_bytes(), key)?;
+    let signature = signature.trim_end_matches('\n').to_string();
+    commit.extra_headers.push((header.to_string(), signature));
+    Ok(())
+}
+
+/// Payload the commit signature was made for, with the signature, `None` for unsigned
+/// commits
+pub fn commit_signature(repo: &GitRepo, commit: &Commit) -> Option<(Vec<u8>, String)> {
+    let (payload, signature) = commit.split_header(commit_header(repo.hash_algo()))?;
+    let mut signature = String::from_utf8_lossy(&signature).into_owned();
+    signature.push('\n');
+    Some((payload, signature))
+}
+
+/// Splits raw tag object content into the signed payload and the signature appended to
+/// the message, `None` for unsigned tags. The signature starts at the last line with
+/// signature armor.
+pub fn tag_signature(data: &[u8]) -> Option<(&[u8], &str)> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut start = None;
+    let mut pos = 0;
+    for line in text.split_inclusive('\n') {
+        if SignatureFormat::detect(line).is_some() {
+            start = Some(pos);
+        }
+        pos += line.len();
+    }
+    let start = start?;
+    Some((&data[..start], &text[start..]))
+}
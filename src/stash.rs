@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+
+use crate::attributes::Attributes;
+use crate::commit::Commit;
+use crate::error::{ensure, format_err, Context, Result};
+use crate::index::{self, Index, IndexEntry};
+use crate::merge::{self, Conflict, Labels};
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::sequencer::subject;
+use crate::tree::Tree;
+use crate::{reflog, refs, GitRepo};
+
+const STASH_REF: &str = "refs/stash";
+
+/// Stashed state listed in the stash reflog
+pub struct StashEntry {
+    pub id: ObjectId,
+    pub message: String,
+}
+
+/// Parses `stash@{N}` or plain `N`
+pub fn parse_selector(selector: &str) -> Result<usize> {
+    let n = selector
+        .strip_prefix("stash@{")
+        .and_then(|rest| rest.strip_suffix('}'))
+        .unwrap_or(selector);
+    n.parse()
+        .with_context(|| format!("{} is not a valid stash reference", selector))
+}
+
+/// Stash entries, the most recent first
+pub fn list(repo: &GitRepo) -> Result<Vec<StashEntry>> {
+    Ok(reflog::read(repo, STASH_REF)?
+        .into_iter()
+        .rev()
+        .map(|entry| StashEntry {
+            id: entry.new,
+            message: entry.message,
+        })
+        .collect())
+}
+
+fn get(repo: &GitRepo, n: usize) -> Result<StashEntry> {
+    list(repo)?
+        .into_iter()
+        .nth(n)
+        .ok_or(format_err!("stash@{{{}}} is not a valid reference", n))
+}
+
+/// Writes blob of the checked out file, `None` when it was deleted
+fn snapshot_file(
+    repo: &GitRepo,
+    path: &[u8],
+    index: &Index,
+    attributes: &mut Attributes,
+) -> Result<Option<(u32, ObjectId)>> {
+    let Some((mode, data)) = index::worktree_file_with(repo, path, index, attributes)? else {
+        return Ok(None);
+    };
+    let obj = Object::new(repo.hash_algo(), ObjectKind::Blob, data);
+    obj.write(repo)?;
+    Ok(Some((mode, obj.hash)))
+}
+
+/// Saves changes of tracked files in index and working tree under `refs/stash` and resets
+/// them back to HEAD. Returns the stash message, `None` when there is nothing to save.
+pub fn push(repo: &GitRepo, message: Option<&str>) -> Result<Option<String>> {
+    let head = refs::resolve(repo, "HEAD")?
+        .ok_or(format_err!("You do not have the initial commit yet"))?;
+    let head_commit = Commit::read(repo, &head)?;
+    let index = Index::read(repo)?;
+    let index_tree = index.write_tree(repo)?;
+
+    let mut worktree = Index::default();
+    let mut attributes = Attributes::load(repo)?;
+    for entry in &index.entries {
+        if let Some((mode, id)) = snapshot_file(repo, &entry.path, &index, &mut attributes)? {
+            worktree.add(IndexEntry::new(&entry.path, mode, &id, 0));
+        }
+    }
+    let worktree_tree = worktree.write_tree(repo)?;
+    if index_tree == head_commit.tree_sha && worktree_tree == head_commit.tree_sha {
+        return Ok(None);
+    }
+
+    let branch = refs::head_ref(repo)?;
+    let branch = branch.strip_prefix("refs/heads/").unwrap_or("(no branch)");
+    let description = format!(
+        "{}: {} {}",
+        branch,
+        &head.to_hex()[..7],
+        subject(&head_commit)
+    );
+    let index_commit = Commit::new(
+        repo,
+        index_tree,
+        vec![head],
+        format!("index on {}\n", description),
+    )?
+    .write(repo)?;
+    let message = match message {
+        Some(message) => format!("On {}: {}", branch, message),
+        None => format!("WIP on {}", description),
+    };
+    let stash = Commit::new(
+        repo,
+        worktree_tree,
+        vec![head, index_commit],
+        format!("{}\n", message),
+    )?
+    .write(repo)?;
+
+    let old = refs::resolve(repo, STASH_REF)?;
+    refs::update(repo, STASH_REF, old.as_ref(), Some(&stash), &message)?;
+
+    let head_tree = Tree::flatten(repo, &head_commit.tree_sha)?;
+    merge::reset_worktree(repo, &head_tree, &head_tree)?;
+    Ok(Some(message))
+}
+
+/// Applies stashed changes onto the current working tree as three-way merge with the
+/// commit the stash was created on. Returns conflicts left in the working tree.
+pub fn apply(repo: &GitRepo, n: usize) -> Result<Vec<Conflict>> {
+    let entry = get(repo, n)?;
+    let stash = Commit::read(repo, &entry.id)?;
+    let base = stash
+        .parents
+        .first()
+        .ok_or(format_err!("{} is not a stash commit", entry.id))?;
+    let head = refs::resolve(repo, "HEAD")?.ok_or(format_err!("HEAD is unborn"))?;
+    let head_tree = Tree::flatten(repo, &Commit::read(repo, &head)?.tree_sha)?;
+    let labels = Labels {
+        ours: "Updated upstream",
+        theirs: "Stashed changes",
+    };
+    let result = merge::merge_trees(
+        repo,
+        Some(&Commit::read(repo, base)?.tree_sha),
+        &Commit::read(repo, &head)?.tree_sha,
+        &stash.tree_sha,
+        &labels,
+    )?;
+    merge::update_worktree(repo, &head_tree, &result)?;
+    if !result.conflicts.is_empty() {
+        return Ok(result.conflicts);
+    }
+
+    // Like git, leave the changes unstaged except for new files
+    let mut index = Index::read(repo)?;
+    let merged: BTreeMap<Vec<u8>, (u32, ObjectId)> = index.to_map();
+    for (path, (mode, id)) in &head_tree {
+        if merged.get(path) != Some(&(*mode, *id)) {
+            index.add(IndexEntry::new(path, *mode, id, 0));
+        }
+    }
+    index.write(repo)?;
+    Ok(Vec::new())
+}
+
+/// Removes stash entry, returns its commit id
+pub fn drop(repo: &GitRepo, n: usize) -> Result<ObjectId> {
+    let mut entries = reflog::read(repo, STASH_REF)?;
+    ensure!(
+        n < entries.len(),
+        "stash@{{{}}} is not a valid reference",
+        n
+    );
+    let removed = entries.remove(entries.len() - 1 - n);
+    let current = refs::resolve(repo, STASH_REF)?;
+    match entries.last() {
+        Some(latest) => refs::update(repo, STASH_REF, current.as_ref(), Some(&latest.new), "")?,
+        None => refs::update(repo, STASH_REF, current.as_ref(), None, "")?,
+    }
+    reflog::write(repo, STASH_REF, &entries)?;
+    Ok(removed.new)
+}
@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use flate2::Compression;
+
+use crate::config::Config;
+use crate::error::{bail, ensure, Context, Result};
+use crate::GitRepo;
+
+/// Kind of repository files whose writes may be flushed to disk, `core.fsync` component
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncComponent {
+    LooseObject,
+    Pack,
+    /// Pack indexes
+    PackMetadata,
+    Reference,
+    Index,
+}
+
+impl FsyncComponent {
+    const ALL: [Self; 5] = [
+        Self::LooseObject,
+        Self::Pack,
+        Self::PackMetadata,
+        Self::Reference,
+        Self::Index,
+    ];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+
+    /// Components selected by name of single component or an aggregate
+    fn parse_set(name: &str) -> Result<u8> {
+        let bits = |components: &[Self]| components.iter().fold(0, |bits, c| bits | c.bit());
+        Ok(match name {
+            "none" => 0,
+            "loose-object" => bits(&[Self::LooseObject]),
+            "pack" => bits(&[Self::Pack]),
+            "pack-metadata" => bits(&[Self::PackMetadata]),
+            "reference" => bits(&[Self::Reference]),
+            "index" => bits(&[Self::Index]),
+            "objects" => bits(&[Self::LooseObject, Self::Pack, Self::PackMetadata]),
+            "committed" => bits(&[
+                Self::LooseObject,
+                Self::Pack,
+                Self::PackMetadata,
+                Self::Reference,
+            ]),
+            "added" | "all" => bits(&Self::ALL),
+            // Files we never write, accepted for compatibility
+            "commit-graph" | "derived-metadata" => 0,
+            _ => bail!("Unknown core.fsync component {:?}", name),
+        })
+    }
+}
+
+/// How objects, packs, refs and the index are written, from `core.fsync`,
+/// `core.compression`, `core.looseCompression` and `pack.compression`
+#[derive(Debug, Clone, Copy)]
+pub struct WriteOptions {
+    fsync: u8,
+    /// zlib level of loose objects
+    pub loose_compression: Compression,
+    /// zlib level of pack entries
+    pub pack_compression: Compression,
+}
+
+impl Default for WriteOptions {
+    /// Everything committed to the repository is durable. Loose objects use the fastest
+    /// compression while packs, which are kept for long, use the zlib default.
+    fn default() -> Self {
+        Self {
+            fsync: FsyncComponent::parse_set("committed").unwrap_or_default(),
+            loose_compression: Compression::fast(),
+            pack_compression: Compression::default(),
+        }
+    }
+}
+
+/// Parses zlib level, -1 is the zlib default
+fn compression(config: &Config, key: &str) -> Result<Option<Compression>> {
+    let Some(level) = config.get_int(key)? else {
+        return Ok(None);
+    };
+    ensure!(
+        (-1..=9).contains(&level),
+        "bad zlib compression level {}",
+        level
+    );
+    Ok(Some(match level {
+        -1 => Compression::default(),
+        level => Compression::new(level as u32),
+    }))
+}
+
+impl WriteOptions {
+    pub fn load(config: &Config) -> Result<Self> {
+        let mut options = Self::default();
+        if let Some(value) = config.get("core.fsync") {
+            // Listed components are added to the defaults, `-component` removes them
+            for item in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                match item.strip_prefix('-') {
+                    Some(name) => options.fsync &= !FsyncComponent::parse_set(name)?,
+                    None if item == "none" => options.fsync = 0,
+                    None => options.fsync |= FsyncComponent::parse_set(item)?,
+                }
+            }
+        }
+        if config.get_bool("core.fsyncObjectFiles")? == Some(true) {
+            options.fsync |= FsyncComponent::LooseObject.bit();
+        }
+        let core = compression(config, "core.compression")?;
+        if let Some(level) = compression(config, "core.looseCompression")?.or(core) {
+            options.loose_compression = level;
+        }
+        if let Some(level) = compression(config, "pack.compression")?.or(core) {
+            options.pack_compression = level;
+        }
+        Ok(options)
+    }
+
+    /// Whether writes of the component are flushed to disk
+    pub fn fsync(&self, component: FsyncComponent) -> bool {
+        self.fsync & component.bit() != 0
+    }
+
+    /// Flushes the file to disk when writes of the component are durable
+    pub fn sync(&self, file: &File, component: FsyncComponent) -> Result<()> {
+        if self.fsync(component) {
+            file.sync_all().context("Syncing file")?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the whole file, flushing it to disk when writes of the component are durable
+pub fn write_file(
+    repo: &GitRepo,
+    path: &Path,
+    data: &[u8],
+    component: FsyncComponent,
+) -> Result<()> {
+    let mut file = File::create(path).with_context(|| format!("Creating {}", path.display()))?;
+    file.write_all(data)
+        .with_context(|| format!("Writing {}", path.display()))?;
+    repo.write_options()?.sync(&file, component)
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+
+use crate::commit::Commit;
+use crate::config::{Config, ConfigFile, ConfigScope};
+use crate::error::{Context, Result};
+use crate::index::Index;
+use crate::oid::ObjectId;
+use crate::refs;
+use crate::tree::Tree;
+use crate::GitRepo;
+
+/// Mode of tree and index entries recording commit of a submodule
+pub const GITLINK_MODE: u32 = 0o160000;
+
+/// Submodule described in `.gitmodules`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submodule {
+    pub name: String,
+    /// Path relative to the repository root, defaults to the name
+    pub path: String,
+    pub url: Option<String>,
+    pub branch: Option<String>,
+}
+
+/// Submodules of `submodule.<name>.*` sections, in order of their first appearance
+pub fn parse(config: &Config) -> Vec<Submodule> {
+    let mut submodules: Vec<Submodule> = Vec::new();
+    for entry in config.entries() {
+        let Some(name) = entry.key.subsection.as_ref() else {
+            continue;
+        };
+        if entry.key.section != "submodule" {
+            continue;
+        }
+        let index = match submodules.iter().position(|s| s.name == *name) {
+            Some(index) => index,
+            None => {
+                submodules.push(Submodule {
+                    name: name.clone(),
+                    path: name.clone(),
+                    url: None,
+                    branch: None,
+                });
+                submodules.len() - 1
+            }
+        };
+        let submodule = &mut submodules[index];
+        let value = entry.value.clone().unwrap_or_default();
+        match entry.key.name.as_str() {
+            "path" => submodule.path = value.trim_end_matches('/').to_string(),
+            "url" => submodule.url = Some(value),
+            "branch" => submodule.branch = Some(value),
+            _ => {}
+        }
+    }
+    submodules
+}
+
+/// Submodules listed in `.gitmodules` of the working tree, none when it is missing
+pub fn list(repo: &GitRepo) -> Result<Vec<Submodule>> {
+    let config = Config::load_file(&repo.repo_root.join(".gitmodules"), ConfigScope::Local)
+        .context("Reading .gitmodules")?;
+    Ok(parse(&config))
+}
+
+/// Repository checked out at the path of the working tree, `None` when the submodule is
+/// not initialized. Both `.git` directory and `.git` file with `gitdir:` are recognized.
+pub fn open(repo: &GitRepo, path: &[u8]) -> Result<Option<GitRepo>> {
+    let root = repo.worktree_path(path);
+    let submodule = GitRepo::new(&root).with_progress(repo.progress.clone());
+    Ok(submodule.git_dir.is_dir().then_some(submodule))
+}
+
+/// Commit checked out in the submodule, `None` when it is not initialized or has no
+/// commits yet
+pub fn head(repo: &GitRepo, path: &[u8]) -> Result<Option<ObjectId>> {
+    match open(repo, path)? {
+        Some(submodule) => refs::resolve(&submodule, "HEAD"),
+        None => Ok(None),
+    }
+}
+
+/// Resolves URL of submodule starting with `./` or `../` against URL of the superproject
+pub fn resolve_url(base: &str, url: &str) -> String {
+    let mut base = base.trim_end_matches('/').to_string();
+    let mut rest = url;
+    loop {
+        if let Some(stripped) = rest.strip_prefix("./") {
+            rest = stripped;
+        } else if let Some(stripped) = rest.strip_prefix("../") {
+            rest = stripped;
+            match base.rfind('/') {
+                Some(i) => base.truncate(i),
+                None => base.clear(),
+            }
+        } else {
+            break;
+        }
+    }
+    if rest == url {
+        return url.to_string();
+    }
+    format!("{}/{}", base, rest)
+}
+
+/// Registers the submodule in `.git/config` with its URL resolved against the `origin`
+/// remote, or against the repository itself without it. Returns the URL when the
+/// submodule was not registered before.
+pub fn init(repo: &GitRepo, submodule: &Submodule) -> Result<Option<String>> {
+    let config = Config::load(repo)?;
+    let key = format!("submodule.{}.url", submodule.name);
+    if config.get(&key).is_some() {
+        return Ok(None);
+    }
+    let Some(url) = &submodule.url else {
+        return Ok(None);
+    };
+    let base = match config.get("remote.origin.url") {
+        Some(base) => base.to_string(),
+        None => repo.repo_root.to_string_lossy().into_owned(),
+    };
+    let url = resolve_url(&base, url);
+    let mut file = ConfigFile::open(&Config::local_path(repo))?;
+    file.set(&format!("submodule.{}.active", submodule.name), "true")?;
+    file.set(&key, &url)?;
+    file.save()?;
+    Ok(Some(url))
+}
+
+/// Commits of submodules recorded in the index, or in the HEAD tree when the index is
+/// missing, keyed by path
+pub fn recorded(repo: &GitRepo) -> Result<BTreeMap<Vec<u8>, ObjectId>> {
+    let index = Index::read(repo)?;
+    let entries = if index.entries.is_empty() {
+        match refs::resolve(repo, "HEAD")? {
+            Some(head) => Tree::flatten(repo, &Commit::read(repo, &head)?.tree_sha)?,
+            None => BTreeMap::new(),
+        }
+    } else {
+        index.to_map()
+    };
+    Ok(entries
+        .into_iter()
+        .filter(|(_, (mode, _))| *mode == GITLINK_MODE)
+        .map(|(path, (_, id))| (path, id))
+        .collect())
+}
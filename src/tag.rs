@@ -0,0 +1,94 @@
+use crate::commit::{Author, Role};
+use crate::error::{ensure, format_err, Context, Error, Result};
+use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::GitRepo;
+
+/// Annotated tag object
+#[derive(Debug, Clone)]
+pub struct Tag {
+    pub object: ObjectId,
+    pub kind: ObjectKind,
+    pub name: String,
+    /// Very old tags were created without tagger
+    pub tagger: Option<Author>,
+    /// Raw message including trailing newline and signature, if any
+    pub message: String,
+}
+
+impl TryFrom<Object> for Tag {
+    type Error = Error;
+
+    fn try_from(object: Object) -> Result<Self> {
+        ensure!(object.header.kind == ObjectKind::Tag, "Invalid object kind");
+        let data = &object.data;
+        let (header, message) = match data.windows(2).position(|w| w == b"\n\n") {
+            Some(split) => (&data[..split], &data[split + 2..]),
+            None => (&data[..], &[][..]),
+        };
+        let header = std::str::from_utf8(header).context("Parsing tag header")?;
+
+        let (mut target, mut kind, mut name, mut tagger) = (None, None, None, None);
+        for line in header.lines() {
+            let (key, value) = line
+                .split_once(' ')
+                .ok_or(format_err!("Invalid tag header line {:?}", line))?;
+            match key {
+                "object" => target = Some(value.parse()?),
+                "type" => kind = Some(value.parse()?),
+                "tag" => name = Some(value.to_string()),
+                "tagger" => tagger = Some(Author::parse(value)?),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            object: target.ok_or(format_err!("Tag doesn't contain object reference"))?,
+            kind: kind.ok_or(format_err!("Tag is missing object type"))?,
+            name: name.ok_or(format_err!("Tag is missing name"))?,
+            tagger,
+            message: String::from_utf8_lossy(message).into_owned(),
+        })
+    }
+}
+
+impl Tag {
+    /// New tag of the object tagged now by the configured committer identity
+    pub fn new(
+        repo: &GitRepo,
+        object: ObjectId,
+        kind: ObjectKind,
+        name: String,
+        message: String,
+    ) -> Result<Self> {
+        Ok(Self {
+            object,
+            kind,
+            name,
+            tagger: Some(Author::from_env(repo, Role::Committer)?),
+            message,
+        })
+    }
+
+    /// Writes the tag object and returns its id
+    pub fn write(self, repo: &GitRepo) -> Result<ObjectId> {
+        let obj = Object::new(repo.hash_algo(), ObjectKind::Tag, self.to_bytes());
+        obj.write(repo)?;
+        Ok(obj.hash)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(format!("object {}\n", self.object).as_bytes());
+        data.extend(format!("type {}\n", self.kind.as_str()).as_bytes());
+        data.extend(format!("tag {}\n", self.name).as_bytes());
+        if let Some(tagger) = &self.tagger {
+            data.extend(b"tagger ");
+            tagger.write_to_buf(&mut data);
+            data.push(b'\n');
+        }
+        data.push(b'\n');
+        data.extend(self.message.as_bytes());
+        data
+    }
+}
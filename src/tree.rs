@@ -1,16 +1,148 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::Path;
+use std::str::FromStr;
 
-use anyhow::{anyhow, bail, ensure, Context, Result};
-
+use crate::attributes::Attributes;
+use crate::error::{bail, ensure, Context, Error, Result};
+use crate::hash::HashAlgo;
+use crate::ignore::Ignore;
+use crate::index::Index;
 use crate::object::{Object, ObjectKind};
+use crate::oid::ObjectId;
+use crate::pathname;
+use crate::platform;
+use crate::submodule::{self, GITLINK_MODE};
 use crate::GitRepo;
 
+/// Path of directory relative to the repository root with trailing `/`, empty for the
+/// root itself, `None` outside of the working tree
+fn relative_dir(repo: &GitRepo, dir: &Path) -> Option<Vec<u8>> {
+    match dir.strip_prefix(&repo.repo_root) {
+        Ok(relative) if relative.as_os_str().is_empty() => Some(Vec::new()),
+        Ok(relative) => {
+            let mut dir = pathname::from_os(relative.as_os_str());
+            dir.push(b'/');
+            Some(dir)
+        }
+        Err(_) => None,
+    }
+}
+
+/// Mode of tree entry, telling the kind of the object it refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMode {
+    Regular,
+    Executable,
+    Symlink,
+    /// Commit of a submodule
+    Gitlink,
+    Tree,
+}
+
+impl FileMode {
+    /// Canonical octal form, as written to trees
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Regular => "100644",
+            Self::Executable => "100755",
+            Self::Symlink => "120000",
+            Self::Gitlink => "160000",
+            Self::Tree => "40000",
+        }
+    }
+
+    pub fn as_u32(self) -> u32 {
+        match self {
+            Self::Regular => 0o100644,
+            Self::Executable => 0o100755,
+            Self::Symlink => 0o120000,
+            Self::Gitlink => GITLINK_MODE,
+            Self::Tree => 0o40000,
+        }
+    }
+
+    /// Kind of the object entries with the mode refer to
+    pub fn kind(self) -> ObjectKind {
+        match self {
+            Self::Tree => ObjectKind::Tree,
+            Self::Gitlink => ObjectKind::Commit,
+            _ => ObjectKind::Blob,
+        }
+    }
+}
+
+impl TryFrom<u32> for FileMode {
+    type Error = Error;
+
+    /// Group-writable regular files of old git versions are regular files
+    fn try_from(mode: u32) -> Result<Self> {
+        Ok(match mode {
+            0o100644 | 0o100664 => Self::Regular,
+            0o100755 => Self::Executable,
+            0o120000 => Self::Symlink,
+            GITLINK_MODE => Self::Gitlink,
+            0o40000 => Self::Tree,
+            _ => bail!("Invalid filemode {:o}", mode),
+        })
+    }
+}
+
+impl FromStr for FileMode {
+    type Err = Error;
+
+    /// Zero-padded modes written by some old tools are accepted too
+    fn from_str(mode: &str) -> Result<Self> {
+        ensure!(
+            !mode.is_empty() && mode.len() <= 6 && mode.bytes().all(|b| (b'0'..=b'7').contains(&b)),
+            "Invalid filemode {:?}",
+            mode
+        );
+        Self::try_from(u32::from_str_radix(mode, 8)?)
+    }
+}
+
+impl fmt::Display for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
 pub struct TreeEntry {
-    pub mode: String,
-    pub name: String,
-    pub reference: Vec<u8>,
+    pub mode: FileMode,
+    /// Raw bytes of the name, not necessarily UTF-8
+    pub name: Vec<u8>,
+    pub reference: ObjectId,
+}
+
+impl TreeEntry {
+    /// Kind of the object the entry refers to, implied by its mode
+    pub fn kind(&self) -> ObjectKind {
+        self.mode.kind()
+    }
+
+    /// Whether the name is safe to check out: not empty, `.`, `..` or `.git` in any case,
+    /// and without `/` or NUL
+    pub fn is_valid_name(name: &[u8]) -> bool {
+        !(name.is_empty()
+            || name.contains(&b'/')
+            || name.contains(&b'\0')
+            || matches!(name, b"." | b"..")
+            || name.eq_ignore_ascii_case(b".git"))
+    }
+
+    /// Orders entries the way git sorts trees, by name bytes with names of subtrees
+    /// compared as if they ended with `/`
+    pub fn tree_order(&self, other: &TreeEntry) -> Ordering {
+        let suffix = |entry: &TreeEntry| (entry.mode == FileMode::Tree).then_some(b'/');
+        self.name
+            .iter()
+            .chain(&suffix(self))
+            .cmp(other.name.iter().chain(&suffix(other)))
+    }
 }
 
 pub struct Tree {
@@ -18,181 +150,463 @@ pub struct Tree {
 }
 
 impl TryFrom<Object> for Tree {
-    type Error = anyhow::Error;
+    type Error = Error;
 
+    /// Trees with duplicate names are corrupt, checkout of one would write through the
+    /// other, e.g. through a symlink into a directory of the same name
     fn try_from(object: Object) -> Result<Self> {
-        ensure!(
-            object.header.kind == ObjectKind::Tree,
-            "Invalid object kind"
-        );
         let mut entries = Vec::new();
-
-        let mut data = &*object.data;
-        loop {
-            let split = if let Some(pos) = data.iter().position(|c| *c == b'\0') {
-                pos
-            } else {
-                break;
-            };
-            let (mode, name) = std::str::from_utf8(&data[..split])
-                .context("Parsing entry header")?
-                .split_once(" ")
-                .ok_or(anyhow!("Invalid entry header"))?;
-            let reference = data[split + 1..split + 21].to_vec();
-            data = &data[split + 21..];
+        let mut names = HashSet::new();
+        for (mode, name, reference) in Tree::raw_entries(&object)? {
+            let mode = mode
+                .parse()
+                .map_err(|_| Error::corrupt(object.hash, format!("invalid mode {:?}", mode)))?;
+            ensure!(
+                names.insert(name.clone()),
+                Error::corrupt(object.hash, "contains duplicate file entries")
+            );
             entries.push(TreeEntry {
-                mode: mode.to_string(),
-                name: name.to_string(),
+                mode,
+                name,
                 reference,
             });
         }
-
         Ok(Self { entries })
     }
 }
 
 impl Tree {
+    /// Modes as written, names and ids of the tree entries, without validating the modes
+    pub fn raw_entries(object: &Object) -> Result<Vec<(String, Vec<u8>, ObjectId)>> {
+        ensure!(
+            object.header.kind == ObjectKind::Tree,
+            "Invalid object kind"
+        );
+        let mut entries = Vec::new();
+        // Entry ids are of the same algorithm as the tree id
+        let id_len = object.hash.algo().id_len();
+
+        let mut data = &*object.data;
+        while let Some(split) = data.iter().position(|c| *c == b'\0') {
+            let space = data[..split]
+                .iter()
+                .position(|c| *c == b' ')
+                .ok_or(Error::corrupt(object.hash, "invalid tree entry header"))?;
+            let mode = std::str::from_utf8(&data[..space]).context("Parsing entry header")?;
+            let name = data[space + 1..split].to_vec();
+            ensure!(
+                data.len() > split + id_len,
+                Error::corrupt(object.hash, "truncated tree entry")
+            );
+            let reference = ObjectId::from_slice(&data[split + 1..split + 1 + id_len])?;
+            data = &data[split + 1 + id_len..];
+            entries.push((mode.to_string(), name, reference));
+        }
+        Ok(entries)
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut data = Vec::new();
         for entry in &self.entries {
-            data.extend(entry.mode.as_bytes());
+            data.extend(entry.mode.as_str().as_bytes());
             data.push(b' ');
-            data.extend(entry.name.as_bytes());
+            data.extend(&entry.name);
             data.push(b'\0');
-            data.extend(&entry.reference);
+            data.extend(entry.reference.as_bytes());
         }
         data
     }
 
-    pub fn into_object(&self) -> Object {
-        Object::new(ObjectKind::Tree, self.to_bytes())
+    pub fn into_object(&self, algo: HashAlgo) -> Object {
+        Object::new(algo, ObjectKind::Tree, self.to_bytes())
     }
 
-    fn filemode(d: &fs::DirEntry) -> Result<String> {
-        Ok(if cfg!(unix) {
-            if 0o100 & std::os::unix::fs::PermissionsExt::mode(&d.metadata()?.permissions()) > 0 {
-                String::from("100755")
-            } else {
-                String::from("100644")
-            }
+    fn filemode(d: &fs::DirEntry) -> Result<FileMode> {
+        Ok(if platform::is_executable(&d.metadata()?) {
+            FileMode::Executable
         } else {
-            String::from("100644")
+            FileMode::Regular
         })
     }
 
-    fn set_permissions(file: &File, executable: bool) -> Result<()> {
-        if cfg!(unix) {
-            let metadata = file.metadata()?;
-            let mut permissions = metadata.permissions();
-            std::os::unix::fs::PermissionsExt::set_mode(
-                &mut permissions,
-                if executable { 0o755 } else { 0o644 },
-            );
-        } else {
-            // We ignore permissions on non-unix systems
+    /// Creates symlink to the target stored in blob. Without symlinks the link is a plain
+    /// file holding the target, as with `core.symlinks` off.
+    fn create_symlink(target: &[u8], link: &Path, symlinks: bool) -> Result<()> {
+        #[cfg(unix)]
+        if symlinks {
+            let target = pathname::to_os(target);
+            std::os::unix::fs::symlink(target, link)?;
+            return Ok(());
         }
+        fs::write(link, target)?;
         Ok(())
     }
 
-    fn create_symlink(original: &Path, link: &Path) -> Result<()> {
-        if cfg!(unix) {
-            std::os::unix::fs::symlink(original, link)?;
-        } else {
-            bail!("Symlink on non-unix platforms are not supported");
-        }
-        Ok(())
+    /// Target of the symlink as stored in its blob
+    pub fn read_symlink(link: &Path) -> Result<Vec<u8>> {
+        let target = fs::read_link(link)?;
+        Ok(pathname::from_os(target.as_os_str()))
     }
 
     pub fn checkout(&self, repo: &GitRepo, path: &Path) -> Result<()> {
+        let symlinks = platform::symlinks(repo)?;
+        self.checkout_with(repo, path, &mut Attributes::load(repo)?, symlinks)
+    }
+
+    fn checkout_with(
+        &self,
+        repo: &GitRepo,
+        path: &Path,
+        attributes: &mut Attributes,
+        symlinks: bool,
+    ) -> Result<()> {
+        let prefix = relative_dir(repo, path);
         for entry in &self.entries {
-            let subpath = path.join(&entry.name);
-            match entry.mode.as_str() {
-                "40000" => {
-                    // dir
-                    fs::create_dir(&subpath)?;
-                    let subtree: Tree =
-                        Object::read(repo, String::from_utf8(entry.reference.clone())?)?
-                            .try_into()?;
-                    subtree.checkout(repo, &subpath)?;
+            let relative = pathname::concat(prefix.as_deref().unwrap_or_default(), &entry.name);
+            ensure!(
+                TreeEntry::is_valid_name(&entry.name),
+                "invalid path '{}'",
+                pathname::display(&relative)
+            );
+            let subpath = pathname::join(path, &entry.name);
+            match entry.mode {
+                FileMode::Tree => {
+                    // dir, may exist already for trees differing only in case. Symlink
+                    // checked out under a name differing in case is never followed.
+                    match fs::symlink_metadata(&subpath) {
+                        Ok(metadata) if metadata.is_dir() => {}
+                        Ok(metadata) if metadata.is_symlink() => {
+                            bail!(
+                                "'{}/' is beyond a symbolic link",
+                                pathname::display(&relative)
+                            )
+                        }
+                        _ => fs::create_dir(&subpath)?,
+                    }
+                    let subtree: Tree = Object::read(repo, &entry.reference)?.try_into()?;
+                    subtree.checkout_with(repo, &subpath, attributes, symlinks)?;
                 }
-                "120000" => {
-                    // symlink
-                    Self::create_symlink(
-                        Path::new(std::str::from_utf8(&entry.reference)?),
-                        &subpath,
-                    )?;
+                FileMode::Symlink => {
+                    // symlink, the blob holds its target
+                    let target = Object::read(repo, &entry.reference)?;
+                    Self::create_symlink(&target.data, &subpath, symlinks)?;
                 }
-                "100644" | "100755" => {
+                FileMode::Gitlink => {
+                    // gitlink, the submodule is left uninitialized
+                    fs::create_dir(&subpath)?;
+                }
+                FileMode::Regular | FileMode::Executable => {
                     // file
-                    let mut file = File::create(subpath)?;
-                    Self::set_permissions(&file, entry.mode == "100755")?;
-                    let mut obj = Object::read(repo, String::from_utf8(entry.reference.clone())?)?;
-                    file.write_all(&mut obj.data)?;
+                    let mut file = Self::create_file(&subpath, entry.mode.as_u32())?;
+                    let path = prefix.as_ref().map(|p| pathname::concat(p, &entry.name));
+                    match path {
+                        Some(path) if !attributes.is_verbatim(&path)? => {
+                            let obj = Object::read(repo, &entry.reference)?;
+                            file.write_all(&attributes.to_worktree(&path, obj.data)?)?;
+                        }
+                        _ => {
+                            let mut reader = Object::reader(repo, &entry.reference)?;
+                            io::copy(&mut reader, &mut file)?;
+                        }
+                    }
                     file.flush()?;
+                    if entry.name == b".gitattributes" {
+                        if let Some(prefix) = &prefix {
+                            attributes.invalidate(prefix);
+                        }
+                    }
                 }
-                _ => {
-                    bail!("Unrecognized filemode {}", entry.mode)
-                }
             }
         }
         Ok(())
     }
 
+    /// Writes single blob from tree to the working tree, creating parent directories.
+    /// Existing file is replaced. Written `.gitattributes` file is reloaded by
+    /// `attributes` when they are needed next.
+    pub fn checkout_blob(
+        repo: &GitRepo,
+        path: &Path,
+        mode: u32,
+        id: &ObjectId,
+        attributes: &mut Attributes,
+    ) -> Result<()> {
+        if mode == GITLINK_MODE {
+            // Commit of submodule is not stored in this repository
+            return Self::checkout_data(repo, path, mode, &[]);
+        }
+        let relative = pathname::relative_to(path, &repo.repo_root);
+        match relative.as_deref() {
+            Some(relative) if mode != 0o120000 && attributes.is_verbatim(relative)? => {
+                Self::create_leading_dirs(repo, path)?;
+                let mut file = Self::create_file(path, mode)?;
+                io::copy(&mut Object::reader(repo, id)?, &mut file)?;
+                file.flush()?;
+            }
+            Some(relative) if mode != 0o120000 => {
+                let data = attributes.to_worktree(relative, Object::read(repo, id)?.data)?;
+                Self::checkout_data(repo, path, mode, &data)?;
+            }
+            _ => Self::checkout_data(repo, path, mode, &Object::read(repo, id)?.data)?,
+        }
+        if let Some(relative) = &relative {
+            attributes.invalidate_file(relative);
+        }
+        Ok(())
+    }
+
+    /// Whether a directory leading to the path in the working tree is a symlink, files
+    /// below it are wherever the link points
+    pub fn is_beyond_symlink(repo: &GitRepo, path: &[u8]) -> bool {
+        let Some((dir, _)) = pathname::split_last(path) else {
+            return false;
+        };
+        let mut full_path = repo.repo_root.clone();
+        pathname::components(dir).any(|name| {
+            full_path.push(pathname::to_os(name));
+            fs::symlink_metadata(&full_path).is_ok_and(|m| m.is_symlink())
+        })
+    }
+
+    /// Creates missing directories leading to the path. Within the working tree no
+    /// directory is entered through a symlink, which could have been checked out to point
+    /// anywhere.
+    fn create_leading_dirs(repo: &GitRepo, path: &Path) -> Result<()> {
+        let Ok(relative) = path.strip_prefix(&repo.repo_root) else {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            return Ok(());
+        };
+        let mut dir = repo.repo_root.clone();
+        let mut components = relative.components().peekable();
+        while let Some(component) = components.next() {
+            if components.peek().is_none() {
+                break;
+            }
+            dir.push(component);
+            match fs::symlink_metadata(&dir) {
+                Ok(metadata) if metadata.is_symlink() => bail!(
+                    "'{}' is beyond a symbolic link",
+                    pathname::display(&pathname::from_os(relative.as_os_str()))
+                ),
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => fs::create_dir(&dir)?,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces whatever is at the path with new empty regular file, the parent directory
+    /// has to exist
+    fn create_file(path: &Path, mode: u32) -> Result<File> {
+        if fs::symlink_metadata(path).is_ok_and(|m| !m.is_dir()) {
+            fs::remove_file(path)?;
+        }
+        let file = File::create(path)?;
+        platform::set_executable(&file, mode == 0o100755)?;
+        Ok(file)
+    }
+
+    /// Writes file of given mode and content to the working tree, creating parent
+    /// directories. Existing file is replaced. Gitlinks get an empty directory.
+    pub fn checkout_data(repo: &GitRepo, path: &Path, mode: u32, data: &[u8]) -> Result<()> {
+        Self::create_leading_dirs(repo, path)?;
+        if mode == GITLINK_MODE {
+            if !fs::symlink_metadata(path).is_ok_and(|m| m.is_dir()) {
+                fs::create_dir(path)?;
+            }
+            return Ok(());
+        }
+        if fs::symlink_metadata(path).is_ok() {
+            fs::remove_file(path)?;
+        }
+        match mode {
+            0o120000 => {
+                Self::create_symlink(data, path, platform::symlinks(repo)?)?;
+            }
+            0o100644 | 0o100755 => {
+                let mut file = Self::create_file(path, mode)?;
+                file.write_all(data)?;
+                file.flush()?;
+            }
+            _ => bail!("Unrecognized filemode {:o}", mode),
+        }
+        Ok(())
+    }
+
+    /// Mode and id of the entry at the path within the tree, the tree itself for empty
+    /// path. Only the trees along the path are read.
+    pub fn entry_at(repo: &GitRepo, id: &ObjectId, path: &[u8]) -> Result<Option<(u32, ObjectId)>> {
+        let mut entry = (0o40000, *id);
+        for name in pathname::components(path) {
+            if entry.0 != 0o40000 {
+                return Ok(None);
+            }
+            let tree: Tree = Object::read(repo, &entry.1)?.try_into()?;
+            let Some(found) = tree.entries.into_iter().find(|e| e.name == name) else {
+                return Ok(None);
+            };
+            entry = (found.mode.as_u32(), found.reference);
+        }
+        Ok(Some(entry))
+    }
+
+    /// All non-tree entries reachable from the tree, as path to (mode, id) map
+    pub fn flatten(repo: &GitRepo, id: &ObjectId) -> Result<BTreeMap<Vec<u8>, (u32, ObjectId)>> {
+        let mut result = BTreeMap::new();
+        let mut stack = vec![(Vec::new(), *id)];
+        while let Some((prefix, id)) = stack.pop() {
+            let tree: Tree = Object::read(repo, &id)?.try_into()?;
+            for entry in tree.entries {
+                let path = pathname::concat(&prefix, &entry.name);
+                // Such paths could write outside of the directory or into `.git`
+                ensure!(
+                    TreeEntry::is_valid_name(&entry.name),
+                    "invalid path '{}'",
+                    pathname::display(&path)
+                );
+                let id = entry.reference;
+                if entry.mode == FileMode::Tree {
+                    stack.push((path, id));
+                } else {
+                    result.insert(path, (entry.mode.as_u32(), id));
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Snapshots directory of the working tree. Ignored files are skipped unless they are
+    /// tracked in the index, directories without any files are left out. Directories with
+    /// another repository are recorded as gitlinks to its HEAD.
     pub fn create(repo: &GitRepo, root: &Path) -> Result<Self> {
+        let mut ignore = Ignore::load(repo)?;
+        let index = Index::read(repo)?;
+        let mut tracked = BTreeSet::new();
+        let mut gitlinks = BTreeMap::new();
+        for entry in &index.entries {
+            let path = entry.path.as_slice();
+            tracked.insert(path);
+            if entry.mode == GITLINK_MODE {
+                gitlinks.insert(path, entry.id);
+            }
+            for (i, b) in path.iter().enumerate() {
+                if *b == b'/' {
+                    tracked.insert(&path[..i]);
+                }
+            }
+        }
+        let mut attributes = Attributes::load(repo)?;
+        Self::create_filtered(
+            repo,
+            root,
+            &mut ignore,
+            &mut attributes,
+            &tracked,
+            &gitlinks,
+        )
+    }
+
+    fn create_filtered(
+        repo: &GitRepo,
+        root: &Path,
+        ignore: &mut Ignore,
+        attributes: &mut Attributes,
+        tracked: &BTreeSet<&[u8]>,
+        gitlinks: &BTreeMap<&[u8], ObjectId>,
+    ) -> Result<Self> {
         ensure!(root.is_dir(), "Path must be directory");
+        let prefix = relative_dir(repo, root);
         let mut entries = Vec::new();
         for item in fs::read_dir(root)? {
             let item = item?;
             let file_type = item.file_type()?;
-            let name = item
-                .file_name()
-                .into_string()
-                .map_err(|s| anyhow!("Cannot convert filename into str: {:?}", s))?;
-            if file_type.is_dir() {
-                if name == ".git" {
+            let name = pathname::from_os(&item.file_name());
+            // `.git` is a file in linked working trees and submodules
+            if name == b".git" {
+                continue;
+            }
+            if let Some(prefix) = &prefix {
+                let path = pathname::concat(prefix, &name);
+                if !tracked.contains(path.as_slice())
+                    && ignore.is_ignored(&path, file_type.is_dir())?
+                {
+                    continue;
+                }
+            }
+            let gitlink = match &prefix {
+                Some(prefix) if file_type.is_dir() => {
+                    let path = pathname::concat(prefix, &name);
+                    match submodule::head(repo, &path)? {
+                        Some(id) => Some(id),
+                        // Uninitialized submodule keeps the commit recorded in the index
+                        None => gitlinks.get(path.as_slice()).copied(),
+                    }
+                }
+                _ => None,
+            };
+            if let Some(id) = gitlink {
+                entries.push(TreeEntry {
+                    mode: FileMode::Gitlink,
+                    name,
+                    reference: id,
+                })
+            } else if file_type.is_dir() {
+                let subtree = Self::create_filtered(
+                    repo,
+                    &item.path(),
+                    ignore,
+                    attributes,
+                    tracked,
+                    gitlinks,
+                )?;
+                if subtree.entries.is_empty() {
                     continue;
                 }
-                let object = Self::write(repo, &item.path())?;
+                let object = subtree.into_object(repo.hash_algo());
+                object.write(repo)?;
                 entries.push(TreeEntry {
-                    mode: String::from("40000"),
+                    mode: FileMode::Tree,
                     name,
-                    reference: hex::decode(object.hash)?,
+                    reference: object.hash,
                 })
             } else if file_type.is_file() {
-                let object: Object = File::open(item.path())?.try_into()?;
+                let data = fs::read(item.path())?;
+                let data = match &prefix {
+                    Some(prefix) => attributes.to_repo(&pathname::concat(prefix, &name), data)?,
+                    None => data,
+                };
+                let object = Object::new(repo.hash_algo(), ObjectKind::Blob, data);
                 object.write(repo)?;
 
                 entries.push(TreeEntry {
                     mode: Self::filemode(&item)?,
                     name,
-                    reference: hex::decode(object.hash)?,
+                    reference: object.hash,
                 })
             } else if file_type.is_symlink() {
-                let reference = item
-                    .path()
-                    .read_link()?
-                    .as_os_str()
-                    .to_str()
-                    .ok_or(anyhow!("Failed to read link as str"))?
-                    .as_bytes()
-                    .to_vec();
+                let target = Self::read_symlink(&item.path())?;
+                let object = Object::new(repo.hash_algo(), ObjectKind::Blob, target);
+                object.write(repo)?;
                 entries.push(TreeEntry {
-                    mode: String::from("120000"),
+                    mode: FileMode::Symlink,
                     name,
-                    reference,
+                    reference: object.hash,
                 });
             } else {
                 bail!("Unsupported file type {:?}", file_type);
             }
         }
 
-        entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        entries.sort_unstable_by(TreeEntry::tree_order);
 
         Ok(Self { entries })
     }
 
     pub fn write(repo: &GitRepo, path: &Path) -> Result<Object> {
-        let obj = Self::create(repo, path)?.into_object();
+        let obj = Self::create(repo, path)?.into_object(repo.hash_algo());
         obj.write(repo)?;
         Ok(obj)
     }
@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::attributes::Attributes;
+use crate::commit::Commit;
+use crate::config::Config;
+use crate::error::{bail, ensure, format_err, Context, Result};
+use crate::index::Index;
+use crate::merge;
+use crate::oid::ObjectId;
+use crate::refs::{self, RefValue};
+use crate::revparse;
+use crate::revwalk::peel_to_commit;
+use crate::tree::Tree;
+use crate::GitRepo;
+
+/// Working tree of the repository, the main one or a linked one
+#[derive(Debug, Clone)]
+pub struct Worktree {
+    pub path: PathBuf,
+    /// Directory with HEAD and index of the working tree
+    pub git_dir: PathBuf,
+    /// Checked out commit, `None` for unborn branch
+    pub head: Option<ObjectId>,
+    /// Checked out branch, `None` for detached HEAD
+    pub branch: Option<String>,
+    /// Main working tree of bare repository, which has nothing checked out
+    pub bare: bool,
+}
+
+impl Worktree {
+    fn load(path: PathBuf, git_dir: PathBuf) -> Result<Self> {
+        let repo = GitRepo::with_git_dir(&path, &git_dir);
+        let branch = match refs::read(&repo, "HEAD")? {
+            Some(RefValue::Symbolic(target)) => Some(target),
+            _ => None,
+        };
+        Ok(Self {
+            head: refs::resolve(&repo, "HEAD")?,
+            path,
+            git_dir,
+            branch,
+            bare: false,
+        })
+    }
+
+    pub fn is_main(&self) -> bool {
+        self.bare || self.git_dir.parent() == Some(&self.path)
+    }
+}
+
+/// What the new working tree checks out
+pub enum Checkout {
+    /// Existing branch, which must not be checked out elsewhere
+    Branch(String),
+    /// New branch created at the commit
+    NewBranch(String, ObjectId),
+    /// Detached HEAD at the commit
+    Detached(ObjectId),
+}
+
+impl Checkout {
+    /// Resolves arguments of `worktree add`. Without any, new branch named after the
+    /// working tree directory is created at HEAD, or the branch checked out if it exists.
+    pub fn resolve(
+        repo: &GitRepo,
+        path: &Path,
+        new_branch: Option<String>,
+        detach: bool,
+        commit: Option<&str>,
+    ) -> Result<Self> {
+        let resolve_commit = |rev: &str| peel_to_commit(repo, &revparse::resolve(repo, rev)?);
+        let start = || resolve_commit(commit.unwrap_or("HEAD"));
+        if let Some(branch) = new_branch {
+            return Ok(Self::NewBranch(branch, start()?));
+        }
+        if detach {
+            return Ok(Self::Detached(start()?));
+        }
+        match commit {
+            Some(commit) => {
+                let branch = format!("refs/heads/{}", commit);
+                if refs::resolve(repo, &branch)?.is_some() {
+                    Ok(Self::Branch(branch))
+                } else {
+                    Ok(Self::Detached(resolve_commit(commit)?))
+                }
+            }
+            None => {
+                let name = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or(format_err!("Invalid worktree path {}", path.display()))?;
+                let branch = format!("refs/heads/{}", name);
+                if refs::resolve(repo, &branch)?.is_some() {
+                    Ok(Self::Branch(branch))
+                } else {
+                    Ok(Self::NewBranch(name.to_string(), start()?))
+                }
+            }
+        }
+    }
+}
+
+/// All working trees of the repository, the main one first
+pub fn list(repo: &GitRepo) -> Result<Vec<Worktree>> {
+    // Linked working trees of bare repository know it from the shared config only
+    let bare = Config::load(repo)?.get_bool("core.bare")? == Some(true);
+    let main = if bare {
+        Worktree {
+            path: repo.common_dir.clone(),
+            git_dir: repo.common_dir.clone(),
+            head: None,
+            branch: None,
+            bare,
+        }
+    } else {
+        let path = repo
+            .common_dir
+            .parent()
+            .ok_or(format_err!("Repository without working tree"))?;
+        Worktree::load(path.to_path_buf(), repo.common_dir.clone())?
+    };
+    let mut worktrees = vec![main];
+    let dir = repo.common_dir.join("worktrees");
+    if !dir.is_dir() {
+        return Ok(worktrees);
+    }
+    let mut names = fs::read_dir(&dir)?
+        .map(|item| Ok(item?.file_name()))
+        .collect::<Result<Vec<_>>>()?;
+    names.sort();
+    for name in names {
+        let git_dir = dir.join(name);
+        // `gitdir` holds path of the `.git` file in the working tree
+        let Ok(dot_git) = fs::read_to_string(git_dir.join("gitdir")) else {
+            continue;
+        };
+        let path = Path::new(dot_git.trim_end()).parent().ok_or(format_err!(
+            "Invalid gitdir of worktree {}",
+            git_dir.display()
+        ))?;
+        worktrees.push(Worktree::load(path.to_path_buf(), git_dir)?);
+    }
+    Ok(worktrees)
+}
+
+/// Working tree with the branch checked out
+pub fn find_branch(repo: &GitRepo, branch: &str) -> Result<Option<Worktree>> {
+    Ok(list(repo)?
+        .into_iter()
+        .find(|w| w.branch.as_deref() == Some(branch)))
+}
+
+/// Creates linked working tree at the path and checks out the commit there
+pub fn add(repo: &GitRepo, path: &Path, checkout: &Checkout) -> Result<GitRepo> {
+    let is_empty_dir = fs::read_dir(path).is_ok_and(|mut items| items.next().is_none());
+    ensure!(
+        !path.exists() || is_empty_dir,
+        "'{}' already exists",
+        path.display()
+    );
+    if let Checkout::Branch(branch) = checkout {
+        if let Some(worktree) = find_branch(repo, branch)? {
+            bail!(
+                "'{}' is already checked out at '{}'",
+                branch.strip_prefix("refs/heads/").unwrap_or(branch),
+                worktree.path.display()
+            );
+        }
+    }
+    if let Checkout::NewBranch(name, _) = checkout {
+        let branch = format!("refs/heads/{}", name);
+        ensure!(
+            refs::resolve(repo, &branch)?.is_none(),
+            "A branch named '{}' already exists.",
+            name
+        );
+    }
+
+    // Name of the administrative directory, made unique with number suffix
+    let base = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(format_err!("Invalid worktree path {}", path.display()))?;
+    let worktrees_dir = repo.common_dir.join("worktrees");
+    let mut name = base.to_string();
+    let mut counter = 0;
+    while worktrees_dir.join(&name).exists() {
+        counter += 1;
+        name = format!("{}{}", base, counter);
+    }
+
+    fs::create_dir_all(path).context("Creating worktree directory")?;
+    let path = fs::canonicalize(path)?;
+    let git_dir = fs::canonicalize(&repo.common_dir)?
+        .join("worktrees")
+        .join(&name);
+    fs::create_dir_all(&git_dir)?;
+    fs::write(git_dir.join("commondir"), "../..\n")?;
+    fs::write(
+        git_dir.join("gitdir"),
+        format!("{}\n", path.join(".git").display()),
+    )?;
+    fs::write(
+        path.join(".git"),
+        format!("gitdir: {}\n", git_dir.display()),
+    )?;
+
+    let worktree = GitRepo::new(&path).with_progress(repo.progress.clone());
+    let id = match checkout {
+        Checkout::Branch(branch) => {
+            refs::set_symbolic(&worktree, "HEAD", branch, None)?;
+            refs::resolve(repo, branch)?.ok_or(format_err!("Branch {} is unborn", branch))?
+        }
+        Checkout::NewBranch(branch, id) => {
+            let branch = format!("refs/heads/{}", branch);
+            let message = format!("branch: Created from {}", id);
+            refs::update(repo, &branch, None, Some(id), &message)?;
+            refs::set_symbolic(&worktree, "HEAD", &branch, None)?;
+            *id
+        }
+        Checkout::Detached(id) => {
+            refs::detach_head(&worktree, id, "")?;
+            *id
+        }
+    };
+    let target = Tree::flatten(&worktree, &Commit::read(&worktree, &id)?.tree_sha)?;
+    merge::reset_worktree(&worktree, &BTreeMap::new(), &target)?;
+    Ok(worktree)
+}
+
+/// Removes linked working tree together with its administrative files. Without `force`
+/// it must not contain any local modifications or untracked files.
+pub fn remove(repo: &GitRepo, path: &Path, force: bool) -> Result<()> {
+    let full_path = fs::canonicalize(path)
+        .with_context(|| format!("'{}' is not a working tree", path.display()))?;
+    let worktree = list(repo)?
+        .into_iter()
+        .find(|w| fs::canonicalize(&w.path).is_ok_and(|p| p == full_path))
+        .ok_or(format_err!("'{}' is not a working tree", path.display()))?;
+    ensure!(
+        !worktree.is_main(),
+        "'{}' is a main working tree",
+        path.display()
+    );
+    if !force {
+        let linked = GitRepo::new(&worktree.path);
+        let index = Index::read(&linked)?;
+        let mut dirty = !index.untracked(&linked)?.is_empty();
+        let mut attributes = Attributes::load(&linked)?;
+        for entry in &index.entries {
+            dirty = dirty || Index::is_modified(&linked, entry, &index, &mut attributes)?;
+        }
+        ensure!(
+            !dirty,
+            "'{}' contains modified or untracked files, use --force to delete it",
+            path.display()
+        );
+    }
+    fs::remove_dir_all(&worktree.path).context("Removing worktree directory")?;
+    fs::remove_dir_all(&worktree.git_dir).context("Removing worktree administrative files")?;
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_git-starter-rust"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+/// Freshly initialized repository in an empty temporary directory
+fn empty_repo(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("git-starter-rust-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    assert!(run(&dir, &["init"]).status.success());
+    dir
+}
+
+#[test]
+fn patches_outside_of_the_working_tree_are_refused() {
+    let dir = empty_repo("apply-invalid-path");
+    let outside = format!(
+        "../{}-outside/x",
+        dir.file_name().unwrap().to_str().unwrap()
+    );
+    let git_patch = format!(
+        "diff --git a/{0} b/{0}\n\
+         new file mode 100644\n\
+         --- /dev/null\n\
+         +++ b/{0}\n\
+         @@ -0,0 +1 @@\n\
+         +x\n",
+        outside
+    );
+    let plain_patch = format!(
+        "--- /dev/null\n\
+         +++ b/{}\n\
+         @@ -0,0 +1 @@\n\
+         +x\n",
+        outside
+    );
+    for (patch, args) in [
+        (&git_patch, &["apply", "x.patch"][..]),
+        (&git_patch, &["apply", "--index", "x.patch"]),
+        (&plain_patch, &["apply", "x.patch"]),
+        (&plain_patch, &["apply", "--check", "x.patch"]),
+    ] {
+        fs::write(dir.join("x.patch"), patch).unwrap();
+
+        let output = run(&dir, args);
+
+        assert!(!output.status.success(), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains(&format!("invalid path '{}'", outside)),
+            "{}",
+            stderr
+        );
+    }
+    assert!(!dir.join(&outside).exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn patches_are_not_written_through_symlinks() {
+    let dir = empty_repo("apply-symlink");
+    let outside = dir.with_extension("outside");
+    fs::create_dir_all(&outside).unwrap();
+    std::os::unix::fs::symlink(&outside, dir.join("l")).unwrap();
+    fs::write(
+        dir.join("x.patch"),
+        "diff --git a/l/x b/l/x\n\
+         new file mode 100644\n\
+         --- /dev/null\n\
+         +++ b/l/x\n\
+         @@ -0,0 +1 @@\n\
+         +x\n",
+    )
+    .unwrap();
+
+    for args in [&["apply", "x.patch"][..], &["apply", "--check", "x.patch"]] {
+        let output = run(&dir, args);
+
+        assert!(!output.status.success(), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("affected file 'l/x' is beyond a symbolic link"),
+            "{}",
+            stderr
+        );
+    }
+    assert!(!outside.join("x").exists());
+    fs::remove_dir_all(dir).unwrap();
+    fs::remove_dir_all(outside).unwrap();
+}
@@ -0,0 +1,60 @@
+use git_starter_rust::commit::Commit;
+use git_starter_rust::hash::HashAlgo;
+use git_starter_rust::object::{Object, ObjectKind};
+
+/// Commit git accepts but which doesn't survive parsing into fields: non-UTF-8 header
+/// without encoding, padded name, date before 1970, CR at the end of header line,
+/// signature and non-UTF-8 message
+const RAW: &[u8] = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author J\xf6rg  Doe <j@example.com> -86400 +0100\n\
+committer C O Mitter <c@example.com> 1700000000 -0500\r\n\
+gpgsig -----BEGIN PGP SIGNATURE-----\n \n iQEz\n -----END PGP SIGNATURE-----\n\
+\n\
+Caf\xe9\r\n\nbody\n";
+
+fn parse(data: &[u8]) -> (Object, Commit) {
+    let obj = Object::new(HashAlgo::Sha1, ObjectKind::Commit, data.to_vec());
+    let commit = Commit::try_from(obj.clone()).unwrap();
+    (obj, commit)
+}
+
+#[test]
+fn unchanged_commit_is_written_back_exactly() {
+    let (obj, commit) = parse(RAW);
+    assert_eq!(commit.author.unix_time(), -86400);
+
+    let data = commit.to_bytes();
+
+    assert_eq!(data, RAW);
+    let written = Object::new(HashAlgo::Sha1, ObjectKind::Commit, data);
+    assert_eq!(written.hash, obj.hash);
+}
+
+#[test]
+fn signature_payload_is_taken_from_raw_data() {
+    let (_, commit) = parse(RAW);
+
+    let (payload, signature) = commit.split_header("gpgsig").unwrap();
+
+    let expected: Vec<u8> = [
+        &RAW[..RAW.windows(7).position(|w| w == b"gpgsig ").unwrap()],
+        &b"\nCaf\xe9\r\n\nbody\n"[..],
+    ]
+    .concat();
+    assert_eq!(payload, expected);
+    assert_eq!(
+        signature,
+        b"-----BEGIN PGP SIGNATURE-----\n\niQEz\n-----END PGP SIGNATURE-----"
+    );
+}
+
+#[test]
+fn modified_commit_is_serialized_from_fields() {
+    let (_, mut commit) = parse(RAW);
+    commit.message = String::from("Changed\n");
+
+    let data = commit.to_bytes();
+
+    assert!(data.ends_with(b"\n\nChanged\n"));
+    assert_eq!(parse(&data).1.author.unix_time(), -86400);
+}
@@ -0,0 +1,507 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Command run in the directory with fixed identities and dates, without system and
+/// user config
+fn command(program: &str, dir: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .current_dir(dir)
+        .env("HOME", dir)
+        .env("XDG_CONFIG_HOME", dir)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_AUTHOR_NAME", "A U Thor")
+        .env("GIT_AUTHOR_EMAIL", "author@example.com")
+        .env("GIT_AUTHOR_DATE", "1700000000 +0000")
+        .env("GIT_COMMITTER_NAME", "C O Mitter")
+        .env("GIT_COMMITTER_EMAIL", "committer@example.com")
+        .env("GIT_COMMITTER_DATE", "1700000000 +0000");
+    command
+}
+
+fn ours(dir: &Path, args: &[&str]) -> Output {
+    command(env!("CARGO_BIN_EXE_git-starter-rust"), dir, args)
+        .output()
+        .unwrap()
+}
+
+fn git(dir: &Path, args: &[&str]) -> Output {
+    command("git", dir, args).output().unwrap()
+}
+
+fn stdout(output: Output, args: &[&str]) -> Vec<u8> {
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+/// Asserts that the command succeeds with the same output as git
+fn assert_same(dir: &Path, args: &[&str]) {
+    let expected = stdout(git(dir, args), args);
+    let actual = stdout(ours(dir, args), args);
+    assert!(
+        actual == expected,
+        "{:?} differs from git:\n{}\ngit:\n{}",
+        args,
+        String::from_utf8_lossy(&actual),
+        String::from_utf8_lossy(&expected)
+    );
+}
+
+/// Comparisons are skipped where git is not installed
+fn has_git() -> bool {
+    Command::new("git").arg("--version").output().is_ok()
+}
+
+/// Repository initialized by git in an empty temporary directory
+fn git_repo(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "git-starter-rust-compat-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    stdout(git(&dir, &["init", "-q", "-b", "main"]), &["init"]);
+    dir
+}
+
+/// Stages everything in the working tree and commits it with git
+fn commit_all(dir: &Path, message: &str) {
+    stdout(git(dir, &["add", "-A"]), &["add"]);
+    stdout(git(dir, &["commit", "-q", "-m", message]), &["commit"]);
+}
+
+/// Deterministic pseudo-random numbers below `n`
+struct Lcg(u64);
+
+impl Lcg {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1);
+        (self.0 >> 33) as usize % n
+    }
+}
+
+#[test]
+fn diff_matches_git() {
+    if !has_git() {
+        return;
+    }
+    let dir = git_repo("diff");
+    let mut lines: Vec<String> = (0..200).map(|i| format!("line {}\n", i)).collect();
+    fs::write(dir.join("a.txt"), lines.concat()).unwrap();
+    fs::write(dir.join("b.txt"), "x\ny\nz\n").unwrap();
+    let functions = "int f() {\n  return 1;\n}\n\nint g() {\n  return 2;\n}\n".repeat(5);
+    fs::write(dir.join("c.c"), &functions).unwrap();
+    let moved: Vec<String> = (0..50).map(|i| format!("moved {}\n", i)).collect();
+    fs::write(dir.join("moved.txt"), moved.concat()).unwrap();
+    fs::write(dir.join("no-newline.txt"), "a\nb").unwrap();
+    fs::write(dir.join("binary.dat"), (0..=255).collect::<Vec<u8>>()).unwrap();
+    commit_all(&dir, "first");
+
+    let mut random = Lcg(7);
+    for _ in 0..30 {
+        let i = random.below(lines.len());
+        match random.below(3) {
+            0 => lines.insert(i, format!("new {}\n", i)),
+            1 => drop(lines.remove(i)),
+            _ => lines[i] = lines[i].to_uppercase(),
+        }
+    }
+    fs::write(dir.join("a.txt"), lines.concat()).unwrap();
+    fs::remove_file(dir.join("b.txt")).unwrap();
+    fs::write(
+        dir.join("c.c"),
+        functions.replacen("return 2;", "return 3;", 2),
+    )
+    .unwrap();
+    fs::remove_file(dir.join("moved.txt")).unwrap();
+    let mut renamed = moved.clone();
+    renamed[10] = String::from("changed\n");
+    fs::write(dir.join("renamed.txt"), renamed.concat()).unwrap();
+    fs::write(dir.join("no-newline.txt"), "a\nb\nc").unwrap();
+    fs::write(dir.join("binary.dat"), (0..=255).rev().collect::<Vec<u8>>()).unwrap();
+    fs::write(dir.join("new.txt"), "new\n").unwrap();
+    commit_all(&dir, "second");
+
+    for args in [
+        &["diff", "--no-renames", "HEAD~1", "HEAD"][..],
+        &["diff", "-M", "HEAD~1", "HEAD"],
+        &["diff", "-U1", "HEAD~1", "HEAD"],
+        &["diff", "--stat", "HEAD~1", "HEAD"],
+        &["diff", "--numstat", "HEAD~1", "HEAD"],
+        &["diff", "--shortstat", "-M", "HEAD~1", "HEAD"],
+    ] {
+        assert_same(&dir, args);
+    }
+
+    // Staged and unstaged changes
+    fs::write(dir.join("c.c"), "q\n").unwrap();
+    stdout(git(&dir, &["add", "c.c"]), &["add"]);
+    fs::write(dir.join("c.c"), "q\nzz\n").unwrap();
+    fs::write(
+        dir.join("a.txt"),
+        [lines.concat(), "extra\n".into()].concat(),
+    )
+    .unwrap();
+    for args in [
+        &["diff"][..],
+        &["diff", "--cached"],
+        &["diff", "HEAD"],
+        &["diff", "--stat"],
+    ] {
+        assert_same(&dir, args);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn config_matches_git() {
+    if !has_git() {
+        return;
+    }
+    let dir = git_repo("config");
+    let content = "# comment\n\
+        [core]\n\
+        \tbare = false\n\
+        \tfilemode\n\
+        \tName = \"quoted ; not comment\" ; comment\n\
+        [Section \"Sub Section\"]\n\
+        \tkey = value with \\\"escape\\\" and \\\\ backslash\n\
+        \tmulti = one\n\
+        \tmulti = two\n\
+        \tcont = first \\\n\
+        second\n\
+        \tempty =\n\
+        [section.old]\n\
+        \tkey = old style\n\
+        [alias]\n\
+        \tlg = log --oneline # trailing\n\
+        \ttab = \"a\\tb\"\n\
+        [number]\n\
+        \tk = 1k\n\
+        \tm = 2M\n\
+        \tneg = -3\n\
+        [bool]\n\
+        \tyes = yes\n\
+        \toff = off\n\
+        \tzero = 0\n";
+    fs::write(dir.join("config"), content).unwrap();
+
+    assert_same(&dir, &["config", "-f", "config", "--list"]);
+    for key in [
+        "core.bare",
+        "core.filemode",
+        "CORE.NAME",
+        "section.Sub Section.key",
+        "section.Sub Section.multi",
+        "Section.Sub Section.cont",
+        "section.Sub Section.empty",
+        "section.old.key",
+        "alias.lg",
+        "alias.tab",
+    ] {
+        assert_same(&dir, &["config", "-f", "config", "--get", key]);
+    }
+    assert_same(
+        &dir,
+        &[
+            "config",
+            "-f",
+            "config",
+            "--get-all",
+            "section.Sub Section.multi",
+        ],
+    );
+    for (kind, key) in [
+        ("int", "number.k"),
+        ("int", "number.m"),
+        ("int", "number.neg"),
+        ("bool", "bool.yes"),
+        ("bool", "bool.off"),
+        ("bool", "bool.zero"),
+        ("bool", "core.filemode"),
+    ] {
+        assert_same(
+            &dir,
+            &["config", "-f", "config", "--type", kind, "--get", key],
+        );
+    }
+    for key in ["core.missing", "section.sub section.key"] {
+        let args = ["config", "-f", "config", "--get", key];
+        assert!(!git(&dir, &args).status.success());
+        assert!(!ours(&dir, &args).status.success(), "{}", key);
+    }
+
+    // Both edit copies of the same file the same way
+    fs::write(dir.join("ours"), content).unwrap();
+    fs::write(dir.join("theirs"), content).unwrap();
+    for args in [
+        &["core.bare", "true"][..],
+        &["section.Sub Section.new", "with space"],
+        &["--add", "section.Sub Section.multi", "three"],
+        &["--unset", "section.old.key"],
+        &["--unset-all", "section.Sub Section.multi"],
+        &["new.section.key", "\"quoted\" # hash"],
+        &["--unset", "alias.lg"],
+        &["--unset", "alias.tab"],
+    ] {
+        stdout(
+            git(&dir, &[&["config", "-f", "theirs"][..], args].concat()),
+            args,
+        );
+        stdout(
+            ours(&dir, &[&["config", "-f", "ours"][..], args].concat()),
+            args,
+        );
+    }
+    assert_eq!(
+        fs::read_to_string(dir.join("ours")).unwrap(),
+        fs::read_to_string(dir.join("theirs")).unwrap()
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn revparse_matches_git() {
+    if !has_git() {
+        return;
+    }
+    let dir = git_repo("revparse");
+    for i in 1..=3 {
+        fs::write(dir.join("f"), format!("{}\n", i)).unwrap();
+        commit_all(&dir, &format!("c{}", i));
+    }
+    stdout(git(&dir, &["tag", "light", "HEAD~1"]), &["tag"]);
+    stdout(
+        git(
+            &dir,
+            &["tag", "-a", "-m", "annotated", "annotated", "HEAD~2"],
+        ),
+        &["tag"],
+    );
+    stdout(
+        git(&dir, &["checkout", "-q", "-b", "side", "HEAD~1"]),
+        &["checkout"],
+    );
+    fs::create_dir(dir.join("d")).unwrap();
+    fs::write(dir.join("d/s"), "s\n").unwrap();
+    commit_all(&dir, "side");
+    stdout(git(&dir, &["checkout", "-q", "main"]), &["checkout"]);
+    stdout(
+        git(&dir, &["merge", "-q", "--no-ff", "-m", "merge", "side"]),
+        &["merge"],
+    );
+    let head = String::from_utf8(stdout(git(&dir, &["rev-parse", "HEAD"]), &[])).unwrap();
+
+    for rev in [
+        "HEAD",
+        "@",
+        "HEAD~1",
+        "HEAD~2^",
+        "HEAD^^",
+        "HEAD^2",
+        "HEAD^0",
+        "main",
+        "side",
+        "heads/side",
+        "refs/heads/side",
+        "light",
+        "tags/light",
+        "annotated",
+        "annotated^{}",
+        "annotated^{commit}",
+        "annotated^{tree}",
+        "HEAD^{tree}",
+        "main~1^{commit}",
+        "HEAD:f",
+        "HEAD:d/s",
+        "HEAD^2:d",
+        &head[..7],
+        &head[..12],
+        "HEAD~1..HEAD",
+        "side...main",
+    ] {
+        assert_same(&dir, &["rev-parse", rev]);
+    }
+    for rev in ["HEAD", "side"] {
+        assert_same(&dir, &["rev-parse", "--short", rev]);
+    }
+    for rev in ["HEAD~10", "HEAD^3", "missing", "HEAD:missing"] {
+        assert!(!git(&dir, &["rev-parse", rev]).status.success());
+        assert!(!ours(&dir, &["rev-parse", rev]).status.success(), "{}", rev);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Copies the working tree with its repository
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for item in fs::read_dir(from).unwrap() {
+        let item = item.unwrap();
+        if item.file_type().unwrap().is_dir() {
+            copy_dir(&item.path(), &to.join(item.file_name()));
+        } else {
+            fs::copy(item.path(), to.join(item.file_name())).unwrap();
+        }
+    }
+}
+
+#[test]
+fn merge_matches_git() {
+    if !has_git() {
+        return;
+    }
+    let dir = git_repo("merge");
+    let numbers = |n: usize| (1..=n).map(|i| format!("{}\n", i)).collect::<String>();
+    fs::write(dir.join("clean"), numbers(20)).unwrap();
+    fs::write(dir.join("conflict"), numbers(10)).unwrap();
+    fs::write(dir.join("deleted"), "gone\n").unwrap();
+    commit_all(&dir, "base");
+    stdout(git(&dir, &["checkout", "-q", "-b", "topic"]), &["checkout"]);
+    fs::write(dir.join("clean"), numbers(20).replace("\n2\n", "\ntwo\n")).unwrap();
+    fs::write(
+        dir.join("conflict"),
+        numbers(10).replace("5\n", "five topic\n"),
+    )
+    .unwrap();
+    fs::write(dir.join("added"), "new\n").unwrap();
+    fs::remove_file(dir.join("deleted")).unwrap();
+    commit_all(&dir, "topic");
+    stdout(
+        git(&dir, &["checkout", "-q", "-b", "clean", "main"]),
+        &["checkout"],
+    );
+    fs::write(dir.join("clean"), numbers(20).replace("18\n", "eighteen\n")).unwrap();
+    commit_all(&dir, "clean");
+    stdout(git(&dir, &["checkout", "-q", "main"]), &["checkout"]);
+    fs::write(dir.join("clean"), numbers(20).replace("18\n", "eighteen\n")).unwrap();
+    fs::write(
+        dir.join("conflict"),
+        numbers(10).replace("5\n", "five main\n"),
+    )
+    .unwrap();
+    commit_all(&dir, "main");
+
+    // Clean merge gives the same tree
+    let (with_git, with_ours) = (dir.with_extension("git"), dir.with_extension("ours"));
+    for branch in ["clean", "main"] {
+        for target in [&with_git, &with_ours] {
+            let _ = fs::remove_dir_all(target);
+            copy_dir(&dir, target);
+            stdout(git(target, &["checkout", "-q", branch]), &["checkout"]);
+        }
+        let git_merge = git(&with_git, &["merge", "-q", "-m", "merge", "topic"]);
+        let our_merge = ours(&with_ours, &["merge", "topic"]);
+
+        assert_eq!(
+            our_merge.status.code(),
+            git_merge.status.code(),
+            "{}",
+            branch
+        );
+        for name in ["clean", "conflict", "added"] {
+            assert_eq!(
+                fs::read(with_ours.join(name)).unwrap(),
+                fs::read(with_git.join(name)).unwrap(),
+                "{} on {}",
+                name,
+                branch
+            );
+        }
+        assert!(!with_ours.join("deleted").exists());
+        let index = |dir: &Path| stdout(git(dir, &["ls-files", "-s"]), &["ls-files"]);
+        assert_eq!(index(&with_ours), index(&with_git), "{}", branch);
+        if git_merge.status.success() {
+            let tree = |dir: &Path| stdout(git(dir, &["rev-parse", "HEAD^{tree}"]), &[]);
+            assert_eq!(tree(&with_ours), tree(&with_git));
+        }
+    }
+    fs::remove_dir_all(dir).unwrap();
+    fs::remove_dir_all(with_git).unwrap();
+    fs::remove_dir_all(with_ours).unwrap();
+}
+
+#[test]
+fn packs_resolve_as_in_git() {
+    if !has_git() {
+        return;
+    }
+    let dir = git_repo("pack");
+    for i in 1..=30 {
+        let grown: String = (1..=i * 20)
+            .map(|n| {
+                if n == i {
+                    format!("changed {}\n", n)
+                } else {
+                    format!("{}\n", n)
+                }
+            })
+            .collect();
+        fs::write(dir.join("grown"), grown).unwrap();
+        let shrunk: String = (i..=200).map(|n| format!("{}\n", n)).collect();
+        fs::write(dir.join("shrunk"), shrunk).unwrap();
+        commit_all(&dir, &format!("c{}", i));
+    }
+    // Offset deltas of gc, deltas of pack-objects refer to bases by id
+    stdout(git(&dir, &["gc", "-q", "--aggressive"]), &["gc"]);
+    let mut pack_objects = command("git", &dir, &["pack-objects", "--all", "--revs", "ref"]);
+    let output = pack_objects
+        .stdin(std::process::Stdio::null())
+        .output()
+        .unwrap();
+    let ref_pack = String::from_utf8(stdout(output, &["pack-objects"])).unwrap();
+    let gc_pack = fs::read_dir(dir.join(".git/objects/pack"))
+        .unwrap()
+        .map(|item| item.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "pack"))
+        .unwrap();
+
+    for (name, pack) in [
+        ("ofs", gc_pack.clone()),
+        ("ref", dir.join(format!("ref-{}.pack", ref_pack.trim()))),
+    ] {
+        let copy = dir.join(format!("{}-copy.pack", name));
+        fs::copy(&pack, &copy).unwrap();
+        stdout(
+            ours(&dir, &["index-pack", copy.to_str().unwrap()]),
+            &["index-pack"],
+        );
+        assert_eq!(
+            fs::read(copy.with_extension("idx")).unwrap(),
+            fs::read(pack.with_extension("idx")).unwrap(),
+            "{}",
+            name
+        );
+    }
+
+    // Objects resolved from the pack into a fresh repository are the same
+    let clone = git_repo("pack-clone");
+    let output = command(
+        env!("CARGO_BIN_EXE_git-starter-rust"),
+        &clone,
+        &["index-pack", "--stdin"],
+    )
+    .stdin(fs::File::open(gc_pack).unwrap())
+    .output()
+    .unwrap();
+    stdout(output, &["index-pack", "--stdin"]);
+    let ids = stdout(
+        git(&dir, &["rev-list", "--objects", "--all"]),
+        &["rev-list"],
+    );
+    for line in String::from_utf8(ids).unwrap().lines() {
+        let id = &line[..40];
+        let expected = stdout(git(&dir, &["cat-file", "-p", id]), &["cat-file"]);
+        let actual = stdout(ours(&clone, &["cat-file", "-p", id]), &["cat-file"]);
+        assert!(actual == expected, "{}", id);
+    }
+    fs::remove_dir_all(dir).unwrap();
+    fs::remove_dir_all(clone).unwrap();
+}
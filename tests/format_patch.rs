@@ -0,0 +1,111 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_git-starter-rust"))
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_AUTHOR_NAME", "A U Thor")
+        .env("GIT_AUTHOR_EMAIL", "author@example.com")
+        .env("GIT_AUTHOR_DATE", "1700000000 +0000")
+        .env("GIT_COMMITTER_NAME", "C O Mitter")
+        .env("GIT_COMMITTER_EMAIL", "committer@example.com")
+        .env("GIT_COMMITTER_DATE", "1700000000 +0000")
+        .output()
+        .unwrap()
+}
+
+fn git(dir: &Path, args: &[&str]) -> String {
+    let output = run(dir, args);
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Repository with commits `c1` to `c4`, each changing file `f`
+fn repo_with_commits(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("git-starter-rust-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    git(&dir, &["init"]);
+    for i in 1..=4 {
+        fs::write(dir.join("f"), format!("{}\n", i)).unwrap();
+        git(&dir, &["update-index", "--add", "f"]);
+        git(&dir, &["commit", "-m", &format!("c{}", i)]);
+    }
+    dir
+}
+
+fn subjects(patches: &str) -> Vec<&str> {
+    patches
+        .lines()
+        .filter_map(|line| line.strip_prefix("Subject: "))
+        .collect()
+}
+
+#[test]
+fn single_revision_formats_commits_since_it() {
+    let dir = repo_with_commits("format-patch-since");
+
+    let patches = git(&dir, &["format-patch", "--stdout", "HEAD~2"]);
+
+    assert_eq!(subjects(&patches), ["[PATCH 1/2] c3", "[PATCH 2/2] c4"]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn count_formats_topmost_commits() {
+    let dir = repo_with_commits("format-patch-count");
+
+    let patches = git(&dir, &["format-patch", "--stdout", "-2"]);
+    assert_eq!(subjects(&patches), ["[PATCH 1/2] c3", "[PATCH 2/2] c4"]);
+
+    let patches = git(&dir, &["format-patch", "--stdout", "-1", "HEAD~2"]);
+    assert_eq!(subjects(&patches), ["[PATCH] c2"]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn am_refuses_paths_outside_of_the_working_tree() {
+    let dir = repo_with_commits("am-invalid-path");
+    let outside = format!(
+        "../{}-outside/f",
+        dir.file_name().unwrap().to_str().unwrap()
+    );
+    for path in [outside.as_str(), ".git/hooks/post-commit"] {
+        let mail = format!(
+            "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\n\
+             From: A U Thor <author@example.com>\n\
+             Date: Tue, 14 Nov 2023 22:13:20 +0000\n\
+             Subject: [PATCH] evil\n\n\
+             ---\n\
+             diff --git a/{0} b/{0}\n\
+             new file mode 100755\n\
+             --- /dev/null\n\
+             +++ b/{0}\n\
+             @@ -0,0 +1 @@\n\
+             +echo pwned\n",
+            path
+        );
+        fs::write(dir.join("evil.patch"), mail).unwrap();
+
+        let output = run(&dir, &["am", "evil.patch"]);
+
+        assert!(!output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            stdout.contains(&format!("invalid path '{}'", path)),
+            "{}",
+            stdout
+        );
+    }
+    assert!(!dir.join(&outside).exists());
+    assert!(!dir.join(".git/hooks/post-commit").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,36 @@
+use git_starter_rust::fsck;
+use git_starter_rust::hash::HashAlgo;
+use git_starter_rust::object::{Object, ObjectKind};
+use git_starter_rust::GitRepo;
+
+/// Tree object of the raw (mode, name) entries, all pointing to the same id
+fn tree_of(entries: &[(&str, &str)]) -> Object {
+    let mut data = Vec::new();
+    for (mode, name) in entries {
+        data.extend(format!("{} {}\0", mode, name).as_bytes());
+        data.extend([0x11; 20]);
+    }
+    Object::new(HashAlgo::Sha1, ObjectKind::Tree, data)
+}
+
+#[test]
+fn duplicates_are_found_when_not_adjacent() {
+    let repo = GitRepo::new_bare(&std::env::temp_dir());
+    let tree = tree_of(&[("100644", "a"), ("100644", "a.b"), ("40000", "a")]);
+
+    let err = fsck::verify_object(&repo, &tree).unwrap_err();
+
+    assert!(err.to_string().contains("duplicateEntries"), "{}", err);
+}
+
+#[test]
+fn trees_sort_as_if_ending_with_slash() {
+    let repo = GitRepo::new_bare(&std::env::temp_dir());
+    let sorted = tree_of(&[("100644", "a.b"), ("40000", "a"), ("100644", "a0")]);
+    let unsorted = tree_of(&[("40000", "a"), ("100644", "a.b")]);
+
+    fsck::verify_object(&repo, &sorted).unwrap();
+    let err = fsck::verify_object(&repo, &unsorted).unwrap_err();
+
+    assert!(err.to_string().contains("treeNotSorted"), "{}", err);
+}
@@ -0,0 +1,28 @@
+use git_starter_rust::grep::Pattern;
+
+fn matches(pattern: &str, line: &str) -> bool {
+    Pattern::new(pattern, false)
+        .unwrap()
+        .is_match(line.as_bytes())
+}
+
+#[test]
+fn basic_regular_expressions_match() {
+    assert!(matches("b*c", "abbbc"));
+    assert!(matches("a.c", "xabcx"));
+    assert!(matches("^ab*$", "abbb\n"));
+    assert!(!matches("^b", "ab"));
+    assert!(!matches("a$", "ab"));
+    assert!(matches("[0-9][^a]", "x12"));
+    assert!(matches("*a", "*a"));
+    assert!(matches("x*", ""));
+    assert!(Pattern::new("ABC", true).unwrap().is_match(b"xabcx"));
+}
+
+#[test]
+fn repetitions_do_not_backtrack_exponentially() {
+    let line = "a".repeat(10000);
+
+    assert!(!matches("a*a*a*a*a*a*a*a*b", &line));
+    assert!(matches("^a*a*a*a*a*a*a*a*$", &line));
+}
@@ -0,0 +1,54 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use git_starter_rust::hooks::{self, HookName};
+use git_starter_rust::GitRepo;
+
+/// Fresh bare repository `repo.git` in an empty temporary directory
+fn bare_repo(name: &str) -> (PathBuf, GitRepo) {
+    let dir =
+        std::env::temp_dir().join(format!("git-starter-rust-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let repo = GitRepo::new_bare(&dir.join("repo.git"));
+    repo.init(Some("main")).unwrap();
+    (dir, repo)
+}
+
+#[test]
+fn hooks_are_not_shared_between_repos() {
+    let (dir, repo) = bare_repo("hooks-per-repo");
+    let other = GitRepo::new_bare(&dir.join("repo.git"));
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counter = runs.clone();
+    let repo = repo.with_hook(
+        HookName::PrePush,
+        Arc::new(move |_: &GitRepo, _: &[String], _: &[u8]| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }),
+    );
+
+    hooks::run(&repo, HookName::PrePush, &[], &[]).unwrap();
+    hooks::run(&repo.clone(), HookName::PrePush, &[], &[]).unwrap();
+    hooks::run(&other, HookName::PrePush, &[], &[]).unwrap();
+    hooks::run(&repo, HookName::PreCommit, &[], &[]).unwrap();
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn failing_hook_aborts_only_hooks_which_can_abort() {
+    let (dir, repo) = bare_repo("hooks-abort");
+    let fail = |_: &GitRepo, _: &[String], _: &[u8]| Err("rejected".into());
+    let repo = repo
+        .with_hook(HookName::PrePush, Arc::new(fail))
+        .with_hook(HookName::PostCommit, Arc::new(fail));
+
+    assert!(hooks::run(&repo, HookName::PrePush, &[], &[]).is_err());
+    assert!(hooks::run(&repo, HookName::PostCommit, &[], &[]).is_ok());
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,113 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use git_starter_rust::hash::HashAlgo;
+use git_starter_rust::pack::{self, delta};
+use git_starter_rust::GitRepo;
+
+/// Fresh bare repository `repo.git` in an empty temporary directory
+fn bare_repo(name: &str) -> (PathBuf, GitRepo) {
+    let dir =
+        std::env::temp_dir().join(format!("git-starter-rust-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let repo = GitRepo::new_bare(&dir.join("repo.git"));
+    repo.init(Some("main")).unwrap();
+    (dir, repo)
+}
+
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Pack of the raw entries with valid header and checksum
+fn pack_of(entries: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = b"PACK\0\0\0\x02".to_vec();
+    data.extend((entries.len() as u32).to_be_bytes());
+    for entry in entries {
+        data.extend(entry);
+    }
+    let checksum = HashAlgo::Sha1.digest(&data);
+    data.extend(checksum.as_bytes());
+    data
+}
+
+/// Blob entry with the header bytes and data
+fn blob_entry(header: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut entry = header.to_vec();
+    entry.extend(deflate(data));
+    entry
+}
+
+#[test]
+fn malformed_packs_are_errors() {
+    let (dir, repo) = bare_repo("pack-malformed");
+    let blob = blob_entry(&[0x31], b"x");
+    let packs = [
+        // Size continuing beyond 64 bits
+        pack_of(&[blob_entry(
+            &[
+                0xb0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+            ],
+            b"x",
+        )]),
+        // Declared size of 2^50 bytes
+        pack_of(&[blob_entry(
+            &[0xb0, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x40],
+            b"x",
+        )]),
+        // Data longer than the declared size
+        pack_of(&[blob_entry(&[0x31], b"xx")]),
+        // Offset of the delta base beyond 64 bits
+        pack_of(&[blob.clone(), {
+            let mut entry = vec![0x61];
+            entry.extend([0xff; 10]);
+            entry.push(0x01);
+            entry.extend(deflate(b"\x01\x01\x01x"));
+            entry
+        }]),
+        // Count of objects much larger than the pack
+        {
+            let mut data = pack_of(&[blob]);
+            data[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+            let len = data.len() - 20;
+            let checksum = HashAlgo::Sha1.digest(&data[..len]);
+            data[len..].copy_from_slice(checksum.as_bytes());
+            data
+        },
+    ];
+    for (i, data) in packs.iter().enumerate() {
+        assert!(
+            pack::read_objects(&repo, data, false).is_err(),
+            "pack {}",
+            i
+        );
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn malformed_deltas_are_errors() {
+    let base = b"base";
+    let deltas: [&[u8]; 4] = [
+        // Result size continuing beyond 64 bits
+        b"\x04\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01",
+        // Result of 2^50 bytes declared, one byte produced
+        b"\x04\x80\x80\x80\x80\x80\x80\x80\x02\x01x",
+        // Copies producing more than the declared size
+        b"\x04\x01\x90\x04\x90\x04",
+        // Copy beyond the end of the base
+        b"\x04\x04\x91\x02\x04",
+    ];
+    for delta in deltas {
+        assert!(delta::apply(base, delta).is_err(), "{:?}", delta);
+    }
+    assert_eq!(
+        delta::apply(base, b"\x04\x05\x90\x04\x01!").unwrap(),
+        b"base!"
+    );
+}
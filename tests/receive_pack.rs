@@ -0,0 +1,125 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use flate2::Compression;
+use git_starter_rust::hash::HashAlgo;
+use git_starter_rust::object::{Object, ObjectKind};
+use git_starter_rust::pkt_line;
+use git_starter_rust::serve::{http, ReceivePack};
+use git_starter_rust::{pack, refs, GitRepo};
+
+/// Fresh bare repository `repo.git` in an empty temporary directory
+fn bare_repo(name: &str) -> (PathBuf, GitRepo) {
+    let dir =
+        std::env::temp_dir().join(format!("git-starter-rust-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let repo = GitRepo::new_bare(&dir.join("repo.git"));
+    repo.init(Some("main")).unwrap();
+    (dir, repo)
+}
+
+/// Request creating the ref at a root commit, with pack containing the commit
+fn push_request(repo: &GitRepo, name: &str) -> Vec<u8> {
+    let algo = repo.hash_algo();
+    let tree = Object::new(algo, ObjectKind::Tree, Vec::new());
+    let commit = format!(
+        "tree {}\nauthor A U Thor <author@example.com> 1700000000 +0000\n\
+         committer A U Thor <author@example.com> 1700000000 +0000\n\nroot\n",
+        tree.hash
+    );
+    let commit = Object::new(algo, ObjectKind::Commit, commit.into_bytes());
+
+    let mut request = Vec::new();
+    let command = format!(
+        "{} {} {}\0report-status\n",
+        HashAlgo::Sha1.null_id(),
+        commit.hash,
+        name
+    );
+    pkt_line::write_data(&mut request, command.as_bytes());
+    pkt_line::write_flush(&mut request);
+    let objects = [tree, commit];
+    request.extend(pack::write_pack(objects.iter(), Compression::default(), algo).unwrap());
+    request
+}
+
+#[test]
+fn push_of_ref_outside_refs_dir_is_rejected() {
+    let (dir, repo) = bare_repo("push-traversal");
+    let request = push_request(&repo, "refs/../../pwned");
+
+    let response = ReceivePack::new(&repo).receive_pack(&request).unwrap();
+
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.contains("unpack ok"), "{}", response);
+    assert!(
+        response.contains("ng refs/../../pwned funny refname"),
+        "{}",
+        response
+    );
+    assert!(!dir.join("pwned").exists());
+    assert!(!dir.join("pwned.lock").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn transaction_rejects_malformed_ref_names() {
+    let (dir, repo) = bare_repo("transaction-names");
+    let id = repo.hash_algo().digest(b"");
+    for name in [
+        "refs/heads/../../../pwned",
+        "refs/heads/.hidden",
+        "refs/heads/main.lock",
+        "refs/heads/a\x01b",
+        "refs/heads/a@{1}",
+        "refs/heads/a\\b",
+        "refs/heads//main",
+    ] {
+        let mut transaction = refs::RefTransaction::new();
+        transaction.force_update(name, Some(&id), "test");
+        assert!(
+            transaction.commit(&repo).is_err(),
+            "{:?} was accepted",
+            name
+        );
+    }
+    assert!(!dir.join("pwned").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn push_to_head_branch_of_bare_repository_is_accepted() {
+    let (dir, repo) = bare_repo("push-bare-head");
+    let request = push_request(&repo, "refs/heads/main");
+
+    let response = ReceivePack::new(&repo).receive_pack(&request).unwrap();
+
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.contains("ok refs/heads/main"), "{}", response);
+    assert!(refs::resolve(&repo, "HEAD").unwrap().is_some());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn http_push_needs_to_be_enabled() {
+    let (dir, repo) = bare_repo("push-http-disabled");
+    let request = push_request(&repo, "refs/heads/main");
+    let push = || http::handle_request(&repo, "POST", "/git-receive-pack", "", &request);
+    let advertise =
+        || http::handle_request(&repo, "GET", "/info/refs", "service=git-receive-pack", b"");
+
+    assert_eq!(advertise().status, 403);
+    assert_eq!(push().status, 403);
+    assert!(refs::resolve(&repo, "refs/heads/main").unwrap().is_none());
+
+    let mut config = fs::OpenOptions::new()
+        .append(true)
+        .open(dir.join("repo.git").join("config"))
+        .unwrap();
+    config.write_all(b"[http]\n\treceivepack = true\n").unwrap();
+    assert_eq!(advertise().status, 200);
+    assert_eq!(push().status, 200);
+    assert!(refs::resolve(&repo, "refs/heads/main").unwrap().is_some());
+    fs::remove_dir_all(dir).unwrap();
+}
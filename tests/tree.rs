@@ -0,0 +1,47 @@
+use git_starter_rust::hash::HashAlgo;
+use git_starter_rust::object::{Object, ObjectKind};
+use git_starter_rust::tree::{Tree, TreeEntry};
+
+#[test]
+fn names_escaping_the_directory_are_invalid() {
+    for name in [
+        &b""[..],
+        b".",
+        b"..",
+        b".git",
+        b".GIT",
+        b".Git",
+        b"a/b",
+        b"a\0b",
+    ] {
+        assert!(!TreeEntry::is_valid_name(name), "{:?}", name);
+    }
+}
+
+#[test]
+fn other_names_are_valid() {
+    for name in [
+        &b"a"[..],
+        b"...",
+        b".gitignore",
+        b"git",
+        b".git~",
+        b"caf\xe9",
+    ] {
+        assert!(TreeEntry::is_valid_name(name), "{:?}", name);
+    }
+}
+
+#[test]
+fn duplicate_names_are_corrupt() {
+    let id = [0x11; 20];
+    let mut data = b"120000 a\0".to_vec();
+    data.extend(id);
+    data.extend(b"40000 a\0");
+    data.extend(id);
+    let obj = Object::new(HashAlgo::Sha1, ObjectKind::Tree, data);
+
+    let err = Tree::try_from(obj).err().unwrap();
+
+    assert!(err.to_string().contains("duplicate"), "{}", err);
+}